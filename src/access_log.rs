@@ -0,0 +1,186 @@
+use crate::config::{AccessLogFormat, AccessLogOptions};
+use crate::error::CbltError;
+use crate::log_file::RotatingFile;
+use crate::otel::{Span, SpanKind};
+use crate::response::log_request_response;
+use crate::sendfile::Sendfile;
+use bytes::BytesMut;
+use http::header::{REFERER, USER_AGENT};
+use http::{Request, StatusCode};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// What is known about a request once it has been answered
+#[derive(Debug, Default)]
+pub struct RequestLog {
+    pub host: Option<String>,    // configured host the request was routed to
+    pub country: Option<String>, // client's country when the host has a geoip database
+    pub span: Option<Span>,      // server span when the host has `tracing`
+    request_line: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    status: Option<StatusCode>,
+}
+
+impl RequestLog {
+    /// Remembers the request as received, before any rewrites
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn received(&mut self, request: &Request<BytesMut>) {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value: &http::HeaderValue| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        self.request_line = Some(format!(
+            "{} {} {:?}",
+            request.method(),
+            request.uri(),
+            request.version()
+        ));
+        self.referer = header(REFERER);
+        self.user_agent = header(USER_AGENT);
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn record(&mut self, request: &Request<BytesMut>, status: StatusCode) {
+        log_request_response(request, status);
+        self.status = Some(status);
+    }
+
+    pub fn status(&self) -> Option<StatusCode> {
+        self.status
+    }
+
+    /// Span of an operation within the request's span, if it is traced
+    pub fn child_span(&self, name: &str, kind: SpanKind) -> Option<Span> {
+        self.span.as_ref().map(|span| span.child(name, kind))
+    }
+}
+
+pub struct AccessLogger {
+    format: AccessLogFormat,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLogger {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn new(options: &AccessLogOptions) -> Result<Self, CbltError> {
+        let output: Box<dyn Write + Send> = match &options.output {
+            Some(path) => Box::new(RotatingFile::open(Path::new(path), options.roll.clone())?),
+            None => Box::new(std::io::stdout()),
+        };
+        Ok(AccessLogger {
+            format: options.format.clone(),
+            output: Mutex::new(output),
+        })
+    }
+
+    /// Writes a Common or Combined Log Format line followed by the latency in seconds and, with
+    /// GeoIP, the client's country
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn write(&self, addr: SocketAddr, entry: &RequestLog, bytes_sent: u64, latency: Duration) {
+        let status = match entry.status {
+            Some(status) => status,
+            None => return,
+        };
+        let quoted = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+            None => "\"-\"".to_string(),
+        };
+        let mut line = format!(
+            "{} - - [{}] {} {} {}",
+            addr.ip(),
+            chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+            quoted(&entry.request_line),
+            status.as_u16(),
+            bytes_sent
+        );
+        if self.format == AccessLogFormat::Combined {
+            line.push(' ');
+            line.push_str(&quoted(&entry.referer));
+            line.push(' ');
+            line.push_str(&quoted(&entry.user_agent));
+        }
+        line.push_str(&format!(" {:.3}", latency.as_secs_f64()));
+        if let Some(country) = &entry.country {
+            line.push(' ');
+            line.push_str(if country.is_empty() { "-" } else { country });
+        }
+        line.push('\n');
+
+        if let Ok(mut output) = self.output.lock() {
+            let _ = output.write_all(line.as_bytes());
+            let _ = output.flush();
+        }
+    }
+}
+
+/// Counts the bytes written to the client for the access log
+pub struct CountingStream<S> {
+    inner: S,
+    written: u64,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S) -> Self {
+        CountingStream { inner, written: 0 }
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.written += written as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: Sendfile> Sendfile for CountingStream<S> {
+    fn tcp_socket(&mut self) -> Option<&TcpStream> {
+        self.inner.tcp_socket()
+    }
+
+    fn sent(&mut self, bytes: u64) {
+        self.written += bytes;
+        self.inner.sent(bytes);
+    }
+}
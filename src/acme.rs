@@ -0,0 +1,688 @@
+use crate::config::{AcmeChallenge, AcmeOptions, Directive};
+use crate::dns::{dns_provider, http_request};
+use crate::error::CbltError;
+use crate::server::Server;
+use crate::tls::load_certified_key;
+use bytes::Bytes;
+use http::Request;
+use http_body_util::Full;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, Order, OrderStatus,
+};
+use log::{error, info};
+use rcgen::{CertificateParams, CustomExtension, DistinguishedName, KeyPair};
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::sign::CertifiedKey;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::fs;
+use tokio::task::JoinHandle;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 60 * 60); // Let's Encrypt certs live 90 days
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const POLL_ATTEMPTS: u32 = 10;
+const ON_DEMAND_RETRY: Duration = Duration::from_secs(10 * 60);
+const ON_DEMAND_FAILED_MAX: usize = 10_000; // names remembered as failed, the oldest are forgotten first
+const ON_DEMAND_ORDERS: usize = 20; // per ON_DEMAND_ORDER_WINDOW, well under the CA's own rate limits
+const ON_DEMAND_ORDER_WINDOW: Duration = Duration::from_secs(60 * 60);
+const ASK_TIMEOUT: Duration = Duration::from_secs(10);
+const DNS_PROPAGATION_DELAY: Duration = Duration::from_secs(30);
+
+static CHALLENGES: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(Default::default); // token -> key authorization
+static CERTS: LazyLock<RwLock<HashMap<String, Arc<CertifiedKey>>>> =
+    LazyLock::new(Default::default); // host -> issued certificate
+static ALPN_CERTS: LazyLock<RwLock<HashMap<String, Arc<CertifiedKey>>>> =
+    LazyLock::new(Default::default); // host -> TLS-ALPN-01 challenge certificate
+static ON_DEMAND: LazyLock<Mutex<HashMap<String, AcmeOptions>>> = LazyLock::new(Default::default); // names issued on demand
+static ON_DEMAND_FAILED: LazyLock<Mutex<HashMap<String, Instant>>> =
+    LazyLock::new(Default::default); // denied or failed names, not retried for a while
+static ON_DEMAND_PENDING: LazyLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(Default::default); // names being issued, their handshakes wait for one another
+static ON_DEMAND_ORDERED: LazyLock<Mutex<VecDeque<Instant>>> = LazyLock::new(Default::default); // on-demand orders within ON_DEMAND_ORDER_WINDOW
+static MANAGER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Key authorization for a pending HTTP-01 challenge
+pub fn http01_response(path: &str) -> Option<String> {
+    let token = path.strip_prefix(CHALLENGE_PATH)?;
+    CHALLENGES.lock().ok()?.get(token).cloned()
+}
+
+/// Challenge certificate for a handshake negotiating `acme-tls/1`
+pub fn tls_alpn01_certificate(host: &str) -> Option<Arc<CertifiedKey>> {
+    ALPN_CERTS
+        .read()
+        .ok()?
+        .get(&host.to_ascii_lowercase())
+        .cloned()
+}
+
+/// Certificate obtained for a host or its wildcard, if any
+pub fn certificate(host: &str) -> Option<Arc<CertifiedKey>> {
+    let host = host.to_ascii_lowercase();
+    let certs = CERTS.read().ok()?;
+    certs
+        .get(&host)
+        .or_else(|| {
+            let (_, parent) = host.split_once('.')?;
+            certs.get(&format!("*.{}", parent))
+        })
+        .cloned()
+}
+
+/// Hosts with a `tls "acme"` directive, on-demand ones included
+pub fn acme_hosts(servers: &HashMap<u16, Server>) -> Vec<(String, AcmeOptions)> {
+    let mut hosts = Vec::new();
+    for server in servers.values() {
+        for (host, directives) in &server.hosts {
+            for directive in directives {
+                if let Directive::TlsAcme { options } = directive {
+                    hosts.push((host.to_ascii_lowercase(), options.clone()));
+                }
+            }
+        }
+    }
+    hosts
+}
+
+/// Replaces the renewal task with one managing the given hosts
+pub fn start(hosts: Vec<(String, AcmeOptions)>) {
+    let Ok(mut manager) = MANAGER.lock() else {
+        return;
+    };
+    if let Some(task) = manager.take() {
+        task.abort();
+    }
+    if hosts.is_empty() {
+        return;
+    }
+    *manager = Some(tokio::spawn(async move {
+        loop {
+            let issued: Vec<(String, AcmeOptions)> = ON_DEMAND
+                .lock()
+                .map(|issued| issued.clone().into_iter().collect())
+                .unwrap_or_default();
+            // On-demand hosts only stand for the names issued through them
+            let configured = hosts
+                .iter()
+                .filter(|(_, options)| options.on_demand.is_none());
+            for (host, options) in configured.chain(issued.iter()) {
+                if let Err(err) = ensure_certificate(host, options).await {
+                    error!("ACME certificate for {}: {}", host, err);
+                }
+            }
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }));
+}
+
+/// Options of the host issuing certificates on demand, if any
+pub fn on_demand_options(hosts: &HashMap<String, Vec<Directive>>) -> Option<AcmeOptions> {
+    hosts
+        .values()
+        .flatten()
+        .find_map(|directive| match directive {
+            Directive::TlsAcme { options } if options.on_demand.is_some() => Some(options.clone()),
+            _ => None,
+        })
+}
+
+/// Obtains a certificate for an unknown SNI name when the on-demand policy allows it
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn issue_on_demand(name: &str, options: &AcmeOptions) {
+    if certificate(name).is_some() || recently_failed(name) {
+        return;
+    }
+    // Asked before waiting on anything, so a slow answer only holds up its own handshake
+    match on_demand_allowed(name, options, ASK_TIMEOUT).await {
+        Ok(true) => {}
+        Ok(false) => return failed(name, "not allowed on demand".to_string()),
+        Err(err) => return failed(name, err.to_string()),
+    }
+
+    // One issuance per name, the handshakes waiting for it then find the certificate
+    let pending = ON_DEMAND_PENDING
+        .lock()
+        .ok()
+        .map(|mut pending| pending.entry(name.to_string()).or_default().clone());
+    let Some(pending) = pending else {
+        return;
+    };
+    let issuing = pending.lock().await;
+    if certificate(name).is_none() && !recently_failed(name) {
+        let result = match install_stored(name, options).await {
+            Ok(false) if !take_order() => {
+                // Not held against the name, it is allowed and only has to wait
+                error!("On-demand certificate for {}: too many orders", name);
+                Ok(false)
+            }
+            Ok(false) => ensure_certificate(name, options).await.map(|()| true),
+            result => result,
+        };
+        match result {
+            Ok(true) => {
+                if let Ok(mut issued) = ON_DEMAND.lock() {
+                    issued.insert(name.to_string(), options.clone());
+                }
+            }
+            Ok(false) => {}
+            Err(err) => failed(name, err.to_string()),
+        }
+    }
+    drop(issuing);
+    if let Ok(mut pending) = ON_DEMAND_PENDING.lock() {
+        pending.remove(name);
+    }
+}
+
+fn recently_failed(name: &str) -> bool {
+    ON_DEMAND_FAILED.lock().is_ok_and(|failed| {
+        failed
+            .get(name)
+            .is_some_and(|at| at.elapsed() < ON_DEMAND_RETRY)
+    })
+}
+
+/// Remembers a denied or failed name for ON_DEMAND_RETRY, forgetting the oldest names when
+/// there are too many
+fn failed(name: &str, details: String) {
+    error!("On-demand certificate for {}: {}", name, details);
+    let Ok(mut failed) = ON_DEMAND_FAILED.lock() else {
+        return;
+    };
+    if failed.len() >= ON_DEMAND_FAILED_MAX {
+        failed.retain(|_, at| at.elapsed() < ON_DEMAND_RETRY);
+    }
+    if failed.len() >= ON_DEMAND_FAILED_MAX {
+        let oldest = failed
+            .iter()
+            .min_by_key(|(_, at)| **at)
+            .map(|(name, _)| name.clone());
+        if let Some(oldest) = oldest {
+            failed.remove(&oldest);
+        }
+    }
+    failed.insert(name.to_string(), Instant::now());
+}
+
+/// Whether another on-demand order fits in the rate limit, counting it when it does
+fn take_order() -> bool {
+    let Ok(mut ordered) = ON_DEMAND_ORDERED.lock() else {
+        return false;
+    };
+    while ordered
+        .front()
+        .is_some_and(|at| at.elapsed() >= ON_DEMAND_ORDER_WINDOW)
+    {
+        ordered.pop_front();
+    }
+    if ordered.len() >= ON_DEMAND_ORDERS {
+        return false;
+    }
+    ordered.push_back(Instant::now());
+    true
+}
+
+async fn on_demand_allowed(
+    name: &str,
+    options: &AcmeOptions,
+    ask_timeout: Duration,
+) -> Result<bool, CbltError> {
+    let Some(on_demand) = &options.on_demand else {
+        return Ok(false);
+    };
+    if on_demand.allow.iter().any(|allowed| allowed == name) {
+        return Ok(true);
+    }
+    let Some(ask) = &on_demand.ask else {
+        return Ok(false);
+    };
+    if !name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-')
+    {
+        return Ok(false);
+    }
+    let separator = if ask.contains('?') { '&' } else { '?' };
+    let request = Request::builder()
+        .uri(format!("{}{}domain={}", ask, separator, name))
+        .body(Full::new(Bytes::new()))?;
+    let (status, _) = tokio::time::timeout(ask_timeout, http_request(request))
+        .await
+        .map_err(|_| CbltError::AcmeOrderFailed {
+            details: format!("ask endpoint {} timed out", ask),
+        })??;
+    Ok(status.is_success())
+}
+
+/// Loads the stored certificate, obtaining a new one when it is missing or due for renewal
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn ensure_certificate(host: &str, options: &AcmeOptions) -> Result<(), CbltError> {
+    if host.contains('*') && options.challenge != AcmeChallenge::Dns01 {
+        return Err(CbltError::AcmeOrderFailed {
+            details: "wildcard hosts can only be validated with dns-01".to_string(),
+        });
+    }
+    if install_stored(host, options).await? {
+        return Ok(());
+    }
+
+    let storage = Path::new(&options.storage);
+    let (cert_path, key_path) = certificate_paths(host, options);
+    info!("Requesting ACME certificate for {}", host);
+    let (chain, key) = order_certificate(host, options).await?;
+    fs::create_dir_all(storage).await?;
+    fs::write(&cert_path, chain).await?;
+    write_private(&key_path, key.as_bytes()).await?;
+    install(host, &cert_path, &key_path)?;
+    info!(
+        "ACME certificate for {} stored in {}",
+        host,
+        storage.display()
+    );
+    Ok(())
+}
+
+/// Installs the stored certificate unless it is missing or due for renewal, telling whether it did
+async fn install_stored(host: &str, options: &AcmeOptions) -> Result<bool, CbltError> {
+    let (cert_path, key_path) = certificate_paths(host, options);
+    let age = match fs::metadata(&cert_path).await {
+        Ok(metadata) => metadata.modified()?.elapsed()?,
+        Err(_) => Duration::MAX,
+    };
+    if age >= RENEW_AFTER {
+        return Ok(false);
+    }
+    if certificate(host).is_none() {
+        install(host, &cert_path, &key_path)?;
+    }
+    Ok(true)
+}
+
+fn certificate_paths(host: &str, options: &AcmeOptions) -> (PathBuf, PathBuf) {
+    let storage = Path::new(&options.storage);
+    let file_name = host.replace('*', "_");
+    (
+        storage.join(format!("{}.crt", file_name)),
+        storage.join(format!("{}.key", file_name)),
+    )
+}
+
+fn install(host: &str, cert_path: &Path, key_path: &Path) -> Result<(), CbltError> {
+    let certified_key = load_certified_key(cert_path, key_path)?;
+    if let Ok(mut certs) = CERTS.write() {
+        certs.insert(host.to_string(), certified_key);
+    }
+    Ok(())
+}
+
+async fn write_private(path: &Path, contents: &[u8]) -> Result<(), CbltError> {
+    fs::write(path, contents).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    Ok(())
+}
+
+/// Account registered with the CA, reusing stored credentials
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn account(options: &AcmeOptions) -> Result<Account, CbltError> {
+    let path = account_path(options);
+    if let Ok(credentials) = fs::read(&path).await {
+        let credentials: AccountCredentials = serde_json::from_slice(&credentials)?;
+        return Ok(Account::from_credentials(credentials).await?);
+    }
+
+    let contact = options
+        .email
+        .as_ref()
+        .map(|email| format!("mailto:{}", email));
+    let contact: Vec<&str> = contact.iter().map(String::as_str).collect();
+    let (account, credentials) = Account::create(
+        &NewAccount {
+            contact: &contact,
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        &options.ca,
+        None,
+    )
+    .await?;
+    fs::create_dir_all(&options.storage).await?;
+    write_private(&path, &serde_json::to_vec(&credentials)?).await?;
+    Ok(account)
+}
+
+/// One account file per CA, so switching from staging to production registers again
+fn account_path(options: &AcmeOptions) -> PathBuf {
+    let ca = options
+        .ca
+        .split_once("://")
+        .map_or(&*options.ca, |(_, rest)| rest);
+    let name: String = ca
+        .trim_end_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Path::new(&options.storage).join(format!("account-{}.json", name))
+}
+
+/// Certificate chain and private key in PEM, validated with the configured challenge
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn order_certificate(
+    host: &str,
+    options: &AcmeOptions,
+) -> Result<(String, String), CbltError> {
+    let account = account(options).await?;
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &[Identifier::Dns(host.to_string())],
+        })
+        .await?;
+
+    let challenge_type = match options.challenge {
+        AcmeChallenge::Http01 => ChallengeType::Http01,
+        AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+        AcmeChallenge::Dns01 => ChallengeType::Dns01,
+    };
+    let dns = match (options.challenge, &options.dns) {
+        (AcmeChallenge::Dns01, Some(dns)) => Some(dns_provider(&dns.provider)?),
+        (AcmeChallenge::Dns01, None) => {
+            return Err(CbltError::AcmeOrderFailed {
+                details: "dns-01 challenge without a dns provider".to_string(),
+            });
+        }
+        _ => None,
+    };
+
+    let mut tokens = Vec::new();
+    let mut records = Vec::new();
+    let result = async {
+        let mut ready = Vec::new();
+        for authorization in order.authorizations().await? {
+            match authorization.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => {
+                    return Err(CbltError::AcmeOrderFailed {
+                        details: format!("authorization is {:?}", status),
+                    });
+                }
+            }
+            let Some(challenge) = authorization
+                .challenges
+                .iter()
+                .find(|challenge| challenge.r#type == challenge_type)
+            else {
+                return Err(CbltError::AcmeOrderFailed {
+                    details: format!("CA offered no {:?} challenge", challenge_type),
+                });
+            };
+            let key_authorization = order.key_authorization(challenge);
+            match (options.challenge, &dns) {
+                (AcmeChallenge::Http01, _) => {
+                    if let Ok(mut challenges) = CHALLENGES.lock() {
+                        challenges.insert(
+                            challenge.token.clone(),
+                            key_authorization.as_str().to_string(),
+                        );
+                    }
+                    tokens.push(challenge.token.clone());
+                }
+                (AcmeChallenge::TlsAlpn01, _) => {
+                    let certified_key =
+                        alpn_certificate(host, key_authorization.digest().as_ref())?;
+                    if let Ok(mut certs) = ALPN_CERTS.write() {
+                        certs.insert(host.to_string(), certified_key);
+                    }
+                }
+                (AcmeChallenge::Dns01, Some(dns)) => {
+                    // "*.example.com" is validated on the record of "example.com"
+                    let name = format!("_acme-challenge.{}", host.trim_start_matches("*."));
+                    records.push(dns.set_txt(&name, &key_authorization.dns_value()).await?);
+                }
+                (AcmeChallenge::Dns01, None) => {}
+            }
+            ready.push(challenge.url.clone());
+        }
+
+        if !records.is_empty() {
+            let delay = options
+                .dns
+                .as_ref()
+                .and_then(|dns| dns.propagation_delay)
+                .unwrap_or(DNS_PROPAGATION_DELAY);
+            tokio::time::sleep(delay).await;
+        }
+        for url in &ready {
+            order.set_challenge_ready(url).await?;
+        }
+        finalize(&mut order, host).await
+    }
+    .await;
+
+    if let Ok(mut challenges) = CHALLENGES.lock() {
+        for token in &tokens {
+            challenges.remove(token);
+        }
+    }
+    if let Ok(mut certs) = ALPN_CERTS.write() {
+        certs.remove(host);
+    }
+    if let Some(dns) = &dns {
+        for record in &records {
+            if let Err(err) = dns.remove_txt(record).await {
+                error!("Removing TXT record {}: {}", record.name, err);
+            }
+        }
+    }
+    result
+}
+
+/// Self-signed certificate carrying the acmeIdentifier extension (RFC 8737)
+fn alpn_certificate(host: &str, digest: &[u8]) -> Result<Arc<CertifiedKey>, CbltError> {
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![host.to_string()])?;
+    params.distinguished_name = DistinguishedName::new();
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let cert = params.self_signed(&key)?;
+    let key = PrivatePkcs8KeyDer::from(key.serialize_der());
+    // Not CertifiedKey::from_der, webpki rejects the critical acmeIdentifier extension
+    let provider = rustls::ServerConfig::builder().crypto_provider().clone();
+    let signing_key = provider.key_provider.load_private_key(key.into())?;
+    Ok(Arc::new(CertifiedKey::new(
+        vec![cert.der().clone()],
+        signing_key,
+    )))
+}
+
+async fn finalize(order: &mut Order, host: &str) -> Result<(String, String), CbltError> {
+    let mut delay = Duration::from_millis(500);
+    let mut attempts = 0;
+    loop {
+        tokio::time::sleep(delay).await;
+        match order.refresh().await?.status {
+            OrderStatus::Ready => break,
+            OrderStatus::Invalid => {
+                return Err(CbltError::AcmeOrderFailed {
+                    details: "order is invalid".to_string(),
+                });
+            }
+            _ => {}
+        }
+        attempts += 1;
+        if attempts >= POLL_ATTEMPTS {
+            return Err(CbltError::AcmeOrderFailed {
+                details: "order did not become ready".to_string(),
+            });
+        }
+        delay *= 2;
+    }
+
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![host.to_string()])?;
+    params.distinguished_name = DistinguishedName::new();
+    let csr = params.serialize_request(&key)?;
+    order.finalize(csr.der()).await?;
+
+    for _ in 0..POLL_ATTEMPTS {
+        if let Some(chain) = order.certificate().await? {
+            return Ok((chain, key.serialize_pem()));
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Err(CbltError::AcmeOrderFailed {
+        details: "certificate was not issued".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::acme::{
+        account_path, alpn_certificate, failed, http01_response, issue_on_demand,
+        on_demand_allowed, recently_failed, take_order, CHALLENGES, ON_DEMAND_FAILED,
+        ON_DEMAND_FAILED_MAX, ON_DEMAND_ORDERS,
+    };
+    use crate::config::{AcmeOptions, OnDemandOptions};
+    use std::error::Error;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Ask endpoint allowing the names starting with "ok.", counting the requests it gets
+    async fn ask_endpoint() -> Result<(AcmeOptions, Arc<AtomicUsize>), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}/allowed", listener.local_addr()?);
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut request = [0; 1024];
+                let len = stream.read(&mut request).await.unwrap_or(0);
+                let status = if request[..len].starts_with(b"GET /allowed?domain=ok.") {
+                    "200 OK"
+                } else {
+                    "403 Forbidden"
+                };
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let options = AcmeOptions {
+            on_demand: Some(OnDemandOptions {
+                allow: vec!["listed.example.com".to_string()],
+                ask: Some(url),
+            }),
+            ..Default::default()
+        };
+        Ok((options, asked))
+    }
+
+    #[test]
+    fn test_http01_response() {
+        CHALLENGES
+            .lock()
+            .unwrap()
+            .insert("token123".to_string(), "token123.thumbprint".to_string());
+        assert_eq!(
+            http01_response("/.well-known/acme-challenge/token123").as_deref(),
+            Some("token123.thumbprint")
+        );
+        assert_eq!(http01_response("/.well-known/acme-challenge/other"), None);
+        assert_eq!(http01_response("/token123"), None);
+    }
+
+    #[test]
+    fn test_account_path() {
+        let options = AcmeOptions {
+            storage: "/var/lib/cblt".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            account_path(&options),
+            Path::new("/var/lib/cblt/account-acme-v02.api.letsencrypt.org_directory.json")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_on_demand_allowed() -> Result<(), Box<dyn Error>> {
+        let timeout = Duration::from_secs(5);
+        let (options, asked) = ask_endpoint().await?;
+        assert!(on_demand_allowed("listed.example.com", &options, timeout).await?);
+        assert_eq!(asked.load(Ordering::SeqCst), 0);
+        assert!(on_demand_allowed("ok.example.com", &options, timeout).await?);
+        assert!(!on_demand_allowed("denied.example.com", &options, timeout).await?);
+        assert_eq!(asked.load(Ordering::SeqCst), 2);
+        // Names that could change the query are never asked about
+        assert!(!on_demand_allowed("ok.example.com&x=1", &options, timeout).await?);
+        assert_eq!(asked.load(Ordering::SeqCst), 2);
+        assert!(!on_demand_allowed("ok.example.com", &AcmeOptions::default(), timeout).await?);
+
+        // An endpoint that never answers
+        let silent = TcpListener::bind("127.0.0.1:0").await?;
+        let options = AcmeOptions {
+            on_demand: Some(OnDemandOptions {
+                allow: Vec::new(),
+                ask: Some(format!("http://{}/allowed", silent.local_addr()?)),
+            }),
+            ..Default::default()
+        };
+        let allowed = on_demand_allowed("ok.example.com", &options, Duration::from_millis(200));
+        assert!(allowed.await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_on_demand_failures() -> Result<(), Box<dyn Error>> {
+        let (options, asked) = ask_endpoint().await?;
+        issue_on_demand("denied.backoff.test", &options).await;
+        assert!(recently_failed("denied.backoff.test"));
+        // Not asked again until the failure expires
+        issue_on_demand("denied.backoff.test", &options).await;
+        assert_eq!(asked.load(Ordering::SeqCst), 1);
+
+        // The oldest failures are forgotten once there are too many
+        for i in 0..ON_DEMAND_FAILED_MAX {
+            failed(&format!("{}.flood.test", i), "denied".to_string());
+        }
+        assert!(ON_DEMAND_FAILED.lock().unwrap().len() <= ON_DEMAND_FAILED_MAX);
+        assert!(!recently_failed("denied.backoff.test"));
+        assert!(recently_failed(&format!(
+            "{}.flood.test",
+            ON_DEMAND_FAILED_MAX - 1
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_take_order() {
+        for _ in 0..ON_DEMAND_ORDERS {
+            assert!(take_order());
+        }
+        assert!(!take_order());
+    }
+
+    #[test]
+    fn test_alpn_certificate() {
+        let certified_key = alpn_certificate("example.com", &[7; 32]).unwrap();
+        assert_eq!(certified_key.cert.len(), 1);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::cidr::contains_ip;
+use crate::config::{AutoBanOptions, Directive};
+use crate::response::status_matches;
+use http::StatusCode;
+use log::info;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+const SWEEP_AT: usize = 10_000; // clients tracked before stale ones are dropped
+
+/// The first `auto_ban` of the hosts on a port applies to the whole listener
+pub fn listener_options(hosts: &HashMap<String, Vec<Directive>>) -> Option<AutoBanOptions> {
+    let mut hostnames: Vec<&String> = hosts.keys().collect();
+    hostnames.sort();
+    hostnames.iter().find_map(|host| {
+        hosts[*host].iter().find_map(|directive| match directive {
+            Directive::AutoBan { options } => Some(options.clone()),
+            _ => None,
+        })
+    })
+}
+
+/// Clients of a server that answered too many requests with a failure status, kept across
+/// configuration reloads
+pub struct Bans {
+    state: Mutex<State>,
+}
+
+struct State {
+    options: Option<AutoBanOptions>,
+    failures: HashMap<IpAddr, (u32, Instant)>, // in the window started then
+    banned: HashMap<IpAddr, Instant>,          // until then
+    sweep_at: usize,
+}
+
+impl Bans {
+    pub fn new(options: Option<AutoBanOptions>) -> Self {
+        Bans {
+            state: Mutex::new(State {
+                options,
+                failures: HashMap::new(),
+                banned: HashMap::new(),
+                sweep_at: SWEEP_AT,
+            }),
+        }
+    }
+
+    /// Bans in force stay until they expire, unless `auto_ban` is gone
+    pub fn set_options(&self, options: Option<AutoBanOptions>) {
+        if let Ok(mut state) = self.state.lock() {
+            if options.is_none() {
+                state.failures.clear();
+                state.banned.clear();
+            }
+            state.options = options;
+        }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+        match state.banned.get(&ip) {
+            Some(until) if Instant::now() < *until => true,
+            Some(_) => {
+                state.banned.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Counts a failure status against the client, true when that gets it banned
+    pub fn record(&self, ip: IpAddr, status: StatusCode) -> bool {
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+        let Some(options) = state.options.clone() else {
+            return false;
+        };
+        let failed = options
+            .statuses
+            .iter()
+            .any(|pattern| status_matches(pattern, status));
+        if !failed || contains_ip(&options.allow, &ip) {
+            return false;
+        }
+
+        let now = Instant::now();
+        if state.failures.len() >= state.sweep_at {
+            state
+                .failures
+                .retain(|_, (_, since)| now.duration_since(*since) < options.window);
+            state.banned.retain(|_, until| now < *until);
+            state.sweep_at = (state.failures.len() * 2).max(SWEEP_AT);
+        }
+        let (count, since) = state.failures.entry(ip).or_insert((0, now));
+        if now.duration_since(*since) >= options.window {
+            *count = 0;
+            *since = now;
+        }
+        *count += 1;
+        if *count < options.max_failures {
+            return false;
+        }
+        state.failures.remove(&ip);
+        state.banned.insert(ip, now + options.ban);
+        info!(
+            "Banned {} for {} after {} failed requests",
+            ip,
+            humantime::format_duration(options.ban),
+            options.max_failures
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::auto_ban::Bans;
+    use crate::config::AutoBanOptions;
+    use http::StatusCode;
+    use std::error::Error;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_auto_ban() -> Result<(), Box<dyn Error>> {
+        let bans = Bans::new(Some(AutoBanOptions {
+            statuses: vec!["401".to_string(), "403".to_string()],
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            ban: Duration::from_millis(200),
+            allow: vec!["10.0.0.0/8".parse()?],
+        }));
+        let (client, trusted): (IpAddr, IpAddr) = ("192.0.2.1".parse()?, "10.0.0.1".parse()?);
+
+        assert!(!bans.record(client, StatusCode::UNAUTHORIZED));
+        assert!(!bans.record(client, StatusCode::NOT_FOUND));
+        assert!(!bans.record(client, StatusCode::FORBIDDEN));
+        assert!(!bans.is_banned(client));
+        assert!(bans.record(client, StatusCode::UNAUTHORIZED));
+        assert!(bans.is_banned(client));
+
+        for _ in 0..10 {
+            assert!(!bans.record(trusted, StatusCode::UNAUTHORIZED));
+        }
+        assert!(!bans.is_banned(trusted));
+
+        // The ban runs out, and the count starts over
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(!bans.is_banned(client));
+        assert!(!bans.record(client, StatusCode::UNAUTHORIZED));
+
+        bans.set_options(None);
+        for _ in 0..10 {
+            assert!(!bans.record(client, StatusCode::UNAUTHORIZED));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,309 @@
+use crate::error::CbltError;
+use bytes::{Bytes, BytesMut};
+use http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout_at, Instant};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+const MAX_BATCH_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    Empty,
+    Length(u64),
+    Chunked,
+    UntilClose,
+}
+
+impl BodyKind {
+    /// Message body framing of a response as described in RFC 9112 section 6.3
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn of_response(method: &Method, status: StatusCode, headers: &HeaderMap) -> Self {
+        if method == Method::HEAD
+            || status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return BodyKind::Empty;
+        }
+        if is_chunked(headers) {
+            return BodyKind::Chunked;
+        }
+        match content_length(headers) {
+            Some(0) => BodyKind::Empty,
+            Some(len) => BodyKind::Length(len),
+            None => BodyKind::UntilClose,
+        }
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn is_chunked(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TRANSFER_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("chunked"))
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+enum ChunkState {
+    Size,
+    Data(u64),
+    DataEnd,
+    Done,
+}
+
+/// Reads a message body piece by piece without buffering all of it
+pub struct BodyReader<'a, S> {
+    stream: &'a mut S,
+    buf: BytesMut,
+    kind: BodyKind,
+    remaining: u64,
+    chunk: ChunkState,
+    trailers: HeaderMap,
+}
+
+impl<'a, S> BodyReader<'a, S>
+where
+    S: AsyncReadExt + Unpin,
+{
+    /// `buf` holds body bytes that were read together with the headers
+    pub fn new(stream: &'a mut S, buf: BytesMut, kind: BodyKind) -> Self {
+        let remaining = match kind {
+            BodyKind::Length(len) => len,
+            _ => 0,
+        };
+        BodyReader {
+            stream,
+            buf,
+            kind,
+            remaining,
+            chunk: ChunkState::Size,
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn next_chunk(&mut self) -> Result<Option<Bytes>, CbltError> {
+        match self.kind {
+            BodyKind::Empty => Ok(None),
+            BodyKind::Length(_) => {
+                if self.remaining == 0 {
+                    return Ok(None);
+                }
+                if self.buf.is_empty() {
+                    self.fill().await?;
+                }
+                let len = self.remaining.min(self.buf.len() as u64);
+                self.remaining -= len;
+                Ok(Some(self.buf.split_to(len as usize).freeze()))
+            }
+            BodyKind::UntilClose => {
+                if self.buf.is_empty() && self.stream.read_buf(&mut self.buf).await? == 0 {
+                    return Ok(None);
+                }
+                Ok(Some(self.buf.split().freeze()))
+            }
+            BodyKind::Chunked => loop {
+                match self.chunk {
+                    ChunkState::Size => {
+                        let line = self.read_line().await?;
+                        let size_str = line.split(';').next().unwrap_or("").trim();
+                        let size = u64::from_str_radix(size_str, 16).map_err(|_| {
+                            std::io::Error::new(ErrorKind::InvalidData, "Invalid chunk size")
+                        })?;
+                        if size == 0 {
+                            self.read_trailers().await?;
+                            self.chunk = ChunkState::Done;
+                        } else {
+                            self.chunk = ChunkState::Data(size);
+                        }
+                    }
+                    ChunkState::Data(left) => {
+                        if self.buf.is_empty() {
+                            self.fill().await?;
+                        }
+                        let len = left.min(self.buf.len() as u64);
+                        self.chunk = if left == len {
+                            ChunkState::DataEnd
+                        } else {
+                            ChunkState::Data(left - len)
+                        };
+                        return Ok(Some(self.buf.split_to(len as usize).freeze()));
+                    }
+                    ChunkState::DataEnd => {
+                        self.read_line().await?;
+                        self.chunk = ChunkState::Size;
+                    }
+                    ChunkState::Done => return Ok(None),
+                }
+            },
+        }
+    }
+
+    /// Like `next_chunk`, but collects what arrives within `interval` of the first piece
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn next_batch(
+        &mut self,
+        interval: Option<Duration>,
+    ) -> Result<Option<Bytes>, CbltError> {
+        let Some(first) = self.next_chunk().await? else {
+            return Ok(None);
+        };
+        let Some(interval) = interval.filter(|interval| !interval.is_zero()) else {
+            return Ok(Some(first));
+        };
+        let deadline = Instant::now() + interval;
+        let mut batch = BytesMut::from(&first[..]);
+        // The end of the body is reported by the next call
+        while batch.len() < MAX_BATCH_SIZE {
+            match timeout_at(deadline, self.next_chunk()).await {
+                Ok(chunk) => match chunk? {
+                    Some(chunk) => batch.extend_from_slice(&chunk),
+                    None => break,
+                },
+                Err(_) => break,
+            }
+        }
+        Ok(Some(batch.freeze()))
+    }
+
+    async fn fill(&mut self) -> Result<(), CbltError> {
+        if self.stream.read_buf(&mut self.buf).await? == 0 {
+            return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<String, CbltError> {
+        loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buf.split_to(pos + 2);
+                return Ok(String::from_utf8_lossy(&line[..pos]).into_owned());
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn read_trailers(&mut self) -> Result<(), CbltError> {
+        loop {
+            let line = self.read_line().await?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if let (Ok(name), Ok(value)) = (
+                    HeaderName::from_bytes(name.trim().as_bytes()),
+                    HeaderValue::from_str(value.trim()),
+                ) {
+                    self.trailers.append(name, value);
+                }
+            }
+        }
+    }
+}
+
+/// Writes a message body, framing it with chunked encoding when its length is unknown
+pub struct BodyWriter<'a, S> {
+    socket: &'a mut S,
+    chunked: bool,
+}
+
+impl<'a, S> BodyWriter<'a, S>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    pub fn new(socket: &'a mut S, chunked: bool) -> Self {
+        BodyWriter { socket, chunked }
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), CbltError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.chunked {
+            let size_line = format!("{:x}\r\n", data.len());
+            self.socket.write_all(size_line.as_bytes()).await?;
+            self.socket.write_all(data).await?;
+            self.socket.write_all(b"\r\n").await?;
+        } else {
+            self.socket.write_all(data).await?;
+        }
+        self.socket.flush().await?;
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn finish(self, trailers: &HeaderMap) -> Result<(), CbltError> {
+        if self.chunked {
+            let mut end = Vec::with_capacity(5);
+            end.extend_from_slice(b"0\r\n");
+            for (key, value) in trailers.iter() {
+                end.extend_from_slice(key.as_str().as_bytes());
+                end.extend_from_slice(b": ");
+                end.extend_from_slice(value.as_bytes());
+                end.extend_from_slice(b"\r\n");
+            }
+            end.extend_from_slice(b"\r\n");
+            self.socket.write_all(&end).await?;
+        }
+        self.socket.flush().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::body::{BodyKind, BodyReader};
+    use bytes::BytesMut;
+    use std::error::Error;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_chunked_reader() -> Result<(), Box<dyn Error>> {
+        let mut stream: &[u8] = b"lo\r\n6\r\n world\r\n0\r\nGrpc-Status: 0\r\n\r\n";
+        let leftover = BytesMut::from(&b"5\r\nhel"[..]);
+        let mut reader = BodyReader::new(&mut stream, leftover, BodyKind::Chunked);
+        let mut body = Vec::new();
+        while let Some(chunk) = reader.next_chunk().await? {
+            body.extend_from_slice(&chunk);
+        }
+        assert_eq!(body, b"hello world");
+        assert_eq!(reader.trailers().get("grpc-status").unwrap(), "0");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_next_batch() -> Result<(), Box<dyn Error>> {
+        let body: &[u8] = b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let mut stream = body;
+        let mut reader = BodyReader::new(&mut stream, BytesMut::new(), BodyKind::Chunked);
+        assert_eq!(reader.next_batch(None).await?.unwrap(), "hello");
+
+        let mut stream = body;
+        let mut reader = BodyReader::new(&mut stream, BytesMut::new(), BodyKind::Chunked);
+        let interval = Some(Duration::from_secs(1));
+        assert_eq!(reader.next_batch(interval).await?.unwrap(), "hello world");
+        assert_eq!(reader.next_batch(interval).await?, None);
+
+        Ok(())
+    }
+}
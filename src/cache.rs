@@ -0,0 +1,755 @@
+use crate::cidr::contains_ip;
+use crate::config::CacheOptions;
+use crate::reverse_proxy::remove_hop_by_hop_headers;
+use bytes::{Bytes, BytesMut};
+use http::header::{
+    AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, DATE, EXPIRES, HOST, SET_COOKIE,
+    TRANSFER_ENCODING, VARY,
+};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+pub const CACHE_STATUS: &str = "cache-status";
+/// Statuses a response may be stored with, the others are passed through
+const CACHEABLE: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+/// How long a background refresh has before another request may start one
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Responses of a `reverse_proxy` kept for repeated identical requests
+pub struct ProxyCache {
+    options: CacheOptions,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    vary: HashMap<String, (Vec<HeaderName>, usize)>, // request headers a URL's variants differ in
+    entries: HashMap<String, Entry>,                 // by URL and the values of those headers
+    used: BTreeMap<u64, String>,                     // least recently used first
+    size: usize,
+    clock: u64,
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant, // when the response was generated, going by its Age
+    fresh_for: Duration,
+    stale: Stale,
+    refreshing: Option<Instant>, // since a request went to refresh it
+    used: u64,
+}
+
+/// How long past its freshness a response may still be served
+#[derive(Clone, Copy, Default)]
+struct Stale {
+    while_revalidate: Duration,
+    if_error: Duration,
+}
+
+/// What the cache holds for a request
+pub enum Lookup {
+    Fresh(Cached),
+    /// Served while being refreshed, true when it is up to this request to refresh it
+    Stale(Cached, bool),
+    /// The backend has to answer, with what may stand in when it fails to
+    Miss(Option<Cached>),
+}
+
+/// Response read from the cache, with its Age as of now
+pub struct Cached {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// Response on its way to the client that will be stored once it has been read in full
+pub struct Capture {
+    key: String,
+    vary: Vec<HeaderName>,
+    variant: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    stored: Instant,
+    fresh_for: Duration,
+    stale: Stale,
+    body: BytesMut,
+    limit: usize,
+    overflowed: bool,
+}
+
+impl Capture {
+    /// Collects a piece of the body, giving up on storing it past `max_object_size`
+    pub fn push(&mut self, chunk: &[u8]) {
+        if self.overflowed {
+            return;
+        }
+        if self.body.len() + chunk.len() > self.limit {
+            self.overflowed = true;
+            self.body = BytesMut::new();
+            return;
+        }
+        self.body.extend_from_slice(chunk);
+    }
+}
+
+impl ProxyCache {
+    pub fn new(options: CacheOptions) -> Self {
+        ProxyCache {
+            options,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Stored response for the request, stale ones only within their `stale-while-revalidate`
+    /// or `stale-if-error` time. A client asking for a new response only gets one as a fallback.
+    pub fn lookup<B>(&self, request: &Request<B>) -> Lookup {
+        self.find(request).unwrap_or(Lookup::Miss(None))
+    }
+
+    fn find<B>(&self, request: &Request<B>) -> Option<Lookup> {
+        let key = cache_key(request)?;
+        let reload = cache_control(request.headers())
+            .iter()
+            .any(|(name, value)| name == "no-cache" || (name == "max-age" && value == "0"));
+        let mut state = self.state.lock().ok()?;
+        let (vary, _) = state.vary.get(&key)?;
+        let variant = variant_key(&key, vary, request.headers());
+        let entry = state.entries.get_mut(&variant)?;
+        let age = entry.stored.elapsed();
+        let stale_for = age.saturating_sub(entry.fresh_for);
+        let fresh = age < entry.fresh_for;
+        if !fresh && stale_for >= entry.stale.while_revalidate && stale_for >= entry.stale.if_error
+        {
+            state.remove(&variant);
+            return None;
+        }
+        let mut cached = Cached {
+            status: entry.status,
+            headers: entry.headers.clone(),
+            body: entry.body.clone(),
+        };
+        cached.headers.insert(AGE, HeaderValue::from(age.as_secs()));
+        let lookup = if fresh && !reload {
+            Lookup::Fresh(cached)
+        } else if !reload && stale_for < entry.stale.while_revalidate {
+            let refresh = entry
+                .refreshing
+                .is_none_or(|since| since.elapsed() >= REFRESH_TIMEOUT);
+            if refresh {
+                entry.refreshing = Some(Instant::now());
+            }
+            Lookup::Stale(cached, refresh)
+        } else if fresh || stale_for < entry.stale.if_error {
+            return Some(Lookup::Miss(Some(cached)));
+        } else {
+            return None;
+        };
+        state.touch(&variant);
+        Some(lookup)
+    }
+
+    /// Starts storing a backend's response to the request, when both allow it
+    pub fn capture<B>(
+        &self,
+        request: &Request<B>,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Option<Capture> {
+        if request.method() != Method::GET || !CACHEABLE.contains(&status.as_u16()) {
+            return None;
+        }
+        let key = cache_key(request)?;
+        let request_directives = cache_control(request.headers());
+        let directives = cache_control(headers);
+        let has = |directives: &[(String, String)], name: &str| {
+            directives.iter().any(|(directive, _)| directive == name)
+        };
+        if has(&request_directives, "no-store")
+            || ["no-store", "no-cache", "private"]
+                .iter()
+                .any(|name| has(&directives, name))
+            || headers.contains_key(SET_COOKIE)
+        {
+            return None;
+        }
+        // A shared cache only keeps answers to authorized requests it was told it may share
+        if request.headers().contains_key(AUTHORIZATION)
+            && !has(&directives, "public")
+            && !has(&directives, "s-maxage")
+        {
+            return None;
+        }
+        let vary = vary_headers(headers)?;
+        let fresh_for = match self.options.ttl {
+            Some(ttl) => ttl,
+            None => freshness(headers, &directives)?,
+        };
+        let stale = self.stale(&directives);
+        let age = headers
+            .get(AGE)
+            .and_then(|age| age.to_str().ok()?.trim().parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+        if age >= fresh_for {
+            return None;
+        }
+        if crate::body::content_length(headers)
+            .is_some_and(|len| len > self.options.max_object_size)
+        {
+            return None;
+        }
+
+        let mut headers = headers.clone();
+        remove_hop_by_hop_headers(&mut headers);
+        headers.remove(AGE);
+        Some(Capture {
+            variant: variant_key(&key, &vary, request.headers()),
+            key,
+            vary,
+            status,
+            headers,
+            stored: Instant::now().checked_sub(age).unwrap_or_else(Instant::now),
+            fresh_for,
+            stale,
+            body: BytesMut::new(),
+            limit: self.options.max_object_size as usize,
+            overflowed: false,
+        })
+    }
+
+    /// Stores a response read in full, making room by dropping the least recently used ones
+    pub fn store(&self, capture: Capture) {
+        if capture.overflowed {
+            return;
+        }
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let Capture {
+            key,
+            vary,
+            variant,
+            status,
+            mut headers,
+            stored,
+            fresh_for,
+            stale,
+            body,
+            ..
+        } = capture;
+        headers.remove(TRANSFER_ENCODING);
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+        let entry = Entry {
+            status,
+            headers,
+            body: body.freeze(),
+            stored,
+            fresh_for,
+            stale,
+            refreshing: None,
+            used: 0,
+        };
+        if entry.size() + variant.len() > self.options.max_size as usize {
+            return;
+        }
+
+        // Variants stored by other request headers cannot be told apart anymore
+        if state.vary.get(&key).map(|(stored, _)| stored) != Some(&vary) {
+            state.remove_url(&key);
+        }
+        state.remove(&variant);
+        state.vary.entry(key).or_insert((vary, 0)).1 += 1;
+        state.size += entry.size() + variant.len();
+        state.entries.insert(variant.clone(), entry);
+        state.touch(&variant);
+        while state.size > self.options.max_size as usize {
+            let Some((_, oldest)) = state.used.pop_first() else {
+                break;
+            };
+            state.remove(&oldest);
+        }
+    }
+
+    /// Drops the responses for a URL, or every URL it starts when it ends with "*", a scheme
+    /// before the host is ignored. Returns how many were dropped.
+    pub fn purge(&self, url: &str) -> usize {
+        let url = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))
+            .unwrap_or(url);
+        let (host, path) = url.split_at(url.find('/').unwrap_or(url.len()));
+        let url = format!("{}{}", host.to_ascii_lowercase(), path);
+        let Ok(mut state) = self.state.lock() else {
+            return 0;
+        };
+        let keys: Vec<String> = match url.strip_suffix('*') {
+            Some(prefix) => state
+                .vary
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect(),
+            None => vec![url],
+        };
+        let before = state.entries.len();
+        for key in keys {
+            state.remove_url(&key);
+        }
+        before - state.entries.len()
+    }
+
+    /// Handles a PURGE request for the URL it names
+    pub fn purge_request<B>(&self, request: &Request<B>) -> usize {
+        let host = request
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or_default();
+        let path = request
+            .uri()
+            .path_and_query()
+            .map_or("/", |path| path.as_str());
+        self.purge(&format!("{}{}", host, path))
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            *state = State::default();
+        }
+    }
+
+    /// Whether the client may send PURGE requests
+    pub fn purge_allowed(&self, ip: &IpAddr) -> bool {
+        contains_ip(&self.options.purge_allow, ip)
+    }
+
+    /// Stale times the response names, the configured ones unless it asks to be revalidated
+    fn stale(&self, directives: &[(String, String)]) -> Stale {
+        let revalidate = directives
+            .iter()
+            .any(|(name, _)| name == "must-revalidate" || name == "proxy-revalidate");
+        let window = |name: &str, configured: Option<Duration>| {
+            directives
+                .iter()
+                .find(|(directive, _)| directive == name)
+                .and_then(|(_, value)| value.parse().ok())
+                .map(Duration::from_secs)
+                .or(configured.filter(|_| !revalidate))
+                .unwrap_or_default()
+        };
+        Stale {
+            while_revalidate: window(
+                "stale-while-revalidate",
+                self.options.stale_while_revalidate,
+            ),
+            if_error: window("stale-if-error", self.options.stale_if_error),
+        }
+    }
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        self.body.len() + headers
+    }
+}
+
+impl State {
+    fn touch(&mut self, variant: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(variant) {
+            self.used.remove(&entry.used);
+            entry.used = clock;
+            self.used.insert(clock, variant.to_string());
+        }
+    }
+
+    fn remove(&mut self, variant: &str) {
+        let Some(entry) = self.entries.remove(variant) else {
+            return;
+        };
+        self.used.remove(&entry.used);
+        self.size -= entry.size() + variant.len();
+        let key = variant.split_once('\n').map_or(variant, |(key, _)| key);
+        if let Some((_, variants)) = self.vary.get_mut(key) {
+            *variants -= 1;
+            if *variants == 0 {
+                self.vary.remove(key);
+            }
+        }
+    }
+
+    /// Drops every variant stored for the URL
+    fn remove_url(&mut self, key: &str) {
+        let prefix = format!("{}\n", key);
+        let variants: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|variant| variant.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for variant in variants {
+            self.remove(&variant);
+        }
+        self.vary.remove(key);
+    }
+}
+
+/// Host and path with query, none for requests never answered from the cache
+fn cache_key<B>(request: &Request<B>) -> Option<String> {
+    if request.method() != Method::GET && request.method() != Method::HEAD {
+        return None;
+    }
+    if cache_control(request.headers())
+        .iter()
+        .any(|(name, _)| name == "no-store")
+    {
+        return None;
+    }
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or(request.uri().host())
+        .unwrap_or_default();
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    Some(format!("{}{}", host.to_ascii_lowercase(), path))
+}
+
+/// URL followed by the request's values of the headers the response varies by
+fn variant_key(key: &str, vary: &[HeaderName], headers: &HeaderMap) -> String {
+    let mut variant = format!("{}\n", key);
+    for name in vary {
+        let values: Vec<&str> = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        variant.push_str(&format!("{}: {}\n", name, values.join(", ")));
+    }
+    variant
+}
+
+/// Headers named by Vary, none when it is "*" and no request can reuse the response
+fn vary_headers(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut vary = Vec::new();
+    for value in headers.get_all(VARY) {
+        for name in value.to_str().ok()?.split(',') {
+            let name = name.trim();
+            if name == "*" {
+                return None;
+            }
+            if let Ok(name) = HeaderName::from_bytes(name.as_bytes()) {
+                if !vary.contains(&name) {
+                    vary.push(name);
+                }
+            }
+        }
+    }
+    vary.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    Some(vary)
+}
+
+/// Cache-Control directives, lowercase and unquoted
+pub fn cache_control(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter(|directive| !directive.trim().is_empty())
+        .map(|directive| {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            (
+                name.trim().to_ascii_lowercase(),
+                value.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect()
+}
+
+/// How long the response stays fresh going by s-maxage, max-age or Expires
+fn freshness(headers: &HeaderMap, directives: &[(String, String)]) -> Option<Duration> {
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .find(|(directive, _)| directive == name)
+            .and_then(|(_, value)| value.parse::<u64>().ok())
+    };
+    if let Some(max_age) = seconds("s-maxage").or_else(|| seconds("max-age")) {
+        return (max_age > 0).then(|| Duration::from_secs(max_age));
+    }
+    let date = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+    };
+    let expires = date(EXPIRES)?;
+    let now = date(DATE).unwrap_or_else(SystemTime::now);
+    expires
+        .duration_since(now)
+        .ok()
+        .filter(|fresh_for| !fresh_for.is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::{Cached, Lookup, ProxyCache};
+    use crate::config::CacheOptions;
+    use http::header::{ACCEPT_LANGUAGE, AGE, CACHE_CONTROL, CONTENT_LENGTH, EXPIRES, VARY};
+    use http::{HeaderMap, HeaderValue, Request, StatusCode};
+    use std::error::Error;
+    use std::time::{Duration, SystemTime};
+
+    fn headers(pairs: &[(&'static str, &str)]) -> Result<HeaderMap, Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value)?);
+        }
+        Ok(headers)
+    }
+
+    fn fresh(cache: &ProxyCache, request: &Request<()>) -> Option<Cached> {
+        match cache.lookup(request) {
+            Lookup::Fresh(cached) => Some(cached),
+            _ => None,
+        }
+    }
+
+    fn store(cache: &ProxyCache, request: &Request<()>, response: &HeaderMap, body: &[u8]) -> bool {
+        match cache.capture(request, StatusCode::OK, response) {
+            Some(mut capture) => {
+                capture.push(body);
+                cache.store(capture);
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[test]
+    fn test_cache_freshness() -> Result<(), Box<dyn Error>> {
+        let cache = ProxyCache::new(CacheOptions::default());
+        let page = Request::get("/page?a=1")
+            .header("host", "example.com")
+            .body(())?;
+
+        // Nothing says how long the response stays fresh
+        assert!(!store(&cache, &page, &headers(&[])?, b"x"));
+        for refused in [
+            "max-age=60, private",
+            "no-store",
+            "no-cache, max-age=60",
+            "max-age=0",
+        ] {
+            let response = headers(&[("cache-control", refused)])?;
+            assert!(!store(&cache, &page, &response, b"x"), "{}", refused);
+        }
+        let response = headers(&[("cache-control", "max-age=60"), ("set-cookie", "a=b")])?;
+        assert!(!store(&cache, &page, &response, b"x"));
+
+        let expires = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let response = headers(&[("expires", &expires), ("age", "10")])?;
+        assert!(store(&cache, &page, &response, b"hello"));
+        let cached = fresh(&cache, &page).ok_or("expected a hit")?;
+        assert_eq!(&cached.body[..], b"hello");
+        assert_eq!(cached.headers[AGE], "10");
+        assert_eq!(cached.headers[CONTENT_LENGTH], "5");
+        assert!(cached.headers.contains_key(EXPIRES));
+
+        // HEAD is answered from the GET response, other URLs and no-cache requests are not
+        let head = Request::head("/page?a=1")
+            .header("host", "example.com")
+            .body(())?;
+        assert!(fresh(&cache, &head).is_some());
+        let other = Request::get("/page?a=2")
+            .header("host", "example.com")
+            .body(())?;
+        assert!(fresh(&cache, &other).is_none());
+        let reload = Request::get("/page?a=1")
+            .header("host", "example.com")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(())?;
+        assert!(fresh(&cache, &reload).is_none());
+
+        // Authorized requests are stored only when the response is public
+        let private = Request::get("/me")
+            .header("host", "example.com")
+            .header("authorization", "Bearer x")
+            .body(())?;
+        let response = headers(&[("cache-control", "max-age=60")])?;
+        assert!(!store(&cache, &private, &response, b"x"));
+        let response = headers(&[("cache-control", "public, max-age=60")])?;
+        assert!(store(&cache, &private, &response, b"x"));
+
+        // `ttl` overrides what the backend says
+        let cache = ProxyCache::new(CacheOptions {
+            ttl: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+        assert!(store(&cache, &page, &headers(&[])?, b"x"));
+        assert!(fresh(&cache, &page).is_some());
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(fresh(&cache, &page).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_vary_and_size() -> Result<(), Box<dyn Error>> {
+        let cache = ProxyCache::new(CacheOptions {
+            max_size: 400,
+            max_object_size: 100,
+            ..Default::default()
+        });
+        let request = |language: &str| {
+            Request::get("/")
+                .header("host", "example.com")
+                .header(ACCEPT_LANGUAGE, language)
+                .body(())
+        };
+        let response = headers(&[("cache-control", "max-age=60"), ("vary", "Accept-Language")])?;
+        assert!(store(&cache, &request("en")?, &response, b"hello"));
+        assert!(store(&cache, &request("de")?, &response, b"hallo"));
+        let en = fresh(&cache, &request("en")?).ok_or("expected a hit")?;
+        assert_eq!(&en.body[..], b"hello");
+        assert_eq!(en.headers[VARY], "Accept-Language");
+        let de = fresh(&cache, &request("de")?).ok_or("expected a hit")?;
+        assert_eq!(&de.body[..], b"hallo");
+        assert!(fresh(&cache, &request("fr")?).is_none());
+
+        let response = headers(&[("cache-control", "max-age=60"), ("vary", "*")])?;
+        assert!(!store(&cache, &request("en")?, &response, b"x"));
+
+        // Too large a body is passed through without being stored
+        let response = headers(&[("cache-control", "max-age=60")])?;
+        let big = Request::get("/big")
+            .header("host", "example.com")
+            .body(())?;
+        store(&cache, &big, &response, &[0u8; 101]);
+        assert!(fresh(&cache, &big).is_none());
+
+        // The least recently used response makes room
+        let pages: Vec<Request<()>> = (0..4)
+            .map(|i| {
+                Request::get(format!("/{}", i))
+                    .header("host", "example.com")
+                    .body(())
+            })
+            .collect::<Result<_, _>>()?;
+        for page in &pages {
+            assert!(store(&cache, page, &response, &[1u8; 80]));
+            fresh(&cache, &request("en")?);
+        }
+        assert!(fresh(&cache, &request("en")?).is_some());
+        assert!(fresh(&cache, &pages[0]).is_none());
+        assert!(fresh(&cache, &pages[3]).is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_purge() -> Result<(), Box<dyn Error>> {
+        let cache = ProxyCache::new(CacheOptions {
+            purge_allow: vec!["10.0.0.0/8".parse()?],
+            ..Default::default()
+        });
+        let response = headers(&[("cache-control", "max-age=60"), ("vary", "Accept")])?;
+        let page = |host: &str, path: &str, accept: &str| {
+            Request::get(path)
+                .header("host", host)
+                .header("accept", accept)
+                .body(())
+        };
+        for (host, path) in [
+            ("example.com", "/static/app.js"),
+            ("example.com", "/static/app.css?v=2"),
+            ("example.com", "/index.html"),
+            ("example.org", "/static/app.js"),
+        ] {
+            assert!(store(&cache, &page(host, path, "*/*")?, &response, b"x"));
+        }
+        assert!(store(
+            &cache,
+            &page("example.com", "/index.html", "text/html")?,
+            &response,
+            b"x"
+        ));
+
+        // Every variant of the URL goes, the scheme and the case of the host do not matter
+        assert_eq!(cache.purge("https://Example.com/index.html"), 2);
+        assert!(fresh(&cache, &page("example.com", "/index.html", "*/*")?).is_none());
+        assert_eq!(cache.purge("example.com/index.html"), 0);
+
+        let purge = Request::builder()
+            .method("PURGE")
+            .uri("/static/*")
+            .header("host", "example.com")
+            .body(())?;
+        assert_eq!(cache.purge_request(&purge), 2);
+        assert!(fresh(&cache, &page("example.org", "/static/app.js", "*/*")?).is_some());
+        cache.clear();
+        assert!(fresh(&cache, &page("example.org", "/static/app.js", "*/*")?).is_none());
+
+        assert!(cache.purge_allowed(&"10.1.2.3".parse()?));
+        assert!(!cache.purge_allowed(&"192.0.2.1".parse()?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_stale() -> Result<(), Box<dyn Error>> {
+        let cache = ProxyCache::new(CacheOptions {
+            ttl: Some(Duration::from_millis(100)),
+            stale_while_revalidate: Some(Duration::from_millis(200)),
+            stale_if_error: Some(Duration::from_millis(600)),
+            ..Default::default()
+        });
+        let page = Request::get("/").header("host", "example.com").body(())?;
+        let reload = Request::get("/")
+            .header("host", "example.com")
+            .header(CACHE_CONTROL, "no-cache")
+            .body(())?;
+        assert!(store(&cache, &page, &headers(&[])?, b"x"));
+        assert!(matches!(cache.lookup(&page), Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup(&reload), Lookup::Miss(Some(_))));
+
+        // One request refreshes the stale response, the others are served it meanwhile
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(matches!(cache.lookup(&page), Lookup::Stale(_, true)));
+        assert!(matches!(cache.lookup(&page), Lookup::Stale(_, false)));
+        assert!(matches!(cache.lookup(&reload), Lookup::Miss(Some(_))));
+
+        // Then it only stands in for errors, until that runs out too
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(matches!(cache.lookup(&page), Lookup::Miss(Some(_))));
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(matches!(cache.lookup(&page), Lookup::Miss(None)));
+
+        // The configured times give way to must-revalidate, not to what the response names
+        let response = headers(&[("cache-control", "must-revalidate")])?;
+        assert!(store(&cache, &page, &response, b"x"));
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(matches!(cache.lookup(&page), Lookup::Miss(None)));
+        let response = headers(&[("cache-control", "must-revalidate, stale-if-error=60")])?;
+        assert!(store(&cache, &page, &response, b"x"));
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(matches!(cache.lookup(&page), Lookup::Miss(Some(_))));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,911 @@
+use crate::error::CbltError;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Line of a Caddyfile with the block it opens
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    tokens: Vec<String>,
+    children: Option<Vec<Entry>>,
+}
+
+/// Cbltfile converted from a Caddyfile, with notes about what could not be converted
+pub struct Adapted {
+    pub cbltfile: String,
+    pub warnings: Vec<String>,
+}
+
+/// Converts the root, file_server, reverse_proxy, forward_auth, redir, header, uri, tls and import
+/// directives of a Caddyfile, with path, method, header and query named matchers
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn adapt_caddyfile(source: &str) -> Result<Adapted, CbltError> {
+    let lines = tokenize(source)?;
+    let mut index = 0;
+    let mut entries = parse_block(&lines, &mut index, false)?;
+    let mut adapter = Adapter::default();
+
+    // Global options block
+    if entries
+        .first()
+        .is_some_and(|entry| entry.tokens.is_empty() && entry.children.is_some())
+    {
+        let entry = entries.remove(0);
+        adapter.warn(entry.line, "global options are not supported".to_string());
+    }
+
+    // A single site may omit the braces
+    if entries.first().is_some_and(|entry| {
+        entry.children.is_none() && entry.tokens.first().is_none_or(|t| t != "import")
+    }) {
+        let addresses = entries.remove(0);
+        let site = Entry {
+            line: addresses.line,
+            tokens: addresses.tokens,
+            children: Some(entries),
+        };
+        adapter.site(&site);
+    } else {
+        for entry in &entries {
+            adapter.top_level(entry);
+        }
+    }
+
+    Ok(Adapted {
+        cbltfile: adapter.output,
+        warnings: adapter.warnings,
+    })
+}
+
+#[derive(Default)]
+struct Adapter {
+    output: String,
+    warnings: Vec<String>,
+    matchers: Vec<String>, // named matchers converted in the current block
+}
+
+impl Adapter {
+    fn warn(&mut self, line: usize, message: String) {
+        // Sites with several addresses are converted once per address
+        let warning = format!("line {}: {}", line, message);
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
+    fn start_block(&mut self, header: String) {
+        if !self.output.is_empty() {
+            self.output.push('\n');
+        }
+        self.output.push_str(&format!("{} {{\n", header));
+        self.matchers.clear();
+    }
+
+    fn top_level(&mut self, entry: &Entry) {
+        let first = entry.tokens.first().map(String::as_str).unwrap_or("");
+        if first == "import" && entry.children.is_none() {
+            for file in &entry.tokens[1..] {
+                self.output.push_str(&format!("import {}\n", quote(file)));
+            }
+        } else if let Some(name) = first.strip_prefix('(').and_then(|n| n.strip_suffix(')')) {
+            self.start_block(format!("snippet {}", quote(name)));
+            self.directives(entry.children.as_deref().unwrap_or_default());
+            self.output.push_str("}\n");
+        } else if entry.children.is_some() {
+            self.site(entry);
+        } else {
+            self.warn(
+                entry.line,
+                format!("unexpected '{}' outside of a site block", first),
+            );
+        }
+    }
+
+    fn site(&mut self, entry: &Entry) {
+        let addresses: Vec<&str> = entry
+            .tokens
+            .iter()
+            .flat_map(|token| token.split(','))
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .collect();
+        for address in addresses {
+            let host = match self.host(address) {
+                Some(host) => host,
+                None => {
+                    self.warn(
+                        entry.line,
+                        format!("unsupported site address '{}'", address),
+                    );
+                    continue;
+                }
+            };
+            self.start_block(quote(&host));
+            self.directives(entry.children.as_deref().unwrap_or_default());
+            self.output.push_str("}\n");
+        }
+    }
+
+    /// Cbltfile host for a site address like "example.com", ":8080" or "http://example.com"
+    fn host(&mut self, address: &str) -> Option<String> {
+        let (scheme, rest) = match address.split_once("://") {
+            Some((scheme, rest)) => (Some(scheme), rest),
+            None => (None, address),
+        };
+        let rest = rest.split('/').next().unwrap_or(rest);
+        let host = if rest.is_empty() || rest.starts_with(':') {
+            format!("*{}", rest)
+        } else {
+            rest.to_string()
+        };
+        match scheme {
+            None => Some(host),
+            Some("http") if !host.contains(':') => Some(format!("{}:80", host)),
+            Some("http") | Some("https") => Some(host),
+            Some(_) => None,
+        }
+    }
+
+    fn directives(&mut self, entries: &[Entry]) {
+        for entry in entries {
+            let Some(name) = entry.tokens.first() else {
+                continue;
+            };
+            let args = &entry.tokens[1..];
+            let converted = match name.as_str() {
+                name if name.starts_with('@') => self.named_matcher(entry, name),
+                "root" => self.root(entry, args),
+                "file_server" => self.file_server(entry, args),
+                "reverse_proxy" => self.reverse_proxy(entry, args),
+                "forward_auth" => self.forward_auth(entry, args),
+                "php_fastcgi" => self.php_fastcgi(entry, args),
+                "redir" => self.redir(entry, args),
+                "header" => self.header(entry, args),
+                "uri" => self.uri(entry, args),
+                "tls" => self.tls(entry, args),
+                "import" => Some(format!("import {}", quote_all(args))),
+                _ => None,
+            };
+            match converted {
+                Some(line) => {
+                    for line in line.lines() {
+                        self.output.push_str(&format!("    {}\n", line));
+                    }
+                }
+                None => {
+                    self.warn(
+                        entry.line,
+                        format!("directive '{}' is not supported, skipped", name),
+                    );
+                    self.output
+                        .push_str(&format!("    // unsupported: {}\n", entry.tokens.join(" ")));
+                }
+            }
+        }
+    }
+
+    fn root(&mut self, entry: &Entry, args: &[String]) -> Option<String> {
+        let (pattern, path) = match args {
+            [path] => ("*", path),
+            [matcher, path] => (self.matcher(entry, matcher)?, path),
+            _ => return None,
+        };
+        Some(format!("root {} {}", quote(pattern), quote(path)))
+    }
+
+    fn file_server(&mut self, entry: &Entry, args: &[String]) -> Option<String> {
+        let mut options = Vec::new();
+        for arg in args {
+            match arg.as_str() {
+                "browse" => options.push("browse".to_string()),
+                "*" => {}
+                _ => return None,
+            }
+        }
+        for child in entry.children.as_deref().unwrap_or_default() {
+            match child.tokens.first().map(String::as_str) {
+                Some("browse") => options.push("browse".to_string()),
+                Some("precompressed") => {
+                    options.push(format!("precompressed {}", quote_all(&child.tokens[1..])))
+                }
+                Some(option) => {
+                    self.warn(
+                        child.line,
+                        format!("file_server option '{}' is not supported", option),
+                    );
+                }
+                None => {}
+            }
+        }
+        if options.is_empty() {
+            Some("file_server".to_string())
+        } else {
+            Some(format!(
+                "file_server {{\n    {}\n}}",
+                options.join("\n    ")
+            ))
+        }
+    }
+
+    fn reverse_proxy(&mut self, entry: &Entry, args: &[String]) -> Option<String> {
+        let (pattern, upstreams) = match args.first() {
+            Some(first) if is_matcher(first) => (self.matcher(entry, first)?, &args[1..]),
+            _ => ("*", args),
+        };
+        let mut upstreams: Vec<String> = upstreams.iter().map(|u| upstream(u)).collect();
+        let mut options = Vec::new();
+        for child in entry.children.as_deref().unwrap_or_default() {
+            match child.tokens.first().map(String::as_str) {
+                Some("to") => upstreams.extend(child.tokens[1..].iter().map(|u| upstream(u))),
+                Some("lb_policy") => match child.tokens.get(1).map(String::as_str) {
+                    Some(policy @ ("round_robin" | "ip_hash" | "least_conn" | "random")) => {
+                        options.push(format!("lb_policy {}", quote(policy)))
+                    }
+                    _ => self.warn(child.line, "lb_policy is not supported".to_string()),
+                },
+                Some(option @ ("health_uri" | "health_interval" | "health_timeout")) => {
+                    match child.tokens.get(1) {
+                        Some(value) => options.push(format!("{} {}", option, quote(value))),
+                        None => self.warn(child.line, format!("{} needs a value", option)),
+                    }
+                }
+                Some("health_status") => match child.tokens.get(1) {
+                    Some(status) if status.parse::<u16>().is_ok() => {
+                        options.push(format!("health_status {}", quote(status)))
+                    }
+                    _ => self.warn(
+                        child.line,
+                        "health_status is only supported as a status code".to_string(),
+                    ),
+                },
+                Some(direction @ ("header_up" | "header_down")) => {
+                    if let Some(field) = self.header_field(child, &child.tokens[1..]) {
+                        options.push(format!("{} {}", direction, field));
+                    }
+                }
+                Some(option) => {
+                    self.warn(
+                        child.line,
+                        format!("reverse_proxy option '{}' is not supported", option),
+                    );
+                }
+                None => {}
+            }
+        }
+        if upstreams.is_empty() {
+            return None;
+        }
+        let mut line = format!("reverse_proxy {} {}", quote(pattern), quote_all(&upstreams));
+        if !options.is_empty() {
+            line.push_str(&format!(" {{\n    {}\n}}", options.join("\n    ")));
+        }
+        Some(line)
+    }
+
+    /// Only the first upstream is asked, there is no load balancing of auth services
+    fn forward_auth(&mut self, entry: &Entry, args: &[String]) -> Option<String> {
+        let (pattern, upstreams) = match args.first() {
+            Some(first) if is_matcher(first) => (self.matcher(entry, first)?, &args[1..]),
+            _ => ("*", args),
+        };
+        let mut upstreams: Vec<String> = upstreams.iter().map(|u| upstream(u)).collect();
+        let mut options = Vec::new();
+        let mut copy_headers = Vec::new();
+        for child in entry.children.as_deref().unwrap_or_default() {
+            match child.tokens.first().map(String::as_str) {
+                Some("to") => upstreams.extend(child.tokens[1..].iter().map(|u| upstream(u))),
+                Some("uri") => match child.tokens.get(1) {
+                    Some(uri) => options.push(format!("uri {}", quote(uri))),
+                    None => self.warn(child.line, "uri needs a value".to_string()),
+                },
+                Some("copy_headers") => {
+                    copy_headers.extend_from_slice(&child.tokens[1..]);
+                    for field in child.children.as_deref().unwrap_or_default() {
+                        copy_headers.extend_from_slice(&field.tokens);
+                    }
+                }
+                Some(option) => {
+                    self.warn(
+                        child.line,
+                        format!("forward_auth option '{}' is not supported", option),
+                    );
+                }
+                None => {}
+            }
+        }
+        let (first, rest) = upstreams.split_first()?;
+        if !rest.is_empty() {
+            self.warn(
+                entry.line,
+                format!(
+                    "forward_auth asks {} only, other upstreams are ignored",
+                    first
+                ),
+            );
+        }
+        if !copy_headers.is_empty() {
+            options.push(format!("copy_headers {}", quote_all(&copy_headers)));
+        }
+        let mut line = format!("forward_auth {} {}", quote(pattern), quote(first));
+        if !options.is_empty() {
+            line.push_str(&format!(" {{\n    {}\n}}", options.join("\n    ")));
+        }
+        Some(line)
+    }
+
+    /// Only the first upstream is asked, there is no load balancing of FastCGI servers
+    fn php_fastcgi(&mut self, entry: &Entry, args: &[String]) -> Option<String> {
+        let (pattern, upstreams) = match args.first() {
+            Some(first) if is_matcher(first) => (self.matcher(entry, first)?, &args[1..]),
+            _ => ("*", args),
+        };
+        let mut upstreams: Vec<String> = upstreams.to_vec();
+        let mut options = Vec::new();
+        for child in entry.children.as_deref().unwrap_or_default() {
+            match child.tokens.first().map(String::as_str) {
+                Some("to") => upstreams.extend_from_slice(&child.tokens[1..]),
+                Some(option @ ("root" | "index" | "dial_timeout" | "read_timeout")) => {
+                    match child.tokens.get(1) {
+                        Some(value) => options.push(format!("{} {}", option, quote(value))),
+                        None => self.warn(child.line, format!("{} needs a value", option)),
+                    }
+                }
+                Some("split") => options.push(format!("split {}", quote_all(&child.tokens[1..]))),
+                Some("env") => match &child.tokens[1..] {
+                    [name, value] => options.push(format!("env {} {}", quote(name), quote(value))),
+                    _ => self.warn(child.line, "env needs a name and a value".to_string()),
+                },
+                Some(option) => {
+                    self.warn(
+                        child.line,
+                        format!("php_fastcgi option '{}' is not supported", option),
+                    );
+                }
+                None => {}
+            }
+        }
+        let upstreams: Vec<String> = upstreams
+            .iter()
+            .map(|address| match address.strip_prefix(':') {
+                Some(port) => format!("localhost:{}", port),
+                None => address.to_string(),
+            })
+            .collect();
+        let (first, rest) = upstreams.split_first()?;
+        if !rest.is_empty() {
+            self.warn(
+                entry.line,
+                format!(
+                    "php_fastcgi asks {} only, other upstreams are ignored",
+                    first
+                ),
+            );
+        }
+        let mut line = format!("php_fastcgi {} {}", quote(pattern), quote(first));
+        if !options.is_empty() {
+            line.push_str(&format!(" {{\n    {}\n}}", options.join("\n    ")));
+        }
+        Some(line)
+    }
+
+    fn redir(&mut self, entry: &Entry, args: &[String]) -> Option<String> {
+        match args {
+            [first, destination, ..] if is_matcher(first) => {
+                let pattern = self.matcher(entry, first)?;
+                Some(format!("redir {} {}", quote(pattern), quote(destination)))
+            }
+            [destination, ..] => Some(format!("redir {}", quote(destination))),
+            [] => None,
+        }
+    }
+
+    fn header(&mut self, entry: &Entry, args: &[String]) -> Option<String> {
+        let (pattern, field) = match args {
+            [first, rest @ ..] if is_matcher(first) => (self.matcher(entry, first)?, rest),
+            _ => ("*", args),
+        };
+        let children = entry.children.as_deref().unwrap_or_default();
+        if children.is_empty() {
+            let field = self.header_field(entry, field)?;
+            return Some(format!("header {} {}", quote(pattern), field));
+        }
+        let fields: Vec<String> = children
+            .iter()
+            .filter_map(|child| self.header_field(child, &child.tokens))
+            .collect();
+        if fields.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "header {} {{\n    {}\n}}",
+            quote(pattern),
+            fields.join("\n    ")
+        ))
+    }
+
+    fn uri(&mut self, entry: &Entry, args: &[String]) -> Option<String> {
+        let (pattern, operation) = match args {
+            [first, rest @ ..] if is_matcher(first) => (self.matcher(entry, first)?, rest),
+            _ => ("*", args),
+        };
+        match operation {
+            [name, prefix] if name == "strip_prefix" || name == "add_prefix" => Some(format!(
+                "uri {} {} {}",
+                quote(pattern),
+                quote(name),
+                quote(prefix)
+            )),
+            // Caddy's optional limit has no equivalent, every occurrence is replaced
+            [name, find, replace] if name == "replace" => Some(format!(
+                "uri {} \"replace\" {} {}",
+                quote(pattern),
+                quote(find),
+                quote(replace)
+            )),
+            _ => None,
+        }
+    }
+
+    /// `[+|-]field [value|find] [replace]`, the deferred and default forms have no equivalent
+    fn header_field(&mut self, entry: &Entry, field: &[String]) -> Option<String> {
+        match field {
+            [name, ..] if name.starts_with(['?', '>']) => {
+                self.warn(
+                    entry.line,
+                    format!("header field '{}' is not supported", name),
+                );
+                None
+            }
+            [name] if name.starts_with('-') => Some(quote(name)),
+            [_, _] | [_, _, _] => Some(quote_all(field)),
+            _ => {
+                self.warn(
+                    entry.line,
+                    format!("header field '{}' is not supported", field.join(" ")),
+                );
+                None
+            }
+        }
+    }
+
+    fn tls(&mut self, entry: &Entry, args: &[String]) -> Option<String> {
+        match args {
+            [cert, key] => Some(format!("tls {} {}", quote(cert), quote(key))),
+            _ => {
+                self.warn(
+                    entry.line,
+                    "only 'tls <cert> <key>' is supported, certificates must be provided"
+                        .to_string(),
+                );
+                None
+            }
+        }
+    }
+
+    /// `@name <condition>` or a block of conditions, each a path, method, header or query, or a
+    /// `not` of them
+    fn named_matcher(&mut self, entry: &Entry, name: &str) -> Option<String> {
+        let lines = match entry.children.as_deref() {
+            Some(children) => self.match_conditions(name, children)?,
+            None => self.match_condition(name, entry.line, &entry.tokens[1..], None)?,
+        };
+        if lines.is_empty() {
+            return None;
+        }
+        self.matchers.push(name.to_string());
+        Some(format!("{} {{\n    {}\n}}", name, lines.join("\n    ")))
+    }
+
+    fn match_conditions(&mut self, name: &str, entries: &[Entry]) -> Option<Vec<String>> {
+        let mut lines = Vec::new();
+        for entry in entries {
+            lines.extend(self.match_condition(
+                name,
+                entry.line,
+                &entry.tokens,
+                entry.children.as_deref(),
+            )?);
+        }
+        Some(lines)
+    }
+
+    fn match_condition(
+        &mut self,
+        name: &str,
+        line: usize,
+        condition: &[String],
+        children: Option<&[Entry]>,
+    ) -> Option<Vec<String>> {
+        let lines = match condition {
+            [kind] if kind == "not" && children.is_some() => block(
+                "not",
+                self.match_conditions(name, children.unwrap_or_default())?,
+            ),
+            [kind, rest @ ..] if kind == "not" && !rest.is_empty() => {
+                block("not", self.match_condition(name, line, rest, children)?)
+            }
+            [kind, values @ ..]
+                if matches!(kind.as_str(), "path" | "method") && !values.is_empty() =>
+            {
+                vec![format!("{} {}", kind, quote_all(values))]
+            }
+            [kind, field, values @ ..] if kind == "header" && !field.starts_with('!') => {
+                let mut line = format!("header {}", quote(field));
+                if !values.is_empty() {
+                    line.push_str(&format!(" {}", quote_all(values)));
+                }
+                vec![line]
+            }
+            [kind, pairs @ ..] if kind == "query" && !pairs.is_empty() => {
+                // Values of one key are alternatives, different keys all have to match
+                let mut keys: Vec<(&str, Vec<&str>)> = Vec::new();
+                for pair in pairs {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, "*"));
+                    match keys.iter_mut().find(|(k, _)| *k == key) {
+                        Some((_, values)) => values.push(value),
+                        None => keys.push((key, vec![value])),
+                    }
+                }
+                keys.into_iter()
+                    .map(|(key, values)| {
+                        let mut line = format!("query {}", quote(key));
+                        for value in values.into_iter().filter(|value| *value != "*") {
+                            line.push_str(&format!(" {}", quote(value)));
+                        }
+                        line
+                    })
+                    .collect()
+            }
+            _ => {
+                self.warn(
+                    line,
+                    format!(
+                        "matcher {} condition '{}' is not supported",
+                        name,
+                        condition.join(" ")
+                    ),
+                );
+                return None;
+            }
+        };
+        Some(lines)
+    }
+
+    /// Path matchers and the named matchers converted so far have a Cbltfile equivalent
+    fn matcher<'a>(&mut self, entry: &Entry, matcher: &'a str) -> Option<&'a str> {
+        if matcher == "*" || matcher.starts_with('/') || self.matchers.iter().any(|m| m == matcher)
+        {
+            Some(matcher)
+        } else {
+            self.warn(
+                entry.line,
+                format!("matcher '{}' is not supported", matcher),
+            );
+            None
+        }
+    }
+}
+
+/// Lines of a nested block, indented under its name
+fn block(name: &str, lines: Vec<String>) -> Vec<String> {
+    let mut block = vec![format!("{} {{", name)];
+    block.extend(lines.iter().map(|line| format!("    {}", line)));
+    block.push("}".to_string());
+    block
+}
+
+fn is_matcher(token: &str) -> bool {
+    token == "*" || token.starts_with('/') || token.starts_with('@')
+}
+
+/// Caddy allows upstreams without a scheme, e.g. "localhost:8080" or ":8080"
+fn upstream(address: &str) -> String {
+    if address.contains("://") {
+        address.to_string()
+    } else if address.starts_with(':') {
+        format!("http://localhost{}", address)
+    } else {
+        format!("http://{}", address)
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn quote_all(values: &[String]) -> String {
+    values
+        .iter()
+        .map(|value| quote(value))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits the Caddyfile into lines of tokens, honoring quotes and comments
+fn tokenize(source: &str) -> Result<Vec<(usize, Vec<String>)>, CbltError> {
+    let mut lines = Vec::new();
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    let mut line = 1;
+    let mut token_line = 1;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                if !tokens.is_empty() {
+                    lines.push((token_line, std::mem::take(&mut tokens)));
+                }
+                line += 1;
+            }
+            '#' => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            c if c.is_whitespace() => {}
+            '"' | '`' => {
+                let mut token = String::new();
+                let start = line;
+                loop {
+                    match chars.next() {
+                        Some('\\') if c == '"' => {
+                            if let Some(escaped) = chars.next() {
+                                token.push(escaped);
+                            }
+                        }
+                        Some(end) if end == c => break,
+                        Some(other) => {
+                            if other == '\n' {
+                                line += 1;
+                            }
+                            token.push(other);
+                        }
+                        None => {
+                            return Err(CbltError::KdlParseError {
+                                details: format!("Unterminated quote on line {}", start),
+                            });
+                        }
+                    }
+                }
+                if tokens.is_empty() {
+                    token_line = start;
+                }
+                tokens.push(token);
+            }
+            c => {
+                let mut token = String::from(c);
+                while chars.peek().is_some_and(|c| !c.is_whitespace()) {
+                    token.push(chars.next().unwrap_or_default());
+                }
+                if tokens.is_empty() {
+                    token_line = line;
+                }
+                tokens.push(token);
+            }
+        }
+    }
+    if !tokens.is_empty() {
+        lines.push((token_line, tokens));
+    }
+    Ok(lines)
+}
+
+fn parse_block(
+    lines: &[(usize, Vec<String>)],
+    index: &mut usize,
+    nested: bool,
+) -> Result<Vec<Entry>, CbltError> {
+    let mut entries = Vec::new();
+    while let Some((line, tokens)) = lines.get(*index) {
+        *index += 1;
+        if tokens.len() == 1 && tokens[0] == "}" {
+            if nested {
+                return Ok(entries);
+            }
+            return Err(CbltError::KdlParseError {
+                details: format!("Unexpected '}}' on line {}", line),
+            });
+        }
+        let mut tokens = tokens.clone();
+        let children = if tokens.last().is_some_and(|t| t == "{") {
+            tokens.pop();
+            Some(parse_block(lines, index, true)?)
+        } else {
+            None
+        };
+        entries.push(Entry {
+            line: *line,
+            tokens,
+            children,
+        });
+    }
+    if nested {
+        return Err(CbltError::KdlParseError {
+            details: "Missing '}' at the end of the Caddyfile".to_string(),
+        });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::caddyfile::adapt_caddyfile;
+    use crate::config::build_config;
+    use kdl::KdlDocument;
+    use std::error::Error;
+
+    #[test]
+    fn test_adapt_caddyfile() -> Result<(), Box<dyn Error>> {
+        let caddyfile = r#"
+{
+    email admin@example.com
+}
+
+(common) {
+    header X-Frame-Options DENY
+    log
+}
+
+example.com, http://www.example.com {
+    import common
+    root * /var/www # site files
+    file_server browse
+    reverse_proxy /api/* localhost:8080 :8081 {
+        lb_policy ip_hash
+        health_uri /health
+    }
+    tls /etc/cert.pem /etc/key.pem
+}
+
+:8080 {
+    redir https://example.com{uri}
+}
+"#;
+        let adapted = adapt_caddyfile(caddyfile)?;
+        assert_eq!(
+            adapted.cbltfile,
+            r#"snippet "common" {
+    header "*" "X-Frame-Options" "DENY"
+    // unsupported: log
+}
+
+"example.com" {
+    import "common"
+    root "*" "/var/www"
+    file_server {
+        browse
+    }
+    reverse_proxy "/api/*" "http://localhost:8080" "http://localhost:8081" {
+        lb_policy "ip_hash"
+        health_uri "/health"
+    }
+    tls "/etc/cert.pem" "/etc/key.pem"
+}
+
+"www.example.com:80" {
+    import "common"
+    root "*" "/var/www"
+    file_server {
+        browse
+    }
+    reverse_proxy "/api/*" "http://localhost:8080" "http://localhost:8081" {
+        lb_policy "ip_hash"
+        health_uri "/health"
+    }
+    tls "/etc/cert.pem" "/etc/key.pem"
+}
+
+"*:8080" {
+    redir "https://example.com{uri}"
+}
+"#
+        );
+        assert_eq!(
+            adapted.warnings,
+            vec![
+                "line 2: global options are not supported",
+                "line 8: directive 'log' is not supported, skipped",
+            ]
+        );
+
+        let doc: KdlDocument = adapted.cbltfile.parse()?;
+        build_config(&doc)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_adapt_single_site() -> Result<(), Box<dyn Error>> {
+        let adapted = adapt_caddyfile("localhost:3000\nreverse_proxy 127.0.0.1:9000\n")?;
+        assert_eq!(
+            adapted.cbltfile,
+            "\"localhost:3000\" {\n    reverse_proxy \"*\" \"http://127.0.0.1:9000\"\n}\n"
+        );
+        assert!(adapted.warnings.is_empty());
+
+        let adapted = adapt_caddyfile(
+            "localhost:3000\nreverse_proxy 127.0.0.1:9000 {\n    header_up Host {upstream_hostport}\n}\n",
+        )?;
+        assert!(adapted
+            .cbltfile
+            .contains("header_up \"Host\" \"{upstream_hostport}\""));
+        assert!(adapted.warnings.is_empty());
+
+        let adapted = adapt_caddyfile(
+            "localhost:3000\nheader /api/* {\n    -Server\n    +Link \"</a.css>\"\n}\nreverse_proxy 127.0.0.1:9000 {\n    header_down -X-Powered-By\n}\n",
+        )?;
+        assert!(adapted.cbltfile.contains(
+            "header \"/api/*\" {\n        \"-Server\"\n        \"+Link\" \"</a.css>\"\n    }"
+        ));
+        assert!(adapted.cbltfile.contains("header_down \"-X-Powered-By\""));
+        assert!(adapted.warnings.is_empty());
+        let doc: KdlDocument = adapted.cbltfile.parse()?;
+        build_config(&doc)?;
+
+        let adapted = adapt_caddyfile(
+            "localhost:3000\n@api {\n    path /api/* /graphql\n    method POST\n    header Content-Type application/json*\n    query debug=* v=1 v=2\n    not {\n        path /api/internal/*\n    }\n    not header X-Internal\n}\n@local remote_ip 127.0.0.1\nreverse_proxy @api 127.0.0.1:9000\nredir @local /\n",
+        )?;
+        assert!(adapted.cbltfile.contains(
+            "@api {\n        path \"/api/*\" \"/graphql\"\n        method \"POST\"\n        header \"Content-Type\" \"application/json*\"\n        query \"debug\"\n        query \"v\" \"1\" \"2\"\n        not {\n            path \"/api/internal/*\"\n        }\n        not {\n            header \"X-Internal\"\n        }\n    }"
+        ));
+        assert!(adapted
+            .cbltfile
+            .contains("reverse_proxy \"@api\" \"http://127.0.0.1:9000\""));
+        assert_eq!(
+            adapted.warnings,
+            vec![
+                "line 12: matcher @local condition 'remote_ip 127.0.0.1' is not supported",
+                "line 12: directive '@local' is not supported, skipped",
+                "line 14: matcher '@local' is not supported",
+                "line 14: directive 'redir' is not supported, skipped",
+            ]
+        );
+        let doc: KdlDocument = adapted.cbltfile.parse()?;
+        build_config(&doc)?;
+
+        let adapted = adapt_caddyfile(
+            "localhost:3000\nuri /api/* strip_prefix /api\nuri replace /old/ /new/\nredir /docs/* https://docs.example.com{uri}\nreverse_proxy 127.0.0.1:9000\n",
+        )?;
+        assert!(adapted
+            .cbltfile
+            .contains("uri \"/api/*\" \"strip_prefix\" \"/api\""));
+        assert!(adapted
+            .cbltfile
+            .contains("uri \"*\" \"replace\" \"/old/\" \"/new/\""));
+        assert!(adapted
+            .cbltfile
+            .contains("redir \"/docs/*\" \"https://docs.example.com{uri}\""));
+        assert!(adapted.warnings.is_empty());
+        let doc: KdlDocument = adapted.cbltfile.parse()?;
+        build_config(&doc)?;
+
+        let adapted = adapt_caddyfile(
+            "localhost:3000\nforward_auth authelia:9091 authelia2:9091 {\n    uri /api/verify?rd=https://auth.example.com\n    copy_headers Remote-User Remote-Groups>X-Groups {\n        Remote-Email\n    }\n    header_up X-Real-Ip {remote_host}\n}\nreverse_proxy 127.0.0.1:9000\n",
+        )?;
+        assert!(adapted.cbltfile.contains(
+            "forward_auth \"*\" \"http://authelia:9091\" {\n        uri \"/api/verify?rd=https://auth.example.com\"\n        copy_headers \"Remote-User\" \"Remote-Groups>X-Groups\" \"Remote-Email\"\n    }"
+        ));
+        assert_eq!(
+            adapted.warnings,
+            vec![
+                "line 7: forward_auth option 'header_up' is not supported",
+                "line 2: forward_auth asks http://authelia:9091 only, other upstreams are ignored",
+            ]
+        );
+        let doc: KdlDocument = adapted.cbltfile.parse()?;
+        build_config(&doc)?;
+
+        let adapted = adapt_caddyfile(
+            "localhost:3000\nroot * /var/www/wordpress\nphp_fastcgi unix//run/php/php-fpm.sock {\n    env APP_ENV production\n    capture_stderr\n}\nfile_server\n",
+        )?;
+        assert!(adapted.cbltfile.contains(
+            "php_fastcgi \"*\" \"unix//run/php/php-fpm.sock\" {\n        env \"APP_ENV\" \"production\"\n    }"
+        ));
+        assert_eq!(
+            adapted.warnings,
+            vec!["line 5: php_fastcgi option 'capture_stderr' is not supported"]
+        );
+        let doc: KdlDocument = adapted.cbltfile.parse()?;
+        build_config(&doc)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,419 @@
+use crate::error::CbltError;
+use crate::reverse_proxy::parse_response_head;
+use crate::split_port;
+use bytes::{Buf, BytesMut};
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, HOST, LOCATION};
+use http::{HeaderMap, HeaderName, HeaderValue, Request, StatusCode, Version};
+use percent_encoding::percent_decode_str;
+use std::net::SocketAddr;
+use tokio::io::AsyncReadExt;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Largest response head read before giving up on the backend
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Protocol other than HTTP spoken by a `reverse_proxy` destination, named by its scheme
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Uwsgi,
+    Scgi,
+}
+
+/// Protocol and address of a destination like `uwsgi://127.0.0.1:3031` or
+/// `scgi://unix//run/app.sock`, none for HTTP ones
+pub fn backend_protocol(url: &str) -> Option<(Protocol, &str)> {
+    if let Some(address) = url.strip_prefix("uwsgi://") {
+        Some((Protocol::Uwsgi, address))
+    } else {
+        url.strip_prefix("scgi://")
+            .map(|address| (Protocol::Scgi, address))
+    }
+}
+
+/// CGI/1.1 variables of the request with `headers` as `HTTP_*` ones, the script ones are left
+/// to the caller
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn variables(
+    request: &Request<BytesMut>,
+    headers: &HeaderMap,
+    addr: SocketAddr,
+    scheme: &str,
+) -> Vec<(String, String)> {
+    let host = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("");
+    let (server_name, port) = split_port(host);
+    let default_port = if scheme == "https" { "443" } else { "80" };
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+
+    let mut variables = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        (
+            "SERVER_SOFTWARE",
+            format!("cblt/{}", env!("CARGO_PKG_VERSION")),
+        ),
+        ("SERVER_PROTOCOL", format!("{:?}", request.version())),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", port.unwrap_or(default_port).to_string()),
+        ("REQUEST_SCHEME", scheme.to_string()),
+        ("REQUEST_METHOD", request.method().to_string()),
+        (
+            "REQUEST_URI",
+            request
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_string(),
+        ),
+        (
+            "QUERY_STRING",
+            request.uri().query().unwrap_or("").to_string(),
+        ),
+        ("REMOTE_ADDR", addr.ip().to_string()),
+        ("REMOTE_PORT", addr.port().to_string()),
+        ("CONTENT_TYPE", content_type.to_string()),
+        ("CONTENT_LENGTH", request.body().len().to_string()),
+    ];
+    if scheme == "https" {
+        variables.push(("HTTPS", "on".to_string()));
+    }
+
+    let mut variables: Vec<(String, String)> = variables
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    for name in headers.keys() {
+        // "Proxy" would turn into HTTP_PROXY, which scripts mistake for their proxy setting
+        if *name == CONTENT_TYPE || *name == CONTENT_LENGTH || name.as_str() == "proxy" {
+            continue;
+        }
+        let separator = if name.as_str() == "cookie" {
+            "; "
+        } else {
+            ", "
+        };
+        let value = headers
+            .get_all(name)
+            .iter()
+            .map(|value| String::from_utf8_lossy(value.as_bytes()))
+            .collect::<Vec<_>>()
+            .join(separator);
+        let name = format!(
+            "HTTP_{}",
+            name.as_str().to_ascii_uppercase().replace('-', "_")
+        );
+        variables.push((name, value));
+    }
+    variables
+}
+
+/// Request for an application mounted at the root: the whole path is its `PATH_INFO`
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn encode_request(
+    protocol: Protocol,
+    request: &Request<BytesMut>,
+    headers: &HeaderMap,
+    addr: SocketAddr,
+    scheme: &str,
+) -> Result<Vec<u8>, CbltError> {
+    let mut variables = variables(request, headers, addr, scheme);
+    let path_info = percent_decode_str(request.uri().path())
+        .decode_utf8_lossy()
+        .into_owned();
+    variables.push(("SCRIPT_NAME".to_string(), String::new()));
+    variables.push(("PATH_INFO".to_string(), path_info));
+    match protocol {
+        Protocol::Uwsgi => encode_uwsgi(&variables, request.body()),
+        Protocol::Scgi => Ok(encode_scgi(&variables, request.body())),
+    }
+}
+
+/// uwsgi packet of modifier 0 (WSGI) followed by the body
+fn encode_uwsgi(variables: &[(String, String)], body: &[u8]) -> Result<Vec<u8>, CbltError> {
+    let too_large = || CbltError::ResponseError {
+        details: "Request headers too large for uwsgi".to_string(),
+        status_code: StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+    };
+    let mut vars = Vec::new();
+    for (name, value) in variables {
+        for string in [name, value] {
+            let len = u16::try_from(string.len()).map_err(|_| too_large())?;
+            vars.extend_from_slice(&len.to_le_bytes());
+            vars.extend_from_slice(string.as_bytes());
+        }
+    }
+    let size = u16::try_from(vars.len()).map_err(|_| too_large())?;
+
+    let mut request = Vec::with_capacity(4 + vars.len() + body.len());
+    request.push(0);
+    request.extend_from_slice(&size.to_le_bytes());
+    request.push(0);
+    request.extend_from_slice(&vars);
+    request.extend_from_slice(body);
+    Ok(request)
+}
+
+/// Netstring of the variables, `CONTENT_LENGTH` first and `SCGI` among them, then the body
+fn encode_scgi(variables: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    let mut headers = Vec::new();
+    let content_length = body.len().to_string();
+    let first = [("CONTENT_LENGTH", content_length.as_str()), ("SCGI", "1")];
+    let rest = variables
+        .iter()
+        .filter(|(name, _)| name != "CONTENT_LENGTH" && name != "SCGI")
+        .map(|(name, value)| (name.as_str(), value.as_str()));
+    for (name, value) in first.into_iter().chain(rest) {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    }
+
+    let mut request = Vec::with_capacity(headers.len() + body.len() + 8);
+    request.extend_from_slice(headers.len().to_string().as_bytes());
+    request.push(b':');
+    request.extend_from_slice(&headers);
+    request.push(b',');
+    request.extend_from_slice(body);
+    request
+}
+
+/// Reads the head of the response, either an HTTP one or a CGI header block where `Status`
+/// gives the status and a `Location` alone redirects. The head is taken off `buf`.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn response_head<R>(
+    stream: &mut R,
+    buf: &mut BytesMut,
+) -> Result<(StatusCode, HeaderMap, Version), CbltError>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let bad_gateway = |details: String| CbltError::ResponseError {
+        details,
+        status_code: StatusCode::BAD_GATEWAY,
+    };
+    loop {
+        let complete = if buf.len() < 5 && b"HTTP/".starts_with(buf) {
+            None
+        } else if buf.starts_with(b"HTTP/") {
+            let mut parsed = [httparse::EMPTY_HEADER; 64];
+            match httparse::Response::new(&mut parsed).parse(buf) {
+                Ok(httparse::Status::Complete(head_len)) => {
+                    let head = parse_response_head(&buf[..head_len])?;
+                    Some((head_len, head))
+                }
+                Ok(httparse::Status::Partial) => None,
+                Err(err) => return Err(bad_gateway(format!("Invalid response: {}", err))),
+            }
+        } else {
+            let mut parsed = [httparse::EMPTY_HEADER; 64];
+            match httparse::parse_headers(buf, &mut parsed) {
+                Ok(httparse::Status::Complete((head_len, parsed))) => {
+                    Some((head_len, cgi_head(parsed)))
+                }
+                Ok(httparse::Status::Partial) => None,
+                Err(err) => return Err(bad_gateway(format!("Invalid response: {}", err))),
+            }
+        };
+        if let Some((head_len, head)) = complete {
+            buf.advance(head_len);
+            return Ok(head);
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(bad_gateway("Response head too large".to_string()));
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(bad_gateway("Response ended before its head".to_string()));
+        }
+    }
+}
+
+fn cgi_head(parsed: &[httparse::Header]) -> (StatusCode, HeaderMap, Version) {
+    let mut headers = HeaderMap::new();
+    let mut status = None;
+    for header in parsed {
+        if header.name.eq_ignore_ascii_case("status") {
+            status = String::from_utf8_lossy(header.value)
+                .split_whitespace()
+                .next()
+                .and_then(|code| code.parse::<u16>().ok())
+                .and_then(|code| StatusCode::from_u16(code).ok());
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(header.name.as_bytes()),
+            HeaderValue::from_bytes(header.value),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    let status = status.unwrap_or(if headers.contains_key(LOCATION) {
+        StatusCode::FOUND
+    } else {
+        StatusCode::OK
+    });
+    (status, headers, Version::HTTP_10)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cgi::{backend_protocol, encode_request, response_head, Protocol};
+    use crate::reverse_proxy::{backend_authority, is_valid_destination};
+    use bytes::BytesMut;
+    use http::{HeaderMap, Request};
+    use std::error::Error;
+    use std::net::SocketAddr;
+
+    fn request() -> Result<Request<BytesMut>, Box<dyn Error>> {
+        Ok(Request::post("/app/caf%C3%A9?q=1")
+            .header("Host", "example.com")
+            .header("Content-Type", "text/plain")
+            .header("Proxy", "evil")
+            .body(BytesMut::from(&b"hello"[..]))?)
+    }
+
+    fn variables(encoded: &[u8], separator: u8) -> Vec<&[u8]> {
+        encoded.split(|byte| *byte == separator).collect()
+    }
+
+    #[test]
+    fn test_backend_protocol() -> Result<(), Box<dyn Error>> {
+        assert_eq!(
+            backend_protocol("uwsgi://127.0.0.1:3031"),
+            Some((Protocol::Uwsgi, "127.0.0.1:3031"))
+        );
+        assert_eq!(
+            backend_protocol("scgi://unix//run/app.sock"),
+            Some((Protocol::Scgi, "unix//run/app.sock"))
+        );
+        assert_eq!(backend_protocol("http://127.0.0.1:8080"), None);
+        assert_eq!(
+            backend_authority("uwsgi://localhost:3031")?,
+            "localhost:3031"
+        );
+        assert_eq!(
+            backend_authority("scgi://unix//run/app.sock")?,
+            "unix//run/app.sock"
+        );
+        assert!(is_valid_destination("scgi://[::1]:4000"));
+        assert!(!is_valid_destination("uwsgi://localhost"));
+        assert!(!is_valid_destination("uwsgi://unix/"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_scgi() -> Result<(), Box<dyn Error>> {
+        let request = request()?;
+        let addr: SocketAddr = "10.0.0.1:5000".parse()?;
+        let encoded = encode_request(Protocol::Scgi, &request, request.headers(), addr, "https")?;
+        let (len, rest) = encoded.split_at(encoded.iter().position(|b| *b == b':').unwrap_or(0));
+        let len: usize = std::str::from_utf8(len)?.parse()?;
+        assert_eq!(rest[len + 1], b',');
+        assert_eq!(&rest[len + 2..], b"hello");
+        let headers = variables(&rest[1..len + 1], 0);
+        assert_eq!(headers[..4], [&b"CONTENT_LENGTH"[..], b"5", b"SCGI", b"1"]);
+        let pairs: Vec<_> = headers.chunks(2).collect();
+        for pair in [
+            [&b"REQUEST_METHOD"[..], b"POST"],
+            [b"REQUEST_URI", b"/app/caf%C3%A9?q=1"],
+            [b"PATH_INFO", "/app/café".as_bytes()],
+            [b"SCRIPT_NAME", b""],
+            [b"SERVER_NAME", b"example.com"],
+            [b"SERVER_PORT", b"443"],
+            [b"HTTPS", b"on"],
+            [b"REMOTE_ADDR", b"10.0.0.1"],
+            [b"CONTENT_TYPE", b"text/plain"],
+            [b"HTTP_HOST", b"example.com"],
+        ] {
+            assert!(pairs.contains(&&pair[..]), "{:?}", pair);
+        }
+        assert!(!pairs.iter().any(|pair| pair[0] == b"HTTP_PROXY"));
+        assert_eq!(
+            pairs
+                .iter()
+                .filter(|pair| pair[0] == b"CONTENT_LENGTH")
+                .count(),
+            1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_uwsgi() -> Result<(), Box<dyn Error>> {
+        let request = request()?;
+        let addr: SocketAddr = "10.0.0.1:5000".parse()?;
+        let encoded = encode_request(Protocol::Uwsgi, &request, request.headers(), addr, "http")?;
+        assert_eq!((encoded[0], encoded[3]), (0, 0));
+        let size = u16::from_le_bytes([encoded[1], encoded[2]]) as usize;
+        assert_eq!(&encoded[4 + size..], b"hello");
+        let mut vars = &encoded[4..4 + size];
+        let mut pairs = Vec::new();
+        while !vars.is_empty() {
+            let mut pair = Vec::new();
+            for _ in 0..2 {
+                let len = u16::from_le_bytes([vars[0], vars[1]]) as usize;
+                pair.push(String::from_utf8(vars[2..2 + len].to_vec())?);
+                vars = &vars[2 + len..];
+            }
+            pairs.push((pair[0].clone(), pair[1].clone()));
+        }
+        let get = |name: &str| {
+            pairs
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(get("REQUEST_METHOD"), Some("POST"));
+        assert_eq!(get("QUERY_STRING"), Some("q=1"));
+        assert_eq!(get("PATH_INFO"), Some("/app/café"));
+        assert_eq!(get("SERVER_PORT"), Some("80"));
+        assert_eq!(get("CONTENT_LENGTH"), Some("5"));
+        assert_eq!(get("HTTPS"), None);
+
+        // Variables beyond what the 16-bit size can carry
+        let mut headers = HeaderMap::new();
+        headers.insert("x-large", "x".repeat(40_000).parse()?);
+        headers.insert("x-larger", "y".repeat(40_000).parse()?);
+        let encoded = encode_request(Protocol::Uwsgi, &request, &headers, addr, "http");
+        assert!(encoded.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_response_head() -> Result<(), Box<dyn Error>> {
+        let response = b"Status: 201 Created\r\nContent-Type: text/plain\r\n\r\nbody";
+        let mut stream = &response[..];
+        let mut buf = BytesMut::new();
+        let (status, headers, _) = response_head(&mut stream, &mut buf).await?;
+        assert_eq!(status, 201);
+        assert_eq!(headers["content-type"], "text/plain");
+        assert_eq!(&buf[..], b"body");
+
+        let response = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+        let mut stream = &response[..];
+        let mut buf = BytesMut::new();
+        let (status, headers, _) = response_head(&mut stream, &mut buf).await?;
+        assert_eq!(status, 404);
+        assert_eq!(headers["content-length"], "0");
+
+        let response = b"Location: /login\n\n";
+        let mut stream = &response[..];
+        let (status, _, _) = response_head(&mut stream, &mut BytesMut::new()).await?;
+        assert_eq!(status, 302);
+
+        let mut stream = &b"Content-Type: text/pl"[..];
+        assert!(response_head(&mut stream, &mut BytesMut::new())
+            .await
+            .is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+use crate::error::CbltError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Address range such as `10.0.0.0/8` or `2001:db8::/32`, a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        // IPv4 clients on a dual-stack socket show up as mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            IpAddr::V4(_) => *ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                masked(u32::from(net) as u128, 32, self.prefix)
+                    == masked(u32::from(ip) as u128, 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                masked(u128::from(net), 128, self.prefix)
+                    == masked(u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn masked(bits: u128, width: u8, prefix: u8) -> u128 {
+    match width - prefix {
+        0 => bits,
+        shift if shift >= 128 => 0,
+        shift => bits >> shift,
+    }
+}
+
+/// Whether any of the ranges holds the address
+pub fn contains_ip(ranges: &[Cidr], ip: &IpAddr) -> bool {
+    ranges.iter().any(|range| range.contains(ip))
+}
+
+impl FromStr for Cidr {
+    type Err = CbltError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || CbltError::KdlParseError {
+            details: format!("Invalid IP range '{}'", s),
+        };
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+            None => width,
+        };
+        if prefix > width {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = CbltError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cidr::{contains_ip, Cidr};
+    use std::error::Error;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_cidr() -> Result<(), Box<dyn Error>> {
+        let ranges: Vec<Cidr> = ["10.0.0.0/8", "192.168.1.7", "2001:db8::/32"]
+            .iter()
+            .map(|range| range.parse())
+            .collect::<Result<_, _>>()?;
+        for (ip, expected) in [
+            ("10.1.2.3", true),
+            ("11.0.0.1", false),
+            ("192.168.1.7", true),
+            ("192.168.1.8", false),
+            ("::ffff:10.0.0.1", true),
+            ("2001:db8:1::5", true),
+            ("2001:db9::5", false),
+        ] {
+            assert_eq!(
+                contains_ip(&ranges, &ip.parse::<IpAddr>()?),
+                expected,
+                "{}",
+                ip
+            );
+        }
+        let any: Cidr = "0.0.0.0/0".parse()?;
+        assert!(any.contains(&"8.8.8.8".parse()?));
+        assert_eq!(any.to_string(), "0.0.0.0/0");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+
+        Ok(())
+    }
+}
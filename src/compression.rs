@@ -0,0 +1,239 @@
+use crate::body::{content_length, BodyWriter};
+use crate::config::{EncodeOptions, Encoding};
+use crate::error::CbltError;
+use async_compression::tokio::write::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use bytes::BytesMut;
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_TYPE, ETAG, VARY};
+use http::{HeaderMap, HeaderValue, Request, Version};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+const COMPRESSIBLE_TYPES: [&str; 8] = [
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/xhtml+xml",
+    "application/rss+xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    /// File extension of precompressed assets with this encoding
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gz",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+        }
+    }
+}
+
+/// Negotiated encoding together with its configured compression level
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    pub encoding: Encoding,
+    pub level: Option<i32>,
+}
+
+/// Picks the configured encoding the client accepts with the highest q-value
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn negotiate(request: &Request<BytesMut>, options: &EncodeOptions) -> Option<Codec> {
+    // Chunked framing is needed for the encoded body, HTTP/1.0 clients don't support it
+    if request.version() == Version::HTTP_10 {
+        return None;
+    }
+    let encoding = preferred_encoding(request, &options.encodings)?;
+    Some(Codec {
+        encoding,
+        level: options.levels.get(&encoding).copied(),
+    })
+}
+
+/// Picks the encoding the client accepts with the highest q-value, earlier ones win ties
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn preferred_encoding(request: &Request<BytesMut>, encodings: &[Encoding]) -> Option<Encoding> {
+    let accept_encoding = request.headers().get(ACCEPT_ENCODING)?.to_str().ok()?;
+    let accepted: Vec<(&str, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let name = params.next()?.trim();
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((name, q))
+        })
+        .collect();
+    let quality = |encoding: &Encoding| {
+        accepted
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.as_str()))
+            .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in encodings {
+        let q = quality(encoding);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((*encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether a response with these headers is worth compressing
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn is_compressible(headers: &HeaderMap, options: &EncodeOptions) -> bool {
+    if headers.contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    if !COMPRESSIBLE_TYPES
+        .iter()
+        .any(|prefix| content_type.starts_with(prefix))
+    {
+        return false;
+    }
+    content_length(headers)
+        .map(|len| len >= options.min_length)
+        .unwrap_or(true)
+}
+
+/// Rewrites the response headers for a body that is about to be encoded
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn encoded_headers(headers: &mut HeaderMap, encoding: Encoding) {
+    headers.remove(http::header::CONTENT_LENGTH);
+    headers.remove(http::header::ACCEPT_RANGES);
+    headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    headers.insert(
+        http::header::TRANSFER_ENCODING,
+        HeaderValue::from_static("chunked"),
+    );
+    // The encoded representation is no longer byte-for-byte identical
+    if let Some(etag) = headers.get(ETAG).and_then(|value| value.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                headers.insert(ETAG, weak);
+            }
+        }
+    }
+    add_vary(headers);
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn add_vary(headers: &mut HeaderMap) {
+    let varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| {
+            let value = value.trim();
+            value == "*" || value.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+enum Compressor {
+    Gzip(GzipEncoder<Vec<u8>>),
+    Brotli(Box<BrotliEncoder<Vec<u8>>>),
+    Zstd(ZstdEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    fn new(codec: Codec) -> Self {
+        let level = codec.level.map(Level::Precise).unwrap_or(Level::Default);
+        match codec.encoding {
+            Encoding::Gzip => Compressor::Gzip(GzipEncoder::with_quality(Vec::new(), level)),
+            Encoding::Brotli => {
+                Compressor::Brotli(Box::new(BrotliEncoder::with_quality(Vec::new(), level)))
+            }
+            Encoding::Zstd => Compressor::Zstd(ZstdEncoder::with_quality(Vec::new(), level)),
+        }
+    }
+
+    fn writer(&mut self) -> &mut (dyn AsyncWrite + Unpin + Send) {
+        match self {
+            Compressor::Gzip(encoder) => encoder,
+            Compressor::Brotli(encoder) => encoder.as_mut(),
+            Compressor::Zstd(encoder) => encoder,
+        }
+    }
+
+    fn output(&mut self) -> &mut Vec<u8> {
+        match self {
+            Compressor::Gzip(encoder) => encoder.get_mut(),
+            Compressor::Brotli(encoder) => encoder.get_mut(),
+            Compressor::Zstd(encoder) => encoder.get_mut(),
+        }
+    }
+}
+
+/// Compresses a body on the fly and writes it with chunked framing
+pub struct EncodedBodyWriter<'a, S> {
+    compressor: Compressor,
+    writer: BodyWriter<'a, S>,
+}
+
+impl<'a, S> EncodedBodyWriter<'a, S>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    pub fn new(socket: &'a mut S, codec: Codec) -> Self {
+        EncodedBodyWriter {
+            compressor: Compressor::new(codec),
+            writer: BodyWriter::new(socket, true),
+        }
+    }
+
+    /// With `flush` every piece is sent right away instead of waiting for a full block
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn write(&mut self, data: &[u8], flush: bool) -> Result<(), CbltError> {
+        let encoder = self.compressor.writer();
+        encoder.write_all(data).await?;
+        if flush {
+            encoder.flush().await?;
+        }
+        self.drain().await
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn finish(mut self, trailers: &HeaderMap) -> Result<(), CbltError> {
+        self.compressor.writer().shutdown().await?;
+        self.drain().await?;
+        self.writer.finish(trailers).await
+    }
+
+    async fn drain(&mut self) -> Result<(), CbltError> {
+        let output = self.compressor.output();
+        if !output.is_empty() {
+            let data = std::mem::take(output);
+            self.writer.write(&data).await?;
+        }
+        Ok(())
+    }
+}
@@ -1,545 +1,6171 @@
+use crate::cidr::Cidr;
+use crate::discovery::{consul_service, upstream_host};
 use crate::error::CbltError;
-use crate::server::Server;
+use crate::handler::{self, HandlerRef};
+use crate::headers::{fill_value, Placeholders};
+use crate::pattern::path_regex;
+use crate::reverse_proxy::{is_socket_address, is_valid_destination};
+use crate::server::{Listener, Server};
 use crate::{build_servers, Args};
 use bollard::container::ListContainersOptions;
 use bollard::service::ListServicesOptions;
+use bollard::system::EventsOptions;
+use futures_util::StreamExt;
 use kdl::{KdlDocument, KdlNode};
-use log::debug;
-use std::collections::HashMap;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
-use tokio::fs;
+use std::time::Duration;
+use tokio::sync::Notify;
 #[cfg(feature = "trace")]
 use tracing::instrument;
 
-#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)] // built once per configuration load
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Directive {
     Root {
         pattern: String,
         path: String,
     },
-    FileServer,
+    FileServer {
+        #[serde(default)]
+        options: FileServerOptions,
+    },
     ReverseProxy {
         pattern: String,
         destinations: Vec<String>,
+        #[serde(default)]
         options: ReverseProxyOptions,
     },
     Redir {
+        pattern: Option<String>, // every request when unset
         destination: String,
     },
+    #[serde(rename = "redirifnotcookie")]
     RedirIfNotCookie {
         cookiename: String,
         destination: String,
     },
+    #[serde(rename = "tls")]
     TlS {
         cert: String,
         key: String,
     },
+    TlsAcme {
+        #[serde(default)]
+        options: AcmeOptions,
+    },
+    TlsOptions {
+        #[serde(default)]
+        options: TlsOptions,
+    },
+    Hsts {
+        #[serde(default)]
+        options: HstsOptions,
+    },
+    SecurityHeaders {
+        #[serde(default)]
+        options: SecurityHeadersOptions,
+    },
+    Encode {
+        #[serde(default)]
+        options: EncodeOptions,
+    },
+    TryFiles {
+        pattern: String,
+        candidates: Vec<String>, // "{path}" is replaced with the request path
+    },
+    ErrorPage {
+        statuses: Vec<String>, // "404" or a whole class like "5xx"
+        page: ErrorPage,
+    },
+    AccessLog {
+        #[serde(default)]
+        options: AccessLogOptions,
+    },
+    Tracing {
+        #[serde(default)]
+        options: TracingOptions,
+    },
+    ProxyProtocol {
+        #[serde(default)]
+        options: ProxyProtocolOptions,
+    },
+    RequestLimits {
+        #[serde(default)]
+        options: RequestLimitOptions,
+    },
+    ConnectionLimits {
+        #[serde(default)]
+        options: ConnectionLimitOptions,
+    },
+    AutoBan {
+        #[serde(default)]
+        options: AutoBanOptions,
+    },
+    Listen {
+        addresses: Vec<String>, // "unix//run/cblt.sock"
+        #[serde(default)]
+        options: ListenOptions,
+    },
+    Header {
+        pattern: String,
+        operations: Vec<HeaderOp>, // on the response to the client
+    },
+    Uri {
+        pattern: String,
+        operation: UriOp,
+    },
+    Matcher {
+        name: String, // "@api", usable as the pattern of other directives
+        conditions: Vec<MatchCondition>,
+    },
+    ForwardAuth {
+        pattern: String,
+        upstream: String, // auth service asked about each request
+        #[serde(default)]
+        options: ForwardAuthOptions,
+    },
+    ForwardProxy {
+        #[serde(default)]
+        options: ForwardProxyOptions,
+    },
+    PhpFastcgi {
+        pattern: String,
+        upstream: String, // "localhost:9000" or "unix//run/php/php-fpm.sock"
+        #[serde(default)]
+        options: FastcgiOptions,
+    },
+    RemoteIp {
+        pattern: String,
+        action: IpAction,
+        ranges: Vec<Cidr>,
+        #[serde(default)]
+        trusted_proxies: Vec<Cidr>, // peers whose X-Forwarded-For / Forwarded name the client
+    },
+    #[serde(rename = "geoip")]
+    GeoIp {
+        database: String, // MaxMind GeoLite2/GeoIP2 Country or City .mmdb
+        #[serde(default)]
+        trusted_proxies: Vec<Cidr>,
+    },
+    Country {
+        pattern: String,
+        action: IpAction,
+        countries: Vec<String>, // ISO 3166-1 alpha-2 codes, uppercase
+    },
+    RateLimit {
+        pattern: String,
+        requests: u32, // allowed per window, also the largest burst
+        window: Duration,
+        #[serde(default)]
+        options: RateLimitOptions,
+    },
+    Throttle {
+        pattern: String,
+        rate: u64, // response bytes per second
+        #[serde(default)]
+        options: ThrottleOptions,
+    },
+    Wasm {
+        pattern: String,
+        path: String, // module in the binary or the text format
+        #[serde(default)]
+        options: WasmOptions,
+    },
+    Layer4 {
+        upstreams: Vec<String>, // "db:5432" or "unix//run/app.sock", tried in turn
+        #[serde(default)]
+        options: Layer4Options,
+    },
+    Handler {
+        name: String, // registered with `handler::register`
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(skip)]
+        handler: Option<HandlerRef>, // built from the registry when the configuration loads
+    },
 }
 
-#[derive(Debug, Clone)]
-pub enum LoadBalancePolicy {
-    RoundRobin,
-    IPHash,
+impl Directive {
+    /// Path pattern or named matcher choosing the requests the directive applies to
+    pub fn pattern(&self) -> Option<&str> {
+        match self {
+            Directive::Root { pattern, .. }
+            | Directive::ReverseProxy { pattern, .. }
+            | Directive::TryFiles { pattern, .. }
+            | Directive::Header { pattern, .. }
+            | Directive::Uri { pattern, .. }
+            | Directive::ForwardAuth { pattern, .. }
+            | Directive::PhpFastcgi { pattern, .. }
+            | Directive::RemoteIp { pattern, .. }
+            | Directive::Country { pattern, .. }
+            | Directive::RateLimit { pattern, .. }
+            | Directive::Throttle { pattern, .. } => Some(pattern),
+            Directive::Redir { pattern, .. } => pattern.as_deref(),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct ReverseProxyOptions {
-    pub lb_retries: u64,
-    pub lb_interval: u64,
-    pub lb_timeout: u64,
-    pub lb_policy: Option<LoadBalancePolicy>,
+/// Condition of a named matcher, a request has to meet all of a matcher's conditions
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchCondition {
+    Path(Vec<String>),   // any of the patterns
+    Method(Vec<String>), // any of the methods
+    Header {
+        name: String,
+        values: Vec<String>, // any of them, "*" at either end for a prefix or suffix; present when empty
+    },
+    Query {
+        name: String,
+        values: Vec<String>, // any of them; present when empty
+    },
+    Cookie {
+        name: CookieName,
+        values: Vec<String>, // any of them; present when empty
+    },
+    Not(Vec<MatchCondition>), // not all of them
+    And(Vec<MatchCondition>),
+    Or(Vec<MatchCondition>),
 }
 
-#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>, CbltError> {
-    let mut hosts = HashMap::new();
-
-    for node in doc.nodes() {
-        let hostname = node.name().value().to_string();
-        let mut directives = Vec::new();
+/// What `remote_ip` and `country` do with the clients they list, the others are refused once a rule allows
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpAction {
+    Allow,
+    Deny,
+}
 
-        if let Some(children) = node.children() {
-            for child_node in children.nodes() {
-                let child_name = child_node.name().value();
-
-                match child_name {
-                    "root" => {
-                        let args = get_string_args(child_node);
-                        if args.len() >= 2 {
-                            let pattern = args[0].to_string();
-                            let path = args[1].to_string();
-                            directives.push(Directive::Root { pattern, path });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!("Invalid 'root' directive for host {}", hostname),
-                            });
-                        }
-                    }
-                    "file_server" => {
-                        directives.push(Directive::FileServer);
-                    }
-                    "reverse_proxy" => {
-                        let args = get_string_args(child_node);
-                        if args.len() >= 2 {
-                            let pattern = args[0].to_string();
-                            let destinations = args[1..].iter().map(|s| s.to_string()).collect();
+/// How the name of a cookie condition is compared, so `_oauth2_proxy` and `_oauth2_proxy_csrf`
+/// are told apart unless a prefix is asked for
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CookieName {
+    Exact(String),
+    Prefix(String),
+    Regex(String), // unanchored unless the expression has "^" and "$"
+}
 
-                            let options = parse_reverse_proxy_options(child_node)?;
-                            directives.push(Directive::ReverseProxy {
-                                pattern,
-                                destinations,
-                                options,
-                            });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!(
-                                    "Invalid 'reverse_proxy' directive for host {}",
-                                    hostname
-                                ),
-                            });
-                        }
-                    }
-                    "redir" => {
-                        let args = get_string_args(child_node);
-                        if !args.is_empty() {
-                            let destination = args[0].to_string();
-                            directives.push(Directive::Redir { destination });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!("Invalid 'redir' directive for host {}", hostname),
-                            });
-                        }
-                    }
-                    "redirifnotcookie" => {
-                        let args = get_string_args(child_node);
-                        if !args.is_empty() {
-                            let cookiename = args[0].to_string();
-                            let destination = args[1].to_string();
-                            directives.push(Directive::RedirIfNotCookie {
-                                cookiename,
-                                destination,
-                            });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!("Invalid 'redir' directive for host {}", hostname),
-                            });
-                        }
-                    }
+/// Rewrite of the request path by `uri`, directives after it see the result
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UriOp {
+    StripPrefix { prefix: String },
+    AddPrefix { prefix: String },
+    Replace { find: String, replace: String },
+}
 
-                    "tls" => {
-                        let args = get_string_args(child_node);
-                        if args.len() >= 2 {
-                            let cert = args[0].to_string();
-                            let key = args[1].to_string();
-                            directives.push(Directive::TlS { cert, key });
-                        } else {
-                            return Err(CbltError::KdlParseError {
-                                details: format!("Invalid 'tls' directive for host {}", hostname),
-                            });
-                        }
-                    }
-                    _ => {
-                        return Err(CbltError::KdlParseError {
-                            details: format!(
-                                "Unknown directive '{}' for host {}",
-                                child_name, hostname
-                            ),
-                        });
-                    }
-                }
+impl UriOp {
+    /// Rewritten path, always starting with "/"; the placeholders are filled in the operation's
+    /// values first
+    pub fn apply(&self, path: &str, placeholders: &Placeholders) -> String {
+        let path = match self {
+            UriOp::StripPrefix { prefix } => {
+                let prefix = fill_value(prefix, placeholders);
+                path.strip_prefix(prefix.as_str())
+                    .unwrap_or(path)
+                    .to_string()
             }
+            UriOp::AddPrefix { prefix } => format!("{}{}", fill_value(prefix, placeholders), path),
+            UriOp::Replace { find, replace } => path.replace(
+                fill_value(find, placeholders).as_str(),
+                &fill_value(replace, placeholders),
+            ),
+        };
+        if path.starts_with('/') {
+            path
+        } else {
+            format!("/{}", path)
         }
+    }
+}
 
-        if directives.is_empty() {
-            return Err(CbltError::KdlParseError {
-                details: format!("No directives specified for host {}", hostname),
-            });
+/// Edit of a header field by `header`, `header_up` or `header_down`, values may hold placeholders
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderOp {
+    Set {
+        name: String,
+        value: String,
+    },
+    Add {
+        name: String,
+        value: String,
+    },
+    Remove {
+        name: String,
+    },
+    Replace {
+        name: String,
+        find: String, // substring of the values
+        replace: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ListenOptions {
+    pub mode: Option<u32>, // permissions of a Unix socket file
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HstsOptions {
+    pub max_age: Duration,
+    pub include_subdomains: bool,
+    pub preload: bool,
+}
+
+impl Default for HstsOptions {
+    fn default() -> Self {
+        HstsOptions {
+            max_age: Duration::from_secs(365 * 24 * 60 * 60),
+            include_subdomains: false,
+            preload: false,
         }
+    }
+}
 
-        if hosts.contains_key(&hostname) {
-            return Err(CbltError::KdlParseError {
-                details: format!("Host '{}' already exists", hostname),
-            });
+impl HstsOptions {
+    /// Strict-Transport-Security header value
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
         }
-        hosts.insert(hostname, directives);
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
     }
+}
 
-    #[cfg(debug_assertions)]
-    debug!("{:#?}", hosts);
-    Ok(hosts)
+/// Values of the `security_headers` preset, none leaves the header out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersOptions {
+    pub content_type_options: Option<String>,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+    pub content_security_policy: Option<String>, // no default, it depends on the site
 }
 
-#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-fn get_string_args<'a>(node: &'a KdlNode) -> Vec<&'a str> {
-    node.entries()
-        .iter()
-        .filter_map(|e| e.value().as_string())
-        .collect::<Vec<&'a str>>()
+impl Default for SecurityHeadersOptions {
+    fn default() -> Self {
+        SecurityHeadersOptions {
+            content_type_options: Some("nosniff".to_string()),
+            frame_options: Some("SAMEORIGIN".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            permissions_policy: Some("camera=(), microphone=(), geolocation=()".to_string()),
+            content_security_policy: None,
+        }
+    }
 }
 
-#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-fn parse_reverse_proxy_options(node: &KdlNode) -> Result<ReverseProxyOptions, CbltError> {
-    let mut options = ReverseProxyOptions {
-        lb_retries: 2,
-        lb_interval: 60,
-        lb_timeout: 1,
-        lb_policy: Some(LoadBalancePolicy::RoundRobin),
-    };
+impl SecurityHeadersOptions {
+    /// Headers to set, by name
+    pub fn headers(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("x-content-type-options", &self.content_type_options),
+            ("x-frame-options", &self.frame_options),
+            ("referrer-policy", &self.referrer_policy),
+            ("permissions-policy", &self.permissions_policy),
+            ("content-security-policy", &self.content_security_policy),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+        .collect()
+    }
+}
 
-    if let Some(children) = node.children() {
-        for child in children.nodes() {
-            let name = child.name().value();
-            match name {
-                "lb_retries" => {
-                    let args = get_string_args(child);
-                    if let Some(retries) = args.first() {
-                        options.lb_retries = retries.parse()?;
-                    } else {
-                        options.lb_retries = 2;
-                    }
-                }
-                "lb_interval" => {
-                    let args = get_string_args(child);
-                    if let Some(interval) = args.first() {
-                        options.lb_interval = interval.parse::<humantime::Duration>()?.as_secs();
-                    } else {
-                        options.lb_interval = 10;
-                    }
-                }
-                "lb_timeout" => {
-                    let args = get_string_args(child);
-                    if let Some(timeout) = args.first() {
-                        options.lb_timeout = timeout.parse::<humantime::Duration>()?.as_secs();
-                    } else {
-                        options.lb_timeout = 1;
-                    }
-                }
-                "lb_policy" => {
-                    let args = get_string_args(child);
-                    if let Some(policy_name) = args.first() {
-                        match *policy_name {
-                            "round_robin" => {
-                                options.lb_policy = Some(LoadBalancePolicy::RoundRobin);
-                            }
-                            "ip_hash" => {
-                                options.lb_policy = Some(LoadBalancePolicy::IPHash);
-                            }
-                            _ => {
-                                return Err(CbltError::KdlParseError {
-                                    details: format!("Unknown lb_policy '{}'", policy_name),
-                                });
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    return Err(CbltError::KdlParseError {
-                        details: format!("Unknown reverse_proxy option '{}'", name),
-                    });
-                }
-            }
+/// Protocol versions and cipher suites of a TLS listener
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsOptions {
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    pub ciphers: Vec<String>, // rustls names like "TLS13_AES_256_GCM_SHA384", all when empty
+}
+
+/// How `forward_auth` asks the auth service and what it takes from the answer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForwardAuthOptions {
+    pub uri: String,               // path and query requested from the auth service
+    pub copy_headers: Vec<String>, // "remote-user", or "remote-groups>x-groups" to rename
+    pub trusted_proxies: Vec<Cidr>,
+    pub timeout: Duration,
+}
+
+impl Default for ForwardAuthOptions {
+    fn default() -> Self {
+        ForwardAuthOptions {
+            uri: "/".to_string(),
+            copy_headers: Vec::new(),
+            trusted_proxies: Vec::new(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Who may open `CONNECT` tunnels through a `forward_proxy` and where to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ForwardProxyOptions {
+    pub users: Vec<ProxyUser>,     // Proxy-Authorization is required when set
+    pub allow: Vec<ProxyTarget>,   // any destination when empty
+    pub deny: Vec<ProxyTarget>,    // checked before `allow`
+    pub ports: Vec<u16>,           // destination ports
+    pub connect_timeout: Duration, // resolving and connecting to the destination
+}
+
+impl Default for ForwardProxyOptions {
+    fn default() -> Self {
+        ForwardProxyOptions {
+            users: Vec::new(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            ports: vec![443],
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Basic credentials of a `forward_proxy` client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProxyUser {
+    pub name: String,
+    pub password: Secret,
+}
+
+thread_local! {
+    static REDACTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Credential in the configuration, serialized as is except within [`Secret::redacted`] and
+/// never shown by `Debug`
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Runs `f` with every secret it serializes masked as "***"
+    pub fn redacted<T>(f: impl FnOnce() -> T) -> T {
+        let previous = REDACTING.replace(true);
+        let result = f();
+        REDACTING.set(previous);
+        result
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Self {
+        Secret(secret.to_string())
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Secret(secret)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if REDACTING.get() {
+            serializer.serialize_str("***")
+        } else {
+            serializer.serialize_str(&self.0)
+        }
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+/// Destination of a `forward_proxy` rule
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyTarget {
+    Range(Cidr), // addresses the destination resolves to
+    Host {
+        pattern: String,   // "example.com", or "*.example.com" for its subdomains
+        port: Option<u16>, // any allowed port when unset
+    },
+}
+
+impl FromStr for ProxyTarget {
+    type Err = CbltError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(range) = s.parse::<Cidr>() {
+            return Ok(ProxyTarget::Range(range));
+        }
+        let invalid = || CbltError::KdlParseError {
+            details: format!("Invalid forward_proxy destination '{}'", s),
+        };
+        let (pattern, port) = match s.rsplit_once(':') {
+            Some((pattern, port)) => (pattern, Some(port.parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let name = pattern.strip_prefix("*.").unwrap_or(pattern);
+        if name.is_empty() || name.contains(['*', '/', ':']) || s.contains('/') {
+            return Err(invalid());
+        }
+        Ok(ProxyTarget::Host {
+            pattern: pattern.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+/// Which requests `php_fastcgi` runs as scripts and what it tells them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FastcgiOptions {
+    pub root: Option<String>,         // the host's `root` when unset
+    pub split: Vec<String>,           // extensions ending the script name, PATH_INFO follows
+    pub index: Option<String>,        // script of directories and of paths matching no file
+    pub env: HashMap<String, String>, // extra parameters, replacing computed ones
+    pub dial_timeout: Duration,
+    pub read_timeout: Option<Duration>, // wait for the response head
+}
+
+impl Default for FastcgiOptions {
+    fn default() -> Self {
+        FastcgiOptions {
+            root: None,
+            split: vec![".php".to_string()],
+            index: Some("index.php".to_string()),
+            env: HashMap::new(),
+            dial_timeout: Duration::from_secs(10),
+            read_timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThrottleOptions {
+    pub upload: Option<u64>, // request body bytes per second
+    pub per: ThrottleScope,
+}
+
+/// What shares the bandwidth of a `throttle`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThrottleScope {
+    #[default]
+    Connection,
+    Route, // every request the directive takes
+}
+
+/// Configuration and sandbox limits of a `wasm` plugin
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WasmOptions {
+    pub config: String, // read by the plugin when it is configured
+    pub fuel: u64,      // instructions per call into the plugin
+    pub memory: u64,    // bytes of linear memory
+}
+
+impl Default for WasmOptions {
+    fn default() -> Self {
+        WasmOptions {
+            config: String::new(),
+            fuel: 100_000_000,
+            memory: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// How a `layer4` host reaches its upstreams
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Layer4Options {
+    pub connect_timeout: Duration, // per upstream, the next one is tried after it
+    pub proxy_protocol: Option<ProxyProtocolVersion>, // header sent to the upstream
+}
+
+impl Default for Layer4Options {
+    fn default() -> Self {
+        Layer4Options {
+            connect_timeout: Duration::from_secs(10),
+            proxy_protocol: None,
+        }
+    }
+}
+
+/// Whose requests a `rate_limit` counts together
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    RemoteIp,
+    Header(String), // the client IP when the request does not have it
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitOptions {
+    pub key: RateLimitKey,
+    pub trusted_proxies: Vec<Cidr>,
+    pub redis: Option<Secret>, // "redis://host:6379/0" shared by every instance, in-process otherwise
+}
+
+/// PROXY protocol header expected in front of the connections of a listener
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProxyProtocolOptions {
+    pub allow: Vec<Cidr>,  // peers whose header is read, any when empty
+    pub timeout: Duration, // to receive the header
+}
+
+impl Default for ProxyProtocolOptions {
+    fn default() -> Self {
+        ProxyProtocolOptions {
+            allow: Vec::new(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Bounds on reading the requests of a listener's connections, a client over them is sent a
+/// 408, 413 or 431 and disconnected
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestLimitOptions {
+    pub header_timeout: Duration, // from the first byte of a request to the end of its head
+    pub request_timeout: Option<Duration>, // head and body
+    pub max_header_size: usize,   // bytes of the request line and headers
+    pub max_body_size: usize,     // bytes of a body, which is read whole before handling
+    pub keep_alive_timeout: Duration, // idle time between requests before the connection is closed
+}
+
+impl Default for RequestLimitOptions {
+    fn default() -> Self {
+        RequestLimitOptions {
+            header_timeout: Duration::from_secs(10),
+            request_timeout: None,
+            max_header_size: 64 * 1024,
+            max_body_size: 100 * 1024 * 1024,
+            keep_alive_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Simultaneous connections of a listener, those over the limits are closed once accepted
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionLimitOptions {
+    pub max_connections: Option<usize>,
+    pub max_per_ip: Option<usize>, // by client address, as told by the PROXY protocol
+}
+
+/// Clients answered `max_failures` times with one of `statuses` within `window` are refused
+/// connections for `ban`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoBanOptions {
+    pub statuses: Vec<String>, // "401" or a whole class like "4xx"
+    pub max_failures: u32,
+    pub window: Duration,
+    pub ban: Duration,
+    pub allow: Vec<Cidr>, // never banned
+}
+
+impl Default for AutoBanOptions {
+    fn default() -> Self {
+        AutoBanOptions {
+            statuses: vec!["4xx".to_string()],
+            max_failures: 20,
+            window: Duration::from_secs(60),
+            ban: Duration::from_secs(600),
+            allow: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AcmeOptions {
+    pub email: Option<String>, // account contact
+    pub ca: String,            // ACME directory URL
+    pub storage: String,       // account, certificates and keys
+    pub challenge: AcmeChallenge,
+    pub dns: Option<DnsOptions>, // provider for the DNS-01 challenge
+    pub on_demand: Option<OnDemandOptions>, // issue for unknown SNI names at handshake time
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OnDemandOptions {
+    pub allow: Vec<String>,  // names issued without asking
+    pub ask: Option<String>, // URL answering 2xx for "?domain=<name>" when allowed
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DnsOptions {
+    pub provider: DnsProviderOptions,
+    #[serde(default)]
+    pub propagation_delay: Option<Duration>, // wait for the TXT record to reach the authoritative servers
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsProviderOptions {
+    Cloudflare {
+        api_token: Secret,
+        zone_id: Option<String>, // looked up from the record name otherwise
+    },
+    Route53 {
+        access_key_id: String,
+        secret_access_key: Secret,
+        hosted_zone_id: String,
+    },
+    Rfc2136 {
+        server: String, // "ns1.example.com:53"
+        zone: String,
+        key_name: String,
+        key_secret: Secret, // base64, hmac-sha256
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AcmeChallenge {
+    #[serde(rename = "http-01")]
+    Http01, // answered on port 80
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01, // answered in the TLS handshake on the host's own port
+    #[serde(rename = "dns-01")]
+    Dns01, // TXT record created through a DNS provider, needed for wildcard hosts
+}
+
+impl Default for AcmeOptions {
+    fn default() -> Self {
+        AcmeOptions {
+            email: None,
+            ca: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+            storage: "./acme".to_string(),
+            challenge: AcmeChallenge::Http01,
+            dns: None,
+            on_demand: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    Common,
+    Combined,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessLogOptions {
+    pub output: Option<String>, // file to append to, stdout otherwise
+    pub format: AccessLogFormat,
+    pub roll: RollOptions,
+}
+
+impl Default for AccessLogOptions {
+    fn default() -> Self {
+        AccessLogOptions {
+            output: None,
+            format: AccessLogFormat::Combined,
+            roll: RollOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TracingOptions {
+    pub endpoint: String, // OTLP/HTTP traces URL of the collector
+    pub service_name: String,
+    pub sample: f64, // share of traces started here that are exported
+}
+
+impl Default for TracingOptions {
+    fn default() -> Self {
+        TracingOptions {
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+            service_name: "cblt".to_string(),
+            sample: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RollOptions {
+    pub max_size: Option<u64>,      // bytes
+    pub interval: Option<Duration>, // age of the current file
+    pub keep: usize,                // rotated files kept next to the current one
+    pub gzip: bool,
+}
+
+impl Default for RollOptions {
+    fn default() -> Self {
+        RollOptions {
+            max_size: None,
+            interval: None,
+            keep: 10,
+            gzip: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorPage {
+    File(String),
+    Inline(String), // "{status}" and "{reason}" are replaced
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileServerOptions {
+    pub mime_types: HashMap<String, String>, // extension -> Content-Type
+    pub precompressed: Vec<Encoding>,        // sidecar files like "app.js.br"
+    pub browse: bool,                        // list directories without index.html
+    pub allow_dotfiles: bool,                // serve paths like ".git" or ".env"
+    pub dotfile_exceptions: Vec<String>,     // served even when dotfiles are denied
+    pub cache: Option<FileCacheOptions>,     // small files kept in memory
+    pub markdown: Option<MarkdownOptions>,   // ".md" files rendered to HTML
+    pub webdav: bool,                        // PROPFIND, PUT, LOCK and the other WebDAV methods
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MarkdownOptions {
+    pub layout: Option<String>, // HTML file with "{title}", "{path}" and "{content}"
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FileCacheOptions {
+    pub max_file_size: u64, // larger files are read from disk every time
+    pub max_size: u64,      // bytes held in all, least recently used files go first
+}
+
+impl Default for FileCacheOptions {
+    fn default() -> Self {
+        FileCacheOptions {
+            max_file_size: 64 * 1024,
+            max_size: 32 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for FileServerOptions {
+    fn default() -> Self {
+        FileServerOptions {
+            mime_types: HashMap::new(),
+            precompressed: Vec::new(),
+            browse: false,
+            allow_dotfiles: false,
+            dotfile_exceptions: vec![".well-known".to_string()],
+            cache: None,
+            markdown: None,
+            webdav: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    Gzip,
+    #[serde(rename = "br")]
+    Brotli,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncodeOptions {
+    pub encodings: Vec<Encoding>,       // in order of preference
+    pub min_length: u64,                // smaller responses are sent as is
+    pub levels: HashMap<Encoding, i32>, // encoder defaults are used otherwise
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            encodings: vec![Encoding::Gzip],
+            min_length: 512,
+            levels: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancePolicy {
+    RoundRobin,
+    #[serde(rename = "ip_hash")]
+    IPHash,
+    LeastConn,             // fewest requests in flight
+    Random,                // the less busy of two random backends
+    Cookie(CookieOptions), // round robin, then the backend named by the affinity cookie
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CookieOptions {
+    pub name: String,
+    pub path: String,
+    pub max_age: Option<u64>, // seconds, a session cookie when unset
+}
+
+impl Default for CookieOptions {
+    fn default() -> Self {
+        CookieOptions {
+            name: "lb".to_string(),
+            path: "/".to_string(),
+            max_age: None,
+        }
+    }
+}
+
+/// Headers describing the client to backends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardHeaders {
+    #[default]
+    XForwarded, // X-Forwarded-For, -Proto, -Host and X-Real-IP
+    Forwarded, // RFC 7239
+    Both,
+}
+
+/// Failures a proxied request is sent to another backend on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    ConnectFailure,
+    Timeout,
+    Status(u16),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReverseProxyOptions {
+    pub lb_retries: u64,
+    pub lb_interval: u64,
+    pub lb_timeout: u64, // connect timeout, seconds
+    pub lb_policy: Option<LoadBalancePolicy>,
+    pub lb_max_fails: u64, // failed requests in a row before a backend is ejected
+    pub lb_max_backoff: u64, // seconds, cap of the doubling ejection time
+    pub retries: Option<u64>, // one try per backend when unset
+    pub retry_on: Vec<RetryOn>,
+    pub header_timeout: Option<Duration>, // wait for the response head of a backend
+    pub timeout: Option<Duration>,        // whole exchange with the backends, retries included
+    pub trusted_proxies: Vec<Cidr>,       // clients whose forwarding headers are passed on
+    pub forward_headers: ForwardHeaders,
+    pub header_up: Vec<HeaderOp>,   // on the request to the backend
+    pub header_down: Vec<HeaderOp>, // on the backend's response
+    pub tls_trusted_ca_certs: Vec<PathBuf>, // PEM files trusted for https backends instead of the system roots
+    pub tls_insecure_skip_verify: bool,
+    pub tls_server_name: Option<String>, // SNI and verified name, the backend host when unset
+    pub proxy_protocol: Option<ProxyProtocolVersion>, // header sent on new backend connections
+    pub pool_max_idle: usize,            // idle connections kept per backend
+    pub pool_idle_timeout: u64,          // seconds
+    pub flush_interval: Option<Duration>, // coalesce body writes, event streams are never held back
+    pub health_uri: Option<String>,      // active health checks are off without it
+    pub health_interval: u64,            // seconds
+    pub health_timeout: u64,             // seconds
+    pub health_status: Option<u16>,      // expected status, any 2xx when unset
+    pub cache: Option<CacheOptions>,     // responses are not cached without it
+    pub mirror: Option<String>,          // shadow backend getting a copy of every request
+    pub canary: Option<CanaryOptions>,   // upstream taking a share of the requests
+    pub groups: Vec<UpstreamGroup>,      // the first one whose conditions a request meets takes it
+    pub backups: Vec<String>,            // upstreams taking requests only while all others are down
+    pub dns_discovery: Option<DnsDiscoveryOptions>, // upstream hostnames are resolved once without it
+    pub consul: ConsulOptions, // agent asked for the instances of `consul://service` upstreams
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DnsDiscoveryOptions {
+    pub resolver: Option<SocketAddr>, // the nameservers of /etc/resolv.conf when unset
+    pub min_refresh: u64,             // seconds, also the wait after a failed lookup
+    pub max_refresh: u64,             // seconds
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConsulOptions {
+    pub address: String,            // HTTP API of the Consul agent
+    pub token: Option<Secret>,      // ACL token sent as `X-Consul-Token`
+    pub datacenter: Option<String>, // the agent's own when unset
+    pub tag: Option<String>,        // only instances carrying it
+    pub scheme: String,             // of the instance URLs: http, https, uwsgi or scgi
+}
+
+impl Default for ConsulOptions {
+    fn default() -> Self {
+        ConsulOptions {
+            address: "http://127.0.0.1:8500".to_string(),
+            token: None,
+            datacenter: None,
+            tag: None,
+            scheme: "http".to_string(),
+        }
+    }
+}
+
+impl Default for DnsDiscoveryOptions {
+    fn default() -> Self {
+        DnsDiscoveryOptions {
+            resolver: None,
+            min_refresh: 5,
+            max_refresh: 300,
+        }
+    }
+}
+
+/// Upstreams for the requests meeting some conditions, e.g. a beta pool for `X-Beta: 1`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamGroup {
+    pub name: String,
+    pub conditions: Vec<MatchCondition>, // as in a named matcher
+    pub upstreams: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanaryOptions {
+    pub upstream: String,
+    pub percent: f64, // of the requests, the rest go to the backends
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CacheOptions {
+    pub max_size: u64, // bytes held in all, least recently used responses go first
+    pub max_object_size: u64, // larger responses are passed through
+    pub ttl: Option<Duration>, // replaces the freshness lifetime the backend gives
+    pub stale_while_revalidate: Option<Duration>, // for responses that name none
+    pub stale_if_error: Option<Duration>, // for responses that name none
+    pub purge_allow: Vec<Cidr>, // clients whose PURGE requests evict responses
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            max_size: 64 * 1024 * 1024,
+            max_object_size: 1024 * 1024,
+            ttl: None,
+            stale_while_revalidate: None,
+            stale_if_error: None,
+            purge_allow: Vec::new(),
+        }
+    }
+}
+
+impl Default for ReverseProxyOptions {
+    fn default() -> Self {
+        ReverseProxyOptions {
+            lb_retries: 2,
+            lb_interval: 60,
+            lb_timeout: 1,
+            lb_policy: Some(LoadBalancePolicy::RoundRobin),
+            lb_max_fails: 1,
+            lb_max_backoff: 600,
+            retries: None,
+            retry_on: vec![RetryOn::ConnectFailure, RetryOn::Timeout],
+            header_timeout: None,
+            timeout: None,
+            trusted_proxies: Vec::new(),
+            forward_headers: ForwardHeaders::default(),
+            header_up: Vec::new(),
+            header_down: Vec::new(),
+            tls_trusted_ca_certs: Vec::new(),
+            tls_insecure_skip_verify: false,
+            tls_server_name: None,
+            proxy_protocol: None,
+            pool_max_idle: 32,
+            pool_idle_timeout: 90,
+            flush_interval: None,
+            health_uri: None,
+            health_interval: 30,
+            health_timeout: 5,
+            health_status: None,
+            cache: None,
+            mirror: None,
+            canary: None,
+            groups: Vec::new(),
+            backups: Vec::new(),
+            dns_discovery: None,
+            consul: ConsulOptions::default(),
+        }
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn build_config(doc: &KdlDocument) -> Result<HashMap<String, Vec<Directive>>, CbltError> {
+    let mut hosts = HashMap::new();
+    let snippets = snippets(doc)?;
+
+    for node in doc.nodes() {
+        if node.name().value() == "snippet" {
+            continue;
+        }
+        let hostname = node.name().value().to_string();
+        let mut directives = Vec::new();
+
+        if let Some(children) = node.children() {
+            expand_directives(
+                children.nodes(),
+                &hostname,
+                &snippets,
+                &mut Vec::new(),
+                &mut directives,
+            )?;
+        }
+
+        if directives.is_empty() {
+            return Err(CbltError::KdlParseError {
+                details: format!("No directives specified for host {}", hostname),
+            });
+        }
+
+        if hosts.contains_key(&hostname) {
+            return Err(CbltError::KdlParseError {
+                details: format!("Host '{}' already exists", hostname),
+            });
+        }
+        check_matchers(&hostname, &directives)?;
+        check_wasm(&hostname, &directives)?;
+        check_layer4(&hostname, &directives)?;
+        hosts.insert(hostname, directives);
+    }
+
+    #[cfg(debug_assertions)]
+    debug!("{:#?}", hosts);
+    Ok(hosts)
+}
+
+/// Named directive sets declared with `snippet "name" { ... }`
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn snippets(doc: &KdlDocument) -> Result<HashMap<&str, &[KdlNode]>, CbltError> {
+    let mut snippets = HashMap::new();
+    for node in doc.nodes() {
+        if node.name().value() != "snippet" {
+            continue;
+        }
+        let name =
+            get_string_args(node)
+                .first()
+                .copied()
+                .ok_or_else(|| CbltError::KdlParseError {
+                    details: "Snippet without a name".to_string(),
+                })?;
+        let children = node.children().map(|c| c.nodes()).unwrap_or_default();
+        if snippets.insert(name, children).is_some() {
+            return Err(CbltError::KdlParseError {
+                details: format!("Snippet '{}' already exists", name),
+            });
+        }
+    }
+    Ok(snippets)
+}
+
+/// Parses host directives, replacing `import "snippet"` with the snippet's directives
+fn expand_directives<'a>(
+    nodes: &'a [KdlNode],
+    hostname: &str,
+    snippets: &HashMap<&'a str, &'a [KdlNode]>,
+    stack: &mut Vec<&'a str>,
+    directives: &mut Vec<Directive>,
+) -> Result<(), CbltError> {
+    for node in nodes {
+        if node.name().value() != "import" {
+            directives.push(parse_directive(node, hostname)?);
+            continue;
+        }
+        for name in get_string_args(node) {
+            let (&name, &snippet) =
+                snippets
+                    .get_key_value(name)
+                    .ok_or_else(|| CbltError::KdlParseError {
+                        details: format!("Unknown snippet '{}' for host {}", name, hostname),
+                    })?;
+            if stack.contains(&name) {
+                return Err(CbltError::KdlParseError {
+                    details: format!("Snippet '{}' imports itself", name),
+                });
+            }
+            stack.push(name);
+            expand_directives(snippet, hostname, snippets, stack, directives)?;
+            stack.pop();
+        }
+    }
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn parse_directive(node: &KdlNode, hostname: &str) -> Result<Directive, CbltError> {
+    let args = get_string_args(node);
+    let invalid = |name: &str| CbltError::KdlParseError {
+        details: format!("Invalid '{}' directive for host {}", name, hostname),
+    };
+
+    match node.name().value() {
+        "root" => {
+            if args.len() >= 2 {
+                Ok(Directive::Root {
+                    pattern: args[0].to_string(),
+                    path: args[1].to_string(),
+                })
+            } else {
+                Err(invalid("root"))
+            }
+        }
+        "file_server" => Ok(Directive::FileServer {
+            options: parse_file_server_options(node)?,
+        }),
+        "reverse_proxy" => {
+            // Upstreams follow the pattern or are listed by `to` in the block
+            let mut destinations: Vec<String> =
+                args.iter().skip(1).map(|s| s.to_string()).collect();
+            for child in node.children().iter().flat_map(|children| children.nodes()) {
+                if child.name().value() == "to" {
+                    destinations.extend(get_string_args(child).iter().map(|s| s.to_string()));
+                }
+            }
+            let options = parse_reverse_proxy_options(node, hostname)?;
+            // The mirror gets its copies at one fixed address
+            let invalid_consul = destinations
+                .iter()
+                .chain(options.backups.iter())
+                .filter(|url| consul_service(url).is_none())
+                .chain(options.mirror.iter())
+                .find(|url| url.starts_with("consul://"));
+            if let Some(url) = invalid_consul {
+                return Err(CbltError::KdlParseError {
+                    details: format!("Invalid Consul upstream '{}'", url),
+                });
+            }
+            if options.dns_discovery.is_some() && options.tls_server_name.is_none() {
+                // Discovered https backends are verified against the one upstream host
+                let mut hosts: Vec<String> = destinations
+                    .iter()
+                    .chain(options.backups.iter())
+                    .chain(options.mirror.iter())
+                    .filter(|url| url.starts_with("https://"))
+                    .filter_map(|url| upstream_host(url).map(|(host, _)| host))
+                    .collect();
+                hosts.sort();
+                hosts.dedup();
+                if hosts.len() > 1 {
+                    return Err(CbltError::KdlParseError {
+                        details: format!(
+                            "dns_discovery with https upstreams on several hosts needs tls_server_name for host {}",
+                            hostname
+                        ),
+                    });
+                }
+            }
+            match args.first() {
+                Some(pattern) if !destinations.is_empty() => Ok(Directive::ReverseProxy {
+                    pattern: pattern.to_string(),
+                    destinations,
+                    options,
+                }),
+                _ => Err(invalid("reverse_proxy")),
+            }
+        }
+        "redir" => match args[..] {
+            [destination] => Ok(Directive::Redir {
+                pattern: None,
+                destination: destination.to_string(),
+            }),
+            [pattern, destination] => Ok(Directive::Redir {
+                pattern: Some(pattern.to_string()),
+                destination: destination.to_string(),
+            }),
+            _ => Err(invalid("redir")),
+        },
+        "redirifnotcookie" => {
+            if args.len() >= 2 {
+                Ok(Directive::RedirIfNotCookie {
+                    cookiename: args[0].to_string(),
+                    destination: args[1].to_string(),
+                })
+            } else {
+                Err(invalid("redirifnotcookie"))
+            }
+        }
+        "tls" => {
+            if args.first() == Some(&"acme") {
+                Ok(Directive::TlsAcme {
+                    options: parse_acme_options(node)?,
+                })
+            } else if args.len() >= 2 {
+                Ok(Directive::TlS {
+                    cert: args[0].to_string(),
+                    key: args[1].to_string(),
+                })
+            } else {
+                Err(invalid("tls"))
+            }
+        }
+        "encode" => Ok(Directive::Encode {
+            options: parse_encode_options(node)?,
+        }),
+        "access_log" => Ok(Directive::AccessLog {
+            options: parse_access_log_options(node)?,
+        }),
+        "tracing" => Ok(Directive::Tracing {
+            options: parse_tracing_options(node)?,
+        }),
+        "security_headers" => Ok(Directive::SecurityHeaders {
+            options: parse_security_headers_options(node)?,
+        }),
+        "hsts" => Ok(Directive::Hsts {
+            options: parse_hsts_options(node)?,
+        }),
+        "tls_options" => Ok(Directive::TlsOptions {
+            options: parse_tls_options(node)?,
+        }),
+        "header" => {
+            let Some(pattern) = args.first() else {
+                return Err(invalid("header"));
+            };
+            // One field inline or one per line in the block
+            let mut operations = Vec::new();
+            if args.len() > 1 {
+                operations.push(parse_header_op(&args[1..])?);
+            }
+            for child in node.children().iter().flat_map(|children| children.nodes()) {
+                let mut field = vec![child.name().value()];
+                field.extend(get_string_args(child));
+                operations.push(parse_header_op(&field)?);
+            }
+            if operations.is_empty() {
+                return Err(invalid("header"));
+            }
+            Ok(Directive::Header {
+                pattern: pattern.to_string(),
+                operations,
+            })
+        }
+        "uri" => {
+            let operation = match args[..] {
+                [_, "strip_prefix", prefix] => UriOp::StripPrefix {
+                    prefix: slash_prefixed(prefix),
+                },
+                [_, "add_prefix", prefix] => UriOp::AddPrefix {
+                    prefix: slash_prefixed(prefix),
+                },
+                [_, "replace", find, replace] if !find.is_empty() => UriOp::Replace {
+                    find: find.to_string(),
+                    replace: replace.to_string(),
+                },
+                _ => return Err(invalid("uri")),
+            };
+            Ok(Directive::Uri {
+                pattern: args[0].to_string(),
+                operation,
+            })
+        }
+        name if name.starts_with('@') => Ok(Directive::Matcher {
+            name: name.to_string(),
+            conditions: parse_match_conditions(node, name, hostname)?,
+        }),
+        "forward_proxy" => Ok(Directive::ForwardProxy {
+            options: parse_forward_proxy_options(node)?,
+        }),
+        "forward_auth" => match args[..] {
+            [pattern, upstream] => Ok(Directive::ForwardAuth {
+                pattern: pattern.to_string(),
+                upstream: upstream.to_string(),
+                options: parse_forward_auth_options(node)?,
+            }),
+            _ => Err(invalid("forward_auth")),
+        },
+        "wasm" => {
+            let (pattern, path) = match args[..] {
+                [path] => ("*", path),
+                [pattern, path] => (pattern, path),
+                _ => return Err(invalid("wasm")),
+            };
+            Ok(Directive::Wasm {
+                pattern: pattern.to_string(),
+                path: path.to_string(),
+                options: parse_wasm_options(node)?,
+            })
+        }
+        "layer4" => {
+            if args.is_empty() {
+                return Err(invalid("layer4"));
+            }
+            if let Some(upstream) = args.iter().find(|upstream| !is_socket_address(upstream)) {
+                return Err(CbltError::KdlParseError {
+                    details: format!("Invalid layer4 upstream '{}'", upstream),
+                });
+            }
+            Ok(Directive::Layer4 {
+                upstreams: args.iter().map(|upstream| upstream.to_string()).collect(),
+                options: parse_layer4_options(node)?,
+            })
+        }
+        "php_fastcgi" => {
+            let (pattern, upstream) = match args[..] {
+                [upstream] => ("*", upstream),
+                [pattern, upstream] => (pattern, upstream),
+                _ => return Err(invalid("php_fastcgi")),
+            };
+            Ok(Directive::PhpFastcgi {
+                pattern: pattern.to_string(),
+                upstream: upstream.to_string(),
+                options: parse_fastcgi_options(node)?,
+            })
+        }
+        "remote_ip" => {
+            let (pattern, action, ranges) = access_rule(&args).ok_or(invalid("remote_ip"))?;
+            Ok(Directive::RemoteIp {
+                pattern: pattern.to_string(),
+                action,
+                ranges: ranges
+                    .iter()
+                    .map(|range| range.parse())
+                    .collect::<Result<_, _>>()?,
+                trusted_proxies: parse_trusted_proxies(node, "remote_ip")?,
+            })
+        }
+        "rate_limit" => match args[..] {
+            [pattern, requests, window] => Ok(Directive::RateLimit {
+                pattern: pattern.to_string(),
+                requests: requests
+                    .parse()
+                    .ok()
+                    .filter(|requests| *requests > 0)
+                    .ok_or(invalid("rate_limit"))?,
+                window: *window.parse::<humantime::Duration>()?,
+                options: parse_rate_limit_options(node)?,
+            }),
+            _ => Err(invalid("rate_limit")),
+        },
+        "throttle" => match args[..] {
+            [pattern, rate] => Ok(Directive::Throttle {
+                pattern: pattern.to_string(),
+                rate: parse_rate(rate)?,
+                options: parse_throttle_options(node)?,
+            }),
+            _ => Err(invalid("throttle")),
+        },
+        "geoip" => match args[..] {
+            [database] => Ok(Directive::GeoIp {
+                database: database.to_string(),
+                trusted_proxies: parse_trusted_proxies(node, "geoip")?,
+            }),
+            _ => Err(invalid("geoip")),
+        },
+        "country" => {
+            let (pattern, action, countries) = access_rule(&args).ok_or(invalid("country"))?;
+            let mut codes = Vec::new();
+            for country in countries {
+                if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                    return Err(CbltError::KdlParseError {
+                        details: format!(
+                            "Invalid country code '{}' for host {}, expected ISO 3166-1 like \"DE\"",
+                            country, hostname
+                        ),
+                    });
+                }
+                codes.push(country.to_uppercase());
+            }
+            Ok(Directive::Country {
+                pattern: pattern.to_string(),
+                action,
+                countries: codes,
+            })
+        }
+        "proxy_protocol" => Ok(Directive::ProxyProtocol {
+            options: parse_proxy_protocol_options(node)?,
+        }),
+        "request_limits" => Ok(Directive::RequestLimits {
+            options: parse_request_limit_options(node)?,
+        }),
+        "connection_limits" => Ok(Directive::ConnectionLimits {
+            options: parse_connection_limit_options(node)?,
+        }),
+        "auto_ban" => Ok(Directive::AutoBan {
+            options: parse_auto_ban_options(node)?,
+        }),
+        "listen" => {
+            let addresses: Vec<String> = get_string_args(node)
+                .into_iter()
+                .map(String::from)
+                .collect();
+            if addresses.is_empty() {
+                return Err(CbltError::KdlParseError {
+                    details: "listen needs an address".to_string(),
+                });
+            }
+            for address in &addresses {
+                Listener::parse(address, 0, None)?;
+            }
+            Ok(Directive::Listen {
+                addresses,
+                options: parse_listen_options(node)?,
+            })
+        }
+        "error_page" => {
+            let (statuses, page) = parse_error_page(node, hostname)?;
+            Ok(Directive::ErrorPage { statuses, page })
+        }
+        "try_files" => {
+            if args.len() >= 2 {
+                Ok(Directive::TryFiles {
+                    pattern: args[0].to_string(),
+                    candidates: args[1..].iter().map(|s| s.to_string()).collect(),
+                })
+            } else {
+                Err(invalid("try_files"))
+            }
+        }
+        name => {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            match handler::build(name, &args) {
+                Some(Ok(handler)) => Ok(Directive::Handler {
+                    name: name.to_string(),
+                    args,
+                    handler: Some(HandlerRef(handler)),
+                }),
+                Some(Err(err)) => Err(CbltError::KdlParseError {
+                    details: format!(
+                        "Invalid '{}' directive for host {}: {}",
+                        name, hostname, err
+                    ),
+                }),
+                None => Err(CbltError::KdlParseError {
+                    details: format!("Unknown directive '{}' for host {}", name, hostname),
+                }),
+            }
+        }
+    }
+}
+
+/// Builds the handlers of registered directives given without one, as JSON leaves them
+pub fn build_handlers(hosts: &mut HashMap<String, Vec<Directive>>) -> Result<(), CbltError> {
+    for (hostname, directives) in hosts.iter_mut() {
+        for directive in directives.iter_mut() {
+            if let Directive::Handler {
+                name,
+                args,
+                handler: handler @ None,
+            } = directive
+            {
+                let built = handler::build(name, args).ok_or_else(|| CbltError::KdlParseError {
+                    details: format!("Unknown directive '{}' for host {}", name, hostname),
+                })?;
+                *handler = Some(HandlerRef(built?));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// "api" and "/api" are the same prefix
+fn slash_prefixed(prefix: &str) -> String {
+    if prefix.starts_with('/') {
+        prefix.to_string()
+    } else {
+        format!("/{}", prefix)
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+/// Share like "5%" or "0.5", between 0 and 100
+pub fn parse_percent(value: &str) -> Result<f64, CbltError> {
+    value
+        .trim_end_matches('%')
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| CbltError::KdlParseError {
+            details: format!("Invalid percentage '{}'", value),
+        })
+}
+
+fn get_string_args<'a>(node: &'a KdlNode) -> Vec<&'a str> {
+    node.entries()
+        .iter()
+        .filter_map(|e| e.value().as_string())
+        .collect::<Vec<&'a str>>()
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_file_server_options(node: &KdlNode) -> Result<FileServerOptions, CbltError> {
+    let mut options = FileServerOptions::default();
+
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            match name {
+                "mime" => {
+                    let args = get_string_args(child);
+                    if args.len() >= 2 {
+                        let extension = args[0].trim_start_matches('.').to_ascii_lowercase();
+                        options.mime_types.insert(extension, args[1].to_string());
+                    } else {
+                        return Err(CbltError::KdlParseError {
+                            details: "Invalid 'mime' option, expected extension and type"
+                                .to_string(),
+                        });
+                    }
+                }
+                "browse" => {
+                    options.browse = true;
+                }
+                "webdav" => {
+                    options.webdav = true;
+                }
+                "dotfiles" => match get_string_args(child).first() {
+                    Some(&"allow") => options.allow_dotfiles = true,
+                    Some(&"deny") => options.allow_dotfiles = false,
+                    _ => {
+                        return Err(CbltError::KdlParseError {
+                            details: "Invalid 'dotfiles' option, expected \"allow\" or \"deny\""
+                                .to_string(),
+                        });
+                    }
+                },
+                "dotfiles_except" => {
+                    for name in get_string_args(child) {
+                        options.dotfile_exceptions.push(name.to_string());
+                    }
+                }
+                "precompressed" => {
+                    for name in get_string_args(child) {
+                        options.precompressed.push(parse_encoding(name)?);
+                    }
+                    if options.precompressed.is_empty() {
+                        options.precompressed =
+                            vec![Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+                    }
+                }
+                "cache" => {
+                    options.cache = Some(parse_file_cache_options(child)?);
+                }
+                "markdown" => {
+                    options.markdown = Some(MarkdownOptions {
+                        layout: get_string_args(child).first().map(|s| s.to_string()),
+                    });
+                }
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown file_server option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_encode_options(node: &KdlNode) -> Result<EncodeOptions, CbltError> {
+    let mut encodings = Vec::new();
+    for name in get_string_args(node) {
+        encodings.push(parse_encoding(name)?);
+    }
+    let mut options = EncodeOptions::default();
+    if !encodings.is_empty() {
+        options.encodings = encodings;
+    }
+
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            match name {
+                "minimum_length" => {
+                    let args = get_string_args(child);
+                    if let Some(min_length) = args.first() {
+                        options.min_length = min_length.parse()?;
+                    }
+                }
+                "gzip" | "br" | "zstd" => {
+                    let args = get_string_args(child);
+                    if let Some(level) = args.first() {
+                        options.levels.insert(parse_encoding(name)?, level.parse()?);
+                    }
+                }
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown encode option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_hsts_options(node: &KdlNode) -> Result<HstsOptions, CbltError> {
+    let mut options = HstsOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "max_age" => {
+                    if let Some(max_age) = args.first() {
+                        options.max_age = *max_age.parse::<humantime::Duration>()?;
+                    }
+                }
+                // Flags may be bare nodes or take "true"/"false"
+                "include_subdomains" => options.include_subdomains = args.first() != Some(&"false"),
+                "preload" => options.preload = args.first() != Some(&"false"),
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown hsts option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_security_headers_options(node: &KdlNode) -> Result<SecurityHeadersOptions, CbltError> {
+    let mut options = SecurityHeadersOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            let value = match get_string_args(child).as_slice() {
+                ["off"] => None,
+                [value] => Some(value.to_string()),
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("security_headers option '{}' takes one value", name),
+                    });
+                }
+            };
+            match name {
+                "content_type_options" => options.content_type_options = value,
+                "frame_options" => options.frame_options = value,
+                "referrer_policy" => options.referrer_policy = value,
+                "permissions_policy" => options.permissions_policy = value,
+                "content_security_policy" => options.content_security_policy = value,
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown security_headers option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_tls_options(node: &KdlNode) -> Result<TlsOptions, CbltError> {
+    let mut options = TlsOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "min_version" => options.min_version = Some(parse_tls_version(&args)?),
+                "max_version" => options.max_version = Some(parse_tls_version(&args)?),
+                "ciphers" => options.ciphers = args.iter().map(|c| c.to_string()).collect(),
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown tls_options option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    if options.min_version > options.max_version && options.max_version.is_some() {
+        return Err(CbltError::KdlParseError {
+            details: "tls_options min_version is above max_version".to_string(),
+        });
+    }
+    Ok(options)
+}
+
+fn parse_proxy_protocol_options(node: &KdlNode) -> Result<ProxyProtocolOptions, CbltError> {
+    let mut options = ProxyProtocolOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "allow" => {
+                    options.allow = args.into_iter().map(str::parse).collect::<Result<_, _>>()?
+                }
+                "timeout" => {
+                    if let Some(timeout) = args.first() {
+                        options.timeout = *timeout.parse::<humantime::Duration>()?;
+                    }
+                }
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown proxy_protocol option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_request_limit_options(node: &KdlNode) -> Result<RequestLimitOptions, CbltError> {
+    let mut options = RequestLimitOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match (child.name().value(), args.as_slice()) {
+                ("header_timeout", [timeout]) => {
+                    options.header_timeout = *timeout.parse::<humantime::Duration>()?;
+                }
+                ("request_timeout", [timeout]) => {
+                    options.request_timeout = Some(*timeout.parse::<humantime::Duration>()?);
+                }
+                ("max_header_size", [size]) => {
+                    options.max_header_size = parse_size(size)? as usize;
+                }
+                ("max_body_size", [size]) => {
+                    options.max_body_size = parse_size(size)? as usize;
+                }
+                ("keep_alive_timeout", [timeout]) => {
+                    options.keep_alive_timeout = *timeout.parse::<humantime::Duration>()?;
+                }
+                (name, _) => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!(
+                            "Unknown request_limits option '{}', expected header_timeout, \
+                             request_timeout, max_header_size, max_body_size or keep_alive_timeout \
+                             with one value",
+                            name
+                        ),
+                    });
+                }
+            }
+        }
+    }
+    if options.header_timeout.is_zero()
+        || options.max_header_size == 0
+        || options.keep_alive_timeout.is_zero()
+    {
+        return Err(CbltError::KdlParseError {
+            details: "request_limits needs a header_timeout, max_header_size and keep_alive_timeout above zero"
+                .to_string(),
+        });
+    }
+    Ok(options)
+}
+
+fn parse_connection_limit_options(node: &KdlNode) -> Result<ConnectionLimitOptions, CbltError> {
+    let mut options = ConnectionLimitOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            let limit = match args.as_slice() {
+                [limit] => limit.parse::<usize>().ok().filter(|limit| *limit > 0),
+                _ => None,
+            };
+            match (child.name().value(), limit) {
+                ("max_connections", Some(limit)) => options.max_connections = Some(limit),
+                ("max_per_ip", Some(limit)) => options.max_per_ip = Some(limit),
+                (name, _) => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!(
+                            "Invalid connection_limits option '{}', expected max_connections \
+                             or max_per_ip with a number above zero",
+                            name
+                        ),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_auto_ban_options(node: &KdlNode) -> Result<AutoBanOptions, CbltError> {
+    let mut options = AutoBanOptions::default();
+    for child in node.children().iter().flat_map(|children| children.nodes()) {
+        let args = get_string_args(child);
+        match (child.name().value(), &args[..]) {
+            ("statuses", statuses)
+                if !statuses.is_empty() && statuses.iter().all(|status| is_status(status)) =>
+            {
+                options.statuses = statuses.iter().map(|status| status.to_string()).collect();
+            }
+            ("max_failures", [count]) if count.parse::<u32>().is_ok_and(|count| count > 0) => {
+                options.max_failures = count.parse()?;
+            }
+            ("window", [window]) => options.window = *window.parse::<humantime::Duration>()?,
+            ("ban", [ban]) => options.ban = *ban.parse::<humantime::Duration>()?,
+            ("allow", ranges) => {
+                options.allow = ranges
+                    .iter()
+                    .map(|range| range.parse())
+                    .collect::<Result<_, _>>()?
+            }
+            (name, _) => {
+                return Err(CbltError::KdlParseError {
+                    details: format!(
+                        "Invalid auto_ban option '{}', expected statuses, max_failures, window, ban or allow",
+                        name
+                    ),
+                });
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// `[pattern] "allow"|"deny" values...`, without a pattern the rule covers the whole host
+fn access_rule<'a>(args: &'a [&'a str]) -> Option<(&'a str, IpAction, &'a [&'a str])> {
+    let (pattern, rule) = match args.split_first() {
+        Some((first, rest)) if !matches!(*first, "allow" | "deny") => (*first, rest),
+        _ => ("*", args),
+    };
+    match rule.split_first() {
+        Some((&"allow", values)) if !values.is_empty() => Some((pattern, IpAction::Allow, values)),
+        Some((&"deny", values)) if !values.is_empty() => Some((pattern, IpAction::Deny, values)),
+        _ => None,
+    }
+}
+
+fn parse_trusted_proxies(node: &KdlNode, directive: &str) -> Result<Vec<Cidr>, CbltError> {
+    let mut trusted_proxies = Vec::new();
+    for child in node.children().iter().flat_map(|children| children.nodes()) {
+        match child.name().value() {
+            "trusted_proxies" => {
+                for range in get_string_args(child) {
+                    trusted_proxies.push(range.parse()?);
+                }
+            }
+            name => {
+                return Err(CbltError::KdlParseError {
+                    details: format!("Unknown {} option '{}'", directive, name),
+                });
+            }
+        }
+    }
+    Ok(trusted_proxies)
+}
+
+fn parse_rate_limit_options(node: &KdlNode) -> Result<RateLimitOptions, CbltError> {
+    let mut options = RateLimitOptions::default();
+    for child in node.children().iter().flat_map(|children| children.nodes()) {
+        let args = get_string_args(child);
+        match (child.name().value(), &args[..]) {
+            ("key", ["remote_ip"]) => options.key = RateLimitKey::RemoteIp,
+            ("key", ["header", name]) => options.key = RateLimitKey::Header(header_op_name(name)?),
+            ("trusted_proxies", ranges) => {
+                options.trusted_proxies = ranges
+                    .iter()
+                    .map(|range| range.parse())
+                    .collect::<Result<_, _>>()?
+            }
+            ("redis", [url]) => {
+                if redis::Client::open(*url).is_err() {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Invalid rate_limit redis URL '{}'", url),
+                    });
+                }
+                options.redis = Some((*url).into());
+            }
+            (name, _) => {
+                return Err(CbltError::KdlParseError {
+                    details: format!(
+                        "Invalid rate_limit option '{}', expected key \"remote_ip\", key \"header\" <name>, trusted_proxies or redis <url>",
+                        name
+                    ),
+                });
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_throttle_options(node: &KdlNode) -> Result<ThrottleOptions, CbltError> {
+    let mut options = ThrottleOptions::default();
+    for child in node.children().iter().flat_map(|children| children.nodes()) {
+        let args = get_string_args(child);
+        match (child.name().value(), &args[..]) {
+            ("upload", [rate]) => options.upload = Some(parse_rate(rate)?),
+            ("per", ["connection"]) => options.per = ThrottleScope::Connection,
+            ("per", ["route"]) => options.per = ThrottleScope::Route,
+            (name, _) => {
+                return Err(CbltError::KdlParseError {
+                    details: format!(
+                        "Invalid throttle option '{}', expected upload <rate> or per \"connection\"|\"route\"",
+                        name
+                    ),
+                });
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_wasm_options(node: &KdlNode) -> Result<WasmOptions, CbltError> {
+    let mut options = WasmOptions::default();
+    for child in node.children().iter().flat_map(|children| children.nodes()) {
+        let args = get_string_args(child);
+        match (child.name().value(), &args[..]) {
+            ("config", [config]) => options.config = config.to_string(),
+            ("fuel", [fuel]) if fuel.parse::<u64>().is_ok_and(|fuel| fuel > 0) => {
+                options.fuel = fuel.parse()?;
+            }
+            ("memory", [size]) => options.memory = parse_size(size)?,
+            (name, _) => {
+                return Err(CbltError::KdlParseError {
+                    details: format!(
+                        "Invalid wasm option '{}', expected config <string>, fuel <instructions> or memory <size>",
+                        name
+                    ),
+                });
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_layer4_options(node: &KdlNode) -> Result<Layer4Options, CbltError> {
+    let mut options = Layer4Options::default();
+    for child in node.children().iter().flat_map(|children| children.nodes()) {
+        let args = get_string_args(child);
+        match (child.name().value(), &args[..]) {
+            ("connect_timeout", [timeout]) => {
+                options.connect_timeout = *timeout.parse::<humantime::Duration>()?;
+            }
+            ("proxy_protocol", ["v1"]) => options.proxy_protocol = Some(ProxyProtocolVersion::V1),
+            ("proxy_protocol", ["v2"]) => options.proxy_protocol = Some(ProxyProtocolVersion::V2),
+            (name, _) => {
+                return Err(CbltError::KdlParseError {
+                    details: format!(
+                        "Invalid layer4 option '{}', expected connect_timeout <duration> or proxy_protocol \"v1\"|\"v2\"",
+                        name
+                    ),
+                });
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// Bytes per second like "512KiB" or "1MB", "/s" may follow
+fn parse_rate(rate: &str) -> Result<u64, CbltError> {
+    match parse_size(rate.trim_end_matches("/s")) {
+        Ok(rate) if rate > 0 => Ok(rate),
+        _ => Err(CbltError::KdlParseError {
+            details: format!(
+                "Invalid rate '{}', expected bytes per second like \"1MB\"",
+                rate
+            ),
+        }),
+    }
+}
+
+fn parse_forward_proxy_options(node: &KdlNode) -> Result<ForwardProxyOptions, CbltError> {
+    let mut options = ForwardProxyOptions::default();
+    for child in node.children().iter().flat_map(|children| children.nodes()) {
+        let args = get_string_args(child);
+        match (child.name().value(), &args[..]) {
+            ("basic_auth", [name, password]) => options.users.push(ProxyUser {
+                name: name.to_string(),
+                password: (*password).into(),
+            }),
+            ("allow", [_, ..]) => {
+                for target in args {
+                    options.allow.push(target.parse()?);
+                }
+            }
+            ("deny", [_, ..]) => {
+                for target in args {
+                    options.deny.push(target.parse()?);
+                }
+            }
+            ("ports", [_, ..]) => {
+                options.ports = args
+                    .iter()
+                    .map(|port| port.parse())
+                    .collect::<Result<_, _>>()?;
+            }
+            ("connect_timeout", [timeout]) => {
+                options.connect_timeout = *timeout.parse::<humantime::Duration>()?;
+            }
+            (name, _) => {
+                return Err(CbltError::KdlParseError {
+                    details: format!(
+                        "Invalid forward_proxy option '{}', expected basic_auth <user> <password>, allow <destinations>, deny <destinations>, ports <ports> or connect_timeout <duration>",
+                        name
+                    ),
+                });
+            }
+        }
+    }
+    // Otherwise anyone could tunnel anywhere through the host
+    if options.users.is_empty() && options.allow.is_empty() {
+        return Err(CbltError::KdlParseError {
+            details: "forward_proxy needs basic_auth users or allow rules".to_string(),
+        });
+    }
+    Ok(options)
+}
+
+fn parse_forward_auth_options(node: &KdlNode) -> Result<ForwardAuthOptions, CbltError> {
+    let mut options = ForwardAuthOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "uri" => {
+                    if let Some(uri) = args.first() {
+                        options.uri = slash_prefixed(uri);
+                    }
+                }
+                "copy_headers" => {
+                    for field in args {
+                        let copy = match field.split_once('>') {
+                            Some((from, to)) => {
+                                format!("{}>{}", header_op_name(from)?, header_op_name(to)?)
+                            }
+                            None => header_op_name(field)?,
+                        };
+                        options.copy_headers.push(copy);
+                    }
+                }
+                "trusted_proxies" => {
+                    options.trusted_proxies =
+                        args.into_iter().map(str::parse).collect::<Result<_, _>>()?
+                }
+                "timeout" => {
+                    if let Some(timeout) = args.first() {
+                        options.timeout = *timeout.parse::<humantime::Duration>()?;
+                    }
+                }
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown forward_auth option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_fastcgi_options(node: &KdlNode) -> Result<FastcgiOptions, CbltError> {
+    let mut options = FastcgiOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "root" => {
+                    if let Some(root) = args.first() {
+                        options.root = Some(root.to_string());
+                    }
+                }
+                "split" => {
+                    options.split = args.iter().map(|split| split.to_string()).collect();
+                }
+                "index" => match args.first() {
+                    Some(&"off") => options.index = None,
+                    Some(index) => options.index = Some(index.trim_start_matches('/').to_string()),
+                    None => {}
+                },
+                "env" => match args[..] {
+                    [name, value] => {
+                        options.env.insert(name.to_string(), value.to_string());
+                    }
+                    _ => {
+                        return Err(CbltError::KdlParseError {
+                            details: "Invalid 'env' option, expected name and value".to_string(),
+                        });
+                    }
+                },
+                "dial_timeout" => {
+                    if let Some(timeout) = args.first() {
+                        options.dial_timeout = *timeout.parse::<humantime::Duration>()?;
+                    }
+                }
+                "read_timeout" => {
+                    if let Some(timeout) = args.first() {
+                        options.read_timeout = Some(*timeout.parse::<humantime::Duration>()?);
+                    }
+                }
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown php_fastcgi option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// `Name value` sets, `+Name value` adds, `-Name` removes and `Name find replace` replaces
+fn parse_header_op(field: &[&str]) -> Result<HeaderOp, CbltError> {
+    match field {
+        [name] if name.starts_with('-') => Ok(HeaderOp::Remove {
+            name: header_op_name(&name[1..])?,
+        }),
+        [name, value] if name.starts_with('+') => Ok(HeaderOp::Add {
+            name: header_op_name(&name[1..])?,
+            value: value.to_string(),
+        }),
+        [name, value] => Ok(HeaderOp::Set {
+            name: header_op_name(name)?,
+            value: value.to_string(),
+        }),
+        [name, find, replace] => Ok(HeaderOp::Replace {
+            name: header_op_name(name)?,
+            find: find.to_string(),
+            replace: replace.to_string(),
+        }),
+        _ => Err(CbltError::KdlParseError {
+            details: format!(
+                "Invalid header field '{}', expected 'name value', '+name value', '-name' or \
+                 'name find replace'",
+                field.join(" ")
+            ),
+        }),
+    }
+}
+
+/// Conditions in the block of `node`, `not`, `and` and `or` nest blocks of their own
+fn parse_match_conditions(
+    node: &KdlNode,
+    matcher: &str,
+    hostname: &str,
+) -> Result<Vec<MatchCondition>, CbltError> {
+    let invalid = |details: String| CbltError::KdlParseError {
+        details: format!("{} in matcher {} for host {}", details, matcher, hostname),
+    };
+    let mut conditions = Vec::new();
+    for child in node.children().iter().flat_map(|children| children.nodes()) {
+        let args: Vec<String> = get_string_args(child)
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let condition = match (child.name().value(), args.split_first()) {
+            ("path", Some(_)) => MatchCondition::Path(args),
+            ("method", Some(_)) => {
+                MatchCondition::Method(args.iter().map(|m| m.to_uppercase()).collect())
+            }
+            ("header", Some((name, values))) => MatchCondition::Header {
+                name: header_op_name(name)?,
+                values: values.to_vec(),
+            },
+            ("query", Some((name, values))) => MatchCondition::Query {
+                name: name.to_string(),
+                values: values.to_vec(),
+            },
+            ("cookie", Some((mode, rest))) if !rest.is_empty() => {
+                let (name, values) = (rest[0].clone(), rest[1..].to_vec());
+                let name = match mode.as_str() {
+                    "exact" => CookieName::Exact(name),
+                    "prefix" => CookieName::Prefix(name),
+                    "regex" => {
+                        if path_regex(&name).is_none() {
+                            return Err(invalid(format!("Invalid cookie regex '{}'", name)));
+                        }
+                        CookieName::Regex(name)
+                    }
+                    _ => {
+                        return Err(invalid(format!(
+                            "Invalid cookie mode '{}', expected \"exact\", \"prefix\" or \"regex\"",
+                            mode
+                        )))
+                    }
+                };
+                MatchCondition::Cookie { name, values }
+            }
+            ("not", None) => MatchCondition::Not(parse_match_conditions(child, matcher, hostname)?),
+            ("and", None) => MatchCondition::And(parse_match_conditions(child, matcher, hostname)?),
+            ("or", None) => MatchCondition::Or(parse_match_conditions(child, matcher, hostname)?),
+            (name, _) => return Err(invalid(format!("Invalid condition '{}'", name))),
+        };
+        conditions.push(condition);
+    }
+    if conditions.is_empty() {
+        return Err(invalid("No conditions".to_string()));
+    }
+    Ok(conditions)
+}
+
+/// `group` of a reverse_proxy: its upstreams listed by `to`, the other children are conditions
+fn parse_upstream_group(node: &KdlNode, hostname: &str) -> Result<UpstreamGroup, CbltError> {
+    let name = match get_string_args(node).first() {
+        Some(name) => name.to_string(),
+        None => {
+            return Err(CbltError::KdlParseError {
+                details: format!("Unnamed reverse_proxy group for host {}", hostname),
+            })
+        }
+    };
+    let mut upstreams = Vec::new();
+    let mut conditions = node.clone();
+    if let Some(children) = conditions.children_mut() {
+        children.nodes_mut().retain(|child| {
+            if child.name().value() != "to" {
+                return true;
+            }
+            upstreams.extend(get_string_args(child).iter().map(|s| s.to_string()));
+            false
+        });
+    }
+    if let Some(upstream) = upstreams.iter().find(|url| !is_valid_destination(url)) {
+        return Err(CbltError::KdlParseError {
+            details: format!("Invalid upstream URL '{}' in group {}", upstream, name),
+        });
+    }
+    if upstreams.is_empty() {
+        return Err(CbltError::KdlParseError {
+            details: format!("No upstreams in group {} for host {}", name, hostname),
+        });
+    }
+    Ok(UpstreamGroup {
+        conditions: parse_match_conditions(&conditions, &name, hostname)?,
+        name,
+        upstreams,
+    })
+}
+
+/// `wasm` plugins run only in builds with the "wasm" feature
+fn check_wasm(hostname: &str, directives: &[Directive]) -> Result<(), CbltError> {
+    let wasm = directives
+        .iter()
+        .any(|directive| matches!(directive, Directive::Wasm { .. }));
+    if wasm && !cfg!(feature = "wasm") {
+        return Err(CbltError::KdlParseError {
+            details: format!(
+                "'wasm' for host {} needs cblt built with the \"wasm\" feature",
+                hostname
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// A `layer4` host forwards whole connections, so besides it only directives about the listener
+/// and TLS apply
+fn check_layer4(hostname: &str, directives: &[Directive]) -> Result<(), CbltError> {
+    let layer4 = directives
+        .iter()
+        .filter(|directive| matches!(directive, Directive::Layer4 { .. }))
+        .count();
+    if layer4 > 1 {
+        return Err(CbltError::KdlParseError {
+            details: format!("Host {} has more than one 'layer4' directive", hostname),
+        });
+    }
+    let http = directives.iter().any(|directive| {
+        !matches!(
+            directive,
+            Directive::Layer4 { .. }
+                | Directive::TlS { .. }
+                | Directive::TlsAcme { .. }
+                | Directive::TlsOptions { .. }
+                | Directive::ProxyProtocol { .. }
+                | Directive::ConnectionLimits { .. }
+                | Directive::Listen { .. }
+        )
+    });
+    if layer4 == 1 && http {
+        return Err(CbltError::KdlParseError {
+            details: format!(
+                "Host {} forwards connections with 'layer4', it cannot have HTTP directives",
+                hostname
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Every named matcher a directive refers to has to be declared in the host, and country rules need
+/// a `geoip` database
+fn check_matchers(hostname: &str, directives: &[Directive]) -> Result<(), CbltError> {
+    let geoip = directives
+        .iter()
+        .any(|directive| matches!(directive, Directive::GeoIp { .. }));
+    let country = directives
+        .iter()
+        .any(|directive| matches!(directive, Directive::Country { .. }));
+    if country && !geoip {
+        return Err(CbltError::KdlParseError {
+            details: format!(
+                "Country rules without a geoip database for host {}",
+                hostname
+            ),
+        });
+    }
+    let declared: Vec<&str> = directives
+        .iter()
+        .filter_map(|directive| match directive {
+            Directive::Matcher { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    for pattern in directives.iter().filter_map(Directive::pattern) {
+        if pattern.starts_with('@') && !declared.contains(&pattern) {
+            return Err(CbltError::KdlParseError {
+                details: format!("Unknown matcher '{}' for host {}", pattern, hostname),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn header_op_name(name: &str) -> Result<String, CbltError> {
+    match http::HeaderName::from_bytes(name.as_bytes()) {
+        Ok(name) => Ok(name.as_str().to_string()),
+        Err(_) => Err(CbltError::KdlParseError {
+            details: format!("Invalid header name '{}'", name),
+        }),
+    }
+}
+
+fn parse_listen_options(node: &KdlNode) -> Result<ListenOptions, CbltError> {
+    let mut options = ListenOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "mode" => {
+                    let mode = args.first().unwrap_or(&"");
+                    options.mode = Some(u32::from_str_radix(mode, 8).map_err(|_| {
+                        CbltError::KdlParseError {
+                            details: format!("Invalid listen mode '{}', expected octal", mode),
+                        }
+                    })?);
+                }
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown listen option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn parse_tls_version(args: &[&str]) -> Result<TlsVersion, CbltError> {
+    match args.first() {
+        Some(&"1.2") => Ok(TlsVersion::Tls12),
+        Some(&"1.3") => Ok(TlsVersion::Tls13),
+        _ => Err(CbltError::KdlParseError {
+            details: "Invalid TLS version, expected \"1.2\" or \"1.3\"".to_string(),
+        }),
+    }
+}
+
+fn parse_acme_options(node: &KdlNode) -> Result<AcmeOptions, CbltError> {
+    let mut options = AcmeOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            if name == "on_demand" {
+                options.on_demand = Some(parse_on_demand_options(child)?);
+                continue;
+            }
+            let Some(value) = get_string_args(child).first().map(|v| v.to_string()) else {
+                return Err(CbltError::KdlParseError {
+                    details: format!("Missing value for tls acme option '{}'", name),
+                });
+            };
+            match name {
+                "email" => options.email = Some(value),
+                "ca" => options.ca = value,
+                "storage" => options.storage = value,
+                "challenge" => {
+                    options.challenge = match value.as_str() {
+                        "http-01" => AcmeChallenge::Http01,
+                        "tls-alpn-01" => AcmeChallenge::TlsAlpn01,
+                        "dns-01" => AcmeChallenge::Dns01,
+                        _ => {
+                            return Err(CbltError::KdlParseError {
+                                details: format!(
+                                    "Invalid ACME challenge '{}', expected \"http-01\", \"tls-alpn-01\" or \"dns-01\"",
+                                    value
+                                ),
+                            });
+                        }
+                    }
+                }
+                "dns" => options.dns = Some(parse_dns_options(child, &value)?),
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown tls acme option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    // A DNS provider implies the DNS-01 challenge
+    if options.dns.is_some() {
+        options.challenge = AcmeChallenge::Dns01;
+    } else if options.challenge == AcmeChallenge::Dns01 {
+        return Err(CbltError::KdlParseError {
+            details: "The dns-01 challenge needs a 'dns' provider".to_string(),
+        });
+    }
+    Ok(options)
+}
+
+fn parse_on_demand_options(node: &KdlNode) -> Result<OnDemandOptions, CbltError> {
+    let mut options = OnDemandOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "allow" => options
+                    .allow
+                    .extend(args.iter().map(|name| name.to_ascii_lowercase())),
+                "ask" => options.ask = args.first().map(|url| url.to_string()),
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown on_demand option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    if options.allow.is_empty() && options.ask.is_none() {
+        return Err(CbltError::KdlParseError {
+            details: "on_demand needs an 'allow' list or an 'ask' endpoint".to_string(),
+        });
+    }
+    Ok(options)
+}
+
+fn parse_dns_options(node: &KdlNode, provider: &str) -> Result<DnsOptions, CbltError> {
+    let mut values = HashMap::new();
+    let mut propagation_delay = None;
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            let Some(value) = get_string_args(child).first().map(|v| v.to_string()) else {
+                return Err(CbltError::KdlParseError {
+                    details: format!("Missing value for dns option '{}'", name),
+                });
+            };
+            if name == "propagation_delay" {
+                propagation_delay = Some(*value.parse::<humantime::Duration>()?);
+            } else {
+                values.insert(name, value);
+            }
+        }
+    }
+
+    let mut take = |name: &str| {
+        values.remove(name).ok_or_else(|| CbltError::KdlParseError {
+            details: format!("Missing dns option '{}' for {}", name, provider),
+        })
+    };
+    let provider_options = match provider {
+        "cloudflare" => DnsProviderOptions::Cloudflare {
+            api_token: take("api_token")?.into(),
+            zone_id: take("zone_id").ok(),
+        },
+        "route53" => DnsProviderOptions::Route53 {
+            access_key_id: take("access_key_id")?,
+            secret_access_key: take("secret_access_key")?.into(),
+            hosted_zone_id: take("hosted_zone_id")?,
+        },
+        "rfc2136" => DnsProviderOptions::Rfc2136 {
+            server: take("server")?,
+            zone: take("zone")?,
+            key_name: take("key_name")?,
+            key_secret: take("key_secret")?.into(),
+        },
+        _ => {
+            return Err(CbltError::KdlParseError {
+                details: format!(
+                    "Unknown dns provider '{}', expected \"cloudflare\", \"route53\" or \"rfc2136\"",
+                    provider
+                ),
+            });
+        }
+    };
+    if let Some(name) = values.keys().next() {
+        return Err(CbltError::KdlParseError {
+            details: format!("Unknown dns option '{}' for {}", name, provider),
+        });
+    }
+    Ok(DnsOptions {
+        provider: provider_options,
+        propagation_delay,
+    })
+}
+
+fn parse_access_log_options(node: &KdlNode) -> Result<AccessLogOptions, CbltError> {
+    let mut options = AccessLogOptions {
+        output: get_string_args(node).first().map(|path| path.to_string()),
+        ..Default::default()
+    };
+
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            match name {
+                "format" => match get_string_args(child).first() {
+                    Some(&"common") => options.format = AccessLogFormat::Common,
+                    Some(&"combined") => options.format = AccessLogFormat::Combined,
+                    _ => {
+                        return Err(CbltError::KdlParseError {
+                            details: "Invalid 'format' option, expected \"common\" or \"combined\""
+                                .to_string(),
+                        });
+                    }
+                },
+                "roll_size" => {
+                    if let Some(size) = get_string_args(child).first() {
+                        options.roll.max_size = Some(parse_size(size)?);
+                    }
+                }
+                "roll_interval" => {
+                    if let Some(interval) = get_string_args(child).first() {
+                        options.roll.interval = Some(*interval.parse::<humantime::Duration>()?);
+                    }
+                }
+                "roll_keep" => {
+                    if let Some(keep) = get_string_args(child).first() {
+                        options.roll.keep = keep.parse()?;
+                    }
+                }
+                "roll_gzip" => {
+                    if let Some(gzip) = get_string_args(child).first() {
+                        options.roll.gzip = *gzip == "true";
+                    }
+                }
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown access_log option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_tracing_options(node: &KdlNode) -> Result<TracingOptions, CbltError> {
+    let mut options = TracingOptions::default();
+    if let Some(endpoint) = get_string_args(node).first() {
+        options.endpoint = endpoint.to_string();
+    }
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "service_name" => {
+                    if let Some(service_name) = args.first() {
+                        options.service_name = service_name.to_string();
+                    }
+                }
+                "sample" => {
+                    options.sample = args
+                        .first()
+                        .and_then(|sample| sample.parse::<f64>().ok())
+                        .filter(|sample| (0.0..=1.0).contains(sample))
+                        .ok_or_else(|| CbltError::KdlParseError {
+                            details: "Invalid 'sample' option, expected a ratio from 0 to 1"
+                                .to_string(),
+                        })?;
+                }
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown tracing option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// Replaces `{$VAR}` and `{$VAR:default}` placeholders with environment variables.
+/// Placeholders live inside KDL strings, so the values are escaped for them.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn substitute_env(source: &str) -> Result<String, CbltError> {
+    substitute_vars(source, |name| std::env::var(name).ok())
+}
+
+fn substitute_vars(
+    source: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, CbltError> {
+    let mut result = String::with_capacity(source.len());
+    let mut rest = source;
+    while let Some(start) = rest.find("{$") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        let placeholder = &rest[start + 2..start + end];
+        let (name, default) = match placeholder.split_once(':') {
+            Some((name, default)) => (name, Some(default)),
+            None => (placeholder, None),
+        };
+        let value = match (lookup(name), default) {
+            (Some(value), _) => value,
+            (None, Some(default)) => default.to_string(),
+            (None, None) => {
+                return Err(CbltError::EnvVarNotSet {
+                    name: name.to_string(),
+                    offset: source.len() - rest.len() + start,
+                });
+            }
+        };
+        result.push_str(&rest[..start]);
+        result.push_str(&value.replace('\\', "\\\\").replace('"', "\\\""));
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Parses sizes like "1048576", "512KiB", "100MiB" or "1GB"
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn parse_size(size: &str) -> Result<u64, CbltError> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "kib" | "k" => 1024,
+        "mb" => 1000 * 1000,
+        "mib" | "m" => 1024 * 1024,
+        "gb" => 1000 * 1000 * 1000,
+        "gib" | "g" => 1024 * 1024 * 1024,
+        _ => {
+            return Err(CbltError::KdlParseError {
+                details: format!("Invalid size '{}'", size),
+            });
+        }
+    };
+    Ok(number.parse::<u64>()? * multiplier)
+}
+
+/// "404" or a whole class like "5xx"
+fn is_status(arg: &str) -> bool {
+    arg.len() == 3
+        && arg.starts_with(|c: char| ('1'..='5').contains(&c))
+        && (arg[1..].chars().all(|c| c.is_ascii_digit()) || &arg[1..] == "xx")
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_error_page(node: &KdlNode, hostname: &str) -> Result<(Vec<String>, ErrorPage), CbltError> {
+    let invalid = || CbltError::KdlParseError {
+        details: format!("Invalid 'error_page' directive for host {}", hostname),
+    };
+    let args = get_string_args(node);
+    let statuses: Vec<String> = args
+        .iter()
+        .take_while(|arg| is_status(arg))
+        .map(|arg| arg.to_string())
+        .collect();
+    let file = match &args[statuses.len()..] {
+        [] => None,
+        [file] => Some(file.to_string()),
+        _ => return Err(invalid()),
+    };
+    let mut body = None;
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            match name {
+                "body" => {
+                    body = get_string_args(child).first().map(|body| body.to_string());
+                }
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown error_page option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+
+    let page = match (file, body) {
+        (Some(file), None) => ErrorPage::File(file),
+        (None, Some(body)) => ErrorPage::Inline(body),
+        _ => return Err(invalid()),
+    };
+    if statuses.is_empty() {
+        return Err(invalid());
+    }
+    Ok((statuses, page))
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_encoding(name: &str) -> Result<Encoding, CbltError> {
+    match name {
+        "gzip" => Ok(Encoding::Gzip),
+        "br" => Ok(Encoding::Brotli),
+        "zstd" => Ok(Encoding::Zstd),
+        _ => Err(CbltError::KdlParseError {
+            details: format!("Unknown encoding '{}'", name),
+        }),
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_cookie_options(node: &KdlNode) -> Result<CookieOptions, CbltError> {
+    let mut options = CookieOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            let args = get_string_args(child);
+            let Some(value) = args.first() else {
+                continue;
+            };
+            match name {
+                "lb_cookie_name" => options.name = value.to_string(),
+                "lb_cookie_path" => options.path = value.to_string(),
+                "lb_cookie_max_age" => {
+                    // Plain seconds or a duration such as "1h"
+                    options.max_age = Some(match value.parse::<u64>() {
+                        Ok(seconds) => seconds,
+                        Err(_) => value.parse::<humantime::Duration>()?.as_secs(),
+                    })
+                }
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown lb_policy cookie option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    if options.name.is_empty()
+        || !options
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(CbltError::KdlParseError {
+            details: format!("Invalid lb_cookie_name '{}'", options.name),
+        });
+    }
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_reverse_proxy_options(
+    node: &KdlNode,
+    hostname: &str,
+) -> Result<ReverseProxyOptions, CbltError> {
+    let mut options = ReverseProxyOptions::default();
+
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let name = child.name().value();
+            match name {
+                "to" => {}
+                "lb_retries" => {
+                    let args = get_string_args(child);
+                    if let Some(retries) = args.first() {
+                        options.lb_retries = retries.parse()?;
+                    } else {
+                        options.lb_retries = 2;
+                    }
+                }
+                "lb_interval" => {
+                    let args = get_string_args(child);
+                    if let Some(interval) = args.first() {
+                        options.lb_interval = interval.parse::<humantime::Duration>()?.as_secs();
+                    } else {
+                        options.lb_interval = 10;
+                    }
+                }
+                "lb_timeout" => {
+                    let args = get_string_args(child);
+                    if let Some(timeout) = args.first() {
+                        options.lb_timeout = timeout.parse::<humantime::Duration>()?.as_secs();
+                    } else {
+                        options.lb_timeout = 1;
+                    }
+                }
+                "connect_timeout" => {
+                    let args = get_string_args(child);
+                    if let Some(timeout) = args.first() {
+                        options.lb_timeout =
+                            timeout.parse::<humantime::Duration>()?.as_secs().max(1);
+                    }
+                }
+                "header_timeout" => {
+                    let args = get_string_args(child);
+                    if let Some(timeout) = args.first() {
+                        options.header_timeout = Some(*timeout.parse::<humantime::Duration>()?);
+                    }
+                }
+                "timeout" => {
+                    let args = get_string_args(child);
+                    if let Some(timeout) = args.first() {
+                        options.timeout = Some(*timeout.parse::<humantime::Duration>()?);
+                    }
+                }
+                "trusted_proxies" => {
+                    options.trusted_proxies = get_string_args(child)
+                        .into_iter()
+                        .map(str::parse)
+                        .collect::<Result<_, _>>()?;
+                }
+                "forward_headers" => {
+                    let args = get_string_args(child);
+                    if let Some(mode) = args.first() {
+                        options.forward_headers = match *mode {
+                            "x_forwarded" => ForwardHeaders::XForwarded,
+                            "forwarded" => ForwardHeaders::Forwarded,
+                            "both" => ForwardHeaders::Both,
+                            _ => {
+                                return Err(CbltError::KdlParseError {
+                                    details: format!("Unknown forward_headers '{}'", mode),
+                                });
+                            }
+                        };
+                    }
+                }
+                "header_up" => {
+                    let args = get_string_args(child);
+                    options.header_up.push(parse_header_op(&args)?);
+                }
+                "header_down" => {
+                    let args = get_string_args(child);
+                    options.header_down.push(parse_header_op(&args)?);
+                }
+                "proxy_protocol" => {
+                    let args = get_string_args(child);
+                    options.proxy_protocol = match args.first() {
+                        Some(&"v1") => Some(ProxyProtocolVersion::V1),
+                        Some(&"v2") => Some(ProxyProtocolVersion::V2),
+                        _ => {
+                            return Err(CbltError::KdlParseError {
+                                details: "proxy_protocol takes \"v1\" or \"v2\"".to_string(),
+                            });
+                        }
+                    };
+                }
+                "tls_trusted_ca_certs" => {
+                    options.tls_trusted_ca_certs = get_string_args(child)
+                        .into_iter()
+                        .map(PathBuf::from)
+                        .collect();
+                }
+                "tls_insecure_skip_verify" => {
+                    options.tls_insecure_skip_verify = true;
+                }
+                "tls_server_name" => {
+                    let args = get_string_args(child);
+                    if let Some(name) = args.first() {
+                        if rustls::pki_types::ServerName::try_from(*name).is_err() {
+                            return Err(CbltError::KdlParseError {
+                                details: format!("Invalid tls_server_name '{}'", name),
+                            });
+                        }
+                        options.tls_server_name = Some(name.to_string());
+                    }
+                }
+                "lb_policy" => {
+                    let args = get_string_args(child);
+                    if let Some(policy_name) = args.first() {
+                        match *policy_name {
+                            "round_robin" => {
+                                options.lb_policy = Some(LoadBalancePolicy::RoundRobin);
+                            }
+                            "ip_hash" => {
+                                options.lb_policy = Some(LoadBalancePolicy::IPHash);
+                            }
+                            "least_conn" => {
+                                options.lb_policy = Some(LoadBalancePolicy::LeastConn);
+                            }
+                            "random" => {
+                                options.lb_policy = Some(LoadBalancePolicy::Random);
+                            }
+                            "cookie" => {
+                                options.lb_policy =
+                                    Some(LoadBalancePolicy::Cookie(parse_cookie_options(child)?));
+                            }
+                            _ => {
+                                return Err(CbltError::KdlParseError {
+                                    details: format!("Unknown lb_policy '{}'", policy_name),
+                                });
+                            }
+                        }
+                    }
+                }
+                "lb_max_fails" => {
+                    let args = get_string_args(child);
+                    if let Some(max_fails) = args.first() {
+                        options.lb_max_fails = max_fails.parse::<u64>()?.max(1);
+                    }
+                }
+                "lb_max_backoff" => {
+                    let args = get_string_args(child);
+                    if let Some(backoff) = args.first() {
+                        options.lb_max_backoff = backoff.parse::<humantime::Duration>()?.as_secs();
+                    }
+                }
+                "retries" => {
+                    let args = get_string_args(child);
+                    if let Some(retries) = args.first() {
+                        options.retries = Some(retries.parse()?);
+                    }
+                }
+                "retry_on" => {
+                    options.retry_on = get_string_args(child)
+                        .into_iter()
+                        .map(|condition| match condition {
+                            "connect_failure" => Ok(RetryOn::ConnectFailure),
+                            "timeout" => Ok(RetryOn::Timeout),
+                            status => status.parse().map(RetryOn::Status).map_err(|_| {
+                                CbltError::KdlParseError {
+                                    details: format!("Unknown retry_on condition '{}'", status),
+                                }
+                            }),
+                        })
+                        .collect::<Result<_, _>>()?;
+                }
+                "pool_max_idle" => {
+                    let args = get_string_args(child);
+                    if let Some(max_idle) = args.first() {
+                        options.pool_max_idle = max_idle.parse()?;
+                    }
+                }
+                "pool_idle_timeout" => {
+                    let args = get_string_args(child);
+                    if let Some(idle_timeout) = args.first() {
+                        options.pool_idle_timeout =
+                            idle_timeout.parse::<humantime::Duration>()?.as_secs();
+                    }
+                }
+                "flush_interval" => {
+                    let args = get_string_args(child);
+                    if let Some(interval) = args.first() {
+                        options.flush_interval = Some(*interval.parse::<humantime::Duration>()?);
+                    }
+                }
+                "health_uri" => {
+                    let args = get_string_args(child);
+                    if let Some(uri) = args.first() {
+                        if !uri.starts_with('/') {
+                            return Err(CbltError::KdlParseError {
+                                details: format!("health_uri must start with '/': '{}'", uri),
+                            });
+                        }
+                        options.health_uri = Some(uri.to_string());
+                    }
+                }
+                "mirror" => {
+                    let args = get_string_args(child);
+                    if let Some(mirror) = args.first() {
+                        if !is_valid_destination(mirror) {
+                            return Err(CbltError::KdlParseError {
+                                details: format!("Invalid mirror URL '{}'", mirror),
+                            });
+                        }
+                        options.mirror = Some(mirror.to_string());
+                    }
+                }
+                "canary" => {
+                    let args = get_string_args(child);
+                    if let [upstream, percent] = args[..] {
+                        if !is_valid_destination(upstream) {
+                            return Err(CbltError::KdlParseError {
+                                details: format!("Invalid canary URL '{}'", upstream),
+                            });
+                        }
+                        options.canary = Some(CanaryOptions {
+                            upstream: upstream.to_string(),
+                            percent: parse_percent(percent)?,
+                        });
+                    } else {
+                        return Err(CbltError::KdlParseError {
+                            details: "canary takes an upstream and a percentage".to_string(),
+                        });
+                    }
+                }
+                "health_interval" => {
+                    let args = get_string_args(child);
+                    if let Some(interval) = args.first() {
+                        options.health_interval =
+                            interval.parse::<humantime::Duration>()?.as_secs().max(1);
+                    }
+                }
+                "health_timeout" => {
+                    let args = get_string_args(child);
+                    if let Some(timeout) = args.first() {
+                        options.health_timeout =
+                            timeout.parse::<humantime::Duration>()?.as_secs().max(1);
+                    }
+                }
+                "health_status" => {
+                    let args = get_string_args(child);
+                    if let Some(status) = args.first() {
+                        options.health_status = Some(status.parse()?);
+                    }
+                }
+                "cache" => options.cache = Some(parse_cache_options(child)?),
+                "dns_discovery" => {
+                    options.dns_discovery = Some(parse_dns_discovery_options(child)?)
+                }
+                "consul" => options.consul = parse_consul_options(child)?,
+                "group" => options.groups.push(parse_upstream_group(child, hostname)?),
+                "backup" => {
+                    for url in get_string_args(child) {
+                        if !is_valid_destination(url) {
+                            return Err(CbltError::KdlParseError {
+                                details: format!("Invalid backup URL '{}'", url),
+                            });
+                        }
+                        options.backups.push(url.to_string());
+                    }
+                }
+                _ => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown reverse_proxy option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_dns_discovery_options(node: &KdlNode) -> Result<DnsDiscoveryOptions, CbltError> {
+    let mut options = DnsDiscoveryOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "resolver" => {
+                    if let Some(resolver) = args.first() {
+                        options.resolver = Some(match resolver.parse::<IpAddr>() {
+                            Ok(ip) => SocketAddr::new(ip, 53),
+                            Err(_) => resolver.parse().map_err(|_| CbltError::KdlParseError {
+                                details: format!("Invalid DNS resolver '{}'", resolver),
+                            })?,
+                        });
+                    }
+                }
+                "min_refresh" => {
+                    if let Some(refresh) = args.first() {
+                        options.min_refresh =
+                            refresh.parse::<humantime::Duration>()?.as_secs().max(1);
+                    }
+                }
+                "max_refresh" => {
+                    if let Some(refresh) = args.first() {
+                        options.max_refresh =
+                            refresh.parse::<humantime::Duration>()?.as_secs().max(1);
+                    }
+                }
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown dns_discovery option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    options.max_refresh = options.max_refresh.max(options.min_refresh);
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_consul_options(node: &KdlNode) -> Result<ConsulOptions, CbltError> {
+    let mut options = ConsulOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            let Some(value) = args.first().map(|value| value.to_string()) else {
+                continue;
+            };
+            match child.name().value() {
+                "address" => {
+                    if !value.starts_with("http://") && !value.starts_with("https://") {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Invalid Consul address '{}'", value),
+                        });
+                    }
+                    options.address = value.trim_end_matches('/').to_string();
+                }
+                "token" => options.token = Some(value.into()),
+                "datacenter" => options.datacenter = Some(value),
+                "tag" => options.tag = Some(value),
+                "scheme" => {
+                    if !["http", "https", "uwsgi", "scgi"].contains(&value.as_str()) {
+                        return Err(CbltError::KdlParseError {
+                            details: format!("Invalid Consul instance scheme '{}'", value),
+                        });
+                    }
+                    options.scheme = value;
+                }
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown consul option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_cache_options(node: &KdlNode) -> Result<CacheOptions, CbltError> {
+    let mut options = CacheOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "max_size" => {
+                    if let Some(size) = args.first() {
+                        options.max_size = parse_size(size)?;
+                    }
+                }
+                "max_object_size" => {
+                    if let Some(size) = args.first() {
+                        options.max_object_size = parse_size(size)?;
+                    }
+                }
+                "ttl" => {
+                    if let Some(ttl) = args.first() {
+                        options.ttl = Some(*ttl.parse::<humantime::Duration>()?);
+                    }
+                }
+                "stale_while_revalidate" => {
+                    if let Some(stale) = args.first() {
+                        options.stale_while_revalidate =
+                            Some(*stale.parse::<humantime::Duration>()?);
+                    }
+                }
+                "stale_if_error" => {
+                    if let Some(stale) = args.first() {
+                        options.stale_if_error = Some(*stale.parse::<humantime::Duration>()?);
+                    }
+                }
+                "purge_allow" => {
+                    options.purge_allow =
+                        args.into_iter().map(str::parse).collect::<Result<_, _>>()?
+                }
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown cache option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn parse_file_cache_options(node: &KdlNode) -> Result<FileCacheOptions, CbltError> {
+    let mut options = FileCacheOptions::default();
+    if let Some(children) = node.children() {
+        for child in children.nodes() {
+            let args = get_string_args(child);
+            match child.name().value() {
+                "max_file_size" => {
+                    if let Some(size) = args.first() {
+                        options.max_file_size = parse_size(size)?;
+                    }
+                }
+                "max_size" => {
+                    if let Some(size) = args.first() {
+                        options.max_size = parse_size(size)?;
+                    }
+                }
+                name => {
+                    return Err(CbltError::KdlParseError {
+                        details: format!("Unknown file_server cache option '{}'", name),
+                    });
+                }
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// Configuration of `cblt file-server`: one catch-all host serving `root`
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn file_server_config(
+    root: &str,
+    listen: &str,
+) -> Result<HashMap<String, Vec<Directive>>, CbltError> {
+    let directives = vec![
+        Directive::Root {
+            pattern: "*".to_string(),
+            path: root.to_string(),
+        },
+        Directive::FileServer {
+            options: FileServerOptions::default(),
+        },
+    ];
+    Ok(HashMap::from([(listen_host(listen)?, directives)]))
+}
+
+/// Configuration of `cblt reverse-proxy`: one catch-all host proxying everything to `to`
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn reverse_proxy_config(
+    from: &str,
+    to: &str,
+) -> Result<HashMap<String, Vec<Directive>>, CbltError> {
+    if !is_valid_destination(to) {
+        return Err(CbltError::KdlParseError {
+            details: format!("Invalid upstream URL '{}'", to),
+        });
+    }
+    let directives = vec![Directive::ReverseProxy {
+        pattern: "*".to_string(),
+        destinations: vec![to.to_string()],
+        options: ReverseProxyOptions::default(),
+    }];
+    Ok(HashMap::from([(listen_host(from)?, directives)]))
+}
+
+/// Catch-all host for a listen address like ":8080", "8080" or "localhost:8080"
+fn listen_host(listen: &str) -> Result<String, CbltError> {
+    let port = listen.rsplit(':').next().unwrap_or(listen);
+    match port.parse::<u16>() {
+        Ok(port) => Ok(format!("*:{}", port)),
+        Err(_) => Err(CbltError::InvalidListenAddress {
+            details: listen.to_string(),
+        }),
+    }
+}
+
+/// Reads a Cbltfile together with the files it imports, or a JSON configuration
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn load_config(path: &Path) -> Result<HashMap<String, Vec<Directive>>, CbltError> {
+    if is_json(path) {
+        return parse_json_config(&std::fs::read_to_string(path)?);
+    }
+    let mut doc = KdlDocument::new();
+    collect_nodes(path, &mut Vec::new(), doc.nodes_mut())?;
+    build_config(&doc)
+}
+
+pub fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+}
+
+/// JSON configuration: an object mapping hosts to their directives, e.g.
+/// `{"*:80": [{"root": {"pattern": "*", "path": "./assets"}}, {"file_server": {}}]}`
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn parse_json_config(source: &str) -> Result<HashMap<String, Vec<Directive>>, CbltError> {
+    let mut hosts: HashMap<String, Vec<Directive>> =
+        serde_json::from_str(&substitute_env(source)?)?;
+    if let Some((hostname, _)) = hosts.iter().find(|(_, directives)| directives.is_empty()) {
+        return Err(CbltError::KdlParseError {
+            details: format!("No directives specified for host {}", hostname),
+        });
+    }
+    for (hostname, directives) in &hosts {
+        check_matchers(hostname, directives)?;
+        check_wasm(hostname, directives)?;
+        check_layer4(hostname, directives)?;
+    }
+    build_handlers(&mut hosts)?;
+    Ok(hosts)
+}
+
+/// Appends the host nodes of `path`, replacing `import` nodes with the imported hosts
+fn collect_nodes(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    nodes: &mut Vec<KdlNode>,
+) -> Result<(), CbltError> {
+    let canonical = path.canonicalize()?;
+    if stack.contains(&canonical) {
+        return Err(CbltError::KdlParseError {
+            details: format!("Import cycle through '{}'", path.display()),
+        });
+    }
+    let content = std::fs::read_to_string(path)?;
+    let doc: KdlDocument = substitute_env(&content)?.parse()?;
+
+    stack.push(canonical);
+    for node in doc.nodes() {
+        if node.name().value() == "import" {
+            for pattern in get_string_args(node) {
+                for import in resolve_import(path, pattern)? {
+                    collect_nodes(&import, stack, nodes)?;
+                }
+            }
+        } else {
+            nodes.push(node.clone());
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Files matched by an import pattern relative to the importing file.
+/// Wildcards are allowed in the file name, e.g. "sites/*.kdl".
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn resolve_import(from: &Path, pattern: &str) -> Result<Vec<PathBuf>, CbltError> {
+    let pattern = from.parent().unwrap_or(Path::new("")).join(pattern);
+    let file_pattern = match pattern.file_name().and_then(|name| name.to_str()) {
+        Some(name) if name.contains(['*', '?']) => name,
+        _ => return Ok(vec![pattern]),
+    };
+    let dir = match pattern.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_file()
+            && name
+                .to_str()
+                .is_some_and(|name| wildcard_match(file_pattern, name))
+        {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut pattern_chars = pattern.chars();
+    match pattern_chars.next() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len())
+            .filter(|&i| name.is_char_boundary(i))
+            .any(|i| wildcard_match(pattern_chars.as_str(), &name[i..])),
+        Some(p) => {
+            let mut name_chars = name.chars();
+            match name_chars.next() {
+                Some(n) if p == '?' || p == n => {
+                    wildcard_match(pattern_chars.as_str(), name_chars.as_str())
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn load_servers_from_config(args: Arc<Args>) -> Result<HashMap<u16, Server>, CbltError> {
+    let config = load_config(&args.config_path)?;
+
+    build_servers(config)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn load_servers_from_docker(args: Arc<Args>) -> Result<HashMap<u16, Server>, CbltError> {
+    build_servers(load_docker_config(args).await?)
+}
+
+/// Labels of a Swarm service or container
+type Labels = HashMap<String, String>;
+
+/// Hosts and directives from the `cblt.*` labels of Swarm services and of running containers
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn load_docker_config(
+    _args: Arc<Args>,
+) -> Result<HashMap<String, Vec<Directive>>, CbltError> {
+    use bollard::Docker;
+    let docker = Docker::connect_with_local_defaults()?;
+    use std::default::Default;
+
+    let options = Some(ListServicesOptions::<String> {
+        filters: HashMap::new(),
+        ..Default::default()
+    });
+
+    let services = match docker.list_services(options).await {
+        Ok(services) => services,
+        // Not a Swarm manager, only containers then
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 503, ..
+        }) => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+
+    // Map to hold the directives per host
+    let mut hosts: HashMap<String, Vec<Directive>> = HashMap::new();
+    for service in services {
+        // Process each service
+        if let Some(spec) = service.spec {
+            if let Some(labels) = spec.labels {
+                // Check if labels have keys starting with "cblt."
+                if labels.keys().any(|k| k.starts_with("cblt.")) {
+                    // Get service name
+                    let service_name = spec.name.ok_or(CbltError::ServiceNameNotFound)?;
+                    let mut destinations: Vec<String> = Vec::new();
+                    let containers = docker
+                        .list_containers(Some(ListContainersOptions::<String> {
+                            all: false,
+                            filters: HashMap::new(),
+                            ..Default::default()
+                        }))
+                        .await?;
+                    for container in &containers {
+                        if let Some(names) = &container.names {
+                            match names
+                                .iter()
+                                .find(|name| name.starts_with(&format!("/{}.", service_name)))
+                            {
+                                None => {}
+                                Some(name_all) => {
+                                    let container_name = name_all.replace("/", "");
+                                    debug!("{container_name}");
+                                    destinations.push(container_name);
+                                }
+                            }
+                        } else {
+                            return Err(CbltError::ContainerNameNotFound);
+                        }
+                    }
+
+                    add_labeled_routes(&labels, destinations, &mut hosts)?;
+                }
+            }
+        }
+    }
+
+    // Containers started by `docker run` or Compose, the ones with the same route share it
+    let containers = docker
+        .list_containers(Some(ListContainersOptions::<String> {
+            all: false,
+            filters: HashMap::from([("label".to_string(), vec!["cblt.hosts".to_string()])]),
+            ..Default::default()
+        }))
+        .await?;
+    let mut routes: BTreeMap<(String, String), (Labels, Vec<String>)> = BTreeMap::new();
+    for container in containers {
+        let Some(mut labels) = container.labels.clone() else {
+            continue;
+        };
+        // Tasks of a Swarm service are routed by the service labels
+        if labels.contains_key("com.docker.swarm.service.id") {
+            continue;
+        }
+        // Not before its health check passes
+        let status = container.status.as_deref().unwrap_or_default();
+        if status.contains("(unhealthy)") || status.contains("(health: starting)") {
+            continue;
+        }
+        let Some(address) = container_address(&container, labels.get("cblt.network")) else {
+            continue;
+        };
+        labels
+            .entry("cblt.path".to_string())
+            .or_insert_with(|| "/*".to_string());
+        if !labels.contains_key("cblt.port") {
+            // The one port the image exposes
+            let mut ports: Vec<u16> = container
+                .ports
+                .iter()
+                .flatten()
+                .map(|port| port.private_port)
+                .collect();
+            ports.sort();
+            ports.dedup();
+            if let [port] = ports[..] {
+                labels.insert("cblt.port".to_string(), port.to_string());
+            }
+        }
+        let route = (labels["cblt.hosts"].clone(), labels["cblt.path"].clone());
+        let (_, destinations) = routes.entry(route).or_insert_with(|| (labels, Vec::new()));
+        destinations.push(address);
+    }
+    for (labels, mut destinations) in routes.into_values() {
+        destinations.sort();
+        if let Err(err) = add_labeled_routes(&labels, destinations, &mut hosts) {
+            // One misconfigured container leaves the others routed
+            error!("Error: {}", err);
+        }
+    }
+
+    Ok(hosts)
+}
+
+/// Wakes `changes` up whenever a container or Swarm service comes, goes or changes its health,
+/// reconnecting to the Docker daemon after it was lost
+pub async fn watch_docker_events(changes: Arc<Notify>) {
+    loop {
+        match bollard::Docker::connect_with_local_defaults() {
+            Ok(docker) => {
+                let mut events = docker.events(Some(EventsOptions::<String> {
+                    filters: HashMap::from([
+                        (
+                            "type".to_string(),
+                            vec!["container".to_string(), "service".to_string()],
+                        ),
+                        (
+                            "event".to_string(),
+                            [
+                                "start",
+                                "die",
+                                "health_status",
+                                "create",
+                                "update",
+                                "remove",
+                            ]
+                            .map(str::to_string)
+                            .to_vec(),
+                        ),
+                    ]),
+                    ..Default::default()
+                }));
+                while let Some(event) = events.next().await {
+                    if let Err(err) = event {
+                        error!("Error: {}", err);
+                        break;
+                    }
+                    changes.notify_one();
+                }
+            }
+            Err(err) => error!("Error: {}", err),
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// `http://<address>` of a container on the network of its `cblt.network` label, or on its first one
+fn container_address(
+    container: &bollard::models::ContainerSummary,
+    network: Option<&String>,
+) -> Option<String> {
+    let networks = container.network_settings.as_ref()?.networks.as_ref()?;
+    let mut candidates: Vec<(&String, &bollard::models::EndpointSettings)> =
+        networks.iter().collect();
+    candidates.sort_by_key(|(name, _)| *name);
+    let ip = candidates
+        .into_iter()
+        .filter(|(name, _)| network.is_none_or(|network| network == *name))
+        .find_map(|(_, endpoint)| endpoint.ip_address.clone().filter(|ip| !ip.is_empty()))?;
+    Some(format!("http://{}", ip))
+}
+
+/// Adds the `reverse_proxy` the `cblt.*` labels describe for their hosts, and their TLS secrets
+fn add_labeled_routes(
+    labels: &Labels,
+    destinations: Vec<String>,
+    hosts: &mut HashMap<String, Vec<Directive>>,
+) -> Result<(), CbltError> {
+    // Process the labels
+    let hosts_label = labels
+        .get("cblt.hosts")
+        .ok_or_else(|| CbltError::LabelNotFound {
+            details: "cblt.hosts".to_string(),
+        })?;
+    let path_label = labels
+        .get("cblt.path")
+        .ok_or_else(|| CbltError::LabelNotFound {
+            details: "cblt.path".to_string(),
+        })?;
+    let port_label = labels
+        .get("cblt.port")
+        .ok_or_else(|| CbltError::LabelNotFound {
+            details: "cblt.port".to_string(),
+        })?;
+
+    let hosts_list: Vec<&str> = hosts_label.split(',').map(|s| s.trim()).collect();
+    let path = path_label.clone();
+    let port = port_label
+        .parse::<u16>()
+        .map_err(|_| CbltError::InvalidLabelFormat {
+            details: "cblt.port".to_string(),
+        })?;
+
+    // Collect secrets per host
+    let secrets_label = labels.get("cblt.secrets");
+    let secrets_map = if let Some(secrets_label) = secrets_label {
+        let mut map = HashMap::new();
+        let secrets_entries: Vec<&str> = secrets_label.split(',').map(|s| s.trim()).collect();
+        for entry in secrets_entries {
+            let parts: Vec<&str> = entry.split_whitespace().collect();
+            if parts.len() == 3 {
+                let host = parts[0];
+                let key = parts[1].to_string();
+                let cert = parts[2].to_string();
+                map.insert(host.to_string(), (key, cert));
+            } else {
+                return Err(CbltError::InvalidLabelFormat {
+                    details: "cblt.secrets".to_string(),
+                });
+            }
+        }
+        map
+    } else {
+        HashMap::new()
+    };
+
+    // Load balancing options
+    let lb_policy_label = labels.get("cblt.lb_policy");
+    let lb_interval_label = labels.get("cblt.lb_interval");
+    let lb_timeout_label = labels.get("cblt.lb_timeout");
+    let lb_retries_label = labels.get("cblt.lb_retries");
+
+    let lb_policy = if let Some(policy_str) = lb_policy_label {
+        match policy_str.as_str() {
+            "round_robin" => Some(LoadBalancePolicy::RoundRobin),
+            "ip_hash" => Some(LoadBalancePolicy::IPHash),
+            "least_conn" => Some(LoadBalancePolicy::LeastConn),
+            "random" => Some(LoadBalancePolicy::Random),
+            _ => {
+                return Err(CbltError::KdlParseError {
+                    details: format!("Unknown lb_policy '{}'", policy_str),
+                });
+            }
+        }
+    } else {
+        None
+    };
+
+    let lb_interval = if let Some(interval_str) = lb_interval_label {
+        humantime::parse_duration(interval_str)
+            .map_err(|_| CbltError::InvalidLabelFormat {
+                details: "cblt.lb_interval".to_string(),
+            })?
+            .as_secs()
+    } else {
+        10 // Default value
+    };
+
+    let lb_timeout = if let Some(timeout_str) = lb_timeout_label {
+        humantime::parse_duration(timeout_str)
+            .map_err(|_| CbltError::InvalidLabelFormat {
+                details: "cblt.lb_timeout".to_string(),
+            })?
+            .as_secs()
+    } else {
+        1 // Default value
+    };
+
+    let lb_retries = if let Some(retries_str) = lb_retries_label {
+        retries_str
+            .parse::<u64>()
+            .map_err(|_| CbltError::InvalidLabelFormat {
+                details: "cblt.lb_retries".to_string(),
+            })?
+    } else {
+        2 // Default value
+    };
+
+    let options = ReverseProxyOptions {
+        lb_retries,
+        lb_interval,
+        lb_timeout,
+        lb_policy,
+        ..Default::default()
+    };
+
+    // Build the ReverseProxy directive
+    let destinations = destinations
+        .iter()
+        .map(|s| format!("{}:{}", s, port))
+        .collect();
+    let reverse_proxy_directive = Directive::ReverseProxy {
+        pattern: path.clone(),
+        destinations,
+        options,
+    };
+
+    // For each host, add the directives
+    for host in hosts_list {
+        let host_directives = hosts.entry(host.to_string()).or_default();
+        host_directives.push(reverse_proxy_directive.clone());
+
+        // If there is a secret for this host, add the TLS directive
+        if let Some((key, cert)) = secrets_map.get(host) {
+            let key_data = Some(key.into());
+            let cert_data = Some(cert.into());
+            host_directives.push(Directive::TlS {
+                key: key_data.ok_or(CbltError::SecretDataNotFound)?,
+                cert: cert_data.ok_or(CbltError::SecretDataNotFound)?,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Stable text of a configuration, to tell whether a provider's one changed since it was applied
+pub fn config_fingerprint(config: &HashMap<String, Vec<Directive>>) -> String {
+    let sorted: BTreeMap<&String, &Vec<Directive>> = config.iter().collect();
+    serde_json::to_string(&sorted).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_servers;
+    use crate::config::{
+        add_labeled_routes, build_config, config_fingerprint, container_address,
+        file_server_config, load_config, parse_json_config, parse_percent, reverse_proxy_config,
+        substitute_vars, AccessLogFormat, AcmeChallenge, AcmeOptions, AutoBanOptions, CacheOptions,
+        ConnectionLimitOptions, ConsulOptions, CookieName, CookieOptions, Directive,
+        DnsDiscoveryOptions, DnsProviderOptions, Encoding, ErrorPage, FastcgiOptions,
+        FileCacheOptions, ForwardHeaders, ForwardProxyOptions, HeaderOp, IpAction, Layer4Options,
+        LoadBalancePolicy, MarkdownOptions, MatchCondition, ProxyProtocolOptions,
+        ProxyProtocolVersion, ProxyTarget, ProxyUser, RateLimitKey, RateLimitOptions,
+        RequestLimitOptions, RetryOn, ReverseProxyOptions, RollOptions, SecurityHeadersOptions,
+        ThrottleOptions, ThrottleScope, TlsVersion, TracingOptions, UpstreamGroup, UriOp,
+        WasmOptions,
+    };
+    use crate::pattern::capture_placeholders;
+    use crate::server::Listener;
+    use kdl::KdlDocument;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn test_simple() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    root "*" "/path/to/folder"
+    file_server
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_server_mime_types() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    root "*" "/path/to/folder"
+    file_server {
+        mime ".wasm" "application/wasm"
+        mime "md" "text/markdown; charset=utf-8"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][1] {
+            Directive::FileServer { options } => {
+                assert_eq!(options.mime_types["wasm"], "application/wasm");
+                assert_eq!(options.mime_types["md"], "text/markdown; charset=utf-8");
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_server_options() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    root "*" "/path/to/folder"
+    file_server {
+        precompressed "br" "gzip"
+        browse
+        webdav
+        dotfiles_except ".htaccess"
+        markdown "/path/to/layout.html"
+        cache {
+            max_file_size "256KiB"
+        }
+    }
+}
+example.org {
+    root "*" "/path/to/folder"
+    file_server {
+        precompressed
+        dotfiles "allow"
+        markdown
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][1] {
+            Directive::FileServer { options } => {
+                assert_eq!(
+                    options.precompressed,
+                    vec![Encoding::Brotli, Encoding::Gzip]
+                );
+                assert!(options.browse);
+                assert!(options.webdav);
+                assert!(!options.allow_dotfiles);
+                assert_eq!(options.dotfile_exceptions, vec![".well-known", ".htaccess"]);
+                assert_eq!(
+                    options.cache,
+                    Some(FileCacheOptions {
+                        max_file_size: 256 * 1024,
+                        max_size: 32 * 1024 * 1024,
+                    })
+                );
+                assert_eq!(
+                    options.markdown,
+                    Some(MarkdownOptions {
+                        layout: Some("/path/to/layout.html".to_string()),
+                    })
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+        match &config["example.org"][1] {
+            Directive::FileServer { options } => {
+                assert_eq!(options.precompressed.len(), 3);
+                assert!(!options.browse);
+                assert!(!options.webdav);
+                assert!(options.allow_dotfiles);
+                assert_eq!(options.cache, None);
+                assert_eq!(options.markdown, Some(MarkdownOptions::default()));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_files() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    reverse_proxy "/api/*" "http://10.8.0.3:80"
+    root "*" "/path/to/folder"
+    try_files "*" "{path}" "{path}/" "/index.html"
+    file_server
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][2] {
+            Directive::TryFiles {
+                pattern,
+                candidates,
+            } => {
+                assert_eq!(pattern, "*");
+                assert_eq!(candidates, &vec!["{path}", "{path}/", "/index.html"]);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_page() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    error_page "404" "/path/to/404.html"
+    error_page "502" "5xx" {
+        body "<h1>{status} {reason}</h1>"
+    }
+    root "*" "/path/to/folder"
+    file_server
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ErrorPage { statuses, page } => {
+                assert_eq!(statuses, &vec!["404"]);
+                assert_eq!(page, &ErrorPage::File("/path/to/404.html".to_string()));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+        match &config["example.com"][1] {
+            Directive::ErrorPage { statuses, page } => {
+                assert_eq!(statuses, &vec!["502", "5xx"]);
+                assert_eq!(
+                    page,
+                    &ErrorPage::Inline("<h1>{status} {reason}</h1>".to_string())
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let cblt_file = r#"
+example.com {
+    error_page "/path/to/404.html"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_access_log() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    access_log "/var/log/cblt/access.log" {
+        format "common"
+        roll_size "100MiB"
+        roll_interval "1day"
+        roll_keep "5"
+        roll_gzip "false"
+    }
+    root "*" "/path/to/folder"
+    file_server
+}
+example.org {
+    access_log
+    root "*" "/path/to/folder"
+    file_server
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::AccessLog { options } => {
+                assert_eq!(options.output.as_deref(), Some("/var/log/cblt/access.log"));
+                assert_eq!(options.format, AccessLogFormat::Common);
+                assert_eq!(options.roll.max_size, Some(100 * 1024 * 1024));
+                assert_eq!(options.roll.interval, Some(Duration::from_secs(86400)));
+                assert_eq!(options.roll.keep, 5);
+                assert!(!options.roll.gzip);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+        match &config["example.org"][0] {
+            Directive::AccessLog { options } => {
+                assert_eq!(options.output, None);
+                assert_eq!(options.format, AccessLogFormat::Combined);
+                assert_eq!(options.roll, RollOptions::default());
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    encode "gzip" {
+        minimum_length "1024"
+    }
+    root "*" "/path/to/folder"
+    file_server
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::Encode { options } => {
+                assert_eq!(options.encodings, vec![Encoding::Gzip]);
+                assert_eq!(options.min_length, 1024);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_levels() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    encode "zstd" "br" "gzip" {
+        br "5"
+        zstd "9"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::Encode { options } => {
+                assert_eq!(
+                    options.encodings,
+                    vec![Encoding::Zstd, Encoding::Brotli, Encoding::Gzip]
+                );
+                assert_eq!(options.levels.get(&Encoding::Brotli), Some(&5));
+                assert_eq!(options.levels.get(&Encoding::Zstd), Some(&9));
+                assert_eq!(options.levels.get(&Encoding::Gzip), None);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_server_config() -> Result<(), Box<dyn Error>> {
+        for listen in [":8080", "8080", "localhost:8080"] {
+            let config = file_server_config("./public", listen)?;
+            let directives = &config["*:8080"];
+            assert!(matches!(
+                &directives[0],
+                Directive::Root { pattern, path } if pattern == "*" && path == "./public"
+            ));
+            assert!(matches!(&directives[1], Directive::FileServer { .. }));
+        }
+        assert!(file_server_config("./public", ":http").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_config() -> Result<(), Box<dyn Error>> {
+        let config = reverse_proxy_config(":8080", "http://localhost:3000")?;
+        assert!(matches!(
+            &config["*:8080"][0],
+            Directive::ReverseProxy { pattern, destinations, .. }
+                if pattern == "*" && destinations == &["http://localhost:3000"]
+        ));
+        assert!(reverse_proxy_config(":8080", "localhost:3000").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_substitution() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com:{$PORT}" {
+    reverse_proxy "/api/*" "{$UPSTREAM:http://localhost:8080}"
+    tls "{$CERT}" "/etc/key.pem"
+}
+            "#;
+        let lookup = |name: &str| match name {
+            "PORT" => Some("8443".to_string()),
+            "CERT" => Some("C:\\certs\\\"cert\".pem".to_string()),
+            _ => None,
+        };
+        let doc: KdlDocument = substitute_vars(cblt_file, lookup)?.parse()?;
+        let config = build_config(&doc)?;
+        let directives = &config["example.com:8443"];
+        assert!(matches!(
+            &directives[0],
+            Directive::ReverseProxy { destinations, .. }
+                if destinations == &["http://localhost:8080"]
+        ));
+        assert!(matches!(
+            &directives[1],
+            Directive::TlS { cert, .. } if cert == "C:\\certs\\\"cert\".pem"
+        ));
+
+        assert!(substitute_vars(cblt_file, |_| None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-import-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sites"))?;
+        std::fs::write(
+            dir.join("Cbltfile"),
+            "import \"sites/*.kdl\"\nmain.com {\n    root \"*\" \"/main\"\n}\n",
+        )?;
+        std::fs::write(
+            dir.join("sites/a.kdl"),
+            "a.com {\n    root \"*\" \"/a\"\n}\n",
+        )?;
+        std::fs::write(
+            dir.join("sites/b.kdl"),
+            "b.com {\n    root \"*\" \"/b\"\n}\n",
+        )?;
+        std::fs::write(dir.join("sites/notes.txt"), "not imported")?;
+
+        let config = load_config(&dir.join("Cbltfile"))?;
+        let mut hosts: Vec<&String> = config.keys().collect();
+        hosts.sort();
+        assert_eq!(hosts, vec!["a.com", "b.com", "main.com"]);
+
+        // a.kdl importing the main file again is a cycle
+        std::fs::write(dir.join("sites/a.kdl"), "import \"../Cbltfile\"\n")?;
+        assert!(load_config(&dir.join("Cbltfile")).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_snippets() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+snippet "logging" {
+    access_log {
+        format "common"
+    }
+}
+snippet "common" {
+    import "logging"
+    encode "gzip"
+}
+a.com {
+    import "common"
+    root "*" "/a"
+}
+b.com {
+    root "*" "/b"
+    import "common"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        assert_eq!(config.len(), 2);
+        assert!(matches!(config["a.com"][0], Directive::AccessLog { .. }));
+        assert!(matches!(config["a.com"][1], Directive::Encode { .. }));
+        assert!(matches!(config["a.com"][2], Directive::Root { .. }));
+        assert!(matches!(config["b.com"][1], Directive::AccessLog { .. }));
+        assert!(matches!(config["b.com"][2], Directive::Encode { .. }));
+
+        let doc: KdlDocument = "a.com {\n    import \"missing\"\n}".parse()?;
+        assert!(build_config(&doc).is_err());
+        let doc: KdlDocument =
+            "snippet \"loop\" {\n    import \"loop\"\n}\na.com {\n    import \"loop\"\n}"
+                .parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_config() -> Result<(), Box<dyn Error>> {
+        let json = r#"{
+    "example.com": [
+        {"root": {"pattern": "*", "path": "/path/to/folder"}},
+        {"reverse_proxy": {
+            "pattern": "/api/*",
+            "destinations": ["http://localhost:8080"],
+            "options": {"lb_policy": "ip_hash", "pool_max_idle": 8}
+        }},
+        {"encode": {"options": {"encodings": ["zstd", "br"], "levels": {"br": 4}}}},
+        {"error_page": {"statuses": ["404"], "page": {"file": "/404.html"}}},
+        {"file_server": {}}
+    ]
+}"#;
+        let config = parse_json_config(json)?;
+        let directives = &config["example.com"];
+        assert!(matches!(
+            &directives[1],
+            Directive::ReverseProxy { options, .. }
+                if options.pool_max_idle == 8 && options.lb_retries == 2
+        ));
+        assert!(matches!(
+            &directives[2],
+            Directive::Encode { options }
+                if options.encodings == vec![Encoding::Zstd, Encoding::Brotli]
+                    && options.levels[&Encoding::Brotli] == 4
+                    && options.min_length == 512
+        ));
+        assert!(matches!(
+            &directives[4],
+            Directive::FileServer { options } if options.dotfile_exceptions == vec![".well-known"]
+        ));
+
+        // Cbltfile and JSON describe the same model
+        let cblt_file = r#"
+example.com {
+    root "*" "/path/to/folder"
+    tls "/etc/cert.pem" "/etc/key.pem"
+    access_log "/var/log/access.log" {
+        roll_interval "1day"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let json = serde_json::to_string(&config)?;
+        assert!(json.contains(r#"{"tls":{"cert":"/etc/cert.pem","key":"/etc/key.pem"}}"#));
+        let parsed = parse_json_config(&json)?;
+        assert_eq!(serde_json::to_string(&parsed)?, json);
+
+        assert!(parse_json_config(r#"{"example.com": []}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_complicated() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example1.com {
+    root "*" "/path/folder"
+    file_server
+    reverse_proxy "/api/*" "localhost:8080"
+}
+
+"http://example1.com" {
+    redir "https://example2.com{uri}"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    root "*" "/path/to/folder"
+    file_server
+    tls "/path/to/your/certificate.crt" "/path/to/your/private.key"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_with_pool_options() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "backend1:8080" {
+        pool_max_idle "8"
+        pool_idle_timeout "30s"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_upstreams() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "http://10.0.0.1:8080" "http://10.0.0.2:8080"
+    reverse_proxy "/*" "http://10.0.0.3:8080" {
+        to "http://10.0.0.4:8080" "http://10.0.0.5:8080"
+        lb_policy "round_robin"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let upstreams: Vec<&Vec<String>> = config["example.com"]
+            .iter()
+            .filter_map(|d| match d {
+                Directive::ReverseProxy { destinations, .. } => Some(destinations),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            upstreams,
+            vec![
+                &vec!["http://10.0.0.1:8080", "http://10.0.0.2:8080"],
+                &vec![
+                    "http://10.0.0.3:8080",
+                    "http://10.0.0.4:8080",
+                    "http://10.0.0.5:8080"
+                ]
+            ]
+        );
+
+        let no_upstream: KdlDocument = r#""example.com" { reverse_proxy "/*"; }"#.parse()?;
+        assert!(build_config(&no_upstream).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_flush_interval() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "backend1:8080" {
+        flush_interval "100ms"
+    }
+    reverse_proxy "/events/*" "backend2:8080"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let intervals: Vec<Option<Duration>> = config["example.com"]
+            .iter()
+            .filter_map(|d| match d {
+                Directive::ReverseProxy { options, .. } => Some(options.flush_interval),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(intervals, vec![Some(Duration::from_millis(100)), None]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_cache() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "backend1:8080" {
+        cache {
+            max_size "16MiB"
+            ttl "30s"
+            stale_while_revalidate "10s"
+            stale_if_error "1h"
+            purge_allow "127.0.0.1" "10.0.0.0/8"
+        }
+    }
+    reverse_proxy "/static/*" "backend2:8080" {
+        cache
+    }
+    reverse_proxy "/events/*" "backend3:8080"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let caches: Vec<Option<CacheOptions>> = config["example.com"]
+            .iter()
+            .filter_map(|d| match d {
+                Directive::ReverseProxy { options, .. } => Some(options.cache.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            caches,
+            vec![
+                Some(CacheOptions {
+                    max_size: 16 * 1024 * 1024,
+                    max_object_size: 1024 * 1024,
+                    ttl: Some(Duration::from_secs(30)),
+                    stale_while_revalidate: Some(Duration::from_secs(10)),
+                    stale_if_error: Some(Duration::from_secs(3600)),
+                    purge_allow: vec!["127.0.0.1".parse()?, "10.0.0.0/8".parse()?],
+                }),
+                Some(CacheOptions::default()),
+                None,
+            ]
+        );
+
+        let doc: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "b:80" { cache { max_age "1m"; }; }; }"#
+                .parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_health_check() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "backend1:8080" {
+        health_uri "/health"
+        health_interval "10s"
+        health_timeout "2s"
+        health_status "204"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                assert_eq!(options.health_uri.as_deref(), Some("/health"));
+                assert_eq!(options.health_interval, 10);
+                assert_eq!(options.health_timeout, 2);
+                assert_eq!(options.health_status, Some(204));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let relative: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "backend1:8080" { health_uri "health"; }; }"#
+                .parse()?;
+        assert!(build_config(&relative).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_mirror() -> Result<(), Box<dyn Error>> {
+        let doc: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "backend1:8080" { mirror "http://shadow:8080"; }; }"#
+                .parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                assert_eq!(options.mirror.as_deref(), Some("http://shadow:8080"));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let invalid: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "backend1:8080" { mirror "/shadow"; }; }"#
+                .parse()?;
+        assert!(build_config(&invalid).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_canary() -> Result<(), Box<dyn Error>> {
+        let doc: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "backend1:8080" { canary "http://canary:8080" "5%"; }; }"#
+                .parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                let canary = options.canary.as_ref().ok_or("canary not parsed")?;
+                assert_eq!(canary.upstream, "http://canary:8080");
+                assert_eq!(canary.percent, 5.0);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+        assert_eq!(parse_percent("0.5")?, 0.5);
+
+        for invalid in [
+            r#"canary "http://canary:8080" "150%""#,
+            r#"canary "http://canary:8080""#,
+            r#"canary "/canary" "5%""#,
+        ] {
+            let doc: KdlDocument = format!(
+                r#""example.com" {{ reverse_proxy "/*" "backend1:8080" {{ {}; }}; }}"#,
+                invalid
+            )
+            .parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_groups() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/*" "http://stable:8080" {
+        group "beta" {
+            header "X-Beta" "1"
+            to "http://beta1:8080" "http://beta2:8080"
+        }
+        group "variant-b" {
+            cookie "exact" "variant" "b"
+            to "http://variant-b:8080"
+        }
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy {
+                destinations,
+                options,
+                ..
+            } => {
+                assert_eq!(destinations, &vec!["http://stable:8080".to_string()]);
+                assert_eq!(
+                    options.groups,
+                    vec![
+                        UpstreamGroup {
+                            name: "beta".to_string(),
+                            conditions: vec![MatchCondition::Header {
+                                name: "x-beta".to_string(),
+                                values: vec!["1".to_string()],
+                            }],
+                            upstreams: vec![
+                                "http://beta1:8080".to_string(),
+                                "http://beta2:8080".to_string()
+                            ],
+                        },
+                        UpstreamGroup {
+                            name: "variant-b".to_string(),
+                            conditions: vec![MatchCondition::Cookie {
+                                name: CookieName::Exact("variant".to_string()),
+                                values: vec!["b".to_string()],
+                            }],
+                            upstreams: vec!["http://variant-b:8080".to_string()],
+                        },
+                    ]
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        for invalid in [
+            r#"group "beta" { header "X-Beta" "1"; }"#,
+            r#"group "beta" { to "http://beta:8080"; }"#,
+            r#"group { header "X-Beta" "1"; to "http://beta:8080"; }"#,
+            r#"group "beta" { header "X-Beta" "1"; to "/beta"; }"#,
+        ] {
+            let doc: KdlDocument = format!(
+                r#""example.com" {{ reverse_proxy "/*" "http://stable:8080" {{ {}; }}; }}"#,
+                invalid
+            )
+            .parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_backup() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/*" "http://blue:8080" {
+        backup "http://green:8080"
+        backup "http://dr-1:8080" "http://dr-2:8080"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                assert_eq!(
+                    options.backups,
+                    vec!["http://green:8080", "http://dr-1:8080", "http://dr-2:8080"]
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let invalid: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "http://blue:8080" { backup "/green"; }; }"#
+                .parse()?;
+        assert!(build_config(&invalid).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_dns_discovery() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/*" "http://api.default.svc.cluster.local:8080" {
+        dns_discovery {
+            resolver "10.96.0.10"
+            min_refresh "10s"
+            max_refresh "1m"
+        }
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                assert_eq!(
+                    options.dns_discovery,
+                    Some(DnsDiscoveryOptions {
+                        resolver: Some("10.96.0.10:53".parse()?),
+                        min_refresh: 10,
+                        max_refresh: 60,
+                    })
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let doc: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "http://api:8080" { dns_discovery; }; }"#
+                .parse()?;
+        match &build_config(&doc)?["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                assert_eq!(options.dns_discovery, Some(DnsDiscoveryOptions::default()));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        for invalid in [
+            r#"reverse_proxy "/*" "http://api:8080" { dns_discovery { resolver "dns"; }; }"#,
+            r#"reverse_proxy "/*" "https://a:443" "https://b:443" { dns_discovery; }"#,
+        ] {
+            let doc: KdlDocument = format!(r#""example.com" {{ {}; }}"#, invalid).parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_docker_labels() -> Result<(), Box<dyn Error>> {
+        let container: bollard::models::ContainerSummary = serde_json::from_str(
+            r#"{"Id": "3f2a", "Names": ["/shop-web-1"], "Status": "Up 2 minutes (healthy)",
+                "Labels": {"cblt.hosts": "shop.example.com"},
+                "NetworkSettings": {"Networks": {
+                    "bridge": {"IPAddress": "172.17.0.4"},
+                    "shop_default": {"IPAddress": "172.20.0.3"}
+                }}}"#,
+        )?;
+        assert_eq!(
+            container_address(&container, None),
+            Some("http://172.17.0.4".to_string())
+        );
+        assert_eq!(
+            container_address(&container, Some(&"shop_default".to_string())),
+            Some("http://172.20.0.3".to_string())
+        );
+        assert_eq!(
+            container_address(&container, Some(&"other".to_string())),
+            None
+        );
+
+        let labels = HashMap::from([
+            (
+                "cblt.hosts".to_string(),
+                "shop.example.com, www.shop.example.com".to_string(),
+            ),
+            ("cblt.path".to_string(), "/*".to_string()),
+            ("cblt.port".to_string(), "8080".to_string()),
+            ("cblt.lb_policy".to_string(), "least_conn".to_string()),
+        ]);
+        let mut hosts: HashMap<String, Vec<Directive>> = HashMap::new();
+        add_labeled_routes(
+            &labels,
+            vec![
+                "http://172.20.0.3".to_string(),
+                "http://172.20.0.5".to_string(),
+            ],
+            &mut hosts,
+        )?;
+        assert_eq!(hosts.len(), 2);
+        match &hosts["www.shop.example.com"][0] {
+            Directive::ReverseProxy {
+                pattern,
+                destinations,
+                options,
+            } => {
+                assert_eq!(pattern, "/*");
+                assert_eq!(
+                    *destinations,
+                    vec![
+                        "http://172.20.0.3:8080".to_string(),
+                        "http://172.20.0.5:8080".to_string()
+                    ]
+                );
+                assert_eq!(options.lb_policy, Some(LoadBalancePolicy::LeastConn));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+        let fingerprint = config_fingerprint(&hosts);
+        assert_eq!(config_fingerprint(&hosts.clone()), fingerprint);
+
+        let mut invalid = labels.clone();
+        invalid.insert("cblt.port".to_string(), "http".to_string());
+        assert!(add_labeled_routes(&invalid, Vec::new(), &mut hosts).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_consul() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/*" "consul://web" {
+        backup "consul://web-dr"
+        consul {
+            address "https://consul.internal:8501/"
+            token "secret"
+            datacenter "dc2"
+            tag "v2"
+            scheme "https"
+        }
+        tls_server_name "web.service.consul"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy {
+                destinations,
+                options,
+                ..
+            } => {
+                assert_eq!(destinations, &vec!["consul://web".to_string()]);
+                assert_eq!(options.backups, vec!["consul://web-dr".to_string()]);
+                assert_eq!(
+                    options.consul,
+                    ConsulOptions {
+                        address: "https://consul.internal:8501".to_string(),
+                        token: Some("secret".into()),
+                        datacenter: Some("dc2".to_string()),
+                        tag: Some("v2".to_string()),
+                        scheme: "https".to_string(),
+                    }
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        let doc: KdlDocument = r#""example.com" { reverse_proxy "/*" "consul://web"; }"#.parse()?;
+        match &build_config(&doc)?["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                assert_eq!(options.consul, ConsulOptions::default());
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        for invalid in [
+            r#"reverse_proxy "/*" "consul://web/api""#,
+            r#"reverse_proxy "/*" "consul://web" { mirror "consul://shadow"; }"#,
+            r#"reverse_proxy "/*" "consul://web" { consul { scheme "ftp"; }; }"#,
+            r#"reverse_proxy "/*" "consul://web" { consul { address "127.0.0.1:8500"; }; }"#,
+            r#"reverse_proxy "/*" "consul://web" { consul { service "web"; }; }"#,
+        ] {
+            let doc: KdlDocument = format!(r#""example.com" {{ {}; }}"#, invalid).parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_passive_health() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "backend1:8080" {
+        lb_max_fails "3"
+        lb_max_backoff "5m"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                assert_eq!(options.lb_max_fails, 3);
+                assert_eq!(options.lb_max_backoff, 300);
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_retries() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "backend1:8080" "backend2:8080" {
+        retries "2"
+        retry_on "connect_failure" "502" "503"
+    }
+    reverse_proxy "/*" "backend3:8080"
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let options: Vec<&ReverseProxyOptions> = config["example.com"]
+            .iter()
+            .filter_map(|d| match d {
+                Directive::ReverseProxy { options, .. } => Some(options),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(options[0].retries, Some(2));
+        assert_eq!(
+            options[0].retry_on,
+            vec![
+                RetryOn::ConnectFailure,
+                RetryOn::Status(502),
+                RetryOn::Status(503)
+            ]
+        );
+        assert_eq!(options[1].retries, None);
+        assert_eq!(
+            options[1].retry_on,
+            vec![RetryOn::ConnectFailure, RetryOn::Timeout]
+        );
+
+        let unknown: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "b:80" { retry_on "always"; }; }"#.parse()?;
+        assert!(build_config(&unknown).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_timeouts() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "backend1:8080" {
+        connect_timeout "3s"
+        header_timeout "500ms"
+        timeout "30s"
+        trusted_proxies "10.0.0.0/8" "::1"
+        forward_headers "both"
+        header_up "Host" "{upstream_hostport}"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                assert_eq!(options.lb_timeout, 3);
+                assert_eq!(options.header_timeout, Some(Duration::from_millis(500)));
+                assert_eq!(options.timeout, Some(Duration::from_secs(30)));
+                assert_eq!(options.trusted_proxies.len(), 2);
+                assert_eq!(options.forward_headers, ForwardHeaders::Both);
+                assert_eq!(
+                    options.header_up,
+                    vec![HeaderOp::Set {
+                        name: "host".to_string(),
+                        value: "{upstream_hostport}".to_string()
+                    }]
+                );
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+        let missing_value: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "b:80" { header_up "Host"; }; }"#.parse()?;
+        assert!(build_config(&missing_value).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_tls() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/*" "https://10.0.0.1:8443" {
+        tls_trusted_ca_certs "/etc/ssl/internal-ca.pem"
+        tls_server_name "backend.internal"
+        tls_insecure_skip_verify
+        proxy_protocol "v2"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        match &config["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => {
+                assert_eq!(
+                    options.tls_trusted_ca_certs,
+                    vec![PathBuf::from("/etc/ssl/internal-ca.pem")]
+                );
+                assert_eq!(options.tls_server_name.as_deref(), Some("backend.internal"));
+                assert!(options.tls_insecure_skip_verify);
+                assert_eq!(options.proxy_protocol, Some(ProxyProtocolVersion::V2));
+            }
+            other => panic!("Unexpected directive {:?}", other),
+        }
+        let invalid: KdlDocument =
+            r#""example.com" { reverse_proxy "/*" "https://b" { tls_server_name "a b"; }; }"#
+                .parse()?;
+        assert!(build_config(&invalid).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_lb_policies() -> Result<(), Box<dyn Error>> {
+        for (name, policy) in [
+            ("round_robin", LoadBalancePolicy::RoundRobin),
+            ("ip_hash", LoadBalancePolicy::IPHash),
+            ("least_conn", LoadBalancePolicy::LeastConn),
+            ("random", LoadBalancePolicy::Random),
+        ] {
+            let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/*" "backend1:8080" {
+        lb_policy "{policy}"
+    }
+}
+            "#
+            .replace("{policy}", name);
+            let doc: KdlDocument = cblt_file.parse()?;
+            let config = build_config(&doc)?;
+            match &config["example.com"][0] {
+                Directive::ReverseProxy { options, .. } => {
+                    assert_eq!(options.lb_policy, Some(policy))
+                }
+                other => panic!("Unexpected directive {:?}", other),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_with_options() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "backend1:8080" "backend2:8080" {
+        health_uri "/health"
+        health_interval "10s"
+        health_timeout "2s"
+        lb_policy "round_robin"
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reverse_proxy_with_cookie_lb_policy() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    reverse_proxy "/api/*" "backend1:8080" "backend2:8080" {
+        lb_policy "cookie" {
+            lb_cookie_name "my_sticky_cookie"
+            lb_cookie_path "/"
+            lb_cookie_max_age "3600"
+        }
+    }
+}
+            "#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        println!("{:#?}", config);
+        match &config["example.com"][0] {
+            Directive::ReverseProxy { options, .. } => assert_eq!(
+                options.lb_policy,
+                Some(LoadBalancePolicy::Cookie(CookieOptions {
+                    name: "my_sticky_cookie".to_string(),
+                    path: "/".to_string(),
+                    max_age: Some(3600),
+                }))
+            ),
+            other => panic!("Unexpected directive {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_acme() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    tls "acme" {
+        email "admin@example.com"
+        storage "/var/lib/cblt/acme"
+    }
+    root "*" "/path/to/folder"
+    file_server
+}
+www.example.com {
+    tls "acme"
+}
+api.example.com {
+    tls "acme" {
+        challenge "tls-alpn-01"
+    }
+}
+"*.example.com" {
+    tls "acme" {
+        dns "rfc2136" {
+            server "ns1.example.com:53"
+            zone "example.com"
+            key_name "cblt"
+            key_secret "c2VjcmV0"
+            propagation_delay "10s"
+        }
+    }
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        assert!(matches!(
+            &config["example.com"][0],
+            Directive::TlsAcme { options }
+                if options.email.as_deref() == Some("admin@example.com")
+                    && options.storage == "/var/lib/cblt/acme"
+                    && options.ca == "https://acme-v02.api.letsencrypt.org/directory"
+        ));
+        assert!(matches!(
+            &config["www.example.com"][0],
+            Directive::TlsAcme { options }
+                if options.storage == "./acme" && options.challenge == AcmeChallenge::Http01
+        ));
+        assert!(matches!(
+            &config["api.example.com"][0],
+            Directive::TlsAcme { options } if options.challenge == AcmeChallenge::TlsAlpn01
+        ));
+        assert!(matches!(
+            &config["*.example.com"][0],
+            Directive::TlsAcme { options: AcmeOptions { challenge: AcmeChallenge::Dns01, dns: Some(dns), .. } }
+                if dns.propagation_delay == Some(Duration::from_secs(10))
+                    && matches!(&dns.provider, DnsProviderOptions::Rfc2136 { zone, .. } if zone == "example.com")
+        ));
+        let doc: KdlDocument =
+            r#"example.com { tls "acme" { dns "cloudflare" { zone_id "abc"; }; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+        let doc: KdlDocument = r#"example.com { tls "acme" { challenge "dns-01"; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        let doc: KdlDocument = r#"
+"*:443" {
+    tls "acme" {
+        on_demand {
+            allow "Shop.example.com" "blog.example.org"
+            ask "http://127.0.0.1:5555/allowed"
+        }
+    }
+}
+"#
+        .parse()?;
+        let config = build_config(&doc)?;
+        assert!(matches!(
+            &config["*:443"][0],
+            Directive::TlsAcme { options: AcmeOptions { on_demand: Some(on_demand), .. } }
+                if on_demand.allow == vec!["shop.example.com", "blog.example.org"]
+                    && on_demand.ask.as_deref() == Some("http://127.0.0.1:5555/allowed")
+        ));
+        let doc: KdlDocument = r#"example.com { tls "acme" { on_demand; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        let doc: KdlDocument = r#"example.com { tls "acme" { renew "30d"; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tls_options() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    tls "/etc/cert.pem" "/etc/key.pem"
+    tls_options {
+        min_version "1.3"
+        ciphers "TLS13_AES_256_GCM_SHA384" "TLS13_CHACHA20_POLY1305_SHA256"
+    }
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        assert!(matches!(
+            &config["example.com"][1],
+            Directive::TlsOptions { options }
+                if options.min_version == Some(TlsVersion::Tls13)
+                    && options.max_version.is_none()
+                    && options.ciphers.len() == 2
+        ));
+
+        let doc: KdlDocument =
+            r#"example.com { tls_options { min_version "1.3"; max_version "1.2"; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+        let doc: KdlDocument = r#"example.com { tls_options { min_version "1.1"; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_header() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    header "*" "X-Frame-Options" "DENY"
+    header "/api/*" {
+        -Server
+        +Link "</app.css>; rel=preload"
+        Location "http://" "https://"
+    }
+    reverse_proxy "/*" "http://10.0.0.1:8080" {
+        header_up "-X-Debug"
+        header_down "+Via" "cblt"
+    }
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let directives = &config["example.com"];
+        let Directive::Header {
+            pattern,
+            operations,
+        } = &directives[0]
+        else {
+            panic!("expected header");
+        };
+        assert_eq!(pattern, "*");
+        assert_eq!(
+            operations,
+            &vec![HeaderOp::Set {
+                name: "x-frame-options".to_string(),
+                value: "DENY".to_string()
+            }]
+        );
+        let Directive::Header {
+            pattern,
+            operations,
+        } = &directives[1]
+        else {
+            panic!("expected header");
+        };
+        assert_eq!(pattern, "/api/*");
+        assert_eq!(
+            operations,
+            &vec![
+                HeaderOp::Remove {
+                    name: "server".to_string()
+                },
+                HeaderOp::Add {
+                    name: "link".to_string(),
+                    value: "</app.css>; rel=preload".to_string()
+                },
+                HeaderOp::Replace {
+                    name: "location".to_string(),
+                    find: "http://".to_string(),
+                    replace: "https://".to_string()
+                },
+            ]
+        );
+        let Directive::ReverseProxy { options, .. } = &directives[2] else {
+            panic!("expected reverse_proxy");
+        };
+        assert_eq!(
+            options.header_up,
+            vec![HeaderOp::Remove {
+                name: "x-debug".to_string()
+            }]
+        );
+        assert_eq!(
+            options.header_down,
+            vec![HeaderOp::Add {
+                name: "via".to_string(),
+                value: "cblt".to_string()
+            }]
+        );
+
+        for invalid in [
+            r#""example.com" { header "*"; }"#,
+            r#""example.com" { header "*" "-"; }"#,
+            r#""example.com" { header "*" "Bad Name" "x"; }"#,
+            r#""example.com" { header "*" { Server "a" "b" "c"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remote_ip() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    @admin {
+        path "/admin/*"
+    }
+    remote_ip "deny" "10.0.13.0/24"
+    remote_ip "@admin" "allow" "10.0.0.0/8" "::1" {
+        trusted_proxies "172.16.0.0/12"
+    }
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let rules: Vec<_> = config["example.com"]
+            .iter()
+            .filter_map(|directive| match directive {
+                Directive::RemoteIp {
+                    pattern,
+                    action,
+                    ranges,
+                    trusted_proxies,
+                } => Some((pattern.as_str(), *action, ranges, trusted_proxies)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                ("*", IpAction::Deny, &vec!["10.0.13.0/24".parse()?], &vec![]),
+                (
+                    "@admin",
+                    IpAction::Allow,
+                    &vec!["10.0.0.0/8".parse()?, "::1".parse()?],
+                    &vec!["172.16.0.0/12".parse()?]
+                ),
+            ]
+        );
+
+        for invalid in [
+            r#""example.com" { remote_ip "allow"; }"#,
+            r#""example.com" { remote_ip "/admin/*" "permit" "10.0.0.0/8"; }"#,
+            r#""example.com" { remote_ip "allow" "10.0.0.0/33"; }"#,
+            r#""example.com" { remote_ip "allow" "10.0.0.1" { realip "X-Real-IP"; }; }"#,
+            r#""example.com" { geoip "/GeoLite2-Country.mmdb"; country "allow" "DEU"; }"#,
+            r#""example.com" { geoip "/GeoLite2-Country.mmdb"; country "block" "DE"; }"#,
+            r#""example.com" { country "deny" "KP"; }"#,
+            r#""example.com" { geoip; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limit() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    rate_limit "/login" "5" "1m"
+    rate_limit "/api/*" "100" "10s" {
+        key "header" "X-Api-Key"
+        trusted_proxies "10.0.0.0/8"
+        redis "redis://10.0.0.5:6379/1"
+    }
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let limits: Vec<_> = config["example.com"]
+            .iter()
+            .filter_map(|directive| match directive {
+                Directive::RateLimit {
+                    pattern,
+                    requests,
+                    window,
+                    options,
+                } => Some((pattern.as_str(), *requests, *window, options.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            limits,
+            vec![
+                (
+                    "/login",
+                    5,
+                    Duration::from_secs(60),
+                    RateLimitOptions::default()
+                ),
+                (
+                    "/api/*",
+                    100,
+                    Duration::from_secs(10),
+                    RateLimitOptions {
+                        key: RateLimitKey::Header("x-api-key".to_string()),
+                        trusted_proxies: vec!["10.0.0.0/8".parse()?],
+                        redis: Some("redis://10.0.0.5:6379/1".into()),
+                    }
+                ),
+            ]
+        );
+
+        for invalid in [
+            r#""example.com" { rate_limit "*" "0" "1m"; }"#,
+            r#""example.com" { rate_limit "*" "10"; }"#,
+            r#""example.com" { rate_limit "*" "10" "soon"; }"#,
+            r#""example.com" { rate_limit "*" "10" "1m" { key "cookie" "id"; }; }"#,
+            r#""example.com" { rate_limit "*" "10" "1m" { redis "http://10.0.0.5"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_throttle() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    throttle "/downloads/*" "1MB"
+    throttle "/uploads/*" "512KiB/s" {
+        upload "64KiB/s"
+        per "route"
+    }
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let throttles: Vec<_> = config["example.com"]
+            .iter()
+            .filter_map(|directive| match directive {
+                Directive::Throttle {
+                    pattern,
+                    rate,
+                    options,
+                } => Some((pattern.as_str(), *rate, options.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            throttles,
+            vec![
+                ("/downloads/*", 1_000_000, ThrottleOptions::default()),
+                (
+                    "/uploads/*",
+                    512 * 1024,
+                    ThrottleOptions {
+                        upload: Some(64 * 1024),
+                        per: ThrottleScope::Route,
+                    }
+                ),
+            ]
+        );
+
+        for invalid in [
+            r#""example.com" { throttle "*"; }"#,
+            r#""example.com" { throttle "*" "0"; }"#,
+            r#""example.com" { throttle "*" "fast"; }"#,
+            r#""example.com" { throttle "*" "1MB" { per "client"; }; }"#,
+            r#""example.com" { throttle "*" "1MB" { upload; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
         }
+
+        Ok(())
     }
 
-    Ok(options)
+    #[test]
+    fn test_forward_auth() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"app.example.com" {
+    forward_auth "*" "http://127.0.0.1:9091" {
+        uri "/api/verify?rd=https://auth.example.com"
+        copy_headers "Remote-User" "Remote-Groups>X-Groups"
+        trusted_proxies "10.0.0.0/8"
+        timeout "3s"
+    }
+    reverse_proxy "/*" "http://10.0.0.1:8080"
 }
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let Directive::ForwardAuth {
+            pattern,
+            upstream,
+            options,
+        } = &config["app.example.com"][0]
+        else {
+            panic!("expected forward_auth");
+        };
+        assert_eq!(pattern, "*");
+        assert_eq!(upstream, "http://127.0.0.1:9091");
+        assert_eq!(options.uri, "/api/verify?rd=https://auth.example.com");
+        assert_eq!(
+            options.copy_headers,
+            vec!["remote-user", "remote-groups>x-groups"]
+        );
+        assert_eq!(options.trusted_proxies, vec!["10.0.0.0/8".parse()?]);
+        assert_eq!(options.timeout, Duration::from_secs(3));
 
-#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-pub async fn load_servers_from_config(args: Arc<Args>) -> Result<HashMap<u16, Server>, CbltError> {
-    let cbltfile_content = fs::read_to_string(&args.cfg).await?;
-    let doc: KdlDocument = cbltfile_content.parse()?;
-    let config = build_config(&doc)?;
+        for invalid in [
+            r#""example.com" { forward_auth "*"; }"#,
+            r#""example.com" { forward_auth "*" "http://auth" { copy_headers "Bad Name"; }; }"#,
+            r#""example.com" { forward_auth "*" "http://auth" { ask "/"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
 
-    build_servers(config)
+        Ok(())
+    }
+
+    #[test]
+    fn test_php_fastcgi() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"blog.example.com" {
+    root "*" "/var/www/blog"
+    php_fastcgi "localhost:9000"
+    php_fastcgi "/app/*" "unix//run/php/php-fpm.sock" {
+        root "/var/www/app"
+        split ".php" ".phtml"
+        index "off"
+        env "APP_ENV" "production"
+        dial_timeout "2s"
+        read_timeout "30s"
+    }
+    file_server
 }
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let Directive::PhpFastcgi {
+            pattern,
+            upstream,
+            options,
+        } = &config["blog.example.com"][1]
+        else {
+            panic!("expected php_fastcgi");
+        };
+        assert_eq!(pattern, "*");
+        assert_eq!(upstream, "localhost:9000");
+        assert_eq!(*options, FastcgiOptions::default());
+        assert_eq!(options.index.as_deref(), Some("index.php"));
 
-#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-pub async fn load_servers_from_docker(_args: Arc<Args>) -> Result<HashMap<u16, Server>, CbltError> {
-    use bollard::Docker;
-    let docker = Docker::connect_with_local_defaults()?;
-    use std::default::Default;
+        let Directive::PhpFastcgi {
+            pattern,
+            upstream,
+            options,
+        } = &config["blog.example.com"][2]
+        else {
+            panic!("expected php_fastcgi");
+        };
+        assert_eq!(pattern, "/app/*");
+        assert_eq!(upstream, "unix//run/php/php-fpm.sock");
+        assert_eq!(options.root.as_deref(), Some("/var/www/app"));
+        assert_eq!(options.split, vec![".php", ".phtml"]);
+        assert_eq!(options.index, None);
+        assert_eq!(options.env["APP_ENV"], "production");
+        assert_eq!(options.dial_timeout, Duration::from_secs(2));
+        assert_eq!(options.read_timeout, Some(Duration::from_secs(30)));
 
-    let options = Some(ListServicesOptions::<String> {
-        filters: HashMap::new(),
-        ..Default::default()
-    });
+        for invalid in [
+            r#""example.com" { php_fastcgi; }"#,
+            r#""example.com" { php_fastcgi "localhost:9000" { env "A"; }; }"#,
+            r#""example.com" { php_fastcgi "localhost:9000" { try_files "x"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
 
-    let services = docker.list_services(options).await?;
+        Ok(())
+    }
 
-    // Map to hold the directives per host
-    let mut hosts: HashMap<String, Vec<Directive>> = HashMap::new();
-    for service in services {
-        // Process each service
-        if let Some(spec) = service.spec {
-            if let Some(labels) = spec.labels {
-                // Check if labels have keys starting with "cblt."
-                if labels.keys().any(|k| k.starts_with("cblt.")) {
-                    // Get service name
-                    let service_name = spec.name.ok_or(CbltError::ServiceNameNotFound)?;
-                    let mut destinations: Vec<String> = Vec::new();
-                    let containers = docker
-                        .list_containers(Some(ListContainersOptions::<String> {
-                            all: false,
-                            filters: HashMap::new(),
-                            ..Default::default()
-                        }))
-                        .await?;
-                    for container in &containers {
-                        if let Some(names) = &container.names {
-                            match names
-                                .iter()
-                                .find(|name| name.starts_with(&format!("/{}.", service_name)))
-                            {
-                                None => {}
-                                Some(name_all) => {
-                                    let container_name = name_all.replace("/", "");
-                                    debug!("{container_name}");
-                                    destinations.push(container_name);
-                                }
-                            }
-                        } else {
-                            return Err(CbltError::ContainerNameNotFound);
-                        }
-                    }
+    #[test]
+    fn test_uri() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    uri "/api/*" "strip_prefix" "/api"
+    uri "*" "add_prefix" "v2"
+    uri "/v2/old/*" "replace" "/old/" "/new/"
+    reverse_proxy "/*" "http://10.0.0.1:8080"
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let operations: Vec<(&str, &UriOp)> = config["example.com"]
+            .iter()
+            .filter_map(|directive| match directive {
+                Directive::Uri { pattern, operation } => Some((pattern.as_str(), operation)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(operations.len(), 3);
+        assert_eq!(operations[0].0, "/api/*");
+        assert_eq!(operations[1].1.apply("/users", &Vec::new()), "/v2/users");
 
-                    // Process the labels
-                    let hosts_label =
-                        labels
-                            .get("cblt.hosts")
-                            .ok_or_else(|| CbltError::LabelNotFound {
-                                details: "cblt.hosts".to_string(),
-                            })?;
-                    let path_label =
-                        labels
-                            .get("cblt.path")
-                            .ok_or_else(|| CbltError::LabelNotFound {
-                                details: "cblt.path".to_string(),
-                            })?;
-                    let port_label =
-                        labels
-                            .get("cblt.port")
-                            .ok_or_else(|| CbltError::LabelNotFound {
-                                details: "cblt.port".to_string(),
-                            })?;
-
-                    let hosts_list: Vec<&str> = hosts_label.split(',').map(|s| s.trim()).collect();
-                    let path = path_label.clone();
-                    let port =
-                        port_label
-                            .parse::<u16>()
-                            .map_err(|_| CbltError::InvalidLabelFormat {
-                                details: "cblt.port".to_string(),
-                            })?;
-
-                    // Collect secrets per host
-                    let secrets_label = labels.get("cblt.secrets");
-                    let secrets_map = if let Some(secrets_label) = secrets_label {
-                        let mut map = HashMap::new();
-                        let secrets_entries: Vec<&str> =
-                            secrets_label.split(',').map(|s| s.trim()).collect();
-                        for entry in secrets_entries {
-                            let parts: Vec<&str> = entry.split_whitespace().collect();
-                            if parts.len() == 3 {
-                                let host = parts[0];
-                                let key = parts[1].to_string();
-                                let cert = parts[2].to_string();
-                                map.insert(host.to_string(), (key, cert));
-                            } else {
-                                return Err(CbltError::InvalidLabelFormat {
-                                    details: "cblt.secrets".to_string(),
-                                });
-                            }
-                        }
-                        map
-                    } else {
-                        HashMap::new()
-                    };
+        let mut path = "/api/old/users".to_string();
+        for (_, operation) in &operations {
+            path = operation.apply(&path, &Vec::new());
+        }
+        assert_eq!(path, "/v2/new/users");
+        assert_eq!(operations[0].1.apply("/api", &Vec::new()), "/");
+        assert_eq!(operations[0].1.apply("/other", &Vec::new()), "/other");
 
-                    // Load balancing options
-                    let lb_policy_label = labels.get("cblt.lb_policy");
-                    let lb_interval_label = labels.get("cblt.lb_interval");
-                    let lb_timeout_label = labels.get("cblt.lb_timeout");
-                    let lb_retries_label = labels.get("cblt.lb_retries");
+        // Groups a regex pattern captured fill the values
+        let pattern = r"^/v(\d+)/items/(\d+)$";
+        let operation = UriOp::Replace {
+            find: "/v{re.1}/".to_string(),
+            replace: "/api/{re.1}/".to_string(),
+        };
+        let captures = capture_placeholders(pattern, "/v3/items/7");
+        assert_eq!(operation.apply("/v3/items/7", &captures), "/api/3/items/7");
 
-                    let lb_policy = if let Some(policy_str) = lb_policy_label {
-                        match policy_str.as_str() {
-                            "round_robin" => Some(LoadBalancePolicy::RoundRobin),
-                            "ip_hash" => Some(LoadBalancePolicy::IPHash),
-                            _ => {
-                                return Err(CbltError::KdlParseError {
-                                    details: format!("Unknown lb_policy '{}'", policy_str),
-                                });
-                            }
-                        }
-                    } else {
-                        None
-                    };
+        let doc: KdlDocument =
+            r#""example.com" { redir r"^/old/(\d+)$" "/new/{re.1}"; }"#.parse()?;
+        let config = build_config(&doc)?;
+        let Directive::Redir {
+            pattern,
+            destination,
+        } = &config["example.com"][0]
+        else {
+            panic!("expected redir");
+        };
+        assert_eq!(pattern.as_deref(), Some(r"^/old/(\d+)$"));
+        assert_eq!(destination, "/new/{re.1}");
 
-                    let lb_interval = if let Some(interval_str) = lb_interval_label {
-                        humantime::parse_duration(interval_str)
-                            .map_err(|_| CbltError::InvalidLabelFormat {
-                                details: "cblt.lb_interval".to_string(),
-                            })?
-                            .as_secs()
-                    } else {
-                        10 // Default value
-                    };
+        for invalid in [
+            r#""example.com" { uri "*" "strip_prefix"; }"#,
+            r#""example.com" { uri "*" "path_regexp" "a" "b"; }"#,
+            r#""example.com" { uri "*" "replace" "" "x"; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
 
-                    let lb_timeout = if let Some(timeout_str) = lb_timeout_label {
-                        humantime::parse_duration(timeout_str)
-                            .map_err(|_| CbltError::InvalidLabelFormat {
-                                details: "cblt.lb_timeout".to_string(),
-                            })?
-                            .as_secs()
-                    } else {
-                        1 // Default value
-                    };
+        Ok(())
+    }
 
-                    let lb_retries = if let Some(retries_str) = lb_retries_label {
-                        retries_str
-                            .parse::<u64>()
-                            .map_err(|_| CbltError::InvalidLabelFormat {
-                                details: "cblt.lb_retries".to_string(),
-                            })?
-                    } else {
-                        2 // Default value
-                    };
+    #[test]
+    fn test_listen() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"*:8080" {
+    listen "unix//run/cblt.sock" {
+        mode "0660"
+    }
+    root "*" "/var/www"
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let Directive::Listen { addresses, options } = &config["*:8080"][0] else {
+            panic!("expected listen");
+        };
+        assert_eq!(addresses, &vec!["unix//run/cblt.sock".to_string()]);
+        assert_eq!(options.mode, Some(0o660));
 
-                    let options = ReverseProxyOptions {
-                        lb_retries,
-                        lb_interval,
-                        lb_timeout,
-                        lb_policy,
-                    };
+        for invalid in [
+            r#""*:8080" { listen; }"#,
+            r#""*:8080" { listen "unix/"; }"#,
+            r#""*:8080" { listen "unix//run/cblt.sock" { mode "rw"; }; }"#,
+            r#""*:8080" { listen "localhost"; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
 
-                    // Build the ReverseProxy directive
-                    let destinations = destinations
-                        .iter()
-                        .map(|s| format!("{}:{}", s, port))
-                        .collect();
-                    let reverse_proxy_directive = Directive::ReverseProxy {
-                        pattern: path.clone(),
-                        destinations,
-                        options,
-                    };
+        Ok(())
+    }
 
-                    // For each host, add the directives
-                    for host in hosts_list {
-                        let host_directives = hosts.entry(host.to_string()).or_default();
-                        host_directives.push(reverse_proxy_directive.clone());
-
-                        // If there is a secret for this host, add the TLS directive
-                        if let Some((key, cert)) = secrets_map.get(host) {
-                            let key_data = Some(key.into());
-                            let cert_data = Some(cert.into());
-                            host_directives.push(Directive::TlS {
-                                key: key_data.ok_or(CbltError::SecretDataNotFound)?,
-                                cert: cert_data.ok_or(CbltError::SecretDataNotFound)?,
-                            });
-                        }
-                    }
-                }
-            }
+    #[test]
+    fn test_listen_addresses() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"internal.example.com:8080" {
+    listen "127.0.0.1" "10.0.0.1:8080"
+    root "*" "/var/www"
+}
+"admin.example.com:8080" {
+    listen "127.0.0.1:8080"
+    root "*" "/var/www"
+}
+"example.com:9090" {
+    listen "127.0.0.1"
+    root "*" "/var/www"
+}
+"www.example.com:9090" {
+    root "*" "/var/www"
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let servers = build_servers(build_config(&doc)?)?;
+        let listeners = &servers[&8080].listeners;
+        assert_eq!(listeners.len(), 2);
+        assert!(listeners.contains(&Listener::Tcp("127.0.0.1:8080".parse()?)));
+        assert!(listeners.contains(&Listener::Tcp("10.0.0.1:8080".parse()?)));
+        // A host without `listen` takes every address of the port
+        assert_eq!(servers[&9090].listeners, vec![Listener::any(9090)]);
+
+        let cblt_file = r#"
+"[::1]:8443" {
+    listen "::1" "127.0.0.1"
+    root "*" "/var/www"
+}
+"example.com:8443" {
+    listen "[::]:8443"
+    root "*" "/var/www"
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let servers = build_servers(build_config(&doc)?)?;
+        assert!(servers[&8443].hosts.contains_key("[::1]"));
+        // `[::]` is dual-stack
+        assert_eq!(
+            servers[&8443].listeners,
+            vec![Listener::Tcp("[::]:8443".parse()?)]
+        );
+
+        let doc: KdlDocument = r#""*:8080" { listen "127.0.0.1:9090"; }"#.parse()?;
+        assert!(build_servers(build_config(&doc)?).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_proxy_protocol() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    proxy_protocol {
+        allow "10.0.0.0/8" "192.168.1.10"
+        timeout "2s"
+    }
+}
+example.org {
+    proxy_protocol
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let Directive::ProxyProtocol { options } = &config["example.com"][0] else {
+            panic!("expected proxy_protocol");
+        };
+        assert_eq!(options.allow.len(), 2);
+        assert_eq!(options.timeout, Duration::from_secs(2));
+        let Directive::ProxyProtocol { options } = &config["example.org"][0] else {
+            panic!("expected proxy_protocol");
+        };
+        assert_eq!(options, &ProxyProtocolOptions::default());
+
+        let doc: KdlDocument =
+            r#"example.com { proxy_protocol { allow "10.0.0.0/40"; }; }"#.parse()?;
+        assert!(build_config(&doc).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_limits() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    request_limits {
+        header_timeout "5s"
+        request_timeout "1m"
+        max_header_size "16KiB"
+        max_body_size "1MiB"
+        keep_alive_timeout "5m"
+    }
+}
+example.org {
+    request_limits
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let Directive::RequestLimits { options } = &config["example.com"][0] else {
+            panic!("expected request_limits");
+        };
+        assert_eq!(options.header_timeout, Duration::from_secs(5));
+        assert_eq!(options.request_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(options.max_header_size, 16 * 1024);
+        assert_eq!(options.max_body_size, 1024 * 1024);
+        assert_eq!(options.keep_alive_timeout, Duration::from_secs(300));
+        let Directive::RequestLimits { options } = &config["example.org"][0] else {
+            panic!("expected request_limits");
+        };
+        assert_eq!(options, &RequestLimitOptions::default());
+
+        for invalid in [
+            r#"example.com { request_limits { header_timeout "0s"; }; }"#,
+            r#"example.com { request_limits { keep_alive_timeout "0s"; }; }"#,
+            r#"example.com { request_limits { max_header_size "lots"; }; }"#,
+            r#"example.com { request_limits { max_body_size "1MiB" "2MiB"; }; }"#,
+            r#"example.com { request_limits { body_timeout "5s"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
         }
+
+        Ok(())
     }
 
-    // Now we have hosts HashMap<String, Vec<Directive>>
-    // We can now build the servers
-    build_servers(hosts)
+    #[test]
+    fn test_connection_limits() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    connection_limits {
+        max_connections "10000"
+        max_per_ip "50"
+    }
+}
+example.org {
+    connection_limits
 }
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let Directive::ConnectionLimits { options } = &config["example.com"][0] else {
+            panic!("expected connection_limits");
+        };
+        assert_eq!(options.max_connections, Some(10000));
+        assert_eq!(options.max_per_ip, Some(50));
+        let Directive::ConnectionLimits { options } = &config["example.org"][0] else {
+            panic!("expected connection_limits");
+        };
+        assert_eq!(options, &ConnectionLimitOptions::default());
 
-#[cfg(test)]
-mod tests {
-    use crate::config::build_config;
-    use kdl::KdlDocument;
-    use std::error::Error;
+        for invalid in [
+            r#"example.com { connection_limits { max_per_ip "0"; }; }"#,
+            r#"example.com { connection_limits { max_connections "many"; }; }"#,
+            r#"example.com { connection_limits { max_per_host "5"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
 
     #[test]
-    fn test_simple() -> Result<(), Box<dyn Error>> {
+    fn test_auto_ban() -> Result<(), Box<dyn Error>> {
         let cblt_file = r#"
 example.com {
-    root "*" "/path/to/folder"
-    file_server
+    auto_ban {
+        statuses "401" "403" "429"
+        max_failures "5"
+        window "30s"
+        ban "1h"
+        allow "10.0.0.0/8"
+    }
 }
-            "#;
+example.org {
+    auto_ban
+}
+"#;
         let doc: KdlDocument = cblt_file.parse()?;
         let config = build_config(&doc)?;
-        println!("{:#?}", config);
+        let Directive::AutoBan { options } = &config["example.com"][0] else {
+            panic!("expected auto_ban");
+        };
+        assert_eq!(
+            options,
+            &AutoBanOptions {
+                statuses: vec!["401".to_string(), "403".to_string(), "429".to_string()],
+                max_failures: 5,
+                window: Duration::from_secs(30),
+                ban: Duration::from_secs(3600),
+                allow: vec!["10.0.0.0/8".parse()?],
+            }
+        );
+        let Directive::AutoBan { options } = &config["example.org"][0] else {
+            panic!("expected auto_ban");
+        };
+        assert_eq!(options, &AutoBanOptions::default());
+
+        for invalid in [
+            r#"example.com { auto_ban { statuses "4x"; }; }"#,
+            r#"example.com { auto_ban { statuses; }; }"#,
+            r#"example.com { auto_ban { max_failures "0"; }; }"#,
+            r#"example.com { auto_ban { ban "forever"; }; }"#,
+            r#"example.com { auto_ban { allow "10.0.0.0/33"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
 
         Ok(())
     }
 
     #[test]
-    fn test_complicated() -> Result<(), Box<dyn Error>> {
+    fn test_hsts() -> Result<(), Box<dyn Error>> {
         let cblt_file = r#"
-example1.com {
-    root "*" "/path/folder"
-    file_server
-    reverse_proxy "/api/*" "localhost:8080"
+example.com {
+    hsts {
+        max_age "180days"
+        include_subdomains
+        preload
+    }
+}
+example.org {
+    hsts
 }
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let Directive::Hsts { options } = &config["example.com"][0] else {
+            panic!("expected hsts");
+        };
+        assert_eq!(
+            options.header_value(),
+            "max-age=15552000; includeSubDomains; preload"
+        );
+        let Directive::Hsts { options } = &config["example.org"][0] else {
+            panic!("expected hsts");
+        };
+        assert_eq!(options.header_value(), "max-age=31536000");
 
-"http://example1.com" {
-    redir "https://example2.com{uri}"
+        Ok(())
+    }
+
+    #[test]
+    fn test_tracing() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    tracing "https://otel.example.com/v1/traces" {
+        service_name "edge"
+        sample "0.25"
+    }
 }
-            "#;
+example.org {
+    tracing
+}
+"#;
         let doc: KdlDocument = cblt_file.parse()?;
         let config = build_config(&doc)?;
-        println!("{:#?}", config);
+        let Directive::Tracing { options } = &config["example.com"][0] else {
+            panic!("expected tracing");
+        };
+        assert_eq!(
+            options,
+            &TracingOptions {
+                endpoint: "https://otel.example.com/v1/traces".to_string(),
+                service_name: "edge".to_string(),
+                sample: 0.25,
+            }
+        );
+        let Directive::Tracing { options } = &config["example.org"][0] else {
+            panic!("expected tracing");
+        };
+        assert_eq!(options, &TracingOptions::default());
+
+        for invalid in [
+            r#"example.com { tracing { sample "2"; }; }"#,
+            r#"example.com { tracing { sample "often"; }; }"#,
+            r#"example.com { tracing { exporter "grpc"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
 
         Ok(())
     }
 
     #[test]
-    fn test_tls() -> Result<(), Box<dyn Error>> {
+    fn test_security_headers() -> Result<(), Box<dyn Error>> {
         let cblt_file = r#"
 example.com {
-    root "*" "/path/to/folder"
-    file_server
-    tls "/path/to/your/certificate.crt" "/path/to/your/private.key"
+    security_headers {
+        frame_options "DENY"
+        permissions_policy "off"
+        content_security_policy "default-src 'self'"
+    }
 }
-            "#;
+example.org {
+    security_headers
+}
+"#;
         let doc: KdlDocument = cblt_file.parse()?;
         let config = build_config(&doc)?;
-        println!("{:#?}", config);
+        let Directive::SecurityHeaders { options } = &config["example.com"][0] else {
+            panic!("expected security_headers");
+        };
+        assert_eq!(
+            options.headers(),
+            vec![
+                ("x-content-type-options", "nosniff"),
+                ("x-frame-options", "DENY"),
+                ("referrer-policy", "strict-origin-when-cross-origin"),
+                ("content-security-policy", "default-src 'self'"),
+            ]
+        );
+        let Directive::SecurityHeaders { options } = &config["example.org"][0] else {
+            panic!("expected security_headers");
+        };
+        assert_eq!(options, &SecurityHeadersOptions::default());
+
+        for invalid in [
+            r#"example.com { security_headers { frame_options; }; }"#,
+            r#"example.com { security_headers { x_xss_protection "1"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
 
         Ok(())
     }
 
     #[test]
-    fn test_reverse_proxy_with_options() -> Result<(), Box<dyn Error>> {
+    fn test_layer4() -> Result<(), Box<dyn Error>> {
         let cblt_file = r#"
-"example.com" {
-    reverse_proxy "/api/*" "backend1:8080" "backend2:8080" {
-        health_uri "/health"
-        health_interval "10s"
-        health_timeout "2s"
-        lb_policy "round_robin"
+"*:5432" {
+    layer4 "db1:5432" "db2:5432" {
+        connect_timeout "2s"
+        proxy_protocol "v2"
     }
 }
-            "#;
+"mail.example.com:465" {
+    tls "certs/mail.crt" "certs/mail.key"
+    layer4 "unix//run/smtp.sock"
+}
+"#;
         let doc: KdlDocument = cblt_file.parse()?;
         let config = build_config(&doc)?;
-        println!("{:#?}", config);
+        let Directive::Layer4 { upstreams, options } = &config["*:5432"][0] else {
+            panic!("expected layer4");
+        };
+        assert_eq!(upstreams, &vec!["db1:5432", "db2:5432"]);
+        assert_eq!(
+            options,
+            &Layer4Options {
+                connect_timeout: Duration::from_secs(2),
+                proxy_protocol: Some(ProxyProtocolVersion::V2),
+            }
+        );
+        let Directive::Layer4 { upstreams, options } = &config["mail.example.com:465"][1] else {
+            panic!("expected layer4");
+        };
+        assert_eq!(upstreams, &vec!["unix//run/smtp.sock"]);
+        assert_eq!(options, &Layer4Options::default());
+
+        for invalid in [
+            r#""*:5432" { layer4; }"#,
+            r#""*:5432" { layer4 "http://db:5432"; }"#,
+            r#""*:5432" { layer4 "db:5432" { proxy_protocol "v3"; }; }"#,
+            r#""*:5432" { layer4 "db:5432"; layer4 "db:5433"; }"#,
+            r#""*:5432" { layer4 "db:5432"; file_server; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
 
         Ok(())
     }
 
     #[test]
-    fn test_reverse_proxy_with_cookie_lb_policy() -> Result<(), Box<dyn Error>> {
+    fn test_forward_proxy() -> Result<(), Box<dyn Error>> {
         let cblt_file = r#"
-"example.com" {
-    reverse_proxy "/api/*" "backend1:8080" "backend2:8080" {
-        lb_policy "cookie" {
-            lb_cookie_name "my_sticky_cookie"
-            lb_cookie_path "/"
-            lb_cookie_max_age "3600"
+"*:3128" {
+    forward_proxy {
+        basic_auth "alice" "secret"
+        allow "10.0.0.0/8" "*.Example.com" "api.test:8443"
+        deny "10.0.0.1"
+        ports "443" "8443"
+        connect_timeout "2s"
+    }
+}
+"*:8080" {
+    forward_proxy {
+        allow "*.example.com"
+    }
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let Directive::ForwardProxy { options } = &config["*:3128"][0] else {
+            panic!("expected forward_proxy");
+        };
+        assert_eq!(
+            options,
+            &ForwardProxyOptions {
+                users: vec![ProxyUser {
+                    name: "alice".to_string(),
+                    password: "secret".into(),
+                }],
+                allow: vec![
+                    ProxyTarget::Range("10.0.0.0/8".parse()?),
+                    ProxyTarget::Host {
+                        pattern: "*.example.com".to_string(),
+                        port: None,
+                    },
+                    ProxyTarget::Host {
+                        pattern: "api.test".to_string(),
+                        port: Some(8443),
+                    },
+                ],
+                deny: vec![ProxyTarget::Range("10.0.0.1".parse()?)],
+                ports: vec![443, 8443],
+                connect_timeout: Duration::from_secs(2),
+            }
+        );
+        let Directive::ForwardProxy { options } = &config["*:8080"][0] else {
+            panic!("expected forward_proxy");
+        };
+        assert_eq!(options.ports, vec![443]);
+        assert!(options.users.is_empty());
+
+        for invalid in [
+            r#""*:3128" { forward_proxy; }"#,
+            r#""*:3128" { forward_proxy { ports "8443"; }; }"#,
+            r#""*:3128" { forward_proxy { basic_auth "alice"; }; }"#,
+            r#""*:3128" { forward_proxy { allow "*.*.example.com"; }; }"#,
+            r#""*:3128" { forward_proxy { deny "example.com:https"; }; }"#,
+            r#""*:3128" { forward_proxy { ports "0x1bb"; }; }"#,
+            r#""*:3128" { forward_proxy { upstream "proxy:3128"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
         }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wasm() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+example.com {
+    wasm "/api/*" "./filter.wasm" {
+        config "{\"deny\": [\"/api/admin\"]}"
+        fuel "1000000"
+        memory "16MiB"
     }
+    wasm "./log.wasm"
 }
-            "#;
+"#;
         let doc: KdlDocument = cblt_file.parse()?;
+        if !cfg!(feature = "wasm") {
+            assert!(build_config(&doc).is_err());
+            return Ok(());
+        }
         let config = build_config(&doc)?;
-        println!("{:#?}", config);
+        let Directive::Wasm {
+            pattern,
+            path,
+            options,
+        } = &config["example.com"][0]
+        else {
+            panic!("expected wasm");
+        };
+        assert_eq!(
+            (pattern.as_str(), path.as_str()),
+            ("/api/*", "./filter.wasm")
+        );
+        assert_eq!(
+            options,
+            &WasmOptions {
+                config: r#"{"deny": ["/api/admin"]}"#.to_string(),
+                fuel: 1_000_000,
+                memory: 16 * 1024 * 1024,
+            }
+        );
+        let Directive::Wasm {
+            pattern,
+            path,
+            options,
+        } = &config["example.com"][1]
+        else {
+            panic!("expected wasm");
+        };
+        assert_eq!((pattern.as_str(), path.as_str()), ("*", "./log.wasm"));
+        assert_eq!(options, &WasmOptions::default());
+
+        for invalid in [
+            r#"example.com { wasm; }"#,
+            r#"example.com { wasm "./a.wasm" { fuel "0"; }; }"#,
+            r#"example.com { wasm "./a.wasm" { vm "v8"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
 
         Ok(())
     }
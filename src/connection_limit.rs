@@ -0,0 +1,149 @@
+use crate::config::{ConnectionLimitOptions, Directive};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// The first `connection_limits` of the hosts on a port applies to the whole listener
+pub fn listener_options(hosts: &HashMap<String, Vec<Directive>>) -> ConnectionLimitOptions {
+    let mut hostnames: Vec<&String> = hosts.keys().collect();
+    hostnames.sort();
+    hostnames
+        .iter()
+        .find_map(|host| {
+            hosts[*host].iter().find_map(|directive| match directive {
+                Directive::ConnectionLimits { options } => Some(options.clone()),
+                _ => None,
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// Open connections of a server's listeners, kept across configuration reloads
+pub struct ConnectionLimits {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    options: ConnectionLimitOptions,
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts a connection until dropped
+pub struct ConnectionSlot {
+    limits: Arc<ConnectionLimits>,
+    client: Option<IpAddr>,
+}
+
+impl ConnectionLimits {
+    pub fn new(options: ConnectionLimitOptions) -> Self {
+        ConnectionLimits {
+            state: Mutex::new(State {
+                options,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Takes effect for new connections, open ones over a lowered limit are left alone
+    pub fn set_options(&self, options: ConnectionLimitOptions) {
+        if let Ok(mut state) = self.state.lock() {
+            state.options = options;
+        }
+    }
+
+    /// Slot for a new connection, none when `max_connections` are open
+    pub fn open(self: &Arc<Self>) -> Option<ConnectionSlot> {
+        let mut state = self.state.lock().ok()?;
+        if state
+            .options
+            .max_connections
+            .is_some_and(|max| state.total >= max)
+        {
+            return None;
+        }
+        state.total += 1;
+        Some(ConnectionSlot {
+            limits: self.clone(),
+            client: None,
+        })
+    }
+}
+
+impl ConnectionSlot {
+    /// Counts the connection for its client, false when the client has `max_per_ip` open already
+    pub fn client(&mut self, ip: IpAddr) -> bool {
+        let Ok(mut state) = self.limits.state.lock() else {
+            return true;
+        };
+        let open = state.by_ip.get(&ip).copied().unwrap_or(0);
+        if state.options.max_per_ip.is_some_and(|max| open >= max) {
+            return false;
+        }
+        state.by_ip.insert(ip, open + 1);
+        self.client = Some(ip);
+        true
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let Ok(mut state) = self.limits.state.lock() else {
+            return;
+        };
+        state.total -= 1;
+        if let Some(ip) = self.client {
+            if let Some(open) = state.by_ip.get_mut(&ip) {
+                *open -= 1;
+                if *open == 0 {
+                    state.by_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ConnectionLimitOptions;
+    use crate::connection_limit::ConnectionLimits;
+    use std::error::Error;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_connection_limits() -> Result<(), Box<dyn Error>> {
+        let limits = Arc::new(ConnectionLimits::new(ConnectionLimitOptions {
+            max_connections: Some(3),
+            max_per_ip: Some(2),
+        }));
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse()?, "10.0.0.2".parse()?);
+
+        let mut first = limits.open().ok_or("expected a slot")?;
+        assert!(first.client(a));
+        let mut second = limits.open().ok_or("expected a slot")?;
+        assert!(second.client(a));
+        let mut third = limits.open().ok_or("expected a slot")?;
+        assert!(!third.client(a));
+        assert!(limits.open().is_none());
+
+        // A shed connection frees its slot
+        drop(third);
+        let mut third = limits.open().ok_or("expected a slot")?;
+        assert!(third.client(b));
+        drop(first);
+        let mut fourth = limits.open().ok_or("expected a slot")?;
+        assert!(fourth.client(a));
+        drop(second);
+
+        limits.set_options(ConnectionLimitOptions::default());
+        let unlimited: Vec<_> = (0..10).filter_map(|_| limits.open()).collect();
+        assert_eq!(unlimited.len(), 10);
+        let mut fifth = limits.open().ok_or("expected a slot")?;
+        assert!(fifth.client(a));
+        drop((third, fourth));
+
+        Ok(())
+    }
+}
@@ -1,32 +1,77 @@
-use crate::config::Directive;
+use crate::access_log::RequestLog;
+use crate::config::{Directive, EncodeOptions, ErrorPage};
 use crate::error::CbltError;
-use crate::request::{socket_to_request, BUF_SIZE};
-use crate::response::{error_response, log_request_response, send_response};
-use crate::server::ServerSettings;
-use crate::{file_server, matches_pattern, reverse_proxy};
+#[cfg(feature = "wasm")]
+use crate::handler::Handler;
+use crate::handler::{Context, HandlerFuture, Next};
+use crate::headers::{fill_placeholders, fill_value, header_placeholders};
+use crate::matcher::request_cookies;
+use crate::otel::{Span, SpanKind, TRACEPARENT};
+use crate::pattern::capture_placeholders;
+use crate::rate_limit::retry_after;
+use crate::request::{is_keep_alive, socket_to_request};
+use crate::response::{
+    custom_error_response, error_response, send_response, with_headers, ExtraHeaders,
+};
+use crate::sendfile::Sendfile;
+use crate::server::{HostDetails, ServerSettings};
+use crate::split_port;
+use crate::throttle::{Throttle, Throttled};
+use crate::webdav::{is_webdav_method, webdav_directive};
+use crate::{acme, fastcgi, file_server, forward_auth, forward_proxy, remote_ip, reverse_proxy};
 use bytes::BytesMut;
-use http::{Response, StatusCode};
+use http::header::{CONNECTION, CONTENT_TYPE, HOST, RETRY_AFTER, STRICT_TRANSPORT_SECURITY};
+use http::{HeaderValue, Method, Request, Response, StatusCode, Version};
 use log::{debug, error};
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 #[cfg(feature = "trace")]
 use tracing::instrument;
 
+/// Host block for the Host header, a host starting with "*" takes every request
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn find_host<'a>(
+    hosts: &'a HashMap<String, HostDetails>,
+    host: &str,
+) -> Option<(&'a String, &'a HostDetails)> {
+    // Hosts are configured per port, so the port in the header is not part of the name
+    let (host, _) = split_port(host);
+    hosts
+        .iter()
+        .find(|(k, _)| k.starts_with("*"))
+        .or_else(|| hosts.get_key_value(host))
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn directive_process<S>(
     socket: &mut S,
+    buffer: &mut BytesMut,
     settings: Arc<ServerSettings>,
     addr: SocketAddr,
-) -> Result<(), CbltError>
+    request_log: &mut RequestLog,
+) -> Result<bool, CbltError>
 where
-    S: AsyncReadExt + AsyncWriteExt + Unpin,
+    S: AsyncReadExt + AsyncWriteExt + Sendfile + Unpin + Send,
 {
-    let mut buffer = BytesMut::with_capacity(BUF_SIZE);
-    match socket_to_request(socket, &mut buffer).await {
+    let upload = |request: &Request<BytesMut>| {
+        let host = request.headers().get(HOST)?.to_str().ok()?;
+        let (_, host_config) = find_host(&settings.hosts, host)?;
+        host_config.throttle(request)?.upload()
+    };
+    match socket_to_request(socket, buffer, &settings.request_limits, &upload).await {
         Err(err) => {
-            let response = error_response(StatusCode::BAD_REQUEST);
-            let ret = send_response(socket, response?).await;
+            let mut extra_headers = ExtraHeaders::default();
+            extra_headers
+                .set
+                .insert(CONNECTION, HeaderValue::from_static("close"));
+            let status = match &err {
+                CbltError::RequestError { status_code, .. } => *status_code,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            let response = with_headers(error_response(status)?, &extra_headers);
+            let ret = send_response(socket, response).await;
             match ret {
                 Ok(()) => {}
                 Err(err) => {
@@ -38,201 +83,495 @@ where
             Err(err)
         }
         Ok(request) => {
+            request_log.received(&request);
+            let keep_alive = is_keep_alive(&request);
+            let mut extra_headers = ExtraHeaders::default();
+            match (keep_alive, request.version()) {
+                (true, Version::HTTP_10) => {
+                    extra_headers
+                        .set
+                        .insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+                }
+                (false, _) => {
+                    extra_headers
+                        .set
+                        .insert(CONNECTION, HeaderValue::from_static("close"));
+                }
+                _ => {}
+            }
+
+            // HTTP-01 validation requests arrive on port 80 for any managed host
+            if let Some(key_authorization) = acme::http01_response(request.uri().path()) {
+                let response = Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .body(BytesMut::from(key_authorization.as_str()))?;
+                send_response(socket, with_headers(response, &extra_headers)).await?;
+                request_log.record(&request, StatusCode::OK);
+                return Ok(keep_alive);
+            }
+
             let host = match request.headers().get("Host") {
                 Some(h) => h.to_str().unwrap_or(""),
                 None => "",
             };
-
-            // find host starting with "*"
-            let cfg_opt = settings.hosts.iter().find(|(k, _)| k.starts_with("*"));
-            let host_config = match cfg_opt {
+            let (host_name, host_config) = match find_host(&settings.hosts, host) {
+                Some(cfg) => cfg,
                 None => {
-                    let host_config = match settings.hosts.get(host) {
-                        Some(cfg) => cfg,
-                        None => {
-                            let response = error_response(StatusCode::FORBIDDEN);
-                            let _ = send_response(socket, response?).await;
-                            return Err(CbltError::ResponseError {
-                                details: "Forbidden".to_string(),
-                                status_code: StatusCode::FORBIDDEN,
-                            });
-                        }
-                    };
-                    host_config
+                    let response =
+                        with_headers(error_response(StatusCode::FORBIDDEN)?, &extra_headers);
+                    let _ = send_response(socket, response).await;
+                    return Err(CbltError::ResponseError {
+                        details: "Forbidden".to_string(),
+                        status_code: StatusCode::FORBIDDEN,
+                    });
                 }
-                Some((_, cfg)) => cfg,
             };
+            request_log.host = Some(host_name.clone());
+            request_log.span = host_config
+                .tracer
+                .as_ref()
+                .map(|tracer| tracer.start(&request, addr, settings.scheme()));
+            // Whatever answers the request goes out at the rate of its `throttle`
+            let download = host_config.throttle(&request).map(Throttle::download);
+            let socket = &mut Throttled::new(socket, download, None);
+
+            // Browsers ignore HSTS over plain HTTP, so only TLS listeners send it
+            if settings.tls_acceptor.is_some() {
+                let hsts = host_config
+                    .directives
+                    .iter()
+                    .find_map(|directive| match directive {
+                        Directive::Hsts { options } => Some(options),
+                        _ => None,
+                    });
+                if let Some(hsts) = hsts {
+                    extra_headers.set.insert(
+                        STRICT_TRANSPORT_SECURITY,
+                        HeaderValue::from_str(&hsts.header_value())?,
+                    );
+                }
+            }
 
-            let mut root_path: Option<&str> = None;
+            let security_headers =
+                host_config
+                    .directives
+                    .iter()
+                    .find_map(|directive| match directive {
+                        Directive::SecurityHeaders { options } => Some(options),
+                        _ => None,
+                    });
+            if let Some(security_headers) = security_headers {
+                for (name, value) in security_headers.headers() {
+                    extra_headers
+                        .set
+                        .insert(name, HeaderValue::from_str(value)?);
+                }
+            }
 
+            // `header` edits whatever response the request ends up with
+            let mut placeholders =
+                header_placeholders(request.method(), request.uri(), Some(host), addr);
+            placeholders.extend(host_config.geoip_placeholders(&request, addr));
+            if host_config.geoip.is_some() {
+                request_log.country = Some(host_config.country(&request, addr).unwrap_or_default());
+            }
             for directive in &host_config.directives {
-                match directive {
-                    Directive::Root { pattern, path } => {
-                        #[cfg(debug_assertions)]
-                        debug!("Root: {} -> {}", pattern, path);
-                        if matches_pattern(pattern.as_str(), request.uri().path()) {
-                            root_path = Some(path.as_str());
-                        }
+                if let Directive::Header {
+                    pattern,
+                    operations,
+                } = directive
+                {
+                    if host_config.matches(pattern, &request) {
+                        let mut placeholders = placeholders.clone();
+                        placeholders.extend(capture_placeholders(pattern, request.uri().path()));
+                        extra_headers
+                            .operations
+                            .extend(fill_placeholders(operations, &placeholders));
                     }
-                    Directive::FileServer => {
-                        #[cfg(debug_assertions)]
-                        debug!("File server");
-                        let ret = file_server::file_directive(root_path, &request, socket).await;
-                        match ret {
-                            Ok(_) => {
-                                log_request_response(&request, StatusCode::OK);
-                                return Ok(());
-                            }
-                            Err(error) => match error {
-                                CbltError::ResponseError {
-                                    details: _,
-                                    status_code,
-                                } => {
-                                    let response = error_response(status_code);
-                                    match send_response(socket, response?).await {
-                                        Ok(()) => {
-                                            log_request_response(&request, status_code);
-                                            return Ok(());
-                                        }
-                                        Err(err) => {
-                                            log_request_response(
-                                                &request,
-                                                StatusCode::INTERNAL_SERVER_ERROR,
-                                            );
-                                            return Err(err);
-                                        }
-                                    }
-                                }
-                                CbltError::DirectiveNotMatched => {}
-                                err => {
-                                    log_request_response(
-                                        &request,
-                                        StatusCode::INTERNAL_SERVER_ERROR,
-                                    );
-                                    return Err(err);
-                                }
-                            },
+                }
+            }
+
+            // Compression applies to the whole host wherever it is declared
+            let encode: Option<&EncodeOptions> =
+                host_config
+                    .directives
+                    .iter()
+                    .find_map(|directive| match directive {
+                        Directive::Encode { options } => Some(options),
+                        _ => None,
+                    });
+            let error_pages: Vec<(&[String], &ErrorPage)> = host_config
+                .directives
+                .iter()
+                .filter_map(|directive| match directive {
+                    Directive::ErrorPage { statuses, page } => Some((statuses.as_slice(), page)),
+                    _ => None,
+                })
+                .collect();
+
+            // `remote_ip` rules apply wherever they are declared in the host
+            let allowed = remote_ip::allowed(host_config, &request, addr);
+            let mut ctx = Context {
+                request,
+                socket,
+                buffer,
+                addr,
+                settings: &settings,
+                host: host_config,
+                log: request_log,
+                extra_headers,
+                root: None,
+                encode,
+                error_pages,
+                keep_alive,
+            };
+            let ret = if allowed {
+                run_chain(&mut ctx, 0).await
+            } else {
+                ctx.respond_error(StatusCode::FORBIDDEN).await
+            };
+            match ret {
+                Ok(status) => {
+                    ctx.log.record(&ctx.request, status);
+                    Ok(ctx.keep_alive)
+                }
+                Err(err) => {
+                    ctx.log
+                        .record(&ctx.request, StatusCode::INTERNAL_SERVER_ERROR);
+                    Err(err)
+                }
+            }
+        }
+    }
+}
+
+/// Runs the host's directives from `from` on until one answers, the 404 page answers when none
+/// does
+pub(crate) fn run_chain<'a>(ctx: &'a mut Context<'_>, from: usize) -> HandlerFuture<'a> {
+    Box::pin(async move {
+        let host_config = ctx.host;
+        for (index, directive) in host_config.directives.iter().enumerate().skip(from) {
+            match directive {
+                Directive::Root { pattern, path } => {
+                    #[cfg(debug_assertions)]
+                    debug!("Root: {} -> {}", pattern, path);
+                    if ctx.matches(pattern) {
+                        ctx.root = Some(path.as_str());
+                    }
+                }
+                Directive::FileServer { options } => {
+                    #[cfg(debug_assertions)]
+                    debug!("File server");
+                    let span = ctx.log.child_span("file_server", SpanKind::Internal);
+                    let dav_locks = host_config
+                        .dav_locks
+                        .as_ref()
+                        .filter(|_| options.webdav && is_webdav_method(ctx.request.method()));
+                    let ret = match dav_locks {
+                        Some(locks) => {
+                            webdav_directive(
+                                ctx.root,
+                                options,
+                                locks,
+                                &ctx.request,
+                                &mut ctx.socket,
+                                &ctx.extra_headers,
+                            )
+                            .await
                         }
-                        break;
+                        None => {
+                            file_server::file_directive(
+                                ctx.root,
+                                options,
+                                options.cache.as_ref().and(host_config.file_cache.as_ref()),
+                                &ctx.request,
+                                &mut ctx.socket,
+                                &ctx.extra_headers,
+                                ctx.encode,
+                            )
+                            .await
+                        }
+                    };
+                    end_span(span, ret.as_ref().copied());
+                    match answered(ctx, ret).await? {
+                        Some(status) => return Ok(status),
+                        None => break,
                     }
-                    Directive::ReverseProxy {
-                        pattern,
-                        destinations,
-                        ..
-                    } => {
-                        #[cfg(debug_assertions)]
-                        debug!("Reverse proxy: {} -> {:?}", pattern, destinations);
-                        match reverse_proxy::proxy_directive(
-                            &request,
-                            socket,
-                            &host_config.reverse_proxy_states,
-                            addr,
-                            directive,
-                        )
-                        .await
-                        {
-                            Ok(status) => {
-                                log_request_response(&request, status);
-                                return Ok(());
-                            }
-                            Err(err) => match err {
-                                CbltError::DirectiveNotMatched => {}
-                                CbltError::ResponseError {
-                                    details: _,
-                                    status_code,
-                                } => {
-                                    let response = error_response(status_code);
-                                    match send_response(socket, response?).await {
-                                        Ok(()) => {
-                                            log_request_response(&request, status_code);
-                                            return Ok(());
-                                        }
-                                        Err(err) => {
-                                            log_request_response(
-                                                &request,
-                                                StatusCode::INTERNAL_SERVER_ERROR,
-                                            );
-                                            return Err(err);
-                                        }
-                                    }
-                                }
-                                other => {
-                                    log_request_response(
-                                        &request,
-                                        StatusCode::INTERNAL_SERVER_ERROR,
-                                    );
-                                    return Err(other);
-                                }
-                            },
+                }
+                Directive::ReverseProxy {
+                    pattern,
+                    destinations,
+                    ..
+                } => {
+                    #[cfg(debug_assertions)]
+                    debug!("Reverse proxy: {} -> {:?}", pattern, destinations);
+                    // The upstream continues the trace from the proxy's span
+                    let span = ctx.log.child_span("reverse_proxy", SpanKind::Client);
+                    if let Some(span) = &span {
+                        ctx.request.headers_mut().insert(
+                            TRACEPARENT,
+                            HeaderValue::from_str(&span.context.traceparent())?,
+                        );
+                    }
+                    let ret = reverse_proxy::proxy_directive(
+                        &ctx.request,
+                        &mut ctx.socket,
+                        ctx.buffer,
+                        host_config,
+                        ctx.addr,
+                        directive,
+                        &ctx.extra_headers,
+                        ctx.encode,
+                        ctx.settings.scheme(),
+                    )
+                    .await;
+                    end_span(span, ret.as_ref().map(|(status, _)| *status));
+                    let ret = ret.map(|(status, proxy_keep_alive)| {
+                        ctx.keep_alive &= proxy_keep_alive;
+                        status
+                    });
+                    if let Some(status) = answered(ctx, ret).await? {
+                        return Ok(status);
+                    }
+                }
+                Directive::PhpFastcgi {
+                    pattern,
+                    upstream,
+                    options,
+                } => {
+                    if !ctx.matches(pattern) {
+                        continue;
+                    }
+                    let span = ctx.log.child_span("php_fastcgi", SpanKind::Client);
+                    let ret = fastcgi::php_fastcgi_directive(
+                        &ctx.request,
+                        &mut ctx.socket,
+                        ctx.root,
+                        upstream,
+                        options,
+                        ctx.addr,
+                        ctx.settings.scheme(),
+                        &ctx.extra_headers,
+                        ctx.encode,
+                    )
+                    .await;
+                    end_span(span, ret.as_ref().map(|(status, _)| *status));
+                    #[cfg(debug_assertions)]
+                    if let Err(CbltError::ResponseError { details, .. }) = &ret {
+                        error!("Error: {}", details);
+                    }
+                    let ret = ret.map(|(status, fastcgi_keep_alive)| {
+                        ctx.keep_alive &= fastcgi_keep_alive;
+                        status
+                    });
+                    if let Some(status) = answered(ctx, ret).await? {
+                        return Ok(status);
+                    }
+                }
+                Directive::Redir {
+                    pattern,
+                    destination,
+                } => {
+                    let pattern = pattern.as_deref().unwrap_or("*");
+                    if !ctx.matches(pattern) {
+                        continue;
+                    }
+                    let captures = capture_placeholders(pattern, ctx.request.uri().path());
+                    let dest = fill_value(destination, &captures)
+                        .replace("{uri}", ctx.request.uri().path());
+                    let response = Response::builder()
+                        .status(StatusCode::FOUND)
+                        .header("Location", &dest)
+                        .body(BytesMut::new())?; // Empty body for redirects?
+                    return ctx.respond(response).await;
+                }
+                Directive::RedirIfNotCookie {
+                    cookiename,
+                    destination,
+                } => {
+                    // The exact name, so "session" is not satisfied by "session_csrf"
+                    if request_cookies(&ctx.request).any(|(name, _)| name == cookiename) {
+                        debug!("Cookie found: {}", cookiename);
+                        continue;
+                    }
+                    let dest = destination.replace("{uri}", ctx.request.uri().path());
+                    let response = Response::builder()
+                        .status(StatusCode::FOUND)
+                        .header("Location", &dest)
+                        .body(BytesMut::new())?; // Empty body for redirects?
+                    return ctx.respond(response).await;
+                }
+
+                Directive::TryFiles {
+                    pattern,
+                    candidates,
+                } => {
+                    if !ctx.matches(pattern) {
+                        continue;
+                    }
+                    if let Some(root) = ctx.root {
+                        let path = ctx.request.uri().path();
+                        if let Some(found) = file_server::try_files(root, candidates, path) {
+                            #[cfg(debug_assertions)]
+                            debug!("Try files: {} -> {}", path, found);
+                            set_path(&mut ctx.request, found);
                         }
                     }
-                    Directive::Redir { destination } => {
-                        let dest = destination.replace("{uri}", request.uri().path());
-                        let response = Response::builder()
-                            .status(StatusCode::FOUND)
-                            .header("Location", &dest)
-                            .body(BytesMut::new())?; // Empty body for redirects?
-                                                     //
-                        match send_response(socket, response).await {
-                            Ok(_) => {
-                                log_request_response(&request, StatusCode::FOUND);
-                                return Ok(());
-                            }
-                            Err(err) => {
-                                log_request_response(&request, StatusCode::INTERNAL_SERVER_ERROR);
-                                return Err(err);
-                            }
+                }
+
+                Directive::ForwardProxy { options } => {
+                    // Other requests are for the directives after it
+                    if ctx.request.method() == Method::CONNECT {
+                        return forward_proxy::connect(ctx, options).await;
+                    }
+                }
+
+                Directive::ForwardAuth {
+                    pattern,
+                    upstream,
+                    options,
+                } => {
+                    if !ctx.matches(pattern) {
+                        continue;
+                    }
+                    let answer = forward_auth::authorize(
+                        &mut ctx.request,
+                        upstream,
+                        options,
+                        ctx.addr,
+                        ctx.settings.scheme(),
+                    )
+                    .await;
+                    return match answer {
+                        Ok(None) => continue,
+                        Ok(Some(response)) => ctx.respond(response).await,
+                        Err(CbltError::ResponseError {
+                            details: _details,
+                            status_code,
+                        }) => {
+                            #[cfg(debug_assertions)]
+                            error!("Error: {}", _details);
+                            ctx.respond_error(status_code).await
                         }
+                        Err(err) => Err(err),
+                    };
+                }
+
+                Directive::RateLimit { pattern, .. } => {
+                    if !ctx.matches(pattern) {
+                        continue;
                     }
-                    Directive::RedirIfNotCookie {
-                        cookiename,
-                        destination,
-                    } => {
-                        let dest = destination.replace("{uri}", request.uri().path());
-                        let response = Response::builder()
-                            .status(StatusCode::FOUND)
-                            .header("Location", &dest)
-                            .body(BytesMut::new())?; // Empty body for redirects?
-                                                     //
-                        let cookies = match request.headers().get("Cookie") {
-                            Some(cookies) => cookies.to_str().unwrap_or(""),
-                            None => "",
-                        };
-
-                        match cookies
-                            .split(';')
-                            .collect::<Vec<&str>>()
-                            .iter()
-                            .find(|&x| x.contains(cookiename))
-                        {
-                            Some(_) => debug!("Cookie found: {}", cookiename),
-                            None => match send_response(socket, response).await {
-                                Ok(_) => {
-                                    log_request_response(&request, StatusCode::FOUND);
-                                    return Ok(());
-                                }
-                                Err(err) => {
-                                    log_request_response(
-                                        &request,
-                                        StatusCode::INTERNAL_SERVER_ERROR,
-                                    );
-                                    return Err(err);
-                                }
-                            },
-                        };
-                    }
-
-                    Directive::TlS { .. } => {}
+                    let Some(limiter) = host_config.rate_limiters.get(pattern) else {
+                        continue;
+                    };
+                    let Err(wait) = limiter.check(&ctx.request, ctx.addr).await else {
+                        continue;
+                    };
+                    let mut response =
+                        custom_error_response(StatusCode::TOO_MANY_REQUESTS, &ctx.error_pages)
+                            .await?;
+                    response
+                        .headers_mut()
+                        .insert(RETRY_AFTER, HeaderValue::from(retry_after(wait)));
+                    return ctx.respond(response).await;
                 }
-            }
 
-            let response = error_response(StatusCode::NOT_FOUND);
-            if let Err(err) = send_response(socket, response?).await {
-                log_request_response(&request, StatusCode::INTERNAL_SERVER_ERROR);
-                return Err(err);
+                Directive::Uri { pattern, operation } => {
+                    if ctx.matches(pattern) {
+                        let captures = capture_placeholders(pattern, ctx.request.uri().path());
+                        let path = operation.apply(ctx.request.uri().path(), &captures);
+                        #[cfg(debug_assertions)]
+                        debug!("Uri: {} -> {}", ctx.request.uri().path(), path);
+                        set_path(&mut ctx.request, path);
+                    }
+                }
+
+                #[cfg(feature = "wasm")]
+                Directive::Wasm { .. } => {
+                    if let Some(plugin) = host_config.wasm_plugins.get(&index) {
+                        let next = Next { from: index + 1 };
+                        return plugin.handle(ctx, next).await;
+                    }
+                }
+
+                Directive::Handler {
+                    handler: Some(handler),
+                    ..
+                } => {
+                    let next = Next { from: index + 1 };
+                    return handler.0.handle(ctx, next).await;
+                }
+
+                Directive::Encode { .. }
+                | Directive::ErrorPage { .. }
+                | Directive::AccessLog { .. }
+                | Directive::Tracing { .. }
+                | Directive::Hsts { .. }
+                | Directive::SecurityHeaders { .. }
+                | Directive::Header { .. }
+                | Directive::Matcher { .. }
+                | Directive::RemoteIp { .. }
+                | Directive::Country { .. }
+                | Directive::GeoIp { .. }
+                | Directive::Throttle { .. }
+                | Directive::Handler { handler: None, .. } => {}
+
+                #[cfg(not(feature = "wasm"))]
+                Directive::Wasm { .. } => {}
+
+                Directive::TlS { .. }
+                | Directive::TlsAcme { .. }
+                | Directive::TlsOptions { .. }
+                | Directive::ProxyProtocol { .. }
+                | Directive::RequestLimits { .. }
+                | Directive::ConnectionLimits { .. }
+                | Directive::AutoBan { .. }
+                | Directive::Listen { .. }
+                | Directive::Layer4 { .. } => {}
             }
-            log_request_response(&request, StatusCode::NOT_FOUND);
-            Ok(())
         }
+
+        ctx.respond_error(StatusCode::NOT_FOUND).await
+    })
+}
+
+/// Status a directive answered with, sending the error page when it failed with a status, None
+/// when the directive passed the request on
+async fn answered(
+    ctx: &mut Context<'_>,
+    ret: Result<StatusCode, CbltError>,
+) -> Result<Option<StatusCode>, CbltError> {
+    match ret {
+        Ok(status) => Ok(Some(status)),
+        Err(CbltError::DirectiveNotMatched) => Ok(None),
+        Err(CbltError::ResponseError { status_code, .. }) => {
+            ctx.respond_error(status_code).await.map(Some)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Ends a directive's span with the status it answered, dropping it when the directive passed
+fn end_span(span: Option<Span>, ret: Result<StatusCode, &CbltError>) {
+    let status = match ret {
+        Ok(status) => status,
+        Err(CbltError::DirectiveNotMatched) => return,
+        Err(CbltError::ResponseError { status_code, .. }) => *status_code,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if let Some(span) = span {
+        span.end(status);
+    }
+}
+
+/// Replaces the path of the request, keeping its query
+fn set_path(request: &mut Request<BytesMut>, path: String) {
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    if let Ok(uri) = path_and_query.parse() {
+        *request.uri_mut() = uri;
     }
 }
@@ -0,0 +1,386 @@
+use crate::config::{ConsulOptions, DnsDiscoveryOptions};
+use crate::dns::{http_response, lookup_ip};
+use crate::error::CbltError;
+use crate::reverse_proxy::{backend_authority, unix_socket_path, Backend, Backends};
+use bytes::Bytes;
+use futures_util::future::join_all;
+use http::Request;
+use http_body_util::Full;
+use log::info;
+#[cfg(debug_assertions)]
+use log::warn;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+const CONSUL_WAIT: Duration = Duration::from_secs(300); // longest a blocking query is held
+const CONSUL_MIN_INTERVAL: Duration = Duration::from_secs(1); // between two queries of a service
+const CONSUL_RETRY: Duration = Duration::from_secs(5); // after a failed query
+
+/// Upstreams as configured and the addresses found for them, rebuilding the backends on a change
+struct Discovery {
+    backends: Backends,
+    upstreams: Vec<(String, bool)>, // urls with their backup flag, `consul://` ones included
+    resolved: Mutex<HashMap<String, Vec<String>>>, // addresses of each upstream from its last lookup
+}
+
+impl Discovery {
+    fn update(&self, upstream: &str, urls: Vec<String>) {
+        let Ok(mut resolved) = self.resolved.lock() else {
+            return;
+        };
+        if resolved.get(upstream) == Some(&urls) {
+            return;
+        }
+        resolved.insert(upstream.to_string(), urls);
+
+        let current = self.backends.get();
+        let next: Vec<Backend> = self
+            .upstreams
+            .iter()
+            .flat_map(|(url, backup)| {
+                let urls = match resolved.get(url) {
+                    Some(urls) => urls.clone(),
+                    None if consul_service(url).is_some() => Vec::new(),
+                    None => vec![url.clone()],
+                };
+                urls.into_iter().map(move |url| (url, *backup))
+            })
+            .map(|(url, backup)| {
+                // Backends that stay keep their health and counters
+                current
+                    .iter()
+                    .find(|backend| backend.url == url && backend.backup == backup)
+                    .cloned()
+                    .unwrap_or_else(|| Backend {
+                        backup,
+                        ..Backend::new(url)
+                    })
+            })
+            .collect();
+        let urls = |backends: &[Backend]| {
+            backends
+                .iter()
+                .map(|backend| backend.url.clone())
+                .collect::<Vec<_>>()
+        };
+        if urls(&next) != urls(&current) {
+            info!("Discovered backends: {}", urls(&next).join(", "));
+            self.backends.set(next);
+        }
+    }
+}
+
+/// Keeps the backends in sync with the instances of the `consul://` upstreams and, with
+/// `dns_discovery`, with the addresses of the upstreams given by hostname
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn start(
+    backends: &Backends,
+    upstreams: Vec<(String, bool)>,
+    dns_discovery: Option<&DnsDiscoveryOptions>,
+    consul: &ConsulOptions,
+) -> Option<JoinHandle<()>> {
+    let discovery = Arc::new(Discovery {
+        backends: backends.clone(),
+        upstreams,
+        resolved: Mutex::new(HashMap::new()),
+    });
+    let mut tasks: Vec<Pin<Box<dyn Future<Output = ()> + Send>>> = Vec::new();
+    for (url, _) in &discovery.upstreams {
+        if let Some(service) = consul_service(url) {
+            tasks.push(Box::pin(watch_consul(
+                discovery.clone(),
+                url.clone(),
+                service.to_string(),
+                consul.clone(),
+            )));
+        }
+    }
+    if let Some(options) = dns_discovery {
+        if discovery
+            .upstreams
+            .iter()
+            .any(|(url, _)| upstream_host(url).is_some())
+        {
+            tasks.push(Box::pin(refresh_dns(discovery.clone(), options.clone())));
+        }
+    }
+    if tasks.is_empty() {
+        return None;
+    }
+    Some(tokio::spawn(async move {
+        join_all(tasks).await;
+    }))
+}
+
+/// Resolves the upstream hostnames again whenever the shortest TTL of their records runs out,
+/// between `min_refresh` and `max_refresh`, and puts one backend per address in their place
+async fn refresh_dns(discovery: Arc<Discovery>, options: DnsDiscoveryOptions) {
+    loop {
+        let mut refresh = options.max_refresh;
+        for (url, _) in &discovery.upstreams {
+            let Some((host, port)) = upstream_host(url) else {
+                continue;
+            };
+            let ips = match lookup_ip(&host, options.resolver).await {
+                Ok((ips, ttl)) => {
+                    refresh = refresh.min(ttl as u64);
+                    ips
+                }
+                Err(err) => {
+                    #[cfg(debug_assertions)]
+                    warn!("DNS lookup of {} failed: {}", host, err);
+                    // Names only the system knows, e.g. from /etc/hosts, are tried soon again
+                    refresh = options.min_refresh;
+                    match tokio::net::lookup_host((host.as_str(), port)).await {
+                        Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+                        Err(_) => continue, // Keep the addresses found before
+                    }
+                }
+            };
+            let mut urls: Vec<String> = ips.iter().map(|ip| with_ip(url, &host, ip)).collect();
+            urls.sort();
+            urls.dedup();
+            if !urls.is_empty() {
+                discovery.update(url, urls);
+            }
+        }
+        let refresh = refresh.clamp(options.min_refresh, options.max_refresh);
+        tokio::time::sleep(Duration::from_secs(refresh)).await;
+    }
+}
+
+/// Follows the passing instances of a Consul service with blocking queries, which return as soon
+/// as the catalog or the health of an instance changes
+async fn watch_consul(
+    discovery: Arc<Discovery>,
+    upstream: String,
+    service: String,
+    options: ConsulOptions,
+) {
+    let mut index = 0;
+    loop {
+        let started = Instant::now();
+        match consul_instances(&options, &service, index).await {
+            Ok((next_index, urls)) => {
+                // An index going back means the Consul state was reset, start over
+                index = if next_index < index { 0 } else { next_index };
+                discovery.update(&upstream, urls);
+            }
+            Err(err) => {
+                #[cfg(debug_assertions)]
+                warn!("Consul query for {} failed: {}", service, err);
+                tokio::time::sleep(CONSUL_RETRY).await;
+            }
+        }
+        tokio::time::sleep(CONSUL_MIN_INTERVAL.saturating_sub(started.elapsed())).await;
+    }
+}
+
+/// Raft index of the answer and the URLs of the passing instances, waiting for a change past `index`
+async fn consul_instances(
+    options: &ConsulOptions,
+    service: &str,
+    index: u64,
+) -> Result<(u64, Vec<String>), CbltError> {
+    let mut uri = format!(
+        "{}/v1/health/service/{}?passing=1&index={}&wait={}s",
+        options.address,
+        service,
+        index,
+        CONSUL_WAIT.as_secs()
+    );
+    if let Some(datacenter) = &options.datacenter {
+        uri.push_str(&format!(
+            "&dc={}",
+            utf8_percent_encode(datacenter, NON_ALPHANUMERIC)
+        ));
+    }
+    if let Some(tag) = &options.tag {
+        uri.push_str(&format!(
+            "&tag={}",
+            utf8_percent_encode(tag, NON_ALPHANUMERIC)
+        ));
+    }
+    let mut request = Request::get(uri);
+    if let Some(token) = &options.token {
+        request = request.header("X-Consul-Token", token.expose());
+    }
+    // Consul adds up to a sixteenth of the wait as jitter
+    let (status, headers, body) = tokio::time::timeout(
+        CONSUL_WAIT + CONSUL_WAIT / 8,
+        http_response(request.body(Full::new(Bytes::new()))?),
+    )
+    .await
+    .map_err(|_| CbltError::ConsulError {
+        details: format!("No answer for service {}", service),
+    })??;
+    if !status.is_success() {
+        return Err(CbltError::ConsulError {
+            details: format!(
+                "Consul returned {} for service {}: {}",
+                status,
+                service,
+                String::from_utf8_lossy(&body)
+            ),
+        });
+    }
+    let index = headers
+        .get("X-Consul-Index")
+        .and_then(|index| index.to_str().ok())
+        .and_then(|index| index.parse().ok())
+        .unwrap_or(0);
+    Ok((index, instance_urls(&options.scheme, &body)?))
+}
+
+#[derive(Deserialize)]
+struct ServiceEntry {
+    #[serde(rename = "Node")]
+    node: NodeEntry,
+    #[serde(rename = "Service")]
+    service: ServiceInstance,
+}
+
+#[derive(Deserialize)]
+struct NodeEntry {
+    #[serde(rename = "Address")]
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct ServiceInstance {
+    #[serde(rename = "Address")]
+    address: String, // the node's address when empty
+    #[serde(rename = "Port")]
+    port: u16,
+}
+
+/// URLs of the instances in a `/v1/health/service` answer
+fn instance_urls(scheme: &str, body: &[u8]) -> Result<Vec<String>, CbltError> {
+    let entries: Vec<ServiceEntry> = serde_json::from_slice(body)?;
+    let mut urls: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let address = match entry.service.address.as_str() {
+                "" => entry.node.address.as_str(),
+                address => address,
+            };
+            match address.parse::<IpAddr>() {
+                Ok(IpAddr::V6(ip)) => format!("{}://[{}]:{}", scheme, ip, entry.service.port),
+                _ => format!("{}://{}:{}", scheme, address, entry.service.port),
+            }
+        })
+        .collect();
+    urls.sort();
+    urls.dedup();
+    Ok(urls)
+}
+
+/// Service name of a `consul://service` upstream
+pub fn consul_service(url: &str) -> Option<&str> {
+    url.strip_prefix("consul://").filter(|service| {
+        !service.is_empty()
+            && service
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    })
+}
+
+/// Host and port of an upstream given by name, `None` for addresses, sockets and Consul services
+pub fn upstream_host(url: &str) -> Option<(String, u16)> {
+    if url.starts_with("consul://") {
+        return None;
+    }
+    let authority = backend_authority(url).ok()?;
+    if unix_socket_path(&authority).is_some() {
+        return None;
+    }
+    let (host, port) = authority.rsplit_once(':')?;
+    if host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some((host.to_string(), port.parse().ok()?))
+}
+
+/// The upstream URL with its host replaced by `ip`
+fn with_ip(url: &str, host: &str, ip: &IpAddr) -> String {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{}]", ip),
+    };
+    url.replacen(&format!("://{}", host), &format!("://{}", ip), 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::discovery::{consul_service, instance_urls, upstream_host, with_ip};
+    use std::error::Error;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_upstream_host() -> Result<(), Box<dyn Error>> {
+        assert_eq!(
+            upstream_host("http://api.default.svc:8080"),
+            Some(("api.default.svc".to_string(), 8080))
+        );
+        assert_eq!(
+            upstream_host("https://api.example.com"),
+            Some(("api.example.com".to_string(), 443))
+        );
+        assert_eq!(
+            upstream_host("uwsgi://app:3031"),
+            Some(("app".to_string(), 3031))
+        );
+        assert_eq!(upstream_host("http://10.0.0.1:8080"), None);
+        assert_eq!(upstream_host("http://[::1]:8080"), None);
+        assert_eq!(upstream_host("unix//run/app.sock"), None);
+        assert_eq!(upstream_host("consul://web"), None);
+
+        let ip: IpAddr = "10.1.2.3".parse()?;
+        assert_eq!(
+            with_ip("http://api.default.svc:8080", "api.default.svc", &ip),
+            "http://10.1.2.3:8080"
+        );
+        let ip: IpAddr = "fd00::5".parse()?;
+        assert_eq!(
+            with_ip("scgi://app:4000", "app", &ip),
+            "scgi://[fd00::5]:4000"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_consul_instances() -> Result<(), Box<dyn Error>> {
+        assert_eq!(consul_service("consul://web-v2"), Some("web-v2"));
+        assert_eq!(consul_service("consul://"), None);
+        assert_eq!(consul_service("consul://web/api"), None);
+        assert_eq!(consul_service("http://web"), None);
+
+        let body = br#"[
+            {"Node": {"Node": "n1", "Address": "10.0.0.1"}, "Service": {"ID": "web-1", "Address": "", "Port": 8080}, "Checks": []},
+            {"Node": {"Node": "n2", "Address": "10.0.0.2"}, "Service": {"ID": "web-2", "Address": "10.1.0.2", "Port": 8081}, "Checks": []},
+            {"Node": {"Node": "n3", "Address": "10.0.0.3"}, "Service": {"ID": "web-3", "Address": "fd00::3", "Port": 8080}, "Checks": []}
+        ]"#;
+        assert_eq!(
+            instance_urls("http", body)?,
+            vec![
+                "http://10.0.0.1:8080",
+                "http://10.1.0.2:8081",
+                "http://[fd00::3]:8080"
+            ]
+        );
+        assert_eq!(instance_urls("https", b"[]")?, Vec::<String>::new());
+        assert!(instance_urls("http", b"{}").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,836 @@
+use crate::config::DnsProviderOptions;
+use crate::error::CbltError;
+use aws_lc_rs::{digest, hmac};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::Bytes;
+use http::{HeaderMap, Method, Request, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+const TXT_TTL: u32 = 60;
+
+pub type DnsFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, CbltError>> + Send + 'a>>;
+
+/// TXT record created for a DNS-01 challenge
+#[derive(Debug, Clone)]
+pub struct TxtRecord {
+    pub name: String,
+    pub value: String,
+    pub id: Option<String>, // provider handle needed for removal
+}
+
+/// Creates and removes the `_acme-challenge` TXT records
+pub trait DnsProvider: Send + Sync {
+    fn set_txt<'a>(&'a self, name: &'a str, value: &'a str) -> DnsFuture<'a, TxtRecord>;
+    fn remove_txt<'a>(&'a self, record: &'a TxtRecord) -> DnsFuture<'a, ()>;
+}
+
+pub fn dns_provider(options: &DnsProviderOptions) -> Result<Box<dyn DnsProvider>, CbltError> {
+    Ok(match options {
+        DnsProviderOptions::Cloudflare { api_token, zone_id } => Box::new(Cloudflare {
+            api_token: api_token.expose().to_string(),
+            zone_id: zone_id.clone(),
+        }),
+        DnsProviderOptions::Route53 {
+            access_key_id,
+            secret_access_key,
+            hosted_zone_id,
+        } => Box::new(Route53 {
+            access_key_id: access_key_id.clone(),
+            secret_access_key: secret_access_key.expose().to_string(),
+            hosted_zone_id: hosted_zone_id
+                .trim_start_matches("/hostedzone/")
+                .to_string(),
+        }),
+        DnsProviderOptions::Rfc2136 {
+            server,
+            zone,
+            key_name,
+            key_secret,
+        } => Box::new(Rfc2136 {
+            server: server.clone(),
+            zone: zone.clone(),
+            key_name: key_name.clone(),
+            key_secret: BASE64_STANDARD.decode(key_secret.expose()).map_err(|err| {
+                CbltError::DnsProviderError {
+                    details: format!("Invalid rfc2136 key_secret: {}", err),
+                }
+            })?,
+        }),
+    })
+}
+
+/// Status and body of a provider API or on-demand ask endpoint call
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn http_request(request: Request<Full<Bytes>>) -> Result<(StatusCode, Bytes), CbltError> {
+    let (status, _, body) = http_response(request).await?;
+    Ok((status, body))
+}
+
+/// Status, headers and body of an HTTP API call
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn http_response(
+    request: Request<Full<Bytes>>,
+) -> Result<(StatusCode, HeaderMap, Bytes), CbltError> {
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()?
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder(TokioExecutor::new()).build(connector);
+    let response = client.request(request).await?;
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response.into_body().collect().await?.to_bytes();
+    Ok((status, headers, body))
+}
+
+fn api_error(provider: &str, status: StatusCode, body: &[u8]) -> CbltError {
+    CbltError::DnsProviderError {
+        details: format!(
+            "{} API returned {}: {}",
+            provider,
+            status,
+            String::from_utf8_lossy(body)
+        ),
+    }
+}
+
+struct Cloudflare {
+    api_token: String,
+    zone_id: Option<String>,
+}
+
+const CLOUDFLARE_API: &str = "https://api.cloudflare.com/client/v4";
+
+impl Cloudflare {
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, CbltError> {
+        let body = match body {
+            Some(body) => Bytes::from(serde_json::to_vec(&body)?),
+            None => Bytes::new(),
+        };
+        let request = Request::builder()
+            .method(method)
+            .uri(format!("{}{}", CLOUDFLARE_API, path))
+            .header("Authorization", format!("Bearer {}", self.api_token))
+            .header("Content-Type", "application/json")
+            .body(Full::new(body))?;
+        let (status, body) = http_request(request).await?;
+        if !status.is_success() {
+            return Err(api_error("Cloudflare", status, &body));
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Zone holding the record, trying the closest enclosing domain first
+    async fn zone_id(&self, name: &str) -> Result<String, CbltError> {
+        if let Some(zone_id) = &self.zone_id {
+            return Ok(zone_id.clone());
+        }
+        let mut domain = name;
+        while let Some((_, parent)) = domain.split_once('.') {
+            let zones = self
+                .call(Method::GET, &format!("/zones?name={}", parent), None)
+                .await?;
+            if let Some(id) = zones["result"][0]["id"].as_str() {
+                return Ok(id.to_string());
+            }
+            domain = parent;
+        }
+        Err(CbltError::DnsProviderError {
+            details: format!("No Cloudflare zone found for {}", name),
+        })
+    }
+}
+
+impl DnsProvider for Cloudflare {
+    fn set_txt<'a>(&'a self, name: &'a str, value: &'a str) -> DnsFuture<'a, TxtRecord> {
+        Box::pin(async move {
+            let zone_id = self.zone_id(name).await?;
+            let record = json!({ "type": "TXT", "name": name, "content": value, "ttl": TXT_TTL });
+            let created = self
+                .call(
+                    Method::POST,
+                    &format!("/zones/{}/dns_records", zone_id),
+                    Some(record),
+                )
+                .await?;
+            let record_id = created["result"]["id"].as_str().unwrap_or_default();
+            Ok(TxtRecord {
+                name: name.to_string(),
+                value: value.to_string(),
+                id: Some(format!("{}/{}", zone_id, record_id)),
+            })
+        })
+    }
+
+    fn remove_txt<'a>(&'a self, record: &'a TxtRecord) -> DnsFuture<'a, ()> {
+        Box::pin(async move {
+            if let Some((zone_id, record_id)) =
+                record.id.as_deref().and_then(|id| id.split_once('/'))
+            {
+                self.call(
+                    Method::DELETE,
+                    &format!("/zones/{}/dns_records/{}", zone_id, record_id),
+                    None,
+                )
+                .await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+struct Route53 {
+    access_key_id: String,
+    secret_access_key: String,
+    hosted_zone_id: String,
+}
+
+const ROUTE53_HOST: &str = "route53.amazonaws.com";
+
+impl Route53 {
+    async fn change(&self, action: &str, name: &str, value: &str) -> Result<(), CbltError> {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<ChangeResourceRecordSetsRequest xmlns="https://route53.amazonaws.com/doc/2013-04-01/"><ChangeBatch><Changes><Change><Action>{}</Action><ResourceRecordSet><Name>{}</Name><Type>TXT</Type><TTL>{}</TTL><ResourceRecords><ResourceRecord><Value>"{}"</Value></ResourceRecord></ResourceRecords></ResourceRecordSet></Change></Changes></ChangeBatch></ChangeResourceRecordSetsRequest>"#,
+            action, name, TXT_TTL, value
+        );
+        let path = format!("/2013-04-01/hostedzone/{}/rrset/", self.hosted_zone_id);
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let authorization = sigv4_authorization(
+            &SigV4 {
+                access_key_id: &self.access_key_id,
+                secret_access_key: &self.secret_access_key,
+                region: "us-east-1",
+                service: "route53",
+            },
+            "POST",
+            ROUTE53_HOST,
+            &path,
+            &amz_date,
+            body.as_bytes(),
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("https://{}{}", ROUTE53_HOST, path))
+            .header("Host", ROUTE53_HOST)
+            .header("X-Amz-Date", amz_date)
+            .header("Authorization", authorization)
+            .header("Content-Type", "text/xml")
+            .body(Full::new(Bytes::from(body)))?;
+        let (status, body) = http_request(request).await?;
+        if !status.is_success() {
+            return Err(api_error("Route53", status, &body));
+        }
+        Ok(())
+    }
+}
+
+impl DnsProvider for Route53 {
+    fn set_txt<'a>(&'a self, name: &'a str, value: &'a str) -> DnsFuture<'a, TxtRecord> {
+        Box::pin(async move {
+            self.change("UPSERT", name, value).await?;
+            Ok(TxtRecord {
+                name: name.to_string(),
+                value: value.to_string(),
+                id: None,
+            })
+        })
+    }
+
+    fn remove_txt<'a>(&'a self, record: &'a TxtRecord) -> DnsFuture<'a, ()> {
+        Box::pin(self.change("DELETE", &record.name, &record.value))
+    }
+}
+
+struct SigV4<'a> {
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    region: &'a str,
+    service: &'a str,
+}
+
+/// AWS Signature Version 4 over the host and x-amz-date headers
+fn sigv4_authorization(
+    credentials: &SigV4,
+    method: &str,
+    host: &str,
+    path: &str,
+    amz_date: &str,
+    payload: &[u8],
+) -> String {
+    let date = &amz_date[..8];
+    let scope = format!(
+        "{}/{}/{}/aws4_request",
+        date, credentials.region, credentials.service
+    );
+    let canonical_request = format!(
+        "{}\n{}\n\nhost:{}\nx-amz-date:{}\n\nhost;x-amz-date\n{}",
+        method,
+        path,
+        host,
+        amz_date,
+        to_hex(digest::digest(&digest::SHA256, payload).as_ref())
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        to_hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+    );
+
+    let sign = |key: &[u8], data: &str| {
+        hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+            .as_ref()
+            .to_vec()
+    };
+    let key = format!("AWS4{}", credentials.secret_access_key);
+    let key = sign(key.as_bytes(), date);
+    let key = sign(&key, credentials.region);
+    let key = sign(&key, credentials.service);
+    let key = sign(&key, "aws4_request");
+    let signature = to_hex(&sign(&key, &string_to_sign));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-date, Signature={}",
+        credentials.access_key_id, scope, signature
+    )
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Dynamic DNS update (RFC 2136) signed with TSIG hmac-sha256 (RFC 8945)
+struct Rfc2136 {
+    server: String,
+    zone: String,
+    key_name: String,
+    key_secret: Vec<u8>,
+}
+
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const TSIG_ALGORITHM: &str = "hmac-sha256.";
+const TSIG_FUDGE: u16 = 300;
+
+impl Rfc2136 {
+    async fn update(&self, name: &str, value: &str, add: bool) -> Result<(), CbltError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let id = now.subsec_nanos() as u16;
+        let message = update_message(
+            id,
+            &self.zone,
+            name,
+            value,
+            add,
+            &self.key_name,
+            &self.key_secret,
+            now.as_secs(),
+        );
+
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(&self.server).await?;
+        socket.send(&message).await?;
+        let mut response = [0u8; 512];
+        let len = timeout(Duration::from_secs(10), socket.recv(&mut response))
+            .await
+            .map_err(|_| CbltError::DnsProviderError {
+                details: format!("No answer from {}", self.server),
+            })??;
+        if len < 12 || response[..2] != id.to_be_bytes() {
+            return Err(CbltError::DnsProviderError {
+                details: format!("Malformed answer from {}", self.server),
+            });
+        }
+        match response[3] & 0x0f {
+            0 => Ok(()),
+            rcode => Err(CbltError::DnsProviderError {
+                details: format!("{} refused the update with rcode {}", self.server, rcode),
+            }),
+        }
+    }
+}
+
+impl DnsProvider for Rfc2136 {
+    fn set_txt<'a>(&'a self, name: &'a str, value: &'a str) -> DnsFuture<'a, TxtRecord> {
+        Box::pin(async move {
+            self.update(name, value, true).await?;
+            Ok(TxtRecord {
+                name: name.to_string(),
+                value: value.to_string(),
+                id: None,
+            })
+        })
+    }
+
+    fn remove_txt<'a>(&'a self, record: &'a TxtRecord) -> DnsFuture<'a, ()> {
+        Box::pin(self.update(&record.name, &record.value, false))
+    }
+}
+
+/// UPDATE message adding or deleting one TXT record, with a TSIG record appended
+#[allow(clippy::too_many_arguments)]
+fn update_message(
+    id: u16,
+    zone: &str,
+    name: &str,
+    value: &str,
+    add: bool,
+    key_name: &str,
+    key_secret: &[u8],
+    time_signed: u64,
+) -> Vec<u8> {
+    let mut message = Vec::with_capacity(256);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&0x2800u16.to_be_bytes()); // opcode UPDATE
+    for count in [1u16, 0, 1, 0] {
+        // zone, prerequisites, updates, additional
+        message.extend_from_slice(&count.to_be_bytes());
+    }
+
+    message.extend(encode_name(zone));
+    message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let (class, ttl) = if add {
+        (CLASS_IN, TXT_TTL)
+    } else {
+        (CLASS_NONE, 0)
+    };
+    message.extend(encode_name(name));
+    message.extend_from_slice(&TYPE_TXT.to_be_bytes());
+    message.extend_from_slice(&class.to_be_bytes());
+    message.extend_from_slice(&ttl.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16 + 1).to_be_bytes());
+    message.push(value.len() as u8);
+    message.extend_from_slice(value.as_bytes());
+
+    let key_name = encode_name(&key_name.to_ascii_lowercase());
+    let algorithm = encode_name(TSIG_ALGORITHM);
+    let time_signed = &time_signed.to_be_bytes()[2..];
+
+    // MAC over the unsigned message and the TSIG variables
+    let mut signed = message.clone();
+    signed.extend(&key_name);
+    signed.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    signed.extend_from_slice(&0u32.to_be_bytes());
+    signed.extend(&algorithm);
+    signed.extend_from_slice(time_signed);
+    signed.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    signed.extend_from_slice(&[0, 0, 0, 0]); // error, other len
+    let mac = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key_secret), &signed);
+    let mac = mac.as_ref();
+
+    let mut rdata = algorithm;
+    rdata.extend_from_slice(time_signed);
+    rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(mac);
+    rdata.extend_from_slice(&id.to_be_bytes());
+    rdata.extend_from_slice(&[0, 0, 0, 0]); // error, other len
+
+    message.extend(key_name);
+    message.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+    message.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    message.extend_from_slice(&0u32.to_be_bytes());
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend(rdata);
+    message[11] = 1; // additional count now includes TSIG
+    message
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const EDNS_PAYLOAD: u16 = 4096;
+const RCODE_NXDOMAIN: u8 = 3;
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Nameservers and search domains of a resolv.conf
+#[derive(Debug, PartialEq)]
+pub struct ResolvConf {
+    pub nameservers: Vec<SocketAddr>,
+    pub search: Vec<String>,
+    pub ndots: usize, // fewer dots in a name and the search domains are tried first
+}
+
+pub fn parse_resolv_conf(source: &str) -> ResolvConf {
+    let mut conf = ResolvConf {
+        nameservers: Vec::new(),
+        search: Vec::new(),
+        ndots: 1,
+    };
+    for line in source.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some("nameserver") => {
+                if let Some(ip) = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+                    conf.nameservers.push(SocketAddr::new(ip, 53));
+                }
+            }
+            Some("search" | "domain") => {
+                conf.search = fields.map(|domain| domain.to_string()).collect();
+            }
+            Some("options") => {
+                for option in fields {
+                    if let Some(ndots) = option.strip_prefix("ndots:") {
+                        conf.ndots = ndots.parse().unwrap_or(conf.ndots);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    conf
+}
+
+/// Names tried for `name` in turn, with the search domains of `conf`
+fn search_names(name: &str, conf: &ResolvConf) -> Vec<String> {
+    if name.ends_with('.') {
+        return vec![name.to_string()];
+    }
+    let searched = conf
+        .search
+        .iter()
+        .map(|domain| format!("{}.{}", name, domain.trim_end_matches('.')));
+    if name.matches('.').count() >= conf.ndots {
+        std::iter::once(name.to_string()).chain(searched).collect()
+    } else {
+        searched.chain(std::iter::once(name.to_string())).collect()
+    }
+}
+
+/// IPv4 and IPv6 addresses of `name` with the lowest TTL among their records, asking `resolver`
+/// or the nameservers of /etc/resolv.conf
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn lookup_ip(
+    name: &str,
+    resolver: Option<SocketAddr>,
+) -> Result<(Vec<IpAddr>, u32), CbltError> {
+    let mut conf = parse_resolv_conf(
+        &tokio::fs::read_to_string("/etc/resolv.conf")
+            .await
+            .unwrap_or_default(),
+    );
+    if let Some(resolver) = resolver {
+        conf.nameservers = vec![resolver];
+    } else if conf.nameservers.is_empty() {
+        conf.nameservers
+            .push(SocketAddr::from(([127, 0, 0, 1], 53)));
+    }
+    let mut last_error = None;
+    for candidate in search_names(name, &conf) {
+        for server in &conf.nameservers {
+            let mut found = (Vec::new(), u32::MAX);
+            let mut failed = false;
+            for qtype in [TYPE_A, TYPE_AAAA] {
+                match query(*server, &candidate, qtype).await {
+                    Ok((ips, ttl)) => {
+                        found.0.extend(ips);
+                        found.1 = found.1.min(ttl);
+                    }
+                    Err(err) => {
+                        last_error = Some(err);
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            if failed {
+                continue; // Ask the next nameserver
+            }
+            if !found.0.is_empty() {
+                return Ok(found);
+            }
+            break; // The name has no addresses, try the next one
+        }
+    }
+    Err(last_error.unwrap_or_else(|| CbltError::DnsLookupError {
+        details: format!("No addresses for {}", name),
+    }))
+}
+
+/// Records of one type for `name`, over TCP when the answer does not fit a datagram
+async fn query(
+    server: SocketAddr,
+    name: &str,
+    qtype: u16,
+) -> Result<(Vec<IpAddr>, u32), CbltError> {
+    let id = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos() as u16;
+    let message = query_message(id, name, qtype);
+    let no_answer = || CbltError::DnsLookupError {
+        details: format!("No answer from {} for {}", server, name),
+    };
+    let bind = match server {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    socket.send(&message).await?;
+    let mut response = vec![0u8; EDNS_PAYLOAD as usize];
+    let len = timeout(LOOKUP_TIMEOUT, socket.recv(&mut response))
+        .await
+        .map_err(|_| no_answer())??;
+    response.truncate(len);
+    if len > 2 && response[2] & 0x02 != 0 {
+        // Truncated, the whole answer comes over TCP with a length prefix
+        let exchange = async {
+            let mut stream = tokio::net::TcpStream::connect(server).await?;
+            let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+            framed.extend_from_slice(&message);
+            stream.write_all(&framed).await?;
+            let len = stream.read_u16().await? as usize;
+            let mut response = vec![0u8; len];
+            stream.read_exact(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        };
+        response = timeout(LOOKUP_TIMEOUT, exchange)
+            .await
+            .map_err(|_| no_answer())??;
+    }
+    parse_answers(&response, id, name)
+}
+
+/// Recursive query for the records of one type, offering EDNS0 for larger answers
+fn query_message(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut message = Vec::with_capacity(64);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&0x0100u16.to_be_bytes()); // recursion desired
+    for count in [1u16, 0, 0, 1] {
+        // questions, answers, authority, additional
+        message.extend_from_slice(&count.to_be_bytes());
+    }
+    message.extend(encode_name(name));
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // OPT record: root name, the payload size as its class
+    message.push(0);
+    message.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    message.extend_from_slice(&EDNS_PAYLOAD.to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // extended rcode and flags, data length
+    message
+}
+
+/// Addresses in the answer section with their lowest TTL, CNAME records in the chain are skipped
+fn parse_answers(response: &[u8], id: u16, name: &str) -> Result<(Vec<IpAddr>, u32), CbltError> {
+    let malformed = || CbltError::DnsLookupError {
+        details: format!("Malformed answer for {}", name),
+    };
+    if response.len() < 12 || response[..2] != id.to_be_bytes() || response[2] & 0x80 == 0 {
+        return Err(malformed());
+    }
+    match response[3] & 0x0f {
+        0 => {}
+        RCODE_NXDOMAIN => return Ok((Vec::new(), 0)),
+        rcode => {
+            return Err(CbltError::DnsLookupError {
+                details: format!("Lookup of {} failed with rcode {}", name, rcode),
+            })
+        }
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(response, pos).ok_or_else(malformed)? + 4;
+    }
+    let mut ips = Vec::new();
+    let mut min_ttl = u32::MAX;
+    for _ in 0..answers {
+        pos = skip_name(response, pos).ok_or_else(malformed)?;
+        let record = response.get(pos..pos + 10).ok_or_else(malformed)?;
+        let rtype = u16::from_be_bytes([record[0], record[1]]);
+        let ttl = u32::from_be_bytes([record[4], record[5], record[6], record[7]]);
+        let len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let data = response
+            .get(pos + 10..pos + 10 + len)
+            .ok_or_else(malformed)?;
+        let ip = match (rtype, data.len()) {
+            (TYPE_A, 4) => Some(IpAddr::from(
+                <[u8; 4]>::try_from(data).map_err(|_| malformed())?,
+            )),
+            (TYPE_AAAA, 16) => Some(IpAddr::from(
+                <[u8; 16]>::try_from(data).map_err(|_| malformed())?,
+            )),
+            _ => None,
+        };
+        if let Some(ip) = ip {
+            ips.push(ip);
+            min_ttl = min_ttl.min(ttl);
+        }
+        pos += 10 + len;
+    }
+    Ok((ips, if min_ttl == u32::MAX { 0 } else { min_ttl }))
+}
+
+/// Position after the name at `pos`, a compression pointer ends it
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len,
+        }
+    }
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::{
+        encode_name, parse_answers, parse_resolv_conf, query_message, search_names,
+        sigv4_authorization, update_message, SigV4, TYPE_AAAA,
+    };
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_sigv4_authorization() {
+        // "get-vanilla" from the AWS Signature Version 4 test suite
+        let authorization = sigv4_authorization(
+            &SigV4 {
+                access_key_id: "AKIDEXAMPLE",
+                secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+                region: "us-east-1",
+                service: "service",
+            },
+            "GET",
+            "example.amazonaws.com",
+            "/",
+            "20150830T123600Z",
+            b"",
+        );
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn test_update_message() {
+        assert_eq!(encode_name("example.com."), b"\x07example\x03com\x00");
+
+        let message = update_message(
+            0x1234,
+            "example.com",
+            "_acme-challenge.example.com",
+            "token",
+            true,
+            "cblt",
+            b"secret",
+            1_700_000_000,
+        );
+        assert_eq!(&message[..4], &[0x12, 0x34, 0x28, 0x00]);
+        assert_eq!(&message[4..12], &[0, 1, 0, 0, 0, 1, 0, 1]);
+        let update = b"\x0f_acme-challenge\x07example\x03com\x00\x00\x10\x00\x01\x00\x00\x00\x3c\x00\x06\x05token";
+        assert!(message.windows(update.len()).any(|window| window == update));
+        // TSIG record: key name, type 250, class ANY, 32 byte MAC
+        let tsig = b"\x04cblt\x00\x00\xfa\x00\xff";
+        assert!(message.windows(tsig.len()).any(|window| window == tsig));
+        assert!(message.ends_with(&[0x12, 0x34, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_resolv_conf() {
+        let conf = parse_resolv_conf(
+            "# generated\nnameserver 10.96.0.10\nnameserver fd00::a\n\
+             search default.svc.cluster.local svc.cluster.local\noptions ndots:5 timeout:1\n",
+        );
+        assert_eq!(
+            conf.nameservers,
+            vec![
+                "10.96.0.10:53".parse().unwrap(),
+                "[fd00::a]:53".parse().unwrap()
+            ]
+        );
+        assert_eq!(conf.ndots, 5);
+        assert_eq!(
+            search_names("api", &conf),
+            vec![
+                "api.default.svc.cluster.local",
+                "api.svc.cluster.local",
+                "api"
+            ]
+        );
+        assert_eq!(
+            search_names("api.example.com.", &conf),
+            vec!["api.example.com."]
+        );
+
+        let conf = parse_resolv_conf("domain corp\n");
+        assert_eq!(conf.ndots, 1);
+        assert_eq!(
+            search_names("api.example.com", &conf),
+            vec!["api.example.com", "api.example.com.corp"]
+        );
+    }
+
+    #[test]
+    fn test_parse_answers() {
+        let query = query_message(0xbeef, "api.example.com", TYPE_AAAA);
+        assert_eq!(
+            &query[..12],
+            &[0xbe, 0xef, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]
+        );
+        assert!(query.ends_with(b"\x03api\x07example\x03com\x00\x00\x1c\x00\x01\x00\x00\x29\x10\x00\x00\x00\x00\x00\x00\x00"));
+
+        // api.example.com CNAME web.example.com (TTL 300), web A 10.0.0.7 (TTL 30)
+        let mut response = vec![0xbe, 0xef, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+        response.extend_from_slice(b"\x03api\x07example\x03com\x00\x00\x01\x00\x01");
+        response
+            .extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x01\x2c\x00\x06\x03web\xc0\x10");
+        response.extend_from_slice(
+            b"\x03web\xc0\x10\x00\x01\x00\x01\x00\x00\x00\x1e\x00\x04\x0a\x00\x00\x07",
+        );
+        assert_eq!(
+            parse_answers(&response, 0xbeef, "api.example.com").unwrap(),
+            (vec!["10.0.0.7".parse::<IpAddr>().unwrap()], 30)
+        );
+        assert!(parse_answers(&response, 0x1234, "api.example.com").is_err());
+        assert!(parse_answers(&response[..response.len() - 2], 0xbeef, "api.example.com").is_err());
+
+        // NXDOMAIN
+        response[3] = 0x83;
+        assert_eq!(
+            parse_answers(&response[..33], 0xbeef, "api.example.com").unwrap(),
+            (Vec::new(), 0)
+        );
+        // SERVFAIL
+        response[3] = 0x82;
+        assert!(parse_answers(&response, 0xbeef, "api.example.com").is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::config::{build_config, build_handlers, Directive};
+use crate::error::CbltError;
+use crate::{build_servers, ServerSupervisor};
+use kdl::KdlDocument;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Builder for a cblt server embedded in another application
+///
+/// ```no_run
+/// use cblt::config::Directive;
+/// use cblt::CbltServer;
+///
+/// # async fn example() -> Result<(), cblt::error::CbltError> {
+/// let server = CbltServer::new()
+///     .directive(
+///         "example.com",
+///         Directive::Root {
+///             pattern: "*".to_string(),
+///             path: "./assets".to_string(),
+///         },
+///     )
+///     .directive("example.com", Directive::FileServer { options: Default::default() })
+///     .tls("example.com", "certs/example.crt", "certs/example.key")
+///     .start()
+///     .await?;
+/// server.stop();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CbltServer {
+    hosts: HashMap<String, Vec<Directive>>, // same shape as a parsed Cbltfile
+    max_connections: usize,
+    acceptors: usize,
+}
+
+impl Default for CbltServer {
+    fn default() -> Self {
+        CbltServer {
+            hosts: HashMap::new(),
+            max_connections: 10000,
+            acceptors: 1,
+        }
+    }
+}
+
+impl CbltServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Configuration written as a Cbltfile
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn cbltfile(mut self, source: &str) -> Result<Self, CbltError> {
+        let doc: KdlDocument = source.parse()?;
+        for (host, directives) in build_config(&doc)? {
+            self.hosts.entry(host).or_default().extend(directives);
+        }
+        Ok(self)
+    }
+
+    /// Replaces the directives of a host, e.g. `"example.com"` or `"*:8080"`
+    pub fn host(mut self, host: &str, directives: Vec<Directive>) -> Self {
+        self.hosts.insert(host.to_string(), directives);
+        self
+    }
+
+    /// Appends a directive to a host, declaring the host when it is new
+    pub fn directive(mut self, host: &str, directive: Directive) -> Self {
+        self.hosts
+            .entry(host.to_string())
+            .or_default()
+            .push(directive);
+        self
+    }
+
+    /// Serves a host over TLS with a PEM certificate chain and private key
+    pub fn tls(self, host: &str, cert: &str, key: &str) -> Self {
+        self.directive(
+            host,
+            Directive::TlS {
+                cert: cert.to_string(),
+                key: key.to_string(),
+            },
+        )
+    }
+
+    pub fn max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Accept loops per TCP listener
+    pub fn acceptors(mut self, acceptors: usize) -> Self {
+        self.acceptors = acceptors.max(1);
+        self
+    }
+
+    /// Serves the configured hosts on the current tokio runtime, listeners bind in the background
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn start(self) -> Result<RunningServer, CbltError> {
+        let mut running = RunningServer {
+            supervisor: ServerSupervisor {
+                workers: HashMap::new(),
+            },
+        };
+        running.reload(self).await?;
+        Ok(running)
+    }
+}
+
+/// Handle of a started [`CbltServer`]
+pub struct RunningServer {
+    supervisor: ServerSupervisor,
+}
+
+impl RunningServer {
+    /// Ports being served
+    pub fn ports(&self) -> Vec<u16> {
+        let mut ports: Vec<u16> = self.supervisor.workers.keys().copied().collect();
+        ports.sort_unstable();
+        ports
+    }
+
+    /// Addresses being served once the listeners are bound, a host on port 0 gets a free port
+    /// picked by the system
+    pub async fn local_addrs(&self) -> Vec<SocketAddr> {
+        let mut addrs = Vec::new();
+        for port in self.ports() {
+            addrs.extend(self.supervisor.workers[&port].local_addrs().await);
+        }
+        addrs
+    }
+
+    /// Swaps in a new configuration, keeping the listeners of ports still in use
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn reload(&mut self, server: CbltServer) -> Result<(), CbltError> {
+        if server.hosts.is_empty() {
+            return Err(CbltError::KdlParseError {
+                details: "No hosts configured".to_string(),
+            });
+        }
+        let mut hosts = server.hosts;
+        build_handlers(&mut hosts)?;
+        let servers = build_servers(hosts)?;
+        self.supervisor
+            .process_workers(servers, server.max_connections, server.acceptors)
+            .await
+    }
+
+    /// Stops accepting connections on every port
+    pub fn stop(mut self) {
+        for (_, worker) in self.supervisor.workers.drain() {
+            worker.stop();
+        }
+    }
+}
+
+/// Servers on a free port for the tests of every module
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{CbltServer, RunningServer};
+    use std::error::Error;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    /// Starts a Cbltfile whose hosts are on port 0, with the port they got
+    pub async fn serve(source: &str) -> Result<(RunningServer, u16), Box<dyn Error>> {
+        let server = CbltServer::new().cbltfile(source)?.start().await?;
+        let port = server
+            .local_addrs()
+            .await
+            .first()
+            .map(|addr| addr.port())
+            .ok_or("no listener bound")?;
+        Ok((server, port))
+    }
+
+    /// Sends a raw request and reads the response until the server closes the connection
+    pub async fn exchange(port: u16, request: &str) -> Result<String, Box<dyn Error>> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::exchange;
+    use super::*;
+    use std::error::Error;
+    use tokio::net::TcpStream;
+
+    async fn get(port: u16) -> Result<String, Box<dyn Error>> {
+        exchange(
+            port,
+            "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_embedded_server() -> Result<(), Box<dyn Error>> {
+        let mut server = CbltServer::new()
+            .directive(
+                "*:0",
+                Directive::Redir {
+                    pattern: None,
+                    destination: "https://example.com/first".to_string(),
+                },
+            )
+            .start()
+            .await?;
+        assert_eq!(server.ports(), vec![0]);
+        let addrs = server.local_addrs().await;
+        assert_eq!(addrs.len(), 1);
+        let port = addrs[0].port();
+        assert_ne!(port, 0);
+        assert!(get(port).await?.contains("https://example.com/first"));
+
+        // The listener and the port it got outlive a reload
+        let source = "\"*:0\" {\n    redir \"https://example.com/second\"\n}";
+        server.reload(CbltServer::new().cbltfile(source)?).await?;
+        assert_eq!(server.local_addrs().await, addrs);
+        assert!(get(port).await?.contains("https://example.com/second"));
+
+        server.stop();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+        Ok(())
+    }
+}
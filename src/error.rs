@@ -40,6 +40,12 @@ pub enum CbltError {
         #[from]
         source: bollard::errors::Error,
     },
+    // from redis::RedisError
+    #[error("RedisError: {source:?}")]
+    RedisError {
+        #[from]
+        source: redis::RedisError,
+    },
 
     // from pki_types::pem::Error
     #[error("PemError: {source:?}")]
@@ -59,6 +65,12 @@ pub enum CbltError {
         #[from]
         source: http::header::ToStrError,
     },
+    // from http::header::InvalidHeaderValue
+    #[error("InvalidHeaderValue: {source:?}")]
+    InvalidHeaderValue {
+        #[from]
+        source: http::header::InvalidHeaderValue,
+    },
     // from KdlError
     #[error("KdlError: {source:?}")]
     KdlError {
@@ -77,6 +89,42 @@ pub enum CbltError {
         #[from]
         source: std::num::ParseIntError,
     },
+    // from serde_json::Error
+    #[error("SerdeJsonError: {source:?}")]
+    SerdeJsonError {
+        #[from]
+        source: serde_json::Error,
+    },
+    // from instant_acme::Error
+    #[error("AcmeError: {source:?}")]
+    AcmeError {
+        #[from]
+        source: instant_acme::Error,
+    },
+    // from rcgen::Error
+    #[error("RcgenError: {source:?}")]
+    RcgenError {
+        #[from]
+        source: rcgen::Error,
+    },
+    // from hyper_util::client::legacy::Error
+    #[error("HyperClientError: {source:?}")]
+    HyperClientError {
+        #[from]
+        source: hyper_util::client::legacy::Error,
+    },
+    // from hyper::Error
+    #[error("HyperError: {source:?}")]
+    HyperError {
+        #[from]
+        source: hyper::Error,
+    },
+    // from h2::Error
+    #[error("H2Error: {source:?}")]
+    H2Error {
+        #[from]
+        source: h2::Error,
+    },
     // from DurationError
     #[error("DurationError: {source:?}")]
     DurationError {
@@ -98,4 +146,22 @@ pub enum CbltError {
     LabelNotFound { details: String },
     #[error("SecretDataNotFound")]
     SecretDataNotFound,
+    #[error("InvalidListenAddress: {details:?}")]
+    InvalidListenAddress { details: String },
+    #[error("EnvVarNotSet: {name:?}")]
+    EnvVarNotSet { name: String, offset: usize },
+    #[error("AcmeOrderFailed: {details:?}")]
+    AcmeOrderFailed { details: String },
+    #[error("InvalidTlsOptions: {details:?}")]
+    InvalidTlsOptions { details: String },
+    #[error("DnsProviderError: {details:?}")]
+    DnsProviderError { details: String },
+    #[error("DnsLookupError: {details:?}")]
+    DnsLookupError { details: String },
+    #[error("ConsulError: {details:?}")]
+    ConsulError { details: String },
+    #[error("KubernetesError: {details:?}")]
+    KubernetesError { details: String },
+    #[error("WasmError: {details:?}")]
+    WasmError { details: String },
 }
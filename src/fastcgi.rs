@@ -0,0 +1,510 @@
+use crate::cgi::{response_head, variables};
+use crate::config::{EncodeOptions, FastcgiOptions};
+use crate::error::CbltError;
+use crate::file_server::sanitize_path;
+use crate::response::{send_response, with_headers, ExtraHeaders};
+use crate::reverse_proxy::{forward_response, open_backend};
+use bytes::{Buf, BytesMut};
+use http::header::LOCATION;
+use http::{Request, Response, StatusCode};
+use log::warn;
+use percent_encoding::percent_decode_str;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u8 = 1;
+const HEADER_LEN: usize = 8;
+/// Largest content of a record
+const MAX_CONTENT_LEN: usize = 65535;
+
+/// Script a request runs, with the rest of its path as PATH_INFO
+#[derive(Debug, PartialEq)]
+enum Target {
+    Script { name: String, path_info: String },
+    Redirect, // directory with an index script, asked for without the trailing slash
+}
+
+/// Runs the PHP script the request resolves to, requests for other existing files pass on
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn php_fastcgi_directive<S>(
+    request: &Request<BytesMut>,
+    socket: &mut S,
+    root_path: Option<&str>,
+    upstream: &str,
+    options: &FastcgiOptions,
+    addr: SocketAddr,
+    scheme: &str,
+    extra_headers: &ExtraHeaders,
+    encode: Option<&EncodeOptions>,
+) -> Result<(StatusCode, bool), CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let root = options
+        .root
+        .as_deref()
+        .or(root_path)
+        .ok_or(CbltError::ResponseError {
+            details: "php_fastcgi without a root".to_string(),
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+        })?;
+    let (name, path_info) = match resolve(Path::new(root), request.uri().path(), options) {
+        None => return Err(CbltError::DirectiveNotMatched),
+        Some(Target::Redirect) => {
+            let location = match request.uri().query() {
+                Some(query) => format!("{}/?{}", request.uri().path(), query),
+                None => format!("{}/", request.uri().path()),
+            };
+            let response = Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(LOCATION, location)
+                .body(BytesMut::new())?;
+            send_response(socket, with_headers(response, extra_headers)).await?;
+            return Ok((StatusCode::PERMANENT_REDIRECT, true));
+        }
+        Some(Target::Script { name, path_info }) => (name, path_info),
+    };
+    let params = params(request, root, &name, &path_info, addr, scheme, options);
+
+    let mut backend = match timeout(
+        options.dial_timeout,
+        open_backend(upstream, None, false, None),
+    )
+    .await
+    {
+        Ok(Ok(backend)) => backend,
+        Ok(Err(err)) => {
+            return Err(CbltError::ResponseError {
+                details: format!("Failed to connect to FastCGI server {}: {}", upstream, err),
+                status_code: StatusCode::BAD_GATEWAY,
+            });
+        }
+        Err(_) => {
+            return Err(CbltError::ResponseError {
+                details: format!("Connection to FastCGI server {} timed out", upstream),
+                status_code: StatusCode::GATEWAY_TIMEOUT,
+            });
+        }
+    };
+    backend
+        .write_all(&encode_request(&params, request.body()))
+        .await?;
+    backend.flush().await?;
+
+    let mut stdout = Stdout::new(&mut backend);
+    let mut buf = BytesMut::new();
+    let head = response_head(&mut stdout, &mut buf);
+    let (status, headers, _) = match options.read_timeout {
+        Some(read_timeout) => {
+            timeout(read_timeout, head)
+                .await
+                .map_err(|_| CbltError::ResponseError {
+                    details: "FastCGI server timed out".to_string(),
+                    status_code: StatusCode::GATEWAY_TIMEOUT,
+                })??
+        }
+        None => head.await?,
+    };
+    forward_response(
+        socket,
+        request,
+        status,
+        headers,
+        &mut stdout,
+        buf,
+        extra_headers,
+        encode,
+        None,
+        None,
+    )
+    .await
+}
+
+/// Finds the script like `try_files {path} {path}/index.php index.php`, splitting the path
+/// after the script name first. None for existing files that are not scripts.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn resolve(root: &Path, path: &str, options: &FastcgiOptions) -> Option<Target> {
+    if let Some((name, path_info)) = split_path(path, &options.split) {
+        if sanitize_path(root, name).is_some_and(|file| file.is_file()) {
+            return Some(Target::Script {
+                name: name.to_string(),
+                path_info: path_info.to_string(),
+            });
+        }
+    }
+    let file = sanitize_path(root, path)?;
+    if file.is_file() {
+        return None;
+    }
+    let index = options.index.as_deref()?;
+    if file.is_dir() && file.join(index).is_file() {
+        if !path.ends_with('/') {
+            return Some(Target::Redirect);
+        }
+        return Some(Target::Script {
+            name: format!("{}{}", path, index),
+            path_info: String::new(),
+        });
+    }
+    // Front controller of apps with pretty URLs
+    root.join(index).is_file().then(|| Target::Script {
+        name: format!("/{}", index),
+        path_info: String::new(),
+    })
+}
+
+/// "/index.php/posts/1" is split after the first extension followed by a slash or the end
+fn split_path<'a>(path: &'a str, split: &[String]) -> Option<(&'a str, &'a str)> {
+    let lowercase = path.to_ascii_lowercase();
+    split
+        .iter()
+        .filter_map(|extension| {
+            let extension = extension.to_ascii_lowercase();
+            lowercase
+                .match_indices(&extension)
+                .map(|(start, _)| start + extension.len())
+                .find(|&end| end == path.len() || path.as_bytes()[end] == b'/')
+        })
+        .min()
+        .map(|end| path.split_at(end))
+}
+
+/// CGI/1.1 variables of the request with those of the script, `env` has the last word
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn params(
+    request: &Request<BytesMut>,
+    root: &str,
+    name: &str,
+    path_info: &str,
+    addr: SocketAddr,
+    scheme: &str,
+    options: &FastcgiOptions,
+) -> Vec<(String, String)> {
+    let decode = |path: &str| percent_decode_str(path).decode_utf8_lossy().into_owned();
+    let (script_name, path_info) = (decode(name), decode(path_info));
+    let script_filename = sanitize_path(Path::new(root), name)
+        .map(|file| file.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut params = variables(request, request.headers(), addr, scheme);
+    let mut script = vec![
+        ("DOCUMENT_ROOT", root.to_string()),
+        ("DOCUMENT_URI", format!("{}{}", script_name, path_info)),
+        ("SCRIPT_FILENAME", script_filename),
+        ("SCRIPT_NAME", script_name),
+        // php-cgi refuses to run without it when force_redirect is on
+        ("REDIRECT_STATUS", "200".to_string()),
+    ];
+    if !path_info.is_empty() {
+        script.push((
+            "PATH_TRANSLATED",
+            format!("{}{}", root.trim_end_matches('/'), path_info),
+        ));
+    }
+    script.push(("PATH_INFO", path_info));
+    params.extend(
+        script
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value)),
+    );
+    for (name, value) in &options.env {
+        params.retain(|(param, _)| param != name);
+        params.push((name.clone(), value.clone()));
+    }
+    params
+}
+
+/// BEGIN_REQUEST, PARAMS and STDIN records of a request on a connection closed after it
+fn encode_request(params: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    let mut encoded_params = Vec::new();
+    for (name, value) in params {
+        for len in [name.len(), value.len()] {
+            match u8::try_from(len) {
+                Ok(len) if len < 0x80 => encoded_params.push(len),
+                _ => encoded_params.extend_from_slice(&(len as u32 | 1 << 31).to_be_bytes()),
+            }
+        }
+        encoded_params.extend_from_slice(name.as_bytes());
+        encoded_params.extend_from_slice(value.as_bytes());
+    }
+
+    let mut request = Vec::with_capacity(64 + encoded_params.len() + body.len());
+    push_record(
+        &mut request,
+        BEGIN_REQUEST,
+        &[0, RESPONDER, 0, 0, 0, 0, 0, 0],
+    );
+    for (kind, content) in [(PARAMS, &encoded_params[..]), (STDIN, body)] {
+        for chunk in content.chunks(MAX_CONTENT_LEN) {
+            push_record(&mut request, kind, chunk);
+        }
+        // An empty record ends the stream
+        push_record(&mut request, kind, &[]);
+    }
+    request
+}
+
+fn push_record(buf: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let padding = (8 - content.len() % 8) % 8;
+    let len = (content.len() as u16).to_be_bytes();
+    buf.extend_from_slice(&[VERSION, kind, 0, 1, len[0], len[1], padding as u8, 0]);
+    buf.extend_from_slice(content);
+    buf.resize(buf.len() + padding, 0);
+}
+
+/// STDOUT stream of a response, ending with its END_REQUEST record. STDERR is logged.
+struct Stdout<'a, R> {
+    stream: &'a mut R,
+    buf: BytesMut,
+    record: Option<(u8, usize, usize)>, // type, content and padding left of the current record
+    stderr: Vec<u8>,
+    done: bool,
+}
+
+impl<'a, R> Stdout<'a, R> {
+    fn new(stream: &'a mut R) -> Self {
+        Stdout {
+            stream,
+            buf: BytesMut::new(),
+            record: None,
+            stderr: Vec::new(),
+            done: false,
+        }
+    }
+
+    fn log_stderr(&mut self) {
+        if !self.stderr.is_empty() {
+            warn!(
+                "FastCGI stderr: {}",
+                String::from_utf8_lossy(&self.stderr).trim_end()
+            );
+            self.stderr.clear();
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Stdout<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            match this.record {
+                None if this.buf.len() >= HEADER_LEN => {
+                    let header = this.buf.split_to(HEADER_LEN);
+                    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+                    this.record = Some((header[1], len, header[6] as usize));
+                    if header[1] == END_REQUEST {
+                        this.log_stderr();
+                        this.done = true;
+                    }
+                    continue;
+                }
+                Some((_, 0, 0)) => {
+                    this.record = None;
+                    continue;
+                }
+                Some((kind, content, padding)) if !this.buf.is_empty() => {
+                    if content == 0 {
+                        let skipped = padding.min(this.buf.len());
+                        this.buf.advance(skipped);
+                        this.record = Some((kind, 0, padding - skipped));
+                        continue;
+                    }
+                    let mut len = content.min(this.buf.len());
+                    if kind == STDOUT {
+                        len = len.min(out.remaining());
+                        out.put_slice(&this.buf[..len]);
+                    } else if kind == STDERR {
+                        this.stderr.extend_from_slice(&this.buf[..len]);
+                    }
+                    this.buf.advance(len);
+                    this.record = Some((kind, content - len, padding));
+                    if kind == STDOUT {
+                        return Poll::Ready(Ok(()));
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            // More of the record is needed
+            let mut chunk = [0u8; 16 * 1024];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut *this.stream).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                this.log_stderr();
+                return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.buf.extend_from_slice(read.filled());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cgi::response_head;
+    use crate::config::FastcgiOptions;
+    use crate::fastcgi::{
+        encode_request, push_record, resolve, split_path, Stdout, Target, STDERR, STDOUT,
+    };
+    use bytes::BytesMut;
+    use std::error::Error;
+    use std::path::Path;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_split_path() {
+        let split = vec![".php".to_string()];
+        assert_eq!(
+            split_path("/index.php/posts/1", &split),
+            Some(("/index.php", "/posts/1"))
+        );
+        assert_eq!(split_path("/a.PHP", &split), Some(("/a.PHP", "")));
+        assert_eq!(
+            split_path("/a.phpx/b.php", &split),
+            Some(("/a.phpx/b.php", ""))
+        );
+        assert_eq!(split_path("/style.css", &split), None);
+    }
+
+    #[test]
+    fn test_resolve() -> Result<(), Box<dyn Error>> {
+        let root = std::env::temp_dir().join(format!("cblt-fastcgi-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("wp-admin"))?;
+        for file in [
+            "index.php",
+            "wp-login.php",
+            "style.css",
+            "wp-admin/index.php",
+        ] {
+            std::fs::write(root.join(file), "")?;
+        }
+        let options = FastcgiOptions::default();
+        let script = |name: &str, path_info: &str| {
+            Some(Target::Script {
+                name: name.to_string(),
+                path_info: path_info.to_string(),
+            })
+        };
+
+        assert_eq!(
+            resolve(&root, "/wp-login.php", &options),
+            script("/wp-login.php", "")
+        );
+        assert_eq!(
+            resolve(&root, "/index.php/feed", &options),
+            script("/index.php", "/feed")
+        );
+        assert_eq!(resolve(&root, "/style.css", &options), None);
+        assert_eq!(
+            resolve(&root, "/wp-admin", &options),
+            Some(Target::Redirect)
+        );
+        assert_eq!(
+            resolve(&root, "/wp-admin/", &options),
+            script("/wp-admin/index.php", "")
+        );
+        // Pretty URLs and missing scripts go to the front controller
+        assert_eq!(
+            resolve(&root, "/2024/hello/", &options),
+            script("/index.php", "")
+        );
+        assert_eq!(
+            resolve(&root, "/missing.php", &options),
+            script("/index.php", "")
+        );
+        assert_eq!(resolve(&root, "/../etc/passwd", &options), None);
+
+        let options = FastcgiOptions {
+            index: None,
+            ..FastcgiOptions::default()
+        };
+        assert_eq!(resolve(&root, "/2024/hello/", &options), None);
+        assert_eq!(resolve(Path::new("/nonexistent"), "/a.php", &options), None);
+        std::fs::remove_dir_all(&root)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_request() {
+        let params = vec![
+            ("SCRIPT_NAME".to_string(), "/index.php".to_string()),
+            ("HTTP_LONG".to_string(), "x".repeat(200)),
+        ];
+        let request = encode_request(&params, b"a=1");
+        // BEGIN_REQUEST asking for a responder
+        assert_eq!(request[..8], [1, 1, 0, 1, 0, 8, 0, 0]);
+        assert_eq!(request[8..10], [0, 1]);
+        // Lengths of 128 and more take four bytes
+        let params_len = 2 + 11 + 10 + 1 + 4 + 9 + 200;
+        assert_eq!(request[16..24], [1, 4, 0, 1, 0, params_len as u8, 3, 0]);
+        assert_eq!(request[24..26], [11, 10]);
+        let long = 24 + 2 + 21;
+        assert_eq!(request[long..long + 5], [9, 0x80, 0, 0, 200]);
+        let stdin = 24 + params_len + 3 + 8;
+        assert_eq!(request[stdin - 8..stdin], [1, 4, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(
+            request[stdin..stdin + 11],
+            [1, 5, 0, 1, 0, 3, 5, 0, b'a', b'=', b'1']
+        );
+        assert_eq!(request[request.len() - 8..], [1, 5, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_response() -> Result<(), Box<dyn Error>> {
+        let mut response = Vec::new();
+        push_record(&mut response, STDOUT, b"Status: 404 Not Found\r\nX-Po");
+        push_record(&mut response, STDERR, b"PHP Notice: undefined index");
+        push_record(
+            &mut response,
+            STDOUT,
+            b"wered-By: PHP\nContent-Type: text/html\n\nnot ",
+        );
+        push_record(&mut response, STDOUT, b"found");
+        push_record(&mut response, STDOUT, b"");
+        push_record(&mut response, 3, &[0; 8]);
+        response.extend_from_slice(b"ignored");
+
+        let mut stream = &response[..];
+        let mut stdout = Stdout::new(&mut stream);
+        let mut buf = BytesMut::new();
+        let (status, headers, _) = response_head(&mut stdout, &mut buf).await?;
+        assert_eq!(status, 404);
+        assert_eq!(headers["x-powered-by"], "PHP");
+        assert_eq!(headers["content-type"], "text/html");
+        let mut body = buf.to_vec();
+        stdout.read_to_end(&mut body).await?;
+        assert_eq!(body, b"not found");
+
+        let mut response = Vec::new();
+        push_record(&mut response, STDOUT, b"Location: /wp-admin/\r\n\r\n");
+        let mut stream = &response[..];
+        let mut stdout = Stdout::new(&mut stream);
+        let (status, _, _) = response_head(&mut stdout, &mut BytesMut::new()).await?;
+        assert_eq!(status, 302);
+        // The server went away without END_REQUEST
+        assert!(stdout.read_to_end(&mut Vec::new()).await.is_err());
+
+        Ok(())
+    }
+}
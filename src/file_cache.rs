@@ -0,0 +1,253 @@
+use crate::config::{Directive, FileCacheOptions};
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::Metadata;
+use std::io::{Cursor, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+/// Small files of a host's `file_server` kept in memory, checked against their metadata on
+/// every hit
+pub struct FileCache {
+    options: FileCacheOptions,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    used: BTreeMap<u64, PathBuf>, // least recently used first
+    size: usize,
+    clock: u64,
+}
+
+struct Entry {
+    body: Bytes,
+    modified: Option<SystemTime>, // of the file when it was read
+    used: u64,
+}
+
+/// Contents of a file served from disk or from the cache
+#[derive(Debug)]
+pub enum FileBody {
+    Disk(File),
+    Memory(Cursor<Bytes>),
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Uring(crate::uring::UringFile),
+}
+
+impl AsyncRead for FileBody {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            FileBody::Disk(file) => Pin::new(file).poll_read(cx, buf),
+            FileBody::Memory(body) => Pin::new(body).poll_read(cx, buf),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            FileBody::Uring(file) => Pin::new(file).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncSeek for FileBody {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        match self.get_mut() {
+            FileBody::Disk(file) => Pin::new(file).start_seek(position),
+            FileBody::Memory(body) => Pin::new(body).start_seek(position),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            FileBody::Uring(file) => Pin::new(file).start_seek(position),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        match self.get_mut() {
+            FileBody::Disk(file) => Pin::new(file).poll_complete(cx),
+            FileBody::Memory(body) => Pin::new(body).poll_complete(cx),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            FileBody::Uring(file) => Pin::new(file).poll_complete(cx),
+        }
+    }
+}
+
+/// Cache of the first `file_server` of the host that asks for one
+pub fn host_file_cache(directives: &[Directive]) -> Option<FileCache> {
+    directives.iter().find_map(|directive| match directive {
+        Directive::FileServer { options } => options.cache.clone().map(FileCache::new),
+        _ => None,
+    })
+}
+
+impl FileCache {
+    pub fn new(options: FileCacheOptions) -> Self {
+        FileCache {
+            options,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Contents of the file from memory, read and kept on a miss. None for files over
+    /// `max_file_size`, which are read from disk as they are sent.
+    pub async fn open(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+    ) -> std::io::Result<Option<FileBody>> {
+        if !metadata.is_file() || metadata.len() > self.options.max_file_size {
+            return Ok(None);
+        }
+        let modified = metadata.modified().ok();
+        if let Some(body) = self.get(path, modified, metadata.len()) {
+            return Ok(Some(FileBody::Memory(Cursor::new(body))));
+        }
+        let body = Bytes::from(tokio::fs::read(path).await?);
+        // A file written to while it was read is kept on the next request
+        if body.len() as u64 == metadata.len() {
+            self.insert(path, body.clone(), modified);
+        }
+        Ok(Some(FileBody::Memory(Cursor::new(body))))
+    }
+
+    fn get(&self, path: &Path, modified: Option<SystemTime>, len: u64) -> Option<Bytes> {
+        let mut state = self.state.lock().ok()?;
+        let entry = state.entries.get(path)?;
+        if entry.modified != modified || entry.body.len() as u64 != len {
+            state.remove(path);
+            return None;
+        }
+        let body = entry.body.clone();
+        state.touch(path);
+        Some(body)
+    }
+
+    /// Keeps the file, making room by dropping the least recently used ones
+    fn insert(&self, path: &Path, body: Bytes, modified: Option<SystemTime>) {
+        let size = body.len() + path.as_os_str().len();
+        if size > self.options.max_size as usize {
+            return;
+        }
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        state.remove(path);
+        state.entries.insert(
+            path.to_path_buf(),
+            Entry {
+                body,
+                modified,
+                used: 0,
+            },
+        );
+        state.size += size;
+        state.touch(path);
+        while state.size > self.options.max_size as usize {
+            let Some((_, oldest)) = state.used.pop_first() else {
+                break;
+            };
+            state.remove(&oldest);
+        }
+    }
+
+    pub fn clear(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            *state = State::default();
+        }
+    }
+}
+
+impl State {
+    fn touch(&mut self, path: &Path) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(path) {
+            self.used.remove(&entry.used);
+            entry.used = clock;
+            self.used.insert(clock, path.to_path_buf());
+        }
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.used.remove(&entry.used);
+            self.size -= entry.body.len() + path.as_os_str().len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::FileCacheOptions;
+    use crate::file_cache::{FileBody, FileCache};
+    use std::error::Error;
+    use std::path::Path;
+    use tokio::io::AsyncReadExt;
+
+    async fn read(cache: &FileCache, path: &Path) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let metadata = std::fs::metadata(path)?;
+        match cache.open(path, &metadata).await? {
+            Some(FileBody::Memory(mut body)) => {
+                let mut contents = Vec::new();
+                body.read_to_end(&mut contents).await?;
+                Ok(Some(contents))
+            }
+            Some(_) => Err("expected the file in memory".into()),
+            None => Ok(None),
+        }
+    }
+
+    fn cached(cache: &FileCache, path: &Path) -> bool {
+        cache
+            .state
+            .lock()
+            .is_ok_and(|state| state.entries.contains_key(path))
+    }
+
+    #[tokio::test]
+    async fn test_file_cache() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-file-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let cache = FileCache::new(FileCacheOptions {
+            max_file_size: 100,
+            max_size: 250,
+        });
+        let (small, large) = (dir.join("small.txt"), dir.join("large.txt"));
+        std::fs::write(&small, "hello")?;
+        std::fs::write(&large, [0u8; 101])?;
+
+        assert_eq!(read(&cache, &small).await?.as_deref(), Some(&b"hello"[..]));
+        assert!(cached(&cache, &small));
+        assert_eq!(read(&cache, &small).await?.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(read(&cache, &large).await?, None);
+        assert!(cached(&cache, &small) && !cached(&cache, &large));
+
+        // A changed file is read again
+        std::fs::write(&small, "hello, world")?;
+        assert_eq!(
+            read(&cache, &small).await?.as_deref(),
+            Some(&b"hello, world"[..])
+        );
+
+        // The least recently used file makes room
+        let pages: Vec<_> = (0..3).map(|i| dir.join(format!("{}.html", i))).collect();
+        for page in &pages {
+            std::fs::write(page, [1u8; 80])?;
+            read(&cache, page).await?;
+            read(&cache, &small).await?;
+        }
+        assert!(cached(&cache, &small));
+        assert!(!cached(&cache, &pages[0]));
+        assert!(cached(&cache, &pages[2]));
+
+        cache.clear();
+        assert!(!cached(&cache, &small));
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+}
@@ -1,23 +1,58 @@
+use crate::compression::{add_vary, is_compressible, negotiate, preferred_encoding};
+use crate::config::{EncodeOptions, Encoding, FileServerOptions, MarkdownOptions};
 use crate::error::CbltError;
+use crate::file_cache::{FileBody, FileCache};
+use crate::markdown::{render_page, DEFAULT_LAYOUT};
 use crate::request::parse_range_header;
-use crate::response::{ranged_file_response, send_response_file};
-use bytes::BytesMut;
-use http::header::RANGE;
-use http::{Request, Response, StatusCode};
+use crate::response::{
+    ranged_file_response, send_response, send_response_file, with_headers, ExtraHeaders,
+};
+use crate::sendfile::Sendfile;
+use bytes::{Bytes, BytesMut};
+use http::header::{
+    CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+#[cfg(debug_assertions)]
+use log::debug;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::borrow::Cow;
+use std::fs::Metadata;
+use std::io::Cursor;
 use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
 use tokio::io::AsyncWrite;
 #[cfg(feature = "trace")]
 use tracing::instrument;
 
+/// Characters escaped in a path segment of directory listing links
+pub const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn file_directive<S>(
     root_path: Option<&str>,
+    options: &FileServerOptions,
+    file_cache: Option<&FileCache>,
     request: &Request<BytesMut>,
     socket: &mut S,
+    extra_headers: &ExtraHeaders,
+    encode: Option<&EncodeOptions>,
 ) -> Result<StatusCode, CbltError>
 where
-    S: AsyncWrite + Unpin,
+    S: AsyncWrite + Sendfile + Unpin,
 {
     match root_path {
         None => Err(CbltError::ResponseError {
@@ -29,33 +64,141 @@ where
                 Path::new(root),
                 request.uri().path().trim_start_matches('/'),
             ) {
+                if is_hidden(Path::new(root), &file_path, options) {
+                    return Err(CbltError::ResponseError {
+                        details: "Hidden path".to_string(),
+                        status_code: StatusCode::NOT_FOUND,
+                    });
+                }
                 if file_path.is_dir() {
-                    file_path.push("index.html");
+                    let mut index_path = file_path.join("index.html");
+                    if options.markdown.is_some() && !index_path.is_file() {
+                        let markdown_index = file_path.join("index.md");
+                        if markdown_index.is_file() {
+                            index_path = markdown_index;
+                        }
+                    }
+                    if options.browse && !index_path.is_file() {
+                        return directory_listing(
+                            &file_path,
+                            options,
+                            request,
+                            socket,
+                            extra_headers,
+                        )
+                        .await;
+                    }
+                    file_path = index_path;
+                }
+                if let Some(markdown) = &options.markdown {
+                    if is_markdown(&file_path) {
+                        return markdown_page(
+                            &file_path,
+                            markdown,
+                            request,
+                            socket,
+                            extra_headers,
+                            encode,
+                        )
+                        .await;
+                    }
                 }
+                let mime_type = mime_type(&file_path, options);
+                let precompressed = precompressed_variant(request, &file_path, options);
+                let (file_path, content_encoding) = match precompressed {
+                    Some((path, encoding)) => (path, Some(encoding)),
+                    None => (file_path, None),
+                };
 
-                match File::open(&file_path).await {
-                    Ok(file) => {
-                        let content_length = file_size(&file).await?;
+                match open_file(&file_path, file_cache).await {
+                    Ok((file, metadata)) => {
+                        let content_length = metadata.len();
 
-                        if let Some(range_header) = request.headers().get(RANGE) {
-                            let range_str =
-                                range_header
-                                    .to_str()
-                                    .map_err(|_| CbltError::ResponseError {
-                                        details: "Invalid Range header".to_string(),
-                                        status_code: StatusCode::BAD_REQUEST,
-                                    })?;
+                        let mut validators = HeaderMap::new();
+                        if let Some(encoding) = content_encoding {
+                            #[cfg(debug_assertions)]
+                            debug!("Serving precompressed {:?}", file_path);
+                            validators.insert(
+                                CONTENT_ENCODING,
+                                HeaderValue::from_static(encoding.as_str()),
+                            );
+                        }
+                        if !options.precompressed.is_empty() {
+                            add_vary(&mut validators);
+                        }
+                        let modified = metadata.modified().ok();
+                        let etag = modified.map(|modified| entity_tag(modified, content_length));
+                        if let Some(etag) = &etag {
+                            validators.insert(ETAG, HeaderValue::from_str(etag)?);
+                        }
+                        if let Some(modified) = modified {
+                            validators.insert(
+                                LAST_MODIFIED,
+                                HeaderValue::from_str(&httpdate::fmt_http_date(modified))?,
+                            );
+                        }
 
-                            let range = parse_range_header(range_str, content_length)?;
+                        if is_not_modified(request, etag.as_deref(), modified) {
+                            let mut response = Response::builder()
+                                .status(StatusCode::NOT_MODIFIED)
+                                .body(BytesMut::new())?;
+                            response.headers_mut().extend(validators);
+                            send_response(socket, with_headers(response, extra_headers)).await?;
+                            return Ok(StatusCode::NOT_MODIFIED);
+                        }
+
+                        let range = match request.headers().get(RANGE) {
+                            None => None,
+                            Some(_) if !if_range_matches(request, etag.as_deref(), modified) => {
+                                None
+                            }
+                            Some(range_header) => {
+                                let range_str = range_header.to_str().unwrap_or("");
+                                match parse_range_header(range_str, content_length) {
+                                    Ok(range) => range,
+                                    Err(CbltError::ResponseError {
+                                        status_code: StatusCode::RANGE_NOT_SATISFIABLE,
+                                        ..
+                                    }) => {
+                                        let response = Response::builder()
+                                            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                                            .header(
+                                                "Content-Range",
+                                                format!("bytes */{}", content_length),
+                                            )
+                                            .body(BytesMut::new())?;
+                                        send_response(
+                                            socket,
+                                            with_headers(response, extra_headers),
+                                        )
+                                        .await?;
+                                        return Ok(StatusCode::RANGE_NOT_SATISFIABLE);
+                                    }
+                                    Err(err) => return Err(err),
+                                }
+                            }
+                        };
 
-                            let response =
-                                ranged_file_response(file, &file_path, content_length, range)
+                        if let Some(range) = range {
+                            let mut response =
+                                ranged_file_response(file, &mime_type, content_length, range)
                                     .await?;
-                            send_response_file(socket, response, request).await?;
+                            response.headers_mut().extend(validators);
+                            let response = with_headers(response, extra_headers);
+                            send_response_file(socket, response, request, None).await?;
                             Ok(StatusCode::PARTIAL_CONTENT)
                         } else {
-                            let response = file_response(file, &file_path, content_length)?;
-                            send_response_file(socket, response, request).await?;
+                            let mut response = file_response(file, &mime_type, content_length)?;
+                            response.headers_mut().extend(validators);
+                            let mut response = with_headers(response, extra_headers);
+                            let mut codec = None;
+                            if let Some(encode) = encode {
+                                if is_compressible(response.headers(), encode) {
+                                    add_vary(response.headers_mut());
+                                    codec = negotiate(request, encode);
+                                }
+                            }
+                            send_response_file(socket, response, request, codec).await?;
                             Ok(StatusCode::OK)
                         }
                     }
@@ -71,50 +214,631 @@ where
     }
 }
 
+/// The file with its metadata, held in memory when the file cache takes it
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-async fn file_size(file: &File) -> Result<u64, CbltError> {
+async fn open_file(
+    path: &Path,
+    file_cache: Option<&FileCache>,
+) -> std::io::Result<(FileBody, Metadata)> {
+    if let Some(file_cache) = file_cache {
+        let metadata = std::fs::metadata(path)?;
+        if let Some(body) = file_cache.open(path, &metadata).await? {
+            return Ok((body, metadata));
+        }
+    }
+    // Reads go through the ring, opening is as quick as the checks above
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if crate::uring::ring().is_some() {
+        let file = std::fs::File::open(path)?;
+        let metadata = file.metadata()?;
+        return Ok((
+            FileBody::Uring(crate::uring::UringFile::new(file)),
+            metadata,
+        ));
+    }
+    let file = File::open(path).await?;
     let metadata = file.metadata().await?;
-    Ok(metadata.len())
+    Ok((FileBody::Disk(file), metadata))
+}
+
+/// Returns the first candidate path that exists under the root, a trailing slash asks for a directory
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn try_files(root: &str, candidates: &[String], request_path: &str) -> Option<String> {
+    candidates.iter().find_map(|candidate| {
+        let candidate = candidate.replace("{path}", request_path);
+        let file_path = sanitize_path(Path::new(root), candidate.trim_start_matches('/'))?;
+        let exists = if candidate.ends_with('/') {
+            file_path.is_dir()
+        } else {
+            file_path.is_file()
+        };
+        exists.then_some(candidate)
+    })
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn directory_listing<S>(
+    dir_path: &Path,
+    options: &FileServerOptions,
+    request: &Request<BytesMut>,
+    socket: &mut S,
+    extra_headers: &ExtraHeaders,
+) -> Result<StatusCode, CbltError>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir_path).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let metadata = match entry.metadata().await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !options.allow_dotfiles
+            && name.starts_with('.')
+            && !options.dotfile_exceptions.contains(&name)
+        {
+            continue;
+        }
+        entries.push((
+            name,
+            metadata.is_dir(),
+            metadata.len(),
+            metadata.modified().ok(),
+        ));
+    }
+    // Directories first, then by name
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let base = request.uri().path().trim_end_matches('/');
+    let title = html_escape(&format!("{}/", base));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n\
+         <body>\n<h1>Index of {title}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>Last modified</th></tr>\n"
+    );
+    if let Some((parent, _)) = base.rsplit_once('/') {
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>\n",
+            html_escape(parent)
+        ));
+    }
+    for (name, is_dir, size, modified) in entries {
+        let slash = if is_dir { "/" } else { "" };
+        let href = format!(
+            "{}/{}{}",
+            base,
+            utf8_percent_encode(&name, PATH_SEGMENT),
+            slash
+        );
+        let size = if is_dir {
+            "-".to_string()
+        } else {
+            size.to_string()
+        };
+        let modified = modified.map(httpdate::fmt_http_date).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            html_escape(&href),
+            html_escape(&name),
+            slash,
+            size,
+            modified
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8");
+    let body = if request.method() == Method::HEAD {
+        builder = builder.header(CONTENT_LENGTH, html.len());
+        BytesMut::new()
+    } else {
+        BytesMut::from(html.as_bytes())
+    };
+    let response = builder.body(body)?;
+    send_response(socket, with_headers(response, extra_headers)).await?;
+    Ok(StatusCode::OK)
+}
+
+fn is_markdown(file_path: &Path) -> bool {
+    file_path.extension().is_some_and(|extension| {
+        extension.eq_ignore_ascii_case("md") || extension.eq_ignore_ascii_case("markdown")
+    })
+}
+
+/// Serves the Markdown file rendered into the layout, revalidated against both files
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn markdown_page<S>(
+    file_path: &Path,
+    options: &MarkdownOptions,
+    request: &Request<BytesMut>,
+    socket: &mut S,
+    extra_headers: &ExtraHeaders,
+    encode: Option<&EncodeOptions>,
+) -> Result<StatusCode, CbltError>
+where
+    S: AsyncWrite + Sendfile + Unpin,
+{
+    let not_found = |err: std::io::Error| CbltError::ResponseError {
+        details: err.to_string(),
+        status_code: StatusCode::NOT_FOUND,
+    };
+    let metadata = tokio::fs::metadata(file_path).await.map_err(not_found)?;
+    let source = tokio::fs::read(file_path).await.map_err(not_found)?;
+    let mut modified = metadata.modified().ok();
+    let layout = match &options.layout {
+        None => Cow::Borrowed(DEFAULT_LAYOUT),
+        Some(layout_path) => {
+            let layout = tokio::fs::read_to_string(layout_path)
+                .await
+                .map_err(|err| CbltError::ResponseError {
+                    details: format!("Markdown layout {}: {}", layout_path, err),
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                })?;
+            let layout_modified = tokio::fs::metadata(layout_path)
+                .await
+                .ok()
+                .and_then(|metadata| metadata.modified().ok());
+            modified = modified
+                .zip(layout_modified)
+                .map(|(modified, layout_modified)| modified.max(layout_modified));
+            Cow::Owned(layout)
+        }
+    };
+    let fallback_title = file_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let html = render_page(
+        &String::from_utf8_lossy(&source),
+        &layout,
+        &fallback_title,
+        request.uri().path(),
+    );
+
+    let mut validators = HeaderMap::new();
+    let etag = modified.map(|modified| entity_tag(modified, html.len() as u64));
+    if let Some(etag) = &etag {
+        validators.insert(ETAG, HeaderValue::from_str(etag)?);
+    }
+    if let Some(modified) = modified {
+        validators.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_str(&httpdate::fmt_http_date(modified))?,
+        );
+    }
+    if is_not_modified(request, etag.as_deref(), modified) {
+        let mut response = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .body(BytesMut::new())?;
+        response.headers_mut().extend(validators);
+        send_response(socket, with_headers(response, extra_headers)).await?;
+        return Ok(StatusCode::NOT_MODIFIED);
+    }
+
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_LENGTH, html.len())
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(FileBody::Memory(Cursor::new(Bytes::from(html))))?;
+    response.headers_mut().extend(validators);
+    let mut response = with_headers(response, extra_headers);
+    let mut codec = None;
+    if let Some(encode) = encode {
+        if is_compressible(response.headers(), encode) {
+            add_vary(response.headers_mut());
+            codec = negotiate(request, encode);
+        }
+    }
+    send_response_file(socket, response, request, codec).await?;
+    Ok(StatusCode::OK)
+}
+
+pub fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether the path goes through a dotfile that is not allowed to be served
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn is_hidden(root: &Path, file_path: &Path, options: &FileServerOptions) -> bool {
+    if options.allow_dotfiles {
+        return false;
+    }
+    let relative = file_path.strip_prefix(root).unwrap_or(file_path);
+    relative.components().any(|component| match component {
+        Component::Normal(segment) => {
+            let segment = segment.to_string_lossy();
+            segment.starts_with('.')
+                && !options
+                    .dotfile_exceptions
+                    .iter()
+                    .any(|exception| *exception == segment)
+        }
+        _ => false,
+    })
+}
+
+/// Finds a precompressed sidecar of an existing file that the client accepts
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn precompressed_variant(
+    request: &Request<BytesMut>,
+    file_path: &Path,
+    options: &FileServerOptions,
+) -> Option<(PathBuf, Encoding)> {
+    if options.precompressed.is_empty() || !file_path.is_file() {
+        return None;
+    }
+    let sidecar = |encoding: &Encoding| {
+        let mut path = file_path.as_os_str().to_owned();
+        path.push(".");
+        path.push(encoding.extension());
+        PathBuf::from(path)
+    };
+    let available: Vec<Encoding> = options
+        .precompressed
+        .iter()
+        .filter(|encoding| sidecar(encoding).is_file())
+        .copied()
+        .collect();
+    let encoding = preferred_encoding(request, &available)?;
+    Some((sidecar(&encoding), encoding))
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn entity_tag(modified: SystemTime, content_length: u64) -> String {
+    let mtime = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!("\"{:x}-{:x}\"", mtime, content_length)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn is_not_modified(
+    request: &Request<BytesMut>,
+    etag: Option<&str>,
+    modified: Option<SystemTime>,
+) -> bool {
+    // If-None-Match takes precedence over If-Modified-Since
+    if let Some(if_none_match) = request.headers().get(IF_NONE_MATCH) {
+        let if_none_match = if_none_match.to_str().unwrap_or("");
+        return match etag {
+            Some(etag) => if_none_match.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            }),
+            None => false,
+        };
+    }
+    match (request.headers().get(IF_MODIFIED_SINCE), modified) {
+        (Some(since), Some(modified)) => since
+            .to_str()
+            .ok()
+            .and_then(|since| httpdate::parse_http_date(since).ok())
+            .map(|since| truncate_to_secs(modified) <= since)
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn if_range_matches(
+    request: &Request<BytesMut>,
+    etag: Option<&str>,
+    modified: Option<SystemTime>,
+) -> bool {
+    let if_range = match request.headers().get(IF_RANGE) {
+        Some(if_range) => if_range.to_str().unwrap_or(""),
+        None => return true,
+    };
+    if if_range.starts_with('"') {
+        etag == Some(if_range)
+    } else {
+        match (httpdate::parse_http_date(if_range), modified) {
+            (Ok(date), Some(modified)) => truncate_to_secs(modified) == date,
+            _ => false,
+        }
+    }
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    UNIX_EPOCH + Duration::from_secs(secs)
 }
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 fn file_response(
-    file: File,
-    file_path: &PathBuf,
+    file: FileBody,
+    mime_type: &str,
     content_length: u64,
-) -> Result<Response<File>, CbltError> {
-    // Guess the MIME type based on the file extension
-    let mime_type = mime_guess::from_path(file_path)
-        .first_or_octet_stream()
-        .to_string();
+) -> Result<Response<FileBody>, CbltError> {
     Ok(Response::builder()
         .status(StatusCode::OK)
         .header("Content-Length", content_length)
         .header("Content-Type", mime_type)
+        .header("Accept-Ranges", "bytes")
         .body(file)?)
 }
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-fn sanitize_path(base_path: &Path, requested_path: &str) -> Option<PathBuf> {
+pub fn mime_type(file_path: &Path, options: &FileServerOptions) -> String {
+    let configured = file_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(|extension| options.mime_types.get(&extension.to_ascii_lowercase()));
+    match configured {
+        Some(mime_type) => mime_type.clone(),
+        // Guess the MIME type based on the file extension
+        None => mime_guess::from_path(file_path)
+            .first_or_octet_stream()
+            .to_string(),
+    }
+}
+
+/// Percent-decodes the request path and resolves it under the base path,
+/// `None` when it is malformed or would escape the base path
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn sanitize_path(base_path: &Path, requested_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(requested_path).decode_utf8().ok()?;
+    if decoded.contains('\0') {
+        return None;
+    }
+
+    // Remove dot segments, decoding "%2f" may have introduced new ones
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            _ => segments.push(segment),
+        }
+    }
+
     let mut full_path = base_path.to_path_buf();
-    let requested_path = Path::new(requested_path);
-
-    for component in requested_path.components() {
-        match component {
-            Component::Normal(segment) => full_path.push(segment),
-            Component::RootDir | Component::Prefix(_) => return None,
-            Component::ParentDir => {
-                if !full_path.pop() {
-                    return None;
-                }
+    for segment in segments {
+        match Path::new(segment).components().next() {
+            Some(Component::Normal(_)) => full_path.push(segment),
+            _ => return None,
+        }
+    }
+
+    // Symlinks must not lead outside of the base path either
+    if let (Ok(canonical_base), Ok(canonical_path)) =
+        (base_path.canonicalize(), full_path.canonicalize())
+    {
+        if !canonical_path.starts_with(canonical_base) {
+            return None;
+        }
+    }
+    Some(full_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::embed::testing::{exchange, serve};
+    use crate::file_server::sanitize_path;
+    use crate::RunningServer;
+    use std::error::Error;
+    use std::path::{Path, PathBuf};
+
+    /// Serves a directory holding `files` with `file_server` and its `options`
+    async fn site(
+        name: &str,
+        files: &[(&str, &str)],
+        options: &str,
+    ) -> Result<(RunningServer, u16, PathBuf), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-files-{}-{}", std::process::id(), name));
+        for (path, content) in files {
+            let path = dir.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
             }
-            Component::CurDir => {}
+            std::fs::write(path, content)?;
         }
+        let source = format!(
+            "\"*:0\" {{\n    root \"*\" \"{}\"\n    file_server {{\n{}\n    }}\n}}",
+            dir.display(),
+            options
+        );
+        let (server, port) = serve(&source).await?;
+        Ok((server, port, dir))
     }
 
-    if full_path.starts_with(base_path) {
-        Some(full_path)
-    } else {
-        None
+    async fn get(port: u16, path: &str, headers: &str) -> Result<String, Box<dyn Error>> {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+            path, headers
+        );
+        exchange(port, &request).await
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        response.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    fn body(response: &str) -> &str {
+        response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests() -> Result<(), Box<dyn Error>> {
+        let (server, port, dir) = site("conditional", &[("hello.txt", "hello")], "").await?;
+
+        let response = get(port, "/hello.txt", "").await?;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let etag = header(&response, "etag").ok_or("no etag")?.to_string();
+        let modified = header(&response, "last-modified")
+            .ok_or("no last-modified")?
+            .to_string();
+
+        for validator in [
+            format!("If-None-Match: {}\r\n", etag),
+            format!("If-None-Match: \"other\", W/{}\r\n", etag),
+            "If-None-Match: *\r\n".to_string(),
+            format!("If-Modified-Since: {}\r\n", modified),
+        ] {
+            let response = get(port, "/hello.txt", &validator).await?;
+            assert!(response.starts_with("HTTP/1.1 304"), "{}", validator);
+            assert_eq!(header(&response, "etag"), Some(etag.as_str()));
+            assert_eq!(body(&response), "", "{}", validator);
+        }
+
+        for validator in [
+            "If-None-Match: \"stale\"\r\n".to_string(),
+            "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n".to_string(),
+            // If-None-Match decides alone when both are sent
+            format!(
+                "If-None-Match: \"stale\"\r\nIf-Modified-Since: {}\r\n",
+                modified
+            ),
+        ] {
+            let response = get(port, "/hello.txt", &validator).await?;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", validator);
+            assert_eq!(body(&response), "hello", "{}", validator);
+        }
+
+        server.stop();
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_directory_listing() -> Result<(), Box<dyn Error>> {
+        let files = [
+            ("b.txt", "bb"),
+            ("a <i>&.txt", "a"),
+            ("sub/c.txt", "c"),
+            (".secret", "s"),
+        ];
+        let (server, port, dir) = site("browse", &files, "        browse").await?;
+
+        let response = get(port, "/", "").await?;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(
+            header(&response, "content-type"),
+            Some("text/html; charset=utf-8")
+        );
+        let listing = body(&response);
+        assert!(listing.contains("<title>Index of /</title>"));
+        // Directories first, then by name, names escaped in the text and the link
+        let entries: Vec<&str> = listing
+            .lines()
+            .filter(|line| line.starts_with("<tr><td><a"))
+            .collect();
+        assert_eq!(entries.len(), 3, "{}", listing);
+        assert!(entries[0].starts_with("<tr><td><a href=\"/sub/\">sub/</a></td><td>-</td>"));
+        assert!(entries[1].starts_with(
+            "<tr><td><a href=\"/a%20%3Ci%3E&amp;.txt\">a &lt;i&gt;&amp;.txt</a></td><td>1</td>"
+        ));
+        assert!(entries[2].starts_with("<tr><td><a href=\"/b.txt\">b.txt</a></td><td>2</td>"));
+        assert!(!listing.contains(".secret"));
+
+        let response = get(port, "/sub/", "").await?;
+        assert!(body(&response).contains("<a href=\"/\">../</a>"));
+        assert!(body(&response).contains("<a href=\"/sub/c.txt\">c.txt</a>"));
+
+        // A directory with an index is served the index
+        std::fs::write(dir.join("sub/index.html"), "index")?;
+        assert_eq!(body(&get(port, "/sub/", "").await?), "index");
+        server.stop();
+
+        // Nothing is listed without browse
+        let (server, port, _) = site("browse", &[], "").await?;
+        let response = get(port, "/", "").await?;
+        assert!(response.starts_with("HTTP/1.1 404"));
+        assert!(!body(&response).contains("b.txt"));
+        server.stop();
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_hidden_paths() -> Result<(), Box<dyn Error>> {
+        let files = [
+            (".env", "SECRET=1"),
+            ("a/.git/config", "[core]"),
+            (".well-known/security.txt", "contact"),
+            (".htaccess", "deny"),
+            ("public.txt", "public"),
+        ];
+        let (server, port, dir) = site("hidden", &files, "").await?;
+        for path in [
+            "/.env",
+            "/a/.git/config",
+            "/%2eenv",
+            "/a/%2Egit/config",
+            "/.htaccess",
+        ] {
+            let response = get(port, path, "").await?;
+            assert!(response.starts_with("HTTP/1.1 404"), "{}", path);
+        }
+        // Escaping the root is no way around it either
+        for path in ["/%2e%2e/", "/a/%2e%2e/.env", "/%2e%2e/hidden/.env"] {
+            let response = get(port, path, "").await?;
+            assert!(!response.starts_with("HTTP/1.1 200"), "{}", path);
+            assert!(!body(&response).contains("SECRET"), "{}", path);
+        }
+        assert_eq!(body(&get(port, "/public.txt", "").await?), "public");
+        assert_eq!(
+            body(&get(port, "/.well-known/security.txt", "").await?),
+            "contact"
+        );
+        server.stop();
+
+        let (server, port, _) =
+            site("hidden", &[], "        dotfiles_except \".htaccess\"").await?;
+        assert_eq!(body(&get(port, "/.htaccess", "").await?), "deny");
+        assert!(get(port, "/.env", "").await?.starts_with("HTTP/1.1 404"));
+        server.stop();
+
+        let (server, port, _) = site("hidden", &[], "        dotfiles \"allow\"").await?;
+        assert_eq!(body(&get(port, "/.env", "").await?), "SECRET=1");
+        server.stop();
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        let base = Path::new("/nonexistent/www");
+        assert_eq!(
+            sanitize_path(base, "css/site.css"),
+            Some(PathBuf::from("/nonexistent/www/css/site.css"))
+        );
+        assert_eq!(
+            sanitize_path(base, "a/./b/../my%20file.txt"),
+            Some(PathBuf::from("/nonexistent/www/a/my file.txt"))
+        );
+        assert_eq!(
+            sanitize_path(base, ""),
+            Some(PathBuf::from("/nonexistent/www"))
+        );
+        assert_eq!(sanitize_path(base, "../etc/passwd"), None);
+        assert_eq!(sanitize_path(base, "a/..%2f..%2fetc/passwd"), None);
+        assert_eq!(sanitize_path(base, "%2e%2e/etc/passwd"), None);
+        assert_eq!(sanitize_path(base, "a%00.txt"), None);
+        assert_eq!(sanitize_path(base, "%ff"), None);
     }
 }
@@ -0,0 +1,181 @@
+use crate::config::{ForwardAuthOptions, ReverseProxyOptions};
+use crate::error::CbltError;
+use crate::reverse_proxy::{
+    forwarding_headers, remove_hop_by_hop_headers, strip_forwarding_headers,
+};
+use bytes::{Bytes, BytesMut};
+use http::header::{CONTENT_LENGTH, HOST};
+use http::{HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use tokio::time::timeout;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+type AuthClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+static CLIENT: OnceLock<AuthClient> = OnceLock::new(); // keeps connections to the auth services
+
+fn client() -> Result<&'static AuthClient, CbltError> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()?
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok(CLIENT.get_or_init(|| Client::builder(TokioExecutor::new()).build(connector)))
+}
+
+/// Asks the auth service whether the request may go on. A 2xx answer lets it through with the
+/// `copy_headers` of the answer, any other answer is returned to be sent to the client instead.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn authorize(
+    request: &mut Request<BytesMut>,
+    upstream: &str,
+    options: &ForwardAuthOptions,
+    addr: SocketAddr,
+    scheme: &str,
+) -> Result<Option<Response<BytesMut>>, CbltError> {
+    let bad_gateway = |details: String| CbltError::ResponseError {
+        details,
+        status_code: StatusCode::BAD_GATEWAY,
+    };
+
+    // The client's headers without its body, and what the request was about
+    let mut headers = request.headers().clone();
+    remove_hop_by_hop_headers(&mut headers);
+    headers.remove(CONTENT_LENGTH);
+    let host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok());
+    let proxy_options = ReverseProxyOptions {
+        trusted_proxies: options.trusted_proxies.clone(),
+        ..Default::default()
+    };
+    let forwarded = forwarding_headers(request.headers(), host, addr, scheme, &proxy_options);
+    strip_forwarding_headers(&mut headers);
+    headers.extend(forwarded);
+    headers.insert(
+        "x-forwarded-method",
+        HeaderValue::from_str(request.method().as_str())?,
+    );
+    let path_and_query = request.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    headers.insert("x-forwarded-uri", HeaderValue::from_str(path_and_query)?);
+
+    let mut auth_request = Request::builder()
+        .method(Method::GET)
+        .uri(format!("{}{}", upstream.trim_end_matches('/'), options.uri))
+        .body(Full::new(Bytes::new()))?;
+    *auth_request.headers_mut() = headers;
+
+    let response = timeout(options.timeout, client()?.request(auth_request))
+        .await
+        .map_err(|_| CbltError::ResponseError {
+            details: format!("Auth service {} timed out", upstream),
+            status_code: StatusCode::GATEWAY_TIMEOUT,
+        })?
+        .map_err(|err| bad_gateway(format!("Auth service {}: {}", upstream, err)))?;
+
+    if response.status().is_success() {
+        // Headers the auth service did not set are dropped, so a client cannot supply them
+        for copy in &options.copy_headers {
+            let (from, to) = copy.split_once('>').unwrap_or((copy, copy));
+            let Ok(to) = HeaderName::from_bytes(to.as_bytes()) else {
+                continue;
+            };
+            request.headers_mut().remove(&to);
+            for value in response.headers().get_all(from) {
+                request.headers_mut().append(to.clone(), value.clone());
+            }
+        }
+        return Ok(None);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = body
+        .collect()
+        .await
+        .map_err(|err| bad_gateway(format!("Auth service {}: {}", upstream, err)))?
+        .to_bytes();
+    remove_hop_by_hop_headers(&mut parts.headers);
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Some(Response::from_parts(parts, BytesMut::from(&body[..]))))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ForwardAuthOptions;
+    use crate::forward_auth::authorize;
+    use bytes::BytesMut;
+    use http::{Request, StatusCode};
+    use std::error::Error;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_forward_auth() -> Result<(), Box<dyn Error>> {
+        // Allows requests carrying a token and sends the others to a login page
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let upstream = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let len = stream.read(&mut buf).await.unwrap_or(0);
+                let head = String::from_utf8_lossy(&buf[..len]).to_lowercase();
+                let response = if !head.starts_with("get /verify?rd=1 ")
+                    || !head.contains("x-forwarded-method: post")
+                    || !head.contains("x-forwarded-uri: /app/save?id=7")
+                {
+                    "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n"
+                } else if head.contains("authorization: bearer good") {
+                    "HTTP/1.1 200 OK\r\nremote-user: alice\r\nremote-groups: admins\r\ncontent-length: 0\r\n\r\n"
+                } else {
+                    "HTTP/1.1 302 Found\r\nlocation: https://login.example.com/\r\ncontent-length: 5\r\n\r\nlogin"
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let options = ForwardAuthOptions {
+            uri: "/verify?rd=1".to_string(),
+            copy_headers: vec![
+                "remote-user".to_string(),
+                "remote-groups>x-groups".to_string(),
+            ],
+            ..Default::default()
+        };
+        let addr = "10.0.0.7:4000".parse()?;
+
+        let mut allowed = Request::post("/app/save?id=7")
+            .header("authorization", "Bearer good")
+            .header("remote-user", "mallory")
+            .body(BytesMut::new())?;
+        let answer = authorize(&mut allowed, &upstream, &options, addr, "https").await?;
+        assert!(answer.is_none());
+        assert_eq!(allowed.headers()["remote-user"], "alice");
+        assert_eq!(allowed.headers()["x-groups"], "admins");
+
+        let mut denied = Request::post("/app/save?id=7")
+            .header("remote-user", "mallory")
+            .body(BytesMut::new())?;
+        let Some(answer) = authorize(&mut denied, &upstream, &options, addr, "https").await? else {
+            panic!("expected the auth service's answer");
+        };
+        assert_eq!(answer.status(), StatusCode::FOUND);
+        assert_eq!(answer.headers()["location"], "https://login.example.com/");
+        assert_eq!(&answer.body()[..], b"login");
+
+        let err = authorize(&mut denied, "http://127.0.0.1:1", &options, addr, "https").await;
+        assert!(err.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use crate::config::{ForwardProxyOptions, ProxyTarget, ProxyUser};
+use crate::error::CbltError;
+use crate::handler::Context;
+use crate::response::{custom_error_response, write_response_head};
+use crate::reverse_proxy::{tunnel, BackendStream};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::BytesMut;
+use http::header::{PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use http::{HeaderMap, HeaderValue, StatusCode};
+#[cfg(debug_assertions)]
+use log::debug;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::time::timeout;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Opens the tunnel of a `CONNECT host:port` request and relays it until either side closes
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn connect(
+    ctx: &mut Context<'_>,
+    options: &ForwardProxyOptions,
+) -> Result<StatusCode, CbltError> {
+    if !authorized(ctx.request.headers(), &options.users) {
+        let mut response =
+            custom_error_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED, &ctx.error_pages)
+                .await?;
+        response.headers_mut().insert(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"cblt\""),
+        );
+        return ctx.respond(response).await;
+    }
+    let Some((host, port)) = ctx
+        .request
+        .uri()
+        .authority()
+        .and_then(|authority| Some((authority.host(), authority.port_u16()?)))
+    else {
+        return ctx.respond_error(StatusCode::BAD_REQUEST).await;
+    };
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+
+    let addrs = match destinations(&host, port, options).await {
+        Ok(addrs) if addrs.is_empty() => {
+            #[cfg(debug_assertions)]
+            debug!("Forward proxy refused {}:{} for {}", host, port, ctx.addr);
+            return ctx.respond_error(StatusCode::FORBIDDEN).await;
+        }
+        Ok(addrs) => addrs,
+        Err(status) => return ctx.respond_error(status).await,
+    };
+    let mut status = StatusCode::BAD_GATEWAY;
+    let mut upstream = None;
+    for addr in addrs {
+        match timeout(options.connect_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => {
+                upstream = Some(stream);
+                break;
+            }
+            Ok(Err(_)) => status = StatusCode::BAD_GATEWAY,
+            Err(_) => status = StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+    let Some(upstream) = upstream else {
+        return ctx.respond_error(status).await;
+    };
+    let _ = upstream.set_nodelay(true);
+
+    // The connection is the tunnel's from here on
+    ctx.keep_alive = false;
+    write_response_head(&mut ctx.socket, StatusCode::OK, &HeaderMap::new()).await?;
+    tunnel(
+        &mut ctx.socket,
+        ctx.buffer,
+        BackendStream::Plain(upstream),
+        BytesMut::new(),
+    )
+    .await?;
+    Ok(StatusCode::OK)
+}
+
+/// Whether the Basic credentials of `Proxy-Authorization` are of a user, anyone's when there are
+/// no users
+fn authorized(headers: &HeaderMap, users: &[ProxyUser]) -> bool {
+    if users.is_empty() {
+        return true;
+    }
+    let credentials = headers
+        .get(PROXY_AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, encoded)| BASE64_STANDARD.decode(encoded.trim()).ok());
+    let Some(credentials) = credentials else {
+        return false;
+    };
+    // Every user is compared in constant time, so timing tells nothing about the names
+    users.iter().fold(false, |found, user| {
+        let expected = format!("{}:{}", user.name, user.password.expose());
+        let equal =
+            aws_lc_rs::constant_time::verify_slices_are_equal(expected.as_bytes(), &credentials)
+                .is_ok();
+        found | equal
+    })
+}
+
+/// Addresses of the destination the rules let the tunnel reach, the status to answer with when
+/// it cannot be resolved
+async fn destinations(
+    host: &str,
+    port: u16,
+    options: &ForwardProxyOptions,
+) -> Result<Vec<SocketAddr>, StatusCode> {
+    if !options.ports.contains(&port) || options.deny.iter().any(|t| host_matches(t, host, port)) {
+        return Ok(Vec::new());
+    }
+    let name_allowed =
+        options.allow.is_empty() || options.allow.iter().any(|t| host_matches(t, host, port));
+    // Without address rules the name decides alone, no lookup needed
+    let ranges = |targets: &[ProxyTarget]| {
+        targets
+            .iter()
+            .any(|target| matches!(target, ProxyTarget::Range(_)))
+    };
+    if !name_allowed && !ranges(&options.allow) {
+        return Ok(Vec::new());
+    }
+    let addrs = match timeout(options.connect_timeout, lookup_host((host, port))).await {
+        Ok(Ok(addrs)) => addrs,
+        Ok(Err(_)) => return Err(StatusCode::BAD_GATEWAY),
+        Err(_) => return Err(StatusCode::GATEWAY_TIMEOUT),
+    };
+    // Checked on every address, so a name cannot point the tunnel somewhere denied. Internal
+    // addresses are only reached through a range that allows them explicitly
+    Ok(addrs
+        .filter(|addr| !options.deny.iter().any(|t| range_contains(t, addr)))
+        .filter(|addr| {
+            let in_range = options.allow.iter().any(|t| range_contains(t, addr));
+            in_range || (name_allowed && !internal(addr.ip()))
+        })
+        .collect())
+}
+
+/// Loopback, private, link-local and unspecified addresses, which a tunnel must not reach by default
+fn internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => internal(IpAddr::V4(ip)),
+            None => {
+                let segment = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || segment & 0xfe00 == 0xfc00 // unique local
+                    || segment & 0xffc0 == 0xfe80 // link-local
+            }
+        },
+    }
+}
+
+fn host_matches(target: &ProxyTarget, host: &str, port: u16) -> bool {
+    let ProxyTarget::Host {
+        pattern,
+        port: target_port,
+    } = target
+    else {
+        return false;
+    };
+    if target_port.is_some_and(|target_port| target_port != port) {
+        return false;
+    }
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        None => pattern == host,
+    }
+}
+
+fn range_contains(target: &ProxyTarget, addr: &SocketAddr) -> bool {
+    matches!(target, ProxyTarget::Range(range) if range.contains(&addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::testing::serve;
+    use std::error::Error;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn target(s: &str) -> ProxyTarget {
+        s.parse().unwrap_or_else(|_| panic!("invalid target {}", s))
+    }
+
+    #[test]
+    fn test_host_matches() {
+        assert!(host_matches(&target("example.com"), "example.com", 443));
+        assert!(!host_matches(
+            &target("example.com"),
+            "www.example.com",
+            443
+        ));
+        assert!(host_matches(
+            &target("*.example.com"),
+            "a.b.example.com",
+            443
+        ));
+        assert!(!host_matches(&target("*.example.com"), "example.com", 443));
+        assert!(!host_matches(
+            &target("*.example.com"),
+            "badexample.com",
+            443
+        ));
+        assert!(host_matches(
+            &target("example.com:8443"),
+            "example.com",
+            8443
+        ));
+        assert!(!host_matches(
+            &target("example.com:8443"),
+            "example.com",
+            443
+        ));
+        assert!(!host_matches(&target("10.0.0.0/8"), "10.0.0.1", 443));
+        assert!(range_contains(
+            &target("10.0.0.0/8"),
+            &"10.1.2.3:443".parse().unwrap_or_else(|_| unreachable!())
+        ));
+    }
+
+    #[test]
+    fn test_internal() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(
+                internal(ip.parse().unwrap_or_else(|_| unreachable!())),
+                "{}",
+                ip
+            );
+        }
+        for ip in ["192.0.2.1", "8.8.8.8", "2001:db8::1", "::ffff:8.8.8.8"] {
+            assert!(
+                !internal(ip.parse().unwrap_or_else(|_| unreachable!())),
+                "{}",
+                ip
+            );
+        }
+    }
+
+    #[test]
+    fn test_authorized() {
+        let users = vec![ProxyUser {
+            name: "alice".to_string(),
+            password: "secret".into(),
+        }];
+        let mut headers = HeaderMap::new();
+        assert!(authorized(&headers, &[]));
+        assert!(!authorized(&headers, &users));
+        let basic = |credentials: &str| {
+            HeaderValue::from_str(&format!("Basic {}", BASE64_STANDARD.encode(credentials)))
+                .unwrap_or_else(|_| unreachable!())
+        };
+        headers.insert(PROXY_AUTHORIZATION, basic("alice:secret"));
+        assert!(authorized(&headers, &users));
+        headers.insert(PROXY_AUTHORIZATION, basic("alice:guess"));
+        assert!(!authorized(&headers, &users));
+    }
+
+    async fn request(port: u16, head: &str) -> Result<(String, TcpStream), Box<dyn Error>> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream.write_all(head.as_bytes()).await?;
+        let mut response = Vec::new();
+        let mut byte = [0; 1];
+        while !response.ends_with(b"\r\n\r\n") && stream.read(&mut byte).await? == 1 {
+            response.push(byte[0]);
+        }
+        Ok((String::from_utf8(response)?, stream))
+    }
+
+    #[tokio::test]
+    async fn test_forward_proxy() -> Result<(), Box<dyn Error>> {
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let echo_port = echo.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        let source = format!(
+            r#""*:0" {{
+    forward_proxy {{
+        basic_auth "alice" "secret"
+        allow "127.0.0.1" "*.allowed.test"
+        deny "blocked.allowed.test"
+        ports "{echo_port}" "443"
+    }}
+    redir "https://example.com"
+}}"#
+        );
+        let (server, port) = serve(&source).await?;
+        let auth = format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            BASE64_STANDARD.encode("alice:secret")
+        );
+        let connect = |target: String, auth: &str| {
+            format!(
+                "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n{auth}\r\n",
+                target = target,
+                auth = auth
+            )
+        };
+
+        let (head, _) = request(port, &connect(format!("127.0.0.1:{}", echo_port), "")).await?;
+        assert!(head.starts_with("HTTP/1.1 407"));
+        assert!(head.contains("proxy-authenticate: Basic realm=\"cblt\""));
+
+        let (head, mut tunnel) =
+            request(port, &connect(format!("127.0.0.1:{}", echo_port), &auth)).await?;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        tunnel.write_all(b"ping").await?;
+        let mut echoed = [0; 4];
+        tunnel.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"ping");
+
+        for refused in [
+            "127.0.0.1:22".to_string(),
+            "blocked.allowed.test:443".to_string(),
+            "192.0.2.1:443".to_string(),
+        ] {
+            let (head, _) = request(port, &connect(refused.clone(), &auth)).await?;
+            assert!(head.starts_with("HTTP/1.1 403"), "{}", refused);
+        }
+
+        // Everything else is for the next directives
+        let (head, _) = request(port, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+        assert!(head.starts_with("HTTP/1.1 302"));
+        server.stop();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_internal_destinations() -> Result<(), Box<dyn Error>> {
+        let internal = TcpListener::bind("127.0.0.1:0").await?;
+        let internal_port = internal.local_addr()?.port();
+        let source = format!(
+            r#""*:0" {{
+    forward_proxy {{
+        basic_auth "alice" "secret"
+        ports "{internal_port}"
+    }}
+}}"#
+        );
+        let (server, port) = serve(&source).await?;
+        let auth = format!(
+            "Proxy-Authorization: Basic {}\r\n",
+            BASE64_STANDARD.encode("alice:secret")
+        );
+        for target in ["127.0.0.1", "localhost", "[::1]"] {
+            let target = format!("{}:{}", target, internal_port);
+            let head = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n{auth}\r\n");
+            let (head, _) = request(port, &head).await?;
+            assert!(head.starts_with("HTTP/1.1 403"), "{}", target);
+        }
+        server.stop();
+        Ok(())
+    }
+}
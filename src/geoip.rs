@@ -0,0 +1,166 @@
+use crate::cidr::Cidr;
+use crate::config::Directive;
+use crate::error::CbltError;
+use crate::reverse_proxy::client_ip;
+use http::HeaderMap;
+use maxminddb::{path, Reader};
+use std::net::SocketAddr;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// MaxMind GeoLite2/GeoIP2 database of a host, read into memory when the configuration is loaded
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+    trusted_proxies: Vec<Cidr>, // peers whose forwarding headers name the client
+}
+
+impl GeoIp {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn open(database: &str, trusted_proxies: &[Cidr]) -> Result<Self, CbltError> {
+        let reader = Reader::open_readfile(database).map_err(|err| CbltError::KdlParseError {
+            details: format!("Can't open GeoIP database '{}': {}", database, err),
+        })?;
+        Ok(GeoIp {
+            reader,
+            trusted_proxies: trusted_proxies.to_vec(),
+        })
+    }
+
+    /// ISO 3166-1 code of the client's country, when the database knows its address
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn country(&self, headers: &HeaderMap, addr: SocketAddr) -> Option<String> {
+        let ip = client_ip(headers, addr, &self.trusted_proxies);
+        self.reader
+            .lookup(ip)
+            .ok()?
+            .decode_path(&path!["country", "iso_code"])
+            .ok()?
+    }
+}
+
+/// Database of the host's `geoip` directive
+pub fn host_geoip(directives: &[Directive]) -> Result<Option<GeoIp>, CbltError> {
+    directives
+        .iter()
+        .find_map(|directive| match directive {
+            Directive::GeoIp {
+                database,
+                trusted_proxies,
+            } => Some(GeoIp::open(database, trusted_proxies)),
+            _ => None,
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::cidr::Cidr;
+    use crate::geoip::GeoIp;
+    use http::HeaderMap;
+    use std::error::Error;
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    /// IPv4 database with 24-bit records mapping each network to `{"country": {"iso_code": ..}}`,
+    /// the networks must not overlap
+    pub fn write_database(networks: &[(&str, &str)]) -> Result<PathBuf, Box<dyn Error>> {
+        let mut nodes = vec![[Record::Empty, Record::Empty]];
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for (index, (network, code)) in networks.iter().enumerate() {
+            let (addr, prefix) = network.split_once('/').unwrap_or((network, "32"));
+            let IpAddr::V4(addr) = addr.parse()? else {
+                return Err("IPv4 networks only".into());
+            };
+            let (bits, prefix) = (u32::from(addr), prefix.parse::<u32>()?);
+            let mut node = 0;
+            for depth in 0..prefix {
+                let bit = ((bits >> (31 - depth)) & 1) as usize;
+                if depth + 1 == prefix {
+                    nodes[node][bit] = Record::Data(index);
+                } else if let Record::Node(next) = nodes[node][bit] {
+                    node = next;
+                } else {
+                    nodes.push([Record::Empty, Record::Empty]);
+                    nodes[node][bit] = Record::Node(nodes.len() - 1);
+                    node = nodes.len() - 1;
+                }
+            }
+            offsets.push(data.len());
+            data.extend_from_slice(b"\xe1\x47country\xe1\x48iso_code\x42");
+            data.extend_from_slice(code.as_bytes());
+        }
+
+        let count = nodes.len();
+        let mut database = Vec::new();
+        for node in &nodes {
+            for record in node {
+                let value = match record {
+                    Record::Empty => count,
+                    Record::Node(next) => *next,
+                    Record::Data(index) => count + 16 + offsets[*index],
+                };
+                database.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+            }
+        }
+        database.extend_from_slice(&[0; 16]);
+        database.extend_from_slice(&data);
+        database.extend_from_slice(b"\xab\xcd\xefMaxMind.com\xe9");
+        database.extend_from_slice(b"\x5bbinary_format_major_version\xa1\x02");
+        database.extend_from_slice(b"\x5bbinary_format_minor_version\xa0");
+        database.extend_from_slice(b"\x4bbuild_epoch\x00\x02");
+        database.extend_from_slice(b"\x4ddatabase_type\x44Test");
+        database.extend_from_slice(b"\x4bdescription\xe0");
+        database.extend_from_slice(b"\x4aip_version\xa1\x04");
+        database.extend_from_slice(b"\x49languages\x00\x04");
+        database.extend_from_slice(b"\x4anode_count\xc4");
+        database.extend_from_slice(&(count as u32).to_be_bytes());
+        database.extend_from_slice(b"\x4brecord_size\xa1\x18");
+
+        let path = std::env::temp_dir().join(format!(
+            "cblt-geoip-{}-{}.mmdb",
+            std::process::id(),
+            networks.len()
+        ));
+        std::fs::write(&path, database)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test_geoip_country() -> Result<(), Box<dyn Error>> {
+        let path = write_database(&[("81.3.0.0/16", "DE"), ("81.2.69.0/24", "GB")])?;
+        let trusted: Vec<Cidr> = vec!["10.0.0.0/8".parse()?];
+        let geoip = GeoIp::open(&path.to_string_lossy(), &trusted)?;
+        let headers = HeaderMap::new();
+
+        assert_eq!(
+            geoip.country(&headers, "81.3.4.5:4000".parse()?),
+            Some("DE".to_string())
+        );
+        assert_eq!(
+            geoip.country(&headers, "81.2.69.160:4000".parse()?),
+            Some("GB".to_string())
+        );
+        assert_eq!(geoip.country(&headers, "1.1.1.1:4000".parse()?), None);
+        assert_eq!(geoip.country(&headers, "[2001:db8::1]:4000".parse()?), None);
+
+        // Behind a trusted proxy the client named in its headers is looked up
+        let mut forwarded = HeaderMap::new();
+        forwarded.insert("x-forwarded-for", "81.2.69.1".parse()?);
+        assert_eq!(
+            geoip.country(&forwarded, "10.0.0.2:4000".parse()?),
+            Some("GB".to_string())
+        );
+        assert_eq!(geoip.country(&forwarded, "1.1.1.1:4000".parse()?), None);
+
+        assert!(GeoIp::open("/nonexistent.mmdb", &[]).is_err());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
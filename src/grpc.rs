@@ -0,0 +1,424 @@
+use crate::access_log::RequestLog;
+use crate::config::Directive;
+use crate::directive::find_host;
+use crate::error::CbltError;
+use crate::headers::{apply_header_ops, fill_placeholders, header_placeholders, Placeholders};
+use crate::http2::send_data;
+use crate::otel::{SpanKind, TRACEPARENT};
+use crate::rate_limit::retry_after;
+use crate::remote_ip;
+use crate::reverse_proxy::{
+    backend_authority, connect_backend, forwarding_headers, remove_hop_by_hop_headers,
+    strip_forwarding_headers, upstream_placeholders, InFlight, ReverseProxyState,
+};
+use crate::server::{HostDetails, ServerSettings};
+use bytes::{Bytes, BytesMut};
+use h2::client::SendRequest;
+use h2::server::SendResponse;
+use h2::{Reason, RecvStream, SendStream};
+use http::header::{CONTENT_TYPE, HOST, RETRY_AFTER, TE};
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode, Uri};
+use log::error;
+use std::net::SocketAddr;
+use std::time::Instant;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+const GRPC_UNAVAILABLE: &str = "14";
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn is_grpc<B>(request: &Request<B>) -> bool {
+    request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+/// Host and reverse_proxy that take a gRPC request
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn route<'a, B>(
+    settings: &'a ServerSettings,
+    request: &Request<B>,
+) -> Option<(&'a String, &'a HostDetails, &'a ReverseProxyState)> {
+    let host = match request.uri().authority() {
+        Some(authority) => authority.as_str(),
+        None => request
+            .headers()
+            .get(HOST)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(""),
+    };
+    let (host_name, host_config) = find_host(&settings.hosts, host)?;
+    let state = host_config
+        .directives
+        .iter()
+        .find_map(|directive| match directive {
+            Directive::ReverseProxy { pattern, .. } if host_config.matches(pattern, request) => {
+                host_config.reverse_proxy_states.get(pattern)
+            }
+            _ => None,
+        })?;
+    Some((host_name, host_config, state))
+}
+
+/// Relays a gRPC stream to a backend over HTTP/2, streaming both ways and keeping the trailers
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn proxy(
+    mut request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    (host_name, host_config, state): (&String, &HostDetails, &ReverseProxyState),
+    addr: SocketAddr,
+    scheme: &str,
+) -> Result<(), CbltError> {
+    let started = Instant::now();
+    let mut logged = Request::new(BytesMut::new());
+    *logged.method_mut() = request.method().clone();
+    *logged.uri_mut() = request
+        .uri()
+        .path_and_query()
+        .cloned()
+        .map(Uri::from)
+        .unwrap_or_default();
+    *logged.version_mut() = request.version();
+    *logged.headers_mut() = request.headers().clone();
+    let mut request_log = RequestLog::default();
+    request_log.received(&logged);
+    request_log.host = Some(host_name.clone());
+    if host_config.geoip.is_some() {
+        request_log.country = Some(host_config.country(&request, addr).unwrap_or_default());
+    }
+    let span = host_config
+        .tracer
+        .as_ref()
+        .map(|tracer| tracer.start(&logged, addr, scheme));
+
+    // gRPC clients read a 403 as PERMISSION_DENIED
+    if !remote_ip::allowed(host_config, &request, addr) {
+        let mut response = Response::new(());
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        respond.send_response(response, true)?;
+        request_log.record(&logged, StatusCode::FORBIDDEN);
+        if let Some(access_log) = &host_config.access_log {
+            access_log.write(addr, &request_log, 0, started.elapsed());
+        }
+        if let Some(span) = span {
+            span.end(StatusCode::FORBIDDEN);
+        }
+        return Ok(());
+    }
+
+    // Rate limited like any other request to the host, gRPC clients retry a 429 as UNAVAILABLE
+    let mut limited = None;
+    for directive in &host_config.directives {
+        let Directive::RateLimit { pattern, .. } = directive else {
+            continue;
+        };
+        if !host_config.matches(pattern, &request) {
+            continue;
+        }
+        let Some(limiter) = host_config.rate_limiters.get(pattern) else {
+            continue;
+        };
+        if let Err(wait) = limiter.check(&request, addr).await {
+            limited = Some(wait);
+            break;
+        }
+    }
+    if let Some(wait) = limited {
+        let mut response = Response::new(());
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after(wait)));
+        respond.send_response(response, true)?;
+        request_log.record(&logged, StatusCode::TOO_MANY_REQUESTS);
+        if let Some(access_log) = &host_config.access_log {
+            access_log.write(addr, &request_log, 0, started.elapsed());
+        }
+        if let Some(span) = span {
+            span.end(StatusCode::TOO_MANY_REQUESTS);
+        }
+        return Ok(());
+    }
+
+    let mut proxy_span = span
+        .as_ref()
+        .map(|span| span.child("reverse_proxy", SpanKind::Client));
+    if let Some(proxy_span) = &proxy_span {
+        request.headers_mut().insert(
+            TRACEPARENT,
+            HeaderValue::from_str(&proxy_span.context.traceparent())?,
+        );
+    }
+    let geoip = host_config.geoip_placeholders(&request, addr);
+    let (status, sent) = match forward(request, &mut respond, state, geoip, addr, scheme).await {
+        Ok(forwarded) => forwarded,
+        Err(err) => {
+            #[cfg(debug_assertions)]
+            error!("Error: {}", err);
+            // Trailers-only response, so the client sees a gRPC status rather than a broken stream
+            let mut response = Response::new(());
+            let headers = response.headers_mut();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+            headers.insert("grpc-status", HeaderValue::from_static(GRPC_UNAVAILABLE));
+            headers.insert(
+                "grpc-message",
+                HeaderValue::from_static("upstream%20unavailable"),
+            );
+            respond.send_response(response, true)?;
+            if let Some(proxy_span) = proxy_span.take() {
+                proxy_span.end(StatusCode::BAD_GATEWAY);
+            }
+            (StatusCode::OK, 0)
+        }
+    };
+    if let Some(proxy_span) = proxy_span {
+        proxy_span.end(status);
+    }
+
+    request_log.record(&logged, status);
+    if let Some(access_log) = &host_config.access_log {
+        access_log.write(addr, &request_log, sent, started.elapsed());
+    }
+    if let Some(span) = span {
+        span.end(status);
+    }
+    Ok(())
+}
+
+/// Errors before the backend answered are returned, later ones reset the stream
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn forward(
+    request: Request<RecvStream>,
+    respond: &mut SendResponse<Bytes>,
+    state: &ReverseProxyState,
+    geoip: Placeholders,
+    addr: SocketAddr,
+    scheme: &str,
+) -> Result<(StatusCode, u64), CbltError> {
+    let (mut parts, recv) = request.into_parts();
+    let (mut client, _in_flight, backend_addr) = backend_client(state, addr).await?;
+
+    // gRPC needs `te: trailers`, the only hop-by-hop header HTTP/2 allows
+    let te = parts.headers.get(TE).cloned();
+    remove_hop_by_hop_headers(&mut parts.headers);
+    if let Some(te) = te.filter(|te| te == "trailers") {
+        parts.headers.insert(TE, te);
+    }
+    let mut authority = match parts.uri.authority() {
+        Some(authority) => authority.to_string(),
+        None => parts
+            .headers
+            .get(HOST)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string(),
+    };
+    parts.headers.remove(HOST);
+    let forwarded = forwarding_headers(
+        &parts.headers,
+        Some(&authority),
+        addr,
+        scheme,
+        &state.options,
+    );
+    let mut placeholders = header_placeholders(&parts.method, &parts.uri, Some(&authority), addr);
+    placeholders.extend(upstream_placeholders(&backend_addr));
+    placeholders.extend(geoip);
+    strip_forwarding_headers(&mut parts.headers);
+    parts.headers.extend(forwarded);
+    apply_header_ops(
+        &mut parts.headers,
+        &fill_placeholders(&state.options.header_up, &placeholders),
+    );
+    // HTTP/2 carries the host in the `:authority` pseudo-header
+    if let Some(host) = parts
+        .headers
+        .remove(HOST)
+        .and_then(|host| host.to_str().ok().map(String::from))
+    {
+        authority = host;
+    }
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    parts.uri = format!("http://{}{}", authority, path)
+        .parse::<Uri>()
+        .map_err(|e| CbltError::ResponseError {
+            details: e.to_string(),
+            status_code: StatusCode::BAD_REQUEST,
+        })?;
+
+    let end_of_stream = recv.is_end_stream();
+    let (response, send) = client.send_request(Request::from_parts(parts, ()), end_of_stream)?;
+    let upload = (!end_of_stream).then(|| tokio::spawn(copy_request(recv, send)));
+
+    let response = response.await;
+    let result = match response {
+        Ok(response) => {
+            let (mut parts, body) = response.into_parts();
+            remove_hop_by_hop_headers(&mut parts.headers);
+            apply_header_ops(
+                &mut parts.headers,
+                &fill_placeholders(&state.options.header_down, &placeholders),
+            );
+            let status = parts.status;
+            let end_of_stream = body.is_end_stream();
+            let send = respond.send_response(Response::from_parts(parts, ()), end_of_stream)?;
+            let mut sent = 0;
+            if !end_of_stream {
+                if let Err(err) = copy_response(body, send, &mut sent).await {
+                    #[cfg(debug_assertions)]
+                    error!("Error: {}", err);
+                }
+            }
+            Ok((status, sent))
+        }
+        Err(err) => Err(err.into()),
+    };
+    // The backend may answer before the client is done sending
+    if let Some(upload) = upload {
+        upload.abort();
+    }
+    result
+}
+
+/// Ready client of a backend, reusing its HTTP/2 connection, the stream counted on it and the
+/// backend's authority
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn backend_client(
+    state: &ReverseProxyState,
+    addr: SocketAddr,
+) -> Result<(SendRequest<Bytes>, InFlight, String), CbltError> {
+    loop {
+        let backend = state
+            .get_next_backend(addr)
+            .await
+            .map_err(|_| CbltError::ResponseError {
+                details: "No healthy backends".to_string(),
+                status_code: StatusCode::BAD_GATEWAY,
+            })?;
+        let backend_addr = backend_authority(backend.address())?;
+        // A connection that announced a client through the PROXY protocol is not shared
+        let shared = state.options.proxy_protocol.is_none();
+        if let Some(client) = state.pool.h2_client(&backend_addr).filter(|_| shared) {
+            if let Ok(client) = client.ready().await {
+                return Ok((client, state.in_flight(&backend), backend_addr));
+            }
+        }
+        let Ok(stream) = connect_backend(
+            backend.address(),
+            state.tls.as_ref(),
+            true,
+            addr,
+            &state.options,
+        )
+        .await
+        else {
+            state.record_failure(&backend).await?;
+            continue;
+        };
+        let (client, connection) = h2::client::handshake(stream).await?;
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                #[cfg(debug_assertions)]
+                error!("Error: {}", err);
+            }
+        });
+        state.set_alive_backend(&backend).await?;
+        if shared {
+            state.pool.set_h2_client(&backend_addr, client.clone());
+        }
+        return Ok((
+            client.ready().await?,
+            state.in_flight(&backend),
+            backend_addr,
+        ));
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn copy_request(mut recv: RecvStream, mut send: SendStream<Bytes>) -> Result<(), CbltError> {
+    let result = async {
+        while let Some(data) = recv.data().await {
+            let data = data?;
+            let len = data.len();
+            send_data(&mut send, data).await?;
+            // Window credit is given back once the backend took the data
+            let _ = recv.flow_control().release_capacity(len);
+        }
+        finish(&mut send, recv.trailers().await?)
+    }
+    .await;
+    if result.is_err() {
+        send.send_reset(Reason::CANCEL);
+    }
+    result
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn copy_response(
+    mut body: RecvStream,
+    mut send: SendStream<Bytes>,
+    sent: &mut u64,
+) -> Result<(), CbltError> {
+    let result = async {
+        while let Some(data) = body.data().await {
+            let data = data?;
+            let len = data.len();
+            send_data(&mut send, data).await?;
+            let _ = body.flow_control().release_capacity(len);
+            *sent += len as u64;
+        }
+        finish(&mut send, body.trailers().await?)
+    }
+    .await;
+    if result.is_err() {
+        send.send_reset(Reason::INTERNAL_ERROR);
+    }
+    result
+}
+
+fn finish(send: &mut SendStream<Bytes>, trailers: Option<HeaderMap>) -> Result<(), CbltError> {
+    match trailers {
+        Some(trailers) => send.send_trailers(trailers)?,
+        None => send.send_data(Bytes::new(), true)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grpc::is_grpc;
+    use crate::reverse_proxy::backend_authority;
+    use http::Request;
+    use std::error::Error;
+
+    #[test]
+    fn test_is_grpc() -> Result<(), Box<dyn Error>> {
+        let grpc = Request::builder()
+            .header("content-type", "application/grpc+proto")
+            .body(())?;
+        assert!(is_grpc(&grpc));
+        let json = Request::builder()
+            .header("content-type", "application/json")
+            .body(())?;
+        assert!(!is_grpc(&json));
+        assert_eq!(
+            backend_authority("http://10.0.0.1:50051")?,
+            "10.0.0.1:50051"
+        );
+        assert_eq!(backend_authority("http://backend")?, "backend:80");
+        assert_eq!(backend_authority("https://backend")?, "backend:443");
+        assert_eq!(backend_authority("http://[::1]:8080")?, "[::1]:8080");
+        assert_eq!(
+            backend_authority("unix//run/app.sock")?,
+            "unix//run/app.sock"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+use crate::access_log::RequestLog;
+use crate::config::{EncodeOptions, ErrorPage};
+use crate::directive::run_chain;
+use crate::error::CbltError;
+use crate::response::{
+    custom_error_response, send_response, with_headers, ExtraHeaders, ResponseFilter,
+};
+use crate::sendfile::Sendfile;
+use crate::server::{HostDetails, ServerSettings};
+use bytes::BytesMut;
+use http::header::HeaderName;
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock, RwLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Status the request was answered with
+pub type HandlerFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<StatusCode, CbltError>> + Send + 'a>>;
+
+/// Builds a handler from the arguments of its directive
+pub type HandlerFactory =
+    Arc<dyn Fn(&[String]) -> Result<Arc<dyn Handler>, CbltError> + Send + Sync>;
+
+static HANDLERS: LazyLock<RwLock<HashMap<String, HandlerFactory>>> =
+    LazyLock::new(Default::default); // directive name -> factory
+
+/// Request handling of a directive added outside of cblt
+///
+/// A handler answers the request itself or passes it to the directives after it with
+/// `next.run(ctx)`, doing its work around them like a middleware:
+///
+/// ```no_run
+/// use cblt::handler::{Context, Handler, HandlerFuture, Next};
+/// use http::{HeaderName, HeaderValue};
+///
+/// struct PoweredBy;
+///
+/// impl Handler for PoweredBy {
+///     fn handle<'a>(&'a self, ctx: &'a mut Context<'_>, next: Next) -> HandlerFuture<'a> {
+///         Box::pin(async move {
+///             ctx.set_header(
+///                 HeaderName::from_static("x-powered-by"),
+///                 HeaderValue::from_static("cblt"),
+///             );
+///             next.run(ctx).await
+///         })
+///     }
+/// }
+///
+/// cblt::handler::register("powered_by", |_args| Ok(std::sync::Arc::new(PoweredBy)));
+/// ```
+pub trait Handler: Send + Sync {
+    fn handle<'a>(&'a self, ctx: &'a mut Context<'_>, next: Next) -> HandlerFuture<'a>;
+}
+
+/// Makes `name` a directive of the Cbltfile, built-in directives keep their names
+pub fn register<F>(name: &str, factory: F)
+where
+    F: Fn(&[String]) -> Result<Arc<dyn Handler>, CbltError> + Send + Sync + 'static,
+{
+    if let Ok(mut handlers) = HANDLERS.write() {
+        handlers.insert(name.to_string(), Arc::new(factory));
+    }
+}
+
+/// Handler of a registered directive, None when nothing is registered under the name
+pub(crate) fn build(name: &str, args: &[String]) -> Option<Result<Arc<dyn Handler>, CbltError>> {
+    let factory = HANDLERS.read().ok()?.get(name)?.clone();
+    Some(factory(args))
+}
+
+/// Handler kept in its directive, shared by the copies of the configuration
+#[derive(Clone)]
+pub struct HandlerRef(pub Arc<dyn Handler>);
+
+impl fmt::Debug for HandlerRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HandlerRef")
+    }
+}
+
+/// Connection the response is written to
+pub(crate) trait Socket: AsyncRead + AsyncWrite + Sendfile + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Sendfile + Unpin + Send> Socket for S {}
+
+/// Request on its way through the directives of its host
+pub struct Context<'a> {
+    pub(crate) request: Request<BytesMut>,
+    pub(crate) socket: &'a mut dyn Socket,
+    pub(crate) buffer: &'a mut BytesMut, // bytes read past the request
+    pub(crate) addr: SocketAddr,
+    pub(crate) settings: &'a ServerSettings,
+    pub(crate) host: &'a HostDetails,
+    pub(crate) log: &'a mut RequestLog,
+    pub(crate) extra_headers: ExtraHeaders, // added to whatever response goes out
+    pub(crate) root: Option<&'a str>,       // set by `root`
+    pub(crate) encode: Option<&'a EncodeOptions>,
+    pub(crate) error_pages: Vec<(&'a [String], &'a ErrorPage)>,
+    pub(crate) keep_alive: bool,
+}
+
+impl Context<'_> {
+    pub fn request(&self) -> &Request<BytesMut> {
+        &self.request
+    }
+
+    /// The request the following directives see
+    pub fn request_mut(&mut self) -> &mut Request<BytesMut> {
+        &mut self.request
+    }
+
+    pub fn client_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// "http" or "https"
+    pub fn scheme(&self) -> &'static str {
+        self.settings.scheme()
+    }
+
+    /// Directory of the `root` matching the request so far
+    pub fn root(&self) -> Option<&str> {
+        self.root
+    }
+
+    /// Whether the request matches a directive pattern: a path, a regex or a named matcher
+    pub fn matches(&self, pattern: &str) -> bool {
+        self.host.matches(pattern, &self.request)
+    }
+
+    /// Header added to the response whichever directive sends it
+    pub fn set_header(&mut self, name: HeaderName, value: HeaderValue) {
+        self.extra_headers.set.insert(name, value);
+    }
+
+    /// Edits the headers of the response whichever directive sends it, after the `header`
+    /// directives
+    pub fn on_response<F>(&mut self, filter: F)
+    where
+        F: Fn(StatusCode, &mut HeaderMap) + Send + Sync + 'static,
+    {
+        let filter: ResponseFilter = Arc::new(filter);
+        self.extra_headers.filters.push(filter);
+    }
+
+    /// Sends the response, the request is answered
+    pub async fn respond(&mut self, response: Response<BytesMut>) -> Result<StatusCode, CbltError> {
+        let status = response.status();
+        let response = with_headers(response, &self.extra_headers);
+        send_response(&mut self.socket, response).await?;
+        Ok(status)
+    }
+
+    /// Sends the error page configured for the status
+    pub async fn respond_error(&mut self, status: StatusCode) -> Result<StatusCode, CbltError> {
+        let response = custom_error_response(status, &self.error_pages).await?;
+        self.respond(response).await
+    }
+}
+
+/// The directives after a handler
+pub struct Next {
+    pub(crate) from: usize, // index in the host's directives
+}
+
+impl Next {
+    /// Passes the request on, an error page answers when no directive does
+    pub fn run<'a>(self, ctx: &'a mut Context<'_>) -> HandlerFuture<'a> {
+        run_chain(ctx, self.from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::testing::{exchange, serve};
+    use crate::CbltServer;
+    use std::error::Error;
+
+    struct Teapot {
+        pattern: String,
+    }
+
+    impl Handler for Teapot {
+        fn handle<'a>(&'a self, ctx: &'a mut Context<'_>, next: Next) -> HandlerFuture<'a> {
+            Box::pin(async move {
+                if !ctx.matches(&self.pattern) {
+                    return next.run(ctx).await;
+                }
+                let response = Response::builder()
+                    .status(StatusCode::IM_A_TEAPOT)
+                    .body(BytesMut::from("short and stout"))?;
+                ctx.respond(response).await
+            })
+        }
+    }
+
+    struct Tag;
+
+    impl Handler for Tag {
+        fn handle<'a>(&'a self, ctx: &'a mut Context<'_>, next: Next) -> HandlerFuture<'a> {
+            Box::pin(async move {
+                ctx.set_header(
+                    HeaderName::from_static("x-tag"),
+                    HeaderValue::from_static("tagged"),
+                );
+                next.run(ctx).await
+            })
+        }
+    }
+
+    async fn get(port: u16, path: &str) -> Result<String, Box<dyn Error>> {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        exchange(port, &request).await
+    }
+
+    #[tokio::test]
+    async fn test_registered_handlers() -> Result<(), Box<dyn Error>> {
+        register("teapot", |args| match args {
+            [pattern] => Ok(Arc::new(Teapot {
+                pattern: pattern.clone(),
+            })),
+            _ => Err(CbltError::KdlParseError {
+                details: "teapot takes a pattern".to_string(),
+            }),
+        });
+        register("tag", |_| Ok(Arc::new(Tag)));
+
+        let (server, port) = serve(
+            "\"*:0\" {\n    tag\n    teapot \"/tea/*\"\n    redir \"https://example.com\"\n}",
+        )
+        .await?;
+
+        let tea = get(port, "/tea/earl-grey").await?;
+        assert!(tea.starts_with("HTTP/1.1 418"));
+        assert!(tea.contains("x-tag: tagged"));
+        assert!(tea.ends_with("short and stout"));
+        let other = get(port, "/coffee").await?;
+        assert!(other.starts_with("HTTP/1.1 302"));
+        assert!(other.contains("x-tag: tagged"));
+        server.stop();
+
+        assert!(CbltServer::new()
+            .cbltfile("\"*:80\" {\n    teapot\n}")
+            .is_err());
+        Ok(())
+    }
+}
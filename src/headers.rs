@@ -0,0 +1,162 @@
+use crate::config::HeaderOp;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Uri};
+use std::net::SocketAddr;
+
+/// Placeholder names with the values they stand for
+pub type Placeholders = Vec<(String, String)>;
+
+/// `{host}`, `{method}`, `{path}`, `{uri}` and `{remote_host}` of a request
+pub fn header_placeholders(
+    method: &Method,
+    uri: &Uri,
+    host: Option<&str>,
+    addr: SocketAddr,
+) -> Placeholders {
+    let path_and_query = uri.path_and_query().map_or("/", |pq| pq.as_str());
+    vec![
+        ("{host}".to_string(), host.unwrap_or_default().to_string()),
+        ("{method}".to_string(), method.to_string()),
+        ("{path}".to_string(), uri.path().to_string()),
+        ("{uri}".to_string(), path_and_query.to_string()),
+        ("{remote_host}".to_string(), addr.ip().to_string()),
+    ]
+}
+
+/// Value with the placeholders it holds filled in
+pub fn fill_value(value: &str, placeholders: &Placeholders) -> String {
+    placeholders
+        .iter()
+        .fold(value.to_string(), |value, (name, with)| {
+            value.replace(name.as_str(), with)
+        })
+}
+
+/// Operations with the placeholders of their values filled in
+pub fn fill_placeholders(operations: &[HeaderOp], placeholders: &Placeholders) -> Vec<HeaderOp> {
+    let fill_value = |value: &str| fill_value(value, placeholders);
+    operations
+        .iter()
+        .map(|operation| match operation {
+            HeaderOp::Set { name, value } => HeaderOp::Set {
+                name: name.clone(),
+                value: fill_value(value),
+            },
+            HeaderOp::Add { name, value } => HeaderOp::Add {
+                name: name.clone(),
+                value: fill_value(value),
+            },
+            HeaderOp::Remove { name } => HeaderOp::Remove { name: name.clone() },
+            HeaderOp::Replace {
+                name,
+                find,
+                replace,
+            } => HeaderOp::Replace {
+                name: name.clone(),
+                find: fill_value(find),
+                replace: fill_value(replace),
+            },
+        })
+        .collect()
+}
+
+/// Runs the operations in order, values that are not valid in a header are skipped
+pub fn apply_header_ops(headers: &mut HeaderMap, operations: &[HeaderOp]) {
+    for operation in operations {
+        match operation {
+            HeaderOp::Set { name, value } => {
+                if let (Ok(name), Ok(value)) = (header_name(name), HeaderValue::from_str(value)) {
+                    headers.insert(name, value);
+                }
+            }
+            HeaderOp::Add { name, value } => {
+                if let (Ok(name), Ok(value)) = (header_name(name), HeaderValue::from_str(value)) {
+                    headers.append(name, value);
+                }
+            }
+            HeaderOp::Remove { name } => {
+                headers.remove(name.as_str());
+            }
+            HeaderOp::Replace {
+                name,
+                find,
+                replace,
+            } => {
+                let Ok(name) = header_name(name) else {
+                    continue;
+                };
+                let replaced: Vec<HeaderValue> = headers
+                    .get_all(&name)
+                    .iter()
+                    .map(|value| match value.to_str() {
+                        Ok(text) if text.contains(find.as_str()) => {
+                            HeaderValue::from_str(&text.replace(find.as_str(), replace))
+                                .unwrap_or_else(|_| value.clone())
+                        }
+                        _ => value.clone(),
+                    })
+                    .collect();
+                headers.remove(&name);
+                for value in replaced {
+                    headers.append(name.clone(), value);
+                }
+            }
+        }
+    }
+}
+
+fn header_name(name: &str) -> Result<HeaderName, http::header::InvalidHeaderName> {
+    HeaderName::from_bytes(name.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::HeaderOp;
+    use crate::headers::{apply_header_ops, fill_placeholders, header_placeholders};
+    use http::{HeaderMap, HeaderValue, Method, Uri};
+    use std::error::Error;
+
+    #[test]
+    fn test_header_operations() -> Result<(), Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("backend/1.0"));
+        headers.insert("location", HeaderValue::from_static("http://example.com/a"));
+        headers.insert("link", HeaderValue::from_static("</a.css>; rel=preload"));
+
+        let uri: Uri = "/docs?page=2".parse()?;
+        let placeholders = header_placeholders(
+            &Method::GET,
+            &uri,
+            Some("example.com"),
+            "10.0.0.7:4000".parse()?,
+        );
+        let operations = fill_placeholders(
+            &[
+                HeaderOp::Remove {
+                    name: "server".to_string(),
+                },
+                HeaderOp::Set {
+                    name: "x-request".to_string(),
+                    value: "{method} {uri} from {remote_host}".to_string(),
+                },
+                HeaderOp::Add {
+                    name: "link".to_string(),
+                    value: "</b.js>; rel=preload".to_string(),
+                },
+                HeaderOp::Replace {
+                    name: "location".to_string(),
+                    find: "http://{host}".to_string(),
+                    replace: "https://{host}".to_string(),
+                },
+            ],
+            &placeholders,
+        );
+        apply_header_ops(&mut headers, &operations);
+
+        assert!(!headers.contains_key("server"));
+        assert_eq!(headers["x-request"], "GET /docs?page=2 from 10.0.0.7");
+        assert_eq!(headers.get_all("link").iter().count(), 2);
+        assert_eq!(headers["location"], "https://example.com/a");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use crate::cgi::{self, backend_protocol};
+use crate::config::{ProxyProtocolVersion, ReverseProxyOptions};
+use crate::error::CbltError;
+use crate::request::BUF_SIZE;
+use crate::reverse_proxy::{
+    backend_authority, current_timestamp_seconds, get_header_len, open_backend,
+    parse_response_head, unix_socket_path, AliveState, Backend, Backends,
+};
+use crate::tls::UpstreamTls;
+use bytes::BytesMut;
+use http::header::{HOST, USER_AGENT};
+use http::{Request, StatusCode};
+use log::{info, warn};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::timeout;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Probes the backends every `health_interval` when a `health_uri` is set
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn start(
+    backends: &Backends,
+    tls: Option<UpstreamTls>,
+    options: &ReverseProxyOptions,
+) -> Option<JoinHandle<()>> {
+    let uri = options.health_uri.clone()?;
+    let backends = backends.clone();
+    let interval = Duration::from_secs(options.health_interval);
+    let probe_timeout = Duration::from_secs(options.health_timeout);
+    // Probes are not on behalf of a client
+    let proxy_protocol = options.proxy_protocol.map(|version| (version, None));
+    let expected = options.health_status;
+    Some(tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let mut checks = JoinSet::new();
+            // Backends discovery added are probed from the next round on
+            for backend in backends.get().iter().cloned() {
+                let uri = uri.clone();
+                let tls = tls.clone();
+                checks.spawn(async move {
+                    let healthy = matches!(
+                        timeout(
+                            probe_timeout,
+                            probe(&backend.url, &uri, tls.as_ref(), proxy_protocol)
+                        )
+                        .await,
+                        Ok(Ok(status)) if is_expected(status, expected)
+                    );
+                    update(&backend, healthy).await;
+                });
+            }
+            while checks.join_next().await.is_some() {}
+        }
+    }))
+}
+
+/// Status of a `GET` of the health URI on a fresh connection
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn probe(
+    url: &str,
+    uri: &str,
+    tls: Option<&UpstreamTls>,
+    proxy_protocol: Option<(ProxyProtocolVersion, Option<SocketAddr>)>,
+) -> Result<StatusCode, CbltError> {
+    let authority = backend_authority(url)?;
+    let tls = tls.filter(|_| url.starts_with("https://"));
+    let mut stream = open_backend(&authority, tls, false, proxy_protocol).await?;
+    let host = match unix_socket_path(&authority) {
+        Some(_) => "localhost",
+        None => &authority,
+    };
+    let mut buf = BytesMut::with_capacity(BUF_SIZE);
+    if let Some((protocol, _)) = backend_protocol(url) {
+        let request = Request::get(uri)
+            .header(HOST, host)
+            .header(USER_AGENT, "cblt-health-check")
+            .body(BytesMut::new())?;
+        let client = SocketAddr::from(([127, 0, 0, 1], 0));
+        let request = cgi::encode_request(protocol, &request, request.headers(), client, "http")?;
+        stream.write_all(&request).await?;
+        let (status, _, _) = cgi::response_head(&mut stream, &mut buf).await?;
+        return Ok(status);
+    }
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: cblt-health-check\r\nConnection: close\r\n\r\n",
+        uri, host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let header_len = get_header_len(&mut stream, &mut buf).await?;
+    let (status, _, _) = parse_response_head(&buf[..header_len])?;
+    Ok(status)
+}
+
+fn is_expected(status: StatusCode, expected: Option<u16>) -> bool {
+    match expected {
+        Some(expected) => status.as_u16() == expected,
+        None => status.is_success(),
+    }
+}
+
+/// Takes a failing backend out of rotation and brings a passing one back
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn update(backend: &Backend, healthy: bool) {
+    let mut alive_state = backend.alive_state.write().await;
+    match (&*alive_state, healthy) {
+        (AliveState::Alive(_), true) => {}
+        (AliveState::Unhealthy(_), false) => {}
+        (_, true) => {
+            info!("Backend {} passed its health check", backend.url);
+            backend.reset_failures();
+            *alive_state = AliveState::Alive(current_timestamp_seconds());
+        }
+        (_, false) => {
+            warn!("Backend {} failed its health check", backend.url);
+            *alive_state = AliveState::Unhealthy(current_timestamp_seconds());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::ReverseProxyOptions;
+    use crate::health::{is_expected, probe, start};
+    use crate::reverse_proxy::{AliveState, Backend, Backends};
+    use http::StatusCode;
+    use std::error::Error;
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_health_check() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let status = Arc::new(AtomicU16::new(503));
+        let served = status.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n",
+                    served.load(Ordering::SeqCst)
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        assert_eq!(
+            probe(&url, "/health", None, None).await?,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert!(is_expected(StatusCode::NO_CONTENT, None));
+        assert!(!is_expected(StatusCode::NO_CONTENT, Some(200)));
+
+        let backend = Backend::new(url);
+        let options = ReverseProxyOptions {
+            health_uri: Some("/health".to_string()),
+            health_interval: 1,
+            ..Default::default()
+        };
+        let task = start(&Backends::new(vec![backend.clone()]), None, &options)
+            .ok_or("health check not started")?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(matches!(
+            *backend.alive_state.read().await,
+            AliveState::Unhealthy(_)
+        ));
+        status.store(200, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert!(matches!(
+            *backend.alive_state.read().await,
+            AliveState::Alive(_)
+        ));
+        task.abort();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,552 @@
+use crate::body::{BodyKind, BodyReader};
+use crate::error::CbltError;
+use crate::grpc;
+use crate::request::{BUF_SIZE, HEADER_BUF_SIZE};
+use crate::response::{error_response, send_response};
+use crate::reverse_proxy::{parse_response_head, remove_hop_by_hop_headers};
+use crate::sendfile::Sendfile;
+use crate::server::{serve_connection, SettingsLock};
+use bytes::{Bytes, BytesMut};
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use http::header::{CONNECTION, CONTENT_LENGTH, HOST};
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+use log::error;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, timeout_at, Instant};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+pub const H2_ALPN: &[u8] = b"h2";
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+const MAX_FRAME_SIZE: usize = 16_384;
+const SWITCHING_PROTOCOLS: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+/// How a cleartext connection starts
+#[derive(Debug, PartialEq)]
+enum Cleartext {
+    Partial, // more bytes are needed to tell
+    Http1,
+    PriorKnowledge,
+    Upgrade {
+        header_len: usize,
+        frame: Vec<u8>, // HEADERS frame carrying the upgraded request as stream 1
+    },
+}
+
+/// Serves a connection without TLS as HTTP/1.1 or as h2c, with prior knowledge or `Upgrade: h2c`
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn serve_cleartext<S>(
+    mut stream: S,
+    settings_lock: Arc<SettingsLock>,
+    addr: SocketAddr,
+) -> Result<(), CbltError>
+where
+    S: AsyncRead + AsyncWrite + Sendfile + Unpin + Send,
+{
+    let limits = settings_lock.get().await.request_limits.clone();
+    let mut buffer = BytesMut::with_capacity(BUF_SIZE);
+    let mut head_deadline = None; // set by the first byte of the request
+    loop {
+        let read = match head_deadline {
+            Some(deadline) => match timeout_at(deadline, stream.read_buf(&mut buffer)).await {
+                Ok(read) => read,
+                Err(_) => return reject(&mut stream, StatusCode::REQUEST_TIMEOUT).await,
+            },
+            None => match timeout(limits.keep_alive_timeout, stream.read_buf(&mut buffer)).await {
+                Ok(read) => read,
+                Err(_) => return Ok(()),
+            },
+        };
+        match read {
+            Ok(bytes_read) if bytes_read > 0 => {}
+            _ => return Ok(()),
+        }
+        head_deadline.get_or_insert(Instant::now() + limits.header_timeout);
+        if buffer.len() > limits.max_header_size {
+            break; // answered with a 431 by the HTTP/1.1 side
+        }
+        match detect_cleartext(&buffer) {
+            Cleartext::Partial => continue,
+            Cleartext::Http1 => break,
+            Cleartext::PriorKnowledge => {
+                return serve_h2(
+                    PrefixedStream::new(buffer.freeze(), stream),
+                    settings_lock,
+                    addr,
+                )
+                .await;
+            }
+            Cleartext::Upgrade { header_len, frame } => {
+                let _ = buffer.split_to(header_len);
+                stream.write_all(SWITCHING_PROTOCOLS).await?;
+                stream.flush().await?;
+                // The client preface comes first, its SETTINGS frame must precede any stream
+                let settings_end = loop {
+                    if buffer.len() >= PREFACE.len() + FRAME_HEADER_LEN {
+                        let header = &buffer[PREFACE.len()..];
+                        if !buffer.starts_with(PREFACE) || header[3] != 0x4 {
+                            return Err(CbltError::RequestError {
+                                details: "Invalid HTTP/2 preface".to_string(),
+                                status_code: StatusCode::BAD_REQUEST,
+                            });
+                        }
+                        let len = u32::from_be_bytes([0, header[0], header[1], header[2]]);
+                        let end = PREFACE.len() + FRAME_HEADER_LEN + len as usize;
+                        if buffer.len() >= end {
+                            break end;
+                        }
+                    }
+                    if stream.read_buf(&mut buffer).await? == 0 {
+                        return Ok(());
+                    }
+                };
+                let rest = buffer.split_off(settings_end);
+                buffer.extend_from_slice(&frame);
+                buffer.extend_from_slice(&rest);
+                return serve_h2(
+                    PrefixedStream::new(buffer.freeze(), stream),
+                    settings_lock,
+                    addr,
+                )
+                .await;
+            }
+        }
+    }
+    serve_connection(
+        &mut PrefixedStream::new(buffer.freeze(), stream),
+        settings_lock,
+        addr,
+    )
+    .await
+}
+
+/// Answers a client over the listener's `request_limits` and closes the connection
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn reject<S>(stream: &mut S, status: StatusCode) -> Result<(), CbltError>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut response = error_response(status)?;
+    response
+        .headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("close"));
+    send_response(stream, response).await?;
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn detect_cleartext(buffer: &[u8]) -> Cleartext {
+    if buffer.starts_with(PREFACE) {
+        return Cleartext::PriorKnowledge;
+    }
+    if PREFACE.starts_with(buffer) {
+        return Cleartext::Partial;
+    }
+    let mut headers = [httparse::EMPTY_HEADER; HEADER_BUF_SIZE];
+    let mut req = httparse::Request::new(&mut headers);
+    let header_len = match req.parse(buffer) {
+        Ok(httparse::Status::Complete(header_len)) => header_len,
+        Ok(httparse::Status::Partial) => return Cleartext::Partial,
+        Err(_) => return Cleartext::Http1,
+    };
+    let header = |name: &'static str| {
+        req.headers
+            .iter()
+            .filter(move |h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    };
+    let has_token = |name: &'static str, token: &str| {
+        header(name).any(|value| {
+            String::from_utf8_lossy(value)
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    };
+    let body = header("transfer-encoding").next().is_some()
+        || header("content-length").any(|value| value.trim_ascii() != b"0");
+    // Requests with a body stay on HTTP/1.1, a server may ignore the upgrade
+    if req.version != Some(1)
+        || body
+        || !has_token("upgrade", "h2c")
+        || !has_token("connection", "upgrade")
+        || header("http2-settings").count() != 1
+    {
+        return Cleartext::Http1;
+    }
+    let (Some(method), Some(path)) = (req.method, req.path) else {
+        return Cleartext::Http1;
+    };
+
+    let mut block = Vec::new();
+    hpack_literal(&mut block, b":method", method.as_bytes());
+    hpack_literal(&mut block, b":scheme", b"http");
+    hpack_literal(&mut block, b":path", path.as_bytes());
+    if let Some(host) = header("host").next() {
+        hpack_literal(&mut block, b":authority", host);
+    }
+    let skip = [
+        "connection",
+        "host",
+        "http2-settings",
+        "keep-alive",
+        "proxy-connection",
+        "te",
+        "upgrade",
+    ];
+    for h in req.headers.iter() {
+        let name = h.name.to_ascii_lowercase();
+        if !skip.contains(&name.as_str()) {
+            hpack_literal(&mut block, name.as_bytes(), h.value);
+        }
+    }
+    if block.len() > MAX_FRAME_SIZE {
+        return Cleartext::Http1;
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + block.len());
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+    frame.extend_from_slice(&[0x1, 0x5]); // HEADERS, END_STREAM | END_HEADERS
+    frame.extend_from_slice(&1u32.to_be_bytes());
+    frame.extend_from_slice(&block);
+    Cleartext::Upgrade { header_len, frame }
+}
+
+/// Literal header field without indexing, so the HPACK table of the client is not touched
+fn hpack_literal(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0);
+    for string in [name, value] {
+        let mut len = string.len();
+        if len < 0x7f {
+            block.push(len as u8);
+        } else {
+            block.push(0x7f);
+            len -= 0x7f;
+            while len >= 0x80 {
+                block.push((len % 0x80 + 0x80) as u8);
+                len /= 0x80;
+            }
+            block.push(len as u8);
+        }
+        block.extend_from_slice(string);
+    }
+}
+
+/// Replays bytes that were read ahead before reading from the stream
+pub struct PrefixedStream<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Bytes, inner: S) -> Self {
+        PrefixedStream { prefix, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let len = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix.split_to(len));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: Sendfile> Sendfile for PrefixedStream<S> {
+    fn tcp_socket(&mut self) -> Option<&TcpStream> {
+        self.inner.tcp_socket()
+    }
+
+    fn sent(&mut self, bytes: u64) {
+        self.inner.sent(bytes);
+    }
+}
+
+/// Serves an HTTP/2 connection, each stream in its own task
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn serve_h2<S>(
+    stream: S,
+    settings_lock: Arc<SettingsLock>,
+    addr: SocketAddr,
+) -> Result<(), CbltError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connection = h2::server::handshake(stream).await?;
+    while let Some(accepted) = connection.accept().await {
+        let (request, respond) = accepted?;
+        let settings_lock = settings_lock.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_stream(request, respond, settings_lock, addr).await {
+                #[cfg(debug_assertions)]
+                error!("Error: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Runs a stream through the HTTP/1.1 pipeline over an in-memory pipe
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn serve_stream(
+    request: Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    settings_lock: Arc<SettingsLock>,
+    addr: SocketAddr,
+) -> Result<(), CbltError> {
+    if grpc::is_grpc(&request) {
+        let settings = settings_lock.get().await;
+        if let Some(route) = grpc::route(&settings, &request) {
+            return grpc::proxy(request, respond, route, addr, settings.scheme()).await;
+        }
+    }
+    let max_body_size = settings_lock.get().await.request_limits.max_body_size;
+    let (parts, mut recv) = request.into_parts();
+    let mut body = BytesMut::new();
+    while let Some(data) = recv.data().await {
+        let data = data?;
+        let _ = recv.flow_control().release_capacity(data.len());
+        if body.len() + data.len() > max_body_size {
+            let mut response = Response::new(());
+            *response.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+            respond.send_response(response, true)?;
+            return Ok(());
+        }
+        body.extend_from_slice(&data);
+    }
+    let request = Request::from_parts(parts, body);
+    let method = request.method().clone();
+
+    let (client, mut server) = tokio::io::duplex(BUF_SIZE);
+    let forward = async move {
+        let mut client = client;
+        client.write_all(&request_head(&request)).await?;
+        client.write_all(request.body()).await?;
+
+        let mut buf = BytesMut::with_capacity(BUF_SIZE);
+        let (status, mut headers) = read_response_head(&mut client, &mut buf).await?;
+        let kind = BodyKind::of_response(&method, status, &headers);
+        remove_hop_by_hop_headers(&mut headers);
+        let mut response = Response::new(());
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+
+        let mut send = respond.send_response(response, kind == BodyKind::Empty)?;
+        if kind == BodyKind::Empty {
+            return Ok(());
+        }
+        let mut reader = BodyReader::new(&mut client, buf, kind);
+        while let Some(chunk) = reader.next_chunk().await? {
+            send_data(&mut send, chunk).await?;
+        }
+        if reader.trailers().is_empty() {
+            send.send_data(Bytes::new(), true)?;
+        } else {
+            send.send_trailers(reader.trailers().clone())?;
+        }
+        Ok::<(), CbltError>(())
+    };
+    // Dropping the client end when forwarding fails unblocks the pipeline
+    let (served, forwarded) =
+        tokio::join!(serve_connection(&mut server, settings_lock, addr), forward);
+    served.and(forwarded)
+}
+
+/// HTTP/1.1 request head for a stream, asking the pipeline to close after the response
+fn request_head(request: &Request<BytesMut>) -> Vec<u8> {
+    let mut headers = request.headers().clone();
+    remove_hop_by_hop_headers(&mut headers);
+    if !headers.contains_key(HOST) {
+        if let Some(authority) = request.uri().authority() {
+            if let Ok(value) = HeaderValue::from_str(authority.as_str()) {
+                headers.insert(HOST, value);
+            }
+        }
+    }
+    headers.remove(CONTENT_LENGTH);
+    if !request.body().is_empty() {
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(request.body().len()));
+    }
+    headers.insert(CONNECTION, HeaderValue::from_static("close"));
+
+    let mut head = Vec::new();
+    head.extend_from_slice(request.method().as_str().as_bytes());
+    head.extend_from_slice(b" ");
+    head.extend_from_slice(
+        request
+            .uri()
+            .path_and_query()
+            .map(|pq| pq.as_str())
+            .unwrap_or("/")
+            .as_bytes(),
+    );
+    head.extend_from_slice(b" HTTP/1.1\r\n");
+    for (key, value) in headers.iter() {
+        head.extend_from_slice(key.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Final response head, skipping interim 1xx responses
+async fn read_response_head<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+) -> Result<(StatusCode, HeaderMap), CbltError>
+where
+    S: AsyncReadExt + Unpin,
+{
+    loop {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let parsed = httparse::Response::new(&mut headers).parse(buf);
+        if let Ok(httparse::Status::Complete(header_len)) = parsed {
+            let head = buf.split_to(header_len);
+            let (status, headers, _) = parse_response_head(&head)?;
+            if status.is_informational() {
+                continue;
+            }
+            return Ok((status, headers));
+        }
+        if parsed.is_err() || stream.read_buf(buf).await? == 0 {
+            return Err(CbltError::ResponseError {
+                details: "Invalid response for HTTP/2 stream".to_string(),
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            });
+        }
+    }
+}
+
+/// Sends data as the peer's flow control window allows
+pub async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes) -> Result<(), CbltError> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let capacity = match std::future::poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(capacity) => capacity?,
+            None => {
+                return Err(CbltError::ResponseError {
+                    details: "HTTP/2 stream closed".to_string(),
+                    status_code: StatusCode::BAD_REQUEST,
+                })
+            }
+        };
+        if capacity == 0 {
+            continue;
+        }
+        send.send_data(data.split_to(capacity.min(data.len())), false)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http2::{
+        detect_cleartext, hpack_literal, read_response_head, request_head, Cleartext, PREFACE,
+    };
+    use bytes::BytesMut;
+    use http::{Request, StatusCode};
+    use std::error::Error;
+
+    #[test]
+    fn test_request_head() -> Result<(), Box<dyn Error>> {
+        let request = Request::builder()
+            .method("POST")
+            .uri("https://example.com/upload?x=1")
+            .header("content-type", "text/plain")
+            .body(BytesMut::from("hello"))?;
+        let head = String::from_utf8(request_head(&request))?;
+        assert!(head.starts_with("POST /upload?x=1 HTTP/1.1\r\n"));
+        assert!(head.contains("host: example.com\r\n"));
+        assert!(head.contains("content-length: 5\r\n"));
+        assert!(head.contains("connection: close\r\n"));
+        assert!(head.ends_with("\r\n\r\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_response_head() -> Result<(), Box<dyn Error>> {
+        let mut stream: &[u8] =
+            b"ontinue\r\n\r\nHTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok";
+        let mut buf = BytesMut::from(&b"HTTP/1.1 100 C"[..]);
+        let (status, headers) = read_response_head(&mut stream, &mut buf).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get("content-length").unwrap(), "2");
+        assert_eq!(&buf[..], b"ok");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_detect_cleartext() {
+        assert_eq!(detect_cleartext(&PREFACE[..10]), Cleartext::Partial);
+        assert_eq!(detect_cleartext(PREFACE), Cleartext::PriorKnowledge);
+        assert_eq!(
+            detect_cleartext(b"GET / HTTP/1.1\r\nHost: a"),
+            Cleartext::Partial
+        );
+        assert_eq!(
+            detect_cleartext(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"),
+            Cleartext::Http1
+        );
+        let upgrade = b"GET /x HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+            Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n\r\n";
+        let Cleartext::Upgrade { header_len, frame } = detect_cleartext(upgrade) else {
+            panic!("upgrade expected");
+        };
+        assert_eq!(header_len, upgrade.len());
+        let mut block = Vec::new();
+        hpack_literal(&mut block, b":method", b"GET");
+        hpack_literal(&mut block, b":scheme", b"http");
+        hpack_literal(&mut block, b":path", b"/x");
+        hpack_literal(&mut block, b":authority", b"a");
+        assert_eq!(&frame[..9], &[0, 0, block.len() as u8, 1, 5, 0, 0, 0, 1]);
+        assert_eq!(&frame[9..], &block[..]);
+
+        let with_body = b"POST / HTTP/1.1\r\nHost: a\r\nConnection: Upgrade, HTTP2-Settings\r\n\
+            Upgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\nContent-Length: 1\r\n\r\nx";
+        assert_eq!(detect_cleartext(with_body), Cleartext::Http1);
+    }
+
+    #[test]
+    fn test_hpack_literal() {
+        let mut block = Vec::new();
+        hpack_literal(&mut block, b"a", &[b'x'; 200]);
+        assert_eq!(&block[..4], &[0, 1, b'a', 0x7f]);
+        assert_eq!(&block[4..6], &[200 - 0x7f, b'x']);
+    }
+}
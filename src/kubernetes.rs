@@ -0,0 +1,558 @@
+use crate::config::{Directive, DnsDiscoveryOptions, ReverseProxyOptions};
+use crate::error::CbltError;
+use crate::Args;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::Bytes;
+use http::{header, Request};
+use http_body_util::{BodyExt, Full};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+#[cfg(debug_assertions)]
+use log::warn;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::{ClientConfig, RootCertStore};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+const API_TIMEOUT: Duration = Duration::from_secs(10);
+const CLASS_ANNOTATION: &str = "kubernetes.io/ingress.class"; // before `ingressClassName`
+const DEFAULT_CLASS_ANNOTATION: &str = "ingressclass.kubernetes.io/is-default-class";
+
+#[derive(Deserialize)]
+struct List<T> {
+    items: Vec<T>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Metadata {
+    name: String,
+    namespace: String,
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct IngressClass {
+    metadata: Metadata,
+}
+
+#[derive(Deserialize)]
+struct Ingress {
+    metadata: Metadata,
+    spec: IngressSpec,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct IngressSpec {
+    ingress_class_name: Option<String>,
+    default_backend: Option<IngressBackend>,
+    tls: Vec<IngressTls>,
+    rules: Vec<IngressRule>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct IngressTls {
+    hosts: Vec<String>,
+    secret_name: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct IngressRule {
+    host: Option<String>, // every host when unset
+    http: Option<HttpRule>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct HttpRule {
+    paths: Vec<HttpPath>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HttpPath {
+    path: Option<String>,
+    path_type: String, // Exact, Prefix or ImplementationSpecific
+    backend: IngressBackend,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct IngressBackend {
+    service: Option<ServiceBackend>, // `resource` backends are not supported
+}
+
+#[derive(Deserialize)]
+struct ServiceBackend {
+    name: String,
+    port: ServiceBackendPort,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ServiceBackendPort {
+    number: Option<u16>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Service {
+    metadata: Metadata,
+    #[serde(default)]
+    spec: ServiceSpec,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ServiceSpec {
+    ports: Vec<ServicePort>,
+}
+
+#[derive(Deserialize)]
+struct ServicePort {
+    name: Option<String>,
+    port: u16,
+}
+
+#[derive(Deserialize)]
+struct Secret {
+    #[serde(default)]
+    data: HashMap<String, String>, // base64
+}
+
+/// Kubernetes API server, with the credentials of the pod's service account when there are any
+struct ApiServer {
+    url: String,
+    token: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+}
+
+impl ApiServer {
+    /// `url`, or the API server of the cluster cblt runs in
+    fn new(url: Option<&str>) -> Result<Self, CbltError> {
+        let account = Path::new(SERVICE_ACCOUNT);
+        let url = match url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => {
+                let (Ok(host), Ok(port)) = (
+                    std::env::var("KUBERNETES_SERVICE_HOST"),
+                    std::env::var("KUBERNETES_SERVICE_PORT"),
+                ) else {
+                    return Err(CbltError::KubernetesError {
+                        details: "Not running in a cluster, the API server is set by --kube-api"
+                            .to_string(),
+                    });
+                };
+                if host.contains(':') {
+                    format!("https://[{}]:{}", host, port)
+                } else {
+                    format!("https://{}:{}", host, port)
+                }
+            }
+        };
+        let mut roots = RootCertStore::empty();
+        let ca = account.join("ca.crt");
+        if ca.is_file() {
+            for cert in CertificateDer::pem_file_iter(&ca)? {
+                roots.add(cert?)?;
+            }
+        } else {
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(config)
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(ApiServer {
+            url,
+            // Projected tokens are rotated, so it is read again on every load
+            token: std::fs::read_to_string(account.join("token"))
+                .ok()
+                .map(|token| token.trim().to_string()),
+            client: Client::builder(TokioExecutor::new()).build(connector),
+        })
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, CbltError> {
+        let mut request = Request::get(format!("{}{}", self.url, path))
+            .header(header::ACCEPT, "application/json");
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let exchange = async {
+            let response = self
+                .client
+                .request(request.body(Full::new(Bytes::new()))?)
+                .await?;
+            let status = response.status();
+            let body = response.into_body().collect().await?.to_bytes();
+            Ok::<_, CbltError>((status, body))
+        };
+        let (status, body) =
+            tokio::time::timeout(API_TIMEOUT, exchange)
+                .await
+                .map_err(|_| CbltError::KubernetesError {
+                    details: format!("No answer from {} for {}", self.url, path),
+                })??;
+        if !status.is_success() {
+            return Err(CbltError::KubernetesError {
+                details: format!(
+                    "{} returned {}: {}",
+                    path,
+                    status,
+                    String::from_utf8_lossy(&body)
+                ),
+            });
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// Hosts and directives for the Ingresses of `--ingress-class`, what a Cbltfile holds in the
+/// config mode
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn load_config(args: &Args) -> Result<HashMap<String, Vec<Directive>>, CbltError> {
+    let api = ApiServer::new(args.kube_api.as_deref())?;
+    let classes: List<IngressClass> = api.get("/apis/networking.k8s.io/v1/ingressclasses").await?;
+    // Ingresses without a class go to the default one
+    let default_class = classes.items.iter().any(|class| {
+        class.metadata.name == args.ingress_class
+            && class
+                .metadata
+                .annotations
+                .get(DEFAULT_CLASS_ANNOTATION)
+                .is_some_and(|default| default == "true")
+    });
+    let ingresses: List<Ingress> = api.get("/apis/networking.k8s.io/v1/ingresses").await?;
+    let ingresses: Vec<Ingress> = ingresses
+        .items
+        .into_iter()
+        .filter(|ingress| {
+            let class = ingress
+                .spec
+                .ingress_class_name
+                .as_ref()
+                .or(ingress.metadata.annotations.get(CLASS_ANNOTATION));
+            match class {
+                Some(class) => *class == args.ingress_class,
+                None => default_class,
+            }
+        })
+        .collect();
+    let services: List<Service> = api.get("/api/v1/services").await?;
+
+    let mut tls_files = HashMap::new();
+    let storage = std::env::temp_dir().join("cblt-ingress");
+    for ingress in &ingresses {
+        let namespace = &ingress.metadata.namespace;
+        for secret_name in ingress.spec.tls.iter().flat_map(|tls| &tls.secret_name) {
+            let key = (namespace.clone(), secret_name.clone());
+            if tls_files.contains_key(&key) {
+                continue;
+            }
+            let path = format!("/api/v1/namespaces/{}/secrets/{}", namespace, secret_name);
+            match api.get::<Secret>(&path).await {
+                Ok(secret) => {
+                    let name = format!("{}_{}", namespace, secret_name);
+                    tls_files.insert(key, store_certificate(&storage, &name, &secret)?);
+                }
+                // Served over HTTP only until the secret is there
+                Err(err) => {
+                    #[cfg(debug_assertions)]
+                    warn!("TLS secret {}/{}: {}", namespace, secret_name, err);
+                }
+            }
+        }
+    }
+    Ok(ingress_config(&ingresses, &services.items, &tls_files))
+}
+
+/// Certificate and key of a `kubernetes.io/tls` secret as PEM files, rewritten only when changed
+/// so that the listeners reload them just then
+fn store_certificate(
+    storage: &Path,
+    name: &str,
+    secret: &Secret,
+) -> Result<(String, String), CbltError> {
+    std::fs::create_dir_all(storage)?;
+    let mut files = Vec::new();
+    for (field, extension) in [("tls.crt", "crt"), ("tls.key", "key")] {
+        let data = secret.data.get(field).ok_or(CbltError::KubernetesError {
+            details: format!("Secret {} has no {}", name, field),
+        })?;
+        let contents = BASE64_STANDARD
+            .decode(data)
+            .map_err(|err| CbltError::KubernetesError {
+                details: format!("Secret {}: {}", name, err),
+            })?;
+        let path: PathBuf = storage.join(format!("{}.{}", name, extension));
+        if std::fs::read(&path).ok().as_ref() != Some(&contents) {
+            std::fs::write(&path, &contents)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        files.push(path.to_string_lossy().to_string());
+    }
+    Ok((files[0].clone(), files[1].clone()))
+}
+
+/// One `reverse_proxy` per Ingress path, Exact paths and longer prefixes first as Kubernetes
+/// has them win. Hosts with a TLS secret are also served on 443.
+fn ingress_config(
+    ingresses: &[Ingress],
+    services: &[Service],
+    tls_files: &HashMap<(String, String), (String, String)>,
+) -> HashMap<String, Vec<Directive>> {
+    // host -> (exact, path, upstream) in the order they are tried
+    let mut routes: BTreeMap<String, Vec<(bool, String, String)>> = BTreeMap::new();
+    let mut certificates: BTreeMap<String, (String, String)> = BTreeMap::new();
+    let mut fallbacks: Vec<String> = Vec::new(); // default backends, for requests no rule takes
+    let mut ingresses: Vec<&Ingress> = ingresses.iter().collect();
+    ingresses.sort_by_key(|ingress| (&ingress.metadata.namespace, &ingress.metadata.name));
+    for ingress in ingresses {
+        let namespace = &ingress.metadata.namespace;
+        let upstream = |backend: &IngressBackend| {
+            let upstream = backend
+                .service
+                .as_ref()
+                .and_then(|service| service_url(namespace, service, services));
+            #[cfg(debug_assertions)]
+            if upstream.is_none() {
+                warn!(
+                    "Ingress {}/{}: backend without a known service port",
+                    namespace, ingress.metadata.name
+                );
+            }
+            upstream
+        };
+        if let Some(url) = ingress.spec.default_backend.as_ref().and_then(upstream) {
+            fallbacks.push(url);
+        }
+        for tls in &ingress.spec.tls {
+            let Some(files) = tls
+                .secret_name
+                .as_ref()
+                .and_then(|secret| tls_files.get(&(namespace.clone(), secret.clone())))
+            else {
+                continue;
+            };
+            for host in &tls.hosts {
+                certificates
+                    .entry(host.clone())
+                    .or_insert_with(|| files.clone());
+            }
+        }
+        for rule in &ingress.spec.rules {
+            let host = rule.host.clone().unwrap_or_else(|| "*".to_string());
+            for path in rule.http.iter().flat_map(|http| &http.paths) {
+                let Some(url) = upstream(&path.backend) else {
+                    continue;
+                };
+                let exact = path.path_type == "Exact";
+                let prefix = path.path.clone().unwrap_or_else(|| "/".to_string());
+                routes
+                    .entry(host.clone())
+                    .or_default()
+                    .push((exact, prefix, url));
+            }
+        }
+    }
+    if let Some(url) = fallbacks.first() {
+        routes
+            .entry("*".to_string())
+            .or_default()
+            .push((false, "/".to_string(), url.clone()));
+    }
+
+    let mut config = HashMap::new();
+    for (host, mut host_routes) in routes {
+        // Stable, so that of two Ingresses with the same path the first one by name wins
+        host_routes
+            .sort_by_key(|(exact, path, _)| (Reverse(path.trim_end_matches('/').len()), !*exact));
+        let directives: Vec<Directive> = host_routes
+            .into_iter()
+            .map(|(exact, path, url)| Directive::ReverseProxy {
+                pattern: path_pattern(exact, &path),
+                destinations: vec![url],
+                options: ReverseProxyOptions {
+                    dns_discovery: Some(DnsDiscoveryOptions::default()),
+                    ..Default::default()
+                },
+            })
+            .collect();
+        if let Some((cert, key)) = certificates.get(&host) {
+            let mut secure = directives.clone();
+            secure.push(Directive::TlS {
+                cert: cert.clone(),
+                key: key.clone(),
+            });
+            config.insert(host.clone(), secure);
+            config.insert(format!("{}:80", host), directives);
+        } else {
+            config.insert(host, directives);
+        }
+    }
+    config
+}
+
+/// Service DNS name and port of an Ingress backend, named ports looked up in the Service
+fn service_url(namespace: &str, backend: &ServiceBackend, services: &[Service]) -> Option<String> {
+    let port = match (&backend.port.number, &backend.port.name) {
+        (Some(number), _) => *number,
+        (None, Some(name)) => {
+            services
+                .iter()
+                .find(|service| {
+                    service.metadata.namespace == namespace && service.metadata.name == backend.name
+                })?
+                .spec
+                .ports
+                .iter()
+                .find(|port| port.name.as_ref() == Some(name))?
+                .port
+        }
+        (None, None) => return None,
+    };
+    Some(format!(
+        "http://{}.{}.svc:{}",
+        backend.name, namespace, port
+    ))
+}
+
+/// Path pattern for an Ingress path; a prefix matches whole segments, `/foo` takes `/foo/bar`
+/// but not `/foobar`
+fn path_pattern(exact: bool, path: &str) -> String {
+    let path = path.trim_end_matches('/');
+    if exact {
+        format!(
+            "^{}$",
+            regex::escape(if path.is_empty() { "/" } else { path })
+        )
+    } else if path.is_empty() {
+        "/*".to_string()
+    } else {
+        format!("^{}(/|$)", regex::escape(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Directive;
+    use crate::kubernetes::{ingress_config, path_pattern, Ingress, List, Service};
+    use crate::matches_pattern;
+    use std::collections::HashMap;
+    use std::error::Error;
+
+    #[test]
+    fn test_path_pattern() {
+        let prefix = path_pattern(false, "/api/");
+        assert!(matches_pattern(&prefix, "/api"));
+        assert!(matches_pattern(&prefix, "/api/v1/items"));
+        assert!(!matches_pattern(&prefix, "/apiv1"));
+        assert!(matches_pattern(&path_pattern(false, "/"), "/index.html"));
+        let exact = path_pattern(true, "/health.json");
+        assert!(matches_pattern(&exact, "/health.json"));
+        assert!(!matches_pattern(&exact, "/health-json"));
+        assert!(!matches_pattern(&exact, "/health.json/x"));
+    }
+
+    #[test]
+    fn test_ingress_config() -> Result<(), Box<dyn Error>> {
+        let ingresses: List<Ingress> = serde_json::from_str(
+            r#"{"items": [
+                {"metadata": {"name": "shop", "namespace": "prod"}, "spec": {
+                    "ingressClassName": "cblt",
+                    "tls": [{"hosts": ["shop.example.com"], "secretName": "shop-tls"}],
+                    "rules": [{"host": "shop.example.com", "http": {"paths": [
+                        {"path": "/", "pathType": "Prefix",
+                         "backend": {"service": {"name": "web", "port": {"number": 8080}}}},
+                        {"path": "/api", "pathType": "Prefix",
+                         "backend": {"service": {"name": "api", "port": {"name": "http"}}}},
+                        {"path": "/api/health", "pathType": "Exact",
+                         "backend": {"service": {"name": "probe", "port": {"number": 80}}}},
+                        {"path": "/missing", "pathType": "Prefix",
+                         "backend": {"service": {"name": "api", "port": {"name": "grpc"}}}}
+                    ]}}]
+                }},
+                {"metadata": {"name": "fallback", "namespace": "default"}, "spec": {
+                    "defaultBackend": {"service": {"name": "errors", "port": {"number": 80}}}
+                }}
+            ]}"#,
+        )?;
+        let services: List<Service> = serde_json::from_str(
+            r#"{"items": [
+                {"metadata": {"name": "api", "namespace": "prod"},
+                 "spec": {"ports": [{"name": "http", "port": 9000}]}}
+            ]}"#,
+        )?;
+        let tls_files = HashMap::from([(
+            ("prod".to_string(), "shop-tls".to_string()),
+            ("/tmp/shop.crt".to_string(), "/tmp/shop.key".to_string()),
+        )]);
+        let config = ingress_config(&ingresses.items, &services.items, &tls_files);
+
+        let routes = |host: &str| -> Vec<(String, String)> {
+            config[host]
+                .iter()
+                .filter_map(|directive| match directive {
+                    Directive::ReverseProxy {
+                        pattern,
+                        destinations,
+                        options,
+                    } => {
+                        assert!(options.dns_discovery.is_some());
+                        Some((pattern.clone(), destinations[0].clone()))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        let expected = vec![
+            (
+                r"^/api/health$".to_string(),
+                "http://probe.prod.svc:80".to_string(),
+            ),
+            (
+                r"^/api(/|$)".to_string(),
+                "http://api.prod.svc:9000".to_string(),
+            ),
+            ("/*".to_string(), "http://web.prod.svc:8080".to_string()),
+        ];
+        assert_eq!(routes("shop.example.com"), expected);
+        assert_eq!(routes("shop.example.com:80"), expected);
+        assert!(matches!(
+            config["shop.example.com"].last(),
+            Some(Directive::TlS { cert, key }) if cert == "/tmp/shop.crt" && key == "/tmp/shop.key"
+        ));
+        assert!(!config["shop.example.com:80"]
+            .iter()
+            .any(|directive| matches!(directive, Directive::TlS { .. })));
+        assert_eq!(
+            routes("*"),
+            vec![("/*".to_string(), "http://errors.default.svc:80".to_string())]
+        );
+        assert_eq!(config.len(), 3);
+
+        Ok(())
+    }
+}
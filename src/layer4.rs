@@ -0,0 +1,345 @@
+use crate::acme::ACME_TLS_ALPN;
+use crate::config::{Directive, Layer4Options};
+use crate::error::CbltError;
+use crate::reverse_proxy::{open_backend, BackendStream};
+use crate::server::{HostDetails, ServerSettings};
+use crate::tls::select_cert;
+#[cfg(debug_assertions)]
+use log::{debug, error};
+use rustls::server::Acceptor;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Wait for the ClientHello naming the host, clients of protocols where the server speaks first
+/// go to the "*" host after it
+const SNI_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_CLIENT_HELLO: usize = 64 * 1024;
+
+/// Upstreams of a `layer4` host, each connection starts at the next one
+#[derive(Debug)]
+pub struct Layer4Proxy {
+    upstreams: Vec<String>,
+    options: Layer4Options,
+    next: AtomicUsize,
+}
+
+/// Proxy of the host's `layer4` directive
+pub fn host_layer4(directives: &[Directive]) -> Option<Layer4Proxy> {
+    directives.iter().find_map(|directive| match directive {
+        Directive::Layer4 { upstreams, options } => Some(Layer4Proxy {
+            upstreams: upstreams.clone(),
+            options: options.clone(),
+            next: AtomicUsize::new(0),
+        }),
+        _ => None,
+    })
+}
+
+/// Whether a port's hosts forward connections instead of serving HTTP
+pub fn is_layer4(hosts: &HashMap<String, HostDetails>) -> bool {
+    hosts.values().any(|host| host.layer4.is_some())
+}
+
+impl Layer4Proxy {
+    /// Connection to the first upstream answering in time, in turn from the next one
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    async fn connect(&self, client: SocketAddr) -> Result<BackendStream, CbltError> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let proxy_protocol = self
+            .options
+            .proxy_protocol
+            .map(|version| (version, Some(client)));
+        for offset in 0..self.upstreams.len() {
+            let upstream = &self.upstreams[(start + offset) % self.upstreams.len()];
+            let open = open_backend(upstream, None, false, proxy_protocol);
+            match timeout(self.options.connect_timeout, open).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(_err)) => {
+                    #[cfg(debug_assertions)]
+                    error!("Failed to connect to upstream {}: {}", upstream, _err);
+                }
+                Err(_) => {
+                    #[cfg(debug_assertions)]
+                    error!("Connection to upstream {} timed out", upstream);
+                }
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("No upstream of {:?} is reachable", self.upstreams),
+        )
+        .into())
+    }
+
+    /// Copies both ways until either side closes, `received` goes to the upstream first
+    async fn forward<S>(
+        &self,
+        mut stream: S,
+        client: SocketAddr,
+        received: &[u8],
+    ) -> Result<(), CbltError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut upstream = self.connect(client).await?;
+        upstream.write_all(received).await?;
+        let (_sent, _returned) = copy_bidirectional(&mut stream, &mut upstream).await?;
+        #[cfg(debug_assertions)]
+        debug!(
+            "Layer4 {} closed, {} bytes sent, {} returned",
+            client, _sent, _returned
+        );
+        Ok(())
+    }
+}
+
+/// Forwards a connection to the host its TLS server name picks. Ports with `tls` terminate it,
+/// the others pass the handshake through when their hosts have names
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn serve<S>(
+    stream: S,
+    settings: &ServerSettings,
+    client: SocketAddr,
+) -> Result<(), CbltError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hosts = &settings.hosts;
+    if let Some(acceptor) = &settings.tls_acceptor {
+        let stream = acceptor.accept(stream).await?;
+        let (_, connection) = stream.get_ref();
+        // A TLS-ALPN-01 validation only needs the handshake
+        if connection.alpn_protocol() == Some(ACME_TLS_ALPN) {
+            return Ok(());
+        }
+        let name = connection.server_name().map(str::to_string);
+        return match find_proxy(hosts, name.as_deref()) {
+            Some(proxy) => proxy.forward(stream, client, &[]).await,
+            None => Ok(()),
+        };
+    }
+
+    // A lone "*" host takes every connection without waiting for the client to speak
+    if let [(name, host)] = &hosts.iter().collect::<Vec<_>>()[..] {
+        if name.as_str() == "*" {
+            return match &host.layer4 {
+                Some(proxy) => proxy.forward(stream, client, &[]).await,
+                None => Ok(()),
+            };
+        }
+    }
+    let mut stream = stream;
+    let mut received = Vec::new();
+    let name = timeout(SNI_TIMEOUT, server_name(&mut stream, &mut received))
+        .await
+        .unwrap_or(Ok(None))?;
+    match find_proxy(hosts, name.as_deref()) {
+        Some(proxy) => proxy.forward(stream, client, &received).await,
+        None => {
+            #[cfg(debug_assertions)]
+            debug!("No layer4 host for {:?} from {}", name, client);
+            Ok(())
+        }
+    }
+}
+
+/// Host named by the client first, then a wildcard for its first label, then "*"
+fn find_proxy<'a>(
+    hosts: &'a HashMap<String, HostDetails>,
+    name: Option<&str>,
+) -> Option<&'a Layer4Proxy> {
+    select_cert(hosts, hosts.get("*"), name)?.layer4.as_ref()
+}
+
+/// SNI name of the ClientHello the client opens with, None when it sends something else. What
+/// was read is kept in `received` for the upstream
+async fn server_name<S>(stream: &mut S, received: &mut Vec<u8>) -> Result<Option<String>, CbltError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut acceptor = Acceptor::default();
+    let mut buf = [0; 4096];
+    while received.len() < MAX_CLIENT_HELLO {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        received.extend_from_slice(&buf[..read]);
+        let mut tls = &buf[..read];
+        while !tls.is_empty() {
+            if acceptor.read_tls(&mut tls)? == 0 {
+                break;
+            }
+        }
+        match acceptor.accept() {
+            Ok(Some(accepted)) => {
+                let name = accepted.client_hello().server_name().map(str::to_string);
+                return Ok(name);
+            }
+            Ok(None) => {}
+            Err(_) => return Ok(None),
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::embed::testing::serve;
+    use crate::CbltServer;
+    use rcgen::{CertificateParams, KeyPair};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use std::error::Error;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsConnector;
+
+    /// Upstream greeting with its name, then echoing what it gets
+    async fn upstream(name: &'static str) -> Result<u16, Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ = stream.write_all(name.as_bytes()).await;
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        Ok(port)
+    }
+
+    fn client_config(roots: RootCertStore) -> Arc<ClientConfig> {
+        Arc::new(
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_layer4_forward() -> Result<(), Box<dyn Error>> {
+        // Nothing listens on a port once its listener is gone
+        let dead = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let upstream = upstream("db").await?;
+        let source = format!(
+            "\"*:0\" {{\n    layer4 \"127.0.0.1:{}\" \"127.0.0.1:{}\"\n}}",
+            dead, upstream
+        );
+        let (server, port) = serve(&source).await?;
+
+        // The upstream speaks first, the unreachable one is skipped
+        for _ in 0..2 {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+            let mut greeting = [0; 2];
+            stream.read_exact(&mut greeting).await?;
+            assert_eq!(&greeting, b"db");
+            stream.write_all(b"ping").await?;
+            let mut echo = [0; 4];
+            stream.read_exact(&mut echo).await?;
+            assert_eq!(&echo, b"ping");
+        }
+        server.stop();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_layer4_sni() -> Result<(), Box<dyn Error>> {
+        let (a, b, other) = (
+            upstream("a").await?,
+            upstream("b").await?,
+            upstream("*").await?,
+        );
+        let source = format!(
+            r#""a.test:0" {{
+    layer4 "127.0.0.1:{a}"
+}}
+"*.b.test:0" {{
+    layer4 "127.0.0.1:{b}"
+}}
+"*:0" {{
+    layer4 "127.0.0.1:{other}"
+}}"#
+        );
+        let (server, port) = serve(&source).await?;
+
+        for (name, expected) in [("a.test", b"a"), ("x.b.test", b"b"), ("c.test", b"*")] {
+            let mut connection = ClientConnection::new(
+                client_config(RootCertStore::empty()),
+                ServerName::try_from(name)?.to_owned(),
+            )?;
+            let mut hello = Vec::new();
+            connection.write_tls(&mut hello)?;
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+            stream.write_all(&hello).await?;
+            let mut greeting = [0; 1];
+            stream.read_exact(&mut greeting).await?;
+            assert_eq!(&greeting, expected, "{}", name);
+            // The handshake reaches the upstream untouched
+            let mut echo = vec![0; hello.len()];
+            stream.read_exact(&mut echo).await?;
+            assert_eq!(echo, hello);
+        }
+        server.stop();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_layer4_tls() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-layer4-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let key = KeyPair::generate()?;
+        let cert = CertificateParams::new(vec!["db.test".to_string()])?.self_signed(&key)?;
+        let (cert_path, key_path) = (dir.join("db.crt"), dir.join("db.key"));
+        std::fs::write(&cert_path, cert.pem())?;
+        std::fs::write(&key_path, key.serialize_pem())?;
+
+        let upstream = upstream("db").await?;
+        let source = format!(
+            "\"db.test:0\" {{\n    tls \"{}\" \"{}\"\n    layer4 \"127.0.0.1:{}\"\n}}",
+            cert_path.display(),
+            key_path.display(),
+            upstream
+        );
+        let (server, port) = serve(&source).await?;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(&cert_path)?)?;
+        let mut config = ClientConfig::clone(&client_config(roots));
+        config.alpn_protocols = vec![b"postgresql".to_vec()];
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut stream = connector
+            .connect(ServerName::try_from("db.test")?, stream)
+            .await?;
+        // Decrypted on the way to the upstream
+        let mut greeting = [0; 2];
+        stream.read_exact(&mut greeting).await?;
+        assert_eq!(&greeting, b"db");
+        stream.write_all(b"select 1").await?;
+        let mut echo = [0; 8];
+        stream.read_exact(&mut echo).await?;
+        assert_eq!(&echo, b"select 1");
+
+        server.stop();
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_layer4_shared_port() -> Result<(), Box<dyn Error>> {
+        let source = "\"db.test:5432\" {\n    layer4 \"127.0.0.1:5433\"\n}\n\"*:5432\" {\n    redir \"https://example.com\"\n}";
+        assert!(CbltServer::new().cbltfile(source)?.start().await.is_err());
+        Ok(())
+    }
+}
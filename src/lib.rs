@@ -0,0 +1,789 @@
+//! Safe and fast minimalistic web server and reverse proxy.
+//!
+//! The `cblt` binary is a thin wrapper around [`run`]. Applications embed the server with
+//! [`CbltServer`] instead, configuring hosts and their directives in code.
+
+use crate::admin::{run_admin, AdminState};
+use crate::config::{
+    config_fingerprint, file_server_config, load_docker_config, load_servers_from_config,
+    load_servers_from_docker, parse_size, reverse_proxy_config, watch_docker_events, AcmeChallenge,
+    Directive, RollOptions,
+};
+use crate::error::CbltError;
+use crate::log_file::RotatingFile;
+use crate::server::{Listener, Server, ServerWorker};
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use log::{debug, error, info};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::Arc;
+use tokio::runtime::Builder;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex, Notify};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+use tracing::Level;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::FmtSubscriber;
+
+mod access_log;
+mod acme;
+mod admin;
+mod auto_ban;
+mod body;
+mod cache;
+mod caddyfile;
+mod cgi;
+mod cidr;
+mod compression;
+pub mod config;
+mod connection_limit;
+mod directive;
+mod discovery;
+mod dns;
+mod embed;
+pub mod error;
+mod fastcgi;
+mod file_cache;
+mod file_server;
+mod forward_auth;
+mod forward_proxy;
+mod geoip;
+mod grpc;
+pub mod handler;
+mod headers;
+mod health;
+mod http2;
+mod kubernetes;
+mod layer4;
+mod log_file;
+mod markdown;
+mod matcher;
+mod otel;
+mod pattern;
+mod proxy_protocol;
+mod rate_limit;
+mod remote_ip;
+mod request;
+mod response;
+mod reverse_proxy;
+mod sendfile;
+mod server;
+mod throttle;
+mod tls;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod validate;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod webdav;
+
+pub use embed::{CbltServer, RunningServer};
+
+const CONFIG_ENV: &str = "CBLT_CONFIG";
+const ADMIN_TOKEN_ENV: &str = "CBLT_ADMIN_TOKEN";
+const CONFIG_SEARCH_PATHS: [&str; 2] = ["./Cbltfile", "/etc/cblt/Cbltfile"];
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Configuration file path, otherwise $CBLT_CONFIG, ./Cbltfile or /etc/cblt/Cbltfile
+    #[arg(long, alias = "cfg", global = true)]
+    config: Option<String>,
+
+    #[arg(skip)]
+    config_path: PathBuf, // resolved from the options above
+
+    /// Maximum number of connections
+    #[arg(long, default_value_t = 10000)]
+    max_connections: usize,
+
+    /// Accept loops per TCP listener, each on its own SO_REUSEPORT socket (Unix only)
+    #[arg(long, default_value_t = 1)]
+    acceptors: usize,
+
+    /// Enable reload feature
+    #[arg(long)]
+    reload: bool,
+    /// Mode of operation (docker, kubernetes or config)
+    #[arg(long, default_value = "config", value_enum)]
+    mode: Mode, // Add the mode field
+
+    /// IngressClass whose Ingresses the kubernetes mode serves
+    #[arg(long, default_value = "cblt")]
+    ingress_class: String,
+
+    /// Kubernetes API server for the kubernetes mode, e.g. http://127.0.0.1:8001 of `kubectl proxy`,
+    /// the one of the cluster when unset
+    #[arg(long)]
+    kube_api: Option<String>,
+
+    /// Write the server log to this file instead of stderr
+    #[arg(long)]
+    log_file: Option<String>,
+
+    /// Rotate the server log file when it grows beyond this size
+    #[arg(long, default_value = "100MiB")]
+    log_roll_size: String,
+
+    /// Number of rotated server log files to keep
+    #[arg(long, default_value_t = 10)]
+    log_roll_keep: usize,
+
+    /// Serve the admin API on this address, e.g. 127.0.0.1:2019
+    #[arg(long)]
+    admin: Option<String>,
+
+    /// Bearer token the admin API requires, otherwise $CBLT_ADMIN_TOKEN. Needed to serve it on
+    /// anything but loopback
+    #[arg(long)]
+    admin_token: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check the configuration file and exit
+    Validate,
+    /// Convert another server's configuration to a Cbltfile printed to stdout
+    Adapt {
+        /// Format of the input
+        #[arg(long, default_value = "caddyfile", value_enum)]
+        from: AdaptFormat,
+        /// File to convert
+        #[arg(long, default_value = "./Caddyfile")]
+        input: String,
+    },
+    /// Serve a directory without a configuration file
+    FileServer {
+        /// Directory to serve
+        #[arg(long, default_value = ".")]
+        root: String,
+        /// Address to listen on, e.g. ":8080"
+        #[arg(long, default_value = ":80")]
+        listen: String,
+    },
+    /// Proxy all requests to one upstream without a configuration file
+    ReverseProxy {
+        /// Address to listen on, e.g. ":8080"
+        #[arg(long, default_value = ":80")]
+        from: String,
+        /// Upstream URL, e.g. "http://localhost:3000"
+        #[arg(long)]
+        to: String,
+    },
+}
+
+#[derive(ValueEnum, Clone, Debug, Eq, PartialEq)]
+enum AdaptFormat {
+    Caddyfile,
+}
+
+#[derive(ValueEnum, Clone, Debug, Eq, PartialEq)]
+enum Mode {
+    Docker,
+    Kubernetes,
+    Config,
+}
+
+/// Runs cblt as the command line asks, serving until Ctrl-C
+pub fn run(mut args: Args) -> anyhow::Result<()> {
+    fdlimit::raise_fd_limit()?;
+    args.config_path = find_config(args.config.as_deref());
+    let args = Arc::new(args);
+    match &args.command {
+        Some(Command::Validate) => return validate(&args.config_path),
+        Some(Command::Adapt { from, input }) => return adapt(from, input),
+        _ => {}
+    }
+    let log_target = log_target(&args)?;
+    #[cfg(debug_assertions)]
+    only_in_debug(log_target);
+    #[cfg(not(debug_assertions))]
+    only_in_production(log_target);
+    let num_cpus = std::thread::available_parallelism()?.get();
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(num_cpus)
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        server(num_cpus, args).await?;
+        Ok(())
+    })
+}
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn server(num_cpus: usize, args: Arc<Args>) -> anyhow::Result<()> {
+    if args.reload {
+        let reload_file_path = Path::new("reload");
+        if reload_file_path.exists() {
+            anyhow::bail!("File 'reload' already exists");
+        } else {
+            std::fs::File::create(reload_file_path)?;
+            info!("Reloading 'Cbltfile'  has been initiated");
+        }
+        return Ok(());
+    }
+    info!("Workers amount: {}", num_cpus);
+
+    let max_connections: usize = args.max_connections;
+    info!("Max connections: {}", max_connections);
+
+    if args.mode == Mode::Config && args.command.is_none() {
+        info!("Configuration file: {}", args.config_path.display());
+    }
+    let servers = load_servers(args.clone()).await?;
+
+    #[cfg(debug_assertions)]
+    debug!("{:#?}", servers);
+
+    let (tx, mut rx) = watch::channel(servers);
+    let supervisor = Arc::new(Mutex::new(ServerSupervisor {
+        workers: HashMap::new(),
+    }));
+
+    let args_clone = args.clone();
+    let supervisor_clone = supervisor.clone();
+    tokio::spawn(async move {
+        loop {
+            if let Err(err) = apply_servers(&supervisor_clone, &mut rx, &args_clone).await {
+                error!("Error: {}", err);
+                std::process::exit(0);
+            }
+
+            if rx.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+
+    #[cfg(unix)]
+    {
+        let args = args.clone();
+        let tx = tx.clone();
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading configuration");
+                if let Err(err) = reload_servers(args.clone(), &tx).await {
+                    error!("Error: {}", err);
+                }
+            }
+        });
+    }
+
+    // Renewed certificates are picked up without touching the rest of the configuration
+    let certificates_supervisor = supervisor.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            for worker in certificates_supervisor.lock().await.workers.values() {
+                if let Err(err) = worker.reload_certificates().await {
+                    error!("Error: {}", err);
+                }
+            }
+        }
+    });
+
+    if let Some(addr) = args.admin.clone() {
+        let state = Arc::new(AdminState {
+            args: args.clone(),
+            supervisor,
+            servers: tx.clone(),
+            token: args
+                .admin_token
+                .clone()
+                .or_else(|| std::env::var(ADMIN_TOKEN_ENV).ok())
+                .filter(|token| !token.is_empty()),
+        });
+        tokio::spawn(async move {
+            if let Err(err) = run_admin(addr, state).await {
+                error!("Error: {}", err);
+            }
+        });
+    }
+
+    let args = args.clone();
+
+    // Containers starting or stopping reload at once instead of on the next round
+    let changes = Arc::new(Notify::new());
+    if args.mode == Mode::Docker {
+        tokio::spawn(watch_docker_events(changes.clone()));
+    }
+
+    tokio::spawn(async move {
+        let reload_file_path = Path::new("reload");
+        // Provider configuration as last applied, the servers are only rebuilt when it changes
+        let mut applied = None;
+
+        loop {
+            let provided = match args.mode {
+                Mode::Docker => Some(load_docker_config(args.clone()).await),
+                Mode::Kubernetes => Some(kubernetes::load_config(&args).await),
+                Mode::Config => None,
+            };
+            if let Some(provided) = provided {
+                match provided {
+                    Ok(config) => {
+                        let fingerprint = config_fingerprint(&config);
+                        if applied.as_ref() != Some(&fingerprint) {
+                            match build_servers(config) {
+                                Ok(servers) => {
+                                    if let Err(err) = tx.send(servers) {
+                                        error!("Error: {}", err);
+                                    }
+                                    applied = Some(fingerprint);
+                                }
+                                Err(err) => error!("Error: {}", err),
+                            }
+                        }
+                    }
+                    // Keep serving with the current containers or Ingresses
+                    Err(err) => error!("Error: {}", err),
+                }
+            } else if reload_file_path.exists() {
+                match load_servers(args.clone()).await {
+                    Ok(servers) => {
+                        if let Err(err) = tx.send(servers) {
+                            error!("Error: {}", err);
+                        }
+                        if let Err(err) = std::fs::remove_file(reload_file_path) {
+                            error!("Error: {}", err);
+                        }
+                    }
+                    Err(err) => {
+                        error!("Error: {}", err);
+                    }
+                }
+            }
+            let _ =
+                tokio::time::timeout(tokio::time::Duration::from_secs(5), changes.notified()).await;
+        }
+    });
+
+    info!("CBLT started");
+    tokio::signal::ctrl_c().await?;
+    info!("CBLT stopped");
+
+    Ok(())
+}
+
+/// Loads the servers for a one-liner command, or from Docker labels, Kubernetes Ingresses or the configuration file depending on the mode
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn load_servers(args: Arc<Args>) -> Result<HashMap<u16, Server>, CbltError> {
+    if let Some(Command::FileServer { root, listen }) = &args.command {
+        build_servers(file_server_config(root, listen)?)
+    } else if let Some(Command::ReverseProxy { from, to }) = &args.command {
+        build_servers(reverse_proxy_config(from, to)?)
+    } else if args.mode == Mode::Docker {
+        load_servers_from_docker(args).await
+    } else if args.mode == Mode::Kubernetes {
+        build_servers(kubernetes::load_config(&args).await?)
+    } else {
+        load_servers_from_config(args).await
+    }
+}
+
+/// Hands the workers the servers last sent to `servers`
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn apply_servers(
+    supervisor: &Mutex<ServerSupervisor>,
+    servers: &mut watch::Receiver<HashMap<u16, Server>>,
+    args: &Args,
+) -> Result<(), CbltError> {
+    let servers = servers.borrow_and_update().clone();
+    supervisor
+        .lock()
+        .await
+        .process_workers(servers, args.max_connections, args.acceptors)
+        .await
+}
+
+/// Loads the configuration again and sends it to the workers, which keep serving the current one
+/// when it fails to load
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn reload_servers(
+    args: Arc<Args>,
+    servers: &watch::Sender<HashMap<u16, Server>>,
+) -> Result<(), CbltError> {
+    servers.send_replace(load_servers(args).await?);
+    Ok(())
+}
+
+pub struct ServerSupervisor {
+    pub workers: HashMap<u16, ServerWorker>,
+}
+
+impl ServerSupervisor {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    async fn process_workers(
+        &mut self,
+        servers: HashMap<u16, Server>,
+        max_connections: usize,
+        acceptors: usize,
+    ) -> Result<(), CbltError> {
+        let for_stop: Vec<u16> = self
+            .workers
+            .keys()
+            .filter(|port| !servers.contains_key(port))
+            .copied()
+            .collect();
+        for port in for_stop {
+            if let Some(worker) = self.workers.remove(&port) {
+                worker.stop();
+                info!("Server worker stopped on port: {}", port);
+            }
+        }
+
+        acme::start(acme::acme_hosts(&servers));
+
+        for (port, server) in servers {
+            if let Some(worker) = self.workers.get_mut(&port) {
+                worker.update(server.hosts).await?;
+                worker.listen(&server.listeners);
+                info!("Server worker updated on port: {}", port);
+            } else if let Ok(server_worker) =
+                ServerWorker::new(server.clone(), max_connections, acceptors).await
+            {
+                server_worker.listen(&server.listeners);
+                self.workers.insert(port, server_worker);
+            } else {
+                error!("Error creating server worker");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn build_servers(
+    config: HashMap<String, Vec<Directive>>,
+) -> Result<HashMap<u16, Server>, CbltError> {
+    let mut servers: HashMap<u16, Server> = HashMap::new(); // Port -> Server
+
+    for (host, directives) in config {
+        let tls = directives
+            .iter()
+            .any(|d| matches!(d, Directive::TlS { .. } | Directive::TlsAcme { .. }));
+        let parsed_host = ParsedHost::from_str(&host);
+        let port = parsed_host.port.unwrap_or(if tls { 443 } else { 80 });
+        #[cfg(debug_assertions)]
+        debug!("Host: {}, Port: {}", host, port);
+
+        // Hosts without `listen` are served on the port on every interface
+        let mut listeners = Vec::new();
+        for directive in &directives {
+            if let Directive::Listen { addresses, options } = directive {
+                for address in addresses {
+                    listeners.push(Listener::parse(address, port, Some(options))?);
+                }
+            }
+        }
+        if listeners.is_empty() {
+            listeners.push(Listener::any(port));
+        }
+
+        let server = match servers.entry(port) {
+            Entry::Occupied(server) => server.into_mut(),
+            Entry::Vacant(new_server) => new_server.insert(Server {
+                port,
+                hosts: HashMap::new(),
+                listeners: Vec::new(),
+            }),
+        };
+        server.hosts.insert(parsed_host.host, directives);
+        for listener in listeners {
+            if !server.listeners.contains(&listener) {
+                server.listeners.push(listener);
+            }
+        }
+    }
+    // A port either forwards connections or serves HTTP
+    for server in servers.values() {
+        let layer4 = |directives: &Vec<Directive>| {
+            directives
+                .iter()
+                .any(|d| matches!(d, Directive::Layer4 { .. }))
+        };
+        if server.hosts.values().any(layer4) && !server.hosts.values().all(layer4) {
+            return Err(CbltError::KdlParseError {
+                details: format!("Port {} mixes 'layer4' hosts with HTTP hosts", server.port),
+            });
+        }
+    }
+    // Binding an address the wildcard of the port already holds would fail
+    for server in servers.values_mut() {
+        let all = server.listeners.clone();
+        server
+            .listeners
+            .retain(|listener| !all.iter().any(|any| any.covers(listener)));
+    }
+    // HTTP-01 challenges are answered on port 80
+    if acme::acme_hosts(&servers)
+        .iter()
+        .any(|(_, options)| options.challenge == AcmeChallenge::Http01)
+    {
+        servers.entry(80).or_insert_with(|| Server {
+            port: 80,
+            hosts: HashMap::new(),
+            listeners: vec![Listener::any(80)],
+        });
+    }
+    Ok(servers)
+}
+
+/// Configuration file given on the command line, in $CBLT_CONFIG or the first existing default
+fn find_config(explicit: Option<&str>) -> PathBuf {
+    find_config_in(explicit, &CONFIG_SEARCH_PATHS.map(Path::new))
+}
+
+/// Like [`find_config`] with other search paths, the first of them when none exists
+fn find_config_in(explicit: Option<&str>, search_paths: &[&Path]) -> PathBuf {
+    if let Some(path) = explicit {
+        return PathBuf::from(path);
+    }
+    // Set but empty, as `CBLT_CONFIG= cblt` leaves it, is the same as unset
+    if let Some(path) = std::env::var_os(CONFIG_ENV).filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+    search_paths
+        .iter()
+        .find(|path| path.is_file())
+        .unwrap_or(&search_paths[0])
+        .to_path_buf()
+}
+
+fn validate(path: &Path) -> anyhow::Result<()> {
+    let source = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read configuration file {}", path.display()))?;
+    let diagnostics = validate::validate_config(path, &source);
+    if diagnostics.is_empty() {
+        println!("{}: configuration is valid", path.display());
+        return Ok(());
+    }
+    for diagnostic in &diagnostics {
+        eprintln!("{}:{}", diagnostic.file.display(), diagnostic);
+    }
+    std::process::exit(1);
+}
+
+fn adapt(from: &AdaptFormat, input: &str) -> anyhow::Result<()> {
+    let source =
+        std::fs::read_to_string(input).with_context(|| format!("Cannot read {}", input))?;
+    let adapted = match from {
+        AdaptFormat::Caddyfile => caddyfile::adapt_caddyfile(&source)?,
+    };
+    for warning in &adapted.warnings {
+        eprintln!("{}:{}", input, warning);
+    }
+    print!("{}", adapted.cbltfile);
+    Ok(())
+}
+
+fn log_target(args: &Args) -> anyhow::Result<env_logger::Target> {
+    match &args.log_file {
+        Some(path) => {
+            let options = RollOptions {
+                max_size: Some(parse_size(&args.log_roll_size)?),
+                keep: args.log_roll_keep,
+                ..Default::default()
+            };
+            let file = RotatingFile::open(Path::new(path), options)?;
+            Ok(env_logger::Target::Pipe(Box::new(file)))
+        }
+        None => Ok(env_logger::Target::Stderr),
+    }
+}
+
+#[allow(dead_code)]
+pub fn only_in_debug(target: env_logger::Target) {
+    let _ = env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("debug"))
+        .filter_module("bollard::docker", log::LevelFilter::Info)
+        .target(target)
+        .try_init();
+}
+
+#[allow(dead_code)]
+fn only_in_production(target: env_logger::Target) {
+    let _ = env_logger::Builder::from_env(env_logger::Env::new().default_filter_or("info"))
+        .target(target)
+        .try_init();
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(Level::TRACE) // Set the maximum log level
+        .with_span_events(FmtSpan::CLOSE)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).expect("Failed to set subscriber");
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn matches_pattern(pattern: &str, path: &str) -> bool {
+    if pattern == "*" {
+        true
+    } else if pattern::is_regex(pattern) {
+        pattern::path_regex(pattern).is_some_and(|regex| regex.is_match(path))
+    } else if let Some(prefix) = pattern.strip_suffix("*") {
+        path.starts_with(prefix)
+    } else {
+        pattern == path
+    }
+}
+
+pub struct ParsedHost {
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl ParsedHost {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn from_str(host_str: &str) -> Self {
+        if let (host_part, Some(port_part)) = split_port(host_str) {
+            let port = port_part.parse().ok();
+            ParsedHost {
+                host: host_part.to_string(),
+                port,
+            }
+        } else {
+            ParsedHost {
+                host: host_str.to_string(),
+                port: None,
+            }
+        }
+    }
+}
+
+/// Host and port of `example.com:8080` or `[::1]:8080`, the colons of a bare IPv6 address are
+/// not a port
+pub fn split_port(host: &str) -> (&str, Option<&str>) {
+    match host.rsplit_once(':') {
+        Some((name, port))
+            if !port.contains(']') && (name.starts_with('[') || !name.contains(':')) =>
+        {
+            (name, Some(port))
+        }
+        _ => (host, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::testing::exchange;
+    use std::error::Error;
+    use std::ffi::OsString;
+
+    /// Sets an environment variable until dropped, restoring its previous value
+    struct ScopedEnv(&'static str, Option<OsString>);
+
+    impl ScopedEnv {
+        fn set(name: &'static str, value: Option<&str>) -> Self {
+            let previous = std::env::var_os(name);
+            match value {
+                Some(value) => std::env::set_var(name, value),
+                None => std::env::remove_var(name),
+            }
+            ScopedEnv(name, previous)
+        }
+    }
+
+    impl Drop for ScopedEnv {
+        fn drop(&mut self) {
+            match &self.1 {
+                Some(value) => std::env::set_var(self.0, value),
+                None => std::env::remove_var(self.0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_config() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-find-config-{}", std::process::id()));
+        let local = dir.join("Cbltfile");
+        let system = dir.join("etc/Cbltfile");
+        std::fs::create_dir_all(dir.join("etc"))?;
+        let search_paths = [local.as_path(), system.as_path()];
+
+        std::fs::write(&local, "")?;
+        std::fs::write(&system, "")?;
+        let env = ScopedEnv::set(CONFIG_ENV, Some("/srv/env/Cbltfile"));
+        assert_eq!(
+            find_config_in(Some("/srv/flag/Cbltfile"), &search_paths),
+            Path::new("/srv/flag/Cbltfile")
+        );
+        assert_eq!(
+            find_config_in(None, &search_paths),
+            Path::new("/srv/env/Cbltfile")
+        );
+        drop(env);
+        let env = ScopedEnv::set(CONFIG_ENV, Some(""));
+        assert_eq!(find_config_in(None, &search_paths), local);
+        drop(env);
+
+        let _env = ScopedEnv::set(CONFIG_ENV, None);
+        assert_eq!(find_config_in(None, &search_paths), local);
+        std::fs::remove_file(&local)?;
+        assert_eq!(find_config_in(None, &search_paths), system);
+        std::fs::remove_file(&system)?;
+        // Nothing found falls back to the first search path
+        assert_eq!(find_config_in(None, &search_paths), local);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reload_servers() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("Cbltfile");
+        let site = |target: &str| format!("\"*:0\" {{\n    redir \"{}\"\n}}", target);
+        std::fs::write(&path, site("https://old.example"))?;
+        let mut args = Args::parse_from(["cblt"]);
+        args.config_path = path.clone();
+        let args = Arc::new(args);
+
+        let (tx, mut rx) = watch::channel(load_servers(args.clone()).await?);
+        let supervisor = Mutex::new(ServerSupervisor {
+            workers: HashMap::new(),
+        });
+        apply_servers(&supervisor, &mut rx, &args).await?;
+        let addrs = supervisor.lock().await.workers[&0].local_addrs().await;
+        let port = addrs.first().ok_or("no listener bound")?.port();
+        let get = || {
+            exchange(
+                port,
+                "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+        };
+        assert!(get().await?.contains("location: https://old.example"));
+
+        std::fs::write(&path, site("https://new.example"))?;
+        reload_servers(args.clone(), &tx).await?;
+        assert!(rx.has_changed()?);
+        apply_servers(&supervisor, &mut rx, &args).await?;
+        // A listener bound again would have got another port than 0 gave it the first time
+        assert_eq!(
+            supervisor.lock().await.workers[&0].local_addrs().await,
+            addrs
+        );
+        assert!(get().await?.contains("location: https://new.example"));
+
+        // A configuration that doesn't parse leaves the routing as it is
+        std::fs::write(&path, "\"*:0\" {")?;
+        assert!(reload_servers(args.clone(), &tx).await.is_err());
+        assert!(!rx.has_changed()?);
+        assert!(get().await?.contains("location: https://new.example"));
+
+        for (_, worker) in supervisor.lock().await.workers.drain() {
+            worker.stop();
+        }
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
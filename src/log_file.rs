@@ -0,0 +1,156 @@
+use crate::config::RollOptions;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Log file that is rotated to "name.1", "name.2", ... by size or age
+pub struct RotatingFile {
+    path: PathBuf,
+    options: RollOptions,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn open(path: &Path, options: RollOptions) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            options,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    fn should_roll(&self, incoming: usize) -> bool {
+        let too_big = self
+            .options
+            .max_size
+            .is_some_and(|max_size| self.size > 0 && self.size + incoming as u64 > max_size);
+        let too_old = self
+            .options
+            .interval
+            .is_some_and(|interval| self.opened.elapsed() >= interval);
+        too_big || too_old
+    }
+
+    fn rotated_path(&self, index: usize, gzip: bool) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{}", index));
+        if gzip {
+            path.push(".gz");
+        }
+        PathBuf::from(path)
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    fn roll(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        let keep = self.options.keep;
+        for gzip in [false, true] {
+            let _ = std::fs::remove_file(self.rotated_path(keep, gzip));
+            for index in (1..keep).rev() {
+                let from = self.rotated_path(index, gzip);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated_path(index + 1, gzip))?;
+                }
+            }
+        }
+        if keep > 0 {
+            let rotated = self.rotated_path(1, false);
+            std::fs::rename(&self.path, &rotated)?;
+            if self.options.gzip {
+                let compressed = self.rotated_path(1, true);
+                // Compressing a large file must not stall the writers
+                std::thread::spawn(move || {
+                    if let Err(err) = compress(&rotated, &compressed) {
+                        error!("Log compression error: {}", err);
+                    }
+                });
+            }
+        } else {
+            std::fs::remove_file(&self.path)?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.should_roll(buf.len()) {
+            self.roll()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn compress(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut input = File::open(from)?;
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    std::fs::remove_file(from)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RollOptions;
+    use crate::log_file::RotatingFile;
+    use std::error::Error;
+    use std::io::Write;
+
+    #[test]
+    fn test_roll_by_size() -> Result<(), Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("access.log");
+        let options = RollOptions {
+            max_size: Some(10),
+            keep: 2,
+            gzip: false,
+            ..Default::default()
+        };
+        let mut file = RotatingFile::open(&path, options)?;
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes())?;
+        }
+        file.flush()?;
+
+        assert_eq!(std::fs::read_to_string(&path)?, "fourth\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("access.log.1"))?,
+            "third\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("access.log.2"))?,
+            "second\n"
+        );
+        assert!(!dir.join("access.log.3").exists());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use crate::file_server::html_escape;
+use pulldown_cmark::{html, Event, HeadingLevel, Options, Parser, Tag, TagEnd};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Layout of pages when `markdown` names none
+pub const DEFAULT_LAYOUT: &str = "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\">\
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+<title>{title}</title></head>\n<body>\n{content}</body>\n</html>\n";
+
+/// Renders the document into the layout, filling in `{title}`, `{path}` and `{content}`. The
+/// title is the first top-level heading, `fallback_title` without one.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn render_page(markdown: &str, layout: &str, fallback_title: &str, path: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS
+        | Options::ENABLE_HEADING_ATTRIBUTES;
+    let events: Vec<Event> = Parser::new_ext(markdown, options).collect();
+    let title = title(&events).unwrap_or_else(|| fallback_title.to_string());
+    let mut content = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut content, events.into_iter());
+
+    let mut page = String::with_capacity(layout.len() + content.len());
+    let mut rest = layout;
+    // A single pass, so placeholders in the document are left alone
+    while let Some(start) = rest.find('{') {
+        page.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let placeholder = rest.find('}').map_or("", |end| &rest[..=end]);
+        let value = match placeholder {
+            "{title}" => html_escape(&title),
+            "{path}" => html_escape(path),
+            "{content}" => std::mem::take(&mut content),
+            _ => {
+                page.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        page.push_str(&value);
+        rest = &rest[placeholder.len()..];
+    }
+    page.push_str(rest);
+    page
+}
+
+fn title(events: &[Event]) -> Option<String> {
+    let start = events.iter().position(|event| {
+        matches!(
+            event,
+            Event::Start(Tag::Heading {
+                level: HeadingLevel::H1,
+                ..
+            })
+        )
+    })?;
+    let mut title = String::new();
+    for event in &events[start + 1..] {
+        match event {
+            Event::End(TagEnd::Heading(_)) => break,
+            Event::Text(text) | Event::Code(text) => title.push_str(text),
+            _ => {}
+        }
+    }
+    let title = title.trim();
+    (!title.is_empty()).then(|| title.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::markdown::{render_page, DEFAULT_LAYOUT};
+
+    #[test]
+    fn test_render_page() {
+        let layout = "<title>{title}</title><p>{path}</p>{content}<i>{other}</i>";
+        let page = render_page(
+            "Intro\n\n# Hello `cblt` & co\n\n| a |\n|---|\n| {title} |\n",
+            layout,
+            "readme",
+            "/docs/<readme>.md",
+        );
+        assert!(page.starts_with(
+            "<title>Hello cblt &amp; co</title><p>/docs/&lt;readme&gt;.md</p><p>Intro</p>\n"
+        ));
+        assert!(page.contains("<h1>Hello <code>cblt</code> &amp; co</h1>"));
+        assert!(page.contains("<td>{title}</td>"));
+        assert!(page.ends_with("</table>\n<i>{other}</i>"));
+
+        let page = render_page("Some *text*", DEFAULT_LAYOUT, "notes", "/notes.md");
+        assert!(page.contains("<title>notes</title>"));
+        assert!(page.contains("<body>\n<p>Some <em>text</em></p>\n</body>"));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::config::{CookieName, Directive, MatchCondition};
+use crate::matches_pattern;
+use crate::pattern::path_regex;
+use http::header::COOKIE;
+use http::Request;
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+
+/// Named matchers declared in a host, by name
+pub fn host_matchers(directives: &[Directive]) -> HashMap<String, Vec<MatchCondition>> {
+    directives
+        .iter()
+        .filter_map(|directive| match directive {
+            Directive::Matcher { name, conditions } => Some((name.clone(), conditions.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether the pattern of a directive takes the request, "@name" refers to a named matcher
+pub fn matches_request<B>(
+    pattern: &str,
+    request: &Request<B>,
+    matchers: &HashMap<String, Vec<MatchCondition>>,
+) -> bool {
+    if !pattern.starts_with('@') {
+        return matches_pattern(pattern, request.uri().path());
+    }
+    matchers
+        .get(pattern)
+        .is_some_and(|conditions| matches_all(conditions, request))
+}
+
+/// Whether the request meets every condition
+pub fn matches_all<B>(conditions: &[MatchCondition], request: &Request<B>) -> bool {
+    conditions
+        .iter()
+        .all(|condition| matches_condition(condition, request))
+}
+
+fn matches_condition<B>(condition: &MatchCondition, request: &Request<B>) -> bool {
+    match condition {
+        MatchCondition::Not(conditions) => !matches_all(conditions, request),
+        MatchCondition::And(conditions) => matches_all(conditions, request),
+        MatchCondition::Or(conditions) => conditions
+            .iter()
+            .any(|condition| matches_condition(condition, request)),
+        MatchCondition::Path(patterns) => patterns
+            .iter()
+            .any(|pattern| matches_pattern(pattern, request.uri().path())),
+        MatchCondition::Method(methods) => methods
+            .iter()
+            .any(|method| method == request.method().as_str()),
+        MatchCondition::Header { name, values } => {
+            let mut present = request
+                .headers()
+                .get_all(name.as_str())
+                .iter()
+                .filter_map(|value| value.to_str().ok());
+            if values.is_empty() {
+                present.next().is_some()
+            } else {
+                present.any(|value| values.iter().any(|want| matches_value(want, value)))
+            }
+        }
+        MatchCondition::Query { name, values } => {
+            let mut present = request
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter_map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (percent_decode_str(key).decode_utf8_lossy() == name.as_str())
+                        .then(|| percent_decode_str(value).decode_utf8_lossy())
+                });
+            if values.is_empty() {
+                present.next().is_some()
+            } else {
+                present.any(|value| values.iter().any(|want| matches_value(want, &value)))
+            }
+        }
+        MatchCondition::Cookie { name, values } => {
+            let mut present = request_cookies(request)
+                .filter(|(key, _)| matches_cookie_name(name, key))
+                .map(|(_, value)| value);
+            if values.is_empty() {
+                present.next().is_some()
+            } else {
+                present.any(|value| values.iter().any(|want| matches_value(want, value)))
+            }
+        }
+    }
+}
+
+/// Name and value of each cookie sent with the request
+pub fn request_cookies<B>(request: &Request<B>) -> impl Iterator<Item = (&str, &str)> {
+    request
+        .headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            Some((key, value.trim_matches('"')))
+        })
+}
+
+fn matches_cookie_name(name: &CookieName, key: &str) -> bool {
+    match name {
+        CookieName::Exact(name) => key == name,
+        CookieName::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        CookieName::Regex(pattern) => path_regex(pattern).is_some_and(|regex| regex.is_match(key)),
+    }
+}
+
+/// Exact value, or a prefix, suffix or substring with "*" at the open ends
+fn matches_value(pattern: &str, value: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    match (pattern.starts_with('*'), pattern.ends_with('*')) {
+        (true, true) => value.contains(&pattern[1..pattern.len() - 1]),
+        (true, false) => value.ends_with(&pattern[1..]),
+        (false, true) => value.starts_with(&pattern[..pattern.len() - 1]),
+        (false, false) => value == pattern,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::build_config;
+    use crate::matcher::{host_matchers, matches_request};
+    use http::Request;
+    use kdl::KdlDocument;
+    use std::error::Error;
+
+    #[test]
+    fn test_named_matchers() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    @api {
+        path "/api/*" "/graphql"
+        method "post"
+        header "Content-Type" "application/json*"
+    }
+    @debug {
+        query "debug"
+        header "X-Debug"
+    }
+    reverse_proxy "@api" "http://10.0.0.1:8080"
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let config = build_config(&doc)?;
+        let matchers = host_matchers(&config["example.com"]);
+        assert_eq!(matchers.len(), 2);
+
+        let api = Request::post("/api/users")
+            .header("content-type", "application/json; charset=utf-8")
+            .body(())?;
+        assert!(matches_request("@api", &api, &matchers));
+        let get = Request::get("/api/users")
+            .header("content-type", "application/json")
+            .body(())?;
+        assert!(!matches_request("@api", &get, &matchers));
+        let form = Request::post("/graphql")
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(())?;
+        assert!(!matches_request("@api", &form, &matchers));
+
+        let debug = Request::get("/?a=1&debug")
+            .header("x-debug", "1")
+            .body(())?;
+        assert!(matches_request("@debug", &debug, &matchers));
+        let no_query = Request::get("/?a=1").header("x-debug", "1").body(())?;
+        assert!(!matches_request("@debug", &no_query, &matchers));
+
+        assert!(matches_request("/api/*", &get, &matchers));
+        assert!(!matches_request("@missing", &get, &matchers));
+
+        // Everything except the assets and the health check goes to the proxy
+        let cblt_file = r#"
+"example.com" {
+    @proxied {
+        not {
+            path "/assets/*" "/healthz"
+        }
+        or {
+            method "GET" "HEAD"
+            and {
+                method "POST"
+                header "X-Csrf-Token"
+            }
+        }
+    }
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let matchers = host_matchers(&build_config(&doc)?["example.com"]);
+        for (request, expected) in [
+            (Request::get("/app/page").body(())?, true),
+            (Request::get("/assets/app.css").body(())?, false),
+            (Request::head("/healthz").body(())?, false),
+            (Request::post("/app/form").body(())?, false),
+            (
+                Request::post("/app/form")
+                    .header("x-csrf-token", "abc")
+                    .body(())?,
+                true,
+            ),
+            (Request::delete("/app/item").body(())?, false),
+        ] {
+            assert_eq!(
+                matches_request("@proxied", &request, &matchers),
+                expected,
+                "{} {}",
+                request.method(),
+                request.uri()
+            );
+        }
+
+        for invalid in [
+            r#""example.com" { @empty { not; }; }"#,
+            r#""example.com" { reverse_proxy "@missing" "http://10.0.0.1"; }"#,
+            r#""example.com" { @empty; root "@empty" "/var/www"; }"#,
+            r#""example.com" { @ip { remote_ip "10.0.0.0/8"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cookie_matchers() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    @session {
+        cookie "exact" "_oauth2_proxy"
+    }
+    @tracking {
+        cookie "prefix" "_ga"
+    }
+    @tenant {
+        cookie "regex" r"^tenant_[0-9]+$" "eu-*"
+    }
+    @anonymous {
+        not {
+            cookie "exact" "_oauth2_proxy"
+        }
+    }
+    redir "@anonymous" "https://auth.example.com/oauth2/start"
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let matchers = host_matchers(&build_config(&doc)?["example.com"]);
+        let with_cookie = |cookie: &str| Request::get("/").header("cookie", cookie).body(());
+
+        let csrf_only = with_cookie("_oauth2_proxy_csrf=abc; theme=dark")?;
+        assert!(!matches_request("@session", &csrf_only, &matchers));
+        assert!(matches_request("@anonymous", &csrf_only, &matchers));
+        let signed_in = with_cookie("_oauth2_proxy_csrf=abc; _oauth2_proxy=\"token\"")?;
+        assert!(matches_request("@session", &signed_in, &matchers));
+        assert!(!matches_request("@anonymous", &signed_in, &matchers));
+        assert!(matches_request(
+            "@anonymous",
+            &Request::get("/").body(())?,
+            &matchers
+        ));
+
+        assert!(matches_request(
+            "@tracking",
+            &with_cookie("_ga_X1Y2=GS1")?,
+            &matchers
+        ));
+        assert!(!matches_request(
+            "@tracking",
+            &with_cookie("g_a=1")?,
+            &matchers
+        ));
+
+        assert!(matches_request(
+            "@tenant",
+            &with_cookie("tenant_42=eu-west")?,
+            &matchers
+        ));
+        assert!(!matches_request(
+            "@tenant",
+            &with_cookie("tenant_42=us-east")?,
+            &matchers
+        ));
+        assert!(!matches_request(
+            "@tenant",
+            &with_cookie("tenant_x=eu-west")?,
+            &matchers
+        ));
+
+        for invalid in [
+            r#""example.com" { @a { cookie "exact"; }; }"#,
+            r#""example.com" { @a { cookie "suffix" "_ga"; }; }"#,
+            r#""example.com" { @a { cookie "regex" "^(unclosed"; }; }"#,
+        ] {
+            let doc: KdlDocument = invalid.parse()?;
+            assert!(build_config(&doc).is_err(), "{}", invalid);
+        }
+
+        Ok(())
+    }
+}
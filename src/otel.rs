@@ -0,0 +1,462 @@
+use crate::config::{Directive, TracingOptions};
+use crate::error::CbltError;
+use bytes::Bytes;
+use http::header::{CONTENT_TYPE, HOST, USER_AGENT};
+use http::{HeaderMap, Request, StatusCode};
+use http_body_util::Full;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+#[cfg(debug_assertions)]
+use log::error;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, timeout_at, Instant};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+pub const TRACEPARENT: &str = "traceparent";
+const QUEUE: usize = 4096; // spans waiting for export, more are dropped
+const BATCH: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type ExportClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+static CLIENT: OnceLock<ExportClient> = OnceLock::new(); // keeps connections to the collectors
+
+fn client() -> Result<&'static ExportClient, CbltError> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()?
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok(CLIENT.get_or_init(|| Client::builder(TokioExecutor::new()).build(connector)))
+}
+
+/// W3C trace context of a span, as carried by `traceparent`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// Context of the `traceparent` header, none when it is missing or malformed
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(TRACEPARENT)?.to_str().ok()?;
+        let mut fields = value.trim().split('-');
+        let version = fields.next()?;
+        let trace_id = from_hex::<16>(fields.next()?)?;
+        let span_id = from_hex::<8>(fields.next()?)?;
+        let [flags] = from_hex::<1>(fields.next()?)?;
+        // Later versions may append fields, version 00 has exactly four
+        if from_hex::<1>(version)? == [0xff] || (version == "00" && fields.next().is_some()) {
+            return None;
+        }
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(SpanContext {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            to_hex(&self.trace_id),
+            to_hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Lowercase hex of exactly N bytes, as trace context requires
+fn from_hex<const N: usize>(text: &str) -> Option<[u8; N]> {
+    if text.len() != N * 2 || !text.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn random_id<const N: usize>() -> [u8; N] {
+    let mut id = [0u8; N];
+    let _ = aws_lc_rs::rand::fill(&mut id);
+    // An all-zero id is invalid
+    id[N - 1] |= (id == [0; N]) as u8;
+    id
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// Operation being timed, exported when it ends if its trace is sampled
+#[derive(Debug)]
+pub struct Span {
+    tracer: Arc<Tracer>,
+    pub context: SpanContext,
+    parent: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<Value>,
+}
+
+impl Span {
+    /// Span of an operation within this one, in the same trace
+    pub fn child(&self, name: &str, kind: SpanKind) -> Span {
+        Span {
+            tracer: self.tracer.clone(),
+            context: SpanContext {
+                span_id: random_id(),
+                ..self.context
+            },
+            parent: Some(self.context.span_id),
+            name: name.to_string(),
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Adds a string or integer attribute
+    pub fn set_attribute(&mut self, key: &str, value: impl Into<Value>) {
+        let value = match value.into() {
+            Value::Number(number) => json!({ "intValue": number.to_string() }),
+            other => json!({ "stringValue": other.as_str().unwrap_or_default() }),
+        };
+        self.attributes.push(json!({ "key": key, "value": value }));
+    }
+
+    /// Ends the span with the status the operation answered, an error when it is one the
+    /// server is to blame for, or for a client span, any failure
+    pub fn end(mut self, status: StatusCode) {
+        if !self.context.sampled {
+            return;
+        }
+        self.set_attribute("http.response.status_code", status.as_u16());
+        let failed =
+            status.is_server_error() || (self.kind == SpanKind::Client && status.is_client_error());
+        let nanos = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_string()
+        };
+        let mut span = json!({
+            "traceId": to_hex(&self.context.trace_id),
+            "spanId": to_hex(&self.context.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(SystemTime::now()),
+            "attributes": self.attributes,
+            "status": { "code": if failed { 2 } else { 0 } },
+        });
+        if let Some(parent) = self.parent {
+            span["parentSpanId"] = Value::from(to_hex(&parent));
+        }
+        // A collector that cannot keep up loses spans rather than slowing requests down
+        let _ = self.tracer.spans.try_send(span);
+    }
+}
+
+/// Exporter of a host's spans
+#[derive(Debug)]
+pub struct Tracer {
+    sample: f64,
+    spans: mpsc::Sender<Value>,
+}
+
+/// Tracer of the host's `tracing` directive, exporting in the background until it is dropped
+pub fn host_tracer(directives: &[Directive]) -> Option<Arc<Tracer>> {
+    let options = directives.iter().find_map(|directive| match directive {
+        Directive::Tracing { options } => Some(options.clone()),
+        _ => None,
+    })?;
+    let (spans, receiver) = mpsc::channel(QUEUE);
+    let sample = options.sample;
+    tokio::spawn(export(options, receiver));
+    Some(Arc::new(Tracer { sample, spans }))
+}
+
+impl Tracer {
+    /// Server span of a request, continuing the trace of its `traceparent` if any
+    pub fn start<B>(
+        self: &Arc<Self>,
+        request: &Request<B>,
+        addr: SocketAddr,
+        scheme: &str,
+    ) -> Span {
+        let parent = SpanContext::from_headers(request.headers());
+        let context = match parent {
+            Some(parent) => SpanContext {
+                span_id: random_id(),
+                ..parent
+            },
+            None => {
+                let trace_id = random_id();
+                // By trace id, the way OpenTelemetry's ratio sampler decides
+                let ratio = u64::from_be_bytes(trace_id[8..].try_into().unwrap_or_default()) as f64
+                    / u64::MAX as f64;
+                SpanContext {
+                    trace_id,
+                    span_id: random_id(),
+                    sampled: self.sample >= 1.0 || ratio < self.sample,
+                }
+            }
+        };
+        let mut span = Span {
+            tracer: self.clone(),
+            context,
+            parent: parent.map(|parent| parent.span_id),
+            name: request.method().to_string(),
+            kind: SpanKind::Server,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        };
+        span.set_attribute("http.request.method", request.method().as_str());
+        span.set_attribute("url.scheme", scheme);
+        span.set_attribute("url.path", request.uri().path());
+        if let Some(query) = request.uri().query() {
+            span.set_attribute("url.query", query);
+        }
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value: &http::HeaderValue| value.to_str().ok())
+        };
+        if let Some(host) = header(HOST).or(request.uri().host()) {
+            span.set_attribute("server.address", host);
+        }
+        if let Some(user_agent) = header(USER_AGENT) {
+            span.set_attribute("user_agent.original", user_agent);
+        }
+        span.set_attribute("client.address", addr.ip().to_string());
+        let version = format!("{:?}", request.version());
+        span.set_attribute(
+            "network.protocol.version",
+            version.trim_start_matches("HTTP/"),
+        );
+        span
+    }
+}
+
+/// Sends spans to the collector in batches, every few seconds or once a batch is full
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn export(options: TracingOptions, mut receiver: mpsc::Receiver<Value>) {
+    let mut batch = Vec::with_capacity(BATCH);
+    let mut deadline = Instant::now() + EXPORT_INTERVAL;
+    loop {
+        let room = BATCH - batch.len();
+        let received = timeout_at(deadline, receiver.recv_many(&mut batch, room)).await;
+        let closed = matches!(received, Ok(0));
+        if closed || received.is_err() || batch.len() >= BATCH {
+            if !batch.is_empty() {
+                if let Err(_err) = send(&options, std::mem::take(&mut batch)).await {
+                    #[cfg(debug_assertions)]
+                    error!("Error exporting spans to {}: {}", options.endpoint, _err);
+                }
+            }
+            deadline = Instant::now() + EXPORT_INTERVAL;
+        }
+        if closed {
+            break;
+        }
+    }
+}
+
+/// Posts spans as an OTLP/HTTP JSON request
+async fn send(options: &TracingOptions, spans: Vec<Value>) -> Result<(), CbltError> {
+    let body = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{
+                    "key": "service.name",
+                    "value": { "stringValue": options.service_name },
+                }],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "cblt", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    });
+    let request = Request::post(&options.endpoint)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(serde_json::to_vec(&body)?)))?;
+    let response = timeout(EXPORT_TIMEOUT, client()?.request(request))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    if !response.status().is_success() {
+        return Err(CbltError::ResponseError {
+            details: format!("Collector answered {}", response.status()),
+            status_code: response.status(),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Directive, TracingOptions};
+    use crate::otel::{host_tracer, SpanContext, SpanKind, TRACEPARENT};
+    use http::{HeaderMap, HeaderValue, Request, StatusCode};
+    use serde_json::Value;
+    use std::error::Error;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_traceparent() -> Result<(), Box<dyn Error>> {
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_static(traceparent));
+        let context = SpanContext::from_headers(&headers).ok_or("expected a context")?;
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), traceparent);
+
+        for invalid in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+        ] {
+            headers.insert(TRACEPARENT, HeaderValue::from_static(invalid));
+            assert!(SpanContext::from_headers(&headers).is_none(), "{}", invalid);
+        }
+        // A later version may carry more
+        headers.insert(
+            TRACEPARENT,
+            HeaderValue::from_static("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-x"),
+        );
+        let context = SpanContext::from_headers(&headers).ok_or("expected a context")?;
+        assert!(!context.sampled);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_export() -> Result<(), Box<dyn Error>> {
+        let collector = TcpListener::bind("127.0.0.1:0").await?;
+        let tracer = host_tracer(&[Directive::Tracing {
+            options: TracingOptions {
+                endpoint: format!("http://{}/v1/traces", collector.local_addr()?),
+                service_name: "edge".to_string(),
+                sample: 1.0,
+            },
+        }])
+        .ok_or("expected a tracer")?;
+
+        let request = Request::get("/api?id=1")
+            .header("host", "example.com")
+            .header(
+                TRACEPARENT,
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(())?;
+        let span = tracer.start(&request, "192.0.2.1:5000".parse()?, "https");
+        let child = span.child("reverse_proxy", SpanKind::Client);
+        let (server_id, child_id) = (span.context.span_id, child.context.span_id);
+        assert_eq!(child.context.trace_id, span.context.trace_id);
+        child.end(StatusCode::BAD_GATEWAY);
+        span.end(StatusCode::BAD_GATEWAY);
+        // Dropping the tracer flushes what is queued
+        drop(tracer);
+
+        let (mut stream, _) = collector.accept().await?;
+        let mut received = Vec::new();
+        let body = loop {
+            let mut buf = [0u8; 4096];
+            let n = stream.read(&mut buf).await?;
+            assert!(n > 0, "connection closed early");
+            received.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&received).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("content-length: ")
+                            .map(str::to_string)
+                    })
+                    .ok_or("expected a content-length")?
+                    .parse()?;
+                if body.len() >= length {
+                    assert!(head.starts_with("POST /v1/traces HTTP/1.1"));
+                    break body.to_string();
+                }
+            }
+        };
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .await?;
+
+        let body: Value = serde_json::from_str(&body)?;
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "edge"
+        );
+        let spans = resource["scopeSpans"][0]["spans"]
+            .as_array()
+            .ok_or("expected spans")?;
+        assert_eq!(spans.len(), 2);
+        let (client, server) = (&spans[0], &spans[1]);
+        assert_eq!(server["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(server["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(server["spanId"], super::to_hex(&server_id));
+        assert_eq!(server["kind"], 2);
+        assert_eq!(server["name"], "GET");
+        assert_eq!(server["status"]["code"], 2);
+        assert_eq!(client["parentSpanId"], super::to_hex(&server_id));
+        assert_eq!(client["spanId"], super::to_hex(&child_id));
+        assert_eq!(client["kind"], 3);
+        let attribute = |key: &str| {
+            server["attributes"]
+                .as_array()
+                .and_then(|attributes| attributes.iter().find(|a| a["key"] == key))
+                .map(|a| a["value"].clone())
+        };
+        assert_eq!(
+            attribute("url.query"),
+            Some(serde_json::json!({ "stringValue": "id=1" }))
+        );
+        assert_eq!(
+            attribute("http.response.status_code"),
+            Some(serde_json::json!({ "intValue": "502" }))
+        );
+        assert_eq!(
+            attribute("server.address"),
+            Some(serde_json::json!({ "stringValue": "example.com" }))
+        );
+
+        Ok(())
+    }
+}
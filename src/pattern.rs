@@ -0,0 +1,81 @@
+use crate::headers::Placeholders;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, RwLock};
+
+static REGEXES: LazyLock<RwLock<HashMap<String, Option<Arc<Regex>>>>> =
+    LazyLock::new(Default::default); // pattern -> compiled regex, None when invalid
+
+/// Patterns starting with "^" are regular expressions anchored at the start of the path
+pub fn is_regex(pattern: &str) -> bool {
+    pattern.starts_with('^')
+}
+
+/// Compiled once per pattern and shared by every request
+pub fn path_regex(pattern: &str) -> Option<Arc<Regex>> {
+    if let Some(regex) = REGEXES
+        .read()
+        .ok()
+        .and_then(|regexes| regexes.get(pattern).cloned())
+    {
+        return regex;
+    }
+    let regex = Regex::new(pattern).ok().map(Arc::new);
+    if let Ok(mut regexes) = REGEXES.write() {
+        regexes.insert(pattern.to_string(), regex.clone());
+    }
+    regex
+}
+
+/// `{re.1}`, `{re.2}`, ... and `{re.<name>}` of the groups a regex pattern captured in the path
+pub fn capture_placeholders(pattern: &str, path: &str) -> Placeholders {
+    let Some(regex) = is_regex(pattern).then(|| path_regex(pattern)).flatten() else {
+        return Vec::new();
+    };
+    let Some(captures) = regex.captures(path) else {
+        return Vec::new();
+    };
+    let mut placeholders = Vec::new();
+    for (index, name) in regex.capture_names().enumerate().skip(1) {
+        let value = captures
+            .get(index)
+            .map_or("", |group| group.as_str())
+            .to_string();
+        if let Some(name) = name {
+            placeholders.push((format!("{{re.{}}}", name), value.clone()));
+        }
+        placeholders.push((format!("{{re.{}}}", index), value));
+    }
+    placeholders
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::matches_pattern;
+    use crate::pattern::capture_placeholders;
+    use std::error::Error;
+
+    #[test]
+    fn test_regex_patterns() -> Result<(), Box<dyn Error>> {
+        let pattern = r"^/v([0-9]+)/items/(?<id>\d+)$";
+        assert!(matches_pattern(pattern, "/v2/items/42"));
+        assert!(!matches_pattern(pattern, "/v2/items/42/edit"));
+        assert!(!matches_pattern(pattern, "/api/v2/items/42"));
+        assert!(matches_pattern("^/static/", "/static/app.css"));
+        assert!(!matches_pattern("^/(unclosed", "/(unclosed"));
+
+        let placeholders = capture_placeholders(pattern, "/v2/items/42");
+        let value = |name: &str| {
+            placeholders
+                .iter()
+                .find(|(placeholder, _)| placeholder == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(value("{re.1}"), Some("2"));
+        assert_eq!(value("{re.2}"), Some("42"));
+        assert_eq!(value("{re.id}"), Some("42"));
+        assert!(capture_placeholders("/v2/*", "/v2/items").is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,284 @@
+use crate::cidr::contains_ip;
+use crate::config::{Directive, ProxyProtocolOptions, ProxyProtocolVersion};
+use crate::error::CbltError;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+
+/// The first `proxy_protocol` of the hosts on a port applies to the whole listener
+pub fn listener_options(hosts: &HashMap<String, Vec<Directive>>) -> Option<ProxyProtocolOptions> {
+    let mut hostnames: Vec<&String> = hosts.keys().collect();
+    hostnames.sort();
+    hostnames.iter().find_map(|host| {
+        hosts[*host].iter().find_map(|directive| match directive {
+            Directive::ProxyProtocol { options } => Some(options.clone()),
+            _ => None,
+        })
+    })
+}
+
+/// Client address of a connection, taken from its PROXY protocol header when the peer is allowed
+/// to send one
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn accept<S>(
+    stream: &mut S,
+    peer: SocketAddr,
+    options: &ProxyProtocolOptions,
+) -> Result<SocketAddr, CbltError>
+where
+    S: AsyncRead + Unpin,
+{
+    if !options.allow.is_empty() && !contains_ip(&options.allow, &peer.ip()) {
+        return Ok(peer);
+    }
+    timeout(options.timeout, read_header(stream, peer))
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "No PROXY protocol header"))?
+}
+
+/// Reads a v1 or v2 header and nothing past it, `LOCAL` and unknown sources keep the peer address
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn read_header<S>(stream: &mut S, peer: SocketAddr) -> Result<SocketAddr, CbltError>
+where
+    S: AsyncRead + Unpin,
+{
+    // Shorter than either header, so no byte of the connection itself is consumed
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut addresses = vec![0u8; len];
+        stream.read_exact(&mut addresses).await?;
+        return parse_v2(fixed[0], fixed[1], &addresses, peer);
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("No PROXY protocol header"));
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line, peer)
+}
+
+fn parse_v1(line: &[u8], peer: SocketAddr) -> Result<SocketAddr, CbltError> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("Invalid PROXY protocol header"))?;
+    let fields: Vec<&str> = line.trim_end().split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(peer),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("Invalid PROXY protocol source address"))?;
+            let port: u16 = port
+                .parse()
+                .map_err(|_| invalid("Invalid PROXY protocol source port"))?;
+            Ok(SocketAddr::new(ip, port))
+        }
+        _ => Err(invalid("Invalid PROXY protocol header")),
+    }
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+    peer: SocketAddr,
+) -> Result<SocketAddr, CbltError> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(peer), // LOCAL, a health check of the load balancer itself
+        1 => {}
+        _ => return Err(invalid("Unsupported PROXY protocol command")),
+    }
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[0..4].try_into().unwrap_or_default();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[0..16].try_into().unwrap_or_default();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        1 | 2 => Err(invalid("Truncated PROXY protocol addresses")),
+        _ => Ok(peer), // unspecified or Unix sockets
+    }
+}
+
+/// Header telling a backend reached at `backend` who the client is, `LOCAL` without a client
+pub fn header(
+    version: ProxyProtocolVersion,
+    client: Option<SocketAddr>,
+    backend: SocketAddr,
+) -> Vec<u8> {
+    let Some(client) = client else {
+        return match version {
+            ProxyProtocolVersion::V1 => b"PROXY UNKNOWN\r\n".to_vec(),
+            ProxyProtocolVersion::V2 => [&V2_SIGNATURE[..], &[0x20, 0x00, 0, 0]].concat(),
+        };
+    };
+    // Both addresses have to be of one family, IPv4 ones are mapped when they differ
+    let (source, destination) = match (client.ip().to_canonical(), backend.ip().to_canonical()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            (IpAddr::V4(source), IpAddr::V4(destination))
+        }
+        (source, destination) => (IpAddr::V6(to_v6(source)), IpAddr::V6(to_v6(destination))),
+    };
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source,
+                destination,
+                client.port(),
+                backend.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let (family, len) = if source.is_ipv4() {
+                (0x11, 12u16)
+            } else {
+                (0x21, 36u16)
+            };
+            header.extend_from_slice(&[0x21, family]);
+            header.extend_from_slice(&len.to_be_bytes());
+            for ip in [source, destination] {
+                match ip {
+                    IpAddr::V4(ip) => header.extend_from_slice(&ip.octets()),
+                    IpAddr::V6(ip) => header.extend_from_slice(&ip.octets()),
+                }
+            }
+            header.extend_from_slice(&client.port().to_be_bytes());
+            header.extend_from_slice(&backend.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid(details: &str) -> CbltError {
+    Error::new(ErrorKind::InvalidData, details).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ProxyProtocolOptions, ProxyProtocolVersion};
+    use crate::proxy_protocol::{accept, header, V2_SIGNATURE};
+    use std::error::Error;
+    use std::net::SocketAddr;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_proxy_protocol() -> Result<(), Box<dyn Error>> {
+        let peer: SocketAddr = "10.0.0.1:40000".parse()?;
+        let options = ProxyProtocolOptions::default();
+
+        let mut stream: &[u8] = b"PROXY TCP4 192.168.0.1 10.0.0.2 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            accept(&mut stream, peer, &options).await?,
+            "192.168.0.1:56324".parse()?
+        );
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).await?;
+        assert_eq!(rest, "GET / HTTP/1.1\r\n");
+
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::7 2001:db8::1 4711 443\r\n";
+        assert_eq!(
+            accept(&mut stream, peer, &options).await?,
+            "[2001:db8::7]:4711".parse()?
+        );
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(accept(&mut stream, peer, &options).await?, peer);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[
+            0x21, 0x11, 0, 12, 203, 0, 113, 9, 10, 0, 0, 2, 0x1f, 0x90, 1, 187,
+        ]);
+        v2.extend_from_slice(b"\x16\x03\x01");
+        let mut stream: &[u8] = &v2;
+        assert_eq!(
+            accept(&mut stream, peer, &options).await?,
+            "203.0.113.9:8080".parse()?
+        );
+        assert_eq!(stream, b"\x16\x03\x01");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        let mut stream: &[u8] = &local;
+        assert_eq!(accept(&mut stream, peer, &options).await?, peer);
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert!(accept(&mut stream, peer, &options).await.is_err());
+
+        // Peers outside `allow` are taken as they are, without a header
+        let options = ProxyProtocolOptions {
+            allow: vec!["192.168.0.0/16".parse()?],
+            ..Default::default()
+        };
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert_eq!(accept(&mut stream, peer, &options).await?, peer);
+        assert_eq!(stream.len(), 16);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proxy_protocol_header() -> Result<(), Box<dyn Error>> {
+        let backend: SocketAddr = "10.0.0.2:8080".parse()?;
+        assert_eq!(
+            header(
+                ProxyProtocolVersion::V1,
+                Some("[::ffff:192.168.0.1]:56324".parse()?),
+                backend
+            ),
+            b"PROXY TCP4 192.168.0.1 10.0.0.2 56324 8080\r\n"
+        );
+        assert_eq!(
+            header(ProxyProtocolVersion::V1, None, backend),
+            b"PROXY UNKNOWN\r\n"
+        );
+
+        // What is sent reads back as the same client
+        let options = ProxyProtocolOptions::default();
+        let peer: SocketAddr = "127.0.0.1:40000".parse()?;
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for client in ["192.168.0.1:56324", "[2001:db8::7]:4711"] {
+                let client: SocketAddr = client.parse()?;
+                let sent = header(version, Some(client), backend);
+                let read = accept(&mut sent.as_slice(), peer, &options).await?;
+                assert_eq!(read.ip().to_canonical(), client.ip());
+                assert_eq!(read.port(), client.port());
+            }
+            let sent = header(version, None, backend);
+            assert_eq!(accept(&mut sent.as_slice(), peer, &options).await?, peer);
+        }
+
+        Ok(())
+    }
+}
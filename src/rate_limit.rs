@@ -0,0 +1,366 @@
+use crate::config::{Directive, RateLimitKey, RateLimitOptions};
+use crate::error::CbltError;
+use crate::reverse_proxy::client_ip;
+use http::Request;
+#[cfg(debug_assertions)]
+use log::error;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use redis::Script;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+const SWEEP_AT: usize = 10_000; // clients tracked before idle ones are dropped
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+const REDIS_RETRY: Duration = Duration::from_secs(5); // in-process limits meanwhile
+
+/// The token bucket of `RateLimiter::take` kept in a hash, timed by the Redis clock so instances
+/// with skewed clocks agree. Returns the milliseconds to wait, 0 when a token was taken.
+const TOKEN_BUCKET: &str = r#"
+local capacity = tonumber(ARGV[1])
+local window = tonumber(ARGV[2])
+local per_ms = capacity / window
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(bucket[1]) or capacity
+local updated = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - updated) * per_ms)
+local wait = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    wait = math.ceil((1 - tokens) / per_ms)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], window)
+return wait
+"#;
+
+static SCRIPT: LazyLock<Script> = LazyLock::new(|| Script::new(TOKEN_BUCKET));
+
+/// Token bucket per client, holding `requests` tokens and refilled over `window`
+pub struct RateLimiter {
+    requests: u32,
+    window: Duration,
+    options: RateLimitOptions,
+    buckets: Mutex<Buckets>,
+    redis: Option<RedisStore>,
+}
+
+/// Buckets shared by every instance using the same Redis
+struct RedisStore {
+    client: redis::Client,
+    connection: OnceCell<ConnectionManager>,
+    prefix: String,                   // "cblt:rate_limit:<host>:<pattern>:"
+    retry_at: Mutex<Option<Instant>>, // Redis is skipped until then after a failure
+}
+
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    sweep_at: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// `prefix` tells the limiters apart in a shared Redis
+    pub fn new(
+        requests: u32,
+        window: Duration,
+        options: RateLimitOptions,
+        prefix: &str,
+    ) -> Result<Self, CbltError> {
+        let redis = match &options.redis {
+            Some(url) => Some(RedisStore {
+                client: redis::Client::open(url.expose())?,
+                connection: OnceCell::new(),
+                prefix: prefix.to_string(),
+                retry_at: Mutex::new(None),
+            }),
+            None => None,
+        };
+        Ok(RateLimiter {
+            requests,
+            window,
+            options,
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                sweep_at: SWEEP_AT,
+            }),
+            redis,
+        })
+    }
+
+    /// Takes a token for the request's client, or tells how long it has to wait for one. Limits
+    /// stay per process while Redis cannot be reached.
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn check<B>(&self, request: &Request<B>, addr: SocketAddr) -> Result<(), Duration> {
+        let key = self.key(request, addr);
+        if let Some(redis) = self.redis.as_ref().filter(|redis| redis.available()) {
+            match redis.take(&key, self.requests, self.window).await {
+                Ok(wait) if wait.is_zero() => return Ok(()),
+                Ok(wait) => return Err(wait),
+                Err(_err) => {
+                    #[cfg(debug_assertions)]
+                    error!("Rate limit store: {}", _err);
+                    redis.failed();
+                }
+            }
+        }
+        self.take(key, Instant::now())
+    }
+
+    fn key<B>(&self, request: &Request<B>, addr: SocketAddr) -> String {
+        if let RateLimitKey::Header(name) = &self.options.key {
+            if let Some(value) = request
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+            {
+                return format!("header:{}", value);
+            }
+        }
+        let ip = client_ip(request.headers(), addr, &self.options.trusted_proxies);
+        format!("ip:{}", ip)
+    }
+
+    fn take(&self, key: String, now: Instant) -> Result<(), Duration> {
+        let capacity = self.requests as f64;
+        let per_second = capacity / self.window.as_secs_f64().max(f64::EPSILON);
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+        if buckets.by_key.len() >= buckets.sweep_at {
+            // A bucket refilled to capacity is the same as none
+            let window = self.window;
+            buckets
+                .by_key
+                .retain(|_, bucket| now.duration_since(bucket.updated) < window);
+            buckets.sweep_at = (buckets.by_key.len() * 2).max(SWEEP_AT);
+        }
+        let bucket = buckets.by_key.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        }
+    }
+}
+
+impl RedisStore {
+    fn available(&self) -> bool {
+        self.retry_at.lock().map_or(true, |retry_at| {
+            retry_at.is_none_or(|at| Instant::now() >= at)
+        })
+    }
+
+    fn failed(&self) {
+        if let Ok(mut retry_at) = self.retry_at.lock() {
+            *retry_at = Some(Instant::now() + REDIS_RETRY);
+        }
+    }
+
+    async fn take(
+        &self,
+        key: &str,
+        requests: u32,
+        window: Duration,
+    ) -> Result<Duration, CbltError> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(Some(REDIS_TIMEOUT))
+                    .set_response_timeout(Some(REDIS_TIMEOUT))
+                    .set_number_of_retries(1);
+                ConnectionManager::new_with_config(self.client.clone(), config)
+            })
+            .await?;
+        let wait: u64 = SCRIPT
+            .key(format!("{}{}", self.prefix, key))
+            .arg(requests)
+            .arg(window.as_millis().max(1) as u64)
+            .invoke_async(&mut connection.clone())
+            .await?;
+        Ok(Duration::from_millis(wait))
+    }
+}
+
+/// Limiters of the host's `rate_limit` directives, by pattern
+pub fn host_rate_limiters(
+    host: &str,
+    directives: &[Directive],
+) -> Result<HashMap<String, RateLimiter>, CbltError> {
+    directives
+        .iter()
+        .filter_map(|directive| match directive {
+            Directive::RateLimit {
+                pattern,
+                requests,
+                window,
+                options,
+            } => {
+                let prefix = format!("cblt:rate_limit:{}:{}:", host, pattern);
+                let limiter = RateLimiter::new(*requests, *window, options.clone(), &prefix);
+                Some(limiter.map(|limiter| (pattern.clone(), limiter)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whole seconds for `Retry-After`, at least one
+pub fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs_f64().ceil().max(1.0) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{RateLimitKey, RateLimitOptions};
+    use crate::rate_limit::{retry_after, RateLimiter};
+    use http::Request;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_rate_limiter() -> Result<(), Box<dyn Error>> {
+        let limiter =
+            RateLimiter::new(3, Duration::from_secs(60), RateLimitOptions::default(), "")?;
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.take("ip:10.0.0.1".to_string(), start).is_ok());
+        }
+        let wait = limiter
+            .take("ip:10.0.0.1".to_string(), start)
+            .err()
+            .ok_or("expected the fourth request to be limited")?;
+        assert_eq!(retry_after(wait), 20);
+        assert!(limiter.take("ip:10.0.0.2".to_string(), start).is_ok());
+
+        // A token comes back every 20 seconds
+        let later = start + Duration::from_secs(20);
+        assert!(limiter.take("ip:10.0.0.1".to_string(), later).is_ok());
+        assert!(limiter.take("ip:10.0.0.1".to_string(), later).is_err());
+
+        let options = RateLimitOptions {
+            key: RateLimitKey::Header("x-api-key".to_string()),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::new(1, Duration::from_secs(1), options, "")?;
+        let addr = "10.0.0.1:4000".parse()?;
+        let with_key = |key: &str| Request::get("/").header("x-api-key", key).body(());
+        assert!(limiter.check(&with_key("a")?, addr).await.is_ok());
+        assert!(limiter.check(&with_key("a")?, addr).await.is_err());
+        assert!(limiter.check(&with_key("b")?, addr).await.is_ok());
+        assert!(limiter
+            .check(&Request::get("/").body(())?, addr)
+            .await
+            .is_ok());
+        assert!(limiter
+            .check(&Request::get("/").body(())?, addr)
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    /// Answers EVALSHA like the token bucket script with no refill, anything else with OK
+    async fn fake_redis() -> Result<String, Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("redis://{}/", listener.local_addr()?);
+        let taken = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let taken = taken.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        let count: usize = line[1..].trim().parse().unwrap_or(0);
+                        let mut args = Vec::new();
+                        for _ in 0..count {
+                            line.clear();
+                            let _ = stream.read_line(&mut line).await;
+                            let len: usize = line[1..].trim().parse().unwrap_or(0);
+                            let mut arg = vec![0; len + 2];
+                            let _ = stream.read_exact(&mut arg).await;
+                            arg.truncate(len);
+                            args.push(String::from_utf8_lossy(&arg).to_string());
+                        }
+                        line.clear();
+                        let reply = match args.as_slice() {
+                            [command, _, _, key, requests, window] if command == "EVALSHA" => {
+                                let Ok(mut taken) = taken.lock() else {
+                                    return;
+                                };
+                                let count = taken.entry(key.clone()).or_insert(0);
+                                *count += 1;
+                                let limited = *count > requests.parse().unwrap_or(0);
+                                format!(":{}\r\n", if limited { window } else { "0" })
+                            }
+                            _ => "+OK\r\n".to_string(),
+                        };
+                        let _ = stream.get_mut().write_all(reply.as_bytes()).await;
+                    }
+                });
+            }
+        });
+        Ok(url)
+    }
+
+    #[tokio::test]
+    async fn test_redis_rate_limiter() -> Result<(), Box<dyn Error>> {
+        // Two instances of the proxy share the client's budget
+        let options = RateLimitOptions {
+            redis: Some(fake_redis().await?.into()),
+            ..Default::default()
+        };
+        let prefix = "cblt:rate_limit:example.com:*:";
+        let first = RateLimiter::new(2, Duration::from_secs(30), options.clone(), prefix)?;
+        let second = RateLimiter::new(2, Duration::from_secs(30), options, prefix)?;
+        let addr = "10.0.0.1:4000".parse()?;
+        let request = Request::get("/").body(())?;
+        assert!(first.check(&request, addr).await.is_ok());
+        assert!(second.check(&request, addr).await.is_ok());
+        let wait = second
+            .check(&request, addr)
+            .await
+            .err()
+            .ok_or("expected the third request to be limited")?;
+        assert_eq!(retry_after(wait), 30);
+        assert!(first
+            .check(&request, "10.0.0.2:4000".parse()?)
+            .await
+            .is_ok());
+
+        // Without Redis each instance limits on its own
+        let options = RateLimitOptions {
+            redis: Some("redis://127.0.0.1:1/".into()),
+            ..Default::default()
+        };
+        let unreachable = RateLimiter::new(1, Duration::from_secs(30), options, prefix)?;
+        assert!(unreachable.check(&request, addr).await.is_ok());
+        assert!(unreachable.check(&request, addr).await.is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use crate::cidr::contains_ip;
+use crate::config::{Directive, IpAction};
+use crate::reverse_proxy::client_ip;
+use crate::server::HostDetails;
+use http::Request;
+use std::net::SocketAddr;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Whether the `remote_ip` and `country` rules of the host that take the request let the client
+/// through. A denied client is refused wherever the rule is declared, and once a rule allows some
+/// clients only those pass.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn allowed<B>(host: &HostDetails, request: &Request<B>, addr: SocketAddr) -> bool {
+    let mut allow_rules = false;
+    let mut allowed = false;
+    let mut country = None; // looked up once, by the first country rule
+    for directive in &host.directives {
+        let (action, listed) = match directive {
+            Directive::RemoteIp {
+                pattern,
+                action,
+                ranges,
+                trusted_proxies,
+            } => {
+                if !host.matches(pattern, request) {
+                    continue;
+                }
+                let client = client_ip(request.headers(), addr, trusted_proxies);
+                (action, contains_ip(ranges, &client))
+            }
+            Directive::Country {
+                pattern,
+                action,
+                countries,
+            } => {
+                if !host.matches(pattern, request) {
+                    continue;
+                }
+                let country = country.get_or_insert_with(|| host.country(request, addr));
+                let listed = country
+                    .as_ref()
+                    .is_some_and(|country| countries.contains(country));
+                (action, listed)
+            }
+            _ => continue,
+        };
+        match action {
+            IpAction::Deny if listed => return false,
+            IpAction::Deny => {}
+            IpAction::Allow => {
+                allow_rules = true;
+                allowed |= listed;
+            }
+        }
+    }
+    allowed || !allow_rules
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::build_config;
+    use crate::geoip::host_geoip;
+    use crate::geoip::tests::write_database;
+    use crate::matcher::host_matchers;
+    use crate::remote_ip::allowed;
+    use crate::server::HostDetails;
+    use http::Request;
+    use kdl::KdlDocument;
+    use std::collections::HashMap;
+    use std::error::Error;
+
+    #[test]
+    fn test_remote_ip() -> Result<(), Box<dyn Error>> {
+        let cblt_file = r#"
+"example.com" {
+    @admin {
+        path "/admin/*"
+    }
+    remote_ip "@admin" "allow" "10.0.0.0/8" {
+        trusted_proxies "172.16.0.0/12"
+    }
+    remote_ip "deny" "10.0.13.0/24" "2001:db8::/32"
+}
+"#;
+        let doc: KdlDocument = cblt_file.parse()?;
+        let directives = build_config(&doc)?
+            .remove("example.com")
+            .unwrap_or_default();
+        let host = HostDetails {
+            matchers: host_matchers(&directives),
+            directives,
+            reverse_proxy_states: HashMap::new(),
+            access_log: None,
+            rate_limiters: HashMap::new(),
+            throttles: HashMap::new(),
+            file_cache: None,
+            dav_locks: None,
+            tracer: None,
+            layer4: None,
+            #[cfg(feature = "wasm")]
+            wasm_plugins: HashMap::new(),
+            geoip: None,
+        };
+        let page = Request::get("/").body(())?;
+        let admin = Request::get("/admin/users").body(())?;
+
+        assert!(allowed(&host, &page, "203.0.113.5:4000".parse()?));
+        assert!(!allowed(&host, &page, "10.0.13.7:4000".parse()?));
+        assert!(!allowed(&host, &page, "[2001:db8::1]:4000".parse()?));
+        assert!(allowed(&host, &admin, "10.1.2.3:4000".parse()?));
+        assert!(allowed(&host, &admin, "[::ffff:10.1.2.3]:4000".parse()?));
+        assert!(!allowed(&host, &admin, "203.0.113.5:4000".parse()?));
+        assert!(!allowed(&host, &admin, "10.0.13.7:4000".parse()?));
+
+        // Behind a trusted proxy the client is the last hop it names
+        let proxy = "172.16.0.2:4000".parse()?;
+        let forwarded = |chain: &str| {
+            Request::get("/admin/users")
+                .header("x-forwarded-for", chain)
+                .body(())
+        };
+        assert!(allowed(&host, &forwarded("203.0.113.5, 10.1.2.3")?, proxy));
+        assert!(!allowed(&host, &forwarded("10.1.2.3, 203.0.113.5")?, proxy));
+        assert!(!allowed(&host, &forwarded("10.1.2.3, unknown")?, proxy));
+        let rfc7239 = Request::get("/admin/users")
+            .header("forwarded", "for=10.1.2.3;proto=https")
+            .body(())?;
+        assert!(allowed(&host, &rfc7239, proxy));
+        // An untrusted client naming an allowed address is still itself
+        assert!(!allowed(
+            &host,
+            &forwarded("10.1.2.3")?,
+            "203.0.113.5:4000".parse()?
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_country_rules() -> Result<(), Box<dyn Error>> {
+        let database = write_database(&[("81.0.0.0/8", "DE"), ("5.0.0.0/8", "RU")])?;
+        let cblt_file = format!(
+            r#"
+"example.com" {{
+    geoip "{}"
+    country "deny" "ru" "KP"
+    country "/admin/*" "allow" "DE"
+    header "*" "X-Country" "{{geoip.country}}"
+}}
+"#,
+            database.display()
+        );
+        let doc: KdlDocument = cblt_file.parse()?;
+        let directives = build_config(&doc)?
+            .remove("example.com")
+            .unwrap_or_default();
+        let host = HostDetails {
+            matchers: host_matchers(&directives),
+            geoip: host_geoip(&directives)?,
+            directives,
+            reverse_proxy_states: HashMap::new(),
+            access_log: None,
+            rate_limiters: HashMap::new(),
+            throttles: HashMap::new(),
+            file_cache: None,
+            dav_locks: None,
+            tracer: None,
+            layer4: None,
+            #[cfg(feature = "wasm")]
+            wasm_plugins: HashMap::new(),
+        };
+        let page = Request::get("/").body(())?;
+        let admin = Request::get("/admin/").body(())?;
+
+        assert!(allowed(&host, &page, "81.1.1.1:4000".parse()?));
+        assert!(allowed(&host, &page, "1.1.1.1:4000".parse()?));
+        assert!(!allowed(&host, &page, "5.5.5.5:4000".parse()?));
+        assert!(allowed(&host, &admin, "81.1.1.1:4000".parse()?));
+        assert!(!allowed(&host, &admin, "1.1.1.1:4000".parse()?));
+        assert_eq!(
+            host.geoip_placeholders(&page, "81.1.1.1:4000".parse()?),
+            vec![("{geoip.country}".to_string(), "DE".to_string())]
+        );
+        assert_eq!(
+            host.geoip_placeholders(&page, "1.1.1.1:4000".parse()?),
+            vec![("{geoip.country}".to_string(), String::new())]
+        );
+
+        std::fs::remove_file(database)?;
+        Ok(())
+    }
+}
@@ -1,60 +1,118 @@
+use crate::config::{Directive, RequestLimitOptions};
 use crate::error::CbltError;
+use crate::throttle::{Bandwidth, Throttled};
 use bytes::BytesMut;
-use http::Version;
+use http::header::{CONNECTION, CONTENT_LENGTH, EXPECT, TRANSFER_ENCODING};
+use http::{HeaderValue, Version};
 use http::{Request, StatusCode};
 use httparse::Status;
 use log::error;
+use std::collections::HashMap;
 use std::str;
+use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{timeout_at, Instant};
 #[cfg(feature = "trace")]
 use tracing::instrument;
 
 pub const BUF_SIZE: usize = 8192;
 pub const HEADER_BUF_SIZE: usize = 32;
+
+/// Bandwidth for the body of a request, chosen from its head
+pub type UploadThrottle<'a> = &'a (dyn Fn(&Request<BytesMut>) -> Option<Arc<Bandwidth>> + Sync);
+
+/// The first `request_limits` of the hosts on a port applies to the whole listener
+pub fn listener_limits(hosts: &HashMap<String, Vec<Directive>>) -> RequestLimitOptions {
+    let mut hostnames: Vec<&String> = hosts.keys().collect();
+    hostnames.sort();
+    hostnames
+        .iter()
+        .find_map(|host| {
+            hosts[*host].iter().find_map(|directive| match directive {
+                Directive::RequestLimits { options } => Some(options.clone()),
+                _ => None,
+            })
+        })
+        .unwrap_or_default()
+}
+
+/// Reads the next request, its head within `header_timeout` of the call and the whole of it
+/// within `request_timeout`, so a client trickling bytes cannot hold the connection
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn socket_to_request<S>(
     socket: &mut S,
     mut buf: &mut BytesMut,
+    limits: &RequestLimitOptions,
+    upload: UploadThrottle<'_>,
 ) -> Result<Request<BytesMut>, CbltError>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
+    let started = Instant::now();
+    let request_deadline = limits.request_timeout.map(|timeout| started + timeout);
+    let header_deadline = started + limits.header_timeout;
+    let header_deadline = request_deadline.map_or(header_deadline, |end| end.min(header_deadline));
+    let timed_out = || CbltError::RequestError {
+        details: "Request timed out".to_string(),
+        status_code: StatusCode::REQUEST_TIMEOUT,
+    };
+    let too_large = || CbltError::RequestError {
+        details: "Request header too large".to_string(),
+        status_code: StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+    };
     loop {
-        let bytes_read = socket.read_buf(&mut buf).await.unwrap_or(0);
-        if bytes_read == 0 {
-            break;
-        }
-        // Try to parse the headers
-        let mut headers = [httparse::EMPTY_HEADER; HEADER_BUF_SIZE];
-        let mut req = httparse::Request::new(&mut headers);
-
-        match req.parse(buf) {
-            Ok(Status::Complete(header_len)) => {
-                let (request, _) = match parse_request_headers(header_len, buf, socket).await? {
-                    Some((req, content_length)) => (req, content_length),
-                    None => {
-                        return Err(CbltError::RequestError {
-                            details: "Bad request".to_string(),
-                            status_code: StatusCode::BAD_REQUEST,
-                        });
-                    }
-                };
+        if !buf.is_empty() {
+            // Try to parse the headers
+            let mut headers = [httparse::EMPTY_HEADER; HEADER_BUF_SIZE];
+            let mut req = httparse::Request::new(&mut headers);
 
-                // #[cfg(debug_assertions)]
-                // debug!("{:?}", request);
-                return Ok(request);
-            }
-            Ok(Status::Partial) => {
-                // Need to read more data
-                continue;
-            }
-            Err(err) => {
-                return Err(CbltError::RequestError {
-                    details: err.to_string(),
-                    status_code: StatusCode::BAD_REQUEST,
-                });
+            match req.parse(buf) {
+                Ok(Status::Complete(header_len)) if header_len > limits.max_header_size => {
+                    return Err(too_large());
+                }
+                Ok(Status::Complete(header_len)) => {
+                    let parse = parse_request_headers(header_len, buf, socket, limits, upload);
+                    let parsed = match request_deadline {
+                        Some(deadline) => {
+                            timeout_at(deadline, parse).await.map_err(|_| timed_out())?
+                        }
+                        None => parse.await,
+                    };
+                    let request = match parsed? {
+                        Some(request) => request,
+                        None => {
+                            return Err(CbltError::RequestError {
+                                details: "Bad request".to_string(),
+                                status_code: StatusCode::BAD_REQUEST,
+                            });
+                        }
+                    };
+
+                    // #[cfg(debug_assertions)]
+                    // debug!("{:?}", request);
+                    return Ok(request);
+                }
+                Ok(Status::Partial) if buf.len() > limits.max_header_size => {
+                    return Err(too_large());
+                }
+                Ok(Status::Partial) => {
+                    // Need to read more data
+                }
+                Err(err) => {
+                    return Err(CbltError::RequestError {
+                        details: err.to_string(),
+                        status_code: StatusCode::BAD_REQUEST,
+                    });
+                }
             }
         }
+        let bytes_read = timeout_at(header_deadline, socket.read_buf(&mut buf))
+            .await
+            .map_err(|_| timed_out())?
+            .unwrap_or(0);
+        if bytes_read == 0 {
+            break;
+        }
     }
 
     Err(CbltError::ResponseError {
@@ -68,7 +126,9 @@ pub async fn parse_request_headers<S>(
     header_len: usize,
     buf: &mut BytesMut,
     socket: &mut S,
-) -> Result<Option<(Request<BytesMut>, Option<usize>)>, CbltError>
+    limits: &RequestLimitOptions,
+    upload: UploadThrottle<'_>,
+) -> Result<Option<Request<BytesMut>>, CbltError>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
@@ -106,6 +166,8 @@ where
             let mut builder = Request::builder().method(method).uri(path).version(version);
 
             let mut content_length_opt = None;
+            let mut codings = Vec::new();
+            let mut expect_continue = false;
 
             for header in req.headers.iter() {
                 let name = header.name;
@@ -113,31 +175,97 @@ where
                 builder = builder.header(name, value);
 
                 if name.eq_ignore_ascii_case("Content-Length") {
-                    if let Ok(s) = std::str::from_utf8(value) {
-                        if let Ok(len) = s.trim().parse::<usize>() {
-                            content_length_opt = Some(len);
-                        }
-                    }
+                    // A length other servers could read differently lets requests be smuggled
+                    let len = std::str::from_utf8(value)
+                        .ok()
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()))
+                        .and_then(|s| s.parse::<usize>().ok())
+                        .filter(|len| content_length_opt.is_none_or(|first| first == *len))
+                        .ok_or_else(|| CbltError::RequestError {
+                            details: "Invalid Content-Length".to_string(),
+                            status_code: StatusCode::BAD_REQUEST,
+                        })?;
+                    content_length_opt = Some(len);
+                } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+                    let value = String::from_utf8_lossy(value).to_ascii_lowercase();
+                    codings.extend(
+                        value
+                            .split(',')
+                            .map(|coding| coding.trim().to_string())
+                            .filter(|coding| !coding.is_empty()),
+                    );
+                } else if name.eq_ignore_ascii_case("Expect") {
+                    expect_continue = value.eq_ignore_ascii_case(b"100-continue");
                 }
             }
 
-            if let Some(content_length) = content_length_opt {
-                let mut body = buf.split_off(header_len);
+            // Framing any other server could read differently lets requests be smuggled, so a body
+            // is only ever chunked or of a length
+            let chunked = !codings.is_empty();
+            if chunked {
+                if codings.iter().any(|coding| coding != "chunked") {
+                    return Err(CbltError::RequestError {
+                        details: "Unsupported Transfer-Encoding".to_string(),
+                        status_code: StatusCode::NOT_IMPLEMENTED,
+                    });
+                }
+                if codings.len() > 1 || content_length_opt.is_some() {
+                    return Err(CbltError::RequestError {
+                        details: "Invalid Transfer-Encoding".to_string(),
+                        status_code: StatusCode::BAD_REQUEST,
+                    });
+                }
+            }
+
+            // Leave anything after this request in the buffer for the next one
+            let _ = buf.split_to(header_len);
+
+            if content_length_opt.is_some_and(|len| len > limits.max_body_size) {
+                return Err(body_too_large());
+            }
+
+            let mut request = match builder.body(BytesMut::new()) {
+                Ok(request) => request,
+                Err(_) => return Ok(None),
+            };
+            let socket = &mut Throttled::new(socket, None, upload(&request));
 
-                while body.len() < content_length {
-                    let bytes_read = socket.read_buf(&mut body).await.unwrap_or(0);
-                    if bytes_read == 0 {
-                        break;
+            let body_pending = chunked || content_length_opt.is_some_and(|len| buf.len() < len);
+            if expect_continue && body_pending {
+                socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+                socket.flush().await?;
+            }
+
+            let body = if chunked {
+                read_chunked_body(socket, buf, limits).await?
+            } else if let Some(content_length) = content_length_opt {
+                while buf.len() < content_length {
+                    if socket.read_buf(buf).await.unwrap_or(0) == 0 {
+                        return Err(CbltError::RequestError {
+                            details: "Unexpected end of body".to_string(),
+                            status_code: StatusCode::BAD_REQUEST,
+                        });
                     }
                 }
-
-                Ok(builder.body(body).ok().map(|req| (req, content_length_opt)))
+                buf.split_to(content_length)
             } else {
-                Ok(builder
-                    .body(BytesMut::new())
-                    .ok()
-                    .map(|req| (req, content_length_opt)))
+                BytesMut::new()
+            };
+
+            *request.body_mut() = body;
+            if chunked {
+                // The body is de-chunked, so describe it by length when forwarding it
+                let content_length = request.body().len();
+                request.headers_mut().remove(TRANSFER_ENCODING);
+                request
+                    .headers_mut()
+                    .insert(CONTENT_LENGTH, HeaderValue::from(content_length));
             }
+            if expect_continue {
+                request.headers_mut().remove(EXPECT);
+            }
+            Ok(Some(request))
         }
         Ok(Status::Partial) => Ok(None),
         Err(err) => {
@@ -148,39 +276,378 @@ where
     }
 }
 
-#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-pub fn parse_range_header(range_header: &str, file_size: u64) -> Result<(u64, u64), CbltError> {
-    // Expected format: "bytes=START-END"
-    if !range_header.starts_with("bytes=") {
-        return Err(CbltError::ResponseError {
-            details: "Invalid Range header".to_string(),
-            status_code: StatusCode::BAD_REQUEST,
-        });
+fn body_too_large() -> CbltError {
+    CbltError::RequestError {
+        details: "Request body too large".to_string(),
+        status_code: StatusCode::PAYLOAD_TOO_LARGE,
     }
+}
 
-    let range_values = &range_header[6..]; // Remove "bytes="
-    let parts: Vec<&str> = range_values.split('-').collect();
-    if parts.len() != 2 {
-        return Err(CbltError::ResponseError {
-            details: "Invalid Range header format".to_string(),
+/// De-chunks a body, no larger than `max_body_size` in all, its lines no longer than
+/// `max_header_size`
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn read_chunked_body<S>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    limits: &RequestLimitOptions,
+) -> Result<BytesMut, CbltError>
+where
+    S: AsyncReadExt + Unpin,
+{
+    let mut body = BytesMut::new();
+    loop {
+        let line_end = read_line(socket, buf, limits.max_header_size).await?;
+        let size_line = str::from_utf8(&buf[..line_end]).unwrap_or("");
+        let size_str = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_str, 16).map_err(|_| CbltError::RequestError {
+            details: "Invalid chunk size".to_string(),
             status_code: StatusCode::BAD_REQUEST,
-        });
+        })?;
+        let _ = buf.split_to(line_end + 2);
+
+        if size == 0 {
+            // Skip trailers up to the terminating empty line
+            loop {
+                let line_end = read_line(socket, buf, limits.max_header_size).await?;
+                let _ = buf.split_to(line_end + 2);
+                if line_end == 0 {
+                    return Ok(body);
+                }
+            }
+        }
+
+        let end = match body.len().checked_add(size) {
+            Some(len) if len <= limits.max_body_size => size + 2,
+            _ => return Err(body_too_large()),
+        };
+        while buf.len() < end {
+            if socket.read_buf(buf).await? == 0 {
+                return Err(CbltError::RequestError {
+                    details: "Unexpected end of chunked body".to_string(),
+                    status_code: StatusCode::BAD_REQUEST,
+                });
+            }
+        }
+        if &buf[size..end] != b"\r\n" {
+            return Err(CbltError::RequestError {
+                details: "Invalid chunk".to_string(),
+                status_code: StatusCode::BAD_REQUEST,
+            });
+        }
+        body.extend_from_slice(&buf[..size]);
+        let _ = buf.split_to(end);
     }
+}
 
-    let start = parts[0].parse::<u64>().ok();
-    let end = parts[1].parse::<u64>().ok();
-
-    let (start, end) = match (start, end) {
-        (Some(s), Some(e)) if s <= e && e < file_size => (s, e),
-        (Some(s), None) if s < file_size => (s, file_size - 1),
-        (None, Some(e)) if e != 0 => (file_size - e, file_size - 1),
-        _ => {
-            return Err(CbltError::ResponseError {
-                details: "Invalid Range header values".to_string(),
-                status_code: StatusCode::RANGE_NOT_SATISFIABLE,
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn read_line<S>(
+    socket: &mut S,
+    buf: &mut BytesMut,
+    max_len: usize,
+) -> Result<usize, CbltError>
+where
+    S: AsyncReadExt + Unpin,
+{
+    loop {
+        if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+            return Ok(pos);
+        }
+        if buf.len() > max_len {
+            return Err(CbltError::RequestError {
+                details: "Chunk line too long".to_string(),
+                status_code: StatusCode::BAD_REQUEST,
+            });
+        }
+        if socket.read_buf(buf).await? == 0 {
+            return Err(CbltError::RequestError {
+                details: "Unexpected end of chunked body".to_string(),
+                status_code: StatusCode::BAD_REQUEST,
             });
         }
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn is_keep_alive(request: &Request<BytesMut>) -> bool {
+    let connection = request
+        .headers()
+        .get(CONNECTION)
+        .and_then(|value| value.to_str().ok());
+    let has_token = |token: &str| {
+        connection
+            .map(|value| {
+                value
+                    .split(',')
+                    .any(|t| t.trim().eq_ignore_ascii_case(token))
+            })
+            .unwrap_or(false)
     };
 
-    Ok((start, end))
+    match request.version() {
+        Version::HTTP_10 => has_token("keep-alive"),
+        _ => !has_token("close"),
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn parse_range_header(
+    range_header: &str,
+    file_size: u64,
+) -> Result<Option<(u64, u64)>, CbltError> {
+    // Expected format: "bytes=START-END", other units are ignored
+    let range_values = match range_header.trim().strip_prefix("bytes=") {
+        Some(range_values) => range_values.trim(),
+        None => return Ok(None),
+    };
+    // Multiple ranges are answered with the whole file
+    if range_values.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = match range_values.split_once('-') {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+
+    let parse_position = |value: &str| -> Result<Option<u64>, ()> {
+        let value = value.trim();
+        if value.is_empty() {
+            Ok(None)
+        } else {
+            value.parse::<u64>().map(Some).map_err(|_| ())
+        }
+    };
+    let (start, end) = match (parse_position(start), parse_position(end)) {
+        (Ok(start), Ok(end)) => (start, end),
+        // A syntactically invalid range is ignored
+        _ => return Ok(None),
+    };
+
+    match (start, end) {
+        (None, None) => Ok(None),
+        (Some(s), Some(e)) if s > e => Ok(None),
+        (Some(s), Some(e)) if s < file_size => Ok(Some((s, e.min(file_size - 1)))),
+        (Some(s), None) if s < file_size => Ok(Some((s, file_size - 1))),
+        (None, Some(e)) if e > 0 && file_size > 0 => {
+            Ok(Some((file_size.saturating_sub(e), file_size - 1)))
+        }
+        _ => Err(CbltError::ResponseError {
+            details: "Invalid Range header values".to_string(),
+            status_code: StatusCode::RANGE_NOT_SATISFIABLE,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::RequestLimitOptions;
+    use crate::error::CbltError;
+    use crate::request::{parse_range_header, socket_to_request, BUF_SIZE};
+    use bytes::BytesMut;
+    use http::StatusCode;
+    use std::error::Error;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    fn status(result: Result<impl Sized, CbltError>) -> Option<StatusCode> {
+        match result {
+            Err(CbltError::RequestError { status_code, .. }) => Some(status_code),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_limits() -> Result<(), Box<dyn Error>> {
+        let limits = RequestLimitOptions {
+            header_timeout: Duration::from_millis(200),
+            request_timeout: Some(Duration::from_millis(400)),
+            max_header_size: 1024,
+            max_body_size: 1024,
+            ..RequestLimitOptions::default()
+        };
+
+        // A client that never finishes its head
+        let (mut client, mut server) = tokio::io::duplex(BUF_SIZE);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n")
+            .await?;
+        let mut buf = BytesMut::new();
+        let request = socket_to_request(&mut server, &mut buf, &limits, &|_| None).await;
+        assert_eq!(status(request), Some(StatusCode::REQUEST_TIMEOUT));
+
+        // Nor its body
+        let (mut client, mut server) = tokio::io::duplex(BUF_SIZE);
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 10\r\n\r\nabc")
+            .await?;
+        let mut buf = BytesMut::new();
+        let request = socket_to_request(&mut server, &mut buf, &limits, &|_| None).await;
+        assert_eq!(status(request), Some(StatusCode::REQUEST_TIMEOUT));
+
+        let (mut client, mut server) = tokio::io::duplex(BUF_SIZE);
+        let padding = "a".repeat(2000);
+        client
+            .write_all(format!("GET / HTTP/1.1\r\nX-Padding: {}\r\n", padding).as_bytes())
+            .await?;
+        let mut buf = BytesMut::new();
+        let request = socket_to_request(&mut server, &mut buf, &limits, &|_| None).await;
+        assert_eq!(
+            status(request),
+            Some(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        );
+
+        let (mut client, mut server) = tokio::io::duplex(BUF_SIZE);
+        client
+            .write_all(b"GET /ok HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await?;
+        let mut buf = BytesMut::new();
+        let request = socket_to_request(&mut server, &mut buf, &limits, &|_| None).await?;
+        assert_eq!(request.uri().path(), "/ok");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_body() -> Result<(), Box<dyn Error>> {
+        let limits = RequestLimitOptions {
+            max_body_size: 16,
+            ..RequestLimitOptions::default()
+        };
+        let read = |raw: &'static [u8]| {
+            let limits = limits.clone();
+            async move {
+                let (mut client, mut server) = tokio::io::duplex(BUF_SIZE);
+                client.write_all(raw).await?;
+                // The client is done sending, as if it closed its end
+                drop(client);
+                let mut buf = BytesMut::new();
+                Ok::<_, Box<dyn Error>>(
+                    socket_to_request(&mut server, &mut buf, &limits, &|_| None).await,
+                )
+            }
+        };
+
+        let request = read(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").await??;
+        assert_eq!(&request.body()[..], b"hello");
+        let request = read(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n1;ext\r\n!\r\n0\r\n\r\n",
+        )
+        .await??;
+        assert_eq!(&request.body()[..], b"hello!");
+        assert_eq!(request.headers()["content-length"], "6");
+        assert!(request.headers().get("transfer-encoding").is_none());
+        let request =
+            read(b"POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n")
+                .await??;
+        assert_eq!(&request.body()[..], b"hello");
+
+        for (raw, expected) in [
+            // Bodies ending early
+            (
+                &b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello"[..],
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+                StatusCode::BAD_REQUEST,
+            ),
+            // Lengths read differently elsewhere
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 5x\r\n\r\nhello",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloXX0\r\n\r\n",
+                StatusCode::BAD_REQUEST,
+            ),
+            // Transfer codings read differently elsewhere
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: identity\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+                StatusCode::NOT_IMPLEMENTED,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: xchunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+                StatusCode::NOT_IMPLEMENTED,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\nhello",
+                StatusCode::NOT_IMPLEMENTED,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked, gzip\r\n\r\nhello",
+                StatusCode::NOT_IMPLEMENTED,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n",
+                StatusCode::BAD_REQUEST,
+            ),
+            // Bodies over max_body_size
+            (
+                b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n",
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n",
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            (
+                b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n9\r\n123456789\r\n9\r\n123456789\r\n0\r\n\r\n",
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+        ] {
+            let request = read(raw).await?;
+            assert_eq!(
+                status(request),
+                Some(expected),
+                "{}",
+                String::from_utf8_lossy(raw)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_range_header() {
+        assert_eq!(
+            parse_range_header("bytes=0-499", 1000).ok(),
+            Some(Some((0, 499)))
+        );
+        assert_eq!(
+            parse_range_header("bytes=500-", 1000).ok(),
+            Some(Some((500, 999)))
+        );
+        assert_eq!(
+            parse_range_header("bytes=-200", 1000).ok(),
+            Some(Some((800, 999)))
+        );
+        assert_eq!(
+            parse_range_header("bytes=-5000", 1000).ok(),
+            Some(Some((0, 999)))
+        );
+        assert_eq!(
+            parse_range_header("bytes=900-5000", 1000).ok(),
+            Some(Some((900, 999)))
+        );
+        assert_eq!(parse_range_header("items=0-1", 1000).ok(), Some(None));
+        assert_eq!(parse_range_header("bytes=0-1,5-6", 1000).ok(), Some(None));
+        assert_eq!(parse_range_header("bytes=9-1", 1000).ok(), Some(None));
+        assert!(parse_range_header("bytes=1000-", 1000).is_err());
+        assert!(parse_range_header("bytes=-0", 1000).is_err());
+    }
 }
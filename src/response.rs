@@ -1,66 +1,65 @@
+use crate::body::content_length;
+use crate::compression::{encoded_headers, Codec, EncodedBodyWriter};
+use crate::config::{ErrorPage, HeaderOp};
 use crate::error::CbltError;
-use async_compression::tokio::write::GzipEncoder;
+use crate::file_cache::FileBody;
+use crate::headers::apply_header_ops;
+use crate::request::BUF_SIZE;
+use crate::sendfile::{send_file_body, Sendfile};
 use bytes::BytesMut;
-use http::{Request, Response, StatusCode};
-use log::{debug, info};
-use std::fmt::Debug;
-use std::path::PathBuf;
-use std::pin;
-use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use log::{debug, error, info};
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncSeek, AsyncWriteExt};
 #[cfg(feature = "trace")]
 use tracing::instrument;
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn send_response_file<S>(
     mut socket: S,
-    response: Response<impl AsyncRead + Debug + AsyncWrite>,
+    response: Response<FileBody>,
     req: &Request<BytesMut>,
+    codec: Option<Codec>,
 ) -> Result<(), CbltError>
 where
-    S: AsyncWriteExt + Unpin,
+    S: AsyncWriteExt + Sendfile + Unpin,
 {
-    let (parts, mut b) = response.into_parts();
-    let mut body = pin::pin!(b);
-
-    // Write status line without allocation
-    socket.write_all(b"HTTP/1.1 ").await?;
-    let mut itoa_buf = itoa::Buffer::new();
-    let status_str = itoa_buf.format(parts.status.as_u16());
-    socket.write_all(status_str.as_bytes()).await?;
-    socket.write_all(b" ").await?;
-    socket
-        .write_all(parts.status.canonical_reason().unwrap_or("").as_bytes())
-        .await?;
-    socket.write_all(b"\r\n").await?;
-    let gzip_supported = gzip_support_detect(req);
-    if gzip_supported {
-        // socket.write_all(b"Content-Encoding: gzip").await?;
-        // socket.write_all(b"\r\n").await?;
-    }
+    let (mut parts, body) = response.into_parts();
+    // A ranged body stops short of the end of the file
+    let length = content_length(&parts.headers);
 
-    // Write headers without allocation
-    for (key, value) in parts.headers.iter() {
-        socket.write_all(key.as_str().as_bytes()).await?;
-        socket.write_all(b": ").await?;
-        socket.write_all(value.as_bytes()).await?;
-        socket.write_all(b"\r\n").await?;
+    if let Some(codec) = codec {
+        encoded_headers(&mut parts.headers, codec.encoding);
     }
 
-    // End headers
-    socket.write_all(b"\r\n").await?;
+    write_response_head(&mut socket, parts.status, &parts.headers).await?;
 
     // Ensure all headers are flushed
     socket.flush().await?;
 
-    if gzip_supported {
-        #[cfg(debug_assertions)]
-        debug!("Gzip supported");
-        let gzip_stream = GzipEncoder::new(body);
-        let mut gzip_reader = tokio::io::BufReader::new(gzip_stream);
-        tokio::io::copy(&mut gzip_reader, &mut socket).await?;
-    } else {
-        tokio::io::copy(&mut body, &mut socket).await?;
+    if req.method() == Method::HEAD {
+        return Ok(());
+    }
+
+    match codec {
+        Some(codec) => {
+            #[cfg(debug_assertions)]
+            debug!("Encoding file with {}", codec.encoding.as_str());
+            let mut body = body.take(length.unwrap_or(u64::MAX));
+            let mut writer = EncodedBodyWriter::new(&mut socket, codec);
+            let mut buf = BytesMut::with_capacity(BUF_SIZE);
+            loop {
+                buf.clear();
+                if body.read_buf(&mut buf).await? == 0 {
+                    break;
+                }
+                writer.write(&buf, false).await?;
+            }
+            writer.finish(&HeaderMap::new()).await?;
+        }
+        None => send_file_body(&mut socket, body, length).await?,
     }
 
     // Ensure all data is flushed
@@ -70,12 +69,44 @@ where
 }
 
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-pub async fn ranged_file_response(
-    file: File,
-    file_path: &PathBuf,
+pub async fn write_response_head<S>(
+    socket: &mut S,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> Result<(), CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+{
+    let mut head = Vec::with_capacity(256);
+    head.extend_from_slice(b"HTTP/1.1 ");
+    let mut itoa_buf = itoa::Buffer::new();
+    head.extend_from_slice(itoa_buf.format(status.as_u16()).as_bytes());
+    head.extend_from_slice(b" ");
+    head.extend_from_slice(status.canonical_reason().unwrap_or("").as_bytes());
+    head.extend_from_slice(b"\r\n");
+
+    for (key, value) in headers.iter() {
+        head.extend_from_slice(key.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+
+    socket.write_all(&head).await?;
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn ranged_file_response<F>(
+    file: F,
+    mime_type: &str,
     file_size: u64,
     range: (u64, u64),
-) -> Result<Response<File>, CbltError> {
+) -> Result<Response<F>, CbltError>
+where
+    F: AsyncSeek + Unpin,
+{
     let (start, end) = range;
     let content_length = end - start + 1;
 
@@ -104,29 +135,15 @@ pub async fn ranged_file_response(
         .push_str(file_size.to_string().as_str())
         .map_err(|_| CbltError::HeaplessError {})?;
 
-    let mime_type = mime_guess::from_path(file_path)
-        .first_or_octet_stream()
-        .to_string();
     Ok(Response::builder()
         .status(StatusCode::PARTIAL_CONTENT)
         .header("Content-Length", content_length)
         .header("Content-Range", content_range.as_str())
         .header("Content-Type", mime_type)
+        .header("Accept-Ranges", "bytes")
         .body(file)?)
 }
 
-#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-fn gzip_support_detect(req_opt: &Request<BytesMut>) -> bool {
-    let accept_encoding = req_opt
-        .headers()
-        .get(http::header::ACCEPT_ENCODING)
-        .and_then(|value| value.to_str().ok());
-
-    accept_encoding
-        .map(|encodings| encodings.contains("gzip"))
-        .unwrap_or(false)
-}
-
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub fn log_request_response(request: &Request<BytesMut>, status_code: StatusCode) {
     let method = &request.method();
@@ -173,15 +190,108 @@ where
         resp_bytes.extend_from_slice(value.as_bytes());
         resp_bytes.extend_from_slice(b"\r\n");
     }
+    // Keep-alive clients need the body length to find the end of the response
+    let bodiless = parts.status.is_informational()
+        || parts.status == StatusCode::NO_CONTENT
+        || parts.status == StatusCode::NOT_MODIFIED;
+    if !bodiless && !parts.headers.contains_key(CONTENT_LENGTH) {
+        let mut itoa_buf = itoa::Buffer::new();
+        resp_bytes.extend_from_slice(b"content-length: ");
+        resp_bytes.extend_from_slice(itoa_buf.format(body.len()).as_bytes());
+        resp_bytes.extend_from_slice(b"\r\n");
+    }
 
     resp_bytes.extend_from_slice(b"\r\n");
     resp_bytes.extend_from_slice(&body);
 
     socket.write_all(&resp_bytes).await?;
+    socket.flush().await?;
 
     Ok(())
 }
 
+/// Edits the headers of the response going out with its status
+pub type ResponseFilter = Arc<dyn Fn(StatusCode, &mut HeaderMap) + Send + Sync>;
+
+/// Headers every response to a request gets: `set` ones replace what the handler produced, then
+/// the `header` directives edit the result, then the filters of handlers
+#[derive(Default)]
+pub struct ExtraHeaders {
+    pub set: HeaderMap,
+    pub operations: Vec<HeaderOp>, // placeholders filled in
+    pub filters: Vec<ResponseFilter>,
+}
+
+impl ExtraHeaders {
+    pub fn apply(&self, status: StatusCode, headers: &mut HeaderMap) {
+        for (key, value) in self.set.iter() {
+            headers.insert(key.clone(), value.clone());
+        }
+        apply_header_ops(headers, &self.operations);
+        for filter in &self.filters {
+            filter(status, headers);
+        }
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn with_headers<T>(mut response: Response<T>, extra_headers: &ExtraHeaders) -> Response<T> {
+    let status = response.status();
+    extra_headers.apply(status, response.headers_mut());
+    response
+}
+
+/// Error response using a custom page configured for the status, if any
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn custom_error_response(
+    status: StatusCode,
+    error_pages: &[(&[String], &ErrorPage)],
+) -> Result<Response<BytesMut>, CbltError> {
+    let page = error_pages.iter().find_map(|(statuses, page)| {
+        statuses
+            .iter()
+            .any(|pattern| status_matches(pattern, status))
+            .then_some(*page)
+    });
+    let (body, content_type) = match page {
+        None => return error_response(status),
+        Some(ErrorPage::File(path)) => match tokio::fs::read(path).await {
+            Ok(body) => {
+                let content_type = mime_guess::from_path(path)
+                    .first_or_octet_stream()
+                    .to_string();
+                (BytesMut::from(&body[..]), content_type)
+            }
+            Err(err) => {
+                #[cfg(debug_assertions)]
+                error!("Error page {}: {}", path, err);
+                return error_response(status);
+            }
+        },
+        Some(ErrorPage::Inline(template)) => {
+            let body = template
+                .replace("{status}", status.as_str())
+                .replace("{reason}", status.canonical_reason().unwrap_or(""));
+            (
+                BytesMut::from(body.as_bytes()),
+                "text/html; charset=utf-8".to_string(),
+            )
+        }
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(body)?)
+}
+
+/// "404" matches a single status, "5xx" the whole class
+pub fn status_matches(pattern: &str, status: StatusCode) -> bool {
+    match pattern.strip_suffix("xx") {
+        Some(class) => status.as_str().starts_with(class),
+        None => pattern == status.as_str(),
+    }
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub fn error_response(status: StatusCode) -> Result<Response<BytesMut>, CbltError> {
     let msg = match status {
@@ -189,6 +299,9 @@ pub fn error_response(status: StatusCode) -> Result<Response<BytesMut>, CbltErro
         StatusCode::FORBIDDEN => "Forbidden",
         StatusCode::NOT_FOUND => "Not found",
         StatusCode::METHOD_NOT_ALLOWED => "Method not allowed",
+        StatusCode::REQUEST_TIMEOUT => "Request timeout",
+        StatusCode::RANGE_NOT_SATISFIABLE => "Range not satisfiable",
+        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE => "Request header fields too large",
         StatusCode::INTERNAL_SERVER_ERROR => "Internal server error",
         StatusCode::BAD_GATEWAY => "Bad gateway",
         _ => "Unknown error",
@@ -1,220 +1,1006 @@
-use crate::{matches_pattern, CbltError};
-use bytes::BytesMut;
-use http::{Request, StatusCode};
+use crate::body::{BodyKind, BodyReader, BodyWriter};
+use crate::cache::{Cached, Capture, Lookup, ProxyCache, CACHE_STATUS};
+use crate::cgi::{self, backend_protocol, Protocol};
+use crate::cidr::{contains_ip, Cidr};
+use crate::compression::{
+    add_vary, encoded_headers, is_compressible, negotiate, EncodedBodyWriter,
+};
+use crate::discovery::{self, consul_service, upstream_host};
+use crate::headers::{apply_header_ops, fill_placeholders, header_placeholders, Placeholders};
+use crate::health;
+use crate::matcher::{matches_all, request_cookies};
+use crate::pattern::capture_placeholders;
+use crate::proxy_protocol;
+use crate::request::BUF_SIZE;
+use crate::response::{send_response, with_headers, write_response_head, ExtraHeaders};
+use crate::server::HostDetails;
+use crate::tls::UpstreamTls;
+use crate::CbltError;
+use bytes::{Bytes, BytesMut};
+use h2::client::SendRequest;
+use http::header::{
+    CONNECTION, CONTENT_TYPE, HOST, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE,
+    IF_UNMODIFIED_SINCE, RANGE, SET_COOKIE, TRANSFER_ENCODING, UPGRADE,
+};
+use http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, Uri, Version};
 use log::debug;
 use log::error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 #[cfg(feature = "trace")]
 use tracing::instrument;
 pub const HEAPLESS_STRING_SIZE: usize = 100;
+const MAX_MIRRORS_IN_FLIGHT: usize = 256;
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(30); // when the proxy sets no `timeout`
 
+#[allow(clippy::too_many_arguments)]
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 pub async fn proxy_directive<S>(
     request: &Request<BytesMut>,
     socket: &mut S,
-    states: &HashMap<String, ReverseProxyState>,
+    client_buf: &mut BytesMut, // bytes the client sent after the request
+    host: &HostDetails,
     addr: SocketAddr,
     directive: &Directive,
-) -> Result<StatusCode, CbltError>
+    extra_headers: &ExtraHeaders,
+    encode: Option<&EncodeOptions>,
+    scheme: &str,
+) -> Result<(StatusCode, bool), CbltError>
 where
     S: AsyncReadExt + AsyncWriteExt + Unpin,
 {
-    let options = match directive {
+    let (pattern, options) = match directive {
         Directive::ReverseProxy {
-            pattern: _,
+            pattern,
             destinations: _,
             options,
-        } => options,
+        } => (pattern, options),
         _ => {
             return Err(CbltError::DirectiveNotMatched);
         }
     };
-    for (pattern, reverse_proxy_state) in states {
-        if matches_pattern(pattern, request.uri().path()) {
-            loop {
-                match reverse_proxy_state.get_next_backend(addr).await {
-                    Ok(backend) => {
-                        #[cfg(debug_assertions)]
-                        debug!("Selected backend: {:?}", backend);
-                        let mut dest_uri: heapless::String<{ 2 * HEAPLESS_STRING_SIZE }> =
-                            heapless::String::new();
-                        dest_uri
-                            .push_str(backend.address.as_str())
-                            .map_err(|_| CbltError::HeaplessError {})?;
-                        dest_uri
-                            .push_str(request.uri().path())
-                            .map_err(|_| CbltError::HeaplessError {})?;
-
-                        #[cfg(debug_assertions)]
-                        debug!("Destination URI: {}", dest_uri);
-
-                        // Parse the destination URI
-                        let dest_uri_parsed = dest_uri.parse::<http::Uri>().map_err(|e| {
-                            CbltError::ResponseError {
-                                details: e.to_string(),
-                                status_code: StatusCode::BAD_GATEWAY,
-                            }
-                        })?;
-                        let host = dest_uri_parsed.host().ok_or(CbltError::ResponseError {
-                            details: "Invalid destination URI".to_string(),
-                            status_code: StatusCode::BAD_GATEWAY,
-                        })?;
-                        let port = dest_uri_parsed.port_u16().unwrap_or_else(|| {
-                            if dest_uri_parsed.scheme_str() == Some("https") {
-                                443
-                            } else {
-                                80
-                            }
-                        });
-                        let mut backend_addr: heapless::String<{ HEAPLESS_STRING_SIZE * 2 }> =
-                            heapless::String::new();
-                        backend_addr
-                            .push_str(host)
-                            .map_err(|_| CbltError::HeaplessError {})?;
-                        backend_addr
-                            .push_str(":")
-                            .map_err(|_| CbltError::HeaplessError {})?;
-                        backend_addr
-                            .push_str(port.to_string().as_str())
-                            .map_err(|_| CbltError::HeaplessError {})?;
-                        #[cfg(debug_assertions)]
-                        debug!("Connecting to backend at {}", backend_addr);
-
-                        // Establish a TCP connection to the backend with retries
-                        let timeout_duration = Duration::from_secs(options.lb_timeout);
-                        let mut retries = options.lb_retries;
-                        let mut backend_stream_result = Err(CbltError::ResponseError {
-                            details: "Failed to connect to backend".to_string(),
-                            status_code: StatusCode::BAD_GATEWAY,
-                        });
-
-                        while retries > 0 {
-                            match timeout(
-                                timeout_duration,
-                                TcpStream::connect(backend_addr.as_str()),
-                            )
-                            .await
-                            {
-                                Ok(connect_result) => match connect_result {
-                                    Ok(stream) => {
-                                        backend_stream_result = Ok(stream);
-                                        break;
-                                    }
-                                    Err(e) => {
-                                        #[cfg(debug_assertions)]
-                                        error!("Failed to connect to backend: {}", e);
-                                        retries -= 1;
-                                    }
-                                },
-                                Err(e) => {
-                                    #[cfg(debug_assertions)]
-                                    error!("Connection to backend timed out: {}", e);
-                                    retries -= 1;
-                                }
+    let Some(reverse_proxy_state) = host
+        .reverse_proxy_states
+        .get(pattern)
+        .filter(|_| host.matches(pattern, request))
+    else {
+        return Err(CbltError::DirectiveNotMatched);
+    };
+    let cache = reverse_proxy_state
+        .cache
+        .as_ref()
+        .filter(|_| !is_upgrade_request(request));
+    if let Some(cache) = cache.filter(|_| request.method() == "PURGE") {
+        if !cache.purge_allowed(&client_ip(
+            request.headers(),
+            addr,
+            &options.trusted_proxies,
+        )) {
+            return Err(CbltError::ResponseError {
+                details: "PURGE not allowed".to_string(),
+                status_code: StatusCode::FORBIDDEN,
+            });
+        }
+        let purged = cache.purge_request(request);
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(BytesMut::from(
+                format!("{{\"purged\":{}}}\n", purged).as_str(),
+            ))?;
+        send_response(socket, with_headers(response, extra_headers)).await?;
+        return Ok((StatusCode::OK, true));
+    }
+    let group = reverse_proxy_state.group(request);
+    let geoip = host.geoip_placeholders(request, addr);
+    if reverse_proxy_state.options.mirror.is_some() && !is_upgrade_request(request) {
+        mirror_request(
+            reverse_proxy_state.clone(),
+            request,
+            pattern,
+            &geoip,
+            addr,
+            scheme,
+        )?;
+    }
+    // Requests of a group neither come from the cache nor go to the canary
+    let fallback = match cache
+        .filter(|_| group.is_none())
+        .map(|cache| cache.lookup(request))
+    {
+        Some(Lookup::Fresh(cached)) => {
+            let cache_status = HeaderValue::from_static("cblt; hit");
+            return send_cached(socket, request, cached, cache_status, extra_headers, encode).await;
+        }
+        Some(Lookup::Stale(cached, refresh)) => {
+            if refresh {
+                refresh_cached(
+                    reverse_proxy_state.clone(),
+                    request,
+                    pattern,
+                    geoip,
+                    addr,
+                    scheme,
+                );
+            }
+            let cache_status = HeaderValue::from_static("cblt; hit; fwd=stale");
+            return send_cached(socket, request, cached, cache_status, extra_headers, encode).await;
+        }
+        Some(Lookup::Miss(fallback)) => fallback,
+        None => None,
+    };
+    // A canary that is down leaves its share to the backends
+    let target = match group {
+        Some(group) => group,
+        None => match reverse_proxy_state.pick_canary().await {
+            Some(canary) => canary,
+            None => reverse_proxy_state,
+        },
+    };
+    let result = proxy_backends(
+        request,
+        socket,
+        client_buf,
+        target,
+        pattern,
+        &geoip,
+        addr,
+        extra_headers,
+        encode,
+        scheme,
+        fallback.is_some(),
+    )
+    .await;
+    match (result, fallback) {
+        // Only failures before the response head went out are ResponseErrors
+        (Err(CbltError::ResponseError { status_code, .. }), Some(stale))
+            if status_code.is_server_error() =>
+        {
+            let cache_status = HeaderValue::from_str(&format!(
+                "cblt; fwd=stale; fwd-status={}",
+                status_code.as_u16()
+            ))?;
+            send_cached(socket, request, stale, cache_status, extra_headers, encode).await
+        }
+        (result, _) => result,
+    }
+}
+
+/// Answers with a stored response, `cache_status` telling how the cache came by it
+async fn send_cached<S>(
+    socket: &mut S,
+    request: &Request<BytesMut>,
+    mut cached: Cached,
+    cache_status: HeaderValue,
+    extra_headers: &ExtraHeaders,
+    encode: Option<&EncodeOptions>,
+) -> Result<(StatusCode, bool), CbltError>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    cached.headers.insert(CACHE_STATUS, cache_status);
+    let mut body = &cached.body[..];
+    forward_response(
+        socket,
+        request,
+        cached.status,
+        cached.headers,
+        &mut body,
+        BytesMut::new(),
+        extra_headers,
+        encode,
+        None,
+        None,
+    )
+    .await
+}
+
+/// Fetches a stale response again in the background, the cache stores the answer as usual
+fn refresh_cached(
+    reverse_proxy_state: Arc<ReverseProxyState>,
+    request: &Request<BytesMut>,
+    pattern: &str,
+    geoip: Placeholders,
+    addr: SocketAddr,
+    scheme: &str,
+) {
+    // A HEAD is refreshed with a GET so that there is a body to store
+    let mut refresh = Request::new(BytesMut::new());
+    *refresh.uri_mut() = request.uri().clone();
+    *refresh.version_mut() = request.version();
+    *refresh.headers_mut() = request.headers().clone();
+    for name in [
+        IF_MATCH,
+        IF_NONE_MATCH,
+        IF_MODIFIED_SINCE,
+        IF_UNMODIFIED_SINCE,
+        IF_RANGE,
+        RANGE,
+    ] {
+        refresh.headers_mut().remove(name);
+    }
+    let (pattern, scheme) = (pattern.to_string(), scheme.to_string());
+    tokio::spawn(async move {
+        let mut discard = tokio::io::join(tokio::io::empty(), tokio::io::sink());
+        if let Err(err) = proxy_backends(
+            &refresh,
+            &mut discard,
+            &mut BytesMut::new(),
+            &reverse_proxy_state,
+            &pattern,
+            &geoip,
+            addr,
+            &ExtraHeaders::default(),
+            None,
+            &scheme,
+            false,
+        )
+        .await
+        {
+            #[cfg(debug_assertions)]
+            error!("Refreshing {} failed: {}", refresh.uri(), err);
+        }
+    });
+}
+
+/// Sends a copy of the request to the `mirror` backend in the background, its response is
+/// dropped after the head. Copies beyond `MAX_MIRRORS_IN_FLIGHT` are skipped.
+fn mirror_request(
+    reverse_proxy_state: Arc<ReverseProxyState>,
+    request: &Request<BytesMut>,
+    pattern: &str,
+    geoip: &Placeholders,
+    addr: SocketAddr,
+    scheme: &str,
+) -> Result<(), CbltError> {
+    let options = &reverse_proxy_state.options;
+    let Some(mirror) = options.mirror.clone() else {
+        return Ok(());
+    };
+    let mirror_addr = backend_authority(&mirror)?;
+    let request_host = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok());
+    let forwarded = forwarding_headers(request.headers(), request_host, addr, scheme, options);
+    let mut placeholders = header_placeholders(request.method(), request.uri(), request_host, addr);
+    placeholders.extend(upstream_placeholders(&mirror_addr));
+    placeholders.extend(capture_placeholders(pattern, request.uri().path()));
+    placeholders.extend(geoip.iter().cloned());
+    let header_up = fill_placeholders(&options.header_up, &placeholders);
+    let protocol = backend_protocol(&mirror).map(|(protocol, _)| protocol);
+    let request_bytes = match protocol {
+        Some(protocol) => {
+            let headers = upstream_headers(request, false, &forwarded, &header_up);
+            cgi::encode_request(protocol, request, &headers, addr, scheme)?
+        }
+        None => request_to_bytes(request, false, &forwarded, &header_up)?,
+    };
+    if reverse_proxy_state.mirrors.fetch_add(1, Ordering::Relaxed) >= MAX_MIRRORS_IN_FLIGHT {
+        reverse_proxy_state.mirrors.fetch_sub(1, Ordering::Relaxed);
+        #[cfg(debug_assertions)]
+        debug!(
+            "Too many mirrored requests in flight, skipping {}",
+            request.uri()
+        );
+        return Ok(());
+    }
+    tokio::spawn(async move {
+        let state = &reverse_proxy_state;
+        let deadline =
+            tokio::time::Instant::now() + state.options.timeout.unwrap_or(MIRROR_TIMEOUT);
+        let result = async {
+            let mut stream = within(
+                connect_backend(&mirror, state.tls.as_ref(), false, addr, &state.options),
+                None,
+                Some(deadline),
+            )
+            .await?;
+            let mut buf = BytesMut::with_capacity(BUF_SIZE);
+            within(
+                send_request(&mut stream, &request_bytes, &mut buf, protocol),
+                state.options.header_timeout,
+                Some(deadline),
+            )
+            .await
+        }
+        .await;
+        state.mirrors.fetch_sub(1, Ordering::Relaxed);
+        match result {
+            Ok((status, _, _)) => {
+                #[cfg(debug_assertions)]
+                debug!("Mirror {} answered {}", mirror, status);
+            }
+            Err(err) => {
+                #[cfg(debug_assertions)]
+                error!("Mirroring to {} failed: {}", mirror, err);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Forwards the request to a backend chosen by the load balancing policy, retrying on the
+/// failures `retry_on` names. With `stale_on_error` an error status is not forwarded but
+/// returned for a stored response to stand in.
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn proxy_backends<S>(
+    request: &Request<BytesMut>,
+    socket: &mut S,
+    client_buf: &mut BytesMut,
+    reverse_proxy_state: &ReverseProxyState,
+    pattern: &str,
+    geoip: &Placeholders,
+    addr: SocketAddr,
+    extra_headers: &ExtraHeaders,
+    encode: Option<&EncodeOptions>,
+    scheme: &str,
+    stale_on_error: bool,
+) -> Result<(StatusCode, bool), CbltError>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    let options = &reverse_proxy_state.options;
+    let cache = reverse_proxy_state
+        .cache
+        .as_ref()
+        .filter(|_| !is_upgrade_request(request));
+    // Without `retries` every backend gets one try
+    let max_retries = options
+        .retries
+        .unwrap_or(reverse_proxy_state.backends.get().len().saturating_sub(1) as u64);
+    let deadline = options
+        .timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+    let mut retries = 0;
+    let mut retry = |failure: RetryOn, sent: bool| {
+        let retry = retries < max_retries && is_retryable(options, &failure, sent, request);
+        if retry {
+            retries += 1;
+            #[cfg(debug_assertions)]
+            debug!("Retrying on {:?}, attempt {}", failure, retries);
+        }
+        retry
+    };
+    // A sticky session starts on the backend named by its cookie, retries go elsewhere
+    let affinity = match &reverse_proxy_state.lb_policy {
+        LoadBalancePolicy::Cookie(cookie) => request_cookie(request, &cookie.name),
+        _ => None,
+    };
+    let mut sticky = affinity.as_deref();
+    loop {
+        let next = match sticky.take() {
+            Some(id) => match reverse_proxy_state.get_sticky_backend(id).await {
+                Some(backend) => Ok(backend),
+                None => reverse_proxy_state.get_next_backend(addr).await,
+            },
+            None => reverse_proxy_state.get_next_backend(addr).await,
+        };
+        match next {
+            Ok(backend) => {
+                #[cfg(debug_assertions)]
+                debug!("Selected backend: {:?}", backend);
+                let _in_flight = reverse_proxy_state.in_flight(&backend);
+                let backend_addr = backend_authority(backend.address())?;
+                #[cfg(debug_assertions)]
+                debug!("Connecting to backend at {}", backend_addr);
+
+                let upgrade = is_upgrade_request(request);
+                let request_host = request
+                    .headers()
+                    .get(HOST)
+                    .and_then(|host| host.to_str().ok());
+                let forwarded =
+                    forwarding_headers(request.headers(), request_host, addr, scheme, options);
+                let mut placeholders =
+                    header_placeholders(request.method(), request.uri(), request_host, addr);
+                placeholders.extend(upstream_placeholders(&backend_addr));
+                placeholders.extend(capture_placeholders(pattern, request.uri().path()));
+                placeholders.extend(geoip.iter().cloned());
+                let header_up = fill_placeholders(&options.header_up, &placeholders);
+                // uwsgi and SCGI backends close the connection after each response
+                let protocol = backend_protocol(backend.address()).map(|(protocol, _)| protocol);
+                let request_bytes = match protocol {
+                    Some(protocol) => {
+                        let headers = upstream_headers(request, false, &forwarded, &header_up);
+                        cgi::encode_request(protocol, request, &headers, addr, scheme)?
+                    }
+                    None => request_to_bytes(request, upgrade, &forwarded, &header_up)?,
+                };
+
+                // Prefer an idle pooled connection over opening a new one, one that
+                // announced another client through the PROXY protocol does not fit
+                let pooled = if upgrade || options.proxy_protocol.is_some() || protocol.is_some() {
+                    None
+                } else {
+                    reverse_proxy_state.pool.checkout(backend_addr.as_str())
+                };
+                let reused = pooled.is_some();
+                let mut backend_stream = match pooled {
+                    Some(stream) => stream,
+                    None => match within(
+                        connect_backend(
+                            backend.address(),
+                            reverse_proxy_state.tls.as_ref(),
+                            false,
+                            addr,
+                            options,
+                        ),
+                        None,
+                        deadline,
+                    )
+                    .await
+                    {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            reverse_proxy_state.record_failure(&backend).await?;
+                            if retry(failure_kind(&err), false) {
+                                continue; // Try the next backend
                             }
+                            return Err(err);
                         }
+                    },
+                };
 
-                        match backend_stream_result {
-                            Ok(mut backend_stream) => {
-                                // Backend is alive, update its state
-                                reverse_proxy_state.set_alive_backend(&backend).await?;
-
-                                // Send the initial request to the backend
-                                let request_bytes = request_to_bytes(request)?;
-                                backend_stream
-                                    .write_all(&request_bytes)
-                                    .await
-                                    .map_err(|e| CbltError::ResponseError {
-                                        details: e.to_string(),
-                                        status_code: StatusCode::BAD_GATEWAY,
-                                    })?;
-
-                                // Read the response from the backend
-                                let mut backend_buf = BytesMut::with_capacity(8192);
-                                let header_len =
-                                    get_header_len(&mut backend_stream, &mut backend_buf).await?;
-
-                                // Send the response headers back to the client
-                                socket.write_all(&backend_buf[..header_len]).await.map_err(
-                                    |e| CbltError::ResponseError {
-                                        details: e.to_string(),
-                                        status_code: StatusCode::BAD_GATEWAY,
-                                    },
-                                )?;
-
-                                // If there's any body data already read, send it
-                                if backend_buf.len() > header_len {
-                                    socket.write_all(&backend_buf[header_len..]).await.map_err(
-                                        |e| CbltError::ResponseError {
-                                            details: e.to_string(),
-                                            status_code: StatusCode::BAD_GATEWAY,
-                                        },
-                                    )?;
-                                }
-
-                                let (mut backend_read_half, mut backend_write_half) =
-                                    backend_stream.split();
-                                let (mut client_read_half, mut client_write_half) =
-                                    tokio::io::split(socket);
-
-                                let client_to_backend = async {
-                                    let result = tokio::io::copy(
-                                        &mut client_read_half,
-                                        &mut backend_write_half,
-                                    )
-                                    .await;
-                                    backend_write_half.shutdown().await.ok();
-                                    result
-                                };
-
-                                let backend_to_client = async {
-                                    let result = tokio::io::copy(
-                                        &mut backend_read_half,
-                                        &mut client_write_half,
-                                    )
-                                    .await;
-                                    client_write_half.shutdown().await.ok();
-                                    result
-                                };
-
-                                let (client_to_backend_res, backend_to_client_res) =
-                                    tokio::join!(client_to_backend, backend_to_client);
-                                match (client_to_backend_res, backend_to_client_res) {
-                                    (Ok(_), Ok(_)) => {
-                                        return Ok(StatusCode::OK);
-                                    }
-                                    _ => {
-                                        return Err(CbltError::ResponseError {
-                                            details:
-                                                "Failed to copy data between client and backend"
-                                                    .to_string(),
-                                            status_code: StatusCode::BAD_GATEWAY,
-                                        });
-                                    }
-                                }
-                            }
-                            Err(_) => {
-                                // Mark the backend as dead and continue to the next backend
-                                reverse_proxy_state.set_dead_backend(&backend).await?;
-                                continue; // Try the next backend
+                let mut backend_buf = BytesMut::with_capacity(BUF_SIZE);
+                let mut head = within(
+                    send_request(
+                        &mut backend_stream,
+                        &request_bytes,
+                        &mut backend_buf,
+                        protocol,
+                    ),
+                    options.header_timeout,
+                    deadline,
+                )
+                .await;
+                if reused
+                    && head
+                        .as_ref()
+                        .is_err_and(|err| failure_kind(err) != RetryOn::Timeout)
+                {
+                    // The backend closed the pooled connection while it was idle
+                    backend_stream = match within(
+                        connect_backend(
+                            backend.address(),
+                            reverse_proxy_state.tls.as_ref(),
+                            false,
+                            addr,
+                            options,
+                        ),
+                        None,
+                        deadline,
+                    )
+                    .await
+                    {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            reverse_proxy_state.record_failure(&backend).await?;
+                            if retry(failure_kind(&err), false) {
+                                continue;
                             }
+                            return Err(err);
                         }
+                    };
+                    backend_buf.clear();
+                    head = within(
+                        send_request(
+                            &mut backend_stream,
+                            &request_bytes,
+                            &mut backend_buf,
+                            protocol,
+                        ),
+                        options.header_timeout,
+                        deadline,
+                    )
+                    .await;
+                }
+                let (status, mut headers, version) = match head {
+                    Ok(head) => head,
+                    Err(err) => {
+                        reverse_proxy_state.record_failure(&backend).await?;
+                        if failure_kind(&err) == RetryOn::Timeout && retry(RetryOn::Timeout, true) {
+                            continue;
+                        }
+                        return Err(err);
                     }
-                    Err(_) => {
-                        return Err(CbltError::ResponseError {
-                            details: "No healthy backends".to_string(),
-                            status_code: StatusCode::BAD_GATEWAY,
-                        });
-                    }
+                };
+
+                // Backend is alive, update its state
+                reverse_proxy_state.set_alive_backend(&backend).await?;
+
+                apply_header_ops(
+                    &mut headers,
+                    &fill_placeholders(&options.header_down, &placeholders),
+                );
+
+                // The connection is dropped with the unread response
+                if retry(RetryOn::Status(status.as_u16()), true) {
+                    continue;
+                }
+                // A stored response stands in for the error, nothing has gone to the client
+                if stale_on_error && matches!(status.as_u16(), 500 | 502 | 503 | 504) {
+                    return Err(CbltError::ResponseError {
+                        details: format!("Backend answered {}", status),
+                        status_code: status,
+                    });
+                }
+                let mut capture = cache.and_then(|cache| cache.capture(request, status, &headers));
+                if cache.is_some() {
+                    headers.insert(CACHE_STATUS, HeaderValue::from_static("cblt; fwd=miss"));
+                }
+                if let Some(cookie) =
+                    reverse_proxy_state.affinity_cookie(&backend, affinity.as_deref())
+                {
+                    headers.append(SET_COOKIE, cookie);
+                }
+
+                if status == StatusCode::SWITCHING_PROTOCOLS {
+                    // The connection now speaks another protocol, relay it as is
+                    write_response_head(socket, status, &headers).await?;
+                    tunnel(socket, client_buf, backend_stream, backend_buf).await?;
+                    return Ok((status, false));
+                }
+
+                let reusable = !upgrade
+                    && options.proxy_protocol.is_none()
+                    && protocol.is_none()
+                    && is_backend_reusable(request, status, &headers, version);
+                let forward = forward_response(
+                    socket,
+                    request,
+                    status,
+                    headers,
+                    &mut backend_stream,
+                    backend_buf,
+                    extra_headers,
+                    encode,
+                    options.flush_interval,
+                    capture.as_mut(),
+                );
+                // The head is out, so running out of time can only cut the body short
+                let result = match deadline {
+                    Some(deadline) => timeout_at(deadline, forward).await.unwrap_or_else(|_| {
+                        Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "Backend response exceeded the proxy timeout",
+                        )
+                        .into())
+                    }),
+                    None => forward.await,
+                };
+                if result.is_ok() && reusable {
+                    reverse_proxy_state
+                        .pool
+                        .checkin(backend_addr.as_str(), backend_stream);
+                }
+                if let (Ok(_), Some(cache), Some(capture)) = (&result, cache, capture) {
+                    cache.store(capture);
+                }
+                return result;
+            }
+            Err(_) => {
+                return Err(CbltError::ResponseError {
+                    details: "No healthy backends".to_string(),
+                    status_code: StatusCode::BAD_GATEWAY,
+                });
+            }
+        }
+    }
+}
+/// Connection to the backend at `url` for a request of `client`, over TLS when it is `https`.
+/// HTTP/2 is offered to TLS backends with `h2`.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn connect_backend(
+    url: &str,
+    tls: Option<&UpstreamTls>,
+    h2: bool,
+    client: SocketAddr,
+    options: &ReverseProxyOptions,
+) -> Result<BackendStream, CbltError> {
+    let backend_addr = backend_authority(url)?;
+    let tls = tls.filter(|_| url.starts_with("https://"));
+    // Establish a connection to the backend with retries, the TLS handshake counts as connecting
+    let timeout_duration = Duration::from_secs(options.lb_timeout);
+    let mut retries = options.lb_retries;
+    let mut status_code = StatusCode::BAD_GATEWAY;
+    while retries > 0 {
+        let proxy_protocol = options
+            .proxy_protocol
+            .map(|version| (version, Some(client)));
+        let open = open_backend(&backend_addr, tls, h2, proxy_protocol);
+        match timeout(timeout_duration, open).await {
+            Ok(Ok(stream)) => {
+                return Ok(stream);
+            }
+            Ok(Err(e)) => {
+                #[cfg(debug_assertions)]
+                error!("Failed to connect to backend: {}", e);
+                status_code = StatusCode::BAD_GATEWAY;
+                retries -= 1;
+            }
+            Err(e) => {
+                #[cfg(debug_assertions)]
+                error!("Connection to backend timed out: {}", e);
+                status_code = StatusCode::GATEWAY_TIMEOUT;
+                retries -= 1;
+            }
+        }
+    }
+    Err(CbltError::ResponseError {
+        details: format!("Failed to connect to backend {}", backend_addr),
+        status_code,
+    })
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn open_backend(
+    backend_addr: &str,
+    tls: Option<&UpstreamTls>,
+    h2: bool,
+    proxy_protocol: Option<(ProxyProtocolVersion, Option<SocketAddr>)>,
+) -> std::io::Result<BackendStream> {
+    if let Some(path) = unix_socket_path(backend_addr) {
+        return open_unix_backend(path, proxy_protocol).await;
+    }
+    let mut stream = TcpStream::connect(backend_addr).await?;
+    let _ = stream.set_nodelay(true);
+    if let Some((version, client)) = proxy_protocol {
+        let header = proxy_protocol::header(version, client, stream.peer_addr()?);
+        stream.write_all(&header).await?;
+    }
+    match tls {
+        Some(tls) => {
+            let host = backend_addr
+                .rsplit_once(':')
+                .map_or(backend_addr, |(host, _)| host);
+            let stream = tls.connect(stream, host, h2).await?;
+            Ok(BackendStream::Tls(Box::new(stream)))
+        }
+        None => Ok(BackendStream::Plain(stream)),
+    }
+}
+
+#[cfg(unix)]
+async fn open_unix_backend(
+    path: &str,
+    proxy_protocol: Option<(ProxyProtocolVersion, Option<SocketAddr>)>,
+) -> std::io::Result<BackendStream> {
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    if let Some((version, client)) = proxy_protocol {
+        // A socket has no address of its own to announce, the backend is this machine
+        let backend = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let header = proxy_protocol::header(version, client, backend);
+        stream.write_all(&header).await?;
+    }
+    Ok(BackendStream::Unix(stream))
+}
+
+#[cfg(not(unix))]
+async fn open_unix_backend(
+    _path: &str,
+    _proxy_protocol: Option<(ProxyProtocolVersion, Option<SocketAddr>)>,
+) -> std::io::Result<BackendStream> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform",
+    ))
+}
+
+/// Runs `future` for at most `limit` and until `deadline`, a backend running late is a 504
+async fn within<F, T>(
+    future: F,
+    limit: Option<Duration>,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<T, CbltError>
+where
+    F: Future<Output = Result<T, CbltError>>,
+{
+    let deadline = match (
+        limit.map(|limit| tokio::time::Instant::now() + limit),
+        deadline,
+    ) {
+        (Some(limit), Some(deadline)) => Some(limit.min(deadline)),
+        (limit, deadline) => limit.or(deadline),
+    };
+    match deadline {
+        Some(deadline) => timeout_at(deadline, future).await.unwrap_or_else(|_| {
+            Err(CbltError::ResponseError {
+                details: "Backend timed out".to_string(),
+                status_code: StatusCode::GATEWAY_TIMEOUT,
+            })
+        }),
+        None => future.await,
+    }
+}
+
+/// Failure a request is retried on, a timeout only when the backend did not answer in time
+fn failure_kind(err: &CbltError) -> RetryOn {
+    match err {
+        CbltError::ResponseError {
+            status_code: StatusCode::GATEWAY_TIMEOUT,
+            ..
+        } => RetryOn::Timeout,
+        _ => RetryOn::ConnectFailure,
+    }
+}
+
+/// A request the backend may have acted on is only sent again when repeating it is harmless
+fn is_retryable(
+    options: &ReverseProxyOptions,
+    failure: &RetryOn,
+    sent: bool,
+    request: &Request<BytesMut>,
+) -> bool {
+    options.retry_on.contains(failure) && (!sent || request.method().is_idempotent())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn send_request(
+    backend_stream: &mut BackendStream,
+    request_bytes: &[u8],
+    backend_buf: &mut BytesMut,
+    protocol: Option<Protocol>,
+) -> Result<(StatusCode, HeaderMap, Version), CbltError> {
+    backend_stream
+        .write_all(request_bytes)
+        .await
+        .map_err(|e| CbltError::ResponseError {
+            details: e.to_string(),
+            status_code: StatusCode::BAD_GATEWAY,
+        })?;
+    if protocol.is_some() {
+        return cgi::response_head(backend_stream, backend_buf).await;
+    }
+    let header_len = get_header_len(backend_stream, backend_buf).await?;
+    let head = parse_response_head(&backend_buf[..header_len])?;
+    let _ = backend_buf.split_to(header_len);
+    Ok(head)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn is_upgrade_request(request: &Request<BytesMut>) -> bool {
+    request.headers().contains_key(UPGRADE)
+        && request
+            .headers()
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("upgrade"))
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn is_backend_reusable(
+    request: &Request<BytesMut>,
+    status: StatusCode,
+    headers: &HeaderMap,
+    version: Version,
+) -> bool {
+    let close = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("close"));
+    version == Version::HTTP_11
+        && !close
+        && BodyKind::of_response(request.method(), status, headers) != BodyKind::UntilClose
+}
+
+#[allow(clippy::too_many_arguments)]
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn forward_response<S, R>(
+    socket: &mut S,
+    request: &Request<BytesMut>,
+    status: StatusCode,
+    mut headers: HeaderMap,
+    backend_stream: &mut R,
+    backend_buf: BytesMut,
+    extra_headers: &ExtraHeaders,
+    encode: Option<&EncodeOptions>,
+    flush_interval: Option<Duration>,
+    mut capture: Option<&mut Capture>, // the body is copied into it as well
+) -> Result<(StatusCode, bool), CbltError>
+where
+    S: AsyncWriteExt + Unpin,
+    R: AsyncReadExt + Unpin,
+{
+    let body_kind = BodyKind::of_response(request.method(), status, &headers);
+    remove_hop_by_hop_headers(&mut headers);
+    // Server-sent events go out as they arrive
+    let flush_interval = flush_interval.filter(|_| !is_event_stream(&headers));
+
+    let mut codec = None;
+    if let Some(encode) = encode {
+        if status == StatusCode::OK
+            && body_kind != BodyKind::Empty
+            && is_compressible(&headers, encode)
+        {
+            add_vary(&mut headers);
+            codec = negotiate(request, encode);
+        }
+    }
+
+    // Bodies of unknown length are re-chunked so the client connection can stay open
+    let mut keep_alive = true;
+    let chunked = match body_kind {
+        _ if codec.is_some() => true,
+        BodyKind::Chunked => true,
+        BodyKind::UntilClose => {
+            if request.version() == Version::HTTP_10 {
+                keep_alive = false;
+                false
+            } else {
+                true
+            }
+        }
+        BodyKind::Empty | BodyKind::Length(_) => false,
+    };
+    extra_headers.apply(status, &mut headers);
+    if let Some(codec) = codec {
+        encoded_headers(&mut headers, codec.encoding);
+    } else if chunked {
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+    }
+    if !keep_alive {
+        headers.insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    write_response_head(socket, status, &headers).await?;
+
+    let mut reader = BodyReader::new(backend_stream, backend_buf, body_kind);
+    match codec {
+        Some(codec) => {
+            let mut writer = EncodedBodyWriter::new(socket, codec);
+            while let Some(chunk) = reader.next_batch(flush_interval).await? {
+                if let Some(capture) = capture.as_mut() {
+                    capture.push(&chunk);
+                }
+                // Flush every piece so streamed responses are not held back
+                writer.write(&chunk, true).await?;
+            }
+            writer.finish(reader.trailers()).await?;
+        }
+        None => {
+            let mut writer = BodyWriter::new(socket, chunked);
+            while let Some(chunk) = reader.next_batch(flush_interval).await? {
+                if let Some(capture) = capture.as_mut() {
+                    capture.push(&chunk);
                 }
+                writer.write(&chunk).await?;
             }
+            writer.finish(reader.trailers()).await?;
         }
     }
 
-    Err(CbltError::DirectiveNotMatched)
+    Ok((status, keep_alive))
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .trim_start()
+                .to_ascii_lowercase()
+                .starts_with("text/event-stream")
+        })
 }
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-fn request_to_bytes(request: &Request<BytesMut>) -> Result<Vec<u8>, CbltError> {
+pub(crate) async fn tunnel<S>(
+    socket: &mut S,
+    client_buf: &mut BytesMut,
+    mut backend_stream: BackendStream,
+    backend_buf: BytesMut,
+) -> Result<(), CbltError>
+where
+    S: AsyncReadExt + AsyncWriteExt + Unpin,
+{
+    if !backend_buf.is_empty() {
+        socket.write_all(&backend_buf).await?;
+    }
+    socket.flush().await?;
+    // Frames the client sent right behind the upgrade request
+    if !client_buf.is_empty() {
+        backend_stream.write_all(&client_buf.split()).await?;
+    }
+
+    let (mut backend_read_half, mut backend_write_half) = tokio::io::split(backend_stream);
+    let (mut client_read_half, mut client_write_half) = tokio::io::split(socket);
+
+    let client_to_backend = async {
+        let result = tokio::io::copy(&mut client_read_half, &mut backend_write_half).await;
+        backend_write_half.shutdown().await.ok();
+        result
+    };
+
+    let backend_to_client = async {
+        let result = tokio::io::copy(&mut backend_read_half, &mut client_write_half).await;
+        client_write_half.shutdown().await.ok();
+        result
+    };
+
+    let (client_to_backend_res, backend_to_client_res) =
+        tokio::join!(client_to_backend, backend_to_client);
+    match (client_to_backend_res, backend_to_client_res) {
+        (Ok(_), Ok(_)) => Ok(()),
+        _ => Err(CbltError::IOError {
+            source: std::io::Error::other("Failed to copy data between client and backend"),
+        }),
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    for name in hop_by_hop_headers(headers) {
+        headers.remove(name.as_str());
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn hop_by_hop_headers(headers: &HeaderMap) -> Vec<String> {
+    let listed: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect();
+    [
+        "connection",
+        "keep-alive",
+        "proxy-connection",
+        "te",
+        "transfer-encoding",
+        "upgrade",
+    ]
+    .iter()
+    .map(|name| name.to_string())
+    .chain(listed)
+    .collect()
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn parse_response_head(head: &[u8]) -> Result<(StatusCode, HeaderMap, Version), CbltError> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut res = httparse::Response::new(&mut headers);
+    res.parse(head).map_err(|e| CbltError::ResponseError {
+        details: e.to_string(),
+        status_code: StatusCode::BAD_GATEWAY,
+    })?;
+    let status = res
+        .code
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or(CbltError::ResponseError {
+            details: "Invalid status code from backend".to_string(),
+            status_code: StatusCode::BAD_GATEWAY,
+        })?;
+
+    let mut header_map = HeaderMap::with_capacity(res.headers.len());
+    for header in res.headers.iter() {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(header.name.as_bytes()),
+            HeaderValue::from_bytes(header.value),
+        ) {
+            header_map.append(name, value);
+        }
+    }
+    let version = match res.version {
+        Some(1) => Version::HTTP_11,
+        _ => Version::HTTP_10,
+    };
+    Ok((status, header_map, version))
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn request_to_bytes(
+    request: &Request<BytesMut>,
+    upgrade: bool,
+    forwarded: &HeaderMap,
+    header_up: &[HeaderOp],
+) -> Result<Vec<u8>, CbltError> {
     let mut buf = Vec::new();
     // Write request line
     buf.extend_from_slice(request.method().as_str().as_bytes());
@@ -230,7 +1016,7 @@ fn request_to_bytes(request: &Request<BytesMut>) -> Result<Vec<u8>, CbltError> {
     buf.extend_from_slice(b" HTTP/1.1\r\n");
 
     // Write headers
-    for (key, value) in request.headers() {
+    for (key, value) in upstream_headers(request, upgrade, forwarded, header_up).iter() {
         buf.extend_from_slice(key.as_str().as_bytes());
         buf.extend_from_slice(b": ");
         buf.extend_from_slice(value.as_bytes());
@@ -244,8 +1030,237 @@ fn request_to_bytes(request: &Request<BytesMut>) -> Result<Vec<u8>, CbltError> {
     Ok(buf)
 }
 
+/// Headers the backend gets, hop-by-hop ones kept only for protocol upgrades; `forwarded`
+/// replaces the client's headers of the same name and `header_up` edits the result
+fn upstream_headers(
+    request: &Request<BytesMut>,
+    upgrade: bool,
+    forwarded: &HeaderMap,
+    header_up: &[HeaderOp],
+) -> HeaderMap {
+    let hop_by_hop = if upgrade {
+        Vec::new()
+    } else {
+        hop_by_hop_headers(request.headers())
+    };
+    let mut headers = HeaderMap::new();
+    for (key, value) in request.headers().iter().filter(|(key, _)| {
+        !hop_by_hop.iter().any(|name| name == key.as_str())
+            && !FORWARDING_HEADERS.contains(&key.as_str())
+            && !forwarded.contains_key(*key)
+    }) {
+        headers.append(key.clone(), value.clone());
+    }
+    for (key, value) in forwarded.iter() {
+        headers.append(key.clone(), value.clone());
+    }
+    apply_header_ops(&mut headers, header_up);
+    headers
+}
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_REAL_IP: &str = "x-real-ip";
+const FORWARDED: &str = "forwarded";
+const FORWARDING_HEADERS: [&str; 5] = [
+    X_FORWARDED_FOR,
+    X_FORWARDED_PROTO,
+    X_FORWARDED_HOST,
+    X_REAL_IP,
+    FORWARDED,
+];
+
+/// Headers telling the backend about the client, `X-Forwarded-*` with `X-Real-IP` and/or the
+/// RFC 7239 `Forwarded`. Values the client sent are only kept when it is one of the `trusted_proxies`.
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-async fn get_header_len<S>(socket: &mut S, buf: &mut BytesMut) -> Result<usize, CbltError>
+pub fn forwarding_headers(
+    headers: &HeaderMap,
+    host: Option<&str>,
+    addr: SocketAddr,
+    scheme: &str,
+    options: &ReverseProxyOptions,
+) -> HeaderMap {
+    let trusted_proxies = &options.trusted_proxies;
+    let client = addr.ip().to_canonical();
+    let trusted = contains_ip(trusted_proxies, &client);
+    let forwarded = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter(|_| trusted)
+    };
+
+    // A trusted proxy's headers pass on, the ones emitted here are then extended
+    let mut result = HeaderMap::new();
+    for name in FORWARDING_HEADERS {
+        for value in headers.get_all(name).iter().filter(|_| trusted) {
+            result.append(HeaderName::from_static(name), value.clone());
+        }
+    }
+
+    let mut chain = if trusted {
+        forwarded_chain(headers)
+    } else {
+        Vec::new()
+    };
+    let client = client.to_string();
+    chain.push(client.clone());
+    // The client is the last hop that is not a trusted proxy
+    let real_ip = chain
+        .iter()
+        .rev()
+        .find(|ip| {
+            !ip.parse::<IpAddr>()
+                .is_ok_and(|ip| contains_ip(trusted_proxies, &ip))
+        })
+        .unwrap_or(&chain[0]);
+
+    let mut values = Vec::new();
+    if options.forward_headers != ForwardHeaders::Forwarded {
+        values.push((X_FORWARDED_FOR, chain.join(", ")));
+        values.push((X_REAL_IP, real_ip.to_string()));
+        values.push((
+            X_FORWARDED_PROTO,
+            forwarded(X_FORWARDED_PROTO)
+                .next()
+                .unwrap_or(scheme)
+                .to_string(),
+        ));
+        if let Some(host) = forwarded(X_FORWARDED_HOST).next().or(host) {
+            values.push((X_FORWARDED_HOST, host.to_string()));
+        }
+    }
+    if options.forward_headers != ForwardHeaders::XForwarded {
+        let mut element = format!("for={}", forwarded_node(&client));
+        if let Some(host) = host {
+            element.push_str(&format!(";host={}", forwarded_value(host)));
+        }
+        element.push_str(&format!(";proto={}", scheme));
+        let elements: Vec<&str> = forwarded(FORWARDED)
+            .chain(std::iter::once(element.as_str()))
+            .collect();
+        values.push((FORWARDED, elements.join(", ")));
+    }
+    for (name, value) in values {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            result.insert(HeaderName::from_static(name), value);
+        }
+    }
+    result
+}
+
+/// Hops named by `X-Forwarded-For`, or the `Forwarded` header without it, the nearest last
+fn forwarded_chain(headers: &HeaderMap) -> Vec<String> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(str::to_string)
+            .collect::<Vec<String>>()
+    };
+    let chain: Vec<String> = values(X_FORWARDED_FOR)
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(str::to_string)
+        .collect();
+    if !chain.is_empty() {
+        return chain;
+    }
+    values(FORWARDED)
+        .iter()
+        .flat_map(|value| forwarded_for(value).collect::<Vec<String>>())
+        .collect()
+}
+
+/// Address of the client, the peer unless it is one of the `trusted_proxies`, then the last hop
+/// before them in its forwarding headers
+pub fn client_ip(headers: &HeaderMap, addr: SocketAddr, trusted_proxies: &[Cidr]) -> IpAddr {
+    let mut client = addr.ip().to_canonical();
+    if !contains_ip(trusted_proxies, &client) {
+        return client;
+    }
+    for hop in forwarded_chain(headers).iter().rev() {
+        // A hop that is not an address cannot be checked, the one after it is the client
+        let Ok(ip) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        client = ip.to_canonical();
+        if !contains_ip(trusted_proxies, &client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Addresses in the `for=` parameters of a `Forwarded` header, without quotes, brackets or ports
+fn forwarded_for(value: &str) -> impl Iterator<Item = String> + '_ {
+    value
+        .split(',')
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let (key, node) = pair.trim().split_once('=')?;
+            if !key.eq_ignore_ascii_case("for") {
+                return None;
+            }
+            let node = node.trim_matches('"');
+            let node = match node.strip_prefix('[') {
+                Some(v6) => v6.split(']').next().unwrap_or(v6),
+                None => node.split(':').next().unwrap_or(node),
+            };
+            Some(node.to_string())
+        })
+}
+
+/// `for=` node of an address, IPv6 is bracketed and quoted
+fn forwarded_node(ip: &str) -> String {
+    if ip.contains(':') {
+        format!("\"[{}]\"", ip)
+    } else {
+        ip.to_string()
+    }
+}
+
+/// Quotes a `Forwarded` parameter value that is not a token
+fn forwarded_value(value: &str) -> String {
+    let token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// `{upstream_host}` and `{upstream_hostport}` of the backend a request goes to
+pub fn upstream_placeholders(upstream_hostport: &str) -> Placeholders {
+    let upstream_host = upstream_hostport
+        .rsplit_once(':')
+        .map_or(upstream_hostport, |(host, _)| host);
+    vec![
+        (
+            "{upstream_hostport}".to_string(),
+            upstream_hostport.to_string(),
+        ),
+        ("{upstream_host}".to_string(), upstream_host.to_string()),
+    ]
+}
+
+/// Drops the forwarding headers a client sent, before `forwarding_headers` are added
+pub fn strip_forwarding_headers(headers: &mut HeaderMap) {
+    for name in FORWARDING_HEADERS {
+        headers.remove(name);
+    }
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn get_header_len<S>(socket: &mut S, buf: &mut BytesMut) -> Result<usize, CbltError>
 where
     S: AsyncReadExt + Unpin,
 {
@@ -281,40 +1296,279 @@ where
     })
 }
 
-use crate::config::{Directive, LoadBalancePolicy, ReverseProxyOptions};
+use crate::config::{
+    Directive, EncodeOptions, ForwardHeaders, HeaderOp, LoadBalancePolicy, ProxyProtocolVersion,
+    RetryOn, ReverseProxyOptions, UpstreamGroup,
+};
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
 use std::str::FromStr;
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
-use tokio::time::timeout;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, timeout_at};
 
 #[derive(Debug, Clone)]
 pub enum AliveState {
     Alive(u64), // timestamp
     Dead {
-        since: u64,        // timestamp when marked dead
-        retries_left: u64, // retries remaining
+        since: u64,   // timestamp when ejected
+        backoff: u64, // seconds before it is tried again
     },
+    Unhealthy(u64), // failed its last health check, out of rotation until one passes
 }
 #[derive(Debug, Clone)]
 pub struct Backend {
     pub url: String,
     pub alive_state: Arc<RwLock<AliveState>>,
+    pub failures: Arc<AtomicU64>,  // failed requests in a row
+    pub ejections: Arc<AtomicU32>, // ejections since the last success, doubles the backoff
+    pub active: Arc<AtomicUsize>,  // requests in flight
+    pub backup: bool,              // only takes requests while no primary backend can
+}
+
+impl Backend {
+    pub fn new(url: String) -> Self {
+        Backend {
+            url,
+            alive_state: Arc::new(RwLock::new(AliveState::Alive(current_timestamp_seconds()))),
+            failures: Arc::new(AtomicU64::new(0)),
+            ejections: Arc::new(AtomicU32::new(0)),
+            active: Arc::new(AtomicUsize::new(0)),
+            backup: false,
+        }
+    }
+
+    pub fn reset_failures(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        self.ejections.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Backends of a reverse_proxy, the backups last. Discovery replaces them as a whole.
+#[derive(Clone, Default)]
+pub struct Backends(Arc<std::sync::RwLock<Arc<Vec<Backend>>>>);
+
+impl Backends {
+    pub fn new(backends: Vec<Backend>) -> Self {
+        Backends(Arc::new(std::sync::RwLock::new(Arc::new(backends))))
+    }
+    pub fn get(&self) -> Arc<Vec<Backend>> {
+        self.0
+            .read()
+            .map(|backends| backends.clone())
+            .unwrap_or_default()
+    }
+    pub fn set(&self, backends: Vec<Backend>) {
+        if let Ok(mut current) = self.0.write() {
+            *current = Arc::new(backends);
+        }
+    }
+}
+
+/// Number of backends before the backups
+fn primaries(backends: &[Backend]) -> usize {
+    backends.partition_point(|backend| !backend.backup)
+}
+
+/// Whether one of the backends would be tried, ejected ones whose backoff ran out included
+async fn any_available(backends: &[Backend]) -> bool {
+    for backend in backends {
+        match *backend.alive_state.read().await {
+            AliveState::Alive(_) => return true,
+            AliveState::Dead { since, backoff }
+                if current_timestamp_seconds() > since + backoff =>
+            {
+                return true
+            }
+            _ => {}
+        }
+    }
+    false
 }
 
 pub struct ReverseProxyState {
-    pub backends: Vec<Backend>,
+    pub backends: Backends,
     pub lb_policy: LoadBalancePolicy,
     pub current_backend: Arc<RwLock<usize>>, // For Round Robin
     pub options: ReverseProxyOptions,
+    pub pool: UpstreamPool,
+    pub tls: Option<UpstreamTls>, // for https backends
+    pub cache: Option<ProxyCache>,
+    pub groups: Vec<(UpstreamGroup, ReverseProxyState)>, // with the state of their upstreams
+    pub canary: Option<Box<ReverseProxyState>>,          // state of the canary upstream
+    canary_weight: AtomicU32,                            // its share in hundredths of a percent
+    mirrors: AtomicUsize,                                // copies on their way to the mirror
+    health_check: Option<JoinHandle<()>>,
+    discovery: Option<JoinHandle<()>>,
+}
+
+impl Drop for ReverseProxyState {
+    fn drop(&mut self) {
+        for task in [&self.health_check, &self.discovery].into_iter().flatten() {
+            task.abort();
+        }
+    }
+}
+
+/// Connection to a backend, TLS for `https` ones
+pub enum BackendStream {
+    Plain(TcpStream),
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl BackendStream {
+    /// Reads what the socket holds without waiting, bypassing TLS
+    fn try_read(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            BackendStream::Plain(stream) => stream.try_read(buf),
+            BackendStream::Tls(stream) => stream.get_ref().0.try_read(buf),
+            #[cfg(unix)]
+            BackendStream::Unix(stream) => stream.try_read(buf),
+        }
+    }
+}
+
+impl AsyncRead for BackendStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            BackendStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            BackendStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            BackendStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            BackendStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            BackendStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Idle keep-alive connections to backends, keyed by backend address
+pub struct UpstreamPool {
+    idle: Mutex<HashMap<String, Vec<(BackendStream, Instant)>>>,
+    h2: Mutex<HashMap<String, SendRequest<Bytes>>>, // multiplexed HTTP/2 connections
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+impl UpstreamPool {
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        UpstreamPool {
+            idle: Mutex::new(HashMap::new()),
+            h2: Mutex::new(HashMap::new()),
+            max_idle,
+            idle_timeout,
+        }
+    }
+
+    pub fn h2_client(&self, backend_addr: &str) -> Option<SendRequest<Bytes>> {
+        self.h2.lock().ok()?.get(backend_addr).cloned()
+    }
+
+    pub fn set_h2_client(&self, backend_addr: &str, client: SendRequest<Bytes>) {
+        if let Ok(mut h2) = self.h2.lock() {
+            h2.insert(backend_addr.to_string(), client);
+        }
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn checkout(&self, backend_addr: &str) -> Option<BackendStream> {
+        let mut idle = self.idle.lock().ok()?;
+        let streams = idle.get_mut(backend_addr)?;
+        while let Some((stream, since)) = streams.pop() {
+            if since.elapsed() > self.idle_timeout {
+                continue;
+            }
+            // An idle connection must have nothing to read, otherwise the backend closed it
+            let mut probe = [0u8; 1];
+            match stream.try_read(&mut probe) {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Some(stream),
+                _ => continue,
+            }
+        }
+        None
+    }
+
+    /// Closes all idle connections
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn clear(&self) {
+        if let Ok(mut idle) = self.idle.lock() {
+            idle.clear();
+        }
+        if let Ok(mut h2) = self.h2.lock() {
+            h2.clear();
+        }
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn checkin(&self, backend_addr: &str, stream: BackendStream) {
+        if self.max_idle == 0 {
+            return;
+        }
+        if let Ok(mut idle) = self.idle.lock() {
+            let streams = idle.entry(backend_addr.to_string()).or_default();
+            streams.retain(|(_, since)| since.elapsed() <= self.idle_timeout);
+            if streams.len() < self.max_idle {
+                streams.push((stream, Instant::now()));
+            }
+        }
+    }
 }
 #[derive(Debug, Clone)]
 pub struct LiveBackend {
     address: heapless::String<HEAPLESS_STRING_SIZE>,
-    backend_index: usize,
+    backend: Backend,
+}
+
+impl LiveBackend {
+    pub fn address(&self) -> &str {
+        self.address.as_str()
+    }
 }
 
 impl ReverseProxyState {
@@ -324,29 +1578,158 @@ impl ReverseProxyState {
         lb_policy: LoadBalancePolicy,
         options: ReverseProxyOptions,
     ) -> Result<Self, CbltError> {
-        let now_timestamp_seconds = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs();
-        Ok(Self {
-            backends: backends
-                .into_iter()
-                .map(|url| Backend {
-                    url,
-                    alive_state: Arc::new(RwLock::new(AliveState::Alive(now_timestamp_seconds))),
+        let https: Vec<&String> = backends
+            .iter()
+            .chain(options.backups.iter())
+            .chain(options.mirror.iter())
+            .filter(|backend| backend.starts_with("https://"))
+            .collect();
+        let tls = match https.first() {
+            // Discovered backends are addresses, their certificates still name the host
+            Some(url) if options.dns_discovery.is_some() && options.tls_server_name.is_none() => {
+                Some(UpstreamTls::new(&ReverseProxyOptions {
+                    tls_server_name: upstream_host(url).map(|(host, _)| host),
+                    ..options.clone()
+                })?)
+            }
+            Some(_) => Some(UpstreamTls::new(&options)?),
+            None if options.consul.scheme == "https"
+                && backends
+                    .iter()
+                    .chain(options.backups.iter())
+                    .any(|url| consul_service(url).is_some()) =>
+            {
+                Some(UpstreamTls::new(&options)?)
+            }
+            None => None,
+        };
+        // Groups and the canary share the options, not the cache, mirror and routing
+        let nested = ReverseProxyOptions {
+            cache: None,
+            mirror: None,
+            canary: None,
+            groups: Vec::new(),
+            backups: Vec::new(),
+            ..options.clone()
+        };
+        let groups = options
+            .groups
+            .iter()
+            .map(|group| {
+                let state = ReverseProxyState::new(
+                    group.upstreams.clone(),
+                    lb_policy.clone(),
+                    nested.clone(),
+                )?;
+                Ok((group.clone(), state))
+            })
+            .collect::<Result<Vec<_>, CbltError>>()?;
+        let canary = match &options.canary {
+            Some(canary) => Some(Box::new(ReverseProxyState::new(
+                vec![canary.upstream.clone()],
+                LoadBalancePolicy::RoundRobin,
+                nested,
+            )?)),
+            None => None,
+        };
+        let canary_weight = options
+            .canary
+            .as_ref()
+            .map_or(0, |canary| canary_weight(canary.percent));
+        let upstreams: Vec<(String, bool)> = backends
+            .into_iter()
+            .map(|url| (url, false))
+            .chain(options.backups.iter().map(|url| (url.clone(), true)))
+            .collect();
+        // Consul services count once their instances are known
+        let backends = Backends::new(
+            upstreams
+                .iter()
+                .filter(|(url, _)| consul_service(url).is_none())
+                .map(|(url, backup)| Backend {
+                    backup: *backup,
+                    ..Backend::new(url.clone())
                 })
                 .collect(),
+        );
+        Ok(Self {
+            health_check: health::start(&backends, tls.clone(), &options),
+            discovery: discovery::start(
+                &backends,
+                upstreams,
+                options.dns_discovery.as_ref(),
+                &options.consul,
+            ),
+            tls,
+            backends,
             lb_policy,
             current_backend: Arc::new(RwLock::new(0)),
+            pool: UpstreamPool::new(
+                options.pool_max_idle,
+                Duration::from_secs(options.pool_idle_timeout),
+            ),
+            cache: options.cache.clone().map(ProxyCache::new),
+            groups,
+            canary,
+            canary_weight: AtomicU32::new(canary_weight),
+            mirrors: AtomicUsize::new(0),
             options: options.clone(),
         })
     }
+    /// State of the first group whose conditions the request meets
+    pub fn group<B>(&self, request: &Request<B>) -> Option<&ReverseProxyState> {
+        self.groups
+            .iter()
+            .find(|(group, _)| matches_all(&group.conditions, request))
+            .map(|(_, state)| state)
+    }
+    /// Share of the requests the canary gets, `None` without one
+    pub fn canary_percent(&self) -> Option<f64> {
+        self.canary.as_ref()?;
+        Some(self.canary_weight.load(Ordering::Relaxed) as f64 / 100.0)
+    }
+    /// Changes the share of the canary until the configuration is reloaded
+    pub fn set_canary_percent(&self, percent: f64) -> bool {
+        if self.canary.is_none() || !(0.0..=100.0).contains(&percent) {
+            return false;
+        }
+        self.canary_weight
+            .store(canary_weight(percent), Ordering::Relaxed);
+        true
+    }
+    /// The canary when the request falls in its share and it is not ejected
+    async fn pick_canary(&self) -> Option<&ReverseProxyState> {
+        let canary = self.canary.as_deref()?;
+        let weight = self.canary_weight.load(Ordering::Relaxed) as usize;
+        if random_index(CANARY_SCALE as usize) >= weight || !canary.is_available().await {
+            return None;
+        }
+        Some(canary)
+    }
+    /// Whether a backend would be tried, ejected ones whose backoff ran out included
+    pub async fn is_available(&self) -> bool {
+        any_available(&self.backends.get()).await
+    }
+    /// Counts a failed request, ejecting the backend after `lb_max_fails` in a row.
+    /// Each ejection without a success in between doubles the wait, up to `lb_max_backoff`.
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-    pub async fn set_dead_backend(&self, live_backend: &LiveBackend) -> Result<(), CbltError> {
-        let now_timestamp_seconds = current_timestamp_seconds();
-        let backend = &self.backends[live_backend.backend_index];
+    pub async fn record_failure(&self, live_backend: &LiveBackend) -> Result<(), CbltError> {
+        let backend = &live_backend.backend;
+        let failures = backend.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < self.options.lb_max_fails {
+            return Ok(());
+        }
+        let ejections = backend.ejections.fetch_add(1, Ordering::Relaxed);
+        let backoff = self
+            .options
+            .lb_interval
+            .saturating_mul(1 << ejections.min(32))
+            .min(self.options.lb_max_backoff.max(self.options.lb_interval));
+        #[cfg(debug_assertions)]
+        debug!("Backend {} ejected for {}s", backend.url, backoff);
         *backend.alive_state.write().await = AliveState::Dead {
-            since: now_timestamp_seconds,
-            retries_left: self.options.lb_retries,
+            since: current_timestamp_seconds(),
+            backoff,
         };
         Ok(())
     }
@@ -354,136 +1737,818 @@ impl ReverseProxyState {
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
     pub async fn set_alive_backend(&self, live_backend: &LiveBackend) -> Result<(), CbltError> {
         let now_timestamp_seconds = current_timestamp_seconds();
-        let backend = &self.backends[live_backend.backend_index];
+        let backend = &live_backend.backend;
+        backend.reset_failures();
         *backend.alive_state.write().await = AliveState::Alive(now_timestamp_seconds);
         Ok(())
     }
 
+    /// Alive backend named by an affinity cookie
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn get_sticky_backend(&self, affinity: &str) -> Option<LiveBackend> {
+        let backends = self.backends.get();
+        let backend = backends
+            .iter()
+            .find(|backend| affinity_id(&backend.url) == affinity)?;
+        if !matches!(*backend.alive_state.read().await, AliveState::Alive(_)) {
+            return None;
+        }
+        // Clients pinned to a backup return to the primaries once one is back
+        if backend.backup && any_available(&backends[..primaries(&backends)]).await {
+            return None;
+        }
+        Some(LiveBackend {
+            address: heapless::String::from_str(backend.url.as_str()).ok()?,
+            backend: backend.clone(),
+        })
+    }
+
+    /// `Set-Cookie` pinning the client to the backend, unless its cookie already does
+    pub fn affinity_cookie(
+        &self,
+        live_backend: &LiveBackend,
+        affinity: Option<&str>,
+    ) -> Option<HeaderValue> {
+        let LoadBalancePolicy::Cookie(cookie) = &self.lb_policy else {
+            return None;
+        };
+        let id = affinity_id(&live_backend.backend.url);
+        if affinity == Some(id.as_str()) {
+            return None;
+        }
+        let mut value = format!("{}={}; Path={}; HttpOnly", cookie.name, id, cookie.path);
+        if let Some(max_age) = cookie.max_age {
+            value.push_str(&format!("; Max-Age={}", max_age));
+        }
+        HeaderValue::from_str(&value).ok()
+    }
+
+    /// Counts a request on the backend until the guard is dropped, for `least_conn` and `random`
+    pub fn in_flight(&self, live_backend: &LiveBackend) -> InFlight {
+        let active = live_backend.backend.active.clone();
+        active.fetch_add(1, Ordering::Relaxed);
+        InFlight(active)
+    }
+
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
     pub async fn get_next_backend(&self, addr: SocketAddr) -> Result<LiveBackend, CbltError> {
-        // Implement load balancing logic here
+        let backends = self.backends.get();
+        let primaries = primaries(&backends);
+        // The backups only come into play when none of the primaries may take the request
+        for tier in [&backends[..primaries], &backends[primaries..]] {
+            if let Some(live_backend) = self.next_in(tier, addr).await? {
+                return Ok(live_backend);
+            }
+        }
+        Err(CbltError::ResponseError {
+            details: "No healthy backends".to_string(),
+            status_code: StatusCode::BAD_GATEWAY,
+        })
+    }
+
+    /// Backend of `tier` the load balancing policy picks
+    async fn next_in(
+        &self,
+        tier: &[Backend],
+        addr: SocketAddr,
+    ) -> Result<Option<LiveBackend>, CbltError> {
+        let total_backends = tier.len();
+        if total_backends == 0 {
+            return Ok(None);
+        }
         match &self.lb_policy {
-            LoadBalancePolicy::RoundRobin => {
+            LoadBalancePolicy::RoundRobin | LoadBalancePolicy::Cookie(_) => {
                 let mut idx = self.current_backend.write().await;
-                let total_backends = self.backends.len();
                 for _ in 0..total_backends {
-                    let backend = &self.backends[*idx];
-                    let mut alive_state = backend.alive_state.write().await;
-                    match &mut *alive_state {
-                        AliveState::Alive(_timestamp) => {
-                            let live_backend = LiveBackend {
-                                address: heapless::String::from_str(backend.url.as_str())
-                                    .map_err(|_| CbltError::HeaplessError {})?,
-                                backend_index: *idx,
-                            };
-                            *idx = (*idx + 1) % total_backends;
-                            return Ok(live_backend);
-                        }
-                        AliveState::Dead {
-                            since,
-                            retries_left,
-                        } => {
-                            let now_timestamp_seconds = current_timestamp_seconds();
-
-                            if now_timestamp_seconds > (*since + self.options.lb_interval) {
-                                if *retries_left > 0 {
-                                    // Attempt to bring backend back to life
-                                    *retries_left -= 1;
-                                    *alive_state = AliveState::Alive(now_timestamp_seconds);
-                                    let live_backend = LiveBackend {
-                                        address: heapless::String::from_str(backend.url.as_str())
-                                            .map_err(|_| CbltError::HeaplessError {})?,
-                                        backend_index: *idx,
-                                    };
-                                    *idx = (*idx + 1) % total_backends;
-                                    return Ok(live_backend);
-                                } else {
-                                    // Keep backend dead
-                                    *since = now_timestamp_seconds; // Reset dead since timestamp
-                                }
-                            }
-                            *idx = (*idx + 1) % total_backends;
-                        }
+                    let index = *idx % total_backends;
+                    *idx = (index + 1) % total_backends;
+                    if let Some(live_backend) = available(&tier[index]).await? {
+                        return Ok(Some(live_backend));
                     }
                 }
-                Err(CbltError::ResponseError {
-                    details: "No healthy backends".to_string(),
-                    status_code: StatusCode::BAD_GATEWAY,
-                })
+                Ok(None)
             }
             LoadBalancePolicy::IPHash => {
-                let addr_octets = match addr.ip() {
-                    IpAddr::V4(addr) => addr.octets(),
-                    IpAddr::V6(..) => {
-                        return Err(CbltError::ResponseError {
-                            details: "IPv6 not supported".to_string(),
-                            status_code: StatusCode::BAD_GATEWAY,
-                        });
-                    }
+                let hash = match addr.ip() {
+                    IpAddr::V4(ip) => fnv1a(&ip.octets()),
+                    IpAddr::V6(ip) => fnv1a(&ip.octets()),
                 };
-                let mut backend_idx =
-                    generate_number_from_octet(addr_octets, self.backends.len() as u32);
-                let total_backends = self.backends.len();
-                for _ in 0..total_backends {
-                    let backend = &self.backends[backend_idx as usize];
-                    let mut alive_state = backend.alive_state.write().await;
-                    match &mut *alive_state {
-                        AliveState::Alive(_timestamp) => {
-                            return Ok(LiveBackend {
-                                address: heapless::String::from_str(backend.url.as_str())
-                                    .map_err(|_| CbltError::HeaplessError {})?,
-                                backend_index: backend_idx as usize,
-                            });
-                        }
-                        AliveState::Dead {
-                            since,
-                            retries_left,
-                        } => {
-                            let now_timestamp_seconds = current_timestamp_seconds();
-                            if now_timestamp_seconds > (*since + self.options.lb_interval) {
-                                if *retries_left > 0 {
-                                    // Attempt to bring backend back to life
-                                    *retries_left -= 1;
-                                    *alive_state = AliveState::Alive(now_timestamp_seconds);
-                                    return Ok(LiveBackend {
-                                        address: heapless::String::from_str(backend.url.as_str())
-                                            .map_err(|_| CbltError::HeaplessError {})?,
-                                        backend_index: backend_idx as usize,
-                                    });
-                                } else {
-                                    // Keep backend dead
-                                    *since = now_timestamp_seconds; // Reset dead since timestamp
-                                }
-                            }
-                            backend_idx = (backend_idx + 1) % total_backends as u32;
-                        }
+                let start = (hash % total_backends as u64) as usize;
+                first_available(tier, start).await
+            }
+            LoadBalancePolicy::LeastConn => {
+                // Fewest requests in flight first, ties in random order
+                let offset = random_index(total_backends);
+                let mut order: Vec<&Backend> = (0..total_backends)
+                    .map(|i| &tier[(offset + i) % total_backends])
+                    .collect();
+                order.sort_by_key(|backend| backend.active.load(Ordering::Relaxed));
+                for backend in order {
+                    if let Some(live_backend) = available(backend).await? {
+                        return Ok(Some(live_backend));
                     }
                 }
-                Err(CbltError::ResponseError {
-                    details: "No healthy backends".to_string(),
-                    status_code: StatusCode::BAD_GATEWAY,
-                })
+                Ok(None)
+            }
+            LoadBalancePolicy::Random => {
+                // Power of two choices: the less busy of two distinct random backends
+                let first = random_index(total_backends);
+                let second = (first + 1 + random_index(total_backends.max(2) - 1)) % total_backends;
+                let active = |index: usize| tier[index].active.load(Ordering::Relaxed);
+                let start = if active(second) < active(first) {
+                    second
+                } else {
+                    first
+                };
+                first_available(tier, start).await
             }
         }
     }
 }
 
-fn current_timestamp_seconds() -> u64 {
+/// First backend from the `start`th on that may take a request
+async fn first_available(
+    backends: &[Backend],
+    start: usize,
+) -> Result<Option<LiveBackend>, CbltError> {
+    for i in 0..backends.len() {
+        if let Some(live_backend) = available(&backends[(start + i) % backends.len()]).await? {
+            return Ok(Some(live_backend));
+        }
+    }
+    Ok(None)
+}
+
+/// The backend if it may take a request, an ejected one once its backoff has passed
+async fn available(backend: &Backend) -> Result<Option<LiveBackend>, CbltError> {
+    let mut alive_state = backend.alive_state.write().await;
+    match &*alive_state {
+        AliveState::Alive(_) => {}
+        AliveState::Dead { since, backoff } => {
+            let now_timestamp_seconds = current_timestamp_seconds();
+            if now_timestamp_seconds <= since + backoff {
+                return Ok(None);
+            }
+            // Let one request through to probe the backend
+            *alive_state = AliveState::Alive(now_timestamp_seconds);
+        }
+        AliveState::Unhealthy(_) => return Ok(None),
+    }
+    Ok(Some(LiveBackend {
+        address: heapless::String::from_str(backend.url.as_str())
+            .map_err(|_| CbltError::HeaplessError {})?,
+        backend: backend.clone(),
+    }))
+}
+
+/// Request in flight on a backend
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// `host:port` of a backend URL, Unix socket backends keep their `unix/<path>` address
+pub fn backend_authority(address: &str) -> Result<String, CbltError> {
+    if let Some((_, address)) = backend_protocol(address) {
+        return backend_authority(address);
+    }
+    if unix_socket_path(address).is_some() {
+        return Ok(address.to_string());
+    }
+    let uri = address
+        .parse::<Uri>()
+        .map_err(|e| CbltError::ResponseError {
+            details: e.to_string(),
+            status_code: StatusCode::BAD_GATEWAY,
+        })?;
+    let host = uri.host().ok_or(CbltError::ResponseError {
+        details: "Invalid destination URI".to_string(),
+        status_code: StatusCode::BAD_GATEWAY,
+    })?;
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+    Ok(format!("{}:{}", host, port))
+}
+
+/// Whether `url` is an HTTP one, a `unix//` socket or a uwsgi/SCGI address
+pub fn is_valid_destination(url: &str) -> bool {
+    match backend_protocol(url) {
+        Some((_, address)) => is_socket_address(address),
+        None => {
+            unix_socket_path(url).is_some()
+                || url
+                    .parse::<Uri>()
+                    .is_ok_and(|uri| uri.scheme().is_some() && uri.authority().is_some())
+        }
+    }
+}
+
+/// Whether `address` is a `host:port` or a `unix//` socket
+pub fn is_socket_address(address: &str) -> bool {
+    unix_socket_path(address).is_some()
+        || address
+            .parse::<http::uri::Authority>()
+            .is_ok_and(|authority| authority.port_u16().is_some())
+}
+
+/// Socket path of a backend address like `unix//run/app.sock`
+pub fn unix_socket_path(address: &str) -> Option<&str> {
+    address
+        .strip_prefix("unix/")
+        .filter(|path| !path.is_empty())
+}
+
+pub fn current_timestamp_seconds() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or(Duration::from_secs(0))
         .as_secs()
 }
+/// Value of a cookie sent with the request
+fn request_cookie(request: &Request<BytesMut>, name: &str) -> Option<String> {
+    request_cookies(request).find_map(|(key, value)| (key == name).then(|| value.to_string()))
+}
+
+/// Opaque name of a backend for affinity cookies, so its address is not exposed
+fn affinity_id(url: &str) -> String {
+    format!("{:016x}", fnv1a(url.as_bytes()))
+}
+
+const CANARY_SCALE: u32 = 10_000; // a hundredth of a percent
+fn canary_weight(percent: f64) -> u32 {
+    ((percent * 100.0).round() as u32).min(CANARY_SCALE)
+}
+
+fn random_index(len: usize) -> usize {
+    // Every RandomState is seeded differently, which is random enough to spread load
+    (RandomState::new().hash_one(()) % len as u64) as usize
+}
+
 const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
 const FNV_PRIME: u64 = 0x100000001b3;
-#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-fn generate_number_from_octet(octets: [u8; 4], max: u32) -> u32 {
+fn fnv1a(bytes: &[u8]) -> u64 {
     let mut hash: u64 = FNV_OFFSET_BASIS;
-    for byte in &octets {
+    for byte in bytes {
         hash ^= *byte as u64;
         hash = hash.wrapping_mul(FNV_PRIME);
     }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{
+        CanaryOptions, CookieOptions, ForwardHeaders, HeaderOp, LoadBalancePolicy, MatchCondition,
+        ReverseProxyOptions, UpstreamGroup,
+    };
+    use crate::embed::testing::serve;
+    use crate::headers::{fill_placeholders, header_placeholders};
+    use crate::reverse_proxy::{
+        current_timestamp_seconds, forwarding_headers, request_cookie, request_to_bytes,
+        upstream_placeholders, within, AliveState, ReverseProxyState,
+    };
+    use crate::CbltError;
+    use bytes::BytesMut;
+    use http::StatusCode;
+    use http::{HeaderMap, HeaderValue, Request};
+    use std::error::Error;
+    use std::net::SocketAddr;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_websocket_upgrade() -> Result<(), Box<dyn Error>> {
+        let backend = TcpListener::bind("127.0.0.1:0").await?;
+        let backend_port = backend.local_addr()?.port();
+        let (head_tx, head_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let Ok((mut stream, _)) = backend.accept().await else {
+                return;
+            };
+            let mut head = Vec::new();
+            let mut byte = [0; 1];
+            while !head.ends_with(b"\r\n\r\n") && stream.read(&mut byte).await.unwrap_or(0) == 1 {
+                head.push(byte[0]);
+            }
+            let _ = head_tx.send(String::from_utf8_lossy(&head).to_ascii_lowercase());
+            let _ = stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+                .await;
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+        let source = format!(
+            r#""*:0" {{
+    reverse_proxy "/*" "http://127.0.0.1:{}"
+}}"#,
+            backend_port
+        );
+        let (server, port) = serve(&source).await?;
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).await?;
+        // The first frame right behind the upgrade request, in the same packet
+        client
+            .write_all(b"GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\nearly")
+            .await?;
+        let mut head = Vec::new();
+        let mut byte = [0; 1];
+        while !head.ends_with(b"\r\n\r\n") && client.read(&mut byte).await? == 1 {
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head)?.to_ascii_lowercase();
+        assert!(head.starts_with("http/1.1 101"), "{}", head);
+        assert!(head.contains("upgrade: websocket"), "{}", head);
+        let backend_head = head_rx.await?;
+        assert!(
+            backend_head.contains("upgrade: websocket"),
+            "{}",
+            backend_head
+        );
+        assert!(
+            backend_head.contains("connection: upgrade"),
+            "{}",
+            backend_head
+        );
+
+        let mut echoed = [0; 5];
+        client.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"early");
+        client.write_all(b"ping").await?;
+        let mut echoed = [0; 4];
+        client.read_exact(&mut echoed).await?;
+        assert_eq!(&echoed, b"ping");
+
+        server.stop();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() -> Result<(), Box<dyn Error>> {
+        let backends = vec![
+            "http://10.0.0.1:8080".to_string(),
+            "http://10.0.0.2:8080".to_string(),
+            "http://10.0.0.3:8080".to_string(),
+        ];
+        let state = ReverseProxyState::new(
+            backends,
+            LoadBalancePolicy::RoundRobin,
+            ReverseProxyOptions::default(),
+        )?;
+        let addr: SocketAddr = "127.0.0.1:50000".parse()?;
+        let mut picked = Vec::new();
+        for _ in 0..4 {
+            picked.push(state.get_next_backend(addr).await?.address().to_string());
+        }
+        assert_eq!(
+            picked,
+            vec![
+                "http://10.0.0.1:8080",
+                "http://10.0.0.2:8080",
+                "http://10.0.0.3:8080",
+                "http://10.0.0.1:8080"
+            ]
+        );
+
+        // A dead backend is skipped until lb_interval has passed
+        let second = state.get_next_backend(addr).await?;
+        assert_eq!(second.address(), "http://10.0.0.2:8080");
+        state.record_failure(&second).await?;
+        for expected in [
+            "http://10.0.0.3:8080",
+            "http://10.0.0.1:8080",
+            "http://10.0.0.3:8080",
+        ] {
+            assert_eq!(state.get_next_backend(addr).await?.address(), expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarding_headers() -> Result<(), Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.2.3.4, 10.0.0.2"),
+        );
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        headers.insert("x-real-ip", HeaderValue::from_static("6.6.6.6"));
+
+        // An untrusted client cannot inject its own chain
+        let addr: SocketAddr = "203.0.113.9:50000".parse()?;
+        let options = ReverseProxyOptions::default();
+        let forwarded = forwarding_headers(&headers, Some("example.com"), addr, "http", &options);
+        assert_eq!(forwarded["x-forwarded-for"], "203.0.113.9");
+        assert_eq!(forwarded["x-real-ip"], "203.0.113.9");
+        assert_eq!(forwarded["x-forwarded-proto"], "http");
+        assert_eq!(forwarded["x-forwarded-host"], "example.com");
+
+        // A trusted proxy's chain is extended, the real IP is the last untrusted hop
+        let options = ReverseProxyOptions {
+            trusted_proxies: vec!["10.0.0.0/8".parse()?],
+            ..Default::default()
+        };
+        let addr: SocketAddr = "10.0.0.1:50000".parse()?;
+        let forwarded = forwarding_headers(&headers, Some("example.com"), addr, "http", &options);
+        assert_eq!(forwarded["x-forwarded-for"], "1.2.3.4, 10.0.0.2, 10.0.0.1");
+        assert_eq!(forwarded["x-real-ip"], "1.2.3.4");
+        assert_eq!(forwarded["x-forwarded-proto"], "https");
+
+        let request = Request::builder()
+            .uri("/path")
+            .header("x-forwarded-for", "6.6.6.6")
+            .header("accept", "*/*")
+            .body(BytesMut::new())?;
+        let bytes = request_to_bytes(&request, false, &forwarded, &[])?;
+        let bytes = String::from_utf8(bytes)?;
+        assert!(!bytes.contains("6.6.6.6"));
+        assert!(bytes.contains("x-forwarded-for: 1.2.3.4, 10.0.0.2, 10.0.0.1\r\n"));
+        assert!(bytes.contains("accept: */*\r\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forwarded_header() -> Result<(), Box<dyn Error>> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "forwarded",
+            HeaderValue::from_static("for=\"[2001:db8::7]:4711\";proto=https, for=10.0.0.2"),
+        );
+        let options = ReverseProxyOptions {
+            forward_headers: ForwardHeaders::Forwarded,
+            ..Default::default()
+        };
+        let addr: SocketAddr = "[2001:db8::1]:50000".parse()?;
+        let forwarded =
+            forwarding_headers(&headers, Some("example.com:8080"), addr, "https", &options);
+        assert_eq!(
+            forwarded["forwarded"],
+            "for=\"[2001:db8::1]\";host=\"example.com:8080\";proto=https"
+        );
+        assert!(!forwarded.contains_key("x-forwarded-for"));
+
+        // A trusted proxy's Forwarded header is extended and feeds X-Forwarded-For
+        let options = ReverseProxyOptions {
+            trusted_proxies: vec!["10.0.0.0/8".parse()?],
+            forward_headers: ForwardHeaders::Both,
+            ..Default::default()
+        };
+        let addr: SocketAddr = "10.0.0.1:50000".parse()?;
+        let forwarded = forwarding_headers(&headers, Some("example.com"), addr, "http", &options);
+        assert_eq!(
+            forwarded["forwarded"],
+            "for=\"[2001:db8::7]:4711\";proto=https, for=10.0.0.2, for=10.0.0.1;host=example.com;proto=http"
+        );
+        assert_eq!(
+            forwarded["x-forwarded-for"],
+            "2001:db8::7, 10.0.0.2, 10.0.0.1"
+        );
+        assert_eq!(forwarded["x-real-ip"], "2001:db8::7");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstream_headers() -> Result<(), Box<dyn Error>> {
+        let request = Request::builder()
+            .uri("/path")
+            .header("host", "example.com")
+            .header("x-app", "client")
+            .body(BytesMut::new())?;
+        let preserved = request_to_bytes(&request, false, &HeaderMap::new(), &[])?;
+        assert!(String::from_utf8(preserved)?.contains("host: example.com\r\n"));
+
+        let header_up = vec![
+            HeaderOp::Set {
+                name: "host".to_string(),
+                value: "{upstream_host}".to_string(),
+            },
+            HeaderOp::Set {
+                name: "x-app".to_string(),
+                value: "{host} via {upstream_hostport}".to_string(),
+            },
+            HeaderOp::Add {
+                name: "x-app".to_string(),
+                value: "{path}".to_string(),
+            },
+        ];
+        let mut placeholders = header_placeholders(
+            request.method(),
+            request.uri(),
+            Some("example.com"),
+            "10.0.0.1:4000".parse()?,
+        );
+        placeholders.extend(upstream_placeholders("backend.local:8080"));
+        let header_up = fill_placeholders(&header_up, &placeholders);
+        let rewritten = String::from_utf8(request_to_bytes(
+            &request,
+            false,
+            &HeaderMap::new(),
+            &header_up,
+        )?)?;
+        assert!(rewritten.contains("host: backend.local\r\n"));
+        assert!(!rewritten.contains("example.com\r\n"));
+        assert!(rewritten.contains("x-app: example.com via backend.local:8080\r\n"));
+        assert!(rewritten.contains("x-app: /path\r\n"));
+        assert!(!rewritten.contains("x-app: client"));
+
+        let header_up = vec![HeaderOp::Remove {
+            name: "x-app".to_string(),
+        }];
+        let removed = request_to_bytes(&request, false, &HeaderMap::new(), &header_up)?;
+        assert!(!String::from_utf8(removed)?.contains("x-app"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_least_conn_and_random() -> Result<(), Box<dyn Error>> {
+        let backends = vec![
+            "http://10.0.0.1:8080".to_string(),
+            "http://10.0.0.2:8080".to_string(),
+        ];
+        let addr: SocketAddr = "127.0.0.1:50000".parse()?;
+        for policy in [LoadBalancePolicy::LeastConn, LoadBalancePolicy::Random] {
+            let state =
+                ReverseProxyState::new(backends.clone(), policy, ReverseProxyOptions::default())?;
+            // With one backend busy, the other one takes every new request
+            let busy = state.get_next_backend(addr).await?;
+            let _in_flight = state.in_flight(&busy);
+            for _ in 0..10 {
+                assert_ne!(
+                    state.get_next_backend(addr).await?.address(),
+                    busy.address()
+                );
+            }
+            drop(_in_flight);
+            assert_eq!(busy.backend.active.load(Ordering::Relaxed), 0);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_canary() -> Result<(), Box<dyn Error>> {
+        let options = ReverseProxyOptions {
+            canary: Some(CanaryOptions {
+                upstream: "http://10.0.0.9:8080".to_string(),
+                percent: 100.0,
+            }),
+            ..ReverseProxyOptions::default()
+        };
+        let state = ReverseProxyState::new(
+            vec!["http://10.0.0.1:8080".to_string()],
+            LoadBalancePolicy::RoundRobin,
+            options,
+        )?;
+        assert_eq!(state.canary_percent(), Some(100.0));
+        let canary = state.pick_canary().await.ok_or("canary not picked")?;
+        assert_eq!(canary.backends.get()[0].url, "http://10.0.0.9:8080");
+
+        // An ejected canary leaves all requests to the backends
+        *canary.backends.get()[0].alive_state.write().await = AliveState::Dead {
+            since: current_timestamp_seconds(),
+            backoff: 60,
+        };
+        assert!(state.pick_canary().await.is_none());
+
+        assert!(state.set_canary_percent(0.0));
+        assert!(!state.set_canary_percent(101.0));
+        assert_eq!(state.canary_percent(), Some(0.0));
+        *canary.backends.get()[0].alive_state.write().await = AliveState::Alive(0);
+        for _ in 0..10 {
+            assert!(state.pick_canary().await.is_none());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_groups() -> Result<(), Box<dyn Error>> {
+        let options = ReverseProxyOptions {
+            groups: vec![UpstreamGroup {
+                name: "beta".to_string(),
+                conditions: vec![MatchCondition::Header {
+                    name: "x-beta".to_string(),
+                    values: vec!["1".to_string()],
+                }],
+                upstreams: vec!["http://10.0.1.1:8080".to_string()],
+            }],
+            ..ReverseProxyOptions::default()
+        };
+        let state = ReverseProxyState::new(
+            vec!["http://10.0.0.1:8080".to_string()],
+            LoadBalancePolicy::RoundRobin,
+            options,
+        )?;
+        let beta = Request::builder().header("X-Beta", "1").body(())?;
+        let group = state.group(&beta).ok_or("group not picked")?;
+        let addr: SocketAddr = "127.0.0.1:50000".parse()?;
+        assert_eq!(
+            group.get_next_backend(addr).await?.address(),
+            "http://10.0.1.1:8080"
+        );
+        let stable = Request::builder().header("X-Beta", "0").body(())?;
+        assert!(state.group(&stable).is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_backup_backends() -> Result<(), Box<dyn Error>> {
+        let options = ReverseProxyOptions {
+            backups: vec!["http://10.0.9.1:8080".to_string()],
+            ..ReverseProxyOptions::default()
+        };
+        let addr: SocketAddr = "127.0.0.1:50000".parse()?;
+        for policy in [
+            LoadBalancePolicy::RoundRobin,
+            LoadBalancePolicy::IPHash,
+            LoadBalancePolicy::LeastConn,
+            LoadBalancePolicy::Random,
+        ] {
+            let state = ReverseProxyState::new(
+                vec![
+                    "http://10.0.0.1:8080".to_string(),
+                    "http://10.0.0.2:8080".to_string(),
+                ],
+                policy,
+                options.clone(),
+            )?;
+            for _ in 0..4 {
+                let primary = state.get_next_backend(addr).await?;
+                assert_ne!(primary.address(), "http://10.0.9.1:8080");
+            }
+            for index in 0..2 {
+                *state.backends.get()[index].alive_state.write().await = AliveState::Dead {
+                    since: current_timestamp_seconds(),
+                    backoff: 60,
+                };
+            }
+            assert_eq!(
+                state.get_next_backend(addr).await?.address(),
+                "http://10.0.9.1:8080"
+            );
+            *state.backends.get()[1].alive_state.write().await = AliveState::Alive(0);
+            assert_eq!(
+                state.get_next_backend(addr).await?.address(),
+                "http://10.0.0.2:8080"
+            );
+        }
+
+        Ok(())
+    }
 
-    (hash % max as u64) as u32
+    #[tokio::test]
+    async fn test_ip_hash() -> Result<(), Box<dyn Error>> {
+        let state = ReverseProxyState::new(
+            vec![
+                "http://10.0.0.1:8080".to_string(),
+                "http://10.0.0.2:8080".to_string(),
+                "http://10.0.0.3:8080".to_string(),
+            ],
+            LoadBalancePolicy::IPHash,
+            ReverseProxyOptions::default(),
+        )?;
+        for addr in ["192.168.1.20:50000", "[2001:db8::1]:50000"] {
+            let addr: SocketAddr = addr.parse()?;
+            let first = state.get_next_backend(addr).await?;
+            for _ in 0..3 {
+                assert_eq!(
+                    state.get_next_backend(addr).await?.address(),
+                    first.address()
+                );
+            }
+            // The client moves to another backend while its own is out
+            state.record_failure(&first).await?;
+            assert_ne!(
+                state.get_next_backend(addr).await?.address(),
+                first.address()
+            );
+            state.set_alive_backend(&first).await?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cookie_affinity() -> Result<(), Box<dyn Error>> {
+        let cookie = CookieOptions {
+            name: "sticky".to_string(),
+            max_age: Some(3600),
+            ..Default::default()
+        };
+        let state = ReverseProxyState::new(
+            vec![
+                "http://10.0.0.1:8080".to_string(),
+                "http://10.0.0.2:8080".to_string(),
+            ],
+            LoadBalancePolicy::Cookie(cookie),
+            ReverseProxyOptions::default(),
+        )?;
+        let addr: SocketAddr = "127.0.0.1:50000".parse()?;
+        let first = state.get_next_backend(addr).await?;
+        let set_cookie = state
+            .affinity_cookie(&first, None)
+            .ok_or("no affinity cookie")?;
+        let set_cookie = set_cookie.to_str()?;
+        assert!(set_cookie.starts_with("sticky="));
+        assert!(set_cookie.ends_with("; Path=/; HttpOnly; Max-Age=3600"));
+
+        // The cookie sent back leads to the same backend, and is not set again
+        let id = set_cookie["sticky=".len()..]
+            .split(';')
+            .next()
+            .unwrap_or_default();
+        let request = Request::builder()
+            .header("Cookie", format!("other=1; sticky={}", id))
+            .body(BytesMut::new())?;
+        let affinity = request_cookie(&request, "sticky");
+        assert_eq!(affinity.as_deref(), Some(id));
+        for _ in 0..3 {
+            let sticky = state
+                .get_sticky_backend(id)
+                .await
+                .ok_or("no sticky backend")?;
+            assert_eq!(sticky.address(), first.address());
+            assert!(state
+                .affinity_cookie(&sticky, affinity.as_deref())
+                .is_none());
+        }
+
+        // A dead backend breaks the affinity
+        state.record_failure(&first).await?;
+        assert!(state.get_sticky_backend(id).await.is_none());
+        assert!(state.get_sticky_backend("unknown").await.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_within() -> Result<(), Box<dyn Error>> {
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok::<_, CbltError>(())
+        };
+        match within(slow, Some(Duration::from_millis(10)), None).await {
+            Err(CbltError::ResponseError { status_code, .. }) => {
+                assert_eq!(status_code, StatusCode::GATEWAY_TIMEOUT)
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(1);
+        assert_eq!(within(async { Ok(7) }, None, Some(deadline)).await?, 7);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_record_failure() -> Result<(), Box<dyn Error>> {
+        let options = ReverseProxyOptions {
+            lb_interval: 10,
+            lb_max_fails: 2,
+            lb_max_backoff: 30,
+            ..Default::default()
+        };
+        let state = ReverseProxyState::new(
+            vec!["http://10.0.0.1:8080".to_string()],
+            LoadBalancePolicy::RoundRobin,
+            options,
+        )?;
+        let addr: SocketAddr = "127.0.0.1:50000".parse()?;
+        let backend = state.get_next_backend(addr).await?;
+
+        // One failure is tolerated, the second ejects
+        state.record_failure(&backend).await?;
+        assert!(state.get_next_backend(addr).await.is_ok());
+        state.record_failure(&backend).await?;
+        assert!(state.get_next_backend(addr).await.is_err());
+
+        // Failing again after the probe doubles the backoff, up to lb_max_backoff
+        let mut backoffs = Vec::new();
+        for _ in 0..3 {
+            state.record_failure(&backend).await?;
+            if let AliveState::Dead { backoff, .. } =
+                *state.backends.get()[0].alive_state.read().await
+            {
+                backoffs.push(backoff);
+            }
+        }
+        assert_eq!(backoffs, vec![20, 30, 30]);
+
+        // A success resets the count
+        state.set_alive_backend(&backend).await?;
+        state.record_failure(&backend).await?;
+        assert!(state.get_next_backend(addr).await.is_ok());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,148 @@
+use crate::file_cache::FileBody;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::net::TcpStream;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Buffer of the copy used when sendfile(2) cannot be
+const COPY_BUF_SIZE: usize = 256 * 1024;
+/// Bytes handed to one sendfile(2) call
+#[cfg(target_os = "linux")]
+const SENDFILE_CHUNK: usize = 1024 * 1024;
+
+/// Connection a file body may be written to by the kernel, bypassing the stream
+pub trait Sendfile {
+    /// Plaintext socket under the stream when what is written reaches it unchanged
+    fn tcp_socket(&mut self) -> Option<&TcpStream> {
+        None
+    }
+
+    /// Counts bytes that went to the socket behind the stream's back
+    fn sent(&mut self, _bytes: u64) {}
+}
+
+impl Sendfile for TcpStream {
+    fn tcp_socket(&mut self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+#[cfg(unix)]
+impl Sendfile for tokio::net::UnixStream {}
+
+impl<S> Sendfile for tokio_rustls::server::TlsStream<S> {}
+
+impl Sendfile for DuplexStream {}
+
+impl<T: Sendfile + ?Sized> Sendfile for &mut T {
+    fn tcp_socket(&mut self) -> Option<&TcpStream> {
+        (**self).tcp_socket()
+    }
+
+    fn sent(&mut self, bytes: u64) {
+        (**self).sent(bytes)
+    }
+}
+
+/// Writes the body up to `length`, a file on disk of known length going to a plaintext socket
+/// with sendfile(2) and anything else through a large buffer
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn send_file_body<S>(
+    socket: &mut S,
+    body: FileBody,
+    length: Option<u64>,
+) -> Result<(), std::io::Error>
+where
+    S: AsyncWrite + Sendfile + Unpin,
+{
+    #[cfg(target_os = "linux")]
+    let body = match (body, length) {
+        (FileBody::Disk(mut file), Some(length)) if socket.tcp_socket().is_some() => {
+            use tokio::io::AsyncSeekExt;
+            let offset = file.stream_position().await?;
+            let file = file.into_std().await;
+            if let Some(tcp) = socket.tcp_socket() {
+                sendfile(tcp, &file, offset, length).await?;
+            }
+            socket.sent(length);
+            return Ok(());
+        }
+        #[cfg(feature = "io-uring")]
+        (FileBody::Uring(file), Some(length)) if socket.tcp_socket().is_some() => {
+            if let Some(tcp) = socket.tcp_socket() {
+                sendfile(tcp, file.file(), file.position(), length).await?;
+            }
+            socket.sent(length);
+            return Ok(());
+        }
+        (body, _) => body,
+    };
+    let length = length.unwrap_or(u64::MAX);
+    let mut reader = BufReader::with_capacity(COPY_BUF_SIZE, body.take(length));
+    tokio::io::copy_buf(&mut reader, socket).await?;
+    socket.flush().await
+}
+
+/// Sends the file from `offset` without copying it through userspace
+#[cfg(target_os = "linux")]
+async fn sendfile(
+    socket: &TcpStream,
+    file: &std::fs::File,
+    offset: u64,
+    length: u64,
+) -> Result<(), std::io::Error> {
+    let socket_ref = socket2::SockRef::from(socket);
+    let (mut offset, end) = (offset as usize, (offset + length) as usize);
+    while offset < end {
+        let count = std::num::NonZeroUsize::new((end - offset).min(SENDFILE_CHUNK));
+        socket.writable().await?;
+        match socket.try_io(tokio::io::Interest::WRITABLE, || {
+            socket_ref.sendfile(file, offset, count)
+        }) {
+            Ok(0) => {
+                // The file got shorter than the Content-Length already sent
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            Ok(sent) => offset += sent,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use crate::file_cache::FileBody;
+    use crate::sendfile::send_file_body;
+    use std::error::Error;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_send_file_body() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("cblt-sendfile-{}", std::process::id()));
+        let contents: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &contents)?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        let (mut server, _) = listener.accept().await?;
+
+        // A range of the file, with the rest left unsent
+        let mut file = tokio::fs::File::open(&path).await?;
+        file.seek(std::io::SeekFrom::Start(1000)).await?;
+        let sending = tokio::spawn(async move {
+            send_file_body(&mut server, FileBody::Disk(file), Some(2_500_000)).await
+        });
+        let mut received = vec![0u8; 2_500_000];
+        client.read_exact(&mut received).await?;
+        sending.await??;
+        assert!(received == contents[1000..2_501_000]);
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await?;
+        assert!(rest.is_empty());
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+}
@@ -1,37 +1,158 @@
-use crate::config::{Directive, LoadBalancePolicy};
+use crate::access_log::{AccessLogger, CountingStream, RequestLog};
+use crate::auto_ban::{self, Bans};
+use crate::config::{
+    AcmeOptions, Directive, ListenOptions, LoadBalancePolicy, MatchCondition, ProxyProtocolOptions,
+    RequestLimitOptions,
+};
+use crate::connection_limit::{self, ConnectionLimits, ConnectionSlot};
 use crate::directive::directive_process;
 use crate::error::CbltError;
+use crate::file_cache::{host_file_cache, FileCache};
+use crate::geoip::{host_geoip, GeoIp};
+use crate::headers::Placeholders;
+use crate::http2::{serve_cleartext, serve_h2, H2_ALPN};
+use crate::layer4::{self, host_layer4, Layer4Proxy};
+use crate::matcher::{host_matchers, matches_request};
+use crate::otel::{host_tracer, Tracer};
+use http::Request;
 use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
 
+use crate::acme::{self, ACME_TLS_ALPN};
+use crate::proxy_protocol;
+use crate::rate_limit::{host_rate_limiters, RateLimiter};
+use crate::request::{listener_limits, BUF_SIZE};
 use crate::reverse_proxy::ReverseProxyState;
+use crate::sendfile::Sendfile;
+use crate::throttle::{host_throttles, Throttle};
+use crate::tls::{tls_acceptor_builder, tls_files, tls_files_changed};
+#[cfg(feature = "wasm")]
+use crate::wasm::{host_wasm_plugins, WasmPlugin};
+use crate::webdav::{host_dav_locks, DavLocks};
+use bytes::BytesMut;
+#[cfg(debug_assertions)]
+use log::debug;
 use log::{error, info};
-use rustls::pki_types::pem::PemObject;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use rustls::server::Acceptor;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tokio::sync::{Notify, RwLock, Semaphore};
-use tokio_rustls::TlsAcceptor;
+use tokio::sync::{watch, RwLock, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{LazyConfigAcceptor, TlsAcceptor};
 #[cfg(feature = "trace")]
 use tracing::instrument;
 
-#[derive(Debug, Clone)]
+/// Unix socket clients have no address of their own, they are on this machine
+const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+#[derive(Debug, Clone, Serialize)]
 pub struct Server {
     pub port: u16,
     pub hosts: HashMap<String, Vec<Directive>>, // Host -> Directives
-    pub cert: Option<String>,
-    pub key: Option<String>,
+    pub listeners: Vec<Listener>,               // of all hosts on the port
+}
+
+/// Address a server accepts connections on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Listener {
+    Tcp(SocketAddr),
+    Unix {
+        path: PathBuf,
+        mode: Option<u32>, // permissions of the socket file
+    },
+}
+
+impl Listener {
+    /// Default listener of a port, on every interface
+    pub fn any(port: u16) -> Self {
+        Listener::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port))
+    }
+
+    /// Address of a `listen` directive for a host on `port`: an IP, optionally with that port,
+    /// or `unix//run/cblt.sock` for a Unix socket. A `port` of 0 only checks the syntax
+    pub fn parse(
+        address: &str,
+        port: u16,
+        options: Option<&ListenOptions>,
+    ) -> Result<Self, CbltError> {
+        if let Some(path) = address.strip_prefix("unix/") {
+            if path.is_empty() {
+                return Err(CbltError::KdlParseError {
+                    details: "listen needs a Unix socket path".to_string(),
+                });
+            }
+            return Ok(Listener::Unix {
+                path: PathBuf::from(path),
+                mode: options.and_then(|options| options.mode),
+            });
+        }
+        if let Ok(addr) = address.parse::<SocketAddr>() {
+            if port != 0 && addr.port() != port {
+                return Err(CbltError::KdlParseError {
+                    details: format!("listen address '{}' is not on port {}", address, port),
+                });
+            }
+            return Ok(Listener::Tcp(addr));
+        }
+        match address.parse::<IpAddr>() {
+            Ok(ip) => Ok(Listener::Tcp(SocketAddr::new(ip, port))),
+            Err(_) => Err(CbltError::KdlParseError {
+                details: format!("Unsupported listen address '{}'", address),
+            }),
+        }
+    }
+
+    /// Whether this wildcard address already takes the port of `other`, `[::]` is dual-stack
+    pub fn covers(&self, other: &Listener) -> bool {
+        match (self, other) {
+            (Listener::Tcp(any), Listener::Tcp(addr)) => {
+                any != addr
+                    && any.ip().is_unspecified()
+                    && any.port() == addr.port()
+                    && (any.is_ipv6() || addr.is_ipv4())
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(addr) => write!(f, "{}", addr),
+            Listener::Unix { path, .. } => write!(f, "unix/{}", path.display()),
+        }
+    }
 }
 
 pub struct ServerWorker {
     pub port: u16,
     pub lock: Arc<SettingsLock>,
-    pub is_running: Arc<AtomicBool>,
-    pub notify_stop: Arc<Notify>,
+    connections: Arc<Semaphore>, // shared by the listeners
+    connection_limits: Arc<ConnectionLimits>,
+    listeners: Mutex<Vec<Accepting>>,
+    acceptors: usize, // accept loops per TCP listener
+}
+
+/// A listener being served
+struct Accepting {
+    listener: Listener,
+    stop: watch::Sender<bool>,                // ends its accept loops
+    bound: watch::Receiver<Option<Listener>>, // once bound, port 0 replaced with the one picked
 }
 
 pub struct SettingsLock {
     settings: RwLock<Arc<ServerSettings>>,
+    pub bans: Bans,
 }
 
 impl SettingsLock {
@@ -41,44 +162,97 @@ impl SettingsLock {
         *settings = s;
     }
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-    async fn get(&self) -> Arc<ServerSettings> {
+    pub async fn get(&self) -> Arc<ServerSettings> {
         let settings = self.settings.read().await;
         settings.clone()
     }
 }
 
 pub struct ServerSettings {
-    pub hosts: HashMap<String, HostDetails>,
+    pub hosts: Arc<HashMap<String, HostDetails>>, // shared with settings rebuilt for new certificates
     pub tls_acceptor: Option<TlsAcceptor>,
+    pub on_demand: Option<AcmeOptions>, // certificates for unknown SNI names
+    pub tls_files: Vec<(PathBuf, Option<SystemTime>)>, // cert and key files the acceptor was built from
+    pub proxy_protocol: Option<ProxyProtocolOptions>,  // header read in front of every connection
+    pub request_limits: RequestLimitOptions,
+}
+
+impl ServerSettings {
+    /// Scheme clients used to reach the listener
+    pub fn scheme(&self) -> &'static str {
+        if self.tls_acceptor.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
 }
 
 pub struct HostDetails {
     pub directives: Vec<Directive>,
-    pub reverse_proxy_states: HashMap<String, ReverseProxyState>,
+    pub reverse_proxy_states: HashMap<String, Arc<ReverseProxyState>>,
+    pub access_log: Option<AccessLogger>,
+    pub matchers: HashMap<String, Vec<MatchCondition>>, // named matchers by "@name"
+    pub geoip: Option<GeoIp>,
+    pub rate_limiters: HashMap<String, RateLimiter>, // rate_limit pattern -> client buckets
+    pub throttles: HashMap<String, Throttle>,        // throttle pattern -> bandwidth
+    pub file_cache: Option<FileCache>,               // small files of its file_server
+    pub dav_locks: Option<DavLocks>,                 // locks of its WebDAV file_server
+    pub tracer: Option<Arc<Tracer>>,
+    pub layer4: Option<Layer4Proxy>, // forwarding the host's connections
+    #[cfg(feature = "wasm")]
+    pub wasm_plugins: HashMap<usize, WasmPlugin>, // index of the `wasm` directive -> plugin
 }
 
-#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-fn tls_acceptor_builder(
-    cert_path: Option<&str>,
-    key_path: Option<&str>,
-) -> Result<Option<TlsAcceptor>, CbltError> {
-    if let (Some(cert_path), Some(key_path)) = (cert_path, key_path) {
-        let certs = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
-        let key = PrivateKeyDer::from_pem_file(key_path)?;
-
-        let server_config = rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)?;
-        Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
-    } else {
-        Ok(None)
+impl HostDetails {
+    /// Whether a directive's path pattern or named matcher takes the request
+    pub fn matches<B>(&self, pattern: &str, request: &Request<B>) -> bool {
+        matches_request(pattern, request, &self.matchers)
+    }
+
+    /// First `throttle` taking the request
+    pub fn throttle<B>(&self, request: &Request<B>) -> Option<&Throttle> {
+        self.directives
+            .iter()
+            .find_map(|directive| match directive {
+                Directive::Throttle { pattern, .. } if self.matches(pattern, request) => {
+                    self.throttles.get(pattern)
+                }
+                _ => None,
+            })
+    }
+
+    /// Country of the client when the host has a `geoip` database
+    pub fn country<B>(&self, request: &Request<B>, addr: SocketAddr) -> Option<String> {
+        self.geoip.as_ref()?.country(request.headers(), addr)
+    }
+
+    /// `{geoip.country}` of the client, empty when unknown, when the host has a `geoip` database
+    pub fn geoip_placeholders<B>(&self, request: &Request<B>, addr: SocketAddr) -> Placeholders {
+        match &self.geoip {
+            Some(_) => vec![(
+                "{geoip.country}".to_string(),
+                self.country(request, addr).unwrap_or_default(),
+            )],
+            None => Vec::new(),
+        }
     }
 }
 
 impl ServerWorker {
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-    pub async fn new(server: Server) -> Result<Self, CbltError> {
-        let tls_acceptor = tls_acceptor_builder(server.cert.as_deref(), server.key.as_deref())?;
+    pub async fn new(
+        server: Server,
+        max_connections: usize,
+        acceptors: usize,
+    ) -> Result<Self, CbltError> {
+        let tls_acceptor = tls_acceptor_builder(&server.hosts)?;
+        let on_demand = acme::on_demand_options(&server.hosts);
+        let tls_files = tls_files(&server.hosts);
+        let proxy_protocol = proxy_protocol::listener_options(&server.hosts);
+        let request_limits = listener_limits(&server.hosts);
+        let connection_limits = connection_limit::listener_options(&server.hosts);
+        let bans = Bans::new(auto_ban::listener_options(&server.hosts));
 
         let mut host_details: HashMap<String, HostDetails> = HashMap::new();
         for (k, v) in server.hosts {
@@ -86,6 +260,17 @@ impl ServerWorker {
                 k.to_string(),
                 HostDetails {
                     reverse_proxy_states: init_proxy_states(&v).await?,
+                    access_log: init_access_log(&v)?,
+                    matchers: host_matchers(&v),
+                    geoip: host_geoip(&v)?,
+                    rate_limiters: host_rate_limiters(&k, &v)?,
+                    throttles: host_throttles(&v),
+                    file_cache: host_file_cache(&v),
+                    dav_locks: host_dav_locks(&v),
+                    tracer: host_tracer(&v),
+                    layer4: host_layer4(&v),
+                    #[cfg(feature = "wasm")]
+                    wasm_plugins: host_wasm_plugins(&v)?,
                     directives: v,
                 },
             );
@@ -96,50 +281,144 @@ impl ServerWorker {
             lock: Arc::new(SettingsLock {
                 settings: RwLock::new(
                     ServerSettings {
-                        hosts: host_details,
+                        hosts: Arc::new(host_details),
                         tls_acceptor,
+                        on_demand,
+                        tls_files,
+                        proxy_protocol,
+                        request_limits,
                     }
                     .into(),
                 ),
+                bans,
             }),
-            is_running: Arc::new(AtomicBool::new(true)),
-            notify_stop: Arc::new(Notify::new()),
+            connections: Arc::new(Semaphore::new(max_connections)),
+            connection_limits: Arc::new(ConnectionLimits::new(connection_limits)),
+            listeners: Mutex::new(Vec::new()),
+            acceptors,
         })
     }
 
+    /// Starts accepting on new listeners and stops the ones no longer wanted, the others keep
+    /// their sockets
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-    pub async fn run(&self, max_connections: usize) -> Result<(), CbltError> {
-        let port = self.port;
-        let settings = self.lock.clone();
-        let is_running = self.is_running.clone();
-        let notify_stop = self.notify_stop.clone();
-
-        tokio::spawn(async move {
-            if let Err(err) =
-                init_server(port, settings, max_connections, is_running, notify_stop).await
-            {
-                error!("Error: {}", err);
+    pub fn listen(&self, wanted: &[Listener]) {
+        let Ok(mut listeners) = self.listeners.lock() else {
+            return;
+        };
+        listeners.retain(|accepting| {
+            let keep = wanted.contains(&accepting.listener);
+            if !keep {
+                accepting.stop.send_replace(true);
+                info!("Stopped listening on: {}", accepting.listener);
             }
+            keep
         });
-        Ok(())
+        for listener in wanted {
+            if listeners
+                .iter()
+                .any(|accepting| &accepting.listener == listener)
+            {
+                continue;
+            }
+            let (stop, stopped) = watch::channel(false);
+            let (bind, bound) = watch::channel(None);
+            listeners.push(Accepting {
+                listener: listener.clone(),
+                stop,
+                bound,
+            });
+            let listener = listener.clone();
+            let settings_lock = self.lock.clone();
+            let connections = self.connections.clone();
+            let limits = self.connection_limits.clone();
+            let acceptors = self.acceptors;
+            tokio::spawn(async move {
+                let init = init_server(
+                    &listener,
+                    settings_lock,
+                    connections,
+                    limits,
+                    stopped,
+                    bind,
+                    acceptors,
+                );
+                if let Err(err) = init.await {
+                    error!("Error on {}: {}", listener, err);
+                }
+            });
+        }
+    }
+
+    pub fn listeners(&self) -> Vec<Listener> {
+        self.listeners
+            .lock()
+            .map(|listeners| {
+                listeners
+                    .iter()
+                    .map(|accepting| accepting.listener.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Addresses of the TCP listeners, waiting for them to be bound, with the port the system
+    /// picked for port 0. Listeners failing to bind are left out
+    pub async fn local_addrs(&self) -> Vec<SocketAddr> {
+        let bound: Vec<_> = self
+            .listeners
+            .lock()
+            .map(|listeners| {
+                listeners
+                    .iter()
+                    .map(|accepting| accepting.bound.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut addrs = Vec::new();
+        for mut bound in bound {
+            if let Ok(listener) = bound.wait_for(Option::is_some).await {
+                if let Some(Listener::Tcp(addr)) = &*listener {
+                    addrs.push(*addr);
+                }
+            }
+        }
+        addrs
+    }
+
+    pub fn stop(&self) {
+        self.listen(&[]);
     }
 
     #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
-    pub async fn update(
-        &self,
-        hosts: HashMap<String, Vec<Directive>>,
-        cert_path: Option<String>,
-        key_path: Option<String>,
-    ) -> Result<(), CbltError> {
-        let cert_path_opt = cert_path.as_deref();
-        let key_path_opt = key_path.as_deref();
-        let tls_acceptor = tls_acceptor_builder(cert_path_opt, key_path_opt)?;
+    pub async fn update(&self, hosts: HashMap<String, Vec<Directive>>) -> Result<(), CbltError> {
+        let tls_acceptor = tls_acceptor_builder(&hosts)?;
+        let on_demand = acme::on_demand_options(&hosts);
+        let tls_files = tls_files(&hosts);
+        let proxy_protocol = proxy_protocol::listener_options(&hosts);
+        let request_limits = listener_limits(&hosts);
+        self.connection_limits
+            .set_options(connection_limit::listener_options(&hosts));
+        self.lock
+            .bans
+            .set_options(auto_ban::listener_options(&hosts));
         let mut host_details: HashMap<String, HostDetails> = HashMap::new();
         for (k, v) in hosts {
             host_details.insert(
                 k.to_string(),
                 HostDetails {
                     reverse_proxy_states: init_proxy_states(&v).await?,
+                    access_log: init_access_log(&v)?,
+                    matchers: host_matchers(&v),
+                    geoip: host_geoip(&v)?,
+                    rate_limiters: host_rate_limiters(&k, &v)?,
+                    throttles: host_throttles(&v),
+                    file_cache: host_file_cache(&v),
+                    dav_locks: host_dav_locks(&v),
+                    tracer: host_tracer(&v),
+                    layer4: host_layer4(&v),
+                    #[cfg(feature = "wasm")]
+                    wasm_plugins: host_wasm_plugins(&v)?,
                     directives: v,
                 },
             );
@@ -148,12 +427,47 @@ impl ServerWorker {
         self.lock
             .update(
                 ServerSettings {
-                    hosts: host_details,
+                    hosts: Arc::new(host_details),
+                    tls_acceptor,
+                    on_demand,
+                    tls_files,
+                    proxy_protocol,
+                    request_limits,
+                }
+                .into(),
+            )
+            .await;
+        Ok(())
+    }
+
+    /// Rebuilds the TLS acceptor when a certificate or key file changed, keeping the hosts as they are
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn reload_certificates(&self) -> Result<(), CbltError> {
+        let settings = self.lock.get().await;
+        if !tls_files_changed(&settings.tls_files) {
+            return Ok(());
+        }
+        let hosts: HashMap<String, Vec<Directive>> = settings
+            .hosts
+            .iter()
+            .map(|(name, host)| (name.clone(), host.directives.clone()))
+            .collect();
+        // A half-written file fails here and is picked up again on the next check
+        let tls_acceptor = tls_acceptor_builder(&hosts)?;
+        self.lock
+            .update(
+                ServerSettings {
+                    hosts: settings.hosts.clone(),
                     tls_acceptor,
+                    on_demand: settings.on_demand.clone(),
+                    tls_files: tls_files(&hosts),
+                    proxy_protocol: settings.proxy_protocol.clone(),
+                    request_limits: settings.request_limits.clone(),
                 }
                 .into(),
             )
             .await;
+        info!("TLS certificates reloaded on port: {}", self.port);
         Ok(())
     }
 }
@@ -161,8 +475,8 @@ impl ServerWorker {
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 async fn init_proxy_states(
     directives: &Vec<Directive>,
-) -> Result<HashMap<String, ReverseProxyState>, CbltError> {
-    let mut reverse_proxy_states: HashMap<String, ReverseProxyState> = HashMap::new(); // (pattern -> ReverseProxyState)
+) -> Result<HashMap<String, Arc<ReverseProxyState>>, CbltError> {
+    let mut reverse_proxy_states: HashMap<String, Arc<ReverseProxyState>> = HashMap::new(); // (pattern -> ReverseProxyState)
     for directive in directives {
         match directive {
             Directive::ReverseProxy {
@@ -179,26 +493,7 @@ async fn init_proxy_states(
                     options.clone(),
                 )?;
 
-                // if let Some(health_uri) = &options.lb_retries {
-                //     let interval = options
-                //         .lb_interval
-                //         .as_deref()
-                //         .unwrap_or("10s")
-                //         .parse::<humantime::Duration>()
-                //         .unwrap_or(Duration::from(std::time::Duration::from_secs(10)))
-                //         .as_secs();
-                //     let timeout = options
-                //         .lb_timeout
-                //         .as_deref()
-                //         .unwrap_or("2s")
-                //         .parse::<humantime::Duration>()
-                //         .unwrap_or(Duration::from(std::time::Duration::from_secs(2)))
-                //         .as_secs();
-                //     reverse_proxy_state
-                //         .start_health_checks(health_uri.clone(), interval, timeout)
-                //         .await;
-                // }
-                reverse_proxy_states.insert(pattern.clone(), reverse_proxy_state);
+                reverse_proxy_states.insert(pattern.clone(), Arc::new(reverse_proxy_state));
             }
             _ => continue,
         }
@@ -207,66 +502,480 @@ async fn init_proxy_states(
     Ok(reverse_proxy_states)
 }
 
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+fn init_access_log(directives: &[Directive]) -> Result<Option<AccessLogger>, CbltError> {
+    directives
+        .iter()
+        .find_map(|directive| match directive {
+            Directive::AccessLog { options } => Some(AccessLogger::new(options)),
+            _ => None,
+        })
+        .transpose()
+}
+
 #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
 async fn init_server(
-    port: u16,
+    listener: &Listener,
     settings_lock: Arc<SettingsLock>,
-    max_connections: usize,
-    is_running: Arc<AtomicBool>,
-    notify_stop: Arc<Notify>,
+    connections: Arc<Semaphore>,
+    limits: Arc<ConnectionLimits>,
+    mut stop: watch::Receiver<bool>,
+    bind: watch::Sender<Option<Listener>>,
+    acceptors: usize,
 ) -> Result<(), CbltError> {
-    let semaphore = Arc::new(Semaphore::new(max_connections));
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(&addr).await?;
-    info!("Listening on port: {}", port);
-    while is_running.load(Ordering::SeqCst) {
-        tokio::select! {
-            _ = notify_stop.notified() => {
-                break;
-            },
-            Ok((mut stream, addr)) =  listener.accept() => {
-                let permit = semaphore.clone().acquire_owned().await?;
-                let settings = settings_lock.clone();
-                tokio::spawn(async move {
-                    let _permit = permit;
-                    let settings = settings.get().await;
-                    let acceptor = settings.tls_acceptor.clone();
-                    match acceptor.as_ref() {
-                        None => {
-                            if let Err(err) = directive_process(
-                                &mut stream,
-                                settings.clone(),
-                                addr,
-                            )
-                            .await
-                            {
-                                #[cfg(debug_assertions)]
-                                error!("Error: {}", err);
+    match listener {
+        Listener::Tcp(addr) => {
+            // Several sockets on one address need SO_REUSEPORT, the kernel spreads connections
+            let acceptors = if cfg!(unix) { acceptors.max(1) } else { 1 };
+            let mut sockets = Vec::with_capacity(acceptors);
+            let first = bind_tcp(*addr, acceptors > 1)?;
+            // The others join the port the first got, picked by the system for port 0
+            let addr = first.local_addr()?;
+            sockets.push(first);
+            for _ in 1..acceptors {
+                sockets.push(bind_tcp(addr, true)?);
+            }
+            let bound = Listener::Tcp(addr);
+            if acceptors > 1 {
+                info!("Listening on: {} with {} acceptors", bound, acceptors);
+            } else {
+                info!("Listening on: {}", bound);
+            }
+            bind.send_replace(Some(bound));
+            let mut accept_loops = JoinSet::new();
+            for tcp in sockets {
+                let mut stop = stop.clone();
+                let connections = connections.clone();
+                let limits = limits.clone();
+                let settings_lock = settings_lock.clone();
+                accept_loops.spawn(async move {
+                    loop {
+                        tokio::select! {
+                            _ = stop.changed() => {
+                                break;
+                            },
+                            Ok((stream, addr)) = tcp.accept() => {
+                                // IPv4 clients of a dual-stack socket arrive as mapped IPv6 addresses
+                                let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+                                spawn_connection(stream, addr, &connections, &limits, &settings_lock).await?;
                             }
                         }
-                        Some(acceptor) => match acceptor.accept(stream).await {
-                            Ok(mut stream) => {
-                                if let Err(err) = directive_process(
-                                    &mut stream,
-                                    settings.clone(),
-                                    addr,
-                                )
-                                .await
-                                {
-                                    #[cfg(debug_assertions)]
-                                    error!("Error: {}", err);
-                                }
-                            }
-                            Err(err) => {
-                                #[cfg(debug_assertions)]
-                                error!("TLS Error: {}", err);
-                            }
-                        },
                     }
+                    Ok::<(), CbltError>(())
                 });
+            }
+            while let Some(accept_loop) = accept_loops.join_next().await {
+                match accept_loop {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => error!("Error on {}: {}", listener, err),
+                    Err(err) => error!("Error on {}: {}", listener, err),
+                }
+            }
+        }
+        #[cfg(unix)]
+        Listener::Unix { path, mode } => {
+            use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+            // A socket file left by an earlier run would make the bind fail
+            if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let unix = tokio::net::UnixListener::bind(path)?;
+            if let Some(mode) = mode {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(*mode))?;
+            }
+            bind.send_replace(Some(listener.clone()));
+            info!("Listening on: {}", listener);
+            loop {
+                tokio::select! {
+                    _ = stop.changed() => {
+                        break;
+                    },
+                    Ok((stream, _)) = unix.accept() => {
+                        spawn_connection(stream, UNIX_PEER, &connections, &limits, &settings_lock).await?;
+                    }
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        Listener::Unix { .. } => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
 
+/// Listening socket of `addr`, `[::]` also takes IPv4 whatever the system default
+fn bind_tcp(addr: SocketAddr, reuse_port: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    // As std does, so a restart does not wait for old connections to time out
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn spawn_connection<S>(
+    stream: S,
+    addr: SocketAddr,
+    connections: &Arc<Semaphore>,
+    limits: &Arc<ConnectionLimits>,
+    settings_lock: &Arc<SettingsLock>,
+) -> Result<(), CbltError>
+where
+    S: AsyncRead + AsyncWrite + Sendfile + Unpin + Send + 'static,
+{
+    // Shed before waiting for a permit, the stream is closed when dropped
+    let Some(slot) = limits.open() else {
+        #[cfg(debug_assertions)]
+        debug!("Connection limit reached, closing connection from {}", addr);
+        return Ok(());
+    };
+    let permit = connections.clone().acquire_owned().await?;
+    let settings_lock = settings_lock.clone();
+    tokio::spawn(async move {
+        let _permit = permit;
+        handle_connection(stream, settings_lock, addr, slot).await;
+    });
+    Ok(())
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn handle_connection<S>(
+    mut stream: S,
+    settings_lock: Arc<SettingsLock>,
+    addr: SocketAddr,
+    mut slot: ConnectionSlot,
+) where
+    S: AsyncRead + AsyncWrite + Sendfile + Unpin + Send + 'static,
+{
+    let settings = settings_lock.get().await;
+    // The load balancer in front tells who the client is before anything else is sent
+    let addr = match &settings.proxy_protocol {
+        Some(options) => match proxy_protocol::accept(&mut stream, addr, options).await {
+            Ok(client) => client,
+            Err(err) => {
+                #[cfg(debug_assertions)]
+                error!("PROXY protocol error from {}: {}", addr, err);
+                return;
             }
+        },
+        None => addr,
+    };
+    // Unix socket clients without a PROXY header share one address
+    if addr != UNIX_PEER {
+        if settings_lock.bans.is_banned(addr.ip()) {
+            #[cfg(debug_assertions)]
+            debug!("Refused connection from banned {}", addr.ip());
+            return;
+        }
+        if !slot.client(addr.ip()) {
+            #[cfg(debug_assertions)]
+            debug!("Too many connections from {}", addr.ip());
+            return;
         }
     }
+    if layer4::is_layer4(&settings.hosts) {
+        if let Err(_err) = layer4::serve(stream, &settings, addr).await {
+            #[cfg(debug_assertions)]
+            error!("Layer4 error from {}: {}", addr, _err);
+        }
+        return;
+    }
+    match settings.tls_acceptor.clone() {
+        None => {
+            if let Err(err) = serve_cleartext(stream, settings_lock, addr).await {
+                #[cfg(debug_assertions)]
+                error!("Error: {}", err);
+            }
+        }
+        Some(acceptor) => {
+            let accepted = match &settings.on_demand {
+                None => acceptor.accept(stream).await,
+                Some(options) => accept_on_demand(stream, &acceptor, &settings, options).await,
+            };
+            match accepted {
+                // A TLS-ALPN-01 validation only needs the handshake
+                Ok(stream) if stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN) => {}
+                Ok(stream) if stream.get_ref().1.alpn_protocol() == Some(H2_ALPN) => {
+                    if let Err(err) = serve_h2(stream, settings_lock, addr).await {
+                        #[cfg(debug_assertions)]
+                        error!("Error: {}", err);
+                    }
+                }
+                Ok(mut stream) => {
+                    if let Err(err) = serve_connection(&mut stream, settings_lock, addr).await {
+                        #[cfg(debug_assertions)]
+                        error!("Error: {}", err);
+                    }
+                }
+                Err(err) => {
+                    #[cfg(debug_assertions)]
+                    error!("TLS Error: {}", err);
+                }
+            }
+        }
+    }
+}
+
+/// Reads the ClientHello first, so a certificate can be issued before the handshake goes on
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn accept_on_demand<S>(
+    stream: S,
+    acceptor: &TlsAcceptor,
+    settings: &ServerSettings,
+    options: &AcmeOptions,
+) -> std::io::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+    let client_hello = start.client_hello();
+    let challenge = client_hello
+        .alpn()
+        .into_iter()
+        .flatten()
+        .any(|protocol| protocol == ACME_TLS_ALPN);
+    let name = client_hello.server_name().map(str::to_ascii_lowercase);
+    if let Some(name) = name.filter(|name| !challenge && !settings.hosts.contains_key(name)) {
+        acme::issue_on_demand(&name, options).await;
+    }
+    start.into_stream(acceptor.config().clone()).await
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn serve_connection<S>(
+    socket: &mut S,
+    settings_lock: Arc<SettingsLock>,
+    addr: SocketAddr,
+) -> Result<(), CbltError>
+where
+    S: AsyncReadExt + AsyncWriteExt + Sendfile + Unpin + Send,
+{
+    let mut buffer = BytesMut::with_capacity(BUF_SIZE);
+    loop {
+        if buffer.is_empty() {
+            // Wait for the next request on an idle keep-alive connection
+            let idle = settings_lock.get().await.request_limits.keep_alive_timeout;
+            match timeout(idle, socket.read_buf(&mut buffer)).await {
+                Ok(Ok(bytes_read)) if bytes_read > 0 => {}
+                _ => break,
+            }
+        }
+        // Pick up reloaded settings between requests
+        let settings = settings_lock.get().await;
+        let started = Instant::now();
+        let mut counting_socket = CountingStream::new(&mut *socket);
+        let mut request_log = RequestLog::default();
+        let keep_alive = directive_process(
+            &mut counting_socket,
+            &mut buffer,
+            settings.clone(),
+            addr,
+            &mut request_log,
+        )
+        .await;
+        let access_log = request_log
+            .host
+            .as_ref()
+            .and_then(|host| settings.hosts.get(host))
+            .and_then(|host| host.access_log.as_ref());
+        if let Some(access_log) = access_log {
+            access_log.write(
+                addr,
+                &request_log,
+                counting_socket.written(),
+                started.elapsed(),
+            );
+        }
+        if let (Some(span), Some(status)) = (request_log.span.take(), request_log.status()) {
+            span.end(status);
+        }
+        let banned = request_log.status().is_some_and(|status| {
+            addr != UNIX_PEER && settings_lock.bans.record(addr.ip(), status)
+        });
+        if banned || !keep_alive? {
+            break;
+        }
+    }
+    let _ = socket.shutdown().await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use crate::embed::testing::exchange;
+    use crate::embed::testing::serve;
+    #[cfg(unix)]
+    use crate::server::bind_tcp;
+    #[cfg(unix)]
+    use crate::CbltServer;
+    use std::error::Error;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    const HOST: &str = "\"*:0\" {\n    redir \"https://example.com\"\n}";
+
+    /// Reads until `count` responses came, the connection staying open
+    async fn read_responses(
+        stream: &mut TcpStream,
+        count: usize,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut received = Vec::new();
+        let mut buf = [0; 1024];
+        while String::from_utf8_lossy(&received)
+            .matches("HTTP/1.1 302")
+            .count()
+            < count
+        {
+            let read =
+                tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await??;
+            if read == 0 {
+                return Err("connection closed".into());
+            }
+            received.extend_from_slice(&buf[..read]);
+        }
+        Ok(String::from_utf8(received)?)
+    }
+
+    /// Whether the server closes the connection after what it sent
+    async fn closed(stream: &mut TcpStream) -> Result<bool, Box<dyn Error>> {
+        let mut rest = Vec::new();
+        let read =
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await;
+        Ok(matches!(read, Ok(Ok(_))))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_acceptors() -> Result<(), Box<dyn Error>> {
+        use socket2::SockRef;
+
+        let server = CbltServer::new()
+            .cbltfile(HOST)?
+            .acceptors(4)
+            .start()
+            .await?;
+        let addr = *server
+            .local_addrs()
+            .await
+            .first()
+            .ok_or("no listener bound")?;
+        for _ in 0..16 {
+            let response = exchange(
+                addr.port(),
+                "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+            assert!(response.starts_with("HTTP/1.1 302"), "{}", response);
+        }
+        // Joining the port only works when every socket on it set SO_REUSEPORT
+        let joined = bind_tcp(addr, true)?;
+        assert!(SockRef::from(&joined).reuse_port()?);
+        drop(joined);
+        server.stop();
+
+        // One acceptor keeps the port to itself, so a conflicting bind fails loudly
+        let server = CbltServer::new().cbltfile(HOST)?.start().await?;
+        let addr = *server
+            .local_addrs()
+            .await
+            .first()
+            .ok_or("no listener bound")?;
+        assert!(bind_tcp(addr, true).is_err());
+        assert!(bind_tcp(addr, false).is_err());
+        server.stop();
+        let single = bind_tcp("127.0.0.1:0".parse()?, false)?;
+        assert!(!SockRef::from(&single).reuse_port()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() -> Result<(), Box<dyn Error>> {
+        let (server, port) = serve(HOST).await?;
+
+        // Sequential requests on one connection
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        for _ in 0..2 {
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await?;
+            read_responses(&mut stream, 1).await?;
+        }
+
+        // Pipelined ones, answered in order
+        stream
+            .write_all(b"GET /a HTTP/1.1\r\nHost: localhost\r\n\r\nGET /b HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        read_responses(&mut stream, 2).await?;
+        server.stop();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_close() -> Result<(), Box<dyn Error>> {
+        let (server, port) = serve(HOST).await?;
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        read_responses(&mut stream, 1).await?;
+        assert!(closed(&mut stream).await?);
+
+        // HTTP/1.0 closes unless asked otherwise
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream
+            .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut response))
+            .await??;
+        assert!(response.starts_with("HTTP/1.1 302"));
+
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream
+            .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\nConnection: keep-alive\r\n\r\n")
+            .await?;
+        read_responses(&mut stream, 1).await?;
+        stream
+            .write_all(b"GET / HTTP/1.0\r\nHost: localhost\r\n\r\n")
+            .await?;
+        read_responses(&mut stream, 1).await?;
+        assert!(closed(&mut stream).await?);
+        server.stop();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_timeout() -> Result<(), Box<dyn Error>> {
+        let (server, port) = serve(
+            "\"*:0\" {\n    request_limits {\n        keep_alive_timeout \"200ms\"\n    }\n    redir \"https://example.com\"\n}",
+        )
+        .await?;
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await?;
+        read_responses(&mut stream, 1).await?;
+        // Well before the default 60s
+        assert!(closed(&mut stream).await?);
+        server.stop();
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use crate::config::{Directive, ThrottleScope};
+use crate::sendfile::Sendfile;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Token bucket of bytes, refilled at `rate` per second and holding a second's worth
+pub struct Bandwidth {
+    rate: f64,
+    bucket: Mutex<(f64, Instant)>, // bytes available, when last refilled
+}
+
+impl Bandwidth {
+    pub fn new(rate: u64) -> Arc<Self> {
+        Arc::new(Bandwidth {
+            rate: rate as f64,
+            bucket: Mutex::new((rate as f64, Instant::now())),
+        })
+    }
+
+    /// Up to `want` bytes that may go now, or how long until one may
+    fn take(&self, want: usize) -> Result<usize, Duration> {
+        let Ok(mut bucket) = self.bucket.lock() else {
+            return Ok(want);
+        };
+        let now = Instant::now();
+        let (available, updated) = *bucket;
+        let available =
+            (available + now.duration_since(updated).as_secs_f64() * self.rate).min(self.rate);
+        if available < 1.0 {
+            *bucket = (available, now);
+            return Err(Duration::from_secs_f64((1.0 - available) / self.rate));
+        }
+        let granted = want.min(available as usize);
+        *bucket = (available - granted as f64, now);
+        Ok(granted)
+    }
+
+    /// Gives back what was granted but not transferred
+    fn refund(&self, bytes: usize) {
+        if let Ok(mut bucket) = self.bucket.lock() {
+            bucket.0 = (bucket.0 + bytes as f64).min(self.rate);
+        }
+    }
+}
+
+/// Rates of a `throttle` directive, with its buckets when they are shared by the whole route
+pub struct Throttle {
+    rate: u64,
+    upload: Option<u64>,
+    shared: Option<(Arc<Bandwidth>, Option<Arc<Bandwidth>>)>,
+}
+
+impl Throttle {
+    /// Bucket for the responses of a request
+    pub fn download(&self) -> Arc<Bandwidth> {
+        match &self.shared {
+            Some((download, _)) => download.clone(),
+            None => Bandwidth::new(self.rate),
+        }
+    }
+
+    /// Bucket for the body of a request
+    pub fn upload(&self) -> Option<Arc<Bandwidth>> {
+        match &self.shared {
+            Some((_, upload)) => upload.clone(),
+            None => self.upload.map(Bandwidth::new),
+        }
+    }
+}
+
+/// Throttles of the host's `throttle` directives, by pattern
+pub fn host_throttles(directives: &[Directive]) -> HashMap<String, Throttle> {
+    directives
+        .iter()
+        .filter_map(|directive| match directive {
+            Directive::Throttle {
+                pattern,
+                rate,
+                options,
+            } => {
+                let shared = (options.per == ThrottleScope::Route)
+                    .then(|| (Bandwidth::new(*rate), options.upload.map(Bandwidth::new)));
+                let throttle = Throttle {
+                    rate: *rate,
+                    upload: options.upload,
+                    shared,
+                };
+                Some((pattern.clone(), throttle))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Stream whose writes and reads are paced by their buckets, unlimited without one
+pub struct Throttled<S> {
+    inner: S,
+    write: Option<Arc<Bandwidth>>,
+    read: Option<Arc<Bandwidth>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+    read_delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, write: Option<Arc<Bandwidth>>, read: Option<Arc<Bandwidth>>) -> Self {
+        Throttled {
+            inner,
+            write,
+            read,
+            write_delay: None,
+            read_delay: None,
+        }
+    }
+}
+
+/// Bytes the bucket grants now, after waiting out its delay
+fn poll_grant(
+    bandwidth: &Bandwidth,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    want: usize,
+    cx: &mut Context<'_>,
+) -> Poll<usize> {
+    loop {
+        if let Some(sleeping) = delay {
+            if sleeping.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            *delay = None;
+        }
+        match bandwidth.take(want) {
+            Ok(granted) => return Poll::Ready(granted),
+            Err(wait) => *delay = Some(Box::pin(sleep(wait))),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Throttled<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let Some(bandwidth) = &this.read else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let granted = match poll_grant(bandwidth, &mut this.read_delay, buf.remaining(), cx) {
+            Poll::Ready(granted) => granted,
+            Poll::Pending => return Poll::Pending,
+        };
+        let mut limited = vec![0u8; granted];
+        let mut limited = ReadBuf::new(&mut limited);
+        let poll = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        bandwidth.refund(granted - limited.filled().len());
+        buf.put_slice(limited.filled());
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Throttled<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let Some(bandwidth) = &this.write else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        let granted = match poll_grant(bandwidth, &mut this.write_delay, buf.len(), cx) {
+            Poll::Ready(granted) => granted,
+            Poll::Pending => return Poll::Pending,
+        };
+        let poll = Pin::new(&mut this.inner).poll_write(cx, &buf[..granted]);
+        match &poll {
+            Poll::Ready(Ok(written)) => bandwidth.refund(granted - written),
+            _ => bandwidth.refund(granted),
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Only an unthrottled stream lets the kernel write for it
+impl<S: Sendfile> Sendfile for Throttled<S> {
+    fn tcp_socket(&mut self) -> Option<&TcpStream> {
+        match self.write {
+            Some(_) => None,
+            None => self.inner.tcp_socket(),
+        }
+    }
+
+    fn sent(&mut self, bytes: u64) {
+        self.inner.sent(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::throttle::{Bandwidth, Throttled};
+    use std::error::Error;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_throttled_stream() -> Result<(), Box<dyn Error>> {
+        // A second's worth goes at once, the rest at the rate
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut server = Throttled::new(server, Some(Bandwidth::new(100_000)), None);
+        let started = Instant::now();
+        let writer = tokio::spawn(async move { server.write_all(&[7u8; 250_000]).await });
+        let mut received = vec![0u8; 250_000];
+        client.read_exact(&mut received).await?;
+        writer.await??;
+        assert!(started.elapsed() >= Duration::from_millis(1500));
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(received.iter().all(|byte| *byte == 7));
+
+        let (client, mut server) = tokio::io::duplex(64 * 1024);
+        let mut client = Throttled::new(client, None, Some(Bandwidth::new(100_000)));
+        let started = Instant::now();
+        let writer = tokio::spawn(async move { server.write_all(&[7u8; 150_000]).await });
+        client.read_exact(&mut received[..150_000]).await?;
+        writer.await??;
+        assert!(started.elapsed() >= Duration::from_millis(500));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // Without a bucket the stream is left alone
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut server = Throttled::new(server, None, None);
+        let started = Instant::now();
+        let writer = tokio::spawn(async move { server.write_all(&[1u8; 250_000]).await });
+        client.read_exact(&mut received).await?;
+        writer.await??;
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        Ok(())
+    }
+}
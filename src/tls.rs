@@ -0,0 +1,479 @@
+use crate::acme::{self, ACME_TLS_ALPN};
+use crate::config::{Directive, ReverseProxyOptions, TlsOptions, TlsVersion};
+use crate::error::CbltError;
+use crate::http2::H2_ALPN;
+use crate::ParsedHost;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    ClientConfig, ConfigBuilder, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme, SupportedProtocolVersion, WantsVerifier,
+};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Picks the certificate of the host named in the TLS SNI extension
+#[derive(Debug)]
+pub struct SniResolver {
+    certs: HashMap<String, Arc<CertifiedKey>>, // lowercase host, may be "*.example.com"
+    default: Option<Arc<CertifiedKey>>,        // for clients without SNI or unknown names
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let server_name = client_hello.server_name();
+        let mut alpn = client_hello.alpn().into_iter().flatten();
+        if alpn.any(|protocol| protocol == ACME_TLS_ALPN) {
+            return server_name.and_then(acme::tls_alpn01_certificate);
+        }
+        select_cert(&self.certs, None, server_name)
+            .cloned()
+            .or_else(|| server_name.and_then(acme::certificate))
+            .or_else(|| self.default.clone())
+    }
+}
+
+/// Exact host first, then a wildcard for the first label, then the default
+pub(crate) fn select_cert<'a, T>(
+    certs: &'a HashMap<String, T>,
+    default: Option<&'a T>,
+    server_name: Option<&str>,
+) -> Option<&'a T> {
+    let Some(name) = server_name.map(|name| name.to_ascii_lowercase()) else {
+        return default;
+    };
+    certs
+        .get(&name)
+        .or_else(|| {
+            let (_, parent) = name.split_once('.')?;
+            certs.get(&format!("*.{}", parent))
+        })
+        .or(default)
+}
+
+/// Certificate chain and private key from PEM files
+pub fn load_certified_key(
+    cert_path: impl AsRef<Path>,
+    key_path: impl AsRef<Path>,
+) -> Result<Arc<CertifiedKey>, CbltError> {
+    let chain = CertificateDer::pem_file_iter(cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key_path)?;
+    let provider = rustls::ServerConfig::builder().crypto_provider().clone();
+    Ok(Arc::new(CertifiedKey::from_der(chain, key, &provider)?))
+}
+
+/// Certificate and key files of the `tls` directives with their modification time
+pub fn tls_files(hosts: &HashMap<String, Vec<Directive>>) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut files: Vec<PathBuf> = hosts
+        .values()
+        .flatten()
+        .filter_map(|d| match d {
+            Directive::TlS { cert, key } => Some([PathBuf::from(cert), PathBuf::from(key)]),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+    files.sort();
+    files.dedup();
+    files
+        .into_iter()
+        .map(|file| {
+            let modified = modified(&file);
+            (file, modified)
+        })
+        .collect()
+}
+
+pub fn tls_files_changed(files: &[(PathBuf, Option<SystemTime>)]) -> bool {
+    files
+        .iter()
+        .any(|(file, modified)| self::modified(file) != *modified)
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+    std::fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+/// Config builder limited to the protocol versions and cipher suites of the options
+pub fn server_config_builder(
+    options: Option<&TlsOptions>,
+) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, CbltError> {
+    let Some(options) = options else {
+        return Ok(ServerConfig::builder());
+    };
+    let mut provider = CryptoProvider::clone(ServerConfig::builder().crypto_provider());
+    if !options.ciphers.is_empty() {
+        let mut suites = Vec::new();
+        for name in &options.ciphers {
+            let suite = provider
+                .cipher_suites
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()) == *name)
+                .ok_or_else(|| CbltError::InvalidTlsOptions {
+                    details: format!("Unknown cipher suite '{}'", name),
+                })?;
+            suites.push(*suite);
+        }
+        provider.cipher_suites = suites;
+    }
+    let versions: Vec<&'static SupportedProtocolVersion> = [
+        (TlsVersion::Tls12, &rustls::version::TLS12),
+        (TlsVersion::Tls13, &rustls::version::TLS13),
+    ]
+    .into_iter()
+    .filter(|(version, _)| {
+        options.min_version.is_none_or(|min| *version >= min)
+            && options.max_version.is_none_or(|max| *version <= max)
+    })
+    .map(|(_, protocol)| protocol)
+    .collect();
+    Ok(
+        ServerConfig::builder_with_provider(Arc::new(provider))
+            .with_protocol_versions(&versions)?,
+    )
+}
+
+/// TLS acceptor serving the certificates of every host on a port that has a `tls` directive
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn tls_acceptor_builder(
+    hosts: &HashMap<String, Vec<Directive>>,
+) -> Result<Option<TlsAcceptor>, CbltError> {
+    let mut hostnames: Vec<&String> = hosts.keys().collect();
+    hostnames.sort();
+    let mut certs = HashMap::new();
+    let mut default = None;
+    for host in hostnames {
+        let Some((cert_path, key_path)) = hosts[host].iter().find_map(|d| match d {
+            Directive::TlS { cert, key } => Some((cert, key)),
+            _ => None,
+        }) else {
+            continue;
+        };
+        let certified_key = load_certified_key(cert_path, key_path)?;
+
+        let name = ParsedHost::from_str(host).host.to_ascii_lowercase();
+        if name == "*" || default.is_none() {
+            default = Some(certified_key.clone());
+        }
+        certs.insert(name, certified_key);
+    }
+    // Hosts managed by ACME get their certificates once issued
+    let acme = hosts
+        .values()
+        .flatten()
+        .any(|d| matches!(d, Directive::TlsAcme { .. }));
+    if certs.is_empty() && !acme {
+        return Ok(None);
+    }
+
+    // The listener takes the first tls_options found, in host order
+    let mut hostnames: Vec<&String> = hosts.keys().collect();
+    hostnames.sort();
+    let options = hostnames.iter().find_map(|host| {
+        hosts[*host].iter().find_map(|d| match d {
+            Directive::TlsOptions { options } => Some(options),
+            _ => None,
+        })
+    });
+
+    let mut server_config = server_config_builder(options)?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver { certs, default }));
+    // Clients without ALPN stay on HTTP/1.1, streams carry whatever protocol their clients speak
+    let layer4 = hosts
+        .values()
+        .flatten()
+        .any(|d| matches!(d, Directive::Layer4 { .. }));
+    if !layer4 {
+        server_config.alpn_protocols = vec![H2_ALPN.to_vec(), b"http/1.1".to_vec()];
+    }
+    if acme {
+        server_config.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+    }
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// TLS to `https` backends, HTTP/2 ones are offered `h2` through ALPN
+#[derive(Clone)]
+pub struct UpstreamTls {
+    http1: TlsConnector,
+    h2: TlsConnector,
+    server_name: Option<ServerName<'static>>, // instead of the backend host
+}
+
+impl UpstreamTls {
+    /// Trusts the system roots unless `tls_trusted_ca_certs` lists others
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn new(options: &ReverseProxyOptions) -> Result<Self, CbltError> {
+        let builder = ClientConfig::builder();
+        let config = if options.tls_insecure_skip_verify {
+            let provider = builder.crypto_provider().clone();
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+                .with_no_client_auth()
+        } else {
+            let mut roots = RootCertStore::empty();
+            if options.tls_trusted_ca_certs.is_empty() {
+                roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+            }
+            for path in &options.tls_trusted_ca_certs {
+                for cert in CertificateDer::pem_file_iter(path)? {
+                    roots.add(cert?)?;
+                }
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+        let server_name =
+            match &options.tls_server_name {
+                Some(name) => Some(ServerName::try_from(name.clone()).map_err(|_| {
+                    CbltError::KdlParseError {
+                        details: format!("Invalid tls_server_name '{}'", name),
+                    }
+                })?),
+                None => None,
+            };
+        let connector = |alpn: &[u8]| {
+            let mut config = config.clone();
+            config.alpn_protocols = vec![alpn.to_vec()];
+            TlsConnector::from(Arc::new(config))
+        };
+        Ok(UpstreamTls {
+            http1: connector(b"http/1.1"),
+            h2: connector(H2_ALPN),
+            server_name,
+        })
+    }
+
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub async fn connect(
+        &self,
+        stream: TcpStream,
+        host: &str,
+        h2: bool,
+    ) -> std::io::Result<TlsStream<TcpStream>> {
+        let server_name = match &self.server_name {
+            Some(server_name) => server_name.clone(),
+            None => ServerName::try_from(host.trim_matches(['[', ']']).to_string())
+                .map_err(std::io::Error::other)?,
+        };
+        let connector = if h2 { &self.h2 } else { &self.http1 };
+        connector.connect(server_name, stream).await
+    }
+}
+
+/// Accepts any backend certificate, for `tls_insecure_skip_verify`
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Directive, ReverseProxyOptions, TlsOptions, TlsVersion};
+    use crate::tls::{
+        select_cert, server_config_builder, tls_files, tls_files_changed, UpstreamTls,
+    };
+    use rcgen::{CertificateParams, KeyPair};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::ServerConfig;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::TlsAcceptor;
+
+    #[test]
+    fn test_select_cert() {
+        let certs = HashMap::from([
+            ("example.com".to_string(), "example"),
+            ("*.example.org".to_string(), "wildcard"),
+        ]);
+        let default = Some(&"default");
+        assert_eq!(
+            select_cert(&certs, default, Some("Example.COM")),
+            Some(&"example")
+        );
+        assert_eq!(
+            select_cert(&certs, default, Some("www.example.org")),
+            Some(&"wildcard")
+        );
+        assert_eq!(
+            select_cert(&certs, default, Some("a.b.example.org")),
+            Some(&"default")
+        );
+        assert_eq!(
+            select_cert(&certs, default, Some("other.com")),
+            Some(&"default")
+        );
+        assert_eq!(select_cert(&certs, default, None), Some(&"default"));
+        assert_eq!(select_cert(&certs, None, Some("other.com")), None);
+    }
+
+    #[test]
+    fn test_tls_files_changed() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-tls-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let cert = dir.join("cert.pem");
+        let key = dir.join("key.pem");
+        std::fs::write(&cert, "cert")?;
+        std::fs::write(&key, "key")?;
+        let hosts = HashMap::from([(
+            "example.com".to_string(),
+            vec![Directive::TlS {
+                cert: cert.display().to_string(),
+                key: key.display().to_string(),
+            }],
+        )]);
+
+        let files = tls_files(&hosts);
+        assert_eq!(files.len(), 2);
+        assert!(!tls_files_changed(&files));
+        std::fs::File::options()
+            .write(true)
+            .open(&key)?
+            .set_modified(SystemTime::now() + Duration::from_secs(60))?;
+        assert!(tls_files_changed(&files));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_server_config_builder() {
+        let options = TlsOptions {
+            min_version: Some(TlsVersion::Tls13),
+            ciphers: vec!["TLS13_AES_128_GCM_SHA256".to_string()],
+            ..Default::default()
+        };
+        let builder = server_config_builder(Some(&options)).unwrap();
+        assert_eq!(builder.crypto_provider().cipher_suites.len(), 1);
+
+        let unknown = TlsOptions {
+            ciphers: vec!["TLS_RSA_WITH_RC4_128_MD5".to_string()],
+            ..Default::default()
+        };
+        assert!(server_config_builder(Some(&unknown)).is_err());
+
+        // TLS 1.2 only with nothing but TLS 1.3 suites leaves no usable suite
+        let mismatch = TlsOptions {
+            max_version: Some(TlsVersion::Tls12),
+            ciphers: vec!["TLS13_AES_128_GCM_SHA256".to_string()],
+            ..Default::default()
+        };
+        assert!(server_config_builder(Some(&mismatch)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upstream_tls() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("cblt-upstream-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let key = KeyPair::generate()?;
+        let cert = CertificateParams::new(vec!["backend.test".to_string()])?.self_signed(&key)?;
+        let ca = dir.join("ca.pem");
+        std::fs::write(&ca, cert.pem())?;
+
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from_pem_file(&ca)?],
+                PrivateKeyDer::from_pem_slice(key.serialize_pem().as_bytes())?,
+            )?;
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                if let Ok(mut stream) = acceptor.accept(stream).await {
+                    let _ = stream.write_all(b"ok").await;
+                    let _ = stream.shutdown().await;
+                }
+            }
+        });
+        let exchange = |options: ReverseProxyOptions| async move {
+            let tls = UpstreamTls::new(&options)?;
+            let stream = TcpStream::connect(addr).await?;
+            let mut stream = tls.connect(stream, "127.0.0.1", false).await?;
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).await?;
+            Ok::<_, Box<dyn std::error::Error>>(reply)
+        };
+
+        // The certificate is neither from a known CA nor issued to the address
+        assert!(exchange(ReverseProxyOptions::default()).await.is_err());
+        let trusted = ReverseProxyOptions {
+            tls_trusted_ca_certs: vec![ca.clone()],
+            ..Default::default()
+        };
+        assert!(exchange(trusted.clone()).await.is_err());
+        let named = ReverseProxyOptions {
+            tls_server_name: Some("backend.test".to_string()),
+            ..trusted
+        };
+        assert_eq!(exchange(named).await?, "ok");
+        let insecure = ReverseProxyOptions {
+            tls_insecure_skip_verify: true,
+            ..Default::default()
+        };
+        assert_eq!(exchange(insecure).await?, "ok");
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,260 @@
+use bytes::Bytes;
+use io_uring::{opcode, types, IoUring};
+use log::info;
+use std::collections::HashMap;
+use std::fs::File;
+use std::future::Future;
+use std::io::{PipeReader, PipeWriter, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::oneshot;
+
+const ENTRIES: u32 = 256;
+/// Bytes asked for by one read
+const READ_SIZE: usize = 256 * 1024;
+/// User data of the read that wakes the ring thread up for new operations
+const WAKE: u64 = u64::MAX;
+
+/// Read of a file the ring thread carries out, the file and buffer are kept until it completes
+struct Op {
+    file: Arc<File>,
+    offset: u64,
+    buf: Vec<u8>,
+    reply: oneshot::Sender<std::io::Result<Vec<u8>>>,
+}
+
+/// Queue of the ring thread that reads the files of `file_server`
+pub struct Uring {
+    ops: Sender<Op>,
+    wake: PipeWriter,
+    woken: Arc<AtomicBool>, // a wake-up is on its way, no need for another
+}
+
+/// The ring shared by every listener, none when the kernel does not offer io_uring
+pub fn ring() -> Option<&'static Uring> {
+    static RING: OnceLock<Option<Uring>> = OnceLock::new();
+    RING.get_or_init(|| match Uring::start() {
+        Ok(ring) => Some(ring),
+        Err(err) => {
+            info!(
+                "io_uring unavailable, reading files with tokio::fs: {}",
+                err
+            );
+            None
+        }
+    })
+    .as_ref()
+}
+
+impl Uring {
+    fn start() -> std::io::Result<Self> {
+        let ring = IoUring::new(ENTRIES)?;
+        let (wake_reader, wake) = std::io::pipe()?;
+        let (ops, queued) = channel();
+        let woken = Arc::new(AtomicBool::new(false));
+        let thread_woken = woken.clone();
+        std::thread::Builder::new()
+            .name("cblt-uring".to_string())
+            .spawn(move || run(ring, queued, wake_reader, thread_woken))?;
+        Ok(Uring { ops, wake, woken })
+    }
+
+    fn read(&self, file: Arc<File>, offset: u64, len: usize) -> ReadFuture {
+        let (reply, receiver) = oneshot::channel();
+        let op = Op {
+            file,
+            offset,
+            buf: vec![0u8; len],
+            reply,
+        };
+        if self.ops.send(op).is_ok() && !self.woken.swap(true, Ordering::AcqRel) {
+            let _ = (&self.wake).write(&[1]);
+        }
+        receiver
+    }
+}
+
+type ReadFuture = oneshot::Receiver<std::io::Result<Vec<u8>>>;
+
+/// Submits queued reads and hands out their results until the process exits
+fn run(mut ring: IoUring, queued: Receiver<Op>, wake: PipeReader, woken: Arc<AtomicBool>) {
+    let mut in_flight: HashMap<u64, Op> = HashMap::new();
+    let mut next_id = 0u64;
+    let mut wake_buf = [0u8; 8];
+    let wake_read = opcode::Read::new(
+        types::Fd(wake.as_raw_fd()),
+        wake_buf.as_mut_ptr(),
+        wake_buf.len() as u32,
+    )
+    .build()
+    .user_data(WAKE);
+    // The buffer lives as long as this function, the loop never leaves with the read pending
+    if unsafe { ring.submission().push(&wake_read) }.is_err() {
+        return;
+    }
+    loop {
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        }
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|entry| (entry.user_data(), entry.result()))
+            .collect();
+        for (id, result) in completed {
+            if id != WAKE {
+                if let Some(mut op) = in_flight.remove(&id) {
+                    let read = if result < 0 {
+                        Err(std::io::Error::from_raw_os_error(-result))
+                    } else {
+                        op.buf.truncate(result as usize);
+                        Ok(op.buf)
+                    };
+                    let _ = op.reply.send(read);
+                }
+                continue;
+            }
+            if result <= 0 {
+                return; // the writing end is gone
+            }
+            woken.store(false, Ordering::Release);
+            while let Ok(mut op) = queued.try_recv() {
+                let read = opcode::Read::new(
+                    types::Fd(op.file.as_raw_fd()),
+                    op.buf.as_mut_ptr(),
+                    op.buf.len() as u32,
+                )
+                .offset(op.offset)
+                .build()
+                .user_data(next_id);
+                // The operation owns its file and buffer until the read completes
+                while unsafe { ring.submission().push(&read) }.is_err() {
+                    if ring.submit().is_err() {
+                        return;
+                    }
+                }
+                in_flight.insert(next_id, op);
+                next_id = (next_id + 1) % WAKE;
+            }
+            while unsafe { ring.submission().push(&wake_read) }.is_err() {
+                if ring.submit().is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// File read through the ring
+#[derive(Debug)]
+pub struct UringFile {
+    file: Arc<File>,
+    position: u64,
+    reading: Option<ReadFuture>,
+    unread: Bytes, // read ahead of what the caller asked for
+}
+
+impl UringFile {
+    pub fn new(file: File) -> Self {
+        UringFile {
+            file: Arc::new(file),
+            position: 0,
+            reading: None,
+            unread: Bytes::new(),
+        }
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Offset of the next byte to be read
+    pub fn position(&self) -> u64 {
+        self.position - self.unread.len() as u64
+    }
+}
+
+impl AsyncRead for UringFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if this.unread.is_empty() {
+            let Some(ring) = ring() else {
+                return Poll::Ready(Err(std::io::ErrorKind::Unsupported.into()));
+            };
+            let reading = this
+                .reading
+                .get_or_insert_with(|| ring.read(this.file.clone(), this.position, READ_SIZE));
+            let read = match Pin::new(reading).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(read) => read,
+            };
+            this.reading = None;
+            let read = read.map_err(|_| std::io::Error::other("io_uring thread exited"))??;
+            this.position += read.len() as u64;
+            this.unread = Bytes::from(read);
+        }
+        let len = this.unread.len().min(buf.remaining());
+        buf.put_slice(&this.unread.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for UringFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => this.position().checked_add_signed(offset),
+            SeekFrom::End(offset) => this.file.metadata()?.len().checked_add_signed(offset),
+        };
+        this.position = position.ok_or(std::io::ErrorKind::InvalidInput)?;
+        this.reading = None;
+        this.unread.clear();
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.position()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uring::{ring, UringFile};
+    use std::error::Error;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    #[tokio::test]
+    async fn test_uring_file() -> Result<(), Box<dyn Error>> {
+        if ring().is_none() {
+            return Ok(()); // not offered by this kernel
+        }
+        let path = std::env::temp_dir().join(format!("cblt-uring-{}", std::process::id()));
+        let contents: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &contents)?;
+
+        let mut file = UringFile::new(std::fs::File::open(&path)?);
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).await?;
+        assert!(read == contents);
+
+        file.seek(std::io::SeekFrom::Start(999_000)).await?;
+        let mut tail = [0u8; 10];
+        file.read_exact(&mut tail).await?;
+        assert_eq!(tail[..], contents[999_000..999_010]);
+        assert_eq!(file.position(), 999_010);
+        std::fs::remove_file(&path)?;
+
+        Ok(())
+    }
+}
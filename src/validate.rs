@@ -0,0 +1,598 @@
+use crate::config::{
+    is_json, parse_directive, parse_json_config, resolve_import, substitute_env, Directive,
+    MatchCondition,
+};
+use crate::error::CbltError;
+use crate::pattern::{is_regex, path_regex};
+use crate::reverse_proxy::{is_socket_address, is_valid_destination};
+use crate::tls::server_config_builder;
+use crate::{split_port, ParsedHost};
+use kdl::{KdlDocument, KdlNode};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Problem found in a Cbltfile, positioned at the offending node
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Checks a Cbltfile and the files it imports without binding any sockets
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn validate_config(path: &Path, source: &str) -> Vec<Diagnostic> {
+    if is_json(path) {
+        return validate_json(path, source);
+    }
+    let mut validator = Validator::default();
+    validator.source(path, source);
+    validator.check();
+    validator.diagnostics
+}
+
+/// JSON has no positions once parsed, so semantic problems are reported per host
+fn validate_json(path: &Path, source: &str) -> Vec<Diagnostic> {
+    let hosts = match parse_json_config(source) {
+        Ok(hosts) => hosts,
+        Err(CbltError::SerdeJsonError { source: err }) => {
+            return vec![Diagnostic {
+                file: path.to_path_buf(),
+                line: err.line(),
+                column: err.column(),
+                message: err.to_string(),
+            }];
+        }
+        Err(err) => return vec![diagnostic(path, source, 0, error_message(err))],
+    };
+    let mut hostnames: Vec<&String> = hosts.keys().collect();
+    hostnames.sort();
+    let mut diagnostics = Vec::new();
+    for hostname in hostnames {
+        for directive in &hosts[hostname] {
+            for message in check_directive(directive) {
+                let message = format!("{} (host {})", message, hostname);
+                diagnostics.push(diagnostic(path, source, 0, message));
+            }
+        }
+    }
+    diagnostics
+}
+
+#[derive(Default)]
+struct Validator {
+    diagnostics: Vec<Diagnostic>,
+    files: Vec<(PathBuf, String, KdlDocument)>, // parsed sources, imports included
+    snippets: HashMap<String, Vec<KdlNode>>,
+    hosts: Vec<String>,
+    port_tls: HashMap<u16, bool>, // port -> first host uses TLS
+    stack: Vec<PathBuf>,          // files being imported, for cycle detection
+}
+
+impl Validator {
+    fn report(&mut self, file: &Path, source: &str, offset: usize, message: String) {
+        self.diagnostics
+            .push(diagnostic(file, source, offset, message));
+    }
+
+    /// Parses a source and the files it imports
+    fn source(&mut self, file: &Path, source: &str) {
+        let source = match substitute_env(source) {
+            Ok(source) => source,
+            Err(CbltError::EnvVarNotSet { name, offset }) => {
+                let message = format!("Environment variable '{}' is not set", name);
+                return self.report(file, source, offset, message);
+            }
+            Err(err) => return self.report(file, source, 0, error_message(err)),
+        };
+        let doc: KdlDocument = match source.parse() {
+            Ok(doc) => doc,
+            Err(err) => {
+                let err: kdl::KdlError = err;
+                let mut message = err.to_string();
+                if let Some(help) = err.help {
+                    message.push_str(&format!(" ({})", help));
+                }
+                return self.report(file, &source, err.span.offset(), message);
+            }
+        };
+
+        let imports: Vec<KdlNode> = doc
+            .nodes()
+            .iter()
+            .filter(|node| node.name().value() == "import")
+            .cloned()
+            .collect();
+        self.files.push((file.to_path_buf(), source.clone(), doc));
+
+        self.stack
+            .push(file.canonicalize().unwrap_or(file.to_path_buf()));
+        for node in &imports {
+            self.import(file, &source, node);
+        }
+        self.stack.pop();
+    }
+
+    fn import(&mut self, file: &Path, source: &str, node: &KdlNode) {
+        let offset = node.name().span().offset();
+        for pattern in node.entries().iter().filter_map(|e| e.value().as_string()) {
+            let imports = match resolve_import(file, pattern) {
+                Ok(imports) => imports,
+                Err(err) => {
+                    let message = format!("Cannot import '{}': {}", pattern, err);
+                    self.report(file, source, offset, message);
+                    continue;
+                }
+            };
+            for import in imports {
+                let canonical = import.canonicalize().unwrap_or(import.clone());
+                if self.stack.contains(&canonical) {
+                    let message = format!("Import cycle through '{}'", import.display());
+                    self.report(file, source, offset, message);
+                    continue;
+                }
+                match std::fs::read_to_string(&import) {
+                    Ok(imported) => self.source(&import, &imported),
+                    Err(err) => {
+                        let message = format!("Cannot import '{}': {}", import.display(), err);
+                        self.report(file, source, offset, message);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks snippets first so hosts can use snippets declared anywhere
+    fn check(&mut self) {
+        let files = std::mem::take(&mut self.files);
+        for (file, source, doc) in &files {
+            for node in doc.nodes() {
+                if node.name().value() == "snippet" {
+                    self.snippet(file, source, node);
+                }
+            }
+        }
+        for (file, source, doc) in &files {
+            for node in doc.nodes() {
+                match node.name().value() {
+                    "import" | "snippet" => {}
+                    _ => self.host(file, source, node),
+                }
+            }
+        }
+        for (file, source, doc) in &files {
+            for node in doc.nodes() {
+                if node.name().value() == "snippet" {
+                    let name = match node.entries().first().and_then(|e| e.value().as_string()) {
+                        Some(name) => format!("snippet '{}'", name),
+                        None => "snippet".to_string(),
+                    };
+                    self.directives(file, source, node, &name);
+                }
+            }
+        }
+    }
+
+    fn snippet(&mut self, file: &Path, source: &str, node: &KdlNode) {
+        let offset = node.name().span().offset();
+        let Some(name) = node.entries().first().and_then(|e| e.value().as_string()) else {
+            return self.report(file, source, offset, "Snippet without a name".to_string());
+        };
+        let children = node
+            .children()
+            .map(|c| c.nodes().to_vec())
+            .unwrap_or_default();
+        if self.snippets.insert(name.to_string(), children).is_some() {
+            let message = format!("Snippet '{}' already exists", name);
+            self.report(file, source, offset, message);
+        }
+    }
+
+    /// Checks the directives of a host or snippet and tells whether TLS is enabled
+    fn directives(&mut self, file: &Path, source: &str, node: &KdlNode, hostname: &str) -> bool {
+        let mut tls = false;
+        let children = node.children().map(|c| c.nodes()).unwrap_or_default();
+        for child in children {
+            let offset = child.name().span().offset();
+            if child.name().value() == "import" {
+                for name in child.entries().iter().filter_map(|e| e.value().as_string()) {
+                    match self.snippet_tls(name, &mut Vec::new()) {
+                        Ok(snippet_tls) => tls |= snippet_tls,
+                        Err(message) => self.report(file, source, offset, message),
+                    }
+                }
+                continue;
+            }
+            match parse_directive(child, hostname) {
+                Ok(directive) => {
+                    tls |= matches!(directive, Directive::TlS { .. } | Directive::TlsAcme { .. });
+                    for message in check_directive(&directive) {
+                        self.report(file, source, offset, message);
+                    }
+                }
+                Err(err) => self.report(file, source, offset, error_message(err)),
+            }
+        }
+        tls
+    }
+
+    /// Whether an imported snippet enables TLS, or why it cannot be imported
+    fn snippet_tls(&self, name: &str, stack: &mut Vec<String>) -> Result<bool, String> {
+        let snippet = self
+            .snippets
+            .get(name)
+            .ok_or_else(|| format!("Unknown snippet '{}'", name))?;
+        if stack.iter().any(|imported| imported == name) {
+            return Err(format!("Snippet '{}' imports itself", name));
+        }
+        stack.push(name.to_string());
+        let mut tls = false;
+        for node in snippet {
+            match node.name().value() {
+                "tls" => tls = true,
+                "import" => {
+                    for name in node.entries().iter().filter_map(|e| e.value().as_string()) {
+                        tls |= self.snippet_tls(name, stack)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        stack.pop();
+        Ok(tls)
+    }
+
+    fn host(&mut self, file: &Path, source: &str, node: &KdlNode) {
+        let hostname = node.name().value();
+        let offset = node.name().span().offset();
+
+        if self.hosts.iter().any(|host| host == hostname) {
+            self.report(
+                file,
+                source,
+                offset,
+                format!("Host '{}' already exists", hostname),
+            );
+        }
+        self.hosts.push(hostname.to_string());
+
+        if let (_, Some(port)) = split_port(hostname) {
+            if port.parse::<u16>().is_err() {
+                let message = format!("Invalid port '{}' for host {}", port, hostname);
+                self.report(file, source, offset, message);
+            }
+        }
+
+        let children = node.children().map(|c| c.nodes()).unwrap_or_default();
+        if children.is_empty() {
+            let message = format!("No directives specified for host {}", hostname);
+            self.report(file, source, offset, message);
+        }
+
+        let tls = self.directives(file, source, node, hostname);
+
+        let port = ParsedHost::from_str(hostname)
+            .port
+            .unwrap_or(if tls { 443 } else { 80 });
+        match self.port_tls.get(&port) {
+            Some(&other) if other != tls => {
+                let message = format!(
+                    "Host {} conflicts with another host on port {}: TLS and plain HTTP cannot share a port",
+                    hostname, port
+                );
+                self.report(file, source, offset, message);
+            }
+            Some(_) => {}
+            None => {
+                self.port_tls.insert(port, tls);
+            }
+        }
+    }
+}
+
+fn check_directive(directive: &Directive) -> Vec<String> {
+    let mut messages = Vec::new();
+    match directive {
+        Directive::Root { pattern, .. }
+        | Directive::TryFiles { pattern, .. }
+        | Directive::Header { pattern, .. }
+        | Directive::Uri { pattern, .. }
+        | Directive::RemoteIp { pattern, .. }
+        | Directive::Country { pattern, .. }
+        | Directive::Redir {
+            pattern: Some(pattern),
+            ..
+        } => {
+            check_pattern(pattern, &mut messages);
+        }
+        Directive::ReverseProxy {
+            pattern,
+            destinations,
+            options,
+        } => {
+            check_pattern(pattern, &mut messages);
+            for destination in destinations {
+                if !is_valid_destination(destination) {
+                    messages.push(format!("Invalid upstream URL '{}'", destination));
+                }
+            }
+            if let Some(mirror) = options
+                .mirror
+                .as_ref()
+                .filter(|mirror| !is_valid_destination(mirror))
+            {
+                messages.push(format!("Invalid mirror URL '{}'", mirror));
+            }
+            if let Some(canary) = &options.canary {
+                if !is_valid_destination(&canary.upstream) {
+                    messages.push(format!("Invalid canary URL '{}'", canary.upstream));
+                }
+                if !(0.0..=100.0).contains(&canary.percent) {
+                    messages.push(format!("Invalid canary percentage {}", canary.percent));
+                }
+            }
+            for backup in options
+                .backups
+                .iter()
+                .filter(|url| !is_valid_destination(url))
+            {
+                messages.push(format!("Invalid backup URL '{}'", backup));
+            }
+            for group in &options.groups {
+                check_conditions(&group.conditions, &mut messages);
+                for upstream in group
+                    .upstreams
+                    .iter()
+                    .filter(|url| !is_valid_destination(url))
+                {
+                    messages.push(format!(
+                        "Invalid upstream URL '{}' in group {}",
+                        upstream, group.name
+                    ));
+                }
+            }
+        }
+        Directive::ForwardAuth {
+            pattern, upstream, ..
+        } => {
+            check_pattern(pattern, &mut messages);
+            let valid = upstream.parse::<http::Uri>().is_ok_and(|uri| {
+                matches!(uri.scheme_str(), Some("http" | "https")) && uri.authority().is_some()
+            });
+            if !valid {
+                messages.push(format!("Invalid auth service URL '{}'", upstream));
+            }
+        }
+        Directive::PhpFastcgi {
+            pattern, upstream, ..
+        } => {
+            check_pattern(pattern, &mut messages);
+            if !is_socket_address(upstream) {
+                messages.push(format!("Invalid FastCGI address '{}'", upstream));
+            }
+        }
+        Directive::Matcher { conditions, .. } => {
+            check_conditions(conditions, &mut messages);
+        }
+        Directive::GeoIp { database, .. } if !Path::new(database).is_file() => {
+            messages.push(format!("GeoIP database '{}' not found", database));
+        }
+        Directive::FileServer { options } => {
+            let layout = options
+                .markdown
+                .as_ref()
+                .and_then(|markdown| markdown.layout.as_ref());
+            if let Some(layout) = layout.filter(|layout| !Path::new(layout).is_file()) {
+                messages.push(format!("Markdown layout '{}' not found", layout));
+            }
+        }
+        Directive::TlS { cert, key } => {
+            for file in [cert, key] {
+                if !Path::new(file).is_file() {
+                    messages.push(format!("TLS file '{}' not found", file));
+                }
+            }
+        }
+        Directive::TlsOptions { options } => {
+            if let Err(err) = server_config_builder(Some(options)) {
+                messages.push(match err {
+                    CbltError::InvalidTlsOptions { details } => details,
+                    err => format!("Invalid tls_options: {}", err),
+                });
+            }
+        }
+        Directive::TlsAcme { options } => {
+            let valid = options
+                .ca
+                .parse::<http::Uri>()
+                .is_ok_and(|uri| uri.scheme_str() == Some("https") && uri.authority().is_some());
+            if !valid {
+                messages.push(format!("Invalid ACME directory URL '{}'", options.ca));
+            }
+            let ask = options
+                .on_demand
+                .as_ref()
+                .and_then(|on_demand| on_demand.ask.as_ref());
+            if let Some(ask) = ask {
+                let valid = ask.parse::<http::Uri>().is_ok_and(|uri| {
+                    matches!(uri.scheme_str(), Some("http" | "https")) && uri.authority().is_some()
+                });
+                if !valid {
+                    messages.push(format!("Invalid on_demand ask URL '{}'", ask));
+                }
+            }
+        }
+        _ => {}
+    }
+    messages
+}
+
+/// Patterns are "*", an exact path or a path prefix ending with "*"
+fn check_conditions(conditions: &[MatchCondition], messages: &mut Vec<String>) {
+    for condition in conditions {
+        match condition {
+            MatchCondition::Path(patterns) => {
+                for pattern in patterns {
+                    check_pattern(pattern, messages);
+                }
+            }
+            MatchCondition::Not(nested)
+            | MatchCondition::And(nested)
+            | MatchCondition::Or(nested) => check_conditions(nested, messages),
+            MatchCondition::Method(_)
+            | MatchCondition::Header { .. }
+            | MatchCondition::Query { .. }
+            | MatchCondition::Cookie { .. } => {}
+        }
+    }
+}
+
+fn check_pattern(pattern: &str, messages: &mut Vec<String>) {
+    // Named matchers are checked when the configuration is built
+    if pattern.starts_with('@') {
+        return;
+    }
+    if is_regex(pattern) {
+        if path_regex(pattern).is_none() {
+            messages.push(format!("Invalid regex pattern '{}'", pattern));
+        }
+        return;
+    }
+    let body = pattern.strip_suffix('*').unwrap_or(pattern);
+    if pattern != "*" && (!pattern.starts_with('/') || body.contains('*')) {
+        messages.push(format!(
+            "Invalid pattern '{}': expected \"*\", \"/path\", \"/prefix/*\", \"^regex\" or \"@matcher\"",
+            pattern
+        ));
+    }
+}
+
+fn error_message(err: CbltError) -> String {
+    match err {
+        CbltError::KdlParseError { details } => details,
+        err => err.to_string(),
+    }
+}
+
+fn diagnostic(file: &Path, source: &str, offset: usize, message: String) -> Diagnostic {
+    let before = source.get(..offset).unwrap_or(source);
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Diagnostic {
+        file: file.to_path_buf(),
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::validate::validate_config;
+    use std::path::Path;
+
+    #[test]
+    fn test_validate_valid() {
+        let cbltfile = r#"
+"example.com" {
+    root "*" "/path/to/folder"
+    reverse_proxy "/api/*" "http://localhost:8080"
+    file_server
+}
+        "#;
+        assert_eq!(validate_config(Path::new("Cbltfile"), cbltfile), vec![]);
+
+        let cbltfile = r#""example.com" {
+    redir "^/old/(.*)$" "/new/{re.1}"
+    reverse_proxy r"^/v[0-9]+/items/(\d+$" "http://localhost:8080"
+}
+"#;
+        let messages: Vec<String> = validate_config(Path::new("Cbltfile"), cbltfile)
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec!["3:5: Invalid regex pattern '^/v[0-9]+/items/(\\d+$'"]
+        );
+    }
+
+    #[test]
+    fn test_validate_diagnostics() {
+        let cbltfile = r#""example.com:80" {
+    root "*" "/path/to/folder"
+    gzip
+    reverse_proxy "api/*" "localhost:8080"
+    file_server { markdown "/missing/layout.html"; }
+}
+"secure.com:80" {
+    tls "/missing/cert.pem" "/missing/key.pem"
+}
+"empty.com" {
+}
+"#;
+        let messages: Vec<String> = validate_config(Path::new("Cbltfile"), cbltfile)
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "3:5: Unknown directive 'gzip' for host example.com:80",
+                "4:5: Invalid pattern 'api/*': expected \"*\", \"/path\", \"/prefix/*\", \"^regex\" or \"@matcher\"",
+                "4:5: Invalid upstream URL 'localhost:8080'",
+                "5:5: Markdown layout '/missing/layout.html' not found",
+                "8:5: TLS file '/missing/cert.pem' not found",
+                "8:5: TLS file '/missing/key.pem' not found",
+                "7:1: Host secure.com:80 conflicts with another host on port 80: TLS and plain HTTP cannot share a port",
+                "10:1: No directives specified for host empty.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_snippets() {
+        let cbltfile = r#"a.com:443 {
+    import "secure"
+}
+b.com:443 {
+    import "missing"
+}
+snippet "secure" {
+    tls "/missing/cert.pem" "/missing/key.pem"
+}
+"#;
+        let messages: Vec<String> = validate_config(Path::new("Cbltfile"), cbltfile)
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "5:5: Unknown snippet 'missing'",
+                "4:1: Host b.com:443 conflicts with another host on port 443: TLS and plain HTTP cannot share a port",
+                "8:5: TLS file '/missing/cert.pem' not found",
+                "8:5: TLS file '/missing/key.pem' not found",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_syntax_error() {
+        let diagnostics = validate_config(
+            Path::new("Cbltfile"),
+            "\"example.com\" {\n    root \"*\n}\n",
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].line >= 2);
+    }
+}
@@ -0,0 +1,1089 @@
+use crate::config::{Directive, WasmOptions};
+use crate::error::CbltError;
+use crate::handler::{Context, Handler, HandlerFuture, Next};
+use bytes::BytesMut;
+use http::header::{HeaderName, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
+use log::{error, Level, LevelFilter};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(feature = "trace")]
+use tracing::instrument;
+use wasmi::{
+    Caller, Config, Engine, Extern, ExternType, Instance, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder, Val,
+};
+
+// proxy-wasm ABI 0.2 values
+const STATUS_OK: i32 = 0;
+const STATUS_NOT_FOUND: i32 = 1;
+const STATUS_BAD_ARGUMENT: i32 = 2;
+const STATUS_UNIMPLEMENTED: i32 = 12;
+const ROOT_CONTEXT: i32 = 1;
+const REQUEST_BODY: i32 = 0;
+const VM_CONFIGURATION: i32 = 6;
+const PLUGIN_CONFIGURATION: i32 = 7;
+const REQUEST_HEADERS: i32 = 0;
+const RESPONSE_HEADERS: i32 = 2;
+
+const WASI: &str = "wasi_snapshot_preview1";
+const WASI_ENOSYS: i32 = 52;
+
+/// Header pairs as plugins see them, pseudo-headers like ":path" first
+type Pairs = Vec<(String, String)>;
+
+type HostCaller<'a> = Caller<'a, HostState>;
+
+/// What the host functions work on while cblt calls into the plugin
+#[derive(Default)]
+struct HostState {
+    path: String, // of the module, for its log lines
+    limits: StoreLimits,
+    config: Vec<u8>,
+    request_headers: Pairs,
+    request_body: Vec<u8>,
+    response_headers: Pairs,
+    trailers: Pairs,                      // never sent, kept empty
+    properties: HashMap<String, Vec<u8>>, // "source.address" -> value
+    local_response: Option<LocalResponse>,
+}
+
+/// Response a plugin answers the request with itself
+struct LocalResponse {
+    status: u32,
+    headers: Pairs,
+    body: Vec<u8>,
+}
+
+/// A plugin instance, the requests take turns calling into it
+struct Vm {
+    store: Store<HostState>,
+    instance: Instance,
+    fuel: u64,         // per call
+    next_context: i32, // id of the next request
+}
+
+/// `wasm` directive with its instantiated module
+pub struct WasmPlugin {
+    pattern: String,
+    vm: Arc<Mutex<Vm>>,
+}
+
+/// Loads the plugins of the host's `wasm` directives, keyed by the index of the directive
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub fn host_wasm_plugins(
+    directives: &[Directive],
+) -> Result<HashMap<usize, WasmPlugin>, CbltError> {
+    let mut plugins = HashMap::new();
+    for (index, directive) in directives.iter().enumerate() {
+        if let Directive::Wasm {
+            pattern,
+            path,
+            options,
+        } = directive
+        {
+            plugins.insert(index, WasmPlugin::load(pattern, path, options)?);
+        }
+    }
+    Ok(plugins)
+}
+
+impl WasmPlugin {
+    /// Instantiates the module and starts and configures its root context
+    #[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+    pub fn load(pattern: &str, path: &str, options: &WasmOptions) -> Result<Self, CbltError> {
+        let failed = |details: String| CbltError::WasmError {
+            details: format!("{}: {}", path, details),
+        };
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module =
+            Module::new(&engine, std::fs::read(path)?).map_err(|err| failed(err.to_string()))?;
+        let state = HostState {
+            path: path.to_string(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(options.memory as usize)
+                .build(),
+            config: options.config.as_bytes().to_vec(),
+            ..HostState::default()
+        };
+        let mut store = Store::new(&engine, state);
+        store.limiter(|state| &mut state.limits);
+
+        let mut linker = Linker::new(&engine);
+        define_host_functions(&mut linker).map_err(|err| failed(err.to_string()))?;
+        // Imports cblt does not provide answer that they are not implemented
+        for import in module.imports() {
+            if let ExternType::Func(ty) = import.ty() {
+                let status = match import.module() {
+                    WASI => WASI_ENOSYS,
+                    _ => STATUS_UNIMPLEMENTED,
+                };
+                let results = ty.results().to_vec();
+                let _ = linker.func_new(import.module(), import.name(), ty.clone(), {
+                    move |_, _, outputs| {
+                        for (output, ty) in outputs.iter_mut().zip(&results) {
+                            *output = match Val::default_for_ty(*ty) {
+                                Val::I32(_) => Val::I32(status),
+                                other => other,
+                            };
+                        }
+                        Ok(())
+                    }
+                });
+            }
+        }
+        let instance = linker
+            .instantiate_and_start(&mut store, &module)
+            .map_err(|err| failed(err.to_string()))?;
+        if instance.get_memory(&store, "memory").is_none() {
+            return Err(failed("the module exports no memory".to_string()));
+        }
+
+        let mut vm = Vm {
+            store,
+            instance,
+            fuel: options.fuel,
+            next_context: ROOT_CONTEXT + 1,
+        };
+        // WASI reactors set up their runtime first
+        vm.call("_initialize", &[])?;
+        vm.call("proxy_on_context_create", &[ROOT_CONTEXT, 0])?;
+        if vm.call("proxy_on_vm_start", &[ROOT_CONTEXT, 0])? == Some(0) {
+            return Err(failed("the plugin failed to start".to_string()));
+        }
+        let config_size = options.config.len() as i32;
+        if vm.call("proxy_on_configure", &[ROOT_CONTEXT, config_size])? == Some(0) {
+            return Err(failed("the plugin rejected its configuration".to_string()));
+        }
+        Ok(WasmPlugin {
+            pattern: pattern.to_string(),
+            vm: Arc::new(Mutex::new(vm)),
+        })
+    }
+
+    /// Creates the request's context and runs the request hooks, applying what they changed to
+    /// the request. The response is there when the plugin answered the request itself
+    fn on_request(
+        &self,
+        ctx: &mut Context<'_>,
+    ) -> Result<(i32, Option<Response<BytesMut>>), CbltError> {
+        let mut vm = lock(&self.vm)?;
+        let id = vm.new_context();
+        vm.call("proxy_on_context_create", &[id, ROOT_CONTEXT])?;
+
+        let request = ctx.request();
+        let headers = request_pairs(request, ctx.scheme());
+        let body_size = request.body().len() as i32;
+        let state = vm.store.data_mut();
+        state.request_headers = headers.clone();
+        state.request_body = request.body().to_vec();
+        state.properties = properties(request, ctx.client_addr(), ctx.scheme());
+        state.local_response = None;
+
+        let end_of_stream = (body_size == 0) as i32;
+        vm.call(
+            "proxy_on_request_headers",
+            &[id, headers.len() as i32, end_of_stream],
+        )?;
+        if body_size > 0 && vm.store.data().local_response.is_none() {
+            vm.call("proxy_on_request_body", &[id, body_size, 1])?;
+        }
+
+        let state = vm.store.data_mut();
+        if let Some(local) = state.local_response.take() {
+            return Ok((id, Some(local_response(local)?)));
+        }
+        if state.request_headers != headers {
+            apply_request_pairs(ctx.request_mut(), &state.request_headers)?;
+        }
+        if state.request_body[..] != ctx.request().body()[..] {
+            let request = ctx.request_mut();
+            *request.body_mut() = BytesMut::from(&state.request_body[..]);
+            request.headers_mut().remove(TRANSFER_ENCODING);
+            request
+                .headers_mut()
+                .insert(CONTENT_LENGTH, HeaderValue::from(state.request_body.len()));
+        }
+        Ok((id, None))
+    }
+
+    /// Ends the request's context
+    fn finish(&self, id: i32) -> Result<(), CbltError> {
+        let mut vm = lock(&self.vm)?;
+        vm.call("proxy_on_done", &[id])?;
+        vm.call("proxy_on_log", &[id])?;
+        vm.call("proxy_on_delete", &[id])?;
+        let state = vm.store.data_mut();
+        state.request_headers.clear();
+        state.request_body.clear();
+        state.response_headers.clear();
+        state.properties.clear();
+        Ok(())
+    }
+}
+
+impl Handler for WasmPlugin {
+    fn handle<'a>(&'a self, ctx: &'a mut Context<'_>, next: Next) -> HandlerFuture<'a> {
+        Box::pin(async move {
+            if !ctx.matches(&self.pattern) {
+                return next.run(ctx).await;
+            }
+            // A failing filter does not let the request through
+            let (id, local_response) = match self.on_request(ctx) {
+                Ok(hooked) => hooked,
+                Err(err) => {
+                    error!("Error: {}", err);
+                    return ctx.respond_error(StatusCode::INTERNAL_SERVER_ERROR).await;
+                }
+            };
+            let ret = match local_response {
+                Some(response) => ctx.respond(response).await,
+                None => {
+                    let vm = self.vm.clone();
+                    ctx.on_response(move |status, headers| {
+                        if let Err(err) = on_response_headers(&vm, id, status, headers) {
+                            error!("Error: {}", err);
+                        }
+                    });
+                    next.run(ctx).await
+                }
+            };
+            if let Err(err) = self.finish(id) {
+                error!("Error: {}", err);
+            }
+            ret
+        })
+    }
+}
+
+impl Vm {
+    /// Calls an export of the plugin with a fresh fuel allowance, None when it is not exported
+    fn call(&mut self, name: &str, args: &[i32]) -> Result<Option<i32>, CbltError> {
+        let Some(func) = self.instance.get_func(&self.store, name) else {
+            return Ok(None);
+        };
+        let params: Vec<Val> = args.iter().map(|arg| Val::I32(*arg)).collect();
+        let mut results: Vec<Val> = func
+            .ty(&self.store)
+            .results()
+            .iter()
+            .map(|ty| Val::default_for_ty(*ty))
+            .collect();
+        let ret = self
+            .store
+            .set_fuel(self.fuel)
+            .and_then(|_| func.call(&mut self.store, &params, &mut results));
+        ret.map_err(|err| CbltError::WasmError {
+            details: format!("{}: {}: {}", self.store.data().path, name, err),
+        })?;
+        Ok(Some(results.first().and_then(Val::i32).unwrap_or(0)))
+    }
+
+    fn new_context(&mut self) -> i32 {
+        let id = self.next_context;
+        self.next_context = id.checked_add(1).unwrap_or(ROOT_CONTEXT + 1);
+        id
+    }
+}
+
+fn lock(vm: &Mutex<Vm>) -> Result<MutexGuard<'_, Vm>, CbltError> {
+    vm.lock().map_err(|_| CbltError::WasmError {
+        details: "plugin lock poisoned".to_string(),
+    })
+}
+
+/// Runs the response hook on the headers of the response going out
+fn on_response_headers(
+    vm: &Mutex<Vm>,
+    id: i32,
+    status: StatusCode,
+    headers: &mut HeaderMap,
+) -> Result<(), CbltError> {
+    let mut vm = lock(vm)?;
+    let mut pairs = vec![(":status".to_string(), status.as_str().to_string())];
+    pairs.extend(header_pairs(headers));
+    vm.store.data_mut().response_headers = pairs.clone();
+    vm.call("proxy_on_response_headers", &[id, pairs.len() as i32, 0])?;
+    let state = vm.store.data_mut();
+    // The request is being answered already
+    state.local_response = None;
+    if state.response_headers != pairs {
+        let mut changed = HeaderMap::new();
+        for (name, value) in &state.response_headers {
+            if !name.starts_with(':') {
+                changed.append(header_name(name)?, header_value(value)?);
+            }
+        }
+        *headers = changed;
+    }
+    Ok(())
+}
+
+/// Request headers with the pseudo-headers of HTTP/2, the Host header is ":authority"
+fn request_pairs(request: &Request<BytesMut>, scheme: &str) -> Pairs {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let authority = request
+        .headers()
+        .get(HOST)
+        .map(|host| String::from_utf8_lossy(host.as_bytes()).into_owned())
+        .unwrap_or_default();
+    let mut pairs = vec![
+        (":method".to_string(), request.method().to_string()),
+        (":path".to_string(), path.to_string()),
+        (":authority".to_string(), authority),
+        (":scheme".to_string(), scheme.to_string()),
+    ];
+    pairs.extend(header_pairs(request.headers()).filter(|(name, _)| name != HOST.as_str()));
+    pairs
+}
+
+fn header_pairs(headers: &HeaderMap) -> impl Iterator<Item = (String, String)> + '_ {
+    headers.iter().map(|(name, value)| {
+        (
+            name.as_str().to_string(),
+            String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        )
+    })
+}
+
+/// Rebuilds the request from the headers a plugin left, pseudo-headers included
+fn apply_request_pairs(request: &mut Request<BytesMut>, pairs: &Pairs) -> Result<(), CbltError> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        match name.as_str() {
+            ":method" => {
+                *request.method_mut() =
+                    Method::from_bytes(value.as_bytes()).map_err(|_| invalid(name, value))?;
+            }
+            ":path" => *request.uri_mut() = value.parse().map_err(|_| invalid(name, value))?,
+            ":authority" => {
+                headers.insert(HOST, header_value(value)?);
+            }
+            ":scheme" => {}
+            name => {
+                headers.append(header_name(name)?, header_value(value)?);
+            }
+        }
+    }
+    *request.headers_mut() = headers;
+    Ok(())
+}
+
+fn header_name(name: &str) -> Result<HeaderName, CbltError> {
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid(name, ""))
+}
+
+fn header_value(value: &str) -> Result<HeaderValue, CbltError> {
+    HeaderValue::from_bytes(value.as_bytes()).map_err(|_| invalid("header value", value))
+}
+
+fn invalid(name: &str, value: &str) -> CbltError {
+    CbltError::WasmError {
+        details: format!("Invalid {} '{}' from the plugin", name, value),
+    }
+}
+
+fn local_response(local: LocalResponse) -> Result<Response<BytesMut>, CbltError> {
+    let status = u16::try_from(local.status)
+        .ok()
+        .and_then(|status| StatusCode::from_u16(status).ok())
+        .ok_or_else(|| invalid("status", &local.status.to_string()))?;
+    let mut response = Response::builder().status(status);
+    for (name, value) in &local.headers {
+        response = response.header(header_name(name)?, header_value(value)?);
+    }
+    Ok(response.body(BytesMut::from(&local.body[..]))?)
+}
+
+/// Properties of the request plugins read with `proxy_get_property`
+fn properties(
+    request: &Request<BytesMut>,
+    addr: SocketAddr,
+    scheme: &str,
+) -> HashMap<String, Vec<u8>> {
+    let mut properties = HashMap::new();
+    let mut set = |key: &str, value: &str| {
+        properties.insert(key.to_string(), value.as_bytes().to_vec());
+    };
+    set("source.address", &addr.to_string());
+    set("source.port", &addr.port().to_string());
+    set("request.method", request.method().as_str());
+    set("request.url_path", request.uri().path());
+    set("request.scheme", scheme);
+    set(
+        "request.path",
+        request
+            .uri()
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or("/"),
+    );
+    set(
+        "request.protocol",
+        match request.version() {
+            http::Version::HTTP_10 => "HTTP/1.0",
+            http::Version::HTTP_2 => "HTTP/2",
+            _ => "HTTP/1.1",
+        },
+    );
+    if let Some(host) = request
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+    {
+        set("request.host", host);
+    }
+    properties
+}
+
+/// Header map of the proxy-wasm ABI: the pair count, the key and value sizes of each pair, then
+/// the keys and values, each followed by a NUL
+fn serialize_pairs(pairs: &Pairs) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend((pairs.len() as u32).to_le_bytes());
+    for (name, value) in pairs {
+        bytes.extend((name.len() as u32).to_le_bytes());
+        bytes.extend((value.len() as u32).to_le_bytes());
+    }
+    for (name, value) in pairs {
+        bytes.extend(name.as_bytes());
+        bytes.push(0);
+        bytes.extend(value.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
+fn deserialize_pairs(bytes: &[u8]) -> Option<Pairs> {
+    let u32_at = |offset: usize| -> Option<usize> {
+        let word = bytes.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_le_bytes(word.try_into().ok()?) as usize)
+    };
+    let count = u32_at(0)?;
+    let mut sizes = Vec::new();
+    for pair in 0..count {
+        sizes.push((u32_at(4 + pair * 8)?, u32_at(8 + pair * 8)?));
+    }
+    let mut offset = 4 + count * 8;
+    let mut pairs = Vec::new();
+    for (name_size, value_size) in sizes {
+        let name = bytes.get(offset..offset + name_size)?;
+        offset += name_size + 1;
+        let value = bytes.get(offset..offset + value_size)?;
+        offset += value_size + 1;
+        pairs.push((
+            String::from_utf8_lossy(name).into_owned(),
+            String::from_utf8_lossy(value).into_owned(),
+        ));
+    }
+    Some(pairs)
+}
+
+fn memory(caller: &HostCaller) -> Result<Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("the module exports no memory"))
+}
+
+fn read(caller: &HostCaller, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::Error> {
+    let memory = memory(caller)?;
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    if ptr.saturating_add(len) > memory.data(caller).len() {
+        return Err(wasmi::Error::new("out of bounds memory access"));
+    }
+    let mut bytes = vec![0; len];
+    memory
+        .read(caller, ptr, &mut bytes)
+        .map_err(|err| wasmi::Error::new(err.to_string()))?;
+    Ok(bytes)
+}
+
+fn write(caller: &mut HostCaller, ptr: i32, bytes: &[u8]) -> Result<(), wasmi::Error> {
+    memory(caller)?
+        .write(caller, ptr as u32 as usize, bytes)
+        .map_err(|err| wasmi::Error::new(err.to_string()))
+}
+
+fn read_string(caller: &HostCaller, ptr: i32, len: i32) -> Result<String, wasmi::Error> {
+    Ok(String::from_utf8_lossy(&read(caller, ptr, len)?).into_owned())
+}
+
+/// Copies bytes into memory the plugin allocates, storing their address and size at the pointers
+fn return_bytes(
+    caller: &mut HostCaller,
+    bytes: &[u8],
+    data_ptr: i32,
+    size_ptr: i32,
+) -> Result<(), wasmi::Error> {
+    let allocate = caller
+        .get_export("proxy_on_memory_allocate")
+        .or_else(|| caller.get_export("malloc"))
+        .and_then(Extern::into_func)
+        .ok_or_else(|| wasmi::Error::new("the module exports no proxy_on_memory_allocate"))?
+        .typed::<i32, i32>(&*caller)?;
+    let addr = allocate.call(&mut *caller, bytes.len() as i32)?;
+    write(caller, addr, bytes)?;
+    write(caller, data_ptr, &addr.to_le_bytes())?;
+    write(caller, size_ptr, &(bytes.len() as u32).to_le_bytes())
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Header map a proxy-wasm call refers to
+fn header_map(state: &mut HostState, map: i32) -> Option<&mut Pairs> {
+    match map {
+        REQUEST_HEADERS => Some(&mut state.request_headers),
+        RESPONSE_HEADERS => Some(&mut state.response_headers),
+        1 | 3 => Some(&mut state.trailers),
+        _ => None,
+    }
+}
+
+fn remove_pairs(pairs: &mut Pairs, name: &str) {
+    pairs.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+}
+
+/// The proxy-wasm host functions cblt implements, and enough of WASI for plugins built for it
+fn define_host_functions(linker: &mut Linker<HostState>) -> Result<(), wasmi::Error> {
+    linker.func_wrap(
+        "env",
+        "proxy_log",
+        |caller: HostCaller, level: i32, ptr: i32, len: i32| -> Result<i32, wasmi::Error> {
+            let message = read_string(&caller, ptr, len)?;
+            let level = match level {
+                0 => Level::Trace,
+                1 => Level::Debug,
+                2 => Level::Info,
+                3 => Level::Warn,
+                _ => Level::Error,
+            };
+            log::log!(level, "{}: {}", caller.data().path, message);
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_log_level",
+        |mut caller: HostCaller, level_ptr: i32| -> Result<i32, wasmi::Error> {
+            let level: u32 = match log::max_level() {
+                LevelFilter::Trace => 0,
+                LevelFilter::Debug => 1,
+                LevelFilter::Info => 2,
+                LevelFilter::Warn => 3,
+                LevelFilter::Error => 4,
+                LevelFilter::Off => 5,
+            };
+            write(&mut caller, level_ptr, &level.to_le_bytes())?;
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_current_time_nanoseconds",
+        |mut caller: HostCaller, time_ptr: i32| -> Result<i32, wasmi::Error> {
+            write(&mut caller, time_ptr, &now_nanos().to_le_bytes())?;
+            Ok(STATUS_OK)
+        },
+    )?;
+    // Timers, streams resumed later and effective contexts do not apply: every hook runs to the
+    // end before the request moves on
+    for name in [
+        "proxy_set_tick_period_milliseconds",
+        "proxy_set_effective_context",
+        "proxy_continue_stream",
+        "proxy_close_stream",
+    ] {
+        linker.func_wrap("env", name, |_: HostCaller, _: i32| STATUS_OK)?;
+    }
+    for name in [
+        "proxy_done",
+        "proxy_continue_request",
+        "proxy_continue_response",
+    ] {
+        linker.func_wrap("env", name, |_: HostCaller| STATUS_OK)?;
+    }
+    linker.func_wrap(
+        "env",
+        "proxy_get_buffer_bytes",
+        |mut caller: HostCaller,
+         buffer: i32,
+         start: i32,
+         max_size: i32,
+         data_ptr: i32,
+         size_ptr: i32|
+         -> Result<i32, wasmi::Error> {
+            let state = caller.data();
+            let bytes = match buffer {
+                REQUEST_BODY => state.request_body.clone(),
+                PLUGIN_CONFIGURATION => state.config.clone(),
+                VM_CONFIGURATION => Vec::new(),
+                _ => return Ok(STATUS_NOT_FOUND),
+            };
+            let start = (start as u32 as usize).min(bytes.len());
+            let end = start
+                .saturating_add(max_size as u32 as usize)
+                .min(bytes.len());
+            return_bytes(&mut caller, &bytes[start..end], data_ptr, size_ptr)?;
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_buffer_status",
+        |mut caller: HostCaller,
+         buffer: i32,
+         size_ptr: i32,
+         flags_ptr: i32|
+         -> Result<i32, wasmi::Error> {
+            let state = caller.data();
+            let size = match buffer {
+                REQUEST_BODY => state.request_body.len(),
+                PLUGIN_CONFIGURATION => state.config.len(),
+                VM_CONFIGURATION => 0,
+                _ => return Ok(STATUS_NOT_FOUND),
+            };
+            write(&mut caller, size_ptr, &(size as u32).to_le_bytes())?;
+            write(&mut caller, flags_ptr, &0u32.to_le_bytes())?;
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_set_buffer_bytes",
+        |mut caller: HostCaller,
+         buffer: i32,
+         start: i32,
+         size: i32,
+         data_ptr: i32,
+         data_size: i32|
+         -> Result<i32, wasmi::Error> {
+            if buffer != REQUEST_BODY {
+                return Ok(STATUS_BAD_ARGUMENT);
+            }
+            let data = read(&caller, data_ptr, data_size)?;
+            let body = &mut caller.data_mut().request_body;
+            let start = (start as u32 as usize).min(body.len());
+            let end = start.saturating_add(size as u32 as usize).min(body.len());
+            body.splice(start..end, data);
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_header_map_pairs",
+        |mut caller: HostCaller,
+         map: i32,
+         data_ptr: i32,
+         size_ptr: i32|
+         -> Result<i32, wasmi::Error> {
+            let Some(pairs) = header_map(caller.data_mut(), map) else {
+                return Ok(STATUS_BAD_ARGUMENT);
+            };
+            let bytes = serialize_pairs(pairs);
+            return_bytes(&mut caller, &bytes, data_ptr, size_ptr)?;
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_set_header_map_pairs",
+        |mut caller: HostCaller, map: i32, ptr: i32, size: i32| -> Result<i32, wasmi::Error> {
+            let Some(changed) = deserialize_pairs(&read(&caller, ptr, size)?) else {
+                return Ok(STATUS_BAD_ARGUMENT);
+            };
+            let Some(pairs) = header_map(caller.data_mut(), map) else {
+                return Ok(STATUS_BAD_ARGUMENT);
+            };
+            *pairs = changed;
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_header_map_value",
+        |mut caller: HostCaller,
+         map: i32,
+         name_ptr: i32,
+         name_size: i32,
+         data_ptr: i32,
+         size_ptr: i32|
+         -> Result<i32, wasmi::Error> {
+            let name = read_string(&caller, name_ptr, name_size)?;
+            let Some(pairs) = header_map(caller.data_mut(), map) else {
+                return Ok(STATUS_BAD_ARGUMENT);
+            };
+            let value = pairs
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(&name))
+                .map(|(_, value)| value.clone());
+            match value {
+                Some(value) => {
+                    return_bytes(&mut caller, value.as_bytes(), data_ptr, size_ptr)?;
+                    Ok(STATUS_OK)
+                }
+                None => Ok(STATUS_NOT_FOUND),
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_replace_header_map_value",
+        |mut caller: HostCaller,
+         map: i32,
+         name_ptr: i32,
+         name_size: i32,
+         value_ptr: i32,
+         value_size: i32|
+         -> Result<i32, wasmi::Error> {
+            let name = read_string(&caller, name_ptr, name_size)?.to_ascii_lowercase();
+            let value = read_string(&caller, value_ptr, value_size)?;
+            let Some(pairs) = header_map(caller.data_mut(), map) else {
+                return Ok(STATUS_BAD_ARGUMENT);
+            };
+            match pairs.iter().position(|(key, _)| *key == name) {
+                Some(position) => {
+                    pairs[position].1 = value;
+                    let mut index = 0;
+                    pairs.retain(|(key, _)| {
+                        index += 1;
+                        index - 1 <= position || *key != name
+                    });
+                }
+                None => pairs.push((name, value)),
+            }
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_remove_header_map_value",
+        |mut caller: HostCaller,
+         map: i32,
+         name_ptr: i32,
+         name_size: i32|
+         -> Result<i32, wasmi::Error> {
+            let name = read_string(&caller, name_ptr, name_size)?;
+            let Some(pairs) = header_map(caller.data_mut(), map) else {
+                return Ok(STATUS_BAD_ARGUMENT);
+            };
+            remove_pairs(pairs, &name);
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_add_header_map_value",
+        |mut caller: HostCaller,
+         map: i32,
+         name_ptr: i32,
+         name_size: i32,
+         value_ptr: i32,
+         value_size: i32|
+         -> Result<i32, wasmi::Error> {
+            let name = read_string(&caller, name_ptr, name_size)?.to_ascii_lowercase();
+            let value = read_string(&caller, value_ptr, value_size)?;
+            let Some(pairs) = header_map(caller.data_mut(), map) else {
+                return Ok(STATUS_BAD_ARGUMENT);
+            };
+            pairs.push((name, value));
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_send_local_response",
+        |mut caller: HostCaller,
+         status: i32,
+         _details_ptr: i32,
+         _details_size: i32,
+         body_ptr: i32,
+         body_size: i32,
+         headers_ptr: i32,
+         headers_size: i32,
+         _grpc_status: i32|
+         -> Result<i32, wasmi::Error> {
+            let body = read(&caller, body_ptr, body_size)?;
+            let headers = match headers_size {
+                0 => Vec::new(),
+                _ => match deserialize_pairs(&read(&caller, headers_ptr, headers_size)?) {
+                    Some(headers) => headers,
+                    None => return Ok(STATUS_BAD_ARGUMENT),
+                },
+            };
+            caller.data_mut().local_response = Some(LocalResponse {
+                status: status as u32,
+                headers,
+                body,
+            });
+            Ok(STATUS_OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_property",
+        |mut caller: HostCaller,
+         path_ptr: i32,
+         path_size: i32,
+         data_ptr: i32,
+         size_ptr: i32|
+         -> Result<i32, wasmi::Error> {
+            // Path segments are separated by NULs
+            let path = read(&caller, path_ptr, path_size)?;
+            let key = path
+                .split(|byte| *byte == 0)
+                .filter(|segment| !segment.is_empty())
+                .map(String::from_utf8_lossy)
+                .collect::<Vec<_>>()
+                .join(".");
+            match caller.data().properties.get(&key).cloned() {
+                Some(value) => {
+                    return_bytes(&mut caller, &value, data_ptr, size_ptr)?;
+                    Ok(STATUS_OK)
+                }
+                None => Ok(STATUS_NOT_FOUND),
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        WASI,
+        "fd_write",
+        |mut caller: HostCaller,
+         fd: i32,
+         iovs_ptr: i32,
+         iovs_len: i32,
+         written_ptr: i32|
+         -> Result<i32, wasmi::Error> {
+            let iovs = read(&caller, iovs_ptr, iovs_len.saturating_mul(8))?;
+            let mut output = Vec::new();
+            for iov in iovs.chunks_exact(8) {
+                let ptr = i32::from_le_bytes([iov[0], iov[1], iov[2], iov[3]]);
+                let len = i32::from_le_bytes([iov[4], iov[5], iov[6], iov[7]]);
+                output.extend(read(&caller, ptr, len)?);
+            }
+            let level = if fd == 2 { Level::Warn } else { Level::Info };
+            let text = String::from_utf8_lossy(&output);
+            log::log!(level, "{}: {}", caller.data().path, text.trim_end());
+            write(
+                &mut caller,
+                written_ptr,
+                &(output.len() as u32).to_le_bytes(),
+            )?;
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        WASI,
+        "random_get",
+        |mut caller: HostCaller, ptr: i32, len: i32| -> Result<i32, wasmi::Error> {
+            let mut bytes = read(&caller, ptr, len)?;
+            aws_lc_rs::rand::fill(&mut bytes)
+                .map_err(|_| wasmi::Error::new("no random bytes available"))?;
+            write(&mut caller, ptr, &bytes)?;
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        WASI,
+        "clock_time_get",
+        |mut caller: HostCaller, _clock: i32, _precision: i64, time_ptr: i32| {
+            write(&mut caller, time_ptr, &now_nanos().to_le_bytes())?;
+            Ok(0)
+        },
+    )?;
+    // No arguments and no environment
+    for name in ["environ_sizes_get", "args_sizes_get"] {
+        linker.func_wrap(
+            WASI,
+            name,
+            |mut caller: HostCaller, count_ptr: i32, size_ptr: i32| {
+                write(&mut caller, count_ptr, &0u32.to_le_bytes())?;
+                write(&mut caller, size_ptr, &0u32.to_le_bytes())?;
+                Ok(0)
+            },
+        )?;
+    }
+    for name in ["environ_get", "args_get"] {
+        linker.func_wrap(WASI, name, |_: HostCaller, _: i32, _: i32| 0)?;
+    }
+    linker.func_wrap(WASI, "proc_exit", |_: HostCaller, code: i32| {
+        Err::<(), _>(wasmi::Error::i32_exit(code))
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::embed::testing::{exchange, serve};
+    use std::error::Error;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Blocks requests carrying "x-block", tags the others and their responses
+    const FILTER: &str = r#"(module
+  (import "env" "proxy_get_header_map_value"
+    (func $get_header (param i32 i32 i32 i32 i32) (result i32)))
+  (import "env" "proxy_add_header_map_value"
+    (func $add_header (param i32 i32 i32 i32 i32) (result i32)))
+  (import "env" "proxy_send_local_response"
+    (func $send_local_response (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+  (import "env" "proxy_something_new" (func $unknown (result i32)))
+  (memory (export "memory") 2)
+  (global $heap (mut i32) (i32.const 4096))
+  (data (i32.const 0) "x-block")
+  (data (i32.const 16) "x-wasm-seen")
+  (data (i32.const 32) "1")
+  (data (i32.const 48) "blocked by wasm")
+  (data (i32.const 80) "x-wasm")
+  (data (i32.const 96) "on")
+  (func (export "proxy_on_memory_allocate") (param $size i32) (result i32)
+    (local $addr i32)
+    (local.set $addr (global.get $heap))
+    (global.set $heap (i32.add (global.get $heap) (local.get $size)))
+    (local.get $addr))
+  (func (export "proxy_on_context_create") (param i32 i32))
+  (func (export "proxy_on_vm_start") (param i32 i32) (result i32) (i32.const 1))
+  (func (export "proxy_on_configure") (param $root i32) (param $size i32) (result i32)
+    (i32.ne (local.get $size) (i32.const 0)))
+  (func (export "proxy_on_request_headers") (param i32 i32 i32) (result i32)
+    (if (i32.eq (call $unknown) (i32.const 12))
+      (then
+        (if (i32.eqz (call $get_header (i32.const 0) (i32.const 0) (i32.const 7)
+                                       (i32.const 200) (i32.const 204)))
+          (then
+            (drop (call $send_local_response (i32.const 403) (i32.const 0) (i32.const 0)
+                                             (i32.const 48) (i32.const 15)
+                                             (i32.const 0) (i32.const 0) (i32.const -1)))
+            (return (i32.const 1))))))
+    (drop (call $add_header (i32.const 0) (i32.const 16) (i32.const 11)
+                            (i32.const 32) (i32.const 1)))
+    (i32.const 0))
+  (func (export "proxy_on_response_headers") (param i32 i32 i32) (result i32)
+    (drop (call $add_header (i32.const 2) (i32.const 80) (i32.const 6)
+                            (i32.const 96) (i32.const 2)))
+    (i32.const 0)))"#;
+
+    const SPIN: &str = r#"(module
+  (memory (export "memory") 1)
+  (func (export "proxy_on_vm_start") (param i32 i32) (result i32)
+    (loop $forever (br $forever))
+    (i32.const 1)))"#;
+
+    fn module_file(name: &str, source: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let path =
+            std::env::temp_dir().join(format!("cblt-wasm-{}-{}.wat", std::process::id(), name));
+        std::fs::write(&path, source)?;
+        Ok(path)
+    }
+
+    async fn get(port: u16, extra: &str) -> Result<String, Box<dyn Error>> {
+        let request = format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+            extra
+        );
+        exchange(port, &request).await
+    }
+
+    /// Upstream answering with the head of the request it got
+    async fn echo_upstream() -> Result<u16, Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => head.extend_from_slice(&buf[..read]),
+                        }
+                    }
+                    let response =
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", head.len());
+                    let _ = stream.write_all(response.as_bytes()).await;
+                    let _ = stream.write_all(&head).await;
+                });
+            }
+        });
+        Ok(port)
+    }
+
+    #[test]
+    fn test_pairs() {
+        let pairs = vec![
+            (":path".to_string(), "/a?b=c".to_string()),
+            ("x-empty".to_string(), String::new()),
+        ];
+        let bytes = serialize_pairs(&pairs);
+        assert_eq!(&bytes[..4], &2u32.to_le_bytes());
+        assert_eq!(deserialize_pairs(&bytes), Some(pairs));
+        assert_eq!(deserialize_pairs(&bytes[..bytes.len() - 3]), None);
+        assert_eq!(deserialize_pairs(&[255, 255, 255, 255]), None);
+    }
+
+    #[test]
+    fn test_load_limits() -> Result<(), Box<dyn Error>> {
+        let filter = module_file("configure", FILTER)?;
+        let filter = filter.to_string_lossy();
+        let configured = WasmOptions {
+            config: "on".to_string(),
+            ..WasmOptions::default()
+        };
+        assert!(WasmPlugin::load("*", &filter, &configured).is_ok());
+        assert!(WasmPlugin::load("*", &filter, &WasmOptions::default()).is_err());
+
+        // Runs out of fuel instead of hanging
+        let spin = module_file("spin", SPIN)?;
+        let fuel = WasmOptions {
+            fuel: 100_000,
+            ..configured
+        };
+        assert!(WasmPlugin::load("*", &spin.to_string_lossy(), &fuel).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wasm_filter() -> Result<(), Box<dyn Error>> {
+        let filter = module_file("filter", FILTER)?;
+        let upstream = echo_upstream().await?;
+        let source = format!(
+            r#""*:0" {{
+    wasm "{}" {{
+        config "on"
+    }}
+    reverse_proxy "/*" "http://127.0.0.1:{}"
+}}"#,
+            filter.display(),
+            upstream
+        );
+        let (server, port) = serve(&source).await?;
+
+        let passed = get(port, "").await?;
+        assert!(passed.starts_with("HTTP/1.1 200"));
+        assert!(passed.contains("x-wasm: on"));
+        assert!(passed.contains("x-wasm-seen: 1"));
+
+        let blocked = get(port, "X-Block: yes\r\n").await?;
+        assert!(blocked.starts_with("HTTP/1.1 403"));
+        assert!(blocked.ends_with("blocked by wasm"));
+        server.stop();
+        Ok(())
+    }
+}
@@ -0,0 +1,1109 @@
+use crate::config::{Directive, FileServerOptions};
+use crate::error::CbltError;
+use crate::file_server::{
+    entity_tag, html_escape, is_hidden, mime_type, sanitize_path, PATH_SEGMENT,
+};
+use crate::response::{send_response, with_headers, ExtraHeaders};
+use bytes::BytesMut;
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::header::{ALLOW, CONTENT_LENGTH, CONTENT_TYPE};
+use http::{Method, Request, Response, StatusCode, Uri};
+use percent_encoding::utf8_percent_encode;
+use quick_xml::events::Event;
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::reader::NsReader;
+use std::fs::Metadata;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+#[cfg(feature = "trace")]
+use tracing::instrument;
+
+/// Longest a lock is held without being refreshed, also what "Infinite" gets
+const LOCK_TIMEOUT: Duration = Duration::from_secs(3600);
+const METHODS: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE, PROPFIND, PROPPATCH, LOCK, UNLOCK";
+/// Properties every resource has, some only for files
+const LIVE_PROPERTIES: [&str; 9] = [
+    "creationdate",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "lockdiscovery",
+    "resourcetype",
+    "supportedlock",
+];
+const SUPPORTED_LOCK: &str = "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+<D:locktype><D:write/></D:locktype></D:lockentry><D:lockentry><D:lockscope><D:shared/>\
+</D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>";
+
+/// Write locks of a host's WebDAV file server, kept in memory
+#[derive(Default)]
+pub struct DavLocks {
+    locks: Mutex<Vec<Lock>>,
+}
+
+#[derive(Debug, Clone)]
+struct Lock {
+    token: String,
+    root: String, // path of the locked resource
+    exclusive: bool,
+    infinite: bool, // covers the resources under the root too
+    owner: Option<String>,
+    timeout: Duration,
+    expires: Instant,
+}
+
+/// Lock store of a host whose `file_server` has `webdav` on
+pub fn host_dav_locks(directives: &[Directive]) -> Option<DavLocks> {
+    directives.iter().find_map(|directive| match directive {
+        Directive::FileServer { options } if options.webdav => Some(DavLocks::default()),
+        _ => None,
+    })
+}
+
+/// Methods answered by the WebDAV handler, GET and HEAD stay with the file server
+pub fn is_webdav_method(method: &Method) -> bool {
+    matches!(
+        method.as_str(),
+        "OPTIONS"
+            | "PROPFIND"
+            | "PROPPATCH"
+            | "MKCOL"
+            | "PUT"
+            | "DELETE"
+            | "COPY"
+            | "MOVE"
+            | "LOCK"
+            | "UNLOCK"
+    )
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+pub async fn webdav_directive<S>(
+    root_path: Option<&str>,
+    options: &FileServerOptions,
+    locks: &DavLocks,
+    request: &Request<BytesMut>,
+    socket: &mut S,
+    extra_headers: &ExtraHeaders,
+) -> Result<StatusCode, CbltError>
+where
+    S: AsyncWrite + Unpin,
+{
+    let root = Path::new(root_path.ok_or(CbltError::ResponseError {
+        details: "".to_string(),
+        status_code: StatusCode::INTERNAL_SERVER_ERROR,
+    })?);
+    let (path, file) =
+        resource(root, request.uri().path()).ok_or(CbltError::DirectiveNotMatched)?;
+    if is_hidden(root, &file, options) {
+        return Err(error(StatusCode::NOT_FOUND, "Hidden path"));
+    }
+    let response = match request.method().as_str() {
+        "OPTIONS" => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("DAV", "1, 2")
+            .header(ALLOW, METHODS)
+            .header("MS-Author-Via", "DAV")
+            .header(CONTENT_LENGTH, 0)
+            .body(BytesMut::new())?),
+        "PROPFIND" => propfind(request, options, locks, root, &path, &file).await,
+        "PROPPATCH" => proppatch(request, locks, &path, &file).await,
+        "MKCOL" => mkcol(request, locks, &path, &file).await,
+        "PUT" => put(request, locks, &path, &file).await,
+        "DELETE" => delete(request, locks, &path, &file).await,
+        "COPY" | "MOVE" => copy_or_move(request, options, locks, root, &path, &file).await,
+        "LOCK" => lock(request, locks, &path, &file).await,
+        "UNLOCK" => unlock(request, locks, &path),
+        _ => Err(CbltError::DirectiveNotMatched),
+    }?;
+    let status = response.status();
+    send_response(socket, with_headers(response, extra_headers)).await?;
+    Ok(status)
+}
+
+enum Query {
+    All,
+    Names,
+    Properties(Vec<(String, String)>), // namespace and name
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn propfind(
+    request: &Request<BytesMut>,
+    options: &FileServerOptions,
+    locks: &DavLocks,
+    root: &Path,
+    path: &str,
+    file: &Path,
+) -> Result<Response<BytesMut>, CbltError> {
+    let metadata = tokio::fs::metadata(file).await.map_err(io_error)?;
+    let children = match header(request, "Depth") {
+        Some("0") => false,
+        Some("1") => true,
+        _ => {
+            return xml_response(
+                StatusCode::FORBIDDEN,
+                "<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>".to_string(),
+            );
+        }
+    };
+    let query = match parse_xml(request.body())? {
+        None => Query::All,
+        Some(propfind) if propfind.is("propfind") => match propfind.children.first() {
+            Some(query) if query.is("allprop") => Query::All,
+            Some(query) if query.is("propname") => Query::Names,
+            Some(query) if query.is("prop") => Query::Properties(
+                query
+                    .children
+                    .iter()
+                    .map(|property| (property.namespace.clone(), property.name.clone()))
+                    .collect(),
+            ),
+            _ => return Err(error(StatusCode::BAD_REQUEST, "Invalid propfind")),
+        },
+        Some(_) => return Err(error(StatusCode::BAD_REQUEST, "Expected propfind")),
+    };
+
+    let mut body = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    body.push_str("<D:multistatus xmlns:D=\"DAV:\">\n");
+    let entry = |path: &str, file: &Path, metadata: &Metadata| {
+        let live = |name: &str| live_property(name, path, file, metadata, locks, options);
+        let (mut found, mut missing) = (String::new(), String::new());
+        match &query {
+            Query::All | Query::Names => {
+                for name in LIVE_PROPERTIES {
+                    if let Some(value) = live(name) {
+                        let value = if matches!(query, Query::All) {
+                            &value
+                        } else {
+                            ""
+                        };
+                        found.push_str(&property(name, value));
+                    }
+                }
+            }
+            Query::Properties(properties) => {
+                for (namespace, name) in properties {
+                    let value = (namespace == "DAV:").then(|| live(name)).flatten();
+                    match value {
+                        Some(value) => found.push_str(&property(name, &value)),
+                        None if namespace == "DAV:" => missing.push_str(&property(name, "")),
+                        None => missing.push_str(&format!(
+                            "<{} xmlns=\"{}\"/>",
+                            name,
+                            html_escape(namespace)
+                        )),
+                    }
+                }
+            }
+        }
+        let mut response = format!("<D:response><D:href>{}</D:href>", href(path, metadata));
+        for (properties, status) in [(found, "200 OK"), (missing, "404 Not Found")] {
+            if !properties.is_empty() {
+                response.push_str(&format!(
+                    "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>",
+                    properties, status
+                ));
+            }
+        }
+        response.push_str("</D:response>\n");
+        response
+    };
+    body.push_str(&entry(path, file, &metadata));
+    if children && metadata.is_dir() {
+        let mut entries = Vec::new();
+        let mut read_dir = tokio::fs::read_dir(file).await.map_err(io_error)?;
+        while let Some(dir_entry) = read_dir.next_entry().await.map_err(io_error)? {
+            let child = dir_entry.path();
+            let Ok(metadata) = tokio::fs::metadata(&child).await else {
+                continue;
+            };
+            if is_hidden(root, &child, options) {
+                continue;
+            }
+            entries.push((
+                dir_entry.file_name().to_string_lossy().into_owned(),
+                child,
+                metadata,
+            ));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, child, metadata) in entries {
+            body.push_str(&entry(&join(path, &name), &child, &metadata));
+        }
+    }
+    body.push_str("</D:multistatus>\n");
+    xml_response(StatusCode::MULTI_STATUS, body)
+}
+
+/// Value of a live property, none when the resource does not have it
+fn live_property(
+    name: &str,
+    path: &str,
+    file: &Path,
+    metadata: &Metadata,
+    locks: &DavLocks,
+    options: &FileServerOptions,
+) -> Option<String> {
+    let modified = metadata.modified().ok();
+    match name {
+        "creationdate" => metadata.created().ok().or(modified).map(|created| {
+            DateTime::<Utc>::from(created).to_rfc3339_opts(SecondsFormat::Secs, true)
+        }),
+        "displayname" => Some(html_escape(path.rsplit('/').next().unwrap_or(""))),
+        "getcontentlength" if metadata.is_file() => Some(metadata.len().to_string()),
+        "getcontenttype" if metadata.is_file() => Some(html_escape(&mime_type(file, options))),
+        "getetag" if metadata.is_file() => {
+            modified.map(|modified| html_escape(&entity_tag(modified, metadata.len())))
+        }
+        "getlastmodified" => modified.map(httpdate::fmt_http_date),
+        "lockdiscovery" => Some(
+            locks
+                .covering(path)
+                .iter()
+                .map(active_lock)
+                .collect::<String>(),
+        ),
+        "resourcetype" if metadata.is_dir() => Some("<D:collection/>".to_string()),
+        "resourcetype" => Some(String::new()),
+        "supportedlock" => Some(SUPPORTED_LOCK.to_string()),
+        _ => None,
+    }
+}
+
+fn property(name: &str, value: &str) -> String {
+    match value {
+        "" => format!("<D:{name}/>"),
+        value => format!("<D:{name}>{value}</D:{name}>"),
+    }
+}
+
+/// Properties are not stored, so setting or removing any is refused
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn proppatch(
+    request: &Request<BytesMut>,
+    locks: &DavLocks,
+    path: &str,
+    file: &Path,
+) -> Result<Response<BytesMut>, CbltError> {
+    tokio::fs::metadata(file).await.map_err(io_error)?;
+    let update = parse_xml(request.body())?
+        .filter(|update| update.is("propertyupdate"))
+        .ok_or(error(StatusCode::BAD_REQUEST, "Expected propertyupdate"))?;
+    locks.check(&[(path, false)], &submitted_tokens(request))?;
+    let mut properties = String::new();
+    for property in update
+        .children
+        .iter()
+        .filter(|action| action.is("set") || action.is("remove"))
+        .filter_map(|action| action.child("prop"))
+        .flat_map(|prop| &prop.children)
+    {
+        properties.push_str(&format!(
+            "<{} xmlns=\"{}\"/>",
+            property.name,
+            html_escape(&property.namespace)
+        ));
+    }
+    xml_response(
+        StatusCode::MULTI_STATUS,
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n\
+             <D:response><D:href>{}</D:href><D:propstat><D:prop>{}</D:prop>\
+             <D:status>HTTP/1.1 403 Forbidden</D:status></D:propstat></D:response>\n\
+             </D:multistatus>\n",
+            html_escape(&encode_path(path)),
+            properties
+        ),
+    )
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn mkcol(
+    request: &Request<BytesMut>,
+    locks: &DavLocks,
+    path: &str,
+    file: &Path,
+) -> Result<Response<BytesMut>, CbltError> {
+    if !request.body().is_empty() {
+        return Err(error(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "MKCOL with a body",
+        ));
+    }
+    if tokio::fs::symlink_metadata(file).await.is_ok() {
+        return Err(error(StatusCode::METHOD_NOT_ALLOWED, "Already exists"));
+    }
+    locks.check(
+        &[(path, false), (parent(path), false)],
+        &submitted_tokens(request),
+    )?;
+    tokio::fs::create_dir(file).await.map_err(missing_parent)?;
+    empty_response(StatusCode::CREATED)
+}
+
+/// Writes the body next to the file and renames it over, readers see the old or the new file
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn put(
+    request: &Request<BytesMut>,
+    locks: &DavLocks,
+    path: &str,
+    file: &Path,
+) -> Result<Response<BytesMut>, CbltError> {
+    let existing = tokio::fs::metadata(file).await.ok();
+    if existing.as_ref().is_some_and(|metadata| metadata.is_dir()) {
+        return Err(error(StatusCode::METHOD_NOT_ALLOWED, "PUT to a collection"));
+    }
+    let mut protected = vec![(path, false)];
+    if existing.is_none() {
+        protected.push((parent(path), false));
+    }
+    locks.check(&protected, &submitted_tokens(request))?;
+
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let mut suffix = [0u8; 8];
+    let _ = aws_lc_rs::rand::fill(&mut suffix);
+    let upload = file.with_file_name(format!(".{}.cblt-upload-{}", name, to_hex(&suffix)));
+    tokio::fs::write(&upload, request.body())
+        .await
+        .map_err(missing_parent)?;
+    if let Err(err) = tokio::fs::rename(&upload, file).await {
+        let _ = tokio::fs::remove_file(&upload).await;
+        return Err(io_error(err));
+    }
+    empty_response(match existing {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::CREATED,
+    })
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn delete(
+    request: &Request<BytesMut>,
+    locks: &DavLocks,
+    path: &str,
+    file: &Path,
+) -> Result<Response<BytesMut>, CbltError> {
+    if path == "/" {
+        return Err(error(StatusCode::FORBIDDEN, "Deleting the root"));
+    }
+    tokio::fs::symlink_metadata(file).await.map_err(io_error)?;
+    locks.check(
+        &[(path, true), (parent(path), false)],
+        &submitted_tokens(request),
+    )?;
+    remove(file).await.map_err(io_error)?;
+    locks.release_within(path);
+    empty_response(StatusCode::NO_CONTENT)
+}
+
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn copy_or_move(
+    request: &Request<BytesMut>,
+    options: &FileServerOptions,
+    locks: &DavLocks,
+    root: &Path,
+    path: &str,
+    file: &Path,
+) -> Result<Response<BytesMut>, CbltError> {
+    let moving = request.method().as_str() == "MOVE";
+    let destination = header(request, "Destination")
+        .and_then(|destination| destination.parse::<Uri>().ok())
+        .ok_or(error(StatusCode::BAD_REQUEST, "Missing Destination"))?;
+    let (target, target_file) = resource(root, destination.path())
+        .filter(|(_, target_file)| !is_hidden(root, target_file, options))
+        .ok_or(error(StatusCode::FORBIDDEN, "Invalid Destination"))?;
+    let metadata = tokio::fs::metadata(file).await.map_err(io_error)?;
+    // One inside the other, overwriting would delete the source or copying never end
+    if is_within(&target, path) || is_within(path, &target) {
+        return Err(error(
+            StatusCode::FORBIDDEN,
+            "Destination overlaps the source",
+        ));
+    }
+    let deep = match header(request, "Depth") {
+        None | Some("infinity") => true,
+        Some("0") if !moving => false,
+        Some(_) => return Err(error(StatusCode::BAD_REQUEST, "Invalid Depth")),
+    };
+    let overwrite = header(request, "Overwrite") != Some("F");
+    let exists = tokio::fs::symlink_metadata(&target_file).await.is_ok();
+    if exists && !overwrite {
+        return Err(error(StatusCode::PRECONDITION_FAILED, "Destination exists"));
+    }
+    let mut protected = vec![(target.as_str(), true), (parent(&target), false)];
+    if moving {
+        protected.extend([(path, true), (parent(path), false)]);
+    }
+    locks.check(&protected, &submitted_tokens(request))?;
+
+    if exists {
+        remove(&target_file).await.map_err(io_error)?;
+    }
+    let moved = match moving {
+        true => tokio::fs::rename(file, &target_file).await,
+        false => Err(ErrorKind::Unsupported.into()),
+    };
+    match moved {
+        Ok(()) => {}
+        // Across file systems a move is a copy and a delete
+        Err(err) if !moving || err.kind() == ErrorKind::CrossesDevices => {
+            let (from, to) = (file.to_path_buf(), target_file.clone());
+            let deep = deep || !metadata.is_dir();
+            tokio::task::spawn_blocking(move || copy_tree(&from, &to, deep))
+                .await
+                .map_err(|err| error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()))?
+                .map_err(missing_parent)?;
+            if moving {
+                remove(file).await.map_err(io_error)?;
+            }
+        }
+        Err(err) => return Err(missing_parent(err)),
+    }
+    if moving {
+        locks.release_within(path);
+    }
+    empty_response(match exists {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::CREATED,
+    })
+}
+
+/// Takes a new lock, or refreshes one named in the `If` header when there is no body. A lock
+/// on a missing resource creates it empty.
+#[cfg_attr(feature = "trace", instrument(level = "trace", skip_all))]
+async fn lock(
+    request: &Request<BytesMut>,
+    locks: &DavLocks,
+    path: &str,
+    file: &Path,
+) -> Result<Response<BytesMut>, CbltError> {
+    let tokens = submitted_tokens(request);
+    let timeout = lock_timeout(request);
+    let Some(info) = parse_xml(request.body())? else {
+        let lock = locks
+            .refresh(path, &tokens, timeout)
+            .ok_or(error(StatusCode::PRECONDITION_FAILED, "No lock to refresh"))?;
+        return lock_response(StatusCode::OK, &lock, false);
+    };
+    let scope = info
+        .child("lockscope")
+        .filter(|_| info.is("lockinfo"))
+        .and_then(|scope| scope.children.first())
+        .ok_or(error(StatusCode::BAD_REQUEST, "Expected lockinfo"))?;
+    let infinite = match header(request, "Depth") {
+        None | Some("infinity") => true,
+        Some("0") => false,
+        Some(_) => return Err(error(StatusCode::BAD_REQUEST, "Invalid Depth")),
+    };
+    let owner = info.child("owner").map(|owner| match owner.child("href") {
+        Some(href) => format!("<D:href>{}</D:href>", html_escape(href.text.trim())),
+        None => html_escape(owner.text.trim()),
+    });
+    let exists = tokio::fs::symlink_metadata(file).await.is_ok();
+    if !exists {
+        locks.check(&[(parent(path), false)], &tokens)?;
+    }
+    let lock = Lock {
+        token: format!("opaquelocktoken:{}", random_uuid()),
+        root: path.to_string(),
+        exclusive: scope.is("exclusive"),
+        infinite,
+        owner,
+        timeout,
+        expires: Instant::now() + timeout,
+    };
+    locks.insert(lock.clone())?;
+    if exists {
+        return lock_response(StatusCode::OK, &lock, true);
+    }
+    let created = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(file)
+        .await;
+    if let Err(err) = created {
+        locks.unlock(path, &lock.token);
+        return Err(missing_parent(err));
+    }
+    lock_response(StatusCode::CREATED, &lock, true)
+}
+
+fn unlock(
+    request: &Request<BytesMut>,
+    locks: &DavLocks,
+    path: &str,
+) -> Result<Response<BytesMut>, CbltError> {
+    let token = header(request, "Lock-Token")
+        .map(|token| token.trim().trim_start_matches('<').trim_end_matches('>'))
+        .ok_or(error(StatusCode::BAD_REQUEST, "Missing Lock-Token"))?;
+    if !locks.unlock(path, token) {
+        return Err(error(
+            StatusCode::CONFLICT,
+            "Lock-Token does not lock the resource",
+        ));
+    }
+    empty_response(StatusCode::NO_CONTENT)
+}
+
+impl DavLocks {
+    /// Locks that have not expired
+    fn live(&self) -> MutexGuard<'_, Vec<Lock>> {
+        let mut locks = self.locks.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        locks.retain(|lock| lock.expires > now);
+        locks
+    }
+
+    /// Fails with 423 when a lock on one of the `(path, with descendants)` is not among the
+    /// submitted tokens, and with 412 when none of those tokens is a lock at all
+    fn check(&self, protected: &[(&str, bool)], tokens: &[String]) -> Result<(), CbltError> {
+        let locks = self.live();
+        if !tokens.is_empty() && !locks.iter().any(|lock| tokens.contains(&lock.token)) {
+            return Err(error(StatusCode::PRECONDITION_FAILED, "Unknown lock token"));
+        }
+        let locked = locks.iter().any(|lock| {
+            !tokens.contains(&lock.token)
+                && protected.iter().any(|(path, descendants)| {
+                    lock.covers(path) || (*descendants && is_within(&lock.root, path))
+                })
+        });
+        match locked {
+            true => Err(error(StatusCode::LOCKED, "Resource is locked")),
+            false => Ok(()),
+        }
+    }
+
+    fn insert(&self, lock: Lock) -> Result<(), CbltError> {
+        let mut locks = self.live();
+        let conflict = locks.iter().any(|held| {
+            (held.exclusive || lock.exclusive)
+                && (held.covers(&lock.root) || (lock.infinite && is_within(&held.root, &lock.root)))
+        });
+        if conflict {
+            return Err(error(StatusCode::LOCKED, "Resource is locked"));
+        }
+        locks.push(lock);
+        Ok(())
+    }
+
+    fn refresh(&self, path: &str, tokens: &[String], timeout: Duration) -> Option<Lock> {
+        let mut locks = self.live();
+        let lock = locks
+            .iter_mut()
+            .find(|lock| tokens.contains(&lock.token) && lock.covers(path))?;
+        lock.timeout = timeout;
+        lock.expires = Instant::now() + timeout;
+        Some(lock.clone())
+    }
+
+    fn unlock(&self, path: &str, token: &str) -> bool {
+        let mut locks = self.live();
+        let count = locks.len();
+        locks.retain(|lock| lock.token != token || !lock.covers(path));
+        locks.len() != count
+    }
+
+    /// Drops the locks of a deleted or moved resource and of everything under it
+    fn release_within(&self, path: &str) {
+        self.live().retain(|lock| !is_within(&lock.root, path));
+    }
+
+    fn covering(&self, path: &str) -> Vec<Lock> {
+        self.live()
+            .iter()
+            .filter(|lock| lock.covers(path))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Lock {
+    fn covers(&self, path: &str) -> bool {
+        self.root == path || (self.infinite && is_within(path, &self.root))
+    }
+}
+
+fn active_lock(lock: &Lock) -> String {
+    format!(
+        "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope><D:{}/></D:lockscope>\
+         <D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout>\
+         <D:locktoken><D:href>{}</D:href></D:locktoken>\
+         <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+        if lock.exclusive {
+            "exclusive"
+        } else {
+            "shared"
+        },
+        if lock.infinite { "infinity" } else { "0" },
+        lock.owner
+            .as_ref()
+            .map(|owner| format!("<D:owner>{}</D:owner>", owner))
+            .unwrap_or_default(),
+        lock.expires
+            .saturating_duration_since(Instant::now())
+            .as_millis()
+            .div_ceil(1000),
+        lock.token,
+        html_escape(&encode_path(&lock.root))
+    )
+}
+
+fn lock_response(
+    status: StatusCode,
+    lock: &Lock,
+    new: bool,
+) -> Result<Response<BytesMut>, CbltError> {
+    let mut response = xml_response(
+        status,
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:prop xmlns:D=\"DAV:\">\
+             <D:lockdiscovery>{}</D:lockdiscovery></D:prop>\n",
+            active_lock(lock)
+        ),
+    )?;
+    if new {
+        response
+            .headers_mut()
+            .insert("Lock-Token", format!("<{}>", lock.token).parse()?);
+    }
+    Ok(response)
+}
+
+/// Timeout the client asked for in `Second-n` or `Infinite`, capped at `LOCK_TIMEOUT`
+fn lock_timeout(request: &Request<BytesMut>) -> Duration {
+    header(request, "Timeout")
+        .and_then(|timeout| {
+            timeout.split(',').find_map(|timeout| match timeout.trim() {
+                "Infinite" => Some(LOCK_TIMEOUT),
+                timeout => timeout
+                    .strip_prefix("Second-")
+                    .and_then(|seconds| seconds.parse().ok())
+                    .map(Duration::from_secs),
+            })
+        })
+        .unwrap_or(LOCK_TIMEOUT)
+        .min(LOCK_TIMEOUT)
+}
+
+/// Lock tokens among the conditions of the `If` header, whatever resource they are tagged with
+fn submitted_tokens(request: &Request<BytesMut>) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let Some(mut conditions) = header(request, "If") else {
+        return tokens;
+    };
+    let mut in_list = false;
+    while let Some(start) = conditions.find(['(', ')', '<', '[']) {
+        let rest = &conditions[start + 1..];
+        let end = match conditions.as_bytes()[start] {
+            b'(' => {
+                in_list = true;
+                0
+            }
+            b')' => {
+                in_list = false;
+                0
+            }
+            b'<' => match rest.find('>') {
+                Some(end) => {
+                    if in_list {
+                        tokens.push(rest[..end].to_string());
+                    }
+                    end + 1
+                }
+                None => break,
+            },
+            // An entity tag, which may hold any of the brackets
+            _ => rest.find(']').map_or(rest.len(), |end| end + 1),
+        };
+        conditions = &rest[end..];
+    }
+    tokens
+}
+
+/// Element of a request body with its namespace
+#[derive(Debug, Default)]
+struct Element {
+    namespace: String,
+    name: String,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn is(&self, name: &str) -> bool {
+        self.namespace == "DAV:" && self.name == name
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.is(name))
+    }
+}
+
+/// Root element of the body, none for an empty one
+fn parse_xml(body: &[u8]) -> Result<Option<Element>, CbltError> {
+    let invalid = || error(StatusCode::BAD_REQUEST, "Invalid XML body");
+    let xml = std::str::from_utf8(body).map_err(|_| invalid())?;
+    if xml.trim().is_empty() {
+        return Ok(None);
+    }
+    let mut reader = NsReader::from_str(xml);
+    let mut open: Vec<Element> = Vec::new();
+    loop {
+        let (namespace, event) = reader.read_resolved_event().map_err(|_| invalid())?;
+        let namespace = match namespace {
+            ResolveResult::Bound(Namespace(namespace)) => {
+                String::from_utf8_lossy(namespace).into_owned()
+            }
+            _ => String::new(),
+        };
+        let closed = match event {
+            Event::Start(start) => {
+                open.push(Element {
+                    namespace,
+                    name: name(start.local_name().as_ref()).ok_or_else(invalid)?,
+                    ..Element::default()
+                });
+                continue;
+            }
+            Event::Empty(empty) => Element {
+                namespace,
+                name: name(empty.local_name().as_ref()).ok_or_else(invalid)?,
+                ..Element::default()
+            },
+            Event::End(_) => open.pop().ok_or_else(invalid)?,
+            Event::Text(text) => {
+                if let Some(element) = open.last_mut() {
+                    element
+                        .text
+                        .push_str(&text.unescape().map_err(|_| invalid())?);
+                }
+                continue;
+            }
+            Event::CData(data) => {
+                if let Some(element) = open.last_mut() {
+                    element.text.push_str(&String::from_utf8_lossy(&data));
+                }
+                continue;
+            }
+            Event::Eof => return Err(invalid()),
+            _ => continue,
+        };
+        match open.last_mut() {
+            Some(parent) => parent.children.push(closed),
+            None => return Ok(Some(closed)),
+        }
+    }
+}
+
+/// Local name of an element, which property responses repeat as is
+fn name(local_name: &[u8]) -> Option<String> {
+    let name = std::str::from_utf8(local_name).ok()?;
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    valid.then(|| name.to_string())
+}
+
+/// Path of the request with dot segments resolved and the file it names. A file yet to be
+/// created must not be reached through a symlink leading out of the root either.
+fn resource(root: &Path, path: &str) -> Option<(String, PathBuf)> {
+    let file = sanitize_path(root, path.trim_start_matches('/'))?;
+    if std::fs::symlink_metadata(&file).is_err() {
+        if let (Ok(root), Some(Ok(parent))) = (
+            root.canonicalize(),
+            file.parent().map(|parent| parent.canonicalize()),
+        ) {
+            if !parent.starts_with(root) {
+                return None;
+            }
+        }
+    }
+    let relative = file.strip_prefix(root).ok()?;
+    let mut path = String::new();
+    for segment in relative.iter() {
+        path.push('/');
+        path.push_str(&segment.to_string_lossy());
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Some((path, file))
+}
+
+/// Whether `path` is `ancestor` or under it
+fn is_within(path: &str, ancestor: &str) -> bool {
+    ancestor == "/"
+        || path
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn parent(path: &str) -> &str {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/",
+        Some((parent, _)) => parent,
+    }
+}
+
+fn join(path: &str, name: &str) -> String {
+    format!("{}/{}", path.trim_end_matches('/'), name)
+}
+
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| utf8_percent_encode(segment, PATH_SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Escaped href of a resource, collections end with a slash
+fn href(path: &str, metadata: &Metadata) -> String {
+    let slash = if metadata.is_dir() && path != "/" {
+        "/"
+    } else {
+        ""
+    };
+    html_escape(&format!("{}{}", encode_path(path), slash))
+}
+
+fn header<'a>(request: &'a Request<BytesMut>, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+}
+
+async fn remove(file: &Path) -> std::io::Result<()> {
+    match tokio::fs::symlink_metadata(file).await?.is_dir() {
+        true => tokio::fs::remove_dir_all(file).await,
+        false => tokio::fs::remove_file(file).await,
+    }
+}
+
+/// Copies a file, or a directory with its contents when `deep`
+fn copy_tree(from: &Path, to: &Path, deep: bool) -> std::io::Result<()> {
+    if !from.is_dir() {
+        return std::fs::copy(from, to).map(|_| ());
+    }
+    std::fs::create_dir(to)?;
+    if deep {
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()), true)?;
+        }
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Random (version 4) UUID
+fn random_uuid() -> String {
+    let mut id = [0u8; 16];
+    let _ = aws_lc_rs::rand::fill(&mut id);
+    id[6] = (id[6] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+    format!(
+        "{}-{}-{}-{}-{}",
+        to_hex(&id[..4]),
+        to_hex(&id[4..6]),
+        to_hex(&id[6..8]),
+        to_hex(&id[8..10]),
+        to_hex(&id[10..])
+    )
+}
+
+fn xml_response(status: StatusCode, body: String) -> Result<Response<BytesMut>, CbltError> {
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/xml; charset=utf-8")
+        .body(BytesMut::from(body.as_bytes()))?)
+}
+
+fn empty_response(status: StatusCode) -> Result<Response<BytesMut>, CbltError> {
+    Ok(Response::builder().status(status).body(BytesMut::new())?)
+}
+
+fn error(status_code: StatusCode, details: &str) -> CbltError {
+    CbltError::ResponseError {
+        details: details.to_string(),
+        status_code,
+    }
+}
+
+fn io_error(err: std::io::Error) -> CbltError {
+    let status_code = match err.kind() {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error(status_code, &err.to_string())
+}
+
+/// A missing parent collection is a conflict, not a missing resource
+fn missing_parent(err: std::io::Error) -> CbltError {
+    match err.kind() {
+        ErrorKind::NotFound => error(StatusCode::CONFLICT, "Parent collection missing"),
+        _ => io_error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::FileServerOptions;
+    use crate::response::ExtraHeaders;
+    use crate::webdav::{parse_xml, submitted_tokens, webdav_directive, DavLocks};
+    use bytes::BytesMut;
+    use http::{Request, StatusCode};
+    use std::error::Error;
+    use std::path::Path;
+
+    /// Status and response of a request to the WebDAV handler
+    async fn send(
+        root: &Path,
+        locks: &DavLocks,
+        request: Request<&str>,
+    ) -> Result<(StatusCode, String), Box<dyn Error>> {
+        let request = request.map(|body| BytesMut::from(body.as_bytes()));
+        let options = FileServerOptions {
+            webdav: true,
+            ..FileServerOptions::default()
+        };
+        let mut response = Vec::new();
+        let status = match webdav_directive(
+            root.to_str(),
+            &options,
+            locks,
+            &request,
+            &mut response,
+            &ExtraHeaders::default(),
+        )
+        .await
+        {
+            Ok(status) => status,
+            Err(crate::error::CbltError::ResponseError { status_code, .. }) => status_code,
+            Err(err) => return Err(err.into()),
+        };
+        Ok((status, String::from_utf8(response)?))
+    }
+
+    #[test]
+    fn test_submitted_tokens() -> Result<(), Box<dyn Error>> {
+        let request = Request::put("/a")
+            .header(
+                "If",
+                "<http://example.com/a> (<urn:uuid:1> [\"etag(<x>)\"]) \
+                 (Not <opaquelocktoken:2>) <urn:outside>",
+            )
+            .body(BytesMut::new())?;
+        assert_eq!(
+            submitted_tokens(&request),
+            vec!["urn:uuid:1", "opaquelocktoken:2"]
+        );
+
+        let propfind = parse_xml(
+            b"<?xml version=\"1.0\"?><a:propfind xmlns:a=\"DAV:\" xmlns:z=\"urn:z\">\
+              <a:prop><a:getetag/><z:color>red</z:color></a:prop></a:propfind>",
+        )?
+        .ok_or("expected an element")?;
+        assert!(propfind.is("propfind"));
+        let prop = propfind.child("prop").ok_or("expected prop")?;
+        assert!(prop.children[0].is("getetag"));
+        assert_eq!(prop.children[1].namespace, "urn:z");
+        assert_eq!(prop.children[1].text, "red");
+        assert!(parse_xml(b"  ")?.is_none());
+        assert!(parse_xml(b"<propfind xmlns=\"DAV:\">").is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_webdav() -> Result<(), Box<dyn Error>> {
+        let root = std::env::temp_dir().join(format!("cblt-webdav-{}", std::process::id()));
+        std::fs::create_dir_all(&root)?;
+        let locks = DavLocks::default();
+
+        let mkcol = Request::builder().method("MKCOL").uri("/docs").body("")?;
+        assert_eq!(send(&root, &locks, mkcol).await?.0, StatusCode::CREATED);
+        let put = || Request::put("/docs/a%20b.txt").body("hello");
+        assert_eq!(send(&root, &locks, put()?).await?.0, StatusCode::CREATED);
+        assert_eq!(std::fs::read_to_string(root.join("docs/a b.txt"))?, "hello");
+        assert_eq!(send(&root, &locks, put()?).await?.0, StatusCode::NO_CONTENT);
+        let orphan = Request::put("/missing/a.txt").body("")?;
+        assert_eq!(send(&root, &locks, orphan).await?.0, StatusCode::CONFLICT);
+
+        let propfind = Request::builder()
+            .method("PROPFIND")
+            .uri("/docs/")
+            .header("Depth", "1")
+            .body("")?;
+        let (status, response) = send(&root, &locks, propfind).await?;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(response.contains("<D:href>/docs/</D:href>"));
+        assert!(response.contains("<D:href>/docs/a%20b.txt</D:href>"));
+        assert!(response.contains("<D:getcontentlength>5</D:getcontentlength>"));
+
+        // A depth-infinity lock on the collection guards what is under it
+        let lock = Request::builder().method("LOCK").uri("/docs").body(
+            "<D:lockinfo xmlns:D=\"DAV:\"><D:lockscope><D:exclusive/></D:lockscope>\
+                 <D:locktype><D:write/></D:locktype><D:owner>me</D:owner></D:lockinfo>",
+        )?;
+        let (status, response) = send(&root, &locks, lock).await?;
+        assert_eq!(status, StatusCode::OK);
+        let token = response
+            .lines()
+            .find_map(|line| line.strip_prefix("lock-token: <"))
+            .and_then(|token| token.strip_suffix('>'))
+            .ok_or("expected a Lock-Token")?
+            .to_string();
+        assert!(response.contains("<D:owner>me</D:owner>"));
+        assert_eq!(send(&root, &locks, put()?).await?.0, StatusCode::LOCKED);
+        let with_token = Request::put("/docs/a%20b.txt")
+            .header("If", format!("(<{}>)", token))
+            .body("locked")?;
+        assert_eq!(
+            send(&root, &locks, with_token).await?.0,
+            StatusCode::NO_CONTENT
+        );
+        let copy = Request::builder()
+            .method("COPY")
+            .uri("/docs/a%20b.txt")
+            .header("Destination", "http://localhost/copy.txt")
+            .body("")?;
+        assert_eq!(send(&root, &locks, copy).await?.0, StatusCode::CREATED);
+        assert_eq!(std::fs::read_to_string(root.join("copy.txt"))?, "locked");
+        let delete = || Request::delete("/docs").body("");
+        assert_eq!(send(&root, &locks, delete()?).await?.0, StatusCode::LOCKED);
+        let unlock = Request::builder()
+            .method("UNLOCK")
+            .uri("/docs/a%20b.txt")
+            .header("Lock-Token", format!("<{}>", token))
+            .body("")?;
+        assert_eq!(send(&root, &locks, unlock).await?.0, StatusCode::NO_CONTENT);
+
+        let moving = Request::builder()
+            .method("MOVE")
+            .uri("/docs")
+            .header("Destination", "/archive")
+            .body("")?;
+        assert_eq!(send(&root, &locks, moving).await?.0, StatusCode::CREATED);
+        assert!(root.join("archive/a b.txt").is_file());
+        let delete = Request::delete("/archive").body("")?;
+        assert_eq!(send(&root, &locks, delete).await?.0, StatusCode::NO_CONTENT);
+        assert!(!root.join("archive").exists());
+        std::fs::remove_dir_all(&root)?;
+
+        Ok(())
+    }
+}